@@ -0,0 +1,99 @@
+//! Streams records through the ring buffer of `IOCTL_ENABLE_EVENT_RING` from a
+//! producer thread to a consumer thread on the host, and checks that every
+//! record is either received intact and in order or counted as dropped. Run it
+//! under Miri to check the atomic orderings of `capcom_abi::ring`:
+//!
+//! ```text
+//! cargo +nightly miri run --package capcom-abi --example event_ring
+//! ```
+
+use std::{
+    process::ExitCode,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+};
+
+// Dependencies of the library the example does not use.
+#[cfg(loom)]
+use loom as _;
+use utf16_lit as _;
+
+use capcom_abi::{
+    EventRecord, EventRingHeader,
+    ring::{self, Consumer, Producer},
+};
+
+/// The number of records to send. Small enough for Miri.
+const COUNT: u32 = 500;
+
+/// The number of records the ring holds, small to wrap around often.
+const CAPACITY: usize = 4;
+
+/// The producer moved to another thread.
+struct Side<T>(T);
+
+// The ring outlives the thread, and the producer is used only by it.
+unsafe impl<T> Send for Side<T> {}
+
+fn main() -> ExitCode {
+    let size = size_of::<EventRingHeader>() + CAPACITY * size_of::<EventRecord>();
+    let mut memory = vec![EventRingHeader::default(); size.div_ceil(size_of::<EventRingHeader>())];
+    let header = memory.as_mut_ptr();
+    let mut producer = Side(unsafe { Producer::format(header, ring::capacity(size)) });
+    let mut consumer = unsafe { Consumer::new(header) };
+
+    let finished = AtomicBool::new(false);
+    let (appended, received) = thread::scope(|scope| {
+        let producer = scope.spawn(|| {
+            let producer = &mut producer;
+            let appended = (0..COUNT)
+                .filter(|&sequence| {
+                    let appended = producer.0.push(&EventRecord {
+                        kind: sequence,
+                        reserved: !sequence,
+                        ..EventRecord::default()
+                    });
+                    // Let the consumer catch up, so that records are both
+                    // received and dropped.
+                    if !appended {
+                        thread::yield_now();
+                    }
+                    appended
+                })
+                .count();
+            finished.store(true, Ordering::Release);
+            appended
+        });
+        let mut received = Vec::new();
+        loop {
+            // Check before popping, so that the records appended right before
+            // the producer finished are still received.
+            let done = finished.load(Ordering::Acquire);
+            match consumer.pop() {
+                Some(record) => received.push(record),
+                None if done => break,
+                None => thread::yield_now(),
+            }
+        }
+        let appended = producer.join().expect("the producer should not panic");
+        (appended, received)
+    });
+
+    let dropped = unsafe { Consumer::new(header) }.dropped();
+    if received.len() != appended || appended as u64 + dropped != u64::from(COUNT) {
+        println!(
+            "{} records were received, {appended} appended and {dropped} dropped",
+            received.len()
+        );
+        return ExitCode::FAILURE;
+    }
+    if let Some(pair) = received
+        .windows(2)
+        .find(|pair| pair[0].kind >= pair[1].kind || pair[1].reserved != !pair[1].kind)
+    {
+        println!("records are out of order or torn: {pair:?}");
+        return ExitCode::FAILURE;
+    }
+    println!("{appended} records were received in order, and {dropped} dropped");
+    ExitCode::SUCCESS
+}
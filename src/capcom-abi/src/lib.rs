@@ -0,0 +1,1666 @@
+//! Definitions shared between the driver and user-mode programs, such as the
+//! device name and IOCTL codes. They are compatible with the original
+//! Capcom.sys.
+//!
+//! Structures have the same layout for 32-bit and 64-bit programs, as addresses
+//! and handles are `u64` regardless of the pointer size. 32-bit programs under
+//! WOW64 give them in the lower 32 bits, and the driver ignores the upper 32
+//! bits if they are zero or all ones, i.e., extended from 32 bits.
+#![no_std]
+
+pub mod map_test;
+pub mod messages;
+pub mod ring;
+pub mod signal;
+mod sync;
+
+/// The name of the device object.
+pub const DEVICE_NAME: &str = r"\Device\Htsysm72FB";
+
+/// [`DEVICE_NAME`] in UTF-16.
+pub const DEVICE_NAME_UTF16: [u16; 18] = utf16_lit::utf16!("\\Device\\Htsysm72FB");
+
+/// The name of the symbolic link to the device object.
+pub const LINK_NAME: &str = r"\DosDevices\Htsysm72FB";
+
+/// [`LINK_NAME`] in UTF-16.
+pub const LINK_NAME_UTF16: [u16; 22] = utf16_lit::utf16!("\\DosDevices\\Htsysm72FB");
+
+/// The path user-mode programs open the device with.
+pub const DEVICE_PATH: &str = r"\\.\Htsysm72FB";
+
+/// The device interface class of the compatible device, registered when the
+/// `DeviceInterface` or `StealthSeed` value of the service key is nonzero, so
+/// the device can be found by enumerating interfaces regardless of the name of
+/// the symbolic link. Not in the original driver.
+pub const DEVICE_INTERFACE_GUID: Guid = Guid {
+    data1: 0x3f0a_5c1e,
+    data2: 0x8d27,
+    data3: 0x4b6e,
+    data4: [0x9c, 0x14, 0x7a, 0x2e, 0x5d, 0x9b, 0x0c, 0x61],
+};
+
+/// The length of the names [`stealth_name`] returns.
+pub const STEALTH_NAME_LENGTH: usize = 12;
+
+/// Returns the name the driver uses instead of `Htsysm72FB`, or
+/// `Htsysm72FBControl` if `control`, for its device object and symbolic link
+/// when the `StealthSeed` value of the service key is nonzero. Not in the
+/// original driver.
+///
+/// The name is lowercase letters derived from `seed`, the value, and
+/// `boot_id`, the `BootId` field of `KUSER_SHARED_DATA`, which user-mode
+/// programs can read at `0x7ffe02c4`. So the name changes every boot, and only
+/// programs knowing the seed can compute it. Others find the device through
+/// [`DEVICE_INTERFACE_GUID`], which is always registered in this mode.
+#[must_use]
+pub const fn stealth_name(seed: u32, boot_id: u32, control: bool) -> [u8; STEALTH_NAME_LENGTH] {
+    // SplitMix64.
+    let mut state = ((seed as u64) << 32 | boot_id as u64) ^ if control { 0x636f_6e74 } else { 0 };
+    let mut name = [0; STEALTH_NAME_LENGTH];
+    let mut i = 0;
+    while i < name.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        name[i] = b'a' + (z % 26) as u8;
+        i += 1;
+    }
+    name
+}
+
+/// The name of the control device object, which only elevated administrators
+/// can open. It accepts [`CONTROL_IOCTLS`] without negotiation. Not in the
+/// original driver.
+pub const CONTROL_DEVICE_NAME: &str = r"\Device\Htsysm72FBControl";
+
+/// [`CONTROL_DEVICE_NAME`] in UTF-16.
+pub const CONTROL_DEVICE_NAME_UTF16: [u16; 25] = utf16_lit::utf16!("\\Device\\Htsysm72FBControl");
+
+/// The name of the symbolic link to the control device object.
+pub const CONTROL_LINK_NAME: &str = r"\DosDevices\Htsysm72FBControl";
+
+/// [`CONTROL_LINK_NAME`] in UTF-16.
+pub const CONTROL_LINK_NAME_UTF16: [u16; 29] = utf16_lit::utf16!("\\DosDevices\\Htsysm72FBControl");
+
+/// The path user-mode programs open the control device with.
+pub const CONTROL_DEVICE_PATH: &str = r"\\.\Htsysm72FBControl";
+
+/// The IOCTLs the control device accepts: configuration, statistics, the kill
+/// switch, and [`IOCTL_CORRELATED`] sending any of them. Others fail with `STATUS_INVALID_DEVICE_REQUEST`.
+pub const CONTROL_IOCTLS: &[u32] = &[
+    IOCTL_GET_VERSION,
+    IOCTL_READ_LOG,
+    IOCTL_GET_AUDIT,
+    IOCTL_KILL_SWITCH,
+    IOCTL_SET_DEBUG_BREAK,
+    IOCTL_SET_PRINT_FILTER,
+    IOCTL_SAVE_CONFIG,
+    IOCTL_SELF_DESTRUCT,
+    IOCTL_QUERY_ALLOCATIONS,
+    IOCTL_SELF_TEST,
+    IOCTL_CORRELATED,
+];
+
+/// The device type of the device object.
+pub const DEVICE_TYPE: u32 = 0xaa01;
+
+/// Executes the payload whose address is given as the 8-byte input buffer,
+/// with CR4.SMEP and interrupts disabled. The payload receives the address of
+/// `MmGetSystemRoutineAddress` as the only parameter. If the output buffer can
+/// hold [`PayloadTranscript`], the driver writes the registers around the call
+/// to it, which the original driver does not.
+pub const IOCTL_RUN_PAYLOAD: u32 = (DEVICE_TYPE << 16) | 0x3044;
+
+/// The 32-bit variant of [`IOCTL_RUN_PAYLOAD`] the original driver accepts,
+/// taking a 4-byte address. The driver implements it only when built for x86.
+pub const IOCTL_RUN_PAYLOAD32: u32 = (DEVICE_TYPE << 16) | 0x2044;
+
+/// Copies the shellcode given as the input buffer into executable non-paged
+/// pool and executes it like [`IOCTL_RUN_PAYLOAD`]. This is the only way to run
+/// a payload on ARM64, where user-mode pages are never executable in
+/// kernel-mode. Not in the original driver.
+pub const IOCTL_RUN_SHELLCODE: u32 = (DEVICE_TYPE << 16) | 0x3048;
+
+/// Returns [`VersionInfo`] as the output buffer, followed by [`BuildInfo`] at
+/// [`BUILD_INFO_OFFSET`] if the output buffer can hold it. Not in the original
+/// driver.
+pub const IOCTL_GET_VERSION: u32 = (DEVICE_TYPE << 16) | 0x304c;
+
+/// Declares the IOCTL classes the handle uses with [`NegotiateRequest`] as the
+/// input buffer, and returns [`NegotiateResponse`] as the output buffer. A
+/// handle must negotiate once before using IOCTLs not in the original driver,
+/// except [`IOCTL_GET_VERSION`]. Handles that do not negotiate can still use
+/// [`IOCTL_RUN_PAYLOAD`] if [`CLASS_EXECUTE`] is enabled. Not in the original
+/// driver.
+pub const IOCTL_NEGOTIATE: u32 = (DEVICE_TYPE << 16) | 0x3050;
+
+/// Moves records of IOCTL callers and [`IOCTL_SAMPLE_NMI`] samples, oldest
+/// first, from the driver's ring buffer to the output buffer as an array of
+/// [`LogRecord`], as many as fit. Records that do not fit are kept for the
+/// next request. Records not read when Windows shuts down are saved in the
+/// registry, and returned first after it starts again. Like
+/// [`IOCTL_GET_VERSION`], this does not require negotiation. Not in the
+/// original driver.
+pub const IOCTL_READ_LOG: u32 = (DEVICE_TYPE << 16) | 0x3054;
+
+/// Refuses payload execution of all handles until the driver restarts. Like
+/// [`IOCTL_GET_VERSION`], this does not require negotiation. Not in the
+/// original driver.
+pub const IOCTL_KILL_SWITCH: u32 = (DEVICE_TYPE << 16) | 0x3058;
+
+/// Returns [`AuditInfo`] as the output buffer. Like [`IOCTL_GET_VERSION`], this
+/// does not require negotiation. Not in the original driver.
+pub const IOCTL_GET_AUDIT: u32 = (DEVICE_TYPE << 16) | 0x305c;
+
+/// Removes the driver from the system with [`SelfDestructRequest`] as the
+/// input buffer. The symbolic link is deleted immediately, and the driver is
+/// unloaded once all handles to the device are closed. Not in the original
+/// driver.
+pub const IOCTL_SELF_DESTRUCT: u32 = (DEVICE_TYPE << 16) | 0x3060;
+
+/// Returns [`CpuState`] of the processor given with [`CpuStateRequest`] as the
+/// input buffer. Requires [`CLASS_KERNEL_MEMORY`]. Not supported on ARM64. Not
+/// in the original driver.
+pub const IOCTL_SNAPSHOT_CPU_STATE: u32 = (DEVICE_TYPE << 16) | 0x3064;
+
+/// Returns [`PteInfo`] of the page table entry translating the address given
+/// with [`PteRequest`] as the input buffer. Requires [`CLASS_KERNEL_MEMORY`].
+/// Supported only on x86_64 with 4-level paging. Not in the original driver.
+pub const IOCTL_GET_PTE: u32 = (DEVICE_TYPE << 16) | 0x3068;
+
+/// Modifies the bits in [`SetPteRequest::mask`] of the page table entry, and
+/// returns the updated [`PteInfo`]. The mask may contain only `PTE_*` flags,
+/// and [`PTE_PFN`] only for 4KB pages. TLBs of all processors are flushed.
+/// Requires [`CLASS_KERNEL_MEMORY`]. Not in the original driver.
+pub const IOCTL_SET_PTE: u32 = (DEVICE_TYPE << 16) | 0x306c;
+
+/// Returns [`ThreadCapture`] of the thread given with [`ThreadCaptureRequest`]
+/// as the input buffer. The backtrace is captured by the thread itself in a
+/// kernel APC. If the thread does not run the APC within
+/// [`ThreadCaptureRequest::timeout_ms`], e.g., as it is deadlocked with APCs
+/// disabled, or with [`THREAD_CAPTURE_SAVED_STACK`], the backtrace is unwound
+/// from the kernel stack the thread saved when it was last switched out
+/// instead. That requires [`KernelOffsets::kthread_kernel_stack`] and x64, and
+/// fails with `STATUS_TIMEOUT` after the timeout, or `STATUS_NOT_SUPPORTED`
+/// with the flag, otherwise. The driver does not unload until the APC runs or
+/// the thread exits. Requires [`CLASS_KERNEL_MEMORY`]. Not in the original
+/// driver.
+pub const IOCTL_CAPTURE_THREAD: u32 = (DEVICE_TYPE << 16) | 0x3070;
+
+/// Lists objects in the object directory given with [`EnumDirectoryRequest`]
+/// as the input buffer, as an array of [`DirectoryEntry`] in the output
+/// buffer, as many as fit. Fewer entries than fit mean the end of the
+/// directory. Requires negotiation but no class. Not in the original driver.
+pub const IOCTL_ENUM_DIRECTORY: u32 = (DEVICE_TYPE << 16) | 0x3074;
+
+/// Duplicates a handle of another process into the caller with
+/// [`DupHandleRequest`] as the input buffer, and returns [`DupHandleResponse`]
+/// as the output buffer. The source process is opened from kernel-mode, so the
+/// caller does not need `PROCESS_DUP_HANDLE` to it. Requires
+/// [`CLASS_ELEVATION`]. Not in the original driver.
+pub const IOCTL_DUP_HANDLE: u32 = (DEVICE_TYPE << 16) | 0x3078;
+
+/// Queues a user-mode APC to the thread given with [`UserApcRequest`] as the
+/// input buffer. The APC runs when the thread next enters an alertable wait,
+/// and the driver does not unload until then or the thread exits. Requires
+/// [`CLASS_ELEVATION`]. Not in the original driver.
+pub const IOCTL_QUEUE_USER_APC: u32 = (DEVICE_TYPE << 16) | 0x307c;
+
+/// Reads the registry value given with [`RegistryRequest`] as the input
+/// buffer, and returns [`RegistryValue`] followed by the data as the output
+/// buffer. If the data does not fit, only [`RegistryValue`] is returned. The
+/// key is opened from kernel-mode regardless of its security descriptor.
+/// Requires [`CLASS_ELEVATION`]. Not in the original driver.
+pub const IOCTL_REG_QUERY: u32 = (DEVICE_TYPE << 16) | 0x3080;
+
+/// Writes the registry value given with [`RegistryRequest`] as the input
+/// buffer. The key must exist. Like [`IOCTL_REG_QUERY`], the key is opened
+/// from kernel-mode. Requires [`CLASS_ELEVATION`]. Not in the original driver.
+pub const IOCTL_REG_SET: u32 = (DEVICE_TYPE << 16) | 0x3084;
+
+/// Reads the file given with [`FileRequest`] as the input buffer into the
+/// output buffer, as many bytes as fit. The file is opened from kernel-mode
+/// ignoring its security descriptor and sharing mode. Requires
+/// [`CLASS_ELEVATION`]. Not in the original driver.
+pub const IOCTL_READ_FILE: u32 = (DEVICE_TYPE << 16) | 0x3088;
+
+/// Writes the data following [`FileRequest`] in the input buffer to the file,
+/// creating it if it does not exist. Like [`IOCTL_READ_FILE`], the file is
+/// opened ignoring its security descriptor and sharing mode. Requires
+/// [`CLASS_ELEVATION`]. Not in the original driver.
+pub const IOCTL_WRITE_FILE: u32 = (DEVICE_TYPE << 16) | 0x308c;
+
+/// Registers or unregisters the NMI callback of the driver as given with
+/// [`NmiCallbackRequest`] as the input buffer. Requires
+/// [`CLASS_KERNEL_MEMORY`]. Not supported on ARM64. Not in the original
+/// driver.
+pub const IOCTL_SET_NMI_CALLBACK: u32 = (DEVICE_TYPE << 16) | 0x3090;
+
+/// Sends an NMI to the processor given with [`NmiSampleRequest`] as the input
+/// buffer and writes the interrupted context as [`NmiSample`] to the output
+/// buffer. Unlike DPCs, this samples code running with interrupts disabled.
+/// The sample is also recorded for [`IOCTL_READ_LOG`]. Requires
+/// [`IOCTL_SET_NMI_CALLBACK`] first and [`CLASS_KERNEL_MEMORY`]. Not supported
+/// on ARM64. Not in the original driver.
+pub const IOCTL_SAMPLE_NMI: u32 = (DEVICE_TYPE << 16) | 0x3094;
+
+/// Reads the local APIC register given with [`ApicRequest`] as the input
+/// buffer on the given processor, and writes it as `u64` to the output buffer.
+/// Requires [`CLASS_MSR`]. Not supported on ARM64. Not in the original driver.
+pub const IOCTL_READ_APIC: u32 = (DEVICE_TYPE << 16) | 0x3098;
+
+/// Writes the value to the local APIC register given with [`ApicRequest`] as
+/// the input buffer on the given processor. Requires [`CLASS_MSR`]. Not
+/// supported on ARM64. Not in the original driver.
+pub const IOCTL_WRITE_APIC: u32 = (DEVICE_TYPE << 16) | 0x309c;
+
+/// Reads or writes the PCI configuration space register given with
+/// [`PciConfigRequest`] as the input buffer. A read writes the value as `u32`
+/// to the output buffer. Requires [`CLASS_PHYSICAL_MEMORY`]. Not in the
+/// original driver.
+pub const IOCTL_PCI_CONFIG_RW: u32 = (DEVICE_TYPE << 16) | 0x30a0;
+
+/// Allocates physically contiguous, zeroed memory as given with
+/// [`ContiguousAllocRequest`] as the input buffer, and writes its addresses as
+/// [`ContiguousAllocation`] to the output buffer. The memory is owned by the
+/// handle and freed when it is closed. Requires [`CLASS_PHYSICAL_MEMORY`]. Not
+/// in the original driver.
+pub const IOCTL_ALLOC_CONTIGUOUS: u32 = (DEVICE_TYPE << 16) | 0x30a4;
+
+/// Frees the memory given with [`ContiguousFreeRequest`] as the input buffer,
+/// allocated with [`IOCTL_ALLOC_CONTIGUOUS`] through the same handle. Requires
+/// [`CLASS_PHYSICAL_MEMORY`]. Not in the original driver.
+pub const IOCTL_FREE_CONTIGUOUS: u32 = (DEVICE_TYPE << 16) | 0x30a8;
+
+/// Allocates non-paged memory of the size given with [`SharedMemoryRequest`]
+/// as the input buffer, maps it into the calling process, and writes both
+/// addresses as [`SharedMemoryInfo`] to the output buffer. A handle can own
+/// one shared memory, unmapped and freed when the handle is closed. Requires
+/// [`CLASS_KERNEL_MEMORY`]. Not in the original driver.
+pub const IOCTL_MAP_SHARED: u32 = (DEVICE_TYPE << 16) | 0x30ac;
+
+/// Formats the memory mapped with [`IOCTL_MAP_SHARED`] through the same handle
+/// as a ring buffer of [`EventRecord`], starting with [`EventRingHeader`], and
+/// makes the driver stream events to it. Writes [`EventRingInfo`] to the output
+/// buffer. One ring is active at a time, until the handle is closed. Not in the
+/// original driver.
+pub const IOCTL_ENABLE_EVENT_RING: u32 = (DEVICE_TYPE << 16) | 0x30b0;
+
+/// The transfer type of IOCTL codes whose input and output buffers are copied
+/// through the system buffer.
+pub const METHOD_BUFFERED: u32 = 0;
+
+/// The transfer type of IOCTL codes whose output buffer is described by an MDL
+/// and read by the driver. The input buffer is still copied.
+pub const METHOD_IN_DIRECT: u32 = 1;
+
+/// The transfer type of IOCTL codes whose output buffer is described by an MDL
+/// and written by the driver. The input buffer is still copied.
+pub const METHOD_OUT_DIRECT: u32 = 2;
+
+/// [`IOCTL_RUN_SHELLCODE`] taking the shellcode as the output buffer with
+/// direct I/O, so large shellcode is not copied into the system buffer. The
+/// input buffer is not used. Requires [`CLASS_EXECUTE`]. Not in the original
+/// driver.
+pub const IOCTL_RUN_SHELLCODE_DIRECT: u32 = (DEVICE_TYPE << 16) | 0x30b4 | METHOD_IN_DIRECT;
+
+/// [`IOCTL_READ_FILE`] reading into the output buffer with direct I/O, so large
+/// reads are not copied through the system buffer. Requires
+/// [`CLASS_ELEVATION`]. Not in the original driver.
+pub const IOCTL_READ_FILE_DIRECT: u32 = (DEVICE_TYPE << 16) | 0x30b8 | METHOD_OUT_DIRECT;
+
+/// [`IOCTL_WRITE_FILE`] taking the data as the output buffer with direct I/O
+/// instead of following [`FileRequest`], so large writes are not copied through
+/// the system buffer. [`FileRequest::data_length`] bytes of it are written.
+/// Requires [`CLASS_ELEVATION`]. Not in the original driver.
+pub const IOCTL_WRITE_FILE_DIRECT: u32 = (DEVICE_TYPE << 16) | 0x30bc | METHOD_IN_DIRECT;
+
+/// Copies the next chunk of the physical range given with
+/// [`PhysicalDumpRequest`] as the input buffer into the output buffer with
+/// direct I/O, as [`PhysicalDumpChunk`] followed by the data. Holes in the range
+/// that are not RAM, such as device memory, are skipped. Requires
+/// [`CLASS_PHYSICAL_MEMORY`]. Not in the original driver.
+pub const IOCTL_DUMP_PHYSICAL_RANGE: u32 = (DEVICE_TYPE << 16) | 0x30c0 | METHOD_OUT_DIRECT;
+
+/// Sets the offsets given with [`KernelOffsets`] as the input buffer for the
+/// running build of Windows, e.g., resolved from the PDB of ntoskrnl.exe. Fields
+/// that are zero keep the current offsets. Requires [`CLASS_KERNEL_MEMORY`]. Not
+/// in the original driver.
+pub const IOCTL_SET_OFFSETS: u32 = (DEVICE_TYPE << 16) | 0x30c4;
+
+/// Returns the [`KernelOffsets`] in use as the output buffer.
+/// [`KernelOffsets::build_number`] is zero if no offsets are known for the
+/// running build. Not in the original driver.
+pub const IOCTL_GET_OFFSETS: u32 = (DEVICE_TYPE << 16) | 0x30c8;
+
+/// Returns [`ModuleInfo`] of the kernel module whose file name follows
+/// [`ModuleRequest`] in the input buffer, or of ntoskrnl.exe if no name is
+/// given, as the output buffer. Unlike `NtQuerySystemInformation`, this works
+/// for clients below medium integrity. Requires [`CLASS_KERNEL_MEMORY`]. Not in
+/// the original driver.
+pub const IOCTL_GET_KERNEL_BASE: u32 = (DEVICE_TYPE << 16) | 0x30cc;
+
+/// Maps the driver image following [`MapDriverRequest`] in the input buffer
+/// into kernel memory, resolves its imports against loaded kernel modules,
+/// applies relocations and calls its entry point with no driver object and
+/// registry path. Returns [`MappedDriver`] as the output buffer. If the entry
+/// point fails, the image is freed and the request fails with its status. The
+/// driver keeps up to [`MAX_MAPPED_DRIVERS`] images mapped until they are
+/// unmapped with [`IOCTL_UNMAP_DRIVER`] or the driver is unloaded. Only
+/// available in builds with the `dangerous` feature; see
+/// [`CAPABILITY_MAP_DRIVER`]. Requires [`CLASS_EXECUTE`]. Not in the original
+/// driver.
+pub const IOCTL_MAP_DRIVER: u32 = (DEVICE_TYPE << 16) | 0x30d0;
+
+/// Lists the images mapped with [`IOCTL_MAP_DRIVER`] as an array of
+/// [`MappedDriver`] in the output buffer, as many as fit. Requires
+/// [`CLASS_EXECUTE`]. Not in the original driver.
+pub const IOCTL_ENUM_MAPPED_DRIVERS: u32 = (DEVICE_TYPE << 16) | 0x30d4;
+
+/// Frees the image mapped with [`IOCTL_MAP_DRIVER`] given with
+/// [`UnmapDriverRequest`] as the input buffer. Nothing of the driver, e.g.,
+/// callbacks or threads, may remain running. Requires [`CLASS_EXECUTE`]. Not in
+/// the original driver.
+pub const IOCTL_UNMAP_DRIVER: u32 = (DEVICE_TYPE << 16) | 0x30d8;
+
+/// Sets when the driver breaks into a kernel debugger to the `DEBUG_BREAK_*`
+/// mode given with [`DebugBreakRequest`] as the input buffer, until the driver
+/// restarts. Not in the original driver.
+pub const IOCTL_SET_DEBUG_BREAK: u32 = (DEVICE_TYPE << 16) | 0x30dc;
+
+/// Returns an array of [`AllocationInfo`] as the output buffer, one for each
+/// pool tag the driver uses, as many as fit. The driver has at most
+/// [`MAX_POOL_TAGS`] tags. Like [`IOCTL_GET_AUDIT`], this does not require
+/// negotiation. Not in the original driver.
+pub const IOCTL_QUERY_ALLOCATIONS: u32 = (DEVICE_TYPE << 16) | 0x30e0;
+
+/// Runs checks of the internal subsystems of the driver and returns
+/// [`SelfTestResult`] as the output buffer, so that tests can tell problems of
+/// the environment from bugs of the driver before testing anything else. Like
+/// [`IOCTL_GET_AUDIT`], this does not require negotiation. Not in the original
+/// driver.
+pub const IOCTL_SELF_TEST: u32 = (DEVICE_TYPE << 16) | 0x30e4;
+
+/// Returns the counters of the branches the dispatch and validation paths of
+/// the driver took as an array of `u32` in the output buffer, as many as fit
+/// up to [`COVERAGE_COUNTERS`], and resets them if [`CoverageRequest`] is
+/// given as the input buffer with `reset` nonzero. A fuzzer reads them after
+/// each input to tell whether the input reached new branches. Only available
+/// in builds with the `coverage` feature; see [`BUILD_FEATURE_COVERAGE`]. Not
+/// in the original driver.
+pub const IOCTL_GET_COVERAGE: u32 = (DEVICE_TYPE << 16) | 0x30e8;
+
+/// Copies the virtual memory at the address given with [`MemoryReadRequest`] as
+/// the input buffer into the output buffer with direct I/O. The memory is read
+/// with `MmCopyMemory`, so unmapped pages fail the copy instead of bug checking
+/// the target. Copying stops at the first page that cannot be read, and the
+/// number of bytes returned tells where it is. Requires
+/// [`CLASS_KERNEL_MEMORY`]. Not in the original driver.
+pub const IOCTL_READ_MEMORY: u32 = (DEVICE_TYPE << 16) | 0x30ec | METHOD_OUT_DIRECT;
+
+/// Searches the virtual or physical range given with [`ScanRequest`] as the
+/// input buffer for the pattern and mask that follow it, and returns
+/// [`ScanResult`] followed by the addresses of matches as `u64`, as many as fit
+/// into the output buffer. Each request scans part of the range, so that it
+/// does not run for long, and the client resumes with the cursor of the
+/// previous result. Pages that cannot be read, and holes in a physical range
+/// that are not RAM, are skipped. Requires [`CLASS_KERNEL_MEMORY`], or
+/// [`CLASS_PHYSICAL_MEMORY`] with [`SCAN_PHYSICAL`]. Not in the original driver.
+pub const IOCTL_SCAN_MEMORY: u32 = (DEVICE_TYPE << 16) | 0x30f0;
+
+/// Sends the `METHOD_BUFFERED` IOCTL given with [`CorrelationHeader`] at the
+/// start of the input buffer, with the rest of the input buffer as its input
+/// and the output buffer as its output. The messages and ETW events the
+/// driver writes while dispatching it carry the correlation ID of the header,
+/// so that they can be joined with the logs of the client. The IOCTL is
+/// checked and completed as if sent directly. Not in the original driver.
+pub const IOCTL_CORRELATED: u32 = (DEVICE_TYPE << 16) | 0x30f4;
+
+/// Sets the `messages::LEVEL_*` levels of the messages the driver prints to
+/// the kernel debugger to the mask given with [`PrintFilterRequest`] as the
+/// input buffer, until the driver restarts. Not in the original driver.
+pub const IOCTL_SET_PRINT_FILTER: u32 = (DEVICE_TYPE << 16) | 0x30f8;
+
+/// Writes the [`SavedConfig`] given as the input buffer as the `Config` value
+/// of the `Parameters` subkey of the service key, which the driver applies
+/// over the values of the service key each time it starts, e.g., after a
+/// reboot or reverting a snapshot without the client. The current settings are
+/// unchanged. A config without fields makes the driver use the values of the
+/// service key again. Not in the original driver.
+pub const IOCTL_SAVE_CONFIG: u32 = (DEVICE_TYPE << 16) | 0x30fc;
+
+/// IOCTL codes the driver implements on x86_64 and ARM64, and their names.
+pub const IOCTLS: &[(u32, &str)] = &[
+    (IOCTL_RUN_PAYLOAD, "IOCTL_RUN_PAYLOAD"),
+    (IOCTL_RUN_SHELLCODE, "IOCTL_RUN_SHELLCODE"),
+    (IOCTL_GET_VERSION, "IOCTL_GET_VERSION"),
+    (IOCTL_NEGOTIATE, "IOCTL_NEGOTIATE"),
+    (IOCTL_READ_LOG, "IOCTL_READ_LOG"),
+    (IOCTL_KILL_SWITCH, "IOCTL_KILL_SWITCH"),
+    (IOCTL_GET_AUDIT, "IOCTL_GET_AUDIT"),
+    (IOCTL_SELF_DESTRUCT, "IOCTL_SELF_DESTRUCT"),
+    (IOCTL_SNAPSHOT_CPU_STATE, "IOCTL_SNAPSHOT_CPU_STATE"),
+    (IOCTL_GET_PTE, "IOCTL_GET_PTE"),
+    (IOCTL_SET_PTE, "IOCTL_SET_PTE"),
+    (IOCTL_CAPTURE_THREAD, "IOCTL_CAPTURE_THREAD"),
+    (IOCTL_ENUM_DIRECTORY, "IOCTL_ENUM_DIRECTORY"),
+    (IOCTL_DUP_HANDLE, "IOCTL_DUP_HANDLE"),
+    (IOCTL_QUEUE_USER_APC, "IOCTL_QUEUE_USER_APC"),
+    (IOCTL_REG_QUERY, "IOCTL_REG_QUERY"),
+    (IOCTL_REG_SET, "IOCTL_REG_SET"),
+    (IOCTL_READ_FILE, "IOCTL_READ_FILE"),
+    (IOCTL_WRITE_FILE, "IOCTL_WRITE_FILE"),
+    (IOCTL_SET_NMI_CALLBACK, "IOCTL_SET_NMI_CALLBACK"),
+    (IOCTL_SAMPLE_NMI, "IOCTL_SAMPLE_NMI"),
+    (IOCTL_READ_APIC, "IOCTL_READ_APIC"),
+    (IOCTL_WRITE_APIC, "IOCTL_WRITE_APIC"),
+    (IOCTL_PCI_CONFIG_RW, "IOCTL_PCI_CONFIG_RW"),
+    (IOCTL_ALLOC_CONTIGUOUS, "IOCTL_ALLOC_CONTIGUOUS"),
+    (IOCTL_FREE_CONTIGUOUS, "IOCTL_FREE_CONTIGUOUS"),
+    (IOCTL_MAP_SHARED, "IOCTL_MAP_SHARED"),
+    (IOCTL_ENABLE_EVENT_RING, "IOCTL_ENABLE_EVENT_RING"),
+    (IOCTL_RUN_SHELLCODE_DIRECT, "IOCTL_RUN_SHELLCODE_DIRECT"),
+    (IOCTL_READ_FILE_DIRECT, "IOCTL_READ_FILE_DIRECT"),
+    (IOCTL_WRITE_FILE_DIRECT, "IOCTL_WRITE_FILE_DIRECT"),
+    (IOCTL_DUMP_PHYSICAL_RANGE, "IOCTL_DUMP_PHYSICAL_RANGE"),
+    (IOCTL_SET_OFFSETS, "IOCTL_SET_OFFSETS"),
+    (IOCTL_GET_OFFSETS, "IOCTL_GET_OFFSETS"),
+    (IOCTL_GET_KERNEL_BASE, "IOCTL_GET_KERNEL_BASE"),
+    (IOCTL_MAP_DRIVER, "IOCTL_MAP_DRIVER"),
+    (IOCTL_ENUM_MAPPED_DRIVERS, "IOCTL_ENUM_MAPPED_DRIVERS"),
+    (IOCTL_UNMAP_DRIVER, "IOCTL_UNMAP_DRIVER"),
+    (IOCTL_SET_DEBUG_BREAK, "IOCTL_SET_DEBUG_BREAK"),
+    (IOCTL_QUERY_ALLOCATIONS, "IOCTL_QUERY_ALLOCATIONS"),
+    (IOCTL_SELF_TEST, "IOCTL_SELF_TEST"),
+    (IOCTL_GET_COVERAGE, "IOCTL_GET_COVERAGE"),
+    (IOCTL_READ_MEMORY, "IOCTL_READ_MEMORY"),
+    (IOCTL_SCAN_MEMORY, "IOCTL_SCAN_MEMORY"),
+    (IOCTL_CORRELATED, "IOCTL_CORRELATED"),
+    (IOCTL_SET_PRINT_FILTER, "IOCTL_SET_PRINT_FILTER"),
+    (IOCTL_SAVE_CONFIG, "IOCTL_SAVE_CONFIG"),
+];
+
+/// A GUID, laid out as `GUID` of the Windows SDK.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Guid {
+    /// The first 8 hex digits.
+    pub data1: u32,
+    /// The next 4 hex digits.
+    pub data2: u16,
+    /// The next 4 hex digits.
+    pub data3: u16,
+    /// The last 16 hex digits, in the order they are written.
+    pub data4: [u8; 8],
+}
+
+/// The version of the interface defined in this crate. It is incremented when
+/// the interface changes incompatibly.
+pub const ABI_VERSION: u32 = 1;
+
+/// [`IOCTL_RUN_PAYLOAD`] is available. It is not on ARM64, with HVCI enabled,
+/// or with indirect branch tracking enabled.
+pub const CAPABILITY_RUN_PAYLOAD: u32 = 1 << 0;
+
+/// [`IOCTL_RUN_SHELLCODE`] is available. It is not with HVCI enabled.
+pub const CAPABILITY_RUN_SHELLCODE: u32 = 1 << 1;
+
+/// Control-flow enforcement technology (CET) is enabled in kernel-mode.
+/// Payloads must return normally as supervisor shadow stacks may be enabled.
+/// The driver makes shellcode a valid indirect branch target if needed.
+pub const CAPABILITY_CET: u32 = 1 << 2;
+
+/// [`IOCTL_MAP_DRIVER`] is available. It is not unless the driver is built with
+/// the `dangerous` feature, or with HVCI enabled.
+pub const CAPABILITY_MAP_DRIVER: u32 = 1 << 3;
+
+/// The class of IOCTLs executing code in kernel-mode.
+pub const CLASS_EXECUTE: u32 = 1 << 0;
+
+/// The class of IOCTLs reading and writing kernel virtual memory.
+pub const CLASS_KERNEL_MEMORY: u32 = 1 << 1;
+
+/// The class of IOCTLs accessing physical memory and devices.
+pub const CLASS_PHYSICAL_MEMORY: u32 = 1 << 2;
+
+/// The class of IOCTLs reading and writing model-specific registers.
+pub const CLASS_MSR: u32 = 1 << 3;
+
+/// The class of IOCTLs elevating privileges of processes.
+pub const CLASS_ELEVATION: u32 = 1 << 4;
+
+/// All IOCTL classes.
+pub const CLASS_ALL: u32 =
+    CLASS_EXECUTE | CLASS_KERNEL_MEMORY | CLASS_PHYSICAL_MEMORY | CLASS_MSR | CLASS_ELEVATION;
+
+/// Deletes the service key after unloading with [`IOCTL_SELF_DESTRUCT`]. The
+/// key must not have subkeys.
+pub const SELF_DESTRUCT_DELETE_SERVICE: u32 = 1 << 0;
+
+/// Schedules the driver file for deletion on the next reboot with
+/// [`IOCTL_SELF_DESTRUCT`], as the file cannot be deleted while mapped.
+pub const SELF_DESTRUCT_DELETE_FILE: u32 = 1 << 1;
+
+/// Never breaks into a kernel debugger.
+pub const DEBUG_BREAK_OFF: u32 = 0;
+
+/// Breaks into a kernel debugger when the driver is loaded and panics. This is
+/// the default.
+pub const DEBUG_BREAK_ON_LOAD: u32 = 1;
+
+/// Breaks into a kernel debugger when the driver is loaded and panics, and
+/// right before every payload runs.
+pub const DEBUG_BREAK_ON_EVERY_PAYLOAD: u32 = 2;
+
+/// Breaks into a kernel debugger only when the driver panics.
+pub const DEBUG_BREAK_ON_PANIC_ONLY: u32 = 3;
+
+/// The component ID the driver prints messages to the kernel debugger with,
+/// `DPFLTR_IHVDRIVER_ID`. The debug print filter of the component, e.g., the
+/// `IHVDRIVER` value of the `Debug Print Filter` key, applies on top of the
+/// mask of [`IOCTL_SET_PRINT_FILTER`].
+pub const PRINT_COMPONENT_ID: u32 = 77;
+
+/// The mask of [`IOCTL_SET_PRINT_FILTER`] printing messages of all levels,
+/// which is the default.
+pub const PRINT_LEVEL_MASK_ALL: u32 = 0xf;
+
+/// The version of [`SavedConfig`]. The driver ignores saved configs of other
+/// versions.
+pub const SAVED_CONFIG_VERSION: u32 = 1;
+
+/// [`SavedConfig::enabled_classes`] is set, over the `EnabledClasses` value.
+pub const CONFIG_ENABLED_CLASSES: u32 = 1 << 0;
+
+/// [`SavedConfig::payload_rate`] is set, over the `PayloadRate` value.
+pub const CONFIG_PAYLOAD_RATE: u32 = 1 << 1;
+
+/// [`SavedConfig::payload_burst`] is set, over the `PayloadBurst` value.
+pub const CONFIG_PAYLOAD_BURST: u32 = 1 << 2;
+
+/// [`SavedConfig::payload_stack_size`] is set, over the `PayloadStackSize`
+/// value.
+pub const CONFIG_PAYLOAD_STACK_SIZE: u32 = 1 << 3;
+
+/// [`SavedConfig::debug_break`] is set, over the `DebugBreak` value.
+pub const CONFIG_DEBUG_BREAK: u32 = 1 << 4;
+
+/// [`SavedConfig::print_level_mask`] is set, over the `PrintLevelMask` value.
+pub const CONFIG_PRINT_LEVEL_MASK: u32 = 1 << 5;
+
+/// [`SavedConfig::device_interface`] is set, over the `DeviceInterface` value.
+pub const CONFIG_DEVICE_INTERFACE: u32 = 1 << 6;
+
+/// [`SavedConfig::stealth_seed`] is set, over the `StealthSeed` value.
+pub const CONFIG_STEALTH_SEED: u32 = 1 << 7;
+
+/// All `CONFIG_*` flags.
+pub const CONFIG_ALL: u32 = (1 << 8) - 1;
+
+/// The present bit of a page table entry.
+pub const PTE_PRESENT: u64 = 1 << 0;
+
+/// The writable bit of a page table entry.
+pub const PTE_WRITE: u64 = 1 << 1;
+
+/// The user/supervisor bit of a page table entry. Set for user-mode pages.
+pub const PTE_USER: u64 = 1 << 2;
+
+/// The page size bit of a PDE or PDPTE, set for large pages.
+pub const PTE_LARGE_PAGE: u64 = 1 << 7;
+
+/// The no-execute bit of a page table entry.
+pub const PTE_NX: u64 = 1 << 63;
+
+/// The page frame number bits of a 4KB page table entry.
+pub const PTE_PFN: u64 = 0x000f_ffff_ffff_f000;
+
+/// The output of [`IOCTL_GET_VERSION`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// [`ABI_VERSION`] of the driver.
+    pub abi_version: u32,
+    /// `CAPABILITY_*` flags.
+    pub capabilities: u32,
+    /// `CLASS_*` flags that can be granted with [`IOCTL_NEGOTIATE`]. The
+    /// others are disabled through the `EnabledClasses` registry value of the
+    /// service.
+    pub enabled_classes: u32,
+}
+
+/// The offset of [`BuildInfo`] in the output of [`IOCTL_GET_VERSION`].
+pub const BUILD_INFO_OFFSET: usize =
+    size_of::<VersionInfo>().next_multiple_of(align_of::<BuildInfo>());
+
+/// The driver is built with the `defanged` feature.
+pub const BUILD_FEATURE_DEFANGED: u32 = 1 << 0;
+
+/// The driver is built with the `dangerous` feature.
+pub const BUILD_FEATURE_DANGEROUS: u32 = 1 << 1;
+
+/// The driver is built with the `coverage` feature, so
+/// [`IOCTL_GET_COVERAGE`] is available.
+pub const BUILD_FEATURE_COVERAGE: u32 = 1 << 2;
+
+/// The driver is built with the `paranoid` feature, so pointers it would
+/// dereference on behalf of a request are validated first, and invalid ones
+/// fail the request with `STATUS_ACCESS_VIOLATION` instead of bug checking.
+pub const BUILD_FEATURE_PARANOID: u32 = 1 << 3;
+
+/// The driver is built with the `strict-compat` feature, so
+/// [`IOCTL_RUN_PAYLOAD`] accepts only the requests the original driver does
+/// and calls the payload the same way, and the devices keep the original
+/// names.
+pub const BUILD_FEATURE_STRICT_COMPAT: u32 = 1 << 4;
+
+/// The driver is built with the `faithful` personality, so
+/// [`IOCTL_RUN_PAYLOAD`] calls the payload straight through the user-mode
+/// pointer like the original driver, without recording the registers around
+/// the call. [`BUILD_FEATURE_STRICT_COMPAT`] is also set.
+pub const BUILD_FEATURE_FAITHFUL: u32 = 1 << 5;
+
+/// The driver is built with the `hardened` personality, so
+/// [`IOCTL_RUN_PAYLOAD`] copies the pages of the payload into memory mapped
+/// read-only into system space, validates the copy and calls the payload
+/// there, never executing user-mode pages.
+pub const BUILD_FEATURE_HARDENED: u32 = 1 << 6;
+
+/// The driver is built with the `teaching` feature, so each step of handling
+/// [`IOCTL_RUN_PAYLOAD`] is logged as one of the `TEACHING_*` messages.
+pub const BUILD_FEATURE_TEACHING: u32 = 1 << 7;
+
+/// The driver is built with the `dynamic-imports` feature, so the routines only
+/// IOCTLs call are resolved when it loads instead of being imported.
+pub const BUILD_FEATURE_DYNAMIC_IMPORTS: u32 = 1 << 8;
+
+/// Metadata of the build of the driver, returned by [`IOCTL_GET_VERSION`] to
+/// tell exactly which build is loaded, e.g., in a crash dump of an old
+/// snapshot.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BuildInfo {
+    /// When the driver was built, in seconds since the Unix epoch, or
+    /// `SOURCE_DATE_EPOCH` if it was set.
+    pub timestamp: u64,
+    /// The hash of the git commit the driver was built from, or zeros if
+    /// unknown.
+    pub commit: [u8; 20],
+    /// Non-zero if the working tree had changes not committed.
+    pub dirty: u32,
+    /// The Cargo profile, e.g., `release`, padded with NULs.
+    pub profile: [u8; 16],
+    /// `BUILD_FEATURE_*` flags.
+    pub features: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The input of [`IOCTL_NEGOTIATE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NegotiateRequest {
+    /// [`ABI_VERSION`] the client is built with.
+    pub abi_version: u32,
+    /// `CLASS_*` flags the client uses.
+    pub classes: u32,
+}
+
+/// The output of [`IOCTL_NEGOTIATE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NegotiateResponse {
+    /// `CLASS_*` flags granted to the handle. Classes requested but disabled
+    /// are not granted.
+    pub granted_classes: u32,
+}
+
+/// A record of a device-control request or of an NMI sample, returned by
+/// [`IOCTL_READ_LOG`]. A record of an NMI sample has [`IOCTL_SAMPLE_NMI`] as
+/// `control_code`, non-zero `rip`, and the process and thread the NMI
+/// interrupted.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LogRecord {
+    /// The number of records made before this one since the driver started,
+    /// continuing from the records saved when Windows last shut down. A gap
+    /// means that the ring buffer overflowed and records were lost.
+    pub sequence: u64,
+    /// The ID of the process that sent the request.
+    pub process_id: u64,
+    /// The ID of the thread that sent the request.
+    pub thread_id: u64,
+    /// The IOCTL code of the request.
+    pub control_code: u32,
+    /// Non-zero if the process runs with an elevated token.
+    pub elevated: u32,
+    /// The null-terminated image file name of the process, truncated to 15
+    /// characters by the kernel.
+    pub image_name: [u8; 16],
+    /// The interrupted instruction pointer for a record of an NMI sample, or
+    /// zero.
+    pub rip: u64,
+}
+
+/// The output of [`IOCTL_GET_AUDIT`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AuditInfo {
+    /// The number of payloads executed since the driver started. The driver
+    /// writes an ETW event and a message with the number for every execution,
+    /// so a gap in the numbers of the events indicates lost events.
+    pub executions: u64,
+    /// The number of payloads refused due to the rate limit.
+    pub throttled: u64,
+    /// Non-zero if [`IOCTL_KILL_SWITCH`] was sent.
+    pub killed: u32,
+    /// Reserved.
+    pub reserved: u32,
+    /// The audit chain after the last execution, or zero if none. See
+    /// [`chain_execution`].
+    pub chain: u64,
+}
+
+/// Returns the audit chain after the execution numbered `execution`, which
+/// `process_id` requested, given `previous`, the chain after the previous
+/// execution or zero for the first, with 64-bit FNV-1a.
+///
+/// The driver writes the chain in the event of every execution along with the
+/// number and the process ID. Recomputing the chain from the events and
+/// comparing it with [`AuditInfo::chain`] detects events that were lost,
+/// inserted or edited.
+#[must_use]
+pub const fn chain_execution(previous: u64, execution: u64, process_id: u64) -> u64 {
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let values = [previous, execution, process_id];
+    let mut i = 0;
+    while i < values.len() {
+        let bytes = values[i].to_le_bytes();
+        let mut j = 0;
+        while j < bytes.len() {
+            hash = (hash ^ bytes[j] as u64).wrapping_mul(PRIME);
+            j += 1;
+        }
+        i += 1;
+    }
+    hash
+}
+
+/// The maximum number of pool tags [`IOCTL_QUERY_ALLOCATIONS`] returns.
+pub const MAX_POOL_TAGS: usize = 16;
+
+/// An element of the output of [`IOCTL_QUERY_ALLOCATIONS`], counting the pool
+/// allocations of a subsystem of the driver since it started.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocationInfo {
+    /// The pool tag, e.g., `CpcC` in little-endian, as shown by poolmon.
+    pub tag: u32,
+    /// Reserved.
+    pub reserved: u32,
+    /// The number of allocations not freed yet. It should return to zero when
+    /// the subsystem is idle, or memory is leaking.
+    pub outstanding: u64,
+    /// The number of allocations made.
+    pub total: u64,
+}
+
+/// [`IOCTL_SELF_TEST`] check allocating, writing and freeing non-paged pool.
+pub const SELF_TEST_ALLOCATOR: u32 = 1 << 0;
+
+/// [`IOCTL_SELF_TEST`] check that the ring buffer of [`IOCTL_READ_LOG`]
+/// returns records in order and overwrites the oldest when full.
+pub const SELF_TEST_LOG_RING: u32 = 1 << 1;
+
+/// [`IOCTL_SELF_TEST`] check writing back the register that payload execution
+/// changes, CR4 or PSTATE.PAN on ARM64, with its current value. SMEP and PAN
+/// stay enabled. The defanged build does not run it.
+pub const SELF_TEST_PROTECTION: u32 = 1 << 2;
+
+/// [`IOCTL_SELF_TEST`] check that the built-in offsets are sorted by the build
+/// number, and that the offsets in use, if any, are for the running build.
+pub const SELF_TEST_OFFSETS: u32 = 1 << 3;
+
+/// The output of [`IOCTL_SELF_TEST`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SelfTestResult {
+    /// `SELF_TEST_*` flags of the checks run.
+    pub checks: u32,
+    /// `SELF_TEST_*` flags of the checks that failed.
+    pub failures: u32,
+}
+
+/// The number of counters [`IOCTL_GET_COVERAGE`] returns. Each branch has a
+/// counter indexed by the hash of where it is in the source, so branches may
+/// share a counter.
+pub const COVERAGE_COUNTERS: usize = 1024;
+
+/// The input of [`IOCTL_GET_COVERAGE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CoverageRequest {
+    /// Non-zero to reset the counters after reading them.
+    pub reset: u32,
+}
+
+/// The input of [`IOCTL_SELF_DESTRUCT`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SelfDestructRequest {
+    /// `SELF_DESTRUCT_*` flags.
+    pub flags: u32,
+}
+
+/// The input of [`IOCTL_SNAPSHOT_CPU_STATE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuStateRequest {
+    /// The system-wide index of the processor to take the snapshot on.
+    pub processor: u32,
+    /// The first vector of [`CpuState::idt_entries`].
+    pub first_vector: u32,
+}
+
+/// The number of IDT entries [`IOCTL_SNAPSHOT_CPU_STATE`] returns at most.
+pub const CPU_STATE_IDT_ENTRIES: usize = 16;
+
+/// The output of [`IOCTL_SNAPSHOT_CPU_STATE`]. Addresses are of the native
+/// width of the driver.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpuState {
+    /// The base address of the IDT.
+    pub idtr_base: u64,
+    /// The base address of the GDT.
+    pub gdtr_base: u64,
+    /// The address of the KPCR of the processor.
+    pub kpcr: u64,
+    /// The base address of the current TSS, taken from the GDT.
+    pub tss_base: u64,
+    /// The limit of the IDT.
+    pub idtr_limit: u16,
+    /// The limit of the GDT.
+    pub gdtr_limit: u16,
+    /// The selector of the current TSS.
+    pub tr: u16,
+    /// Reserved.
+    pub reserved: u16,
+    /// The number of valid entries in [`CpuState::idt_entries`]. It is less
+    /// than [`CPU_STATE_IDT_ENTRIES`] near the end of the IDT.
+    pub idt_entry_count: u32,
+    /// Reserved.
+    pub reserved2: u32,
+    /// IDT entries from [`CpuStateRequest::first_vector`].
+    pub idt_entries: [IdtEntry; CPU_STATE_IDT_ENTRIES],
+}
+
+/// A decoded IDT entry.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IdtEntry {
+    /// The address of the handler.
+    pub handler: u64,
+    /// The code segment selector of the handler.
+    pub selector: u16,
+    /// The interrupt stack table index. Always zero on x86.
+    pub ist: u8,
+    /// The type, DPL and present bits.
+    pub attributes: u8,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The input of [`IOCTL_GET_PTE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PteRequest {
+    /// The process whose address space is walked, or zero for the current
+    /// process.
+    pub process_id: u64,
+    /// The virtual address to translate.
+    pub address: u64,
+}
+
+/// The input of [`IOCTL_SET_PTE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SetPteRequest {
+    /// The process whose address space is walked, or zero for the current
+    /// process.
+    pub process_id: u64,
+    /// The virtual address whose page table entry is modified.
+    pub address: u64,
+    /// `PTE_*` flags to modify.
+    pub mask: u64,
+    /// The new values of the bits in [`SetPteRequest::mask`].
+    pub value: u64,
+}
+
+/// The output of [`IOCTL_GET_PTE`] and [`IOCTL_SET_PTE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PteInfo {
+    /// The entry translating the address. It is the first entry without
+    /// [`PTE_PRESENT`] if the address is not mapped.
+    pub entry: u64,
+    /// The kernel virtual address of the entry.
+    pub entry_address: u64,
+    /// The physical address the address translates to, or zero if not mapped.
+    pub physical_address: u64,
+    /// The level of the entry: 1 for a PTE, 2 for a PDE, 3 for a PDPTE and 4
+    /// for a PML4E.
+    pub level: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The input of [`IOCTL_CAPTURE_THREAD`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadCaptureRequest {
+    /// The ID of the thread to capture.
+    pub thread_id: u64,
+    /// How long to wait for the thread to capture its backtrace, in
+    /// milliseconds. Zero means the default of one second.
+    pub timeout_ms: u32,
+    /// `THREAD_CAPTURE_*` flags.
+    pub flags: u32,
+}
+
+/// [`ThreadCaptureRequest::flags`] to unwind the saved kernel stack without
+/// waiting for the thread to run an APC. The frames are accurate only if the
+/// thread is not running.
+pub const THREAD_CAPTURE_SAVED_STACK: u32 = 1 << 0;
+
+/// The number of frames [`IOCTL_CAPTURE_THREAD`] returns at most.
+pub const THREAD_CAPTURE_FRAMES: usize = 32;
+
+/// The output of [`IOCTL_CAPTURE_THREAD`]. Addresses are of the native width
+/// of the driver.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThreadCapture {
+    /// The ID of the process the thread belongs to.
+    pub process_id: u64,
+    /// The stack pointer of the thread while capturing, or the one saved when
+    /// it was switched out if unwound, which is within its kernel stack.
+    pub stack_pointer: u64,
+    /// The number of valid entries in [`ThreadCapture::frames`].
+    pub frame_count: u32,
+    /// Non-zero if the backtrace was unwound from the saved kernel stack
+    /// instead of captured by the thread.
+    pub unwound: u32,
+    /// Return addresses on the kernel stack, innermost first. The first ones
+    /// are of the APC delivery in the kernel, or of the context switch if
+    /// unwound.
+    pub frames: [u64; THREAD_CAPTURE_FRAMES],
+}
+
+/// The input of [`IOCTL_ENUM_DIRECTORY`], followed by the path of the
+/// directory in UTF-16 without a terminator, e.g., `\Device`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EnumDirectoryRequest {
+    /// The index of the first object to return. Pass the number of objects
+    /// already returned to continue.
+    pub index: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The number of UTF-16 code units of [`DirectoryEntry::name`].
+pub const DIRECTORY_NAME_LENGTH: usize = 64;
+
+/// The number of UTF-16 code units of [`DirectoryEntry::type_name`].
+pub const DIRECTORY_TYPE_NAME_LENGTH: usize = 32;
+
+/// An object returned by [`IOCTL_ENUM_DIRECTORY`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirectoryEntry {
+    /// The length of the name in UTF-16 code units. The name is truncated if
+    /// it is not less than [`DIRECTORY_NAME_LENGTH`].
+    pub name_length: u32,
+    /// Reserved.
+    pub reserved: u32,
+    /// The null-terminated name of the object, e.g., `Htsysm72FB`.
+    pub name: [u16; DIRECTORY_NAME_LENGTH],
+    /// The null-terminated name of the type of the object, e.g., `Device`.
+    pub type_name: [u16; DIRECTORY_TYPE_NAME_LENGTH],
+}
+
+impl Default for DirectoryEntry {
+    fn default() -> Self {
+        Self {
+            name_length: 0,
+            reserved: 0,
+            name: [0; DIRECTORY_NAME_LENGTH],
+            type_name: [0; DIRECTORY_TYPE_NAME_LENGTH],
+        }
+    }
+}
+
+/// The input of [`IOCTL_DUP_HANDLE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DupHandleRequest {
+    /// The process owning the handle.
+    pub process_id: u64,
+    /// The handle to duplicate, valid in the process.
+    pub handle: u64,
+    /// The access of the new handle. Ignored with `DUPLICATE_SAME_ACCESS`.
+    pub desired_access: u32,
+    /// `DUPLICATE_*` options of `DuplicateHandle`.
+    pub options: u32,
+}
+
+/// The output of [`IOCTL_DUP_HANDLE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DupHandleResponse {
+    /// The new handle, valid in the caller. The caller must close it.
+    pub handle: u64,
+}
+
+/// The input of [`IOCTL_QUEUE_USER_APC`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UserApcRequest {
+    /// The thread to queue the APC to. It must not be a system thread.
+    pub thread_id: u64,
+    /// The address of the routine in the process of the thread. It is called
+    /// with the three arguments, like `PKNORMAL_ROUTINE`.
+    pub routine: u64,
+    /// The first argument of the routine.
+    pub argument1: u64,
+    /// The second argument of the routine.
+    pub argument2: u64,
+    /// The third argument of the routine.
+    pub argument3: u64,
+}
+
+/// The input of [`IOCTL_REG_QUERY`] and [`IOCTL_REG_SET`], followed by the key
+/// path and the value name in UTF-16 without terminators, and then the data for
+/// [`IOCTL_REG_SET`]. The key path is a native path, e.g.,
+/// `\Registry\Machine\SYSTEM\CurrentControlSet\Services\capcom`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegistryRequest {
+    /// The length of the key path in bytes.
+    pub key_length: u32,
+    /// The length of the value name in bytes. Zero for the default value.
+    pub name_length: u32,
+    /// The type of the value to write, e.g., `REG_DWORD`. Ignored by
+    /// [`IOCTL_REG_QUERY`].
+    pub value_type: u32,
+    /// The length of the data to write in bytes. Ignored by
+    /// [`IOCTL_REG_QUERY`].
+    pub data_length: u32,
+}
+
+/// The output of [`IOCTL_REG_QUERY`], followed by the data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegistryValue {
+    /// The type of the value, e.g., `REG_SZ`.
+    pub value_type: u32,
+    /// The length of the data in bytes.
+    pub data_length: u32,
+}
+
+/// The input of [`IOCTL_READ_FILE`] and [`IOCTL_WRITE_FILE`], followed by the
+/// path of the file in UTF-16 without a terminator, and then the data for
+/// [`IOCTL_WRITE_FILE`]. The path is a native path, e.g.,
+/// `\??\C:\Windows\System32\config\SAM`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FileRequest {
+    /// The offset in the file to read or write at.
+    pub offset: u64,
+    /// The length of the path in bytes.
+    pub path_length: u32,
+    /// The length of the data to write in bytes. Ignored by
+    /// [`IOCTL_READ_FILE`].
+    pub data_length: u32,
+}
+
+/// The input of [`IOCTL_SET_NMI_CALLBACK`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NmiCallbackRequest {
+    /// Non-zero to register the callback, zero to unregister it.
+    pub enable: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The input of [`IOCTL_SAMPLE_NMI`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NmiSampleRequest {
+    /// The system-wide index of the processor to send the NMI to.
+    pub processor: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The output of [`IOCTL_SAMPLE_NMI`], the context interrupted by the NMI.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NmiSample {
+    /// The interrupted instruction pointer.
+    pub rip: u64,
+    /// The interrupted flags.
+    pub rflags: u64,
+    /// The interrupted stack pointer.
+    pub rsp: u64,
+    /// The ID of the process current on the processor.
+    pub process_id: u64,
+    /// The ID of the thread current on the processor.
+    pub thread_id: u64,
+    /// The interrupted code segment selector.
+    pub cs: u16,
+    /// The interrupted stack segment selector.
+    pub ss: u16,
+    /// The system-wide index of the processor.
+    pub processor: u32,
+}
+
+/// The input of [`IOCTL_READ_APIC`] and [`IOCTL_WRITE_APIC`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ApicRequest {
+    /// The system-wide index of the processor whose local APIC to access.
+    pub processor: u32,
+    /// The offset of the register in the xAPIC MMIO layout, e.g., `0x320` for
+    /// the LVT timer entry. In x2APIC mode, it is translated to the MSR
+    /// `0x800 + offset / 16`. Registers that do not exist in the current mode
+    /// are refused.
+    pub offset: u32,
+    /// The value to write for [`IOCTL_WRITE_APIC`]. Only the ICR at `0x300`
+    /// takes 64 bits in x2APIC mode.
+    pub value: u64,
+}
+
+/// The input of [`IOCTL_PCI_CONFIG_RW`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PciConfigRequest {
+    /// The PCI segment group. Only segment 0 is accessible without the MCFG
+    /// table.
+    pub segment: u16,
+    /// The bus number.
+    pub bus: u8,
+    /// The device number, up to 31.
+    pub device: u8,
+    /// The function number, up to 7.
+    pub function: u8,
+    /// The size of the access in bytes, 1, 2 or 4.
+    pub size: u8,
+    /// Non-zero to write [`PciConfigRequest::value`], zero to read.
+    pub write: u8,
+    /// Reserved.
+    pub reserved: u8,
+    /// The offset of the register, aligned to the size. Offsets from 0x100
+    /// require the MCFG table.
+    pub offset: u32,
+    /// The value to write.
+    pub value: u32,
+}
+
+/// The input of [`IOCTL_ALLOC_CONTIGUOUS`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContiguousAllocRequest {
+    /// The size to allocate in bytes.
+    pub size: u64,
+    /// The highest physical address the memory may end at, or zero for any.
+    pub highest_address: u64,
+    /// How the memory is cached: 0 for non-cached, 1 for cached and 2 for
+    /// write-combined.
+    pub cache_type: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The output of [`IOCTL_ALLOC_CONTIGUOUS`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContiguousAllocation {
+    /// The kernel-mode virtual address of the memory.
+    pub address: u64,
+    /// The physical address of the memory.
+    pub physical_address: u64,
+}
+
+/// The input of [`IOCTL_FREE_CONTIGUOUS`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ContiguousFreeRequest {
+    /// The kernel-mode virtual address returned by [`IOCTL_ALLOC_CONTIGUOUS`].
+    pub address: u64,
+}
+
+/// The input of [`IOCTL_MAP_SHARED`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SharedMemoryRequest {
+    /// The size in bytes, up to 16MB. It is rounded up to the page size.
+    pub size: u64,
+}
+
+/// The output of [`IOCTL_MAP_SHARED`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SharedMemoryInfo {
+    /// The address of the memory in the calling process.
+    pub user_address: u64,
+    /// The address of the memory in the system space.
+    pub kernel_address: u64,
+    /// The size in bytes.
+    pub size: u64,
+}
+
+/// The output of [`IOCTL_ENABLE_EVENT_RING`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventRingInfo {
+    /// A handle to an auto-reset event, signaled for every record. The caller
+    /// must close it.
+    pub event: u64,
+    /// The number of records the ring holds.
+    pub capacity: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The header of the ring buffer of [`IOCTL_ENABLE_EVENT_RING`] at the start
+/// of the shared memory, followed by the records. The record at index `i` is
+/// at `i % capacity`. `head` and `tail` must be accessed atomically.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventRingHeader {
+    /// The number of records written by the driver. Only the driver writes
+    /// this.
+    pub head: u64,
+    /// The number of records read by the client. Only the client writes this,
+    /// after reading records.
+    pub tail: u64,
+    /// The number of records dropped because the ring was full.
+    pub dropped: u64,
+    /// The number of records the ring holds.
+    pub capacity: u32,
+    /// The size of [`EventRecord`] in bytes.
+    pub record_size: u32,
+}
+
+/// [`EventRecord::kind`] of a record of a device-control request.
+pub const EVENT_KIND_IOCTL: u32 = 1;
+
+/// [`EventRecord::kind`] of a message the driver logged.
+pub const EVENT_KIND_MESSAGE: u32 = 2;
+
+/// A record in the ring buffer of [`IOCTL_ENABLE_EVENT_RING`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventRecord {
+    /// The kind of the event, e.g., [`EVENT_KIND_IOCTL`].
+    pub kind: u32,
+    /// Reserved.
+    pub reserved: u32,
+    /// The record of the request for [`EVENT_KIND_IOCTL`], the same as
+    /// returned by [`IOCTL_READ_LOG`].
+    pub log: LogRecord,
+    /// The message for [`EVENT_KIND_MESSAGE`].
+    pub message: MessageRecord,
+}
+
+/// The maximum number of arguments of a message.
+pub const MAX_MESSAGE_ARGUMENTS: usize = 6;
+
+/// A message the driver logged, as the ID and arguments instead of the text.
+/// See [`messages`] for the formats and how the arguments are encoded.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MessageRecord {
+    /// The ID of the message in [`messages::MESSAGES`].
+    pub id: u32,
+    /// The number of valid entries in `arguments`.
+    pub argument_count: u32,
+    /// The arguments.
+    pub arguments: [u64; MAX_MESSAGE_ARGUMENTS],
+}
+
+/// The input of [`IOCTL_DUMP_PHYSICAL_RANGE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhysicalDumpRequest {
+    /// The physical address of the range.
+    pub address: u64,
+    /// The size of the range in bytes.
+    pub size: u64,
+    /// The offset in the range to resume from. Zero at first, then
+    /// [`PhysicalDumpChunk::cursor`] of the previous chunk.
+    pub cursor: u64,
+}
+
+/// The output of [`IOCTL_DUMP_PHYSICAL_RANGE`], followed by the data.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PhysicalDumpChunk {
+    /// The physical address of the data.
+    pub address: u64,
+    /// The size of the data in bytes. Zero if no RAM is left in the range.
+    pub size: u64,
+    /// The offset in the range to resume from. It equals
+    /// [`PhysicalDumpRequest::size`] once the whole range is dumped.
+    pub cursor: u64,
+}
+
+/// The input of [`IOCTL_READ_MEMORY`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryReadRequest {
+    /// The virtual address to read. The length is that of the output buffer.
+    pub address: u64,
+}
+
+/// The maximum length of the pattern of [`IOCTL_SCAN_MEMORY`] in bytes.
+pub const MAX_SCAN_PATTERN_LENGTH: usize = 64;
+
+/// [`ScanRequest::flags`] to scan a physical range instead of a virtual one.
+pub const SCAN_PHYSICAL: u32 = 1 << 0;
+
+/// The input of [`IOCTL_SCAN_MEMORY`], followed by the pattern and then its
+/// mask, both `pattern_length` bytes. A byte of memory matches when it equals
+/// the byte of the pattern in the bits set in the mask, so a mask of 0xff
+/// matches the byte exactly and 0 matches any byte.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanRequest {
+    /// The address of the range.
+    pub address: u64,
+    /// The size of the range in bytes.
+    pub size: u64,
+    /// The offset in the range to resume from. Zero at first, then
+    /// [`ScanResult::cursor`] of the previous result.
+    pub cursor: u64,
+    /// `SCAN_*` flags.
+    pub flags: u32,
+    /// The length of the pattern in bytes, up to [`MAX_SCAN_PATTERN_LENGTH`].
+    pub pattern_length: u32,
+}
+
+/// The output of [`IOCTL_SCAN_MEMORY`], followed by the addresses of matches.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScanResult {
+    /// The offset in the range to resume from. It equals [`ScanRequest::size`]
+    /// once the whole range is scanned.
+    pub cursor: u64,
+    /// The number of addresses that follow.
+    pub count: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The header of the input of [`IOCTL_CORRELATED`], followed by the input of
+/// the IOCTL it sends.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CorrelationHeader {
+    /// The ID the client generated for the IOCTL. Zero is not logged.
+    pub correlation_id: u64,
+    /// The IOCTL code to send. It must be of `METHOD_BUFFERED` and not
+    /// [`IOCTL_CORRELATED`].
+    pub control_code: u32,
+    /// Reserved. Must be zero.
+    pub reserved: u32,
+}
+
+/// Offsets of fields of kernel structures and RVAs of kernel globals, which
+/// change between Windows builds. Zero means unknown.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KernelOffsets {
+    /// The build number of Windows they are for.
+    pub build_number: u32,
+    /// `EPROCESS::UniqueProcessId`.
+    pub eprocess_unique_process_id: u32,
+    /// `EPROCESS::ActiveProcessLinks`.
+    pub eprocess_active_process_links: u32,
+    /// `EPROCESS::Token`.
+    pub eprocess_token: u32,
+    /// `EPROCESS::SignatureLevel`.
+    pub eprocess_signature_level: u32,
+    /// `EPROCESS::Protection`.
+    pub eprocess_protection: u32,
+    /// `KTHREAD::PreviousMode`, at the start of `ETHREAD`.
+    pub kthread_previous_mode: u32,
+    /// `KTHREAD::KernelStack`, the stack pointer saved when the thread is
+    /// switched out.
+    pub kthread_kernel_stack: u32,
+    /// `TOKEN::Privileges`.
+    pub token_privileges: u32,
+    /// The RVA of `PspCreateProcessNotifyRoutine` in ntoskrnl.exe.
+    pub psp_create_process_notify_routine: u32,
+    /// The RVA of `PspCreateThreadNotifyRoutine` in ntoskrnl.exe.
+    pub psp_create_thread_notify_routine: u32,
+    /// The RVA of `PspLoadImageNotifyRoutine` in ntoskrnl.exe.
+    pub psp_load_image_notify_routine: u32,
+    /// The RVA of `g_CiOptions` in CI.dll.
+    pub ci_options: u32,
+}
+
+impl KernelOffsets {
+    /// Offsets that are all unknown.
+    pub const EMPTY: Self = Self {
+        build_number: 0,
+        eprocess_unique_process_id: 0,
+        eprocess_active_process_links: 0,
+        eprocess_token: 0,
+        eprocess_signature_level: 0,
+        eprocess_protection: 0,
+        kthread_previous_mode: 0,
+        kthread_kernel_stack: 0,
+        token_privileges: 0,
+        psp_create_process_notify_routine: 0,
+        psp_create_thread_notify_routine: 0,
+        psp_load_image_notify_routine: 0,
+        ci_options: 0,
+    };
+}
+
+/// The input of [`IOCTL_GET_KERNEL_BASE`], followed by the file name of the
+/// module in ASCII, e.g., `CI.dll`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModuleRequest {
+    /// The length of the name in bytes, or zero for ntoskrnl.exe.
+    pub name_length: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The output of [`IOCTL_GET_KERNEL_BASE`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModuleInfo {
+    /// The address the module is loaded at.
+    pub base: u64,
+    /// The size of the module in bytes.
+    pub size: u32,
+    /// Reserved.
+    pub reserved: u32,
+}
+
+/// The maximum number of images mapped with [`IOCTL_MAP_DRIVER`] at a time.
+pub const MAX_MAPPED_DRIVERS: usize = 16;
+
+/// The input of [`IOCTL_MAP_DRIVER`], followed by the image.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MapDriverRequest {
+    /// The name to identify the image with, in ASCII and padded with zeros.
+    pub name: [u8; 32],
+}
+
+/// The output of [`IOCTL_MAP_DRIVER`], and an entry of the output of
+/// [`IOCTL_ENUM_MAPPED_DRIVERS`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MappedDriver {
+    /// The address the image is mapped at.
+    pub base: u64,
+    /// The address of the entry point.
+    pub entry: u64,
+    /// The size of the image in bytes.
+    pub size: u32,
+    /// The status the entry point returned.
+    pub status: i32,
+    /// [`MapDriverRequest::name`].
+    pub name: [u8; 32],
+}
+
+/// The input of [`IOCTL_UNMAP_DRIVER`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct UnmapDriverRequest {
+    /// [`MappedDriver::base`] of the image.
+    pub base: u64,
+}
+
+/// The registers of the processor at a point around the call of a payload.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RegisterState {
+    /// The general-purpose registers in the order of their encodings. They are
+    /// RAX to R15 in the first 16 entries on x86_64, EAX to EDI in the first 8
+    /// entries on x86, and X0 to X30 followed by SP on ARM64.
+    pub gprs: [u64; 32],
+    /// RFLAGS, or NZCV, DAIF and PAN of PSTATE on ARM64.
+    pub flags: u64,
+    /// CR0, or SCTLR_EL1 on ARM64.
+    pub cr0: u64,
+    /// CR4, or TCR_EL1 on ARM64.
+    pub cr4: u64,
+    /// The IRQL.
+    pub irql: u8,
+    /// Reserved.
+    pub reserved: [u8; 7],
+}
+
+/// The optional output of [`IOCTL_RUN_PAYLOAD`] and [`IOCTL_RUN_SHELLCODE`].
+/// The driver also logs the registers the payload should have preserved but
+/// changed.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PayloadTranscript {
+    /// The registers right before the call, with CR4.SMEP (PSTATE.PAN on
+    /// ARM64) and interrupts already disabled.
+    pub before: RegisterState,
+    /// The registers right after the payload returned.
+    pub after: RegisterState,
+}
+
+/// The input of [`IOCTL_SET_DEBUG_BREAK`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DebugBreakRequest {
+    /// A `DEBUG_BREAK_*` mode.
+    pub mode: u32,
+}
+
+/// The input of [`IOCTL_SET_PRINT_FILTER`].
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PrintFilterRequest {
+    /// The levels to print, with bit `n` for `messages::LEVEL_*` of `n`.
+    /// Bits outside [`PRINT_LEVEL_MASK_ALL`] are ignored.
+    pub level_mask: u32,
+}
+
+/// The input of [`IOCTL_SAVE_CONFIG`], and the data of the `Config` value.
+/// Each field has the meaning of the value of the service key of the same
+/// name, and is used only if its `CONFIG_*` flag is in `fields`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SavedConfig {
+    /// [`SAVED_CONFIG_VERSION`].
+    pub version: u32,
+    /// `CONFIG_*` flags of the fields that are set.
+    pub fields: u32,
+    /// `CLASS_*` flags that can be granted to handles.
+    pub enabled_classes: u32,
+    /// The number of payloads a handle can execute per second, or zero for no
+    /// limit.
+    pub payload_rate: u32,
+    /// The number of payloads a handle can execute in a burst, or zero for as
+    /// many as [`SavedConfig::payload_rate`].
+    pub payload_burst: u32,
+    /// The size in bytes of the stack payloads run on, or zero to run them on
+    /// the stack of the calling thread.
+    pub payload_stack_size: u32,
+    /// A `DEBUG_BREAK_*` mode.
+    pub debug_break: u32,
+    /// The mask of [`IOCTL_SET_PRINT_FILTER`].
+    pub print_level_mask: u32,
+    /// Nonzero to register the device interface of the compatible device.
+    pub device_interface: u32,
+    /// The seed of [`stealth_name`], or zero for the names of the original
+    /// driver.
+    pub stealth_seed: u32,
+}
+
+// Tools written for the original driver hard-code these.
+const _: () = {
+    assert!(DEVICE_TYPE == 0xaa01);
+    assert!(IOCTL_RUN_PAYLOAD == 0xaa01_3044);
+    assert!(IOCTL_RUN_PAYLOAD32 == 0xaa01_2044);
+    let (name, expected) = (DEVICE_NAME.as_bytes(), br"\Device\Htsysm72FB");
+    assert!(name.len() == expected.len() && DEVICE_NAME_UTF16.len() == expected.len());
+    let mut i = 0;
+    while i < expected.len() {
+        assert!(name[i] == expected[i] && DEVICE_NAME_UTF16[i] == expected[i] as u16);
+        i += 1;
+    }
+};
+
+// Structures with addresses and handles must have the same sizes when built
+// for 32-bit programs.
+const _: () = {
+    assert!(size_of::<CpuState>() == 304);
+    assert!(size_of::<IdtEntry>() == 16);
+    assert!(size_of::<PteRequest>() == 16);
+    assert!(size_of::<SetPteRequest>() == 32);
+    assert!(size_of::<PteInfo>() == 32);
+    assert!(size_of::<ThreadCaptureRequest>() == 16);
+    assert!(size_of::<ThreadCapture>() == 280);
+    assert!(size_of::<DupHandleRequest>() == 24);
+    assert!(size_of::<DupHandleResponse>() == 8);
+    assert!(size_of::<UserApcRequest>() == 40);
+    assert!(size_of::<NmiSample>() == 48);
+    assert!(size_of::<ContiguousAllocRequest>() == 24);
+    assert!(size_of::<ContiguousAllocation>() == 16);
+    assert!(size_of::<ContiguousFreeRequest>() == 8);
+    assert!(size_of::<SharedMemoryInfo>() == 24);
+    assert!(size_of::<EventRingInfo>() == 16);
+    assert!(size_of::<EventRingHeader>() == 32);
+    assert!(size_of::<PhysicalDumpRequest>() == 24);
+    assert!(size_of::<PhysicalDumpChunk>() == 24);
+    assert!(size_of::<MemoryReadRequest>() == 8);
+    assert!(size_of::<ScanRequest>() == 32);
+    assert!(size_of::<ScanResult>() == 16);
+    assert!(size_of::<CorrelationHeader>() == 16);
+    assert!(size_of::<PrintFilterRequest>() == 4);
+    assert!(size_of::<SavedConfig>() == 40);
+    assert!(size_of::<ModuleInfo>() == 16);
+    assert!(size_of::<MappedDriver>() == 56);
+    assert!(size_of::<UnmapDriverRequest>() == 8);
+};
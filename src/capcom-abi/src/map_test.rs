@@ -0,0 +1,37 @@
+//! Definitions shared with capcom-map-test, a tiny driver that is not signed
+//! and is loaded with [`IOCTL_MAP_DRIVER`] to test manual mapping end to end.
+//!
+//! Since a mapped driver has no driver object, it creates one named
+//! [`DRIVER_NAME`] and a device with the symbolic link. It is unloaded by
+//! sending [`IOCTL_TEAR_DOWN`], which deletes them, and then unmapping the
+//! image.
+//!
+//! [`IOCTL_MAP_DRIVER`]: crate::IOCTL_MAP_DRIVER
+
+/// The name of the driver object the driver creates.
+pub const DRIVER_NAME_UTF16: [u16; 21] = utf16_lit::utf16!("\\Driver\\CapcomMapTest");
+
+/// The name of the device object.
+pub const DEVICE_NAME_UTF16: [u16; 21] = utf16_lit::utf16!("\\Device\\CapcomMapTest");
+
+/// The name of the symbolic link to the device object.
+pub const LINK_NAME_UTF16: [u16; 25] = utf16_lit::utf16!("\\DosDevices\\CapcomMapTest");
+
+/// The path user-mode programs open the device with.
+pub const DEVICE_PATH: &str = r"\\.\CapcomMapTest";
+
+/// The name the image is mapped with, which [`IOCTL_ENUM_MAPPED_DRIVERS`]
+/// reports.
+///
+/// [`IOCTL_ENUM_MAPPED_DRIVERS`]: crate::IOCTL_ENUM_MAPPED_DRIVERS
+pub const IMAGE_NAME: &str = "capcom-map-test";
+
+/// The device type of the device, distinct from [`DEVICE_TYPE`].
+///
+/// [`DEVICE_TYPE`]: crate::DEVICE_TYPE
+pub const DEVICE_TYPE: u32 = 0xaa02;
+
+/// Deletes the symbolic link and the device, and makes the driver object
+/// temporary so that it is deleted once the last handle is closed. The image
+/// can be unmapped after that.
+pub const IOCTL_TEAR_DOWN: u32 = (DEVICE_TYPE << 16) | 0x2000;
@@ -0,0 +1,185 @@
+//! The IDs and formats of the messages the driver logs.
+//!
+//! The driver logs a message as its ID and up to [`MAX_MESSAGE_ARGUMENTS`]
+//! 64-bit arguments, both to the kernel debugger as `capcom#<id>` followed by
+//! the arguments in hex, and to the ring buffer of
+//! [`IOCTL_ENABLE_EVENT_RING`] as [`MessageRecord`]. Messages written while
+//! dispatching [`IOCTL_CORRELATED`] are printed as `capcom#<id>@<correlation>`
+//! with the correlation ID in hex. Only [`MESSAGES`] has the
+//! text, which `cargo xtask decode` renders the messages with, so the text is
+//! not in the driver.
+//!
+//! A format has placeholders of `format!` with the following specs, each of
+//! which takes one argument in order:
+//!
+//! - `{}` for decimal.
+//! - `{:x}`, `{:#x}`, `{:02x}` and `{:04x}` for hex.
+//!
+//! A format may also have one `{:s}` or `{:02x?}` placeholder, which takes all
+//! arguments after those of the other placeholders as bytes in little-endian,
+//! and renders them as ASCII text up to the first NUL, or as a list of bytes
+//! in hex, respectively. Bytes that do not fit into the arguments are
+//! truncated.
+//!
+//! IDs are never reused, so records remain decodable with newer tables.
+//!
+//! [`MAX_MESSAGE_ARGUMENTS`]: crate::MAX_MESSAGE_ARGUMENTS
+//! [`IOCTL_ENABLE_EVENT_RING`]: crate::IOCTL_ENABLE_EVENT_RING
+//! [`IOCTL_CORRELATED`]: crate::IOCTL_CORRELATED
+//! [`MessageRecord`]: crate::MessageRecord
+
+/// Defines a constant for each message, with the format as the doc comment,
+/// and [`MESSAGES`].
+macro_rules! messages {
+    ($($name:ident = $id:literal: $format:literal,)*) => {
+        $(
+            #[doc = concat!("`", $format, "`")]
+            pub const $name: u32 = $id;
+        )*
+
+        /// The IDs and formats of all messages.
+        pub const MESSAGES: &[(u32, &str)] = &[$(($id, $format)),*];
+    };
+}
+
+messages! {
+    ETW_REGISTRATION_FAILED = 1: "EtwRegister failed: {:#x}",
+    DEVICE_CREATION_FAILED = 2: "IoCreateDevice failed: {:#x}",
+    LINK_CREATION_FAILED = 3: "IoCreateSymbolicLink failed: {:#x}",
+    LOADED = 4: "Loaded the driver successfully",
+    MALFORMED_IRP = 5: "Malformed IRP {:#x}: location {} of {}",
+    ENABLED_CLASSES = 6: "Enabled IOCTL classes: {:#x}",
+    PAYLOAD_RATE = 7: "Payload rate: {}/s",
+    PAYLOAD_BURST = 8: "Payload burst: {}",
+    PAYLOAD_STACK_SIZE = 9: "Payload stack size: {}",
+    DEBUG_BREAK_MODE = 10: "Debug break mode: {}",
+    BUILTIN_OFFSETS = 11: "Using the built-in offsets for build {}",
+    NO_BUILTIN_OFFSETS = 12: "No built-in offsets for build {}",
+    OFFSETS_SET = 13: "Set the offsets for build {}",
+    VERSION_QUERY_FAILED = 14: "RtlGetVersion failed: {:#x}",
+    PAYLOAD_REFUSED_FOR_HVCI = 15: "Refusing to run the payload as HVCI is enabled",
+    USER_PAYLOAD_REFUSED = 16: "Refusing to run the user-mode payload. Use IOCTL_RUN_SHELLCODE",
+    INVALID_CODE = 17: "Refusing to run code starting with {:02x?}",
+    PAYLOAD_REQUESTED = 18: "Process {} requested to run a payload",
+    PAYLOAD_STACK_OVERFLOWED = 19: "The payload overflowed the stack of {} bytes",
+    REGISTER_CHANGED = 20: "The payload changed {:s} from {:#x} to {:#x}",
+    PAYLOAD_EXECUTION_DISABLED = 21: "Payload execution was disabled by process {}",
+    RESOURCES_RELEASED = 22: "Released {} resource(s) left by process {}",
+    CONTIGUOUS_MEMORY_ALLOCATED = 23: "Allocated {:#x} bytes of contiguous memory at {:#x} ({:#x})",
+    PCI_WRITTEN = 24: "Wrote {:#x} to PCI {:04x}:{:02x}:{:02x}.{} at offset {:#x}",
+    PAGE_TABLE_ENTRY_CHANGED = 25: "Changed the level {} entry for {:#x}: {:#x} -> {:#x}",
+    SELF_DESTRUCT_REQUESTED = 26: "Self-destruct requested with flags {:#x}",
+    SERVICE_KEY_UNKNOWN = 27: "The service key is unknown; not unloading",
+    FILE_DELETION_FAILED = 28: "Failed to schedule the driver file for deletion: {:#x}",
+    UNLOAD_REQUESTED = 29: "Requested the unload: {:#x}",
+    SERVICE_DELETION_FAILED = 30: "Failed to delete the service key: {:#x}",
+    MEMORY_SHARED = 31: "Shared {:#x} bytes at {:#x} with process {} at {:#x}",
+    HANDLE_DUPLICATED = 32: "Duplicated handle {:#x} of process {} as {:#x}",
+    FILE_WRITTEN = 33: "Wrote {} bytes to a file at offset {:#x}",
+    APIC_WRITTEN = 34: "Wrote {:#x} to the local APIC register {:#x} of processor {}",
+    APC_QUEUED = 35: "Queued a user-mode APC at {:#x} to thread {}",
+    REGISTRY_WRITTEN = 36: "Wrote {} bytes of type {} to a registry value",
+    MAPPING_REFUSED_FOR_HVCI = 37: "Refusing to map the driver as HVCI is enabled",
+    MAPPING_REFUSED_FOR_ENDBR = 38: "Refusing to map the driver as its entry point lacks ENDBR",
+    DRIVER_MAPPED = 39: "Mapped {:s} at {:#x}, and its entry point returned {:#x}",
+    DRIVER_UNMAPPING = 40: "Unmapping {:s} at {:#x}",
+    MODULE_NOT_LOADED = 41: "{:s} is not loaded",
+    NAME_NOT_EXPORTED = 42: "{:s} is not exported",
+    ORDINAL_NOT_EXPORTED = 43: "Ordinal {} is not exported",
+    DEVICE_INTERFACE = 44: "Device interface: {}",
+    INTERFACE_REGISTRATION_FAILED = 45: "Failed to register the device interface: {:#x}",
+    STEALTH_NAMING = 46: "Stealth naming: {}",
+    ALLOCATIONS_LEAKED = 47: "{} allocation(s) tagged {:s} were not freed",
+    SELF_TEST_FAILED = 48: "Self-test failed: {:#x}",
+    BUILD = 49: "Built at {} with features {:#x} from {:02x?} (dirty: {})",
+    POINTER_REJECTED = 50: "Rejected a pointer to {:#x} bytes at {:#x} expected in {:s}",
+    PAYLOAD_NOT_SELF_REFERENCED = 51: "Refusing to run the payload at {:#x} not preceded by its address",
+    TEACHING_IRP_ARRIVED = 52: "Step 1: IRP_MJ_DEVICE_CONTROL with IOCTL {:#x} arrived from process {}",
+    TEACHING_BUFFERS = 53: "Step 2: The I/O manager copied the {}-byte input buffer into the system buffer at {:#x}, which also receives up to {} bytes of output",
+    TEACHING_PAYLOAD_ADDRESS = 54: "Step 3: The input buffer holds the address of the payload, {:#x}",
+    TEACHING_CALLING = 55: "Step 4: Disabling interrupts and SMEP, and calling the payload at {:#x} with MmGetSystemRoutineAddress ({:#x}) as the only argument",
+    TEACHING_RETURNED = 56: "Step 5: The payload returned, and interrupts and SMEP are enabled again",
+    TEACHING_CR4 = 57: "Step 6: CR4 was {:#x} right before the call, with SMEP cleared, and {:#x} right after it",
+    TEACHING_COMPLETED = 58: "Step 7: Completing the IRP with {:#x} and {} bytes of output",
+    LEGACY_ROUTINE = 59: "{:s} is not exported; using the legacy routine instead",
+    IMPORT_NOT_FOUND = 60: "{:s} is not exported, so the driver cannot resolve its imports",
+    PRINT_LEVEL_MASK = 61: "Print level mask: {:#x}",
+    CONFIG_SAVED = 62: "Saved the configuration with fields {:#x}",
+    SAVED_CONFIG_LOADED = 63: "Loaded the saved configuration with fields {:#x}",
+    SAVED_CONFIG_INVALID = 64: "Ignored the saved configuration of version {} and size {}",
+    BOOT_PHASE = 65: "Started with start type {}, {} ms after boot",
+    LINKS_DEFERRED = 66: "Deferring the symbolic links that could not be created with {:#x}",
+    LINKS_CREATED = 67: "Created the deferred symbolic links on attempt {}, {} ms after boot",
+    SHUTDOWN_REGISTRATION_FAILED = 68: "Could not register for the shutdown notification: {:#x}",
+    LOG_SAVED = 69: "Shutting down; saved {} log records",
+    LOG_SAVE_FAILED = 70: "Shutting down; could not save the log records: {:#x}",
+    LOG_RESTORED = 71: "Restored {} log records saved at shutdown",
+    PAYLOAD_EXECUTED = 72: "Execution {} by process {}, audit chain {:#x}",
+}
+
+/// `DPFLTR_ERROR_LEVEL`: failures of the driver and leaks.
+pub const LEVEL_ERROR: u32 = 0;
+
+/// `DPFLTR_WARNING_LEVEL`: requests refused and fallbacks taken.
+pub const LEVEL_WARNING: u32 = 1;
+
+/// `DPFLTR_TRACE_LEVEL`: what requests did, logged once or more per IOCTL.
+pub const LEVEL_TRACE: u32 = 2;
+
+/// `DPFLTR_INFO_LEVEL`: the build and the configuration when the driver loads.
+pub const LEVEL_INFO: u32 = 3;
+
+/// Returns the `LEVEL_*` the driver prints the message `id` with.
+#[must_use]
+pub const fn level(id: u32) -> u32 {
+    match id {
+        ETW_REGISTRATION_FAILED
+        | DEVICE_CREATION_FAILED
+        | LINK_CREATION_FAILED
+        | VERSION_QUERY_FAILED
+        | PAYLOAD_STACK_OVERFLOWED
+        | FILE_DELETION_FAILED
+        | SERVICE_DELETION_FAILED
+        | INTERFACE_REGISTRATION_FAILED
+        | ALLOCATIONS_LEAKED
+        | SELF_TEST_FAILED
+        | IMPORT_NOT_FOUND => LEVEL_ERROR,
+        MALFORMED_IRP
+        | NO_BUILTIN_OFFSETS
+        | PAYLOAD_REFUSED_FOR_HVCI
+        | USER_PAYLOAD_REFUSED
+        | INVALID_CODE
+        | REGISTER_CHANGED
+        | SERVICE_KEY_UNKNOWN
+        | MAPPING_REFUSED_FOR_HVCI
+        | MAPPING_REFUSED_FOR_ENDBR
+        | MODULE_NOT_LOADED
+        | NAME_NOT_EXPORTED
+        | ORDINAL_NOT_EXPORTED
+        | POINTER_REJECTED
+        | PAYLOAD_NOT_SELF_REFERENCED
+        | LEGACY_ROUTINE
+        | SAVED_CONFIG_INVALID
+        | LINKS_DEFERRED
+        | SHUTDOWN_REGISTRATION_FAILED
+        | LOG_SAVE_FAILED => LEVEL_WARNING,
+        LOADED | ENABLED_CLASSES | PAYLOAD_RATE | PAYLOAD_BURST | PAYLOAD_STACK_SIZE
+        | DEBUG_BREAK_MODE | BUILTIN_OFFSETS | DEVICE_INTERFACE | STEALTH_NAMING | BUILD
+        | PRINT_LEVEL_MASK | SAVED_CONFIG_LOADED | BOOT_PHASE | LINKS_CREATED | LOG_SAVED
+        | LOG_RESTORED => LEVEL_INFO,
+        _ => LEVEL_TRACE,
+    }
+}
+
+// IDs must be unique.
+const _: () = {
+    let mut i = 0;
+    while i < MESSAGES.len() {
+        let mut j = i + 1;
+        while j < MESSAGES.len() {
+            assert!(MESSAGES[i].0 != MESSAGES[j].0, "duplicate message ID");
+            j += 1;
+        }
+        i += 1;
+    }
+};
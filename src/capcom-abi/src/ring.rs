@@ -0,0 +1,326 @@
+//! The protocol of the ring buffer of [`IOCTL_ENABLE_EVENT_RING`], shared by
+//! the driver, which produces records, and clients, which consume them, so
+//! that the atomic orderings are in one place and can be exercised on the host,
+//! e.g., under Miri with `cargo +nightly miri test --package capcom-abi`, or
+//! under loom with `RUSTFLAGS="--cfg loom" cargo test --package capcom-abi
+//! --release`.
+//!
+//! The producer is the only writer of the head, and the consumer is the only
+//! writer of the tail. Each side publishes its index with release ordering
+//! after accessing the records, and acquires the index of the other side
+//! before, so a record is never read while it is written or overwritten
+//! before it is read. The producer keeps its own copy of the head, as the
+//! consumer can overwrite the one in the header.
+//!
+//! [`IOCTL_ENABLE_EVENT_RING`]: crate::IOCTL_ENABLE_EVENT_RING
+
+use core::sync::atomic::Ordering;
+
+use crate::{EventRecord, EventRingHeader, sync::AtomicU64};
+
+// Records follow the header, so they must not require more alignment.
+const _: () = assert!(align_of::<EventRecord>() <= align_of::<EventRingHeader>());
+
+/// Returns the number of records a ring in `size` bytes holds.
+#[must_use]
+pub const fn capacity(size: usize) -> u64 {
+    (size.saturating_sub(size_of::<EventRingHeader>()) / size_of::<EventRecord>()) as u64
+}
+
+/// The producing side of a ring.
+#[derive(Debug)]
+pub struct Producer {
+    header: *mut EventRingHeader,
+    records: *mut EventRecord,
+    capacity: u64,
+    head: u64,
+}
+
+impl Producer {
+    /// Formats the memory at `header` as an empty ring of `capacity` records.
+    ///
+    /// # Safety
+    ///
+    /// `header` must be aligned and point to memory for the header and
+    /// `capacity` records, which stays valid while the producer is used.
+    /// `capacity` must not be zero.
+    #[must_use]
+    pub unsafe fn format(header: *mut EventRingHeader, capacity: u64) -> Self {
+        unsafe {
+            header.write_volatile(EventRingHeader {
+                record_size: size_of::<EventRecord>() as u32,
+                capacity: capacity as u32,
+                ..EventRingHeader::default()
+            });
+        }
+        Self {
+            header,
+            records: unsafe { header.add(1) }.cast(),
+            capacity,
+            head: 0,
+        }
+    }
+
+    /// Appends `record`, and returns whether it was appended. If the ring is
+    /// full, the record is dropped and counted in
+    /// [`EventRingHeader::dropped`].
+    pub fn push(&mut self, record: &EventRecord) -> bool {
+        unsafe {
+            let header = self.header;
+            let tail = AtomicU64::from_ptr(&raw mut (*header).tail).load(Ordering::Acquire);
+            if self.head.wrapping_sub(tail) >= self.capacity {
+                let _ =
+                    AtomicU64::from_ptr(&raw mut (*header).dropped).fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            let index = (self.head % self.capacity) as usize;
+            self.records.add(index).write_volatile(*record);
+            self.head += 1;
+            AtomicU64::from_ptr(&raw mut (*header).head).store(self.head, Ordering::Release);
+        }
+        true
+    }
+}
+
+/// The consuming side of a ring.
+#[derive(Debug)]
+pub struct Consumer {
+    header: *mut EventRingHeader,
+    records: *const EventRecord,
+    capacity: u64,
+}
+
+impl Consumer {
+    /// Returns the consumer of the ring formatted at `header`.
+    ///
+    /// # Safety
+    ///
+    /// `header` must point to a ring formatted by [`Producer::format`], which
+    /// stays valid while the consumer is used.
+    #[must_use]
+    pub unsafe fn new(header: *mut EventRingHeader) -> Self {
+        let capacity = unsafe { (&raw const (*header).capacity).read_volatile() };
+        Self {
+            header,
+            records: unsafe { header.add(1) }.cast(),
+            capacity: u64::from(capacity),
+        }
+    }
+
+    /// Removes and returns the oldest record, if any.
+    pub fn pop(&mut self) -> Option<EventRecord> {
+        unsafe {
+            let header = self.header;
+            let tail = AtomicU64::from_ptr(&raw mut (*header).tail);
+            let index = tail.load(Ordering::Relaxed);
+            if index >= AtomicU64::from_ptr(&raw mut (*header).head).load(Ordering::Acquire) {
+                return None;
+            }
+            let record = self
+                .records
+                .add((index % self.capacity) as usize)
+                .read_volatile();
+            tail.store(index + 1, Ordering::Release);
+            Some(record)
+        }
+    }
+
+    /// Returns the number of records dropped because the ring was full.
+    #[must_use]
+    pub fn dropped(&self) -> u64 {
+        unsafe { AtomicU64::from_ptr(&raw mut (*self.header).dropped).load(Ordering::Relaxed) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    /// Returns aligned memory for a ring of `capacity` records.
+    fn memory(capacity: usize) -> Vec<EventRingHeader> {
+        let size = size_of::<EventRingHeader>() + capacity * size_of::<EventRecord>();
+        vec![EventRingHeader::default(); size.div_ceil(size_of::<EventRingHeader>())]
+    }
+
+    fn record(sequence: u32) -> EventRecord {
+        EventRecord {
+            kind: sequence,
+            reserved: !sequence,
+            ..EventRecord::default()
+        }
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn counts_whole_records_after_the_header() {
+        const CASES: &[(usize, u64)] = &[
+            (0, 0),
+            (size_of::<EventRingHeader>(), 0),
+            (
+                size_of::<EventRingHeader>() + size_of::<EventRecord>() - 1,
+                0,
+            ),
+            (size_of::<EventRingHeader>() + size_of::<EventRecord>(), 1),
+            (
+                size_of::<EventRingHeader>() + 3 * size_of::<EventRecord>() + 1,
+                3,
+            ),
+        ];
+        for &(size, expected) in CASES {
+            assert_eq!(capacity(size), expected, "{size}");
+        }
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn pops_records_in_order() {
+        let mut memory = memory(4);
+        let header = memory.as_mut_ptr();
+        let mut producer = unsafe { Producer::format(header, 4) };
+        let mut consumer = unsafe { Consumer::new(header) };
+
+        assert_eq!(consumer.pop(), None);
+        for sequence in 0..3 {
+            assert!(producer.push(&record(sequence)));
+        }
+        for sequence in 0..3 {
+            assert_eq!(consumer.pop(), Some(record(sequence)));
+        }
+        assert_eq!(consumer.pop(), None);
+        assert_eq!(consumer.dropped(), 0);
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn drops_records_while_full() {
+        let mut memory = memory(2);
+        let header = memory.as_mut_ptr();
+        let mut producer = unsafe { Producer::format(header, 2) };
+        let mut consumer = unsafe { Consumer::new(header) };
+
+        assert!(producer.push(&record(0)));
+        assert!(producer.push(&record(1)));
+        assert!(!producer.push(&record(2)));
+        assert!(!producer.push(&record(3)));
+        assert_eq!(consumer.dropped(), 2);
+
+        // A dropped record does not overwrite the oldest one, and popping makes
+        // room again.
+        assert_eq!(consumer.pop(), Some(record(0)));
+        assert!(producer.push(&record(4)));
+        assert!(!producer.push(&record(5)));
+        assert_eq!(consumer.pop(), Some(record(1)));
+        assert_eq!(consumer.pop(), Some(record(4)));
+        assert_eq!(consumer.pop(), None);
+        assert_eq!(consumer.dropped(), 3);
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn wraps_around() {
+        let mut memory = memory(3);
+        let header = memory.as_mut_ptr();
+        let mut producer = unsafe { Producer::format(header, 3) };
+        let mut consumer = unsafe { Consumer::new(header) };
+
+        // Pop behind the producer, so that the indices pass the capacity many
+        // times and each slot is reused.
+        let mut popped = 0;
+        for sequence in 0..20 {
+            assert!(producer.push(&record(sequence)), "{sequence}");
+            if sequence % 2 == 1 {
+                for _ in 0..2 {
+                    assert_eq!(consumer.pop(), Some(record(popped)), "{sequence}");
+                    popped += 1;
+                }
+            }
+        }
+        assert_eq!(consumer.pop(), None);
+        assert_eq!(popped, 20);
+        assert_eq!(unsafe { (*header).head }, 20);
+        assert_eq!(unsafe { (*header).tail }, 20);
+        assert_eq!(consumer.dropped(), 0);
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn ignores_a_tail_beyond_the_head() {
+        let mut memory = memory(4);
+        let header = memory.as_mut_ptr();
+        let mut producer = unsafe { Producer::format(header, 4) };
+        let mut consumer = unsafe { Consumer::new(header) };
+        assert!(producer.push(&record(0)));
+
+        // The consumer is in another process and may write anything. Records
+        // it did not pop are neither read again nor overwritten.
+        unsafe { (*header).tail = 5 };
+        assert_eq!(consumer.pop(), None);
+        assert!(!producer.push(&record(1)));
+        assert_eq!(consumer.dropped(), 1);
+
+        unsafe { (*header).tail = 1 };
+        assert!(producer.push(&record(2)));
+        assert_eq!(consumer.pop(), Some(record(2)));
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn restores_a_head_overwritten_by_the_consumer() {
+        let mut memory = memory(4);
+        let header = memory.as_mut_ptr();
+        let mut producer = unsafe { Producer::format(header, 4) };
+        let mut consumer = unsafe { Consumer::new(header) };
+        assert!(producer.push(&record(0)));
+
+        unsafe { (*header).head = 3 };
+        assert!(producer.push(&record(1)));
+        assert_eq!(unsafe { (*header).head }, 2);
+        assert_eq!(consumer.pop(), Some(record(0)));
+        assert_eq!(consumer.pop(), Some(record(1)));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    /// A side of the ring moved to another thread.
+    #[cfg(loom)]
+    struct Side<T>(T);
+
+    // The ring outlives the thread, and the side is used only by it.
+    #[cfg(loom)]
+    unsafe impl<T> Send for Side<T> {}
+
+    #[cfg(loom)]
+    #[test]
+    fn delivers_records_in_order_or_counts_them_as_dropped() {
+        const COUNT: u32 = 3;
+
+        loom::model(|| {
+            let mut memory = memory(2);
+            let header = memory.as_mut_ptr();
+            let mut producer = Side(unsafe { Producer::format(header, 2) });
+            let mut consumer = unsafe { Consumer::new(header) };
+
+            let producer = loom::thread::spawn(move || {
+                let producer = &mut producer;
+                (0..COUNT)
+                    .filter(|&sequence| producer.0.push(&record(sequence)))
+                    .count()
+            });
+            let mut received: Vec<_> = (0..COUNT).filter_map(|_| consumer.pop()).collect();
+            let appended = producer.join().unwrap();
+            received.extend(core::iter::from_fn(|| consumer.pop()));
+
+            assert_eq!(received.len(), appended);
+            assert_eq!(appended as u64 + consumer.dropped(), u64::from(COUNT));
+            assert!(received.windows(2).all(|pair| pair[0].kind < pair[1].kind));
+            assert!(
+                received
+                    .iter()
+                    .all(|record| record.reserved == !record.kind)
+            );
+        });
+    }
+}
@@ -0,0 +1,181 @@
+//! A signal a task waits for and another thread sets, e.g., the thread pool
+//! once an event of the driver is signaled, used by the futures of
+//! `capcom_client::overlapped`. It is here so that the protocol, which must not
+//! lose a wake-up, can be exercised on the host, under Miri and loom like
+//! [`crate::ring`].
+//!
+//! The waker is kept under a spin lock, as setting the signal only takes it,
+//! and registering replaces it. The lock orders registering and setting, so
+//! either registering sees the signal, or setting sees the waker.
+
+use core::{cell::UnsafeCell, fmt, sync::atomic::Ordering, task::Waker};
+
+use crate::sync::{AtomicBool, spin_loop};
+
+/// A signal that wakes the task that registered last once set.
+pub struct Signal {
+    set: AtomicBool,
+    /// Whether `waker` is being accessed.
+    locked: AtomicBool,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// The waker is accessed only under the lock.
+unsafe impl Sync for Signal {}
+
+impl Signal {
+    /// Creates an unset signal.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            locked: AtomicBool::new(false),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// Makes the task of `waker` woken once the signal is set, instead of the
+    /// one registered before, and returns whether it is set.
+    pub fn register(&self, waker: &Waker) -> bool {
+        self.with_waker(|registered| match registered {
+            Some(registered) if registered.will_wake(waker) => {}
+            _ => *registered = Some(waker.clone()),
+        });
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Sets the signal and wakes the task registered last, if any.
+    pub fn set(&self) {
+        self.set.store(true, Ordering::Release);
+        if let Some(waker) = self.with_waker(Option::take) {
+            waker.wake();
+        }
+    }
+
+    /// Returns whether the signal is set.
+    #[must_use]
+    pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+
+    /// Calls `f` with the waker under the lock.
+    fn with_waker<R>(&self, f: impl FnOnce(&mut Option<Waker>) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        let result = f(unsafe { &mut *self.waker.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+impl Default for Signal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signal")
+            .field("set", &self.is_set())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        task::Wake,
+    };
+
+    use super::*;
+
+    /// A waker counting how many times it was woken.
+    #[derive(Default)]
+    struct Counter(AtomicUsize);
+
+    impl Wake for Counter {
+        fn wake(self: Arc<Self>) {
+            let _ = self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Counter {
+        fn count(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn wakes_the_task_registered_last() {
+        let signal = Signal::new();
+        let first = Arc::new(Counter::default());
+        let second = Arc::new(Counter::default());
+
+        assert!(!signal.register(&Waker::from(first.clone())));
+        assert!(!signal.register(&Waker::from(second.clone())));
+        signal.set();
+        assert_eq!((first.count(), second.count()), (0, 1));
+        assert!(signal.is_set());
+
+        // Once set, registering returns it instead of waking.
+        assert!(signal.register(&Waker::from(first.clone())));
+        assert_eq!((first.count(), second.count()), (0, 1));
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn sets_without_a_task() {
+        let signal = Signal::new();
+        assert!(!signal.is_set());
+        signal.set();
+        signal.set();
+        assert!(signal.is_set());
+    }
+
+    #[cfg(not(loom))]
+    #[test]
+    fn wakes_across_threads() {
+        let signal = Arc::new(Signal::new());
+        let counter = Arc::new(Counter::default());
+        let waker = Waker::from(counter.clone());
+
+        let setter = std::thread::spawn({
+            let signal = signal.clone();
+            move || signal.set()
+        });
+        let set = signal.register(&waker);
+        setter.join().unwrap();
+        assert!(set || counter.count() == 1);
+    }
+
+    #[cfg(loom)]
+    #[test]
+    fn does_not_lose_the_wake_up() {
+        loom::model(|| {
+            let signal = loom::sync::Arc::new(Signal::new());
+            let counter = Arc::new(Counter::default());
+            let waker = Waker::from(counter.clone());
+
+            let setter = loom::thread::spawn({
+                let signal = signal.clone();
+                move || signal.set()
+            });
+            let set = signal.register(&waker);
+            setter.join().unwrap();
+            assert!(set || counter.count() == 1);
+        });
+    }
+}
@@ -0,0 +1,62 @@
+//! The atomics of the lock-free protocols of [`crate::ring`] and
+//! [`crate::signal`], which are loom's when the crate is built with
+//! `--cfg loom`, so that loom can explore the interleavings and the orderings
+//! of the tests.
+
+#[cfg(not(loom))]
+pub(crate) use core::{
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU64},
+};
+
+#[cfg(loom)]
+pub(crate) use loom::{sync::atomic::AtomicBool, thread::yield_now as spin_loop};
+
+#[cfg(loom)]
+pub(crate) use self::model::AtomicU64;
+
+/// Loom's atomics cannot be created from pointers into shared memory, so each
+/// index of a ring header is modeled by a loom atomic found by the address of
+/// the index. A model uses one ring, whose indices start at zero as formatted
+/// by [`crate::ring::Producer::format`].
+#[cfg(loom)]
+mod model {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    /// An index of the ring header and its address. The address is not part
+    /// of the protocol, so it is a plain atomic loom does not model.
+    struct Index {
+        address: AtomicUsize,
+        value: loom::sync::atomic::AtomicU64,
+    }
+
+    loom::lazy_static! {
+        /// The head, tail and dropped count of the ring of the model.
+        static ref INDICES: [Index; 3] = [(); 3].map(|()| Index {
+            address: AtomicUsize::new(0),
+            value: loom::sync::atomic::AtomicU64::new(0),
+        });
+    }
+
+    /// The alias of `AtomicU64` with the constructor the ring uses.
+    pub(crate) struct AtomicU64;
+
+    impl AtomicU64 {
+        /// Returns the loom atomic modeling the index at `ptr`.
+        pub(crate) unsafe fn from_ptr<'a>(ptr: *mut u64) -> &'a loom::sync::atomic::AtomicU64 {
+            for index in INDICES.iter() {
+                match index.address.compare_exchange(
+                    0,
+                    ptr.addr(),
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return &index.value,
+                    Err(address) if address == ptr.addr() => return &index.value,
+                    Err(_) => {}
+                }
+            }
+            panic!("a loom model must use only one ring");
+        }
+    }
+}
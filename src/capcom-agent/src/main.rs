@@ -0,0 +1,223 @@
+//! An in-guest service that starts the driver again after the target reboots,
+//! so that experiments that reboot the target keep the harness. `cargo xtask`
+//! copies this program next to the driver and creates an auto-start service
+//! for it when it installs the driver.
+//!
+//! Each time Windows starts, it creates the service of the driver if it is
+//! missing, starts the driver, launches the program given with `--forwarder`,
+//! if any, e.g., a program forwarding the debug output to the host, and then
+//! stops. The result is the exit code of the service, shown by `sc query`.
+//!
+//! By default, the driver is `capcom.sys` in the directory of this program,
+//! and its service is `capcom`. Run outside of the service control manager,
+//! it does the same once in the console, which helps to check the arguments.
+//!
+//! ```shell
+//! capcom-agent.exe [--service <name>] [--driver <path>] [--forwarder <path>]
+//! ```
+
+use std::{
+    env,
+    ffi::OsStr,
+    io, iter,
+    os::windows::ffi::OsStrExt,
+    path::PathBuf,
+    process::{Command, ExitCode},
+    ptr,
+};
+
+use windows_sys::{
+    Win32::{
+        Foundation::{
+            ERROR_CALL_NOT_IMPLEMENTED, ERROR_FAILED_SERVICE_CONTROLLER_CONNECT,
+            ERROR_SERVICE_ALREADY_RUNNING, ERROR_SERVICE_DOES_NOT_EXIST, NO_ERROR,
+        },
+        System::Services::{
+            CloseServiceHandle, CreateServiceW, OpenSCManagerW, OpenServiceW,
+            RegisterServiceCtrlHandlerExW, SC_HANDLE, SC_MANAGER_CONNECT,
+            SC_MANAGER_CREATE_SERVICE, SERVICE_CONTROL_INTERROGATE, SERVICE_DEMAND_START,
+            SERVICE_ERROR_NORMAL, SERVICE_KERNEL_DRIVER, SERVICE_RUNNING, SERVICE_START,
+            SERVICE_STATUS, SERVICE_STATUS_HANDLE, SERVICE_STOPPED, SERVICE_TABLE_ENTRYW,
+            SERVICE_WIN32_OWN_PROCESS, SetServiceStatus, StartServiceCtrlDispatcherW,
+            StartServiceW,
+        },
+    },
+    core::PWSTR,
+};
+
+/// The name of the service of this program, which is ignored for services of
+/// their own processes but must not be empty.
+const AGENT_SERVICE_NAME: &str = "capcom-agent";
+
+/// The default name of the service of the driver.
+const DRIVER_SERVICE_NAME: &str = "capcom";
+
+/// The default file name of the driver, in the directory of this program.
+const DRIVER_FILE_NAME: &str = "capcom.sys";
+
+fn main() -> ExitCode {
+    let mut name = wide(AGENT_SERVICE_NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: ptr::null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+    // Returns when the service stops, or fails immediately when not started
+    // by the service control manager.
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } != 0 {
+        return ExitCode::SUCCESS;
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() != Some(ERROR_FAILED_SERVICE_CONTROLLER_CONNECT.cast_signed()) {
+        println!("Failed to connect to the service control manager: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    match run() {
+        Ok(()) => {
+            println!("Started the driver");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            println!("Failed to start the driver: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// The entry point of the service. Arguments given with `sc start` are
+/// ignored in favor of those of the command line of the service.
+unsafe extern "system" fn service_main(_argc: u32, _argv: *mut PWSTR) {
+    let name = wide(AGENT_SERVICE_NAME);
+    let status_handle =
+        unsafe { RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(control_handler), ptr::null()) };
+    if status_handle.is_null() {
+        return;
+    }
+
+    set_status(status_handle, SERVICE_RUNNING, NO_ERROR);
+    let exit_code = match run() {
+        Ok(()) => NO_ERROR,
+        Err(err) => err.raw_os_error().map_or(u32::MAX, i32::cast_unsigned),
+    };
+    set_status(status_handle, SERVICE_STOPPED, exit_code);
+}
+
+/// Handles requests of the service control manager. The service accepts no
+/// control, as it stops by itself.
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut core::ffi::c_void,
+    _context: *mut core::ffi::c_void,
+) -> u32 {
+    if control == SERVICE_CONTROL_INTERROGATE {
+        NO_ERROR
+    } else {
+        ERROR_CALL_NOT_IMPLEMENTED
+    }
+}
+
+/// Reports `state` of the service, with `exit_code` if stopped.
+fn set_status(status_handle: SERVICE_STATUS_HANDLE, state: u32, exit_code: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: 0,
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        dwWaitHint: 0,
+    };
+    let _ = unsafe { SetServiceStatus(status_handle, &raw const status) };
+}
+
+/// Creates the service of the driver if missing, starts the driver, and
+/// launches the forwarder if given.
+fn run() -> io::Result<()> {
+    let service_name = option_value("--service").unwrap_or_else(|| DRIVER_SERVICE_NAME.to_owned());
+    let driver_path = match option_value("--driver") {
+        Some(path) => PathBuf::from(path),
+        None => env::current_exe()?.with_file_name(DRIVER_FILE_NAME),
+    };
+
+    let manager = ServiceHandle::new(unsafe {
+        OpenSCManagerW(
+            ptr::null(),
+            ptr::null(),
+            SC_MANAGER_CONNECT | SC_MANAGER_CREATE_SERVICE,
+        )
+    })?;
+    let service_name = wide(&service_name);
+    let service = match ServiceHandle::new(unsafe {
+        OpenServiceW(manager.0, service_name.as_ptr(), SERVICE_START)
+    }) {
+        Err(err) if err.raw_os_error() == Some(ERROR_SERVICE_DOES_NOT_EXIST.cast_signed()) => {
+            let driver_path = wide(driver_path.as_os_str());
+            ServiceHandle::new(unsafe {
+                CreateServiceW(
+                    manager.0,
+                    service_name.as_ptr(),
+                    ptr::null(),
+                    SERVICE_START,
+                    SERVICE_KERNEL_DRIVER,
+                    SERVICE_DEMAND_START,
+                    SERVICE_ERROR_NORMAL,
+                    driver_path.as_ptr(),
+                    ptr::null(),
+                    ptr::null_mut(),
+                    ptr::null(),
+                    ptr::null(),
+                    ptr::null(),
+                )
+            })?
+        }
+        result => result?,
+    };
+    if unsafe { StartServiceW(service.0, 0, ptr::null()) } == 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(ERROR_SERVICE_ALREADY_RUNNING.cast_signed()) {
+            return Err(err);
+        }
+    }
+
+    if let Some(forwarder) = option_value("--forwarder") {
+        let _child = Command::new(forwarder).spawn()?;
+    }
+    Ok(())
+}
+
+/// A handle of the service control manager or a service, closed when dropped.
+struct ServiceHandle(SC_HANDLE);
+
+impl ServiceHandle {
+    /// Wraps `handle`, or returns the last error if null.
+    fn new(handle: SC_HANDLE) -> io::Result<Self> {
+        if handle.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Self(handle))
+        }
+    }
+}
+
+impl Drop for ServiceHandle {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseServiceHandle(self.0) };
+    }
+}
+
+/// Returns the value following the command line option `name`, if any.
+fn option_value(name: &str) -> Option<String> {
+    env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+/// Returns `text` as a null-terminated UTF-16 string.
+fn wide(text: impl AsRef<OsStr>) -> Vec<u16> {
+    text.as_ref().encode_wide().chain(iter::once(0)).collect()
+}
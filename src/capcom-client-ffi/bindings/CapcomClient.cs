@@ -0,0 +1,109 @@
+// P/Invoke declarations of capcom_client_ffi.dll, built from
+// src/capcom-client-ffi. Structures are laid out as in capcom-abi for
+// ABI_VERSION 1. Each function returns zero on success, or a Windows error
+// code, which `new Win32Exception(error)` describes.
+
+using System;
+using System.Runtime.InteropServices;
+
+namespace Capcom
+{
+    public static class Classes
+    {
+        public const uint Execute = 1u << 0;
+        public const uint KernelMemory = 1u << 1;
+        public const uint PhysicalMemory = 1u << 2;
+        public const uint Msr = 1u << 3;
+        public const uint Elevation = 1u << 4;
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    public struct VersionInfo
+    {
+        public uint AbiVersion;
+        public uint Capabilities;
+        public uint EnabledClasses;
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    public struct ModuleInfo
+    {
+        public ulong Base;
+        public uint Size;
+        public uint Reserved;
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    public unsafe struct RegisterState
+    {
+        public fixed ulong Gprs[32];
+        public ulong Flags;
+        public ulong Cr0;
+        public ulong Cr4;
+        public byte Irql;
+        public fixed byte Reserved[7];
+    }
+
+    [StructLayout(LayoutKind.Sequential)]
+    public struct PayloadTranscript
+    {
+        public RegisterState Before;
+        public RegisterState After;
+    }
+
+    public static class NativeMethods
+    {
+        public const uint AbiVersion = 1;
+
+        private const string Library = "capcom_client_ffi";
+
+        // Returns ABI_VERSION of the structures the library was built with. It
+        // must equal AbiVersion.
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern uint capcom_abi_version();
+
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern uint capcom_open(uint control, out IntPtr device);
+
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern void capcom_close(IntPtr device);
+
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern uint capcom_get_version(IntPtr device, out VersionInfo version);
+
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern uint capcom_negotiate(IntPtr device, uint classes, out uint granted);
+
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern uint capcom_get_module(
+            IntPtr device,
+            [MarshalAs(UnmanagedType.LPStr)] string? name,
+            out ModuleInfo info);
+
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern uint capcom_read_memory(
+            IntPtr device,
+            ulong address,
+            [Out] byte[] buffer,
+            UIntPtr length,
+            out UIntPtr read);
+
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern uint capcom_run_shellcode(
+            IntPtr device,
+            byte[] shellcode,
+            UIntPtr length,
+            out PayloadTranscript transcript,
+            out uint returned);
+
+        [DllImport(Library, CallingConvention = CallingConvention.Cdecl)]
+        public static extern uint capcom_ioctl(
+            IntPtr device,
+            uint code,
+            byte[]? input,
+            UIntPtr inputLength,
+            [Out] byte[]? output,
+            UIntPtr outputLength,
+            out UIntPtr bytesReturned);
+    }
+}
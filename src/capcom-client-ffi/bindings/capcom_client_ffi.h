@@ -0,0 +1,86 @@
+// C declarations of capcom_client_ffi.dll, built from src/capcom-client-ffi.
+// Structures are laid out as in capcom-abi for ABI_VERSION 1. Each function
+// returns zero on success, or a Windows error code.
+
+#pragma once
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CAPCOM_ABI_VERSION 1
+
+#define CAPCOM_CLASS_EXECUTE (1u << 0)
+#define CAPCOM_CLASS_KERNEL_MEMORY (1u << 1)
+#define CAPCOM_CLASS_PHYSICAL_MEMORY (1u << 2)
+#define CAPCOM_CLASS_MSR (1u << 3)
+#define CAPCOM_CLASS_ELEVATION (1u << 4)
+
+typedef struct CapcomDevice CapcomDevice;
+
+typedef struct CapcomVersionInfo {
+    uint32_t abi_version;
+    uint32_t capabilities;
+    uint32_t enabled_classes;
+} CapcomVersionInfo;
+
+typedef struct CapcomModuleInfo {
+    uint64_t base;
+    uint32_t size;
+    uint32_t reserved;
+} CapcomModuleInfo;
+
+typedef struct CapcomRegisterState {
+    uint64_t gprs[32];
+    uint64_t flags;
+    uint64_t cr0;
+    uint64_t cr4;
+    uint8_t irql;
+    uint8_t reserved[7];
+} CapcomRegisterState;
+
+typedef struct CapcomPayloadTranscript {
+    CapcomRegisterState before;
+    CapcomRegisterState after;
+} CapcomPayloadTranscript;
+
+// Returns ABI_VERSION of the structures the library was built with. It must
+// equal CAPCOM_ABI_VERSION.
+uint32_t capcom_abi_version(void);
+
+// Opens the device, or the control device if control is nonzero.
+uint32_t capcom_open(uint32_t control, CapcomDevice **device);
+
+// Closes the device. NULL is ignored.
+void capcom_close(CapcomDevice *device);
+
+uint32_t capcom_get_version(const CapcomDevice *device, CapcomVersionInfo *version);
+
+// Requests CAPCOM_CLASS_* flags for the handle, and returns those granted.
+uint32_t capcom_negotiate(const CapcomDevice *device, uint32_t classes, uint32_t *granted);
+
+// Locates the kernel module whose file name is name, or ntoskrnl.exe if NULL.
+uint32_t capcom_get_module(const CapcomDevice *device, const char *name,
+                           CapcomModuleInfo *info);
+
+// Reads kernel memory. *read is less than length if a page cannot be read.
+uint32_t capcom_read_memory(const CapcomDevice *device, uint64_t address, uint8_t *buffer,
+                            size_t length, size_t *read);
+
+// Runs shellcode in kernel mode. *returned is zero if the driver is defanged
+// and returned no transcript.
+uint32_t capcom_run_shellcode(const CapcomDevice *device, const uint8_t *shellcode,
+                              size_t length, CapcomPayloadTranscript *transcript,
+                              uint32_t *returned);
+
+// Sends any IOCTL. Buffers may be NULL if their lengths are zero.
+uint32_t capcom_ioctl(const CapcomDevice *device, uint32_t code, const void *input,
+                      size_t input_length, void *output, size_t output_length,
+                      size_t *bytes_returned);
+
+#ifdef __cplusplus
+}
+#endif
@@ -0,0 +1,252 @@
+//! A C ABI of `capcom-client`, for programs in other languages, e.g., .NET
+//! with the P/Invoke declarations in `bindings/CapcomClient.cs`, or C with
+//! `bindings/capcom_client_ffi.h`. Structures are passed as laid out in
+//! `capcom-abi`, so callers do not marshal the versioned structures by hand.
+//!
+//! The exported functions are stable: they are only added, never changed, and
+//! [`capcom_abi_version`] tells the structures they were built with. Each
+//! function returns zero on success, or a Windows error code, e.g.,
+//! `ERROR_ACCESS_DENIED` if the handle was not granted the class.
+
+use std::{
+    ffi::{CStr, c_char},
+    io, ptr, slice,
+};
+
+use capcom_abi::{ABI_VERSION, ModuleInfo, PayloadTranscript, RegisterState, VersionInfo};
+use capcom_client::{Device, kernel_memory::KernelMem};
+use windows_sys::Win32::Foundation::{ERROR_GEN_FAILURE, ERROR_INVALID_PARAMETER};
+
+// The declarations in `bindings` are written for this version and layout.
+const _: () = {
+    assert!(ABI_VERSION == 1, "update the bindings for the new ABI");
+    assert!(size_of::<VersionInfo>() == 12);
+    assert!(size_of::<ModuleInfo>() == 16);
+    assert!(size_of::<RegisterState>() == 288);
+    assert!(size_of::<PayloadTranscript>() == 576);
+};
+
+/// Returns `ABI_VERSION` of the structures the library was built with.
+#[unsafe(no_mangle)]
+pub extern "C" fn capcom_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+/// Opens the device, or the control device if `control` is nonzero, and
+/// stores the handle to `device`. Close it with [`capcom_close`].
+///
+/// # Safety
+///
+/// `device` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capcom_open(control: u32, device: *mut *mut Device) -> u32 {
+    if device.is_null() {
+        return ERROR_INVALID_PARAMETER;
+    }
+    let result = if control == 0 {
+        Device::open()
+    } else {
+        Device::open_control()
+    };
+    to_error(result.map(|opened| unsafe { *device = Box::into_raw(Box::new(opened)) }))
+}
+
+/// Closes `device`. Null is ignored.
+///
+/// # Safety
+///
+/// `device` must be null or returned by [`capcom_open`] and not closed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capcom_close(device: *mut Device) {
+    if !device.is_null() {
+        drop(unsafe { Box::from_raw(device) });
+    }
+}
+
+/// Stores the version of the driver to `version`.
+///
+/// # Safety
+///
+/// `device` must be open, and `version` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capcom_get_version(
+    device: *const Device,
+    version: *mut VersionInfo,
+) -> u32 {
+    let Some(device) = (unsafe { device.as_ref() }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    unsafe { write_result(device.get_version(), version) }
+}
+
+/// Requests the `CLASS_*` flags `classes` for the handle, and stores those
+/// granted to `granted`.
+///
+/// # Safety
+///
+/// `device` must be open, and `granted` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capcom_negotiate(
+    device: *const Device,
+    classes: u32,
+    granted: *mut u32,
+) -> u32 {
+    let Some(device) = (unsafe { device.as_ref() }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    let result = device
+        .negotiate(classes)
+        .map(|response| response.granted_classes);
+    unsafe { write_result(result, granted) }
+}
+
+/// Stores the address and size of the kernel module whose file name is the
+/// null-terminated `name`, or of ntoskrnl.exe if `name` is null, to `info`.
+///
+/// # Safety
+///
+/// `device` must be open, `name` must be null or a null-terminated string, and
+/// `info` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capcom_get_module(
+    device: *const Device,
+    name: *const c_char,
+    info: *mut ModuleInfo,
+) -> u32 {
+    let Some(device) = (unsafe { device.as_ref() }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    let name = if name.is_null() {
+        None
+    } else {
+        match unsafe { CStr::from_ptr(name) }.to_str() {
+            Ok(name) => Some(name),
+            Err(_) => return ERROR_INVALID_PARAMETER,
+        }
+    };
+    unsafe { write_result(device.get_module(name), info) }
+}
+
+/// Reads `length` bytes of kernel memory at `address` into `buffer`, and stores
+/// the number of bytes read, which is less than `length` if a page cannot be
+/// read, to `read`.
+///
+/// # Safety
+///
+/// `device` must be open, `buffer` must be valid for writes of `length` bytes,
+/// and `read` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capcom_read_memory(
+    device: *const Device,
+    address: u64,
+    buffer: *mut u8,
+    length: usize,
+    read: *mut usize,
+) -> u32 {
+    let Some(device) = (unsafe { device.as_ref() }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    let Some(buffer) = (unsafe { slice_mut(buffer, length) }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    unsafe { write_result(KernelMem::new(device).read(address, buffer), read) }
+}
+
+/// Runs `length` bytes of `shellcode` in kernel mode, and stores the transcript
+/// to `transcript` and nonzero to `returned`, or zero to `returned` if the
+/// driver is built with the `defanged` feature, which returns none.
+///
+/// # Safety
+///
+/// `device` must be open, `shellcode` must be valid for reads of `length`
+/// bytes, and `transcript` and `returned` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capcom_run_shellcode(
+    device: *const Device,
+    shellcode: *const u8,
+    length: usize,
+    transcript: *mut PayloadTranscript,
+    returned: *mut u32,
+) -> u32 {
+    let Some(device) = (unsafe { device.as_ref() }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    if shellcode.is_null() || transcript.is_null() || returned.is_null() {
+        return ERROR_INVALID_PARAMETER;
+    }
+    let shellcode = unsafe { slice::from_raw_parts(shellcode, length) };
+    to_error(device.run_shellcode(shellcode).map(|result| unsafe {
+        *returned = u32::from(result.is_some());
+        if let Some(result) = result {
+            *transcript = result;
+        }
+    }))
+}
+
+/// Sends the IOCTL `code` with `input_length` bytes of `input`, and stores the
+/// number of bytes written to `output` to `bytes_returned`. Buffers may be
+/// null if their lengths are zero.
+///
+/// # Safety
+///
+/// `device` must be open, `input` and `output` must be valid for reads and
+/// writes of their lengths, and `bytes_returned` must be valid for writes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn capcom_ioctl(
+    device: *const Device,
+    code: u32,
+    input: *const u8,
+    input_length: usize,
+    output: *mut u8,
+    output_length: usize,
+    bytes_returned: *mut usize,
+) -> u32 {
+    let Some(device) = (unsafe { device.as_ref() }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    let input = if input_length == 0 {
+        &[]
+    } else if input.is_null() {
+        return ERROR_INVALID_PARAMETER;
+    } else {
+        unsafe { slice::from_raw_parts(input, input_length) }
+    };
+    let Some(output) = (unsafe { slice_mut(output, output_length) }) else {
+        return ERROR_INVALID_PARAMETER;
+    };
+    unsafe { write_result(device.ioctl(code, input, output), bytes_returned) }
+}
+
+/// Returns the slice of `length` bytes at `buffer`, or an empty slice if
+/// `length` is zero, or `None` if `buffer` is null otherwise.
+unsafe fn slice_mut<'a>(buffer: *mut u8, length: usize) -> Option<&'a mut [u8]> {
+    if length == 0 {
+        Some(&mut [])
+    } else if buffer.is_null() {
+        None
+    } else {
+        Some(unsafe { slice::from_raw_parts_mut(buffer, length) })
+    }
+}
+
+/// Stores the value of `result` to `output`, and returns the error code.
+///
+/// # Safety
+///
+/// `output` must be null or valid for writes.
+unsafe fn write_result<T>(result: io::Result<T>, output: *mut T) -> u32 {
+    if output.is_null() {
+        return ERROR_INVALID_PARAMETER;
+    }
+    to_error(result.map(|value| unsafe { ptr::write_unaligned(output, value) }))
+}
+
+/// Returns the Windows error code of `result`, or zero on success.
+fn to_error(result: io::Result<()>) -> u32 {
+    match result {
+        Ok(()) => 0,
+        Err(err) => err
+            .raw_os_error()
+            .map_or(ERROR_GEN_FAILURE, i32::cast_unsigned),
+    }
+}
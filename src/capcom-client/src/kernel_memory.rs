@@ -0,0 +1,248 @@
+//! Typed reads of kernel memory over [`IOCTL_READ_MEMORY`], and scans for byte
+//! patterns with wildcards over [`IOCTL_SCAN_MEMORY`], which searches in the
+//! driver rather than copying the memory out.
+//!
+//! ```no_run
+//! use capcom_abi::CLASS_KERNEL_MEMORY;
+//! use capcom_client::{
+//!     Device,
+//!     kernel_memory::{KernelMem, Pattern},
+//! };
+//!
+//! let device = Device::open()?;
+//! let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+//! let kernel = device.get_module(None)?;
+//! let memory = KernelMem::new(&device);
+//! let magic = unsafe { memory.read_struct::<u16>(kernel.base)? };
+//! let pattern = Pattern::parse("48 8b 05 ?? ?? ?? ??").expect("valid pattern");
+//! let matches = memory.scan(kernel.base..kernel.base + u64::from(kernel.size), &pattern)?;
+//! println!("{magic:#x} {matches:x?}");
+//! # anyhow::Ok(())
+//! ```
+
+use std::{ffi::CString, io, mem::MaybeUninit, ops::Range, slice};
+
+use capcom_abi::{
+    IOCTL_READ_MEMORY, IOCTL_SCAN_MEMORY, MAX_SCAN_PATTERN_LENGTH, MemoryReadRequest,
+    SCAN_PHYSICAL, ScanRequest, ScanResult,
+};
+use windows_sys::Win32::Foundation::ERROR_PARTIAL_COPY;
+
+use crate::{Device, as_bytes};
+
+/// The number of matches [`KernelMem::scan`] receives per request.
+const SCAN_RESULTS: usize = 0x200;
+
+/// Kernel memory read through a device granted `CLASS_KERNEL_MEMORY`.
+#[derive(Clone, Copy, Debug)]
+pub struct KernelMem<'a> {
+    device: &'a Device,
+}
+
+impl<'a> KernelMem<'a> {
+    /// Returns the memory read through `device`.
+    #[must_use]
+    pub fn new(device: &'a Device) -> Self {
+        Self { device }
+    }
+
+    /// Reads the memory at `address` into `buffer`, and returns the number of
+    /// bytes read, which is less than its length if a page cannot be read.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle was not granted `CLASS_KERNEL_MEMORY`, or
+    /// the first page cannot be read.
+    pub fn read(&self, address: u64, buffer: &mut [u8]) -> io::Result<usize> {
+        let request = MemoryReadRequest { address };
+        self.device
+            .ioctl(IOCTL_READ_MEMORY, as_bytes(&request), buffer)
+    }
+
+    /// Reads the memory at `address` into the whole of `buffer`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`KernelMem::read`], or `ERROR_PARTIAL_COPY` if
+    /// only part of it can be read.
+    pub fn read_exact(&self, address: u64, buffer: &mut [u8]) -> io::Result<()> {
+        if self.read(address, buffer)? == buffer.len() {
+            Ok(())
+        } else {
+            Err(io::Error::from_raw_os_error(
+                ERROR_PARTIAL_COPY.cast_signed(),
+            ))
+        }
+    }
+
+    /// Reads `T` at `address`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`KernelMem::read_exact`].
+    ///
+    /// # Safety
+    ///
+    /// `T` must be valid for any bytes, e.g., integers and `repr(C)` structures
+    /// of them.
+    pub unsafe fn read_struct<T: Copy>(&self, address: u64) -> io::Result<T> {
+        let mut value = MaybeUninit::<T>::zeroed();
+        let buffer =
+            unsafe { slice::from_raw_parts_mut(value.as_mut_ptr().cast(), size_of::<T>()) };
+        self.read_exact(address, buffer)?;
+        Ok(unsafe { value.assume_init() })
+    }
+
+    /// Reads the null-terminated string at `address`, which must be shorter
+    /// than `max_length` bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error like [`KernelMem::read_exact`], or `InvalidData` if no
+    /// null is found in `max_length` bytes.
+    pub fn read_cstr(&self, address: u64, max_length: usize) -> io::Result<CString> {
+        const CHUNK_SIZE: usize = 0x100;
+
+        let mut string = Vec::new();
+        let mut chunk = [0_u8; CHUNK_SIZE];
+        while string.len() < max_length {
+            let length = (max_length - string.len()).min(CHUNK_SIZE);
+            let read = self.read(address + string.len() as u64, &mut chunk[..length])?;
+            if let Some(end) = chunk[..read].iter().position(|&byte| byte == 0) {
+                string.extend_from_slice(&chunk[..=end]);
+                return CString::from_vec_with_nul(string)
+                    .map_err(|_| io::ErrorKind::InvalidData.into());
+            }
+            if read != length {
+                return Err(io::Error::from_raw_os_error(
+                    ERROR_PARTIAL_COPY.cast_signed(),
+                ));
+            }
+            string.extend_from_slice(&chunk[..read]);
+        }
+        Err(io::ErrorKind::InvalidData.into())
+    }
+
+    /// Returns the addresses in `range` where `pattern` matches, in ascending
+    /// order, searched with [`IOCTL_SCAN_MEMORY`]. Pages that cannot be read
+    /// are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle was not granted `CLASS_KERNEL_MEMORY`, or
+    /// `InvalidInput` if `pattern` is empty or longer than
+    /// [`MAX_SCAN_PATTERN_LENGTH`].
+    pub fn scan(&self, range: Range<u64>, pattern: &Pattern) -> io::Result<Vec<u64>> {
+        self.scan_with_flags(range, pattern, 0)
+    }
+
+    /// Returns the physical addresses in `range` where `pattern` matches, like
+    /// [`KernelMem::scan`]. Holes that are not RAM are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle was not granted `CLASS_PHYSICAL_MEMORY`,
+    /// or `InvalidInput` like [`KernelMem::scan`].
+    pub fn scan_physical(&self, range: Range<u64>, pattern: &Pattern) -> io::Result<Vec<u64>> {
+        self.scan_with_flags(range, pattern, SCAN_PHYSICAL)
+    }
+
+    fn scan_with_flags(
+        &self,
+        range: Range<u64>,
+        pattern: &Pattern,
+        flags: u32,
+    ) -> io::Result<Vec<u64>> {
+        if pattern.is_empty() || pattern.len() > MAX_SCAN_PATTERN_LENGTH {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+
+        let mut request = ScanRequest {
+            address: range.start,
+            size: range.end.saturating_sub(range.start),
+            cursor: 0,
+            flags,
+            pattern_length: pattern.len() as u32,
+        };
+        let mut matches = Vec::new();
+        let mut output = vec![0_u8; size_of::<ScanResult>() + SCAN_RESULTS * size_of::<u64>()];
+        while request.cursor < request.size {
+            let mut input = as_bytes(&request).to_vec();
+            input.extend_from_slice(&pattern.bytes);
+            input.extend_from_slice(&pattern.mask);
+            let _ = self.device.ioctl(IOCTL_SCAN_MEMORY, &input, &mut output)?;
+            let result = unsafe { output.as_ptr().cast::<ScanResult>().read_unaligned() };
+            matches.extend(
+                output[size_of::<ScanResult>()..]
+                    .chunks_exact(size_of::<u64>())
+                    .take(result.count as usize)
+                    .map(|address| u64::from_ne_bytes(address.try_into().unwrap_or_default())),
+            );
+            request.cursor = result.cursor;
+        }
+        Ok(matches)
+    }
+}
+
+/// A byte pattern, in which some bytes may match any byte.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pattern {
+    bytes: Vec<u8>,
+    /// 0xff for the bytes to match, and 0 for wildcards.
+    mask: Vec<u8>,
+}
+
+impl Pattern {
+    /// Returns the pattern of `bytes`, where `None` matches any byte.
+    #[must_use]
+    pub fn new(bytes: &[Option<u8>]) -> Self {
+        Self {
+            bytes: bytes.iter().map(|byte| byte.unwrap_or_default()).collect(),
+            mask: bytes
+                .iter()
+                .map(|byte| if byte.is_some() { 0xff } else { 0 })
+                .collect(),
+        }
+    }
+
+    /// Parses bytes in hexadecimal separated by whitespace, e.g.,
+    /// `48 8b ?? 05`, where `?` or `??` matches any byte. Returns `None` if
+    /// `text` is not in this form or has no bytes.
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Self> {
+        let bytes = text
+            .split_whitespace()
+            .map(|byte| match byte {
+                "?" | "??" => Some(None),
+                _ if byte.len() == 2 && byte.bytes().all(|c| c.is_ascii_hexdigit()) => {
+                    u8::from_str_radix(byte, 16).ok().map(Some)
+                }
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+        (!bytes.is_empty()).then(|| Self::new(&bytes))
+    }
+
+    /// Returns the number of bytes of the pattern.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Returns whether the pattern has no bytes.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Returns whether `data` matches the pattern. It must be as long as the
+    /// pattern.
+    #[must_use]
+    pub fn matches(&self, data: &[u8]) -> bool {
+        data.len() == self.len()
+            && data
+                .iter()
+                .zip(self.bytes.iter().zip(&self.mask))
+                .all(|(&byte, (&expected, &mask))| byte & mask == expected)
+    }
+}
@@ -0,0 +1,559 @@
+//! A user-mode client library for the driver. [`Device`] opens the device and
+//! sends IOCTLs defined in `capcom-abi`, which [`trace`] can record and replay,
+//! and [`correlation`] can tag with IDs the driver logs.
+//! [`overlapped::AsyncDevice`] sends them asynchronously,
+//! [`kernel_memory::KernelMem`] reads kernel memory with them, and
+//! [`elevate_current_process`] elevates the current process to SYSTEM with a
+//! payload.
+//!
+//! ```no_run
+//! use capcom_abi::CLASS_KERNEL_MEMORY;
+//! use capcom_client::{Device, symbols};
+//!
+//! let device = Device::open()?;
+//! let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+//! let offsets = symbols::upload_offsets(&device, &std::env::temp_dir().join("symbols"))?;
+//! println!("{offsets:x?}");
+//! # anyhow::Ok(())
+//! ```
+
+pub mod correlation;
+pub mod elevate;
+pub mod kernel_memory;
+pub mod overlapped;
+pub mod symbols;
+pub mod trace;
+
+pub use elevate::elevate_current_process;
+
+use std::{
+    env,
+    ffi::OsString,
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    os::windows::{ffi::OsStringExt, io::AsRawHandle},
+    ptr, slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use capcom_abi::{
+    ABI_VERSION, AllocationInfo, AuditInfo, BUILD_INFO_OFFSET, BuildInfo, CONTROL_DEVICE_PATH,
+    COVERAGE_COUNTERS, CoverageRequest, DEVICE_INTERFACE_GUID, DEVICE_PATH, DebugBreakRequest,
+    IOCTL_CORRELATED, IOCTL_ENUM_MAPPED_DRIVERS, IOCTL_GET_AUDIT, IOCTL_GET_COVERAGE,
+    IOCTL_GET_KERNEL_BASE, IOCTL_GET_OFFSETS, IOCTL_GET_VERSION, IOCTL_MAP_DRIVER, IOCTL_NEGOTIATE,
+    IOCTL_QUERY_ALLOCATIONS, IOCTL_RUN_SHELLCODE, IOCTL_SAVE_CONFIG, IOCTL_SELF_TEST,
+    IOCTL_SET_DEBUG_BREAK, IOCTL_SET_OFFSETS, IOCTL_SET_PRINT_FILTER, IOCTL_UNMAP_DRIVER,
+    KernelOffsets, MAX_MAPPED_DRIVERS, MAX_POOL_TAGS, MapDriverRequest, MappedDriver, ModuleInfo,
+    ModuleRequest, NegotiateRequest, NegotiateResponse, PayloadTranscript, PrintFilterRequest,
+    SavedConfig, SelfTestResult, UnmapDriverRequest, VersionInfo, stealth_name,
+};
+use windows_sys::{
+    Win32::{
+        Devices::DeviceAndDriverInstallation::{
+            CM_GET_DEVICE_INTERFACE_LIST_PRESENT, CM_Get_Device_Interface_List_SizeW,
+            CM_Get_Device_Interface_ListW, CM_MapCrToWin32Err, CONFIGRET, CR_BUFFER_SMALL,
+            CR_SUCCESS,
+        },
+        Foundation::{ERROR_NOT_FOUND, ERROR_NOT_SUPPORTED},
+        System::IO::DeviceIoControl,
+    },
+    core::GUID,
+};
+
+/// An open handle to the device.
+#[derive(Debug)]
+pub struct Device {
+    file: File,
+    /// Whether IOCTLs are sent with correlation IDs.
+    correlated: bool,
+    /// The correlation ID of the last IOCTL sent with one, or zero.
+    last_correlation_id: AtomicU64,
+}
+
+impl Device {
+    /// Opens the device.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the driver is not running or the caller is not an
+    /// administrator.
+    pub fn open() -> io::Result<Self> {
+        Self::open_path(DEVICE_PATH)
+    }
+
+    /// Opens the control device, which accepts `CONTROL_IOCTLS` without
+    /// negotiation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the driver is not running or the caller is not an
+    /// elevated administrator.
+    pub fn open_control() -> io::Result<Self> {
+        Self::open_path(CONTROL_DEVICE_PATH)
+    }
+
+    /// Opens the device through the first present device interface of
+    /// `DEVICE_INTERFACE_GUID`, which does not depend on the name of the
+    /// symbolic link.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `io::ErrorKind::NotFound` if the driver did not
+    /// register the interface, or an error of [`Device::open`].
+    pub fn open_interface() -> io::Result<Self> {
+        let guid = GUID {
+            data1: DEVICE_INTERFACE_GUID.data1,
+            data2: DEVICE_INTERFACE_GUID.data2,
+            data3: DEVICE_INTERFACE_GUID.data3,
+            data4: DEVICE_INTERFACE_GUID.data4,
+        };
+        // The list may grow between the calls.
+        let list = loop {
+            let mut length = 0;
+            check_cr(unsafe {
+                CM_Get_Device_Interface_List_SizeW(
+                    &raw mut length,
+                    &raw const guid,
+                    ptr::null(),
+                    CM_GET_DEVICE_INTERFACE_LIST_PRESENT,
+                )
+            })?;
+            let mut list = vec![0u16; length as usize];
+            let result = unsafe {
+                CM_Get_Device_Interface_ListW(
+                    &raw const guid,
+                    ptr::null(),
+                    list.as_mut_ptr(),
+                    length,
+                    CM_GET_DEVICE_INTERFACE_LIST_PRESENT,
+                )
+            };
+            if result != CR_BUFFER_SMALL {
+                check_cr(result)?;
+                break list;
+            }
+        };
+
+        // The list is of NUL-terminated paths, terminated by an empty one.
+        let length = list.iter().position(|&c| c == 0).unwrap_or(list.len());
+        if length == 0 {
+            return Err(io::Error::from_raw_os_error(ERROR_NOT_FOUND.cast_signed()));
+        }
+        let path = OsString::from_wide(&list[..length]);
+        Self::open_path(&path.to_string_lossy())
+    }
+
+    /// Opens the compatible device, or the control device if `control`, of the
+    /// driver started with `seed` as the `StealthSeed` value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the driver is not running with `seed`, or an error
+    /// of [`Device::open`] or [`Device::open_control`].
+    pub fn open_stealth(seed: u32, control: bool) -> io::Result<Self> {
+        // `BootId` of `KUSER_SHARED_DATA`.
+        let boot_id = unsafe { (0x7ffe_02c4 as *const u32).read_volatile() };
+        let name = stealth_name(seed, boot_id, control);
+        let mut path = r"\\.\".to_owned();
+        path.extend(name.iter().map(|&c| char::from(c)));
+        Self::open_path(&path)
+    }
+
+    /// Opens the device at `path`, records it if recording a trace, and
+    /// enables correlation if [`correlation::CORRELATION_ENV`] is set.
+    fn open_path(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        trace::record_open(file.as_raw_handle(), path);
+        let mut device = Self {
+            file,
+            correlated: false,
+            last_correlation_id: AtomicU64::new(0),
+        };
+        if env::var_os(correlation::CORRELATION_ENV).is_some() {
+            device.enable_correlation()?;
+        }
+        Ok(device)
+    }
+
+    /// Makes [`Device::ioctl`] send `METHOD_BUFFERED` IOCTLs with
+    /// `IOCTL_CORRELATED`, each with a new ID of [`correlation::next_id`].
+    /// Other IOCTLs are sent as is.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error of `ERROR_NOT_SUPPORTED` if the driver does not
+    /// implement `IOCTL_CORRELATED`.
+    pub fn enable_correlation(&mut self) -> io::Result<()> {
+        self.correlated = true;
+        // Older drivers complete unknown IOCTLs without writing anything.
+        let mut version = VersionInfo::default();
+        let result = match self.ioctl(IOCTL_GET_VERSION, &[], as_bytes_mut(&mut version)) {
+            Ok(0) => Err(io::Error::from_raw_os_error(
+                ERROR_NOT_SUPPORTED.cast_signed(),
+            )),
+            result => result.map(drop),
+        };
+        self.correlated = result.is_ok();
+        result
+    }
+
+    /// Returns the correlation ID of the last IOCTL sent with one, if any.
+    #[must_use]
+    pub fn last_correlation_id(&self) -> Option<u64> {
+        match self.last_correlation_id.load(Ordering::Relaxed) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    /// Returns the version and capabilities of the driver.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the driver does not implement the IOCTL.
+    pub fn get_version(&self) -> io::Result<VersionInfo> {
+        let mut version = VersionInfo::default();
+        let _ = self.ioctl(IOCTL_GET_VERSION, &[], as_bytes_mut(&mut version))?;
+        Ok(version)
+    }
+
+    /// Returns the metadata of the build of the driver.
+    ///
+    /// # Errors
+    ///
+    /// Returns `ERROR_NOT_SUPPORTED` if the driver is too old to return it.
+    pub fn get_build_info(&self) -> io::Result<BuildInfo> {
+        let mut output = [0u8; BUILD_INFO_OFFSET + size_of::<BuildInfo>()];
+        let bytes_returned = self.ioctl(IOCTL_GET_VERSION, &[], &mut output)?;
+        if bytes_returned < output.len() {
+            return Err(io::Error::from_raw_os_error(
+                ERROR_NOT_SUPPORTED.cast_signed(),
+            ));
+        }
+        Ok(unsafe {
+            output
+                .as_ptr()
+                .add(BUILD_INFO_OFFSET)
+                .cast::<BuildInfo>()
+                .read_unaligned()
+        })
+    }
+
+    /// Declares that the handle uses `classes`, and returns the granted ones.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle already negotiated or the ABI version of
+    /// the driver differs.
+    pub fn negotiate(&self, classes: u32) -> io::Result<NegotiateResponse> {
+        let request = NegotiateRequest {
+            abi_version: ABI_VERSION,
+            classes,
+        };
+        let mut response = NegotiateResponse::default();
+        let _ = self.ioctl(
+            IOCTL_NEGOTIATE,
+            as_bytes(&request),
+            as_bytes_mut(&mut response),
+        )?;
+        Ok(response)
+    }
+
+    /// Returns the kernel offsets the driver uses.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle did not negotiate.
+    pub fn get_offsets(&self) -> io::Result<KernelOffsets> {
+        let mut offsets = KernelOffsets::default();
+        let _ = self.ioctl(IOCTL_GET_OFFSETS, &[], as_bytes_mut(&mut offsets))?;
+        Ok(offsets)
+    }
+
+    /// Sets the kernel offsets for the running build. Fields that are zero
+    /// keep the offsets the driver uses.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle was not granted `CLASS_KERNEL_MEMORY`, or
+    /// the driver refuses the offsets.
+    pub fn set_offsets(&self, offsets: &KernelOffsets) -> io::Result<()> {
+        let _ = self.ioctl(IOCTL_SET_OFFSETS, as_bytes(offsets), &mut [])?;
+        Ok(())
+    }
+
+    /// Returns the address and size of the kernel module whose file name is
+    /// `name`, e.g., `CI.dll`, or of ntoskrnl.exe if `None`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle was not granted `CLASS_KERNEL_MEMORY`, or
+    /// the module is not loaded.
+    pub fn get_module(&self, name: Option<&str>) -> io::Result<ModuleInfo> {
+        let name = name.unwrap_or_default();
+        let request = ModuleRequest {
+            name_length: name.len() as u32,
+            reserved: 0,
+        };
+        let mut input = as_bytes(&request).to_vec();
+        input.extend_from_slice(name.as_bytes());
+        let mut info = ModuleInfo::default();
+        let _ = self.ioctl(IOCTL_GET_KERNEL_BASE, &input, as_bytes_mut(&mut info))?;
+        Ok(info)
+    }
+
+    /// Maps the driver `image` into kernel memory and calls its entry point.
+    /// `name` identifies the image in [`Device::mapped_drivers`], and must be
+    /// ASCII shorter than 32 bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `name` is too long, the handle was not granted
+    /// `CLASS_EXECUTE`, the driver is not built with the `dangerous` feature,
+    /// the image cannot be mapped, or its entry point fails.
+    pub fn map_driver(&self, name: &str, image: &[u8]) -> io::Result<MappedDriver> {
+        let mut request = MapDriverRequest::default();
+        if !name.is_ascii() || name.len() >= request.name.len() {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        request.name[..name.len()].copy_from_slice(name.as_bytes());
+        let mut input = as_bytes(&request).to_vec();
+        input.extend_from_slice(image);
+        let mut mapped = MappedDriver::default();
+        let _ = self.ioctl(IOCTL_MAP_DRIVER, &input, as_bytes_mut(&mut mapped))?;
+        Ok(mapped)
+    }
+
+    /// Returns the images mapped with [`Device::map_driver`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle was not granted `CLASS_EXECUTE`, or the
+    /// driver is not built with the `dangerous` feature.
+    pub fn mapped_drivers(&self) -> io::Result<Vec<MappedDriver>> {
+        let mut drivers = vec![MappedDriver::default(); MAX_MAPPED_DRIVERS];
+        let output = unsafe {
+            slice::from_raw_parts_mut(drivers.as_mut_ptr().cast(), size_of_val(drivers.as_slice()))
+        };
+        let bytes_returned = self.ioctl(IOCTL_ENUM_MAPPED_DRIVERS, &[], output)?;
+        drivers.truncate(bytes_returned / size_of::<MappedDriver>());
+        Ok(drivers)
+    }
+
+    /// Frees the image mapped at `base` with [`Device::map_driver`]. Nothing of
+    /// the driver, e.g., callbacks or threads, may remain running.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle was not granted `CLASS_EXECUTE`, or no
+    /// image is mapped at `base`.
+    pub fn unmap_driver(&self, base: u64) -> io::Result<()> {
+        let request = UnmapDriverRequest { base };
+        let _ = self.ioctl(IOCTL_UNMAP_DRIVER, as_bytes(&request), &mut [])?;
+        Ok(())
+    }
+
+    /// Runs `shellcode` by writing it to the device, and returns the
+    /// transcript read back. Unlike `IOCTL_RUN_SHELLCODE`, this does not
+    /// require negotiation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `CLASS_EXECUTE` is not enabled, the shellcode is
+    /// refused, or the driver is built with the `defanged` feature, which
+    /// returns no transcript.
+    pub fn stream_shellcode(&self, shellcode: &[u8]) -> io::Result<PayloadTranscript> {
+        let written = (&self.file).write(shellcode)?;
+        if written != shellcode.len() {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        let mut transcript = PayloadTranscript::default();
+        (&self.file).read_exact(as_bytes_mut(&mut transcript))?;
+        Ok(transcript)
+    }
+
+    /// Runs `shellcode` with `IOCTL_RUN_SHELLCODE`, and returns the transcript,
+    /// or `None` if the driver is built with the `defanged` feature, which
+    /// returns none.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle was not granted `CLASS_EXECUTE`, or the
+    /// shellcode is refused.
+    pub fn run_shellcode(&self, shellcode: &[u8]) -> io::Result<Option<PayloadTranscript>> {
+        let mut transcript = PayloadTranscript::default();
+        let bytes_returned = self.ioctl(
+            IOCTL_RUN_SHELLCODE,
+            shellcode,
+            as_bytes_mut(&mut transcript),
+        )?;
+        Ok((bytes_returned == size_of::<PayloadTranscript>()).then_some(transcript))
+    }
+
+    /// Returns the counts of payloads executed and refused, the audit chain,
+    /// and whether the kill switch was sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the driver does not implement the IOCTL.
+    pub fn get_audit(&self) -> io::Result<AuditInfo> {
+        let mut audit = AuditInfo::default();
+        let _ = self.ioctl(IOCTL_GET_AUDIT, &[], as_bytes_mut(&mut audit))?;
+        Ok(audit)
+    }
+
+    /// Returns the counts of pool allocations for each pool tag of the driver.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the driver does not implement the IOCTL.
+    pub fn query_allocations(&self) -> io::Result<Vec<AllocationInfo>> {
+        let mut allocations = vec![AllocationInfo::default(); MAX_POOL_TAGS];
+        let output = unsafe {
+            slice::from_raw_parts_mut(
+                allocations.as_mut_ptr().cast(),
+                size_of_val(allocations.as_slice()),
+            )
+        };
+        let bytes_returned = self.ioctl(IOCTL_QUERY_ALLOCATIONS, &[], output)?;
+        allocations.truncate(bytes_returned / size_of::<AllocationInfo>());
+        Ok(allocations)
+    }
+
+    /// Runs the checks of the internal subsystems of the driver, and returns
+    /// which ones were run and failed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the driver does not implement the IOCTL.
+    pub fn self_test(&self) -> io::Result<SelfTestResult> {
+        let mut result = SelfTestResult::default();
+        let _ = self.ioctl(IOCTL_SELF_TEST, &[], as_bytes_mut(&mut result))?;
+        Ok(result)
+    }
+
+    /// Returns the counters of the branches the driver took, and resets them
+    /// with `reset`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle did not negotiate, or the driver is not
+    /// built with the `coverage` feature.
+    pub fn coverage(&self, reset: bool) -> io::Result<Vec<u32>> {
+        let request = CoverageRequest {
+            reset: u32::from(reset),
+        };
+        let mut counters = vec![0_u32; COVERAGE_COUNTERS];
+        let output = unsafe {
+            slice::from_raw_parts_mut(
+                counters.as_mut_ptr().cast(),
+                size_of_val(counters.as_slice()),
+            )
+        };
+        let bytes_returned = self.ioctl(IOCTL_GET_COVERAGE, as_bytes(&request), output)?;
+        counters.truncate(bytes_returned / size_of::<u32>());
+        Ok(counters)
+    }
+
+    /// Sets when the driver breaks into a kernel debugger to the
+    /// `DEBUG_BREAK_*` mode.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle did not negotiate, or `mode` is invalid.
+    pub fn set_debug_break(&self, mode: u32) -> io::Result<()> {
+        let request = DebugBreakRequest { mode };
+        let _ = self.ioctl(IOCTL_SET_DEBUG_BREAK, as_bytes(&request), &mut [])?;
+        Ok(())
+    }
+
+    /// Sets the levels of the messages the driver prints to the kernel
+    /// debugger, with bit `n` for `messages::LEVEL_*` of `n`, e.g.,
+    /// `1 << LEVEL_ERROR | 1 << LEVEL_WARNING` to silence the messages logged
+    /// for each IOCTL. The event ring still receives all messages.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle did not negotiate.
+    pub fn set_print_filter(&self, level_mask: u32) -> io::Result<()> {
+        let request = PrintFilterRequest { level_mask };
+        let _ = self.ioctl(IOCTL_SET_PRINT_FILTER, as_bytes(&request), &mut [])?;
+        Ok(())
+    }
+
+    /// Saves `config` in the registry, where the driver reads it each time it
+    /// starts, over the values of the service key. The current settings are
+    /// unchanged. Saving a config without fields discards the saved one.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the handle did not negotiate, the version or the
+    /// fields of `config` are invalid, or the registry cannot be written.
+    pub fn save_config(&self, config: &SavedConfig) -> io::Result<()> {
+        let _ = self.ioctl(IOCTL_SAVE_CONFIG, as_bytes(config), &mut [])?;
+        Ok(())
+    }
+
+    /// Sends an IOCTL with `input` to the device, and returns the number of
+    /// bytes written to `output`. With [`Device::enable_correlation`], it is
+    /// sent with a new correlation ID if possible.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error the driver completed the request with.
+    pub fn ioctl(&self, code: u32, input: &[u8], output: &mut [u8]) -> io::Result<usize> {
+        let wrapped = if self.correlated {
+            let id = correlation::next_id();
+            correlation::wrap(id, code, input).map(|wrapped| (id, wrapped))
+        } else {
+            None
+        };
+        let (code, input) = match &wrapped {
+            Some((id, wrapped)) => {
+                self.last_correlation_id.store(*id, Ordering::Relaxed);
+                (IOCTL_CORRELATED, wrapped.as_slice())
+            }
+            None => (code, input),
+        };
+
+        trace::record_ioctl(self.file.as_raw_handle(), code, input, output.len());
+        let mut bytes_returned = 0;
+        let succeeded = unsafe {
+            DeviceIoControl(
+                self.file.as_raw_handle(),
+                code,
+                input.as_ptr().cast(),
+                input.len() as _,
+                output.as_mut_ptr().cast(),
+                output.len() as _,
+                &raw mut bytes_returned,
+                ptr::null_mut(),
+            )
+        };
+        if succeeded == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(bytes_returned as _)
+        }
+    }
+}
+
+/// Converts the result of a configuration manager function to `io::Result`.
+fn check_cr(result: CONFIGRET) -> io::Result<()> {
+    if result == CR_SUCCESS {
+        Ok(())
+    } else {
+        let error = unsafe { CM_MapCrToWin32Err(result, ERROR_NOT_FOUND) };
+        Err(io::Error::from_raw_os_error(error.cast_signed()))
+    }
+}
+
+/// Returns the bytes of `value`.
+fn as_bytes<T: Copy>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(ptr::from_ref(value).cast(), size_of::<T>()) }
+}
+
+/// Returns the bytes of `value` to write. `T` must be valid for any bytes.
+fn as_bytes_mut<T: Copy>(value: &mut T) -> &mut [u8] {
+    unsafe { slice::from_raw_parts_mut(ptr::from_mut(value).cast(), size_of::<T>()) }
+}
@@ -0,0 +1,347 @@
+//! An asynchronous API over overlapped I/O. With it, requests to the driver
+//! and waits for events, such as the one of the event ring, can be awaited
+//! concurrently from one thread with any executor, e.g., tokio.
+//!
+//! [`AsyncDevice`] opens the device for overlapped I/O. A pending request or
+//! wait registers a wait for its event in the thread pool, which wakes the task
+//! once the event is signaled. The driver completes most requests before
+//! `DeviceIoControl` returns, so their futures are ready when first polled.
+//!
+//! Dropping a pending request cancels it and blocks until the driver completes
+//! it, as the driver may write to the buffers until then.
+//!
+//! ```no_run
+//! use capcom_abi::IOCTL_GET_VERSION;
+//! use capcom_client::overlapped::AsyncDevice;
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let device = AsyncDevice::open()?;
+//! let version = device.ioctl(IOCTL_GET_VERSION, Vec::new(), 16).await?;
+//! println!("{version:02x?}");
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    ffi::c_void,
+    fmt,
+    fs::{File, OpenOptions},
+    future::Future,
+    io,
+    marker::PhantomData,
+    mem,
+    os::windows::{
+        fs::OpenOptionsExt,
+        io::{AsRawHandle, BorrowedHandle},
+    },
+    pin::Pin,
+    ptr,
+    task::{Context, Poll},
+};
+
+use capcom_abi::{DEVICE_PATH, signal::Signal};
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle, ERROR_IO_INCOMPLETE, ERROR_IO_PENDING, FALSE, HANDLE, INVALID_HANDLE_VALUE,
+        TRUE,
+    },
+    Storage::FileSystem::FILE_FLAG_OVERLAPPED,
+    System::{
+        IO::{CancelIoEx, DeviceIoControl, GetOverlappedResult, OVERLAPPED},
+        Threading::{
+            CreateEventW, INFINITE, RegisterWaitForSingleObject, UnregisterWaitEx,
+            WT_EXECUTEONLYONCE,
+        },
+    },
+};
+
+use crate::trace;
+
+/// An open handle to the device for overlapped I/O.
+#[derive(Debug)]
+pub struct AsyncDevice(File);
+
+impl AsyncDevice {
+    /// Opens the device.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the driver is not running or the caller is not an
+    /// administrator.
+    pub fn open() -> io::Result<Self> {
+        Self::open_path(DEVICE_PATH)
+    }
+
+    /// Opens the device at `path`, e.g., the control device, and records it if
+    /// recording a trace.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device cannot be opened.
+    pub fn open_path(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(FILE_FLAG_OVERLAPPED)
+            .open(path)?;
+        trace::record_open(file.as_raw_handle(), path);
+        Ok(Self(file))
+    }
+
+    /// Sends an IOCTL with `input` to the device, and returns the future of
+    /// the output buffer of `output_length` bytes, truncated to the bytes
+    /// written. The request is sent before this returns, so requests are
+    /// pended concurrently even if the futures are polled one by one.
+    pub fn ioctl(&self, code: u32, input: Vec<u8>, output_length: usize) -> Ioctl<'_> {
+        trace::record_ioctl(self.0.as_raw_handle(), code, &input, output_length);
+        let operation = Operation::start(&self.0, code, input, output_length);
+        Ioctl {
+            device: PhantomData,
+            operation: operation.map_err(Some),
+        }
+    }
+}
+
+/// The future of a request sent with [`AsyncDevice::ioctl`].
+#[derive(Debug)]
+#[must_use = "dropping the future cancels the request"]
+pub struct Ioctl<'a> {
+    device: PhantomData<&'a AsyncDevice>,
+    /// The pending request, or the error it failed to be sent with. Taken
+    /// when returned.
+    operation: Result<Box<Operation>, Option<io::Error>>,
+}
+
+impl Future for Ioctl<'_> {
+    type Output = io::Result<Vec<u8>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let operation = match &mut self.operation {
+            Ok(operation) => operation,
+            Err(err) => {
+                return Poll::Ready(Err(err.take().expect("`Ioctl` polled after completion")));
+            }
+        };
+        let result = match operation.registration.poll(operation.overlapped.hEvent, cx) {
+            Ok(_) => operation.result(),
+            Err(err) => Some(Err(err)),
+        };
+        match result {
+            Some(result) => {
+                self.operation = Err(None);
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// A request sent with overlapped I/O, and the buffers it uses until it
+/// completes. It is boxed, as the driver and the thread pool use the
+/// addresses.
+struct Operation {
+    handle: HANDLE,
+    overlapped: OVERLAPPED,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    registration: Registration,
+    completed: bool,
+}
+
+impl Operation {
+    /// Sends the IOCTL `code` with `input` through `file`.
+    fn start(
+        file: &File,
+        code: u32,
+        input: Vec<u8>,
+        output_length: usize,
+    ) -> io::Result<Box<Self>> {
+        let event = unsafe { CreateEventW(ptr::null(), TRUE, FALSE, ptr::null()) };
+        if event.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let mut operation = Box::new(Self {
+            handle: file.as_raw_handle(),
+            overlapped: OVERLAPPED {
+                hEvent: event,
+                ..OVERLAPPED::default()
+            },
+            input,
+            output: vec![0; output_length],
+            registration: Registration::default(),
+            completed: false,
+        });
+        let succeeded = unsafe {
+            DeviceIoControl(
+                operation.handle,
+                code,
+                operation.input.as_ptr().cast(),
+                operation.input.len() as _,
+                operation.output.as_mut_ptr().cast(),
+                operation.output.len() as _,
+                ptr::null_mut(),
+                &raw mut operation.overlapped,
+            )
+        };
+        if succeeded == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_IO_PENDING.cast_signed()) {
+                operation.completed = true;
+                return Err(err);
+            }
+        }
+        Ok(operation)
+    }
+
+    /// Returns the output buffer if the request completed successfully, the
+    /// error if it failed, or `None` if it is pending.
+    fn result(&mut self) -> Option<io::Result<Vec<u8>>> {
+        let mut bytes_returned = 0;
+        let succeeded = unsafe {
+            GetOverlappedResult(
+                self.handle,
+                &raw const self.overlapped,
+                &raw mut bytes_returned,
+                FALSE,
+            )
+        };
+        if succeeded == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(ERROR_IO_INCOMPLETE.cast_signed()) {
+                return None;
+            }
+            self.completed = true;
+            return Some(Err(err));
+        }
+        self.completed = true;
+        let mut output = mem::take(&mut self.output);
+        output.truncate(bytes_returned as usize);
+        Some(Ok(output))
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        if !self.completed {
+            let mut bytes_returned = 0;
+            unsafe {
+                let _ = CancelIoEx(self.handle, &raw const self.overlapped);
+                let _ = GetOverlappedResult(
+                    self.handle,
+                    &raw const self.overlapped,
+                    &raw mut bytes_returned,
+                    TRUE,
+                );
+            }
+        }
+        self.registration.unregister();
+        let _ = unsafe { CloseHandle(self.overlapped.hEvent) };
+    }
+}
+
+impl fmt::Debug for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Operation")
+            .field("completed", &self.completed)
+            .finish_non_exhaustive()
+    }
+}
+
+unsafe impl Send for Operation {}
+
+/// Returns the future of `event` being signaled. For an auto-reset event, such
+/// as the one of the event ring, the wait resets it.
+pub fn wait(event: BorrowedHandle<'_>) -> Wait<'_> {
+    Wait {
+        event,
+        registration: Registration::default(),
+    }
+}
+
+/// The future of [`wait`].
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct Wait<'a> {
+    event: BorrowedHandle<'a>,
+    registration: Registration,
+}
+
+impl Future for Wait<'_> {
+    type Output = io::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let event = self.event.as_raw_handle();
+        match self.registration.poll(event, cx) {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => Poll::Pending,
+            Err(err) => Poll::Ready(Err(err)),
+        }
+    }
+}
+
+/// A wait for an event registered in the thread pool when first polled, which
+/// wakes the task that polled last once the event is signaled.
+#[derive(Debug)]
+struct Registration {
+    /// The signal the callback sets. It is boxed, as the thread pool uses the
+    /// address.
+    signal: Box<Signal>,
+    wait: HANDLE,
+}
+
+impl Default for Registration {
+    fn default() -> Self {
+        Self {
+            signal: Box::default(),
+            wait: ptr::null_mut(),
+        }
+    }
+}
+
+impl Registration {
+    /// Makes the task of `cx` woken once `event` is signaled, registering the
+    /// wait if not yet, and returns whether it was signaled.
+    fn poll(&mut self, event: HANDLE, cx: &Context<'_>) -> io::Result<bool> {
+        // Register the waker first, so that a signal after the check wakes it.
+        let signaled = self.signal.register(cx.waker());
+        if self.wait.is_null() {
+            let succeeded = unsafe {
+                RegisterWaitForSingleObject(
+                    &raw mut self.wait,
+                    event,
+                    Some(wake),
+                    ptr::from_ref(&*self.signal).cast_mut().cast(),
+                    INFINITE,
+                    WT_EXECUTEONLYONCE,
+                )
+            };
+            if succeeded == 0 {
+                self.wait = ptr::null_mut();
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(signaled)
+    }
+
+    /// Unregisters the wait, waiting for the callback to return if running.
+    fn unregister(&mut self) {
+        if !self.wait.is_null() {
+            let _ = unsafe { UnregisterWaitEx(self.wait, INVALID_HANDLE_VALUE) };
+            self.wait = ptr::null_mut();
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.unregister();
+    }
+}
+
+unsafe impl Send for Registration {}
+
+/// Called by the thread pool once the event of the [`Signal`] at `context` is
+/// signaled.
+unsafe extern "system" fn wake(context: *mut c_void, _timed_out: bool) {
+    unsafe { &*context.cast::<Signal>() }.set();
+}
@@ -0,0 +1,223 @@
+//! Resolution of [`KernelOffsets`] from the PDBs of ntoskrnl.exe and CI.dll
+//! downloaded from the Microsoft symbol server, so features depending on the
+//! offsets work on builds of Windows the driver has no built-in offsets for.
+//!
+//! PDBs are kept in a directory with the symbol store layout, and downloaded
+//! only once for each version of the files.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    iter,
+    os::windows::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    ptr,
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use capcom_abi::KernelOffsets;
+use object::Object;
+use pdb::{FallibleIterator, PDB, SymbolData, TypeData};
+use uuid::Uuid;
+use windows_sys::{
+    Wdk::System::SystemServices::RtlGetVersion,
+    Win32::System::{
+        Com::Urlmon::URLDownloadToFileW,
+        SystemInformation::{GetSystemDirectoryW, OSVERSIONINFOW},
+    },
+};
+
+/// The URL of the Microsoft symbol server.
+const SYMBOL_SERVER: &str = "https://msdl.microsoft.com/download/symbols";
+
+/// Resolves the offsets for the running build of Windows. PDBs are downloaded
+/// into and reused from `cache_dir`.
+///
+/// # Errors
+///
+/// Returns an error if the PDBs cannot be downloaded or lack any of the
+/// offsets.
+pub fn resolve_offsets(cache_dir: &Path) -> Result<KernelOffsets> {
+    let system_dir = system_directory()?;
+
+    let pdb_path = download_pdb(&system_dir.join("ntoskrnl.exe"), cache_dir)?;
+    let mut pdb = PDB::open(File::open(&pdb_path)?)?;
+    let eprocess = field_offsets(&mut pdb, "_EPROCESS")?;
+    let kthread = field_offsets(&mut pdb, "_KTHREAD")?;
+    let token = field_offsets(&mut pdb, "_TOKEN")?;
+    let nt_globals = global_rvas(&mut pdb)?;
+
+    let pdb_path = download_pdb(&system_dir.join("ci.dll"), cache_dir)?;
+    let mut pdb = PDB::open(File::open(&pdb_path)?)?;
+    let ci_globals = global_rvas(&mut pdb)?;
+
+    let field = |fields: &HashMap<String, u32>, structure: &str, name: &str| {
+        fields
+            .get(name)
+            .copied()
+            .with_context(|| format!("{structure}::{name} is not found"))
+    };
+    let global = |globals: &HashMap<String, u32>, name: &str| {
+        globals
+            .get(name)
+            .copied()
+            .with_context(|| format!("{name} is not found"))
+    };
+    Ok(KernelOffsets {
+        build_number: build_number()?,
+        eprocess_unique_process_id: field(&eprocess, "_EPROCESS", "UniqueProcessId")?,
+        eprocess_active_process_links: field(&eprocess, "_EPROCESS", "ActiveProcessLinks")?,
+        eprocess_token: field(&eprocess, "_EPROCESS", "Token")?,
+        eprocess_signature_level: field(&eprocess, "_EPROCESS", "SignatureLevel")?,
+        eprocess_protection: field(&eprocess, "_EPROCESS", "Protection")?,
+        kthread_previous_mode: field(&kthread, "_KTHREAD", "PreviousMode")?,
+        kthread_kernel_stack: field(&kthread, "_KTHREAD", "KernelStack")?,
+        token_privileges: field(&token, "_TOKEN", "Privileges")?,
+        psp_create_process_notify_routine: global(&nt_globals, "PspCreateProcessNotifyRoutine")?,
+        psp_create_thread_notify_routine: global(&nt_globals, "PspCreateThreadNotifyRoutine")?,
+        psp_load_image_notify_routine: global(&nt_globals, "PspLoadImageNotifyRoutine")?,
+        ci_options: global(&ci_globals, "g_CiOptions")?,
+    })
+}
+
+/// Resolves the offsets for the running build of Windows and sets them to the
+/// driver through `device`, and returns them.
+///
+/// # Errors
+///
+/// Returns an error if the offsets cannot be resolved or the driver refuses
+/// them.
+pub fn upload_offsets(device: &crate::Device, cache_dir: &Path) -> Result<KernelOffsets> {
+    let offsets = resolve_offsets(cache_dir)?;
+    device
+        .set_offsets(&offsets)
+        .context("the driver refused the offsets")?;
+    Ok(offsets)
+}
+
+/// Downloads the PDB of the image at `image_path` into `cache_dir` unless it
+/// is already there, and returns its path.
+fn download_pdb(image_path: &Path, cache_dir: &Path) -> Result<PathBuf> {
+    let data = fs::read(image_path)?;
+    let image = object::File::parse(&*data)?;
+    let code_view = image
+        .pdb_info()?
+        .with_context(|| format!("{} has no CodeView record", image_path.display()))?;
+    // The path may be the full path on the build machine.
+    let pdb_name = String::from_utf8_lossy(code_view.path())
+        .rsplit('\\')
+        .next()
+        .unwrap_or_default()
+        .to_owned();
+    let id = format!(
+        "{}{:X}",
+        Uuid::from_bytes_le(code_view.guid())
+            .simple()
+            .to_string()
+            .to_uppercase(),
+        code_view.age()
+    );
+
+    let pdb_dir = cache_dir.join(&pdb_name).join(&id);
+    let pdb_path = pdb_dir.join(&pdb_name);
+    if pdb_path.exists() {
+        return Ok(pdb_path);
+    }
+    fs::create_dir_all(&pdb_dir)?;
+    let url = format!("{SYMBOL_SERVER}/{pdb_name}/{id}/{pdb_name}");
+    let url_w: Vec<u16> = url.encode_utf16().chain(iter::once(0)).collect();
+    let path_w: Vec<u16> = pdb_path
+        .as_os_str()
+        .encode_wide()
+        .chain(iter::once(0))
+        .collect();
+    let result = unsafe {
+        URLDownloadToFileW(
+            ptr::null_mut(),
+            url_w.as_ptr(),
+            path_w.as_ptr(),
+            0,
+            ptr::null_mut(),
+        )
+    };
+    if result != 0 {
+        let _unused = fs::remove_file(&pdb_path);
+        bail!("could not download {url}: {result:#x}");
+    }
+    Ok(pdb_path)
+}
+
+/// Returns the offsets of the fields of the structure `name`, keyed by the
+/// field names.
+fn field_offsets(pdb: &mut PDB<'_, File>, name: &str) -> Result<HashMap<String, u32>> {
+    let type_information = pdb.type_information()?;
+    let mut finder = type_information.finder();
+    let mut types = type_information.iter();
+    while let Some(item) = types.next()? {
+        finder.update(&types);
+        let Ok(TypeData::Class(class)) = item.parse() else {
+            continue;
+        };
+        if class.properties.forward_reference() || class.name.as_bytes() != name.as_bytes() {
+            continue;
+        }
+
+        let mut offsets = HashMap::new();
+        let mut next = class.fields;
+        while let Some(index) = next {
+            let TypeData::FieldList(list) = finder.find(index)?.parse()? else {
+                bail!("{name} has an unexpected field list");
+            };
+            for field in list.fields {
+                if let TypeData::Member(member) = field {
+                    let _ =
+                        offsets.insert(member.name.to_string().into_owned(), member.offset as u32);
+                }
+            }
+            next = list.continuation;
+        }
+        return Ok(offsets);
+    }
+    bail!("{name} is not found")
+}
+
+/// Returns the RVAs of the public and global data symbols, keyed by the names.
+fn global_rvas(pdb: &mut PDB<'_, File>) -> Result<HashMap<String, u32>> {
+    let address_map = pdb.address_map()?;
+    let symbol_table = pdb.global_symbols()?;
+    let mut symbols = symbol_table.iter();
+    let mut rvas = HashMap::new();
+    while let Some(symbol) = symbols.next()? {
+        let (name, offset) = match symbol.parse() {
+            Ok(SymbolData::Public(public)) => (public.name, public.offset),
+            Ok(SymbolData::Data(data)) => (data.name, data.offset),
+            _ => continue,
+        };
+        if let Some(rva) = offset.to_rva(&address_map) {
+            let _ = rvas.insert(name.to_string().into_owned(), rva.0);
+        }
+    }
+    Ok(rvas)
+}
+
+/// Returns the path of the system directory, e.g., `C:\Windows\System32`.
+fn system_directory() -> Result<PathBuf> {
+    let mut buffer = [0u16; 260];
+    let length = unsafe { GetSystemDirectoryW(buffer.as_mut_ptr(), buffer.len() as _) } as usize;
+    ensure!(
+        length != 0 && length < buffer.len(),
+        "could not get the system directory"
+    );
+    Ok(PathBuf::from(String::from_utf16(&buffer[..length])?))
+}
+
+/// Returns the build number of the running Windows.
+fn build_number() -> Result<u32> {
+    let mut version = OSVERSIONINFOW {
+        dwOSVersionInfoSize: size_of::<OSVERSIONINFOW>() as _,
+        ..unsafe { std::mem::zeroed() }
+    };
+    let status = unsafe { RtlGetVersion(&raw mut version) };
+    ensure!(status >= 0, "RtlGetVersion failed: {status:#x}");
+    Ok(version.dwBuildNumber)
+}
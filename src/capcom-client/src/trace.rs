@@ -0,0 +1,295 @@
+//! Recording the IOCTLs sent to the driver into a trace file, and replaying
+//! them, so that a crash found by the in-guest tests or fuzzing can be
+//! reproduced on a fresh target.
+//!
+//! Recording starts with [`start`], or when the [`TRACE_ENV`] environment
+//! variable is set to the path of the trace file, in which case
+//! [`start_from_env`] returns the error if the file cannot be created.
+//! [`Device`] records the devices it opens and the IOCTLs it sends, and
+//! programs that send IOCTLs through their own handles record them with
+//! [`record_open`] and [`record_ioctl`]. Each record is flushed to disk before
+//! the IOCTL is sent, so the trace survives the crash it leads to, at the cost
+//! of a disk write per IOCTL.
+//!
+//! A trace file is text with a record per line of tab-separated fields, after
+//! a header line starting with `#`:
+//!
+//! - `open`, the handle number, and the path the device was opened with, or
+//!   nothing if unknown.
+//! - `ioctl`, the microseconds since recording started, the handle number, the
+//!   IOCTL code in hex, the size of the output buffer, and the input buffer in
+//!   hex.
+//!
+//! Handle numbers are assigned in the order handles are first seen. Buffers
+//! are replayed as recorded, so addresses in them, e.g., of payloads, refer to
+//! the memory of the recording process.
+//!
+//! ```no_run
+//! use capcom_client::trace;
+//!
+//! let records = trace::parse(&std::fs::read_to_string("capcom.trace")?)?;
+//! trace::replay(&records, true, |index, _, result| println!("{index}: {result:?}"))?;
+//! # anyhow::Ok(())
+//! ```
+
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    env,
+    fmt::Write as _,
+    fs::File,
+    io::{self, Write},
+    os::windows::io::RawHandle,
+    path::Path,
+    sync::{Mutex, MutexGuard, Once, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use capcom_abi::DEVICE_PATH;
+
+use crate::Device;
+
+/// The environment variable that starts recording into the file at its value.
+pub const TRACE_ENV: &str = "CAPCOM_TRACE";
+
+/// The first line of a trace file.
+const HEADER: &str = "# capcom trace 1";
+
+/// A record of a trace file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    /// A handle was opened.
+    Open {
+        /// The number of the handle.
+        handle: u32,
+        /// The path the device was opened with. Empty if unknown.
+        path: String,
+    },
+    /// An IOCTL was sent.
+    Ioctl {
+        /// The time since recording started.
+        elapsed: Duration,
+        /// The number of the handle the IOCTL was sent through.
+        handle: u32,
+        /// The IOCTL code.
+        code: u32,
+        /// The input buffer.
+        input: Vec<u8>,
+        /// The size of the output buffer.
+        output_length: usize,
+    },
+}
+
+/// The trace file being written.
+#[derive(Debug)]
+struct Recorder {
+    file: File,
+    start: Instant,
+    /// The numbers of the open handles by their values.
+    handles: HashMap<usize, u32>,
+    next_handle: u32,
+}
+
+impl Recorder {
+    /// Assigns a new number to `handle`.
+    fn open(&mut self, handle: RawHandle) -> u32 {
+        let number = self.next_handle;
+        self.next_handle += 1;
+        let _ = self.handles.insert(handle.addr(), number);
+        number
+    }
+
+    /// Writes `line` and flushes it to disk. Errors are ignored, as recording
+    /// must not fail the IOCTL.
+    fn write(&mut self, line: &str) {
+        let _unused = self.file.write_all(line.as_bytes());
+        let _unused = self.file.sync_data();
+    }
+}
+
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+
+/// The error of starting recording into the file of [`TRACE_ENV`], until
+/// [`start_from_env`] returns it.
+static ENV_ERROR: Mutex<Option<io::Error>> = Mutex::new(None);
+
+/// Starts recording into a new file at `path`, replacing the file being
+/// recorded into, if any.
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be created.
+pub fn start(path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    writeln!(file, "{HEADER}")?;
+    *RECORDER.lock().unwrap_or_else(PoisonError::into_inner) = Some(Recorder {
+        file,
+        start: Instant::now(),
+        handles: HashMap::new(),
+        next_handle: 0,
+    });
+    Ok(())
+}
+
+/// Starts recording into the file at the value of [`TRACE_ENV`] if it is set
+/// and [`start`] has not been called, and returns whether recording. Recording
+/// otherwise starts from the environment when the first handle is recorded.
+///
+/// # Errors
+///
+/// Returns the error of creating the file of [`TRACE_ENV`], including when
+/// recording failed to start before this call. The error is returned once.
+pub fn start_from_env() -> io::Result<bool> {
+    let recording = lock().is_some();
+    match ENV_ERROR
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .take()
+    {
+        Some(err) => Err(err),
+        None => Ok(recording),
+    }
+}
+
+/// Records that `handle` was opened with `path`, if recording.
+pub fn record_open(handle: RawHandle, path: &str) {
+    let mut recorder = lock();
+    let Some(recorder) = recorder.as_mut() else {
+        return;
+    };
+    let number = recorder.open(handle);
+    recorder.write(&format!("open\t{number}\t{path}\n"));
+}
+
+/// Records that the IOCTL `code` is sent through `handle` with `input` and the
+/// output buffer of `output_length` bytes, if recording.
+pub fn record_ioctl(handle: RawHandle, code: u32, input: &[u8], output_length: usize) {
+    let mut recorder = lock();
+    let Some(recorder) = recorder.as_mut() else {
+        return;
+    };
+    let number = if let Some(&number) = recorder.handles.get(&handle.addr()) {
+        number
+    } else {
+        let number = recorder.open(handle);
+        recorder.write(&format!("open\t{number}\t\n"));
+        number
+    };
+    let mut line = format!(
+        "ioctl\t{}\t{number}\t{code:08x}\t{output_length}\t",
+        recorder.start.elapsed().as_micros()
+    );
+    for byte in input {
+        let _ = write!(line, "{byte:02x}");
+    }
+    line.push('\n');
+    recorder.write(&line);
+}
+
+/// Locks [`RECORDER`], starting recording first if [`TRACE_ENV`] is set and
+/// [`start`] has not been called. The error of starting is kept in
+/// [`ENV_ERROR`].
+fn lock() -> MutexGuard<'static, Option<Recorder>> {
+    static FROM_ENV: Once = Once::new();
+
+    FROM_ENV.call_once(|| {
+        let Some(path) = env::var_os(TRACE_ENV) else {
+            return;
+        };
+        let started = RECORDER
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .is_some();
+        if !started && let Err(err) = start(Path::new(&path)) {
+            *ENV_ERROR.lock().unwrap_or_else(PoisonError::into_inner) = Some(err);
+        }
+    });
+    RECORDER.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Parses the trace file `text`.
+///
+/// # Errors
+///
+/// Returns an error of `io::ErrorKind::InvalidData` if a line is malformed.
+pub fn parse(text: &str) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let record = parse_record(line).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("line {} is malformed", index + 1),
+            )
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+fn parse_record(line: &str) -> Option<Record> {
+    let fields: Vec<_> = line.split('\t').collect();
+    match fields[..] {
+        ["open", handle, path] => Some(Record::Open {
+            handle: handle.parse().ok()?,
+            path: path.to_owned(),
+        }),
+        ["ioctl", micros, handle, code, output_length, input] => Some(Record::Ioctl {
+            elapsed: Duration::from_micros(micros.parse().ok()?),
+            handle: handle.parse().ok()?,
+            code: u32::from_str_radix(code, 16).ok()?,
+            input: (0..input.len())
+                .step_by(2)
+                .map(|offset| u8::from_str_radix(input.get(offset..offset + 2)?, 16).ok())
+                .collect::<Option<_>>()?,
+            output_length: output_length.parse().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+/// Sends the IOCTLs of `records` in order, each through a new handle of the
+/// device its handle was opened with, and calls `report` with the index of the
+/// record and the result. With `timing`, waits until the time each IOCTL was
+/// sent since the start of recording.
+///
+/// # Errors
+///
+/// Returns an error if a device cannot be opened.
+pub fn replay(
+    records: &[Record],
+    timing: bool,
+    mut report: impl FnMut(usize, &Record, &io::Result<usize>),
+) -> io::Result<()> {
+    let start = Instant::now();
+    let mut devices = HashMap::new();
+    for (index, record) in records.iter().enumerate() {
+        match record {
+            Record::Open { handle, path } => {
+                let path = if path.is_empty() { DEVICE_PATH } else { path };
+                let _unused = devices.insert(*handle, Device::open_path(path)?);
+            }
+            Record::Ioctl {
+                elapsed,
+                handle,
+                code,
+                input,
+                output_length,
+            } => {
+                if timing && let Some(wait) = elapsed.checked_sub(start.elapsed()) {
+                    thread::sleep(wait);
+                }
+                let device = match devices.entry(*handle) {
+                    Entry::Occupied(entry) => entry.into_mut(),
+                    Entry::Vacant(entry) => entry.insert(Device::open()?),
+                };
+                let mut output = vec![0; *output_length];
+                let result = device.ioctl(*code, input, &mut output);
+                report(index, record, &result);
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! A tiny driver that is not signed, for testing `IOCTL_MAP_DRIVER` end to
+//! end. capcom-test maps it, opens the device it creates, and tears it down
+//! with [`IOCTL_TEAR_DOWN`] before unmapping it.
+//!
+//! The entry point is called with no driver object and registry path, so it
+//! creates a driver object with `IoCreateDriver` and does the rest of the work
+//! in the initialization routine, like loaders of manually mapped drivers do.
+#![no_std]
+
+use core::ptr;
+
+use capcom_abi::map_test::{
+    DEVICE_NAME_UTF16, DEVICE_TYPE, DRIVER_NAME_UTF16, IOCTL_TEAR_DOWN, LINK_NAME_UTF16,
+};
+use wdk_sys::{
+    DRIVER_OBJECT, FALSE, IO_NO_INCREMENT, IRP_MJ_CLEANUP, IRP_MJ_CLOSE, IRP_MJ_CREATE,
+    IRP_MJ_DEVICE_CONTROL, NT_SUCCESS, NTSTATUS, PDEVICE_OBJECT, PDRIVER_INITIALIZE,
+    PDRIVER_OBJECT, PIRP, PUNICODE_STRING, STATUS_INVALID_DEVICE_REQUEST, STATUS_SUCCESS, ULONG,
+    UNICODE_STRING,
+    ntddk::{
+        IoCreateDevice, IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink,
+        IofCompleteRequest, KeBugCheck, ObMakeTemporaryObject,
+    },
+};
+
+unsafe extern "system" {
+    fn IoCreateDriver(
+        driver_name: PUNICODE_STRING,
+        initialization_function: PDRIVER_INITIALIZE,
+    ) -> NTSTATUS;
+}
+
+/// The entry point, called by the mapper with no driver object and registry
+/// path.
+#[unsafe(export_name = "DriverEntry")]
+extern "system" fn driver_entry(
+    _driver: PDRIVER_OBJECT,
+    _registry_path: PUNICODE_STRING,
+) -> NTSTATUS {
+    let mut driver_name = RTL_CONSTANT_STRING(&DRIVER_NAME_UTF16);
+    unsafe { IoCreateDriver(&raw mut driver_name, Some(driver_init)) }
+}
+
+/// Creates the device and the symbolic link to it, and sets up the dispatch
+/// routines of `driver` created by `IoCreateDriver`.
+extern "C" fn driver_init(driver: PDRIVER_OBJECT, _registry_path: PUNICODE_STRING) -> NTSTATUS {
+    unsafe {
+        let mut device_name = RTL_CONSTANT_STRING(&DEVICE_NAME_UTF16);
+        let mut device = ptr::null_mut();
+        let status = IoCreateDevice(
+            driver,
+            0,
+            &raw mut device_name,
+            DEVICE_TYPE,
+            0,
+            FALSE as _,
+            &raw mut device,
+        );
+        if !NT_SUCCESS(status) {
+            return status;
+        }
+
+        let mut link_name = RTL_CONSTANT_STRING(&LINK_NAME_UTF16);
+        let status = IoCreateSymbolicLink(&raw mut link_name, &raw mut device_name);
+        if !NT_SUCCESS(status) {
+            IoDeleteDevice(device);
+            return status;
+        }
+
+        let driver: &mut DRIVER_OBJECT = &mut *driver;
+        driver.MajorFunction[IRP_MJ_CREATE as usize] = Some(driver_create_close);
+        driver.MajorFunction[IRP_MJ_CLEANUP as usize] = Some(driver_create_close);
+        driver.MajorFunction[IRP_MJ_CLOSE as usize] = Some(driver_create_close);
+        driver.MajorFunction[IRP_MJ_DEVICE_CONTROL as usize] = Some(driver_ioctl);
+    }
+    STATUS_SUCCESS
+}
+
+/// Handles the open, cleanup and close requests, which need no work.
+extern "C" fn driver_create_close(_device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    unsafe { complete_request(irp, STATUS_SUCCESS) }
+}
+
+/// Handles [`IOCTL_TEAR_DOWN`]. The device object remains until the handle the
+/// request came through is closed.
+extern "C" fn driver_ioctl(device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    unsafe {
+        let stack = (*irp)
+            .Tail
+            .Overlay
+            .__bindgen_anon_2
+            .__bindgen_anon_1
+            .CurrentStackLocation;
+        if (*stack).Parameters.DeviceIoControl.IoControlCode != IOCTL_TEAR_DOWN {
+            return complete_request(irp, STATUS_INVALID_DEVICE_REQUEST);
+        }
+
+        let mut link_name = RTL_CONSTANT_STRING(&LINK_NAME_UTF16);
+        let _ = IoDeleteSymbolicLink(&raw mut link_name);
+        ObMakeTemporaryObject((*device).DriverObject.cast());
+        IoDeleteDevice(device);
+        complete_request(irp, STATUS_SUCCESS)
+    }
+}
+
+/// Completes `irp` with `status` and no information.
+unsafe fn complete_request(irp: PIRP, status: NTSTATUS) -> NTSTATUS {
+    unsafe {
+        (*irp).IoStatus.__bindgen_anon_1.Status = status;
+        (*irp).IoStatus.Information = 0;
+        IofCompleteRequest(irp, IO_NO_INCREMENT as _);
+    }
+    status
+}
+
+/// Builds UNICODE_STRING with the UTF-16 string.
+#[expect(non_snake_case, clippy::inline_always)]
+#[inline(always)]
+fn RTL_CONSTANT_STRING(utf16: &[u16]) -> UNICODE_STRING {
+    let length_in_bytes = (utf16.len() * 2) as u16;
+    UNICODE_STRING {
+        Length: length_in_bytes,
+        MaximumLength: length_in_bytes,
+        Buffer: utf16.as_ptr().cast_mut(),
+    }
+}
+
+/// Handles panic by bug checking.
+#[cfg(not(test))]
+#[panic_handler]
+fn handle_panic(_info: &core::panic::PanicInfo<'_>) -> ! {
+    const MANUALLY_INITIATED_CRASH: ULONG = 0x0000_00e2;
+
+    unsafe { KeBugCheck(MANUALLY_INITIATED_CRASH) };
+}
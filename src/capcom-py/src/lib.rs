@@ -0,0 +1,161 @@
+//! Python bindings of `capcom-client`, so that tools and notebooks in Python
+//! drive the device through the same structures as the Rust client instead of
+//! marshaling them with ctypes.
+//!
+//! ```python
+//! import capcom
+//!
+//! device = capcom.Device()
+//! device.negotiate(capcom.CLASS_KERNEL_MEMORY | capcom.CLASS_EXECUTE)
+//! base, size = device.get_module()
+//! assert device.read(base, 2) == b"MZ"
+//! transcript = device.exec(b"\xc3")
+//! ```
+//!
+//! Errors of the driver are raised as `OSError` with the Windows error code in
+//! `winerror`.
+
+use capcom_abi::{
+    CLASS_ALL, CLASS_ELEVATION, CLASS_EXECUTE, CLASS_KERNEL_MEMORY, CLASS_MSR,
+    CLASS_PHYSICAL_MEMORY, RegisterState,
+};
+use capcom_client::{
+    Device,
+    kernel_memory::{KernelMem, Pattern},
+};
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyDict},
+};
+
+/// An open handle to the device.
+#[pyclass(name = "Device", module = "capcom", frozen)]
+struct PyDevice(Device);
+
+#[pymethods]
+impl PyDevice {
+    /// Opens the device, or the control device with `control`.
+    #[new]
+    #[pyo3(signature = (control = false))]
+    fn open(control: bool) -> PyResult<Self> {
+        let device = if control {
+            Device::open_control()?
+        } else {
+            Device::open()?
+        };
+        Ok(Self(device))
+    }
+
+    /// Returns the ABI version, the capabilities and the enabled classes of
+    /// the driver.
+    fn get_version(&self) -> PyResult<(u32, u32, u32)> {
+        let version = self.0.get_version()?;
+        Ok((
+            version.abi_version,
+            version.capabilities,
+            version.enabled_classes,
+        ))
+    }
+
+    /// Requests the `CLASS_*` flags for the handle, and returns those granted.
+    fn negotiate(&self, classes: u32) -> PyResult<u32> {
+        Ok(self.0.negotiate(classes)?.granted_classes)
+    }
+
+    /// Returns the address and size of the kernel module whose file name is
+    /// `name`, or of ntoskrnl.exe if `None`.
+    #[pyo3(signature = (name = None))]
+    fn get_module(&self, name: Option<&str>) -> PyResult<(u64, u32)> {
+        let module = self.0.get_module(name)?;
+        Ok((module.base, module.size))
+    }
+
+    /// Reads `length` bytes of kernel memory at `address`. The result is
+    /// shorter if a page cannot be read.
+    fn read<'py>(
+        &self,
+        py: Python<'py>,
+        address: u64,
+        length: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let mut buffer = vec![0; length];
+        let read = py.allow_threads(|| KernelMem::new(&self.0).read(address, &mut buffer))?;
+        Ok(PyBytes::new(py, &buffer[..read]))
+    }
+
+    /// Returns the addresses in `start` to `end` where `pattern`, e.g.,
+    /// `"48 8b ?? 05"`, matches, or physical addresses with `physical`.
+    #[pyo3(signature = (start, end, pattern, physical = false))]
+    fn scan(
+        &self,
+        py: Python<'_>,
+        start: u64,
+        end: u64,
+        pattern: &str,
+        physical: bool,
+    ) -> PyResult<Vec<u64>> {
+        let pattern = Pattern::parse(pattern)
+            .ok_or_else(|| PyValueError::new_err(format!("invalid pattern {pattern:?}")))?;
+        let memory = KernelMem::new(&self.0);
+        let matches = py.allow_threads(|| {
+            if physical {
+                memory.scan_physical(start..end, &pattern)
+            } else {
+                memory.scan(start..end, &pattern)
+            }
+        })?;
+        Ok(matches)
+    }
+
+    /// Runs `shellcode` in kernel mode, and returns the registers before and
+    /// after it as a dictionary, or `None` from a defanged build.
+    fn exec<'py>(&self, py: Python<'py>, shellcode: &[u8]) -> PyResult<Option<Bound<'py, PyDict>>> {
+        let transcript = py.allow_threads(|| self.0.run_shellcode(shellcode))?;
+        let Some(transcript) = transcript else {
+            return Ok(None);
+        };
+        let dict = PyDict::new(py);
+        dict.set_item("before", registers(py, &transcript.before)?)?;
+        dict.set_item("after", registers(py, &transcript.after)?)?;
+        Ok(Some(dict))
+    }
+
+    /// Sends the IOCTL `code` with `input`, and returns the bytes written to
+    /// the output buffer of `output_length` bytes.
+    #[pyo3(signature = (code, input = b"".as_slice(), output_length = 0))]
+    fn ioctl<'py>(
+        &self,
+        py: Python<'py>,
+        code: u32,
+        input: &[u8],
+        output_length: usize,
+    ) -> PyResult<Bound<'py, PyBytes>> {
+        let mut output = vec![0; output_length];
+        let written = py.allow_threads(|| self.0.ioctl(code, input, &mut output))?;
+        Ok(PyBytes::new(py, &output[..written]))
+    }
+}
+
+/// Returns `state` as a dictionary.
+fn registers<'py>(py: Python<'py>, state: &RegisterState) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new(py);
+    dict.set_item("gprs", state.gprs.to_vec())?;
+    dict.set_item("flags", state.flags)?;
+    dict.set_item("cr0", state.cr0)?;
+    dict.set_item("cr4", state.cr4)?;
+    dict.set_item("irql", state.irql)?;
+    Ok(dict)
+}
+
+#[pymodule]
+fn capcom(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyDevice>()?;
+    module.add("CLASS_EXECUTE", CLASS_EXECUTE)?;
+    module.add("CLASS_KERNEL_MEMORY", CLASS_KERNEL_MEMORY)?;
+    module.add("CLASS_PHYSICAL_MEMORY", CLASS_PHYSICAL_MEMORY)?;
+    module.add("CLASS_MSR", CLASS_MSR)?;
+    module.add("CLASS_ELEVATION", CLASS_ELEVATION)?;
+    module.add("CLASS_ALL", CLASS_ALL)?;
+    Ok(())
+}
@@ -0,0 +1,2751 @@
+//! An in-guest test program for the driver. `cargo xtask matrix` copies this
+//! program into the target and runs it after starting the driver. It exits with
+//! a non-zero code if any test fails.
+//!
+//! With `--probe`, it only checks that the device can be opened and queried,
+//! which xtask does after starting the driver.
+//!
+//! With `--replay <path>`, it only sends the IOCTLs of the trace file at the
+//! path, which `cargo xtask replay` does to reproduce a crash. With
+//! `--no-delay`, they are sent without the delays they were recorded with.
+//!
+//! With `--elevate`, it only elevates itself to SYSTEM with
+//! `elevate_current_process` of capcom-client and checks that `whoami` reports
+//! it, which the `elevate` test runs it for with a token that is not an
+//! administrator's. It exits with 2 if the payload is refused as not supported.
+//!
+//! With `--dump-image <path>`, it only writes the image of the driver in kernel
+//! memory to the file, read with `IOCTL_READ_MEMORY` page by page, which
+//! `cargo xtask scan --guest` scans with YARA rules. Pages that cannot be read,
+//! e.g., of discarded sections, are written as zeros.
+//!
+//! With `--trace <path>`, it records the IOCTLs the tests send into the trace
+//! file.
+//!
+//! With `--fuzz <count>`, it only sends the number of IOCTLs with random input
+//! buffers generated from `--seed <seed>`, or from a seed taken from the time,
+//! which it prints. A seed sends the same IOCTLs on every run, which `cargo
+//! xtask fuzz --seed <seed>` repeats a run with.
+//!
+//! With `--test <name>`, it runs only the test, e.g., `get_version`.
+//!
+//! With `--map-test-driver <path>`, `map_test_driver` maps capcom-map-test at
+//! the path and checks that it is loaded and unloaded cleanly. The test passes
+//! without doing anything otherwise.
+//!
+//! With `--persistence save`, `persistence` saves a configuration disabling
+//! `CLASS_MSR`, and with `--persistence check`, it checks that the driver
+//! applied the configuration when it started, e.g., after `cargo xtask scenario
+//! reboot` rebooted the target, and saves an empty one. The test passes without
+//! doing anything otherwise.
+//!
+//! With `--compat-poc <path>`, `compat_poc` runs the program at the path, a
+//! public exploit written for the original driver, e.g., ExploitCapcom, and
+//! checks that it exits with zero. The test is reported as skipped otherwise,
+//! as xtask passes it only if `COMPAT_POC_PATH` in its `config.rs` is set.
+//!
+//! With `--report <path>`, it also writes the results to the file for xtask to
+//! build reports from. Each line is tab-separated fields of one of:
+//!
+//! - `driver`, the commit in hex, `1` if dirty or `0`, the profile, the build
+//!   timestamp and the features in hex, from the build metadata of the driver.
+//! - `test`, the name, `pass`, `fail` or `skip`, the duration in milliseconds
+//!   and the error if failed or the reason if skipped.
+//!
+//! ```shell
+//! capcom-test.exe [--hvci] [--test <name>] [--map-test-driver <path>] [--compat-poc <path>]
+//!                 [--persistence <save|check>] [--report <path>] [--trace <path>]
+//! capcom-test.exe --probe
+//! capcom-test.exe --dump-image <path>
+//! capcom-test.exe --replay <path> [--no-delay]
+//! capcom-test.exe --elevate
+//! capcom-test.exe --fuzz <count> [--seed <seed>] [--trace <path>]
+//! ```
+
+use std::{
+    env,
+    ffi::{CStr, c_void},
+    fmt::{self, Write as _},
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    mem,
+    os::windows::io::{AsHandle, AsRawHandle, FromRawHandle, OwnedHandle},
+    pin::pin,
+    process::{self, Command, ExitCode},
+    ptr, slice,
+    sync::{Arc, Mutex, mpsc},
+    task::{self, Poll, Wake, Waker},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result, bail, ensure};
+use capcom_abi::{
+    ABI_VERSION, ApicRequest, AuditInfo, BUILD_FEATURE_COVERAGE, BUILD_FEATURE_DEFANGED,
+    BUILD_FEATURE_FAITHFUL, BUILD_FEATURE_HARDENED, BUILD_FEATURE_PARANOID,
+    BUILD_FEATURE_STRICT_COMPAT, BUILD_FEATURE_TEACHING, CAPABILITY_MAP_DRIVER,
+    CAPABILITY_RUN_PAYLOAD, CAPABILITY_RUN_SHELLCODE, CLASS_ELEVATION, CLASS_EXECUTE,
+    CLASS_KERNEL_MEMORY, CLASS_MSR, CLASS_PHYSICAL_MEMORY, CONFIG_ENABLED_CLASSES,
+    COVERAGE_COUNTERS, CPU_STATE_IDT_ENTRIES, ContiguousAllocRequest, ContiguousAllocation,
+    ContiguousFreeRequest, CorrelationHeader, CpuState, CpuStateRequest, DEBUG_BREAK_ON_LOAD,
+    DEBUG_BREAK_ON_PANIC_ONLY, DEVICE_NAME, DEVICE_PATH, DebugBreakRequest, DirectoryEntry,
+    DupHandleRequest, DupHandleResponse, EVENT_KIND_IOCTL, EVENT_KIND_MESSAGE,
+    EnumDirectoryRequest, EventRingHeader, EventRingInfo, FileRequest, IOCTL_ALLOC_CONTIGUOUS,
+    IOCTL_CAPTURE_THREAD, IOCTL_CORRELATED, IOCTL_DUMP_PHYSICAL_RANGE, IOCTL_DUP_HANDLE,
+    IOCTL_ENABLE_EVENT_RING, IOCTL_ENUM_DIRECTORY, IOCTL_ENUM_MAPPED_DRIVERS,
+    IOCTL_FREE_CONTIGUOUS, IOCTL_GET_AUDIT, IOCTL_GET_KERNEL_BASE, IOCTL_GET_OFFSETS,
+    IOCTL_GET_PTE, IOCTL_GET_VERSION, IOCTL_MAP_SHARED, IOCTL_NEGOTIATE, IOCTL_PCI_CONFIG_RW,
+    IOCTL_QUERY_ALLOCATIONS, IOCTL_QUEUE_USER_APC, IOCTL_READ_APIC, IOCTL_READ_FILE,
+    IOCTL_READ_FILE_DIRECT, IOCTL_READ_LOG, IOCTL_READ_MEMORY, IOCTL_REG_QUERY, IOCTL_RUN_PAYLOAD,
+    IOCTL_RUN_SHELLCODE, IOCTL_SAMPLE_NMI, IOCTL_SCAN_MEMORY, IOCTL_SELF_TEST,
+    IOCTL_SET_DEBUG_BREAK, IOCTL_SET_NMI_CALLBACK, IOCTL_SET_OFFSETS, IOCTL_SET_PRINT_FILTER,
+    IOCTL_SNAPSHOT_CPU_STATE, KernelOffsets, LogRecord, NegotiateRequest, NegotiateResponse,
+    NmiCallbackRequest, NmiSample, NmiSampleRequest, PRINT_LEVEL_MASK_ALL, PTE_PRESENT,
+    PayloadTranscript, PciConfigRequest, PhysicalDumpChunk, PhysicalDumpRequest,
+    PrintFilterRequest, PteInfo, PteRequest, RegistryRequest, RegistryValue, SAVED_CONFIG_VERSION,
+    SELF_TEST_ALLOCATOR, SELF_TEST_LOG_RING, SELF_TEST_OFFSETS, SavedConfig, ScanRequest,
+    SharedMemoryInfo, SharedMemoryRequest, THREAD_CAPTURE_SAVED_STACK, ThreadCapture,
+    ThreadCaptureRequest, UserApcRequest, VersionInfo, chain_execution, map_test, messages,
+    ring::Consumer, stealth_name,
+};
+use capcom_client::{
+    Device,
+    kernel_memory::{KernelMem, Pattern},
+    overlapped::{self, AsyncDevice},
+    symbols, trace,
+};
+use windows_sys::Win32::{
+    Foundation::{
+        CloseHandle, DUPLICATE_SAME_ACCESS, ERROR_ACCESS_DENIED, ERROR_BAD_EXE_FORMAT,
+        ERROR_FILE_NOT_FOUND, ERROR_INVALID_FUNCTION, ERROR_INVALID_PARAMETER, ERROR_MOD_NOT_FOUND,
+        ERROR_NOACCESS, ERROR_NOT_FOUND, ERROR_NOT_SUPPORTED, ERROR_SUCCESS, FALSE, FreeLibrary,
+        WAIT_IO_COMPLETION, WAIT_OBJECT_0,
+    },
+    Security::{
+        CheckTokenMembership, CreateRestrictedToken, CreateWellKnownSid, SECURITY_MAX_SID_SIZE,
+        SID_AND_ATTRIBUTES, TOKEN_ASSIGN_PRIMARY, TOKEN_DUPLICATE, TOKEN_QUERY,
+        WinBuiltinAdministratorsSid,
+    },
+    System::{
+        IO::DeviceIoControl,
+        LibraryLoader::{DONT_RESOLVE_DLL_REFERENCES, GetProcAddress, LoadLibraryExW},
+        Memory::{
+            MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_EXECUTE_READWRITE, VirtualAlloc, VirtualFree,
+        },
+        Registry::{HKEY_LOCAL_MACHINE, REG_SZ, RRF_RT_REG_DWORD, RegGetValueW},
+        Threading::{
+            CreateEventW, CreateProcessAsUserW, GetCurrentProcess, GetCurrentThreadId,
+            GetExitCodeProcess, INFINITE, OpenProcessToken, PROCESS_INFORMATION, STARTUPINFOW,
+            SetEvent, SleepEx, WaitForSingleObject,
+        },
+    },
+};
+
+type Test = fn(&Environment) -> Result<()>;
+
+/// The exit code of `--elevate` when the payload is refused as not supported.
+const ELEVATE_REFUSED: u8 = 2;
+
+/// The IOCTLs `--fuzz` sends. Those that run code, write memory, files, the
+/// registry or hardware, or turn features of the driver off are left out, as
+/// they break the target by design rather than by bugs, and so are those that
+/// wait for as long as the input buffer says.
+const FUZZ_IOCTLS: &[u32] = &[
+    IOCTL_GET_VERSION,
+    IOCTL_NEGOTIATE,
+    IOCTL_READ_LOG,
+    IOCTL_GET_AUDIT,
+    IOCTL_SNAPSHOT_CPU_STATE,
+    IOCTL_GET_PTE,
+    IOCTL_ENUM_DIRECTORY,
+    IOCTL_DUP_HANDLE,
+    IOCTL_REG_QUERY,
+    IOCTL_READ_FILE,
+    IOCTL_READ_APIC,
+    IOCTL_ALLOC_CONTIGUOUS,
+    IOCTL_FREE_CONTIGUOUS,
+    IOCTL_MAP_SHARED,
+    IOCTL_ENABLE_EVENT_RING,
+    IOCTL_GET_OFFSETS,
+    IOCTL_GET_KERNEL_BASE,
+    IOCTL_ENUM_MAPPED_DRIVERS,
+    IOCTL_QUERY_ALLOCATIONS,
+    IOCTL_SELF_TEST,
+];
+
+/// The values `--fuzz` fills input buffers with besides random ones, as they
+/// tend to hit the edges of lengths, indexes and addresses.
+const FUZZ_VALUES: &[u64] = &[
+    0,
+    1,
+    0x7f,
+    0xff,
+    0x1000,
+    0x7fff_ffff,
+    0x8000_0000,
+    0xffff_ffff,
+    0xffff_8000_0000_0000,
+    u64::MAX,
+];
+
+/// The sizes of the output buffers `--fuzz` gives.
+const FUZZ_OUTPUT_LENGTHS: &[usize] = &[0, 1, 4, 8, 16, 64, 0x100, 0x1000];
+
+/// The file name of the driver in the list of loaded modules.
+const IMAGE_NAME: &str = "capcom.sys";
+
+/// The size of the pages `--dump-image` reads one at a time.
+const PAGE_SIZE: usize = 0x1000;
+
+/// Describes the configuration of the target the tests run on.
+#[derive(Debug)]
+struct Environment {
+    /// Whether HVCI is enabled on the target.
+    hvci: bool,
+    /// The path to the image of capcom-map-test, if given.
+    map_test_driver: Option<String>,
+    /// The path to a public exploit for the original driver, if given.
+    compat_poc: Option<String>,
+    /// `save` or `check` for the persistence test, if given.
+    persistence: Option<String>,
+}
+
+/// The error of a test that could not run in the environment, e.g., as an
+/// option it needs was not given. It is reported as skipped rather than
+/// failed.
+#[derive(Debug)]
+struct Skipped(&'static str);
+
+impl fmt::Display for Skipped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Skipped {}
+
+/// The tests in the order they run. The self-test comes first, so that
+/// failures of the driver itself are told apart from those of the environment.
+const TESTS: &[(&str, Test)] = &[
+    ("self_test", test_self_test),
+    ("get_version", test_get_version),
+    ("build_info", test_build_info),
+    ("coverage", test_coverage),
+    ("run_payload", test_run_payload),
+    ("unmapped_payload", test_unmapped_payload),
+    ("run_shellcode", test_run_shellcode),
+    ("stream_shellcode", test_stream_shellcode),
+    ("reject_invalid_code", test_reject_invalid_code),
+    ("payload_transcript", test_payload_transcript),
+    ("payload_stack", test_payload_stack),
+    ("compat_payload", test_compat_payload),
+    ("compat_poc", test_compat_poc),
+    ("elevate", test_elevate),
+    ("personality", test_personality),
+    ("teaching", test_teaching),
+    ("set_debug_break", test_set_debug_break),
+    ("print_filter", test_print_filter),
+    ("save_config", test_save_config),
+    ("persistence", test_persistence),
+    ("control_device", test_control_device),
+    ("device_interface", test_device_interface),
+    ("stealth_name", test_stealth_name),
+    ("negotiate", test_negotiate),
+    ("read_log", test_read_log),
+    ("audit", test_audit),
+    ("query_allocations", test_query_allocations),
+    ("snapshot_cpu_state", test_snapshot_cpu_state),
+    ("get_pte", test_get_pte),
+    ("capture_thread", test_capture_thread),
+    ("enum_directory", test_enum_directory),
+    ("dup_handle", test_dup_handle),
+    ("queue_user_apc", test_queue_user_apc),
+    ("reg_query", test_reg_query),
+    ("read_file", test_read_file),
+    ("read_file_direct", test_read_file_direct),
+    ("sample_nmi", test_sample_nmi),
+    ("read_apic", test_read_apic),
+    ("pci_config_rw", test_pci_config_rw),
+    ("alloc_contiguous", test_alloc_contiguous),
+    ("dump_physical_range", test_dump_physical_range),
+    ("offsets", test_offsets),
+    ("resolve_offsets", test_resolve_offsets),
+    ("get_kernel_base", test_get_kernel_base),
+    ("read_memory", test_read_memory),
+    ("scan_memory", test_scan_memory),
+    ("correlation", test_correlation),
+    ("map_driver", test_map_driver),
+    ("map_test_driver", test_map_test_driver),
+    ("map_shared", test_map_shared),
+    ("event_ring", test_event_ring),
+    ("overlapped", test_overlapped),
+];
+
+fn main() -> ExitCode {
+    if env::args().any(|arg| arg == "--probe") {
+        return probe();
+    }
+    if let Some(path) = option_value("--dump-image") {
+        return dump_image(&path);
+    }
+    if let Some(path) = option_value("--replay") {
+        return replay(&path, !env::args().any(|arg| arg == "--no-delay"));
+    }
+    if env::args().any(|arg| arg == "--elevate") {
+        return elevate();
+    }
+    if let Some(path) = option_value("--trace")
+        && let Err(err) = trace::start(path.as_ref())
+    {
+        println!("Failed to record IOCTLs into {path}: {err}");
+        return ExitCode::FAILURE;
+    }
+    if let Err(err) = trace::start_from_env() {
+        println!(
+            "Failed to record IOCTLs into the file of {}: {err}",
+            trace::TRACE_ENV
+        );
+        return ExitCode::FAILURE;
+    }
+    if let Some(count) = option_value("--fuzz") {
+        return fuzz(&count, option_value("--seed").as_deref());
+    }
+
+    let env = Environment {
+        hvci: env::args().any(|arg| arg == "--hvci"),
+        map_test_driver: option_value("--map-test-driver"),
+        compat_poc: option_value("--compat-poc"),
+        persistence: option_value("--persistence"),
+    };
+    let report_path = option_value("--report");
+    let only = option_value("--test");
+    let tests: Vec<_> = TESTS
+        .iter()
+        .filter(|(name, _)| only.as_deref().is_none_or(|only| only == *name))
+        .collect();
+    if let Some(only) = &only
+        && tests.is_empty()
+    {
+        println!("No test is named {only}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut report = String::new();
+    write_build_info(&mut report);
+
+    let mut failed = 0;
+    let mut skipped = 0;
+    for &&(name, test) in &tests {
+        let start = Instant::now();
+        let result = test(&env);
+        let millis = start.elapsed().as_millis();
+        match result {
+            Ok(()) => {
+                println!("[PASS] {name}");
+                let _ = writeln!(report, "test\t{name}\tpass\t{millis}\t");
+            }
+            Err(err) if err.is::<Skipped>() => {
+                println!("[SKIP] {name}: {err}");
+                let _ = writeln!(report, "test\t{name}\tskip\t{millis}\t{err}");
+                skipped += 1;
+            }
+            Err(err) => {
+                println!("[FAIL] {name}: {err}");
+                let err = format!("{err:#}").replace(['\t', '\n', '\r'], " ");
+                let _ = writeln!(report, "test\t{name}\tfail\t{millis}\t{err}");
+                failed += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} passed, {skipped} skipped, {failed} failed",
+        tests.len() - skipped - failed
+    );
+    if let Some(path) = report_path
+        && let Err(err) = fs::write(&path, report)
+    {
+        println!("Failed to write the report to {path}: {err}");
+        return ExitCode::FAILURE;
+    }
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Returns the value following the command line option `name`, if any.
+fn option_value(name: &str) -> Option<String> {
+    env::args().skip_while(|arg| arg != name).nth(1)
+}
+
+/// Writes the `driver` line of the report from the build metadata of the
+/// driver, if available.
+fn write_build_info(report: &mut String) {
+    let Ok(build) = Device::open().and_then(|device| device.get_build_info()) else {
+        return;
+    };
+    let profile = build.profile.split(|&c| c == 0).next().unwrap_or_default();
+    report.push_str("driver\t");
+    for byte in build.commit {
+        let _ = write!(report, "{byte:02x}");
+    }
+    let _ = writeln!(
+        report,
+        "\t{}\t{}\t{}\t{:x}",
+        u32::from(build.dirty != 0),
+        profile.escape_ascii(),
+        build.timestamp,
+        build.features
+    );
+}
+
+/// Opens the device and gets the version of the driver, to tell whether the
+/// driver created the device.
+fn probe() -> ExitCode {
+    match Device::open().and_then(|device| device.get_version()) {
+        Ok(version) => {
+            println!(
+                "ABI version {}, capabilities {:#x}",
+                version.abi_version, version.capabilities
+            );
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            println!("Failed to query {DEVICE_PATH}: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Sends the IOCTLs of the trace file at `path`, with the delays they were
+/// recorded with if `timing`. Failures of the IOCTLs are printed but not
+/// errors, as they may have failed when recorded too.
+fn replay(path: &str, timing: bool) -> ExitCode {
+    let records = match fs::read_to_string(path).and_then(|text| trace::parse(&text)) {
+        Ok(records) => records,
+        Err(err) => {
+            println!("Failed to read {path}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = trace::replay(&records, timing, |index, record, result| {
+        if let trace::Record::Ioctl { code, .. } = record {
+            match result {
+                Ok(bytes_returned) => println!("[{index}] {code:#x}: {bytes_returned} bytes"),
+                Err(err) => println!("[{index}] {code:#x}: {err}"),
+            }
+        }
+    });
+    match result {
+        Ok(()) => {
+            println!("Replayed {} records", records.len());
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            println!("Failed to replay {path}: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Sends `count` IOCTLs of [`FUZZ_IOCTLS`] with random input buffers generated
+/// from `seed`, or from a seed taken from the time. Failures of the IOCTLs are
+/// expected and not errors.
+fn fuzz(count: &str, seed: Option<&str>) -> ExitCode {
+    let Ok(count) = count.parse::<u32>() else {
+        println!("{count} is not a number of IOCTLs");
+        return ExitCode::FAILURE;
+    };
+    let seed = match seed {
+        Some(seed) => {
+            let parsed = match seed.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16),
+                None => seed.parse(),
+            };
+            let Ok(seed) = parsed else {
+                println!("{seed} is not a seed");
+                return ExitCode::FAILURE;
+            };
+            seed
+        }
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64,
+    };
+    println!("Fuzzing with seed {seed}");
+
+    let device = match Device::open() {
+        Ok(device) => device,
+        Err(err) => {
+            println!("Failed to open {DEVICE_PATH}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    // Enable the classes, so that the requests reach the handlers rather than
+    // being refused.
+    let classes = CLASS_ELEVATION | CLASS_KERNEL_MEMORY | CLASS_MSR | CLASS_PHYSICAL_MEMORY;
+    if let Err(err) = device.negotiate(classes) {
+        println!("Failed to negotiate: {err}");
+        return ExitCode::FAILURE;
+    }
+
+    let mut rng = Rng(seed);
+    let mut failed = 0;
+    for _ in 0..count {
+        let code = *rng.choose(FUZZ_IOCTLS);
+        let input = fuzz_input(&mut rng);
+        let mut output = vec![0; *rng.choose(FUZZ_OUTPUT_LENGTHS)];
+        if device.ioctl(code, &input, &mut output).is_err() {
+            failed += 1;
+        }
+    }
+    println!("Sent {count} IOCTLs, {failed} of which failed");
+    ExitCode::SUCCESS
+}
+
+/// Returns an input buffer for `--fuzz`, mostly short, as requests are small
+/// structures, and filled with words of [`FUZZ_VALUES`] and random ones.
+fn fuzz_input(rng: &mut Rng) -> Vec<u8> {
+    let length = if rng.below(4) == 0 {
+        rng.below(0x400)
+    } else {
+        rng.below(0x40)
+    };
+    let mut input = Vec::with_capacity(length + size_of::<u64>());
+    while input.len() < length {
+        let word = if rng.below(2) == 0 {
+            *rng.choose(FUZZ_VALUES)
+        } else {
+            rng.next_u64()
+        };
+        input.extend(word.to_le_bytes());
+    }
+    input.truncate(length);
+    input
+}
+
+/// The random number generator of `--fuzz`, SplitMix64, so that a seed gives
+/// the same IOCTLs on every run and build.
+#[derive(Debug)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut value = self.0;
+        value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        value ^ (value >> 31)
+    }
+
+    /// Returns a number below `bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next_u64() % bound as u64) as usize
+    }
+
+    fn choose<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Elevates this process to SYSTEM with [`steal_system_token`], and exits with
+/// [`ELEVATE_REFUSED`] if the payload is refused as not supported.
+fn elevate() -> ExitCode {
+    match steal_system_token() {
+        Ok(()) => {
+            println!("Elevated to SYSTEM");
+            ExitCode::SUCCESS
+        }
+        Err(err) => {
+            println!("Failed to elevate: {err:#}");
+            let refused = err
+                .downcast_ref::<io::Error>()
+                .and_then(io::Error::raw_os_error)
+                == Some(ERROR_NOT_SUPPORTED.cast_signed());
+            if refused {
+                ExitCode::from(ELEVATE_REFUSED)
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+    }
+}
+
+/// Checks that this process is not an administrator, elevates it with
+/// `elevate_current_process` of capcom-client, which checks the resulting
+/// token, and checks that `whoami` reports `SYSTEM` too.
+fn steal_system_token() -> Result<()> {
+    ensure!(
+        !is_administrator()?,
+        "the process is already an administrator"
+    );
+    capcom_client::elevate_current_process()?;
+    let output = Command::new("whoami.exe").output()?;
+    let user = String::from_utf8_lossy(&output.stdout);
+    ensure!(
+        user.trim() == r"nt authority\system",
+        "whoami reported {}",
+        user.trim()
+    );
+    Ok(())
+}
+
+/// Writes the image of the driver in kernel memory to the file at `path`.
+fn dump_image(path: &str) -> ExitCode {
+    match write_image(path) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            println!("Failed to dump {IMAGE_NAME} to {path}: {err:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn write_image(path: &str) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+    let module = device
+        .get_module(Some(IMAGE_NAME))
+        .context("the image is not loaded")?;
+    let memory = KernelMem::new(&device);
+    let mut image = vec![0; module.size as usize];
+    let mut unreadable = 0;
+    for (index, page) in image.chunks_mut(PAGE_SIZE).enumerate() {
+        let address = module.base + (index * PAGE_SIZE) as u64;
+        if memory.read_exact(address, page).is_err() {
+            page.fill(0);
+            unreadable += 1;
+        }
+    }
+    fs::write(path, &image)?;
+    println!(
+        "Dumped {:#x} bytes at {:#x}, {unreadable} pages unreadable",
+        image.len(),
+        module.base
+    );
+    Ok(())
+}
+
+/// Runs the self-test of the driver and checks that every check passed.
+fn test_self_test(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let result = device.self_test()?;
+    let required = SELF_TEST_ALLOCATOR | SELF_TEST_LOG_RING | SELF_TEST_OFFSETS;
+    ensure!(
+        result.checks & required == required,
+        "checks {:#x} were not run",
+        required & !result.checks
+    );
+    ensure!(result.failures == 0, "checks {:#x} failed", result.failures);
+    Ok(())
+}
+
+/// Gets the build metadata and checks that it was filled by the build script.
+fn test_build_info(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let build = device.get_build_info()?;
+    ensure!(build.timestamp != 0, "no build timestamp");
+    ensure!(
+        [&b"debug"[..], b"release"]
+            .iter()
+            .any(|profile| build.profile.starts_with(profile)),
+        "unexpected profile {:?}",
+        build.profile.escape_ascii().to_string()
+    );
+    Ok(())
+}
+
+/// Gets the coverage counters, unless the driver is built without the
+/// `coverage` feature, and checks that a request rejected for the ABI version
+/// reaches counters that were not hit before.
+fn test_coverage(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(0)?;
+    if device.get_build_info()?.features & BUILD_FEATURE_COVERAGE == 0 {
+        let Err(err) = device.coverage(false) else {
+            bail!("coverage was returned by a build without the feature");
+        };
+        ensure!(
+            err.raw_os_error() == Some(ERROR_NOT_SUPPORTED.cast_signed()),
+            "the request was refused with an unexpected error: {err}"
+        );
+        return Ok(());
+    }
+
+    let hit = |counters: &[u32]| counters.iter().filter(|&&count| count != 0).count();
+    let before = hit(&device.coverage(true)?);
+    let request = NegotiateRequest {
+        abi_version: ABI_VERSION + 1,
+        classes: 0,
+    };
+    ensure!(
+        device
+            .ioctl(IOCTL_NEGOTIATE, as_bytes(&request), &mut [])
+            .is_err(),
+        "a mismatched ABI version was accepted"
+    );
+    let counters = device.coverage(false)?;
+    ensure!(
+        counters.len() == COVERAGE_COUNTERS,
+        "unexpected number of counters {}",
+        counters.len()
+    );
+    ensure!(
+        hit(&counters) > before,
+        "the rejected request reached no new counter"
+    );
+    Ok(())
+}
+
+/// Gets the version and checks that the capabilities match the environment.
+fn test_get_version(env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let mut version = VersionInfo::default();
+    let bytes_returned = device_io_control(
+        &device,
+        IOCTL_GET_VERSION,
+        &[],
+        ptr::from_mut(&mut version).cast(),
+        size_of::<VersionInfo>(),
+    )?;
+    ensure!(
+        bytes_returned == size_of::<VersionInfo>(),
+        "unexpected output size {bytes_returned}"
+    );
+    ensure!(
+        version.abi_version == ABI_VERSION,
+        "the driver has ABI version {} but {ABI_VERSION} is expected",
+        version.abi_version
+    );
+    let executable = version.capabilities & (CAPABILITY_RUN_PAYLOAD | CAPABILITY_RUN_SHELLCODE);
+    ensure!(
+        (executable == 0) == env.hvci,
+        "unexpected capabilities {:#x}",
+        version.capabilities
+    );
+    Ok(())
+}
+
+/// Runs a payload that does nothing. It should be refused when HVCI is
+/// enabled instead of crashing the system, and on ARM64, where user-mode pages
+/// are never executable in kernel-mode.
+fn test_run_payload(env: &Environment) -> Result<()> {
+    /// The payload run in kernel-mode. It must not access user-mode memory as
+    /// SMAP may be enabled.
+    unsafe extern "C" fn payload(_get_system_routine_address: *const c_void) {}
+
+    let payload: unsafe extern "C" fn(*const c_void) = payload;
+    // Do not negotiate, like clients of the original driver.
+    let device = open_device()?;
+    let input = (payload as usize).to_ne_bytes();
+    let result = device_io_control(&device, IOCTL_RUN_PAYLOAD, &input, ptr::null_mut(), 0);
+    check_refusal(result, env.hvci || cfg!(target_arch = "aarch64"))
+}
+
+/// Runs a payload at an address that is never mapped. It must be refused as not
+/// supported where payloads cannot run, as an invalid pointer by a build with
+/// the `paranoid` feature, and otherwise, as invalid code.
+fn test_unmapped_payload(env: &Environment) -> Result<()> {
+    // The first 64 KB of the address space are never mapped.
+    const ADDRESS: usize = 0x1000;
+
+    let device = open_device()?;
+    let paranoid = Device::open()?.get_build_info()?.features & BUILD_FEATURE_PARANOID != 0;
+    let input = ADDRESS.to_ne_bytes();
+    let result = device_io_control(&device, IOCTL_RUN_PAYLOAD, &input, ptr::null_mut(), 0);
+    let Err(err) = result else {
+        bail!("the payload at {ADDRESS:#x} was run");
+    };
+    let expected = if env.hvci || cfg!(target_arch = "aarch64") {
+        ERROR_NOT_SUPPORTED
+    } else if paranoid {
+        ERROR_NOACCESS
+    } else {
+        ERROR_BAD_EXE_FORMAT
+    };
+    ensure!(
+        err.raw_os_error() == Some(expected.cast_signed()),
+        "the payload was refused with an unexpected error: {err}"
+    );
+    Ok(())
+}
+
+/// Runs shellcode that only returns. It should be refused when HVCI is enabled.
+fn test_run_shellcode(env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_EXECUTE)?;
+    let result = run_shellcode(&device);
+    check_refusal(result, env.hvci)
+}
+
+/// Runs shellcode by writing it to a handle that did not negotiate, as
+/// scripts without IOCTLs do, and reads back the transcript.
+fn test_stream_shellcode(env: &Environment) -> Result<()> {
+    // `ret`
+    #[cfg(target_arch = "x86_64")]
+    const SHELLCODE: &[u8] = &[0xc3];
+    #[cfg(target_arch = "aarch64")]
+    const SHELLCODE: &[u8] = &0xd65f_03c0_u32.to_le_bytes();
+
+    let mut device = open_device()?;
+    let result = device.write(SHELLCODE);
+    if env.hvci {
+        return check_refusal(result, true);
+    }
+    ensure!(
+        result? == SHELLCODE.len(),
+        "the shellcode was partially written"
+    );
+    let mut transcript = PayloadTranscript::default();
+    let length = device.read(unsafe {
+        slice::from_raw_parts_mut(
+            ptr::from_mut(&mut transcript).cast(),
+            size_of::<PayloadTranscript>(),
+        )
+    })?;
+    ensure!(
+        length == size_of::<PayloadTranscript>(),
+        "unexpected transcript length {length}"
+    );
+    Ok(())
+}
+
+/// Runs shellcode that is not instructions. It should be refused instead of
+/// crashing the system.
+fn test_reject_invalid_code(env: &Environment) -> Result<()> {
+    // Zeros, `int3` padding, and `ud2` or `udf`.
+    #[cfg(target_arch = "x86_64")]
+    const UNDEFINED: &[u8] = &[0x0f, 0x0b];
+    #[cfg(target_arch = "aarch64")]
+    const UNDEFINED: &[u8] = &0_u32.to_le_bytes();
+    let shellcodes: [&[u8]; 3] = [&[0; 16], &[0xcc; 16], UNDEFINED];
+
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_EXECUTE)?;
+    let expected = if env.hvci {
+        ERROR_NOT_SUPPORTED
+    } else {
+        ERROR_BAD_EXE_FORMAT
+    };
+    for shellcode in shellcodes {
+        let result = device_io_control(&device, IOCTL_RUN_SHELLCODE, shellcode, ptr::null_mut(), 0);
+        let Err(err) = result else {
+            bail!("{shellcode:02x?} was not refused");
+        };
+        ensure!(
+            err.raw_os_error() == Some(expected.cast_signed()),
+            "{shellcode:02x?} was refused with an unexpected error: {err}"
+        );
+    }
+    Ok(())
+}
+
+/// Runs shellcode that only returns with an output buffer for the transcript,
+/// and checks that the registers the payload must preserve did not change.
+fn test_payload_transcript(env: &Environment) -> Result<()> {
+    // `ret`
+    #[cfg(target_arch = "x86_64")]
+    const SHELLCODE: &[u8] = &[0xc3];
+    #[cfg(target_arch = "aarch64")]
+    const SHELLCODE: &[u8] = &0xd65f_03c0_u32.to_le_bytes();
+
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_EXECUTE)?;
+    let mut transcript = PayloadTranscript::default();
+    let result = device_io_control(
+        &device,
+        IOCTL_RUN_SHELLCODE,
+        SHELLCODE,
+        ptr::from_mut(&mut transcript).cast(),
+        size_of::<PayloadTranscript>(),
+    );
+    if env.hvci {
+        return check_refusal(result, true);
+    }
+    let bytes_returned = result?;
+    ensure!(
+        bytes_returned == size_of::<PayloadTranscript>(),
+        "unexpected output size {bytes_returned}"
+    );
+    let (before, after) = (&transcript.before, &transcript.after);
+    ensure!(before.gprs != [0; 32], "no registers were recorded");
+    ensure!(
+        before.cr0 == after.cr0 && before.cr4 == after.cr4 && before.irql == after.irql,
+        "the control registers or IRQL changed: {transcript:x?}"
+    );
+    Ok(())
+}
+
+/// Runs shellcode using 32 KB of the stack, more than the stack of the calling
+/// thread has. It should run on the dedicated stack instead of crashing the
+/// system. Skipped unless the `PayloadStackSize` value of the service key
+/// configures a dedicated stack large enough, as the shellcode would crash the
+/// system on the stack of the calling thread.
+fn test_payload_stack(env: &Environment) -> Result<()> {
+    // `sub rsp, 0x8000; mov [rsp], rax; add rsp, 0x8000; ret`
+    #[cfg(target_arch = "x86_64")]
+    const SHELLCODE: &[u8] = &[
+        0x48, 0x81, 0xec, 0x00, 0x80, 0x00, 0x00, 0x48, 0x89, 0x04, 0x24, 0x48, 0x81, 0xc4, 0x00,
+        0x80, 0x00, 0x00, 0xc3,
+    ];
+    // `sub sp, sp, #0x8000; str xzr, [sp]; add sp, sp, #0x8000; ret`
+    #[cfg(target_arch = "aarch64")]
+    const SHELLCODE: &[u8] = &[
+        0xff, 0x23, 0x40, 0xd1, 0xff, 0x03, 0x00, 0xf9, 0xff, 0x23, 0x40, 0x91, 0xc0, 0x03, 0x5f,
+        0xd6,
+    ];
+
+    if service_dword("PayloadStackSize")?.is_none_or(|size| size <= 0x8000) {
+        return Err(Skipped("PayloadStackSize does not configure a dedicated stack").into());
+    }
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_EXECUTE)?;
+    let result = device_io_control(&device, IOCTL_RUN_SHELLCODE, SHELLCODE, ptr::null_mut(), 0);
+    check_refusal(result, env.hvci)
+}
+
+/// Runs a payload laid out as tools written for the original driver do: in
+/// memory allocated with `VirtualAlloc`, preceded by its own address, given as
+/// the input buffer with a 4-byte output buffer. A build with the
+/// `strict-compat` feature must refuse a payload not preceded by its address,
+/// and other sizes of the buffers. Payloads must receive the address of
+/// `MmGetSystemRoutineAddress`.
+fn test_compat_payload(env: &Environment) -> Result<()> {
+    // `ret`
+    #[cfg(target_arch = "x86_64")]
+    const CODE: &[u8] = &[0xc3];
+    #[cfg(target_arch = "aarch64")]
+    const CODE: &[u8] = &0xd65f_03c0_u32.to_le_bytes();
+    // The index of the register the argument is passed in.
+    #[cfg(target_arch = "x86_64")]
+    const ARGUMENT: usize = 1;
+    #[cfg(target_arch = "aarch64")]
+    const ARGUMENT: usize = 0;
+
+    let page = ExecutablePage::new()?;
+    let payload = page.address() + size_of::<usize>();
+    page.write(0, &payload.to_ne_bytes());
+    page.write(size_of::<usize>(), CODE);
+    run_compat_payload(env, payload)?;
+    drop(page);
+
+    // A 4-byte output buffer cannot hold the transcript recording the argument,
+    // so check it with shellcode, which is called the same way.
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_EXECUTE | CLASS_KERNEL_MEMORY)?;
+    if env.hvci {
+        return Ok(());
+    }
+    let Some(transcript) = device.run_shellcode(CODE)? else {
+        return Ok(());
+    };
+    let expected = kernel_export(&device, c"MmGetSystemRoutineAddress")?;
+    let argument = transcript.before.gprs[ARGUMENT];
+    ensure!(
+        argument == expected,
+        "the payload received {argument:#x} instead of {expected:#x}"
+    );
+    Ok(())
+}
+
+/// Sends the payload at `payload` like tools written for the original driver.
+fn run_compat_payload(env: &Environment, payload: usize) -> Result<()> {
+    let strict = Device::open()?.get_build_info()?.features & BUILD_FEATURE_STRICT_COMPAT != 0;
+    // Do not negotiate, like clients of the original driver.
+    let device = open_device()?;
+    let mut output = 0_u32;
+    let result = device_io_control(
+        &device,
+        IOCTL_RUN_PAYLOAD,
+        &payload.to_ne_bytes(),
+        ptr::from_mut(&mut output).cast(),
+        size_of::<u32>(),
+    );
+    let refused = env.hvci || cfg!(target_arch = "aarch64");
+    check_refusal(result, refused)?;
+    if refused || !strict {
+        return Ok(());
+    }
+
+    // The address is preceded by the start of the allocation, and the second
+    // request lacks the output buffer. Neither is run.
+    let unreferenced = payload - size_of::<usize>();
+    for (address, output_length) in [(unreferenced, size_of::<u32>()), (payload, 0)] {
+        let result = device_io_control(
+            &device,
+            IOCTL_RUN_PAYLOAD,
+            &address.to_ne_bytes(),
+            ptr::from_mut(&mut output).cast(),
+            output_length,
+        );
+        let Err(err) = result else {
+            bail!("the payload at {address:#x} was run with {output_length} bytes of output");
+        };
+        ensure!(
+            err.raw_os_error() == Some(ERROR_INVALID_PARAMETER.cast_signed()),
+            "the payload at {address:#x} was refused with an unexpected error: {err}"
+        );
+    }
+    Ok(())
+}
+
+/// Returns the address of `name` exported from ntoskrnl.exe, found in the image
+/// loaded into this process.
+fn kernel_export(device: &Device, name: &CStr) -> Result<u64> {
+    let kernel = device.get_module(None)?;
+    let path: Vec<u16> = "ntoskrnl.exe".encode_utf16().chain([0]).collect();
+    let module =
+        unsafe { LoadLibraryExW(path.as_ptr(), ptr::null_mut(), DONT_RESOLVE_DLL_REFERENCES) };
+    ensure!(
+        !module.is_null(),
+        "could not load ntoskrnl.exe: {}",
+        io::Error::last_os_error()
+    );
+    let address = unsafe { GetProcAddress(module, name.as_ptr().cast()) };
+    let _ = unsafe { FreeLibrary(module) };
+    let Some(address) = address else {
+        bail!("{name:?} is not exported");
+    };
+    Ok(kernel.base + (address as usize - module.addr()) as u64)
+}
+
+/// Checks the personality the driver reports, and that the `hardened` one calls
+/// a payload in a copy in system space instead of at its user-mode address.
+fn test_personality(env: &Environment) -> Result<()> {
+    // `lea rax, [rip]; ret`
+    const CODE: &[u8] = &[0x48, 0x8d, 0x05, 0x00, 0x00, 0x00, 0x00, 0xc3];
+
+    let features = Device::open()?.get_build_info()?.features;
+    let faithful = features & BUILD_FEATURE_FAITHFUL != 0;
+    let hardened = features & BUILD_FEATURE_HARDENED != 0;
+    ensure!(!(faithful && hardened), "both personalities are reported");
+    ensure!(
+        !faithful || features & BUILD_FEATURE_STRICT_COMPAT != 0,
+        "the faithful personality is built without strict-compat"
+    );
+    if !hardened || env.hvci || !cfg!(target_arch = "x86_64") {
+        return Ok(());
+    }
+
+    let page = ExecutablePage::new()?;
+    page.write(0, CODE);
+    let device = open_device()?;
+    let mut transcript = PayloadTranscript::default();
+    let bytes_returned = device_io_control(
+        &device,
+        IOCTL_RUN_PAYLOAD,
+        &page.address().to_ne_bytes(),
+        ptr::from_mut(&mut transcript).cast(),
+        size_of::<PayloadTranscript>(),
+    )?;
+    // The defanged build returns no transcript.
+    if bytes_returned == 0 {
+        return Ok(());
+    }
+    let user = (page.address() + CODE.len() - 1) as u64;
+    let executed = transcript.after.gprs[0];
+    ensure!(
+        executed != user && executed & 0xfff == user & 0xfff,
+        "the payload at {user:#x} ran at {executed:#x}"
+    );
+    Ok(())
+}
+
+/// Runs a payload with the event ring enabled, and checks that a build with
+/// the `teaching` feature logs the steps of handling it in order.
+fn test_teaching(env: &Environment) -> Result<()> {
+    // `ret`
+    #[cfg(target_arch = "x86_64")]
+    const CODE: &[u8] = &[0xc3];
+    #[cfg(target_arch = "aarch64")]
+    const CODE: &[u8] = &0xd65f_03c0_u32.to_le_bytes();
+
+    let features = Device::open()?.get_build_info()?.features;
+    if features & BUILD_FEATURE_TEACHING == 0 {
+        return Ok(());
+    }
+    let refused = env.hvci || cfg!(target_arch = "aarch64");
+    let steps: &[u32] = if refused {
+        &[messages::TEACHING_IRP_ARRIVED, messages::TEACHING_COMPLETED]
+    } else if features & BUILD_FEATURE_DEFANGED != 0 {
+        &[
+            messages::TEACHING_IRP_ARRIVED,
+            messages::TEACHING_BUFFERS,
+            messages::TEACHING_PAYLOAD_ADDRESS,
+            messages::TEACHING_COMPLETED,
+        ]
+    } else if features & BUILD_FEATURE_FAITHFUL != 0 {
+        // No registers are recorded around the call.
+        &[
+            messages::TEACHING_IRP_ARRIVED,
+            messages::TEACHING_BUFFERS,
+            messages::TEACHING_PAYLOAD_ADDRESS,
+            messages::TEACHING_CALLING,
+            messages::TEACHING_RETURNED,
+            messages::TEACHING_COMPLETED,
+        ]
+    } else {
+        &[
+            messages::TEACHING_IRP_ARRIVED,
+            messages::TEACHING_BUFFERS,
+            messages::TEACHING_PAYLOAD_ADDRESS,
+            messages::TEACHING_CALLING,
+            messages::TEACHING_RETURNED,
+            messages::TEACHING_CR4,
+            messages::TEACHING_COMPLETED,
+        ]
+    };
+
+    // Lay the payload out as the original driver requires, which every build
+    // accepts.
+    let page = ExecutablePage::new()?;
+    let payload = page.address() + size_of::<usize>();
+    page.write(0, &payload.to_ne_bytes());
+    page.write(size_of::<usize>(), CODE);
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY | CLASS_EXECUTE)?;
+    let (event, mut ring) = enable_event_ring(&device)?;
+    let mut output = 0_u32;
+    let result = device_io_control(
+        &device,
+        IOCTL_RUN_PAYLOAD,
+        &payload.to_ne_bytes(),
+        ptr::from_mut(&mut output).cast(),
+        size_of::<u32>(),
+    );
+    let mut logged = Vec::new();
+    while !logged.ends_with(&[messages::TEACHING_COMPLETED])
+        && unsafe { WaitForSingleObject(event, 1000) } == WAIT_OBJECT_0
+    {
+        while let Some(record) = ring.pop() {
+            if record.kind == EVENT_KIND_MESSAGE && steps.contains(&record.message.id) {
+                logged.push(record.message.id);
+            }
+        }
+    }
+    let _ = unsafe { CloseHandle(event) };
+    check_refusal(result, refused)?;
+    ensure!(logged == steps, "the steps were logged as {logged:?}");
+    Ok(())
+}
+
+/// Runs the public exploit given with `--compat-poc` unchanged, and checks that
+/// it succeeds against the driver as against the original.
+fn test_compat_poc(env: &Environment) -> Result<()> {
+    let Some(path) = &env.compat_poc else {
+        return Err(Skipped("no PoC was given with --compat-poc").into());
+    };
+    let status = Command::new(path)
+        .status()
+        .with_context(|| format!("could not run {path}"))?;
+    ensure!(status.success(), "{path} failed with {status}");
+    Ok(())
+}
+
+/// Runs this program with `--elevate` and a token in which the Administrators
+/// group is deny-only, and checks that it elevates itself to SYSTEM, or is
+/// refused where payloads cannot run.
+fn test_elevate(env: &Environment) -> Result<()> {
+    if Device::open()?.get_build_info()?.features & BUILD_FEATURE_DEFANGED != 0 {
+        return Err(Skipped("the defanged build does not run payloads").into());
+    }
+    let code = run_restricted("--elevate")?;
+    let expected = if env.hvci || cfg!(target_arch = "aarch64") {
+        u32::from(ELEVATE_REFUSED)
+    } else {
+        0
+    };
+    ensure!(
+        code == expected,
+        "the restricted process exited with {code} instead of {expected}"
+    );
+    Ok(())
+}
+
+/// Sets the debug-break mode to the default, and checks that an unknown mode
+/// is rejected.
+fn test_set_debug_break(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(0)?;
+    device.set_debug_break(DEBUG_BREAK_ON_LOAD)?;
+    let Err(err) = device.set_debug_break(DEBUG_BREAK_ON_PANIC_ONLY + 1) else {
+        bail!("an unknown mode was accepted");
+    };
+    ensure!(
+        err.raw_os_error() == Some(ERROR_INVALID_PARAMETER.cast_signed()),
+        "an unknown mode was rejected with an unexpected error: {err}"
+    );
+    Ok(())
+}
+
+/// Silences the messages below warnings in the debug output, checks that the
+/// event ring still receives them, and restores the default filter.
+fn test_print_filter(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY)?;
+    let (event, mut ring) = enable_event_ring(&device)?;
+    let set_filter = |level_mask| {
+        let request = PrintFilterRequest { level_mask };
+        device_io_control(
+            &device,
+            IOCTL_SET_PRINT_FILTER,
+            as_bytes(&request),
+            ptr::null_mut(),
+            0,
+        )
+    };
+    let result = set_filter(1 << messages::LEVEL_ERROR | 1 << messages::LEVEL_WARNING);
+    let mut logged = false;
+    while result.is_ok() && !logged && unsafe { WaitForSingleObject(event, 1000) } == WAIT_OBJECT_0
+    {
+        while let Some(record) = ring.pop() {
+            logged |= record.kind == EVENT_KIND_MESSAGE
+                && record.message.id == messages::PRINT_LEVEL_MASK;
+        }
+    }
+    let _ = unsafe { CloseHandle(event) };
+    let _ = set_filter(PRINT_LEVEL_MASK_ALL)?;
+    let _ = result?;
+    ensure!(
+        logged,
+        "the message of the filter did not reach the event ring"
+    );
+    Ok(())
+}
+
+/// Saves a config without fields, which leaves the target configured by the
+/// service key, and checks that a config of an unknown version is rejected.
+fn test_save_config(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(0)?;
+    device.save_config(&SavedConfig {
+        version: SAVED_CONFIG_VERSION,
+        ..SavedConfig::default()
+    })?;
+    let Err(err) = device.save_config(&SavedConfig {
+        version: SAVED_CONFIG_VERSION + 1,
+        ..SavedConfig::default()
+    }) else {
+        bail!("an unknown version was accepted");
+    };
+    ensure!(
+        err.raw_os_error() == Some(ERROR_INVALID_PARAMETER.cast_signed()),
+        "an unknown version was rejected with an unexpected error: {err}"
+    );
+    Ok(())
+}
+
+/// Saves a configuration disabling `CLASS_MSR` to be applied after a reboot, or
+/// checks that it was applied and saves an empty one, depending on
+/// `--persistence`.
+fn test_persistence(env: &Environment) -> Result<()> {
+    let Some(persistence) = &env.persistence else {
+        return Ok(());
+    };
+    let device = Device::open()?;
+    let _ = device.negotiate(0)?;
+    let enabled_classes = device.get_version()?.enabled_classes;
+    match persistence.as_str() {
+        "save" => {
+            ensure!(
+                enabled_classes & CLASS_MSR != 0,
+                "CLASS_MSR is already disabled"
+            );
+            device.save_config(&SavedConfig {
+                version: SAVED_CONFIG_VERSION,
+                fields: CONFIG_ENABLED_CLASSES,
+                enabled_classes: enabled_classes & !CLASS_MSR,
+                ..SavedConfig::default()
+            })?;
+        }
+        "check" => {
+            ensure!(
+                enabled_classes & CLASS_MSR == 0,
+                "the saved configuration was not applied: enabled classes {enabled_classes:#x}"
+            );
+            device.save_config(&SavedConfig {
+                version: SAVED_CONFIG_VERSION,
+                ..SavedConfig::default()
+            })?;
+        }
+        _ => bail!("unknown --persistence {persistence}"),
+    }
+    Ok(())
+}
+
+/// Checks that the control device accepts the control IOCTLs without
+/// negotiation, and refuses others.
+fn test_control_device(_env: &Environment) -> Result<()> {
+    let device = Device::open_control()?;
+    let version = device.get_version()?;
+    ensure!(
+        version.abi_version == ABI_VERSION,
+        "unexpected ABI version {}",
+        version.abi_version
+    );
+    let mut audit = AuditInfo::default();
+    let _ = device.ioctl(IOCTL_GET_AUDIT, &[], unsafe {
+        slice::from_raw_parts_mut(ptr::from_mut(&mut audit).cast(), size_of::<AuditInfo>())
+    })?;
+    device.set_debug_break(DEBUG_BREAK_ON_LOAD)?;
+
+    let Err(err) = device.ioctl(IOCTL_RUN_SHELLCODE, &[0xc3], &mut []) else {
+        bail!("the shellcode was run on the control device");
+    };
+    ensure!(
+        err.raw_os_error() == Some(ERROR_INVALID_FUNCTION.cast_signed()),
+        "the shellcode was refused with an unexpected error: {err}"
+    );
+    Ok(())
+}
+
+/// Checks that the device interface, if the driver registered it, opens the
+/// compatible device.
+fn test_device_interface(_env: &Environment) -> Result<()> {
+    let device = match Device::open_interface() {
+        Ok(device) => device,
+        Err(err) if err.raw_os_error() == Some(ERROR_NOT_FOUND.cast_signed()) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    let version = device.get_version()?;
+    ensure!(
+        version.abi_version == ABI_VERSION,
+        "unexpected ABI version {}",
+        version.abi_version
+    );
+    Ok(())
+}
+
+/// Checks that stealth names are letters, and differ between the devices and
+/// boots.
+fn test_stealth_name(_env: &Environment) -> Result<()> {
+    let name = stealth_name(1, 1, false);
+    ensure!(
+        name.iter().all(u8::is_ascii_lowercase),
+        "unexpected name {}",
+        name.escape_ascii()
+    );
+    ensure!(
+        name != stealth_name(1, 1, true),
+        "the devices have the same name"
+    );
+    ensure!(
+        name != stealth_name(1, 2, false),
+        "the name did not change across boots"
+    );
+    Ok(())
+}
+
+/// Checks that the context of an open handle is counted as an outstanding
+/// allocation.
+fn test_query_allocations(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let allocations = device.query_allocations()?;
+    let contexts = allocations
+        .iter()
+        .find(|info| info.tag == u32::from_le_bytes(*b"CpcC"))
+        .context("no allocations of contexts")?;
+    ensure!(
+        contexts.outstanding >= 1 && contexts.total >= contexts.outstanding,
+        "unexpected counts {contexts:?}"
+    );
+    Ok(())
+}
+
+/// Checks that IOCTLs not in the original driver are denied until the handle
+/// negotiates, and that a handle can negotiate only once.
+fn test_negotiate(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let Err(err) = run_shellcode(&device) else {
+        bail!("the shellcode was run without negotiation");
+    };
+    ensure!(
+        err.raw_os_error() == Some(ERROR_ACCESS_DENIED.cast_signed()),
+        "the shellcode was refused with an unexpected error: {err}"
+    );
+
+    let response = negotiate(&device, CLASS_EXECUTE)?;
+    ensure!(
+        response.granted_classes == CLASS_EXECUTE,
+        "unexpected granted classes {:#x}",
+        response.granted_classes
+    );
+    ensure!(
+        negotiate(&device, CLASS_EXECUTE).is_err(),
+        "the handle negotiated twice"
+    );
+    Ok(())
+}
+
+/// Checks that a request is recorded with this process as the caller.
+fn test_read_log(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let mut version = VersionInfo::default();
+    let _ = device_io_control(
+        &device,
+        IOCTL_GET_VERSION,
+        &[],
+        ptr::from_mut(&mut version).cast(),
+        size_of::<VersionInfo>(),
+    )?;
+
+    let found = read_log(&device, |record| {
+        record.process_id == u64::from(process::id()) && record.control_code == IOCTL_GET_VERSION
+    })?;
+    ensure!(found, "the request was not recorded");
+    Ok(())
+}
+
+/// Reads the log of the driver, and returns whether any record satisfies
+/// `predicate`.
+fn read_log(device: &File, predicate: impl Fn(&LogRecord) -> bool) -> Result<bool> {
+    // Read until the buffer is not filled, as other processes may have sent
+    // requests. Each read is also recorded, so the log never becomes empty.
+    let mut records = [LogRecord::default(); 16];
+    let mut found = false;
+    loop {
+        let bytes_returned = device_io_control(
+            device,
+            IOCTL_READ_LOG,
+            &[],
+            records.as_mut_ptr().cast(),
+            size_of_val(&records),
+        )?;
+        found |= records[..bytes_returned / size_of::<LogRecord>()]
+            .iter()
+            .any(&predicate);
+        if bytes_returned < size_of_val(&records) {
+            break;
+        }
+    }
+    Ok(found)
+}
+
+/// Checks that executions are counted and chained. The kill switch is not
+/// tested as it stays engaged until the driver restarts.
+fn test_audit(env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_EXECUTE)?;
+    let before = get_audit(&device)?;
+    let result = run_shellcode(&device);
+    check_refusal(result, env.hvci)?;
+    let after = get_audit(&device)?;
+    let expected = before.executions + u64::from(!env.hvci);
+    ensure!(
+        after.executions >= expected,
+        "{} executions were counted but at least {expected} are expected",
+        after.executions
+    );
+    // Other processes may have executed payloads in between, which cannot be
+    // chained here.
+    if after.executions == before.executions + 1 {
+        let expected = chain_execution(before.chain, after.executions, process::id().into());
+        ensure!(
+            after.chain == expected,
+            "the audit chain is {:#x} but {expected:#x} is expected",
+            after.chain
+        );
+    }
+    ensure!(after.killed == 0, "the kill switch is engaged");
+    Ok(())
+}
+
+/// Takes a snapshot of the first processor. It is not supported on ARM64.
+fn test_snapshot_cpu_state(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY)?;
+    let request = CpuStateRequest {
+        processor: 0,
+        first_vector: 0,
+    };
+    let mut state = CpuState::default();
+    let result = device_io_control(
+        &device,
+        IOCTL_SNAPSHOT_CPU_STATE,
+        as_bytes(&request),
+        ptr::from_mut(&mut state).cast(),
+        size_of::<CpuState>(),
+    );
+    check_refusal(result, cfg!(target_arch = "aarch64"))?;
+    if cfg!(target_arch = "x86_64") {
+        ensure!(
+            state.idtr_base != 0 && state.kpcr != 0 && state.tss_base != 0,
+            "unexpected state {state:x?}"
+        );
+        ensure!(
+            state.idt_entry_count as usize == CPU_STATE_IDT_ENTRIES,
+            "unexpected IDT entry count {}",
+            state.idt_entry_count
+        );
+    }
+    Ok(())
+}
+
+/// Gets the entry translating a local variable, which must be present. It is
+/// not supported on ARM64.
+fn test_get_pte(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY)?;
+    let local = 0u64;
+    let request = PteRequest {
+        process_id: 0,
+        address: ptr::from_ref(&local).addr() as u64,
+    };
+    let mut info = PteInfo::default();
+    let result = device_io_control(
+        &device,
+        IOCTL_GET_PTE,
+        as_bytes(&request),
+        ptr::from_mut(&mut info).cast(),
+        size_of::<PteInfo>(),
+    );
+    check_refusal(result, cfg!(target_arch = "aarch64"))?;
+    if cfg!(target_arch = "x86_64") {
+        ensure!(
+            info.entry & PTE_PRESENT != 0 && (info.level == 1 || info.level == 2),
+            "unexpected entry {info:x?}"
+        );
+        ensure!(
+            info.physical_address & 0xfff == request.address & 0xfff,
+            "unexpected physical address {:#x}",
+            info.physical_address
+        );
+    }
+    Ok(())
+}
+
+/// Captures the kernel stack of a thread blocked on a channel, both with an APC
+/// and by unwinding the saved stack, which must have frames and belong to this
+/// process. Unwinding requires the offset of `KTHREAD::KernelStack` and x64.
+fn test_capture_thread(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY)?;
+    let mut offsets = KernelOffsets::default();
+    let _ = device_io_control(
+        &device,
+        IOCTL_GET_OFFSETS,
+        &[],
+        ptr::from_mut(&mut offsets).cast(),
+        size_of::<KernelOffsets>(),
+    )?;
+
+    let (id_sender, id_receiver) = mpsc::channel();
+    let (exit_sender, exit_receiver) = mpsc::channel::<()>();
+    let waiter = thread::spawn(move || {
+        id_sender.send(unsafe { GetCurrentThreadId() }).unwrap();
+        let _ = exit_receiver.recv();
+    });
+    let thread_id = u64::from(id_receiver.recv()?);
+    let capture = |flags| {
+        let request = ThreadCaptureRequest {
+            thread_id,
+            timeout_ms: 0,
+            flags,
+        };
+        let mut capture = ThreadCapture::default();
+        device_io_control(
+            &device,
+            IOCTL_CAPTURE_THREAD,
+            as_bytes(&request),
+            ptr::from_mut(&mut capture).cast(),
+            size_of::<ThreadCapture>(),
+        )
+        .map(|_| capture)
+    };
+    let results = [capture(0), capture(THREAD_CAPTURE_SAVED_STACK)];
+    drop(exit_sender);
+    waiter.join().unwrap();
+
+    let unwinds = cfg!(target_arch = "x86_64") && offsets.kthread_kernel_stack != 0;
+    for (result, unwound) in results.into_iter().zip([false, true]) {
+        if unwound && !unwinds {
+            ensure!(result.is_err(), "the saved stack was unwound");
+            continue;
+        }
+        let capture = result?;
+        ensure!(
+            capture.process_id == u64::from(process::id()),
+            "unexpected process ID {}",
+            capture.process_id
+        );
+        ensure!(
+            capture.frame_count != 0 && capture.frames[0] != 0 && (capture.unwound != 0) == unwound,
+            "unexpected capture {capture:x?}"
+        );
+    }
+    Ok(())
+}
+
+/// Lists `\\Device` a few objects at a time, which must contain the device of
+/// the driver.
+fn test_enum_directory(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, 0)?;
+
+    let device_name = DEVICE_NAME.rsplit('\\').next().unwrap();
+    let mut entries = [DirectoryEntry::default(); 8];
+    let mut index = 0;
+    let mut found = false;
+    loop {
+        let mut input = as_bytes(&EnumDirectoryRequest { index, reserved: 0 }).to_vec();
+        input.extend(r"\Device".encode_utf16().flat_map(u16::to_ne_bytes));
+        let bytes_returned = device_io_control(
+            &device,
+            IOCTL_ENUM_DIRECTORY,
+            &input,
+            entries.as_mut_ptr().cast(),
+            size_of_val(&entries),
+        )?;
+        let count = bytes_returned / size_of::<DirectoryEntry>();
+        found |= entries[..count].iter().any(|entry| {
+            let length = (entry.name_length as usize).min(entry.name.len());
+            String::from_utf16_lossy(&entry.name[..length]) == device_name
+        });
+        if count < entries.len() {
+            break;
+        }
+        index += count as u32;
+    }
+    ensure!(found, "{device_name} was not listed");
+    Ok(())
+}
+
+/// Duplicates the handle to the device of this process, which must differ from
+/// the original.
+fn test_dup_handle(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_ELEVATION)?;
+    let request = DupHandleRequest {
+        process_id: u64::from(process::id()),
+        handle: device.as_raw_handle().addr() as u64,
+        desired_access: 0,
+        options: DUPLICATE_SAME_ACCESS,
+    };
+    let mut response = DupHandleResponse::default();
+    let _ = device_io_control(
+        &device,
+        IOCTL_DUP_HANDLE,
+        as_bytes(&request),
+        ptr::from_mut(&mut response).cast(),
+        size_of::<DupHandleResponse>(),
+    )?;
+    ensure!(
+        response.handle != 0 && response.handle != request.handle,
+        "unexpected handle {:#x}",
+        response.handle
+    );
+    // Take the ownership to close it.
+    drop(unsafe { File::from_raw_handle(ptr::without_provenance_mut(response.handle as usize)) });
+    Ok(())
+}
+
+/// Queues a user-mode APC to a thread of this process in an alertable wait,
+/// which must run it with the given arguments.
+fn test_queue_user_apc(_env: &Environment) -> Result<()> {
+    static ARGUMENTS: Mutex<Option<[usize; 3]>> = Mutex::new(None);
+
+    extern "system" fn routine(argument1: usize, argument2: usize, argument3: usize) {
+        *ARGUMENTS.lock().unwrap() = Some([argument1, argument2, argument3]);
+    }
+
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_ELEVATION)?;
+
+    let (id_sender, id_receiver) = mpsc::channel();
+    let (ready_sender, ready_receiver) = mpsc::channel::<()>();
+    let waiter = thread::spawn(move || {
+        id_sender.send(unsafe { GetCurrentThreadId() }).unwrap();
+        let _ = ready_receiver.recv();
+        unsafe { SleepEx(5000, 1) }
+    });
+    let routine: extern "system" fn(usize, usize, usize) = routine;
+    let request = UserApcRequest {
+        thread_id: u64::from(id_receiver.recv()?),
+        routine: routine as usize as u64,
+        argument1: 1,
+        argument2: 2,
+        argument3: 3,
+    };
+    let result = device_io_control(
+        &device,
+        IOCTL_QUEUE_USER_APC,
+        as_bytes(&request),
+        ptr::null_mut(),
+        0,
+    );
+    drop(ready_sender);
+    let wait_result = waiter.join().unwrap();
+
+    let _ = result?;
+    ensure!(
+        wait_result == WAIT_IO_COMPLETION,
+        "the wait ended with {wait_result:#x}"
+    );
+    let arguments = *ARGUMENTS.lock().unwrap();
+    ensure!(
+        arguments == Some([1, 2, 3]),
+        "the routine was called with {arguments:?}"
+    );
+    Ok(())
+}
+
+/// Reads the build number of Windows, which must be a non-empty `REG_SZ`.
+/// Writing is not tested to keep the target unchanged.
+fn test_reg_query(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_ELEVATION)?;
+
+    let key_path: Vec<u16> = r"\Registry\Machine\SOFTWARE\Microsoft\Windows NT\CurrentVersion"
+        .encode_utf16()
+        .collect();
+    let name: Vec<u16> = "CurrentBuild".encode_utf16().collect();
+    let request = RegistryRequest {
+        key_length: size_of_val(key_path.as_slice()) as u32,
+        name_length: size_of_val(name.as_slice()) as u32,
+        value_type: 0,
+        data_length: 0,
+    };
+    let mut input = as_bytes(&request).to_vec();
+    input.extend(
+        key_path
+            .iter()
+            .chain(&name)
+            .flat_map(|unit| unit.to_ne_bytes()),
+    );
+
+    let mut output = [0u8; 256];
+    let bytes_returned = device_io_control(
+        &device,
+        IOCTL_REG_QUERY,
+        &input,
+        output.as_mut_ptr().cast(),
+        output.len(),
+    )?;
+    let value = unsafe { output.as_ptr().cast::<RegistryValue>().read_unaligned() };
+    ensure!(
+        value.value_type == REG_SZ && value.data_length > 2,
+        "unexpected value {value:?}"
+    );
+    ensure!(
+        bytes_returned == size_of::<RegistryValue>() + value.data_length as usize,
+        "unexpected output size {bytes_returned}"
+    );
+    Ok(())
+}
+
+/// Reads the header of the SAM hive, which the kernel keeps open exclusively.
+/// Writing is not tested to keep the target unchanged.
+fn test_read_file(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_ELEVATION)?;
+
+    let path: Vec<u16> = r"\SystemRoot\System32\config\SAM".encode_utf16().collect();
+    let request = FileRequest {
+        offset: 0,
+        path_length: size_of_val(path.as_slice()) as u32,
+        data_length: 0,
+    };
+    let mut input = as_bytes(&request).to_vec();
+    input.extend(path.iter().flat_map(|unit| unit.to_ne_bytes()));
+
+    let mut signature = [0u8; 4];
+    let bytes_returned = device_io_control(
+        &device,
+        IOCTL_READ_FILE,
+        &input,
+        signature.as_mut_ptr().cast(),
+        signature.len(),
+    )?;
+    ensure!(
+        bytes_returned == signature.len() && &signature == b"regf",
+        "unexpected header {:x?}",
+        &signature[..bytes_returned]
+    );
+    Ok(())
+}
+
+/// Reads the SAM hive into a large buffer with direct I/O and checks its
+/// signature.
+fn test_read_file_direct(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_ELEVATION)?;
+
+    let path: Vec<u16> = r"\SystemRoot\System32\config\SAM".encode_utf16().collect();
+    let request = FileRequest {
+        offset: 0,
+        path_length: size_of_val(path.as_slice()) as u32,
+        data_length: 0,
+    };
+    let mut input = as_bytes(&request).to_vec();
+    input.extend(path.iter().flat_map(|unit| unit.to_ne_bytes()));
+
+    let mut buffer = vec![0u8; 4 * 1024 * 1024];
+    let bytes_returned = device_io_control(
+        &device,
+        IOCTL_READ_FILE_DIRECT,
+        &input,
+        buffer.as_mut_ptr().cast(),
+        buffer.len(),
+    )?;
+    ensure!(
+        bytes_returned > 4 && buffer.starts_with(b"regf"),
+        "unexpected header {:x?}",
+        &buffer[..bytes_returned.min(4)]
+    );
+    Ok(())
+}
+
+/// Registers the NMI callback, samples processor 0 with an NMI and unregisters
+/// the callback. It is not supported on ARM64.
+fn test_sample_nmi(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY)?;
+    let set_callback = |enable| {
+        let request = NmiCallbackRequest {
+            enable,
+            reserved: 0,
+        };
+        device_io_control(
+            &device,
+            IOCTL_SET_NMI_CALLBACK,
+            as_bytes(&request),
+            ptr::null_mut(),
+            0,
+        )
+    };
+    let _ = set_callback(1)?;
+
+    let request = NmiSampleRequest {
+        processor: 0,
+        reserved: 0,
+    };
+    let mut sample = NmiSample::default();
+    let result = device_io_control(
+        &device,
+        IOCTL_SAMPLE_NMI,
+        as_bytes(&request),
+        ptr::from_mut(&mut sample).cast(),
+        size_of::<NmiSample>(),
+    );
+    let _ = set_callback(0)?;
+    check_refusal(result, cfg!(target_arch = "aarch64"))?;
+    if cfg!(target_arch = "x86_64") {
+        ensure!(
+            sample.rip != 0 && sample.cs != 0 && sample.processor == 0,
+            "unexpected sample {sample:x?}"
+        );
+        let found = read_log(&device, |record| {
+            record.control_code == IOCTL_SAMPLE_NMI && record.rip == sample.rip
+        })?;
+        ensure!(found, "the sample was not recorded");
+    }
+    Ok(())
+}
+
+/// Reads the version register of the local APIC of processor 0. Writing is not
+/// tested, as any write changes how interrupts are delivered. It is not
+/// supported on ARM64.
+fn test_read_apic(_env: &Environment) -> Result<()> {
+    const VERSION: u32 = 0x30;
+
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_MSR)?;
+    let request = ApicRequest {
+        processor: 0,
+        offset: VERSION,
+        value: 0,
+    };
+    let mut version = 0u64;
+    let result = device_io_control(
+        &device,
+        IOCTL_READ_APIC,
+        as_bytes(&request),
+        ptr::from_mut(&mut version).cast(),
+        size_of::<u64>(),
+    );
+    check_refusal(result, cfg!(target_arch = "aarch64"))?;
+    // Integrated APICs have versions from 0x10.
+    if cfg!(target_arch = "x86_64") {
+        ensure!(version & 0xff >= 0x10, "unexpected version {version:#x}");
+    }
+    Ok(())
+}
+
+/// Reads the vendor ID of the device at bus 0, device 0, function 0, which is
+/// the host bridge on the targets. Writing is not tested to keep devices
+/// unchanged.
+fn test_pci_config_rw(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_PHYSICAL_MEMORY)?;
+    let request = PciConfigRequest {
+        size: 2,
+        ..PciConfigRequest::default()
+    };
+    let mut vendor_id = 0u32;
+    let _ = device_io_control(
+        &device,
+        IOCTL_PCI_CONFIG_RW,
+        as_bytes(&request),
+        ptr::from_mut(&mut vendor_id).cast(),
+        size_of::<u32>(),
+    )?;
+    ensure!(
+        vendor_id != 0 && vendor_id != 0xffff,
+        "unexpected vendor ID {vendor_id:#x}"
+    );
+    Ok(())
+}
+
+/// Allocates a page of contiguous memory and frees it. Freeing it again must
+/// fail as the handle no longer owns it.
+fn test_alloc_contiguous(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_PHYSICAL_MEMORY)?;
+    let request = ContiguousAllocRequest {
+        size: 0x1000,
+        ..ContiguousAllocRequest::default()
+    };
+    let mut allocation = ContiguousAllocation::default();
+    let _ = device_io_control(
+        &device,
+        IOCTL_ALLOC_CONTIGUOUS,
+        as_bytes(&request),
+        ptr::from_mut(&mut allocation).cast(),
+        size_of::<ContiguousAllocation>(),
+    )?;
+    ensure!(
+        allocation.address != 0
+            && allocation.physical_address != 0
+            && allocation.physical_address.is_multiple_of(0x1000),
+        "unexpected allocation {allocation:x?}"
+    );
+
+    let request = ContiguousFreeRequest {
+        address: allocation.address,
+    };
+    let free = || {
+        device_io_control(
+            &device,
+            IOCTL_FREE_CONTIGUOUS,
+            as_bytes(&request),
+            ptr::null_mut(),
+            0,
+        )
+    };
+    let _ = free()?;
+    ensure!(free().is_err(), "the memory was freed twice");
+    Ok(())
+}
+
+/// Dumps the first 1MB of physical memory in chunks and checks that the chunks
+/// are in order within the range.
+fn test_dump_physical_range(_env: &Environment) -> Result<()> {
+    const SIZE: u64 = 0x10_0000;
+
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_PHYSICAL_MEMORY)?;
+    let mut request = PhysicalDumpRequest {
+        address: 0,
+        size: SIZE,
+        cursor: 0,
+    };
+    let mut buffer = vec![0u8; size_of::<PhysicalDumpChunk>() + 0x1_0000];
+    let mut dumped = 0;
+    while request.cursor < SIZE {
+        let bytes_returned = device_io_control(
+            &device,
+            IOCTL_DUMP_PHYSICAL_RANGE,
+            as_bytes(&request),
+            buffer.as_mut_ptr().cast(),
+            buffer.len(),
+        )?;
+        let chunk = unsafe { buffer.as_ptr().cast::<PhysicalDumpChunk>().read_unaligned() };
+        ensure!(
+            bytes_returned == size_of::<PhysicalDumpChunk>() + chunk.size as usize
+                && chunk.cursor > request.cursor
+                && chunk.cursor <= SIZE
+                && chunk.address >= request.cursor
+                && (chunk.size == 0 || chunk.address + chunk.size == chunk.cursor),
+            "unexpected chunk {chunk:x?} for {request:x?}"
+        );
+        dumped += chunk.size;
+        request.cursor = chunk.cursor;
+    }
+    ensure!(
+        dumped != 0 && dumped <= SIZE,
+        "unexpected dumped size {dumped:#x}"
+    );
+    Ok(())
+}
+
+/// Reads the offsets in use and sets them back. Offsets for another build must
+/// be refused.
+fn test_offsets(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY)?;
+    let mut offsets = KernelOffsets::default();
+    let _ = device_io_control(
+        &device,
+        IOCTL_GET_OFFSETS,
+        &[],
+        ptr::from_mut(&mut offsets).cast(),
+        size_of::<KernelOffsets>(),
+    )?;
+    let set = |offsets: &KernelOffsets| {
+        device_io_control(
+            &device,
+            IOCTL_SET_OFFSETS,
+            as_bytes(offsets),
+            ptr::null_mut(),
+            0,
+        )
+    };
+    if offsets.build_number != 0 {
+        let _ = set(&offsets)?;
+    }
+
+    let other_build = KernelOffsets {
+        build_number: offsets.build_number + 1,
+        ..offsets
+    };
+    ensure!(
+        set(&other_build).is_err(),
+        "offsets for another build were set"
+    );
+    Ok(())
+}
+
+/// Resolves the offsets from the PDBs on the symbol server and sets them. They
+/// must match the built-in offsets if any.
+fn test_resolve_offsets(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+    let built_in = device.get_offsets()?;
+    let offsets = symbols::upload_offsets(&device, &env::temp_dir().join("capcom-symbols"))?;
+    if built_in.build_number != 0 {
+        let resolved = KernelOffsets {
+            psp_create_process_notify_routine: 0,
+            psp_create_thread_notify_routine: 0,
+            psp_load_image_notify_routine: 0,
+            ci_options: 0,
+            ..offsets
+        };
+        ensure!(
+            resolved == built_in,
+            "resolved {resolved:x?} differs from the built-in {built_in:x?}"
+        );
+    }
+    let active = device.get_offsets()?;
+    ensure!(active == offsets, "unexpected offsets {active:x?}");
+    Ok(())
+}
+
+/// Gets the addresses of ntoskrnl.exe and CI.dll. A module that is not loaded
+/// must not be found.
+fn test_get_kernel_base(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+    let kernel = device.get_module(None)?;
+    let ci = device.get_module(Some("ci.DLL"))?;
+    ensure!(
+        kernel.base.is_multiple_of(0x1000) && kernel.size != 0 && kernel.base != ci.base,
+        "unexpected modules {kernel:x?} and {ci:x?}"
+    );
+    ensure!(
+        device.get_module(Some("nonexistent.sys")).is_err(),
+        "a module that is not loaded was found"
+    );
+    Ok(())
+}
+
+/// Reads the headers of ntoskrnl.exe and finds its PE signature with a
+/// pattern. Reading the null page must fail.
+fn test_read_memory(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+    let kernel = device.get_module(None)?;
+    let memory = KernelMem::new(&device);
+    let magic = unsafe { memory.read_struct::<u16>(kernel.base)? };
+    ensure!(magic == 0x5a4d, "unexpected DOS signature {magic:#x}");
+    let offset = unsafe { memory.read_struct::<u32>(kernel.base + 0x3c)? };
+    let nt_headers = kernel.base + u64::from(offset);
+    let signature = memory.read_cstr(nt_headers, 4)?;
+    ensure!(
+        signature.as_bytes() == b"PE",
+        "unexpected PE signature {signature:?}"
+    );
+
+    let pattern = Pattern::parse("50 45 00 00 ?? ??").context("invalid pattern")?;
+    let matches = memory.scan(kernel.base..kernel.base + 0x1000, &pattern)?;
+    ensure!(
+        matches.contains(&nt_headers),
+        "the PE signature at {nt_headers:#x} was not found in {matches:x?}"
+    );
+
+    let Err(err) = memory.read(0, &mut [0; 8]) else {
+        bail!("the null page was read");
+    };
+    ensure!(
+        err.raw_os_error() == Some(ERROR_NOACCESS.cast_signed()),
+        "the read was refused with an unexpected error: {err}"
+    );
+    Ok(())
+}
+
+/// Scans the first page of ntoskrnl.exe with a pattern that matches anywhere,
+/// which takes several requests to return every match, and a physical range.
+/// Physical memory must not be scanned without its class, and an empty
+/// pattern must be refused.
+fn test_scan_memory(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+    let kernel = device.get_module(None)?;
+    let memory = KernelMem::new(&device);
+    let pattern = Pattern::parse("?? ?? ?? ??").context("invalid pattern")?;
+    let matches = memory.scan(kernel.base..kernel.base + 0x1000, &pattern)?;
+    ensure!(
+        matches
+            .iter()
+            .copied()
+            .eq(kernel.base..kernel.base + 0x1000 - 3),
+        "unexpected {} matches from {:x?}",
+        matches.len(),
+        matches.first()
+    );
+    let Err(err) = memory.scan_physical(0..0x1000, &pattern) else {
+        bail!("physical memory was scanned without the class");
+    };
+    ensure!(
+        err.raw_os_error() == Some(ERROR_ACCESS_DENIED.cast_signed()),
+        "the scan was refused with an unexpected error: {err}"
+    );
+    let request = ScanRequest {
+        address: kernel.base,
+        size: 0x1000,
+        ..ScanRequest::default()
+    };
+    let Err(err) = device.ioctl(IOCTL_SCAN_MEMORY, as_bytes(&request), &mut [0; 0x100]) else {
+        bail!("an empty pattern was accepted");
+    };
+    ensure!(
+        err.raw_os_error() == Some(ERROR_INVALID_PARAMETER.cast_signed()),
+        "the pattern was refused with an unexpected error: {err}"
+    );
+
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_PHYSICAL_MEMORY)?;
+    let pattern = Pattern::parse("4d 5a 90 00").context("invalid pattern")?;
+    let matches = KernelMem::new(&device).scan_physical(0..0x100_0000, &pattern)?;
+    ensure!(
+        matches.is_sorted() && matches.iter().all(|&address| address < 0x100_0000 - 3),
+        "unexpected physical matches {matches:x?}"
+    );
+    Ok(())
+}
+
+/// Sends IOCTLs with correlation IDs, which must complete as if sent directly,
+/// each with a new ID, and malformed `IOCTL_CORRELATED` requests, which must
+/// be refused. IOCTLs with direct I/O must be sent as is.
+fn test_correlation(_env: &Environment) -> Result<()> {
+    let mut device = Device::open()?;
+    let expected = device.get_version()?;
+    device.enable_correlation()?;
+    let version = device.get_version()?;
+    ensure!(version == expected, "unexpected version {version:?}");
+    let first = device
+        .last_correlation_id()
+        .context("no correlation ID was sent")?;
+    let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+    let kernel = device.get_module(None)?;
+    let last = device.last_correlation_id();
+    ensure!(
+        last.is_some_and(|id| id != first),
+        "the correlation ID {first:#x} was followed by {last:x?}"
+    );
+    let magic = unsafe { KernelMem::new(&device).read_struct::<u16>(kernel.base)? };
+    ensure!(magic == 0x5a4d, "unexpected DOS signature {magic:#x}");
+    ensure!(
+        device.last_correlation_id() == last,
+        "IOCTL_READ_MEMORY was sent with a correlation ID"
+    );
+
+    for (control_code, reserved) in [
+        (IOCTL_CORRELATED, 0),
+        (IOCTL_READ_MEMORY, 0),
+        (IOCTL_GET_VERSION, 1),
+    ] {
+        let header = CorrelationHeader {
+            correlation_id: first,
+            control_code,
+            reserved,
+        };
+        let result = device.ioctl(IOCTL_CORRELATED, as_bytes(&header), &mut [0; 16]);
+        ensure!(
+            result.is_err_and(|err| err.raw_os_error() == Some(ERROR_INVALID_PARAMETER.cast_signed())),
+            "IOCTL_CORRELATED of {control_code:#x} with {reserved} was not refused"
+        );
+    }
+    Ok(())
+}
+
+/// Maps this program as a driver. It must be refused as not supported unless
+/// the driver has the capability, and otherwise, fail to resolve imports from
+/// user-mode DLLs before anything runs and leave no image mapped.
+fn test_map_driver(_env: &Environment) -> Result<()> {
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_EXECUTE)?;
+    let capable = device.get_version()?.capabilities & CAPABILITY_MAP_DRIVER != 0;
+    let image = fs::read(env::current_exe()?)?;
+    let Err(err) = device.map_driver("capcom-test", &image) else {
+        bail!("a user-mode program was mapped");
+    };
+    let expected = if capable {
+        ERROR_MOD_NOT_FOUND
+    } else {
+        ERROR_NOT_SUPPORTED
+    };
+    ensure!(
+        err.raw_os_error() == Some(expected.cast_signed()),
+        "the image was refused with an unexpected error: {err}"
+    );
+    if !capable {
+        return Ok(());
+    }
+
+    let drivers = device.mapped_drivers()?;
+    ensure!(
+        drivers
+            .iter()
+            .all(|driver| !driver.name.starts_with(b"capcom-test\0")),
+        "the refused image is mapped"
+    );
+    let Err(err) = device.unmap_driver(0) else {
+        bail!("an image that is not mapped was unmapped");
+    };
+    ensure!(
+        err.raw_os_error() == Some(ERROR_NOT_FOUND.cast_signed()),
+        "unmapping was refused with an unexpected error: {err}"
+    );
+    Ok(())
+}
+
+/// Maps capcom-map-test, opens the device it creates, tears it down and unmaps
+/// it. The device and the image must be gone afterwards.
+fn test_map_test_driver(env: &Environment) -> Result<()> {
+    let Some(path) = &env.map_test_driver else {
+        return Ok(());
+    };
+    let image = fs::read(path).with_context(|| format!("could not read {path}"))?;
+    let device = Device::open()?;
+    let _ = device.negotiate(CLASS_EXECUTE)?;
+    ensure!(
+        device.get_version()?.capabilities & CAPABILITY_MAP_DRIVER != 0,
+        "the driver is not built with the dangerous feature"
+    );
+    let driver = device
+        .map_driver(map_test::IMAGE_NAME, &image)
+        .context("could not map the driver")?;
+
+    let test_device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(map_test::DEVICE_PATH)
+        .with_context(|| format!("could not open {}", map_test::DEVICE_PATH))?;
+    trace::record_open(test_device.as_raw_handle(), map_test::DEVICE_PATH);
+    let _ = device_io_control(
+        &test_device,
+        map_test::IOCTL_TEAR_DOWN,
+        &[],
+        ptr::null_mut(),
+        0,
+    )?;
+    // The close request is handled by the image, so unmap it only after that.
+    drop(test_device);
+    device.unmap_driver(driver.base)?;
+
+    ensure!(
+        File::open(map_test::DEVICE_PATH).is_err(),
+        "{} remains after unloading",
+        map_test::DEVICE_PATH
+    );
+    ensure!(
+        device
+            .mapped_drivers()?
+            .iter()
+            .all(|mapped| mapped.base != driver.base),
+        "the image remains mapped"
+    );
+    Ok(())
+}
+
+/// Maps shared memory and accesses it. Mapping it again through the same handle
+/// must fail.
+fn test_map_shared(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY)?;
+    let request = SharedMemoryRequest { size: 100 };
+    let mut info = SharedMemoryInfo::default();
+    let mut map = || {
+        device_io_control(
+            &device,
+            IOCTL_MAP_SHARED,
+            as_bytes(&request),
+            ptr::from_mut(&mut info).cast(),
+            size_of::<SharedMemoryInfo>(),
+        )
+    };
+    let _ = map()?;
+    ensure!(map().is_err(), "the memory was mapped twice");
+    ensure!(
+        info.user_address != 0 && info.kernel_address != 0 && info.size == 0x1000,
+        "unexpected shared memory {info:x?}"
+    );
+
+    let memory = unsafe { slice::from_raw_parts_mut(info.user_address as *mut u8, 0x1000) };
+    ensure!(
+        memory.iter().all(|&byte| byte == 0),
+        "the memory is not zeroed"
+    );
+    memory.fill(0xcc);
+    Ok(())
+}
+
+/// Streams events to shared memory, and waits for the records of a request and
+/// of the message it logs, sent and logged after that.
+fn test_event_ring(_env: &Environment) -> Result<()> {
+    let device = open_device()?;
+    let _ = negotiate(&device, CLASS_KERNEL_MEMORY)?;
+    let (event, mut ring) = enable_event_ring(&device)?;
+
+    let mut version = VersionInfo::default();
+    let _ = device_io_control(
+        &device,
+        IOCTL_GET_VERSION,
+        &[],
+        ptr::from_mut(&mut version).cast(),
+        size_of::<VersionInfo>(),
+    )?;
+    let request = DebugBreakRequest {
+        mode: DEBUG_BREAK_ON_LOAD,
+    };
+    let _ = device_io_control(
+        &device,
+        IOCTL_SET_DEBUG_BREAK,
+        as_bytes(&request),
+        ptr::null_mut(),
+        0,
+    )?;
+    let result = (|| {
+        // Other processes may send requests too, so look for ours.
+        let (mut request_found, mut message_found) = (false, false);
+        while unsafe { WaitForSingleObject(event, 1000) } == WAIT_OBJECT_0 {
+            while let Some(record) = ring.pop() {
+                request_found |= record.kind == EVENT_KIND_IOCTL
+                    && record.log.process_id == u64::from(process::id())
+                    && record.log.control_code == IOCTL_GET_VERSION;
+                message_found |= record.kind == EVENT_KIND_MESSAGE
+                    && record.message.id == messages::DEBUG_BREAK_MODE
+                    && record.message.arguments[..record.message.argument_count as usize]
+                        == [u64::from(DEBUG_BREAK_ON_LOAD)];
+                if request_found && message_found {
+                    return Ok(());
+                }
+            }
+        }
+        bail!("the records were not streamed")
+    })();
+    let _ = unsafe { CloseHandle(event) };
+    result
+}
+
+/// Sends two requests at once and one that fails through a handle for
+/// overlapped I/O, and waits for an event signaled by another thread.
+fn test_overlapped(_env: &Environment) -> Result<()> {
+    let device = AsyncDevice::open()?;
+    let first = device.ioctl(IOCTL_GET_VERSION, Vec::new(), size_of::<VersionInfo>());
+    let second = device.ioctl(IOCTL_GET_VERSION, Vec::new(), size_of::<VersionInfo>());
+    for output in [block_on(first)?, block_on(second)?] {
+        ensure!(
+            output.len() == size_of::<VersionInfo>(),
+            "unexpected output size {}",
+            output.len()
+        );
+        let version = unsafe { output.as_ptr().cast::<VersionInfo>().read_unaligned() };
+        ensure!(
+            version.abi_version == ABI_VERSION,
+            "unexpected ABI version {}",
+            version.abi_version
+        );
+    }
+    let request = NegotiateRequest {
+        abi_version: ABI_VERSION + 1,
+        classes: 0,
+    };
+    let result = block_on(device.ioctl(
+        IOCTL_NEGOTIATE,
+        as_bytes(&request).to_vec(),
+        size_of::<NegotiateResponse>(),
+    ));
+    ensure!(result.is_err(), "a mismatched ABI version was accepted");
+
+    let event = unsafe { CreateEventW(ptr::null(), FALSE, FALSE, ptr::null()) };
+    if event.is_null() {
+        return Err(io::Error::last_os_error().into());
+    }
+    let event = unsafe { OwnedHandle::from_raw_handle(event) };
+    thread::scope(|scope| {
+        let signaler = scope.spawn(|| {
+            thread::sleep(Duration::from_millis(100));
+            unsafe { SetEvent(event.as_raw_handle()) }
+        });
+        let result = block_on(overlapped::wait(event.as_handle()));
+        ensure!(
+            signaler.join().is_ok_and(|succeeded| succeeded != 0),
+            "failed to signal the event"
+        );
+        Ok(result?)
+    })
+}
+
+/// Runs `future` to completion on the current thread.
+fn block_on<F: Future>(future: F) -> F::Output {
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = task::Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+/// Gets the audit information of the driver.
+fn get_audit(device: &File) -> io::Result<AuditInfo> {
+    let mut audit = AuditInfo::default();
+    let _ = device_io_control(
+        device,
+        IOCTL_GET_AUDIT,
+        &[],
+        ptr::from_mut(&mut audit).cast(),
+        size_of::<AuditInfo>(),
+    )?;
+    Ok(audit)
+}
+
+/// Runs shellcode that only returns.
+fn run_shellcode(device: &File) -> io::Result<usize> {
+    // `ret`
+    #[cfg(target_arch = "x86_64")]
+    const SHELLCODE: &[u8] = &[0xc3];
+    #[cfg(target_arch = "aarch64")]
+    const SHELLCODE: &[u8] = &0xd65f_03c0_u32.to_le_bytes();
+
+    device_io_control(device, IOCTL_RUN_SHELLCODE, SHELLCODE, ptr::null_mut(), 0)
+}
+
+/// Checks that `result` is `ERROR_NOT_SUPPORTED` if `refused` is expected, or
+/// success otherwise.
+fn check_refusal(result: io::Result<usize>, refused: bool) -> Result<()> {
+    if refused {
+        let Err(err) = result else {
+            bail!("the payload was not refused");
+        };
+        ensure!(
+            err.raw_os_error() == Some(ERROR_NOT_SUPPORTED.cast_signed()),
+            "the payload was refused with an unexpected error: {err}"
+        );
+    } else {
+        let _ = result?;
+    }
+    Ok(())
+}
+
+/// Opens a new handle to the device.
+fn open_device() -> Result<File> {
+    let device = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(DEVICE_PATH)
+        .with_context(|| format!("could not open {DEVICE_PATH}"))?;
+    trace::record_open(device.as_raw_handle(), DEVICE_PATH);
+    Ok(device)
+}
+
+/// Returns the SID of the Administrators group.
+fn administrators_sid() -> Result<[u8; SECURITY_MAX_SID_SIZE as usize]> {
+    let mut sid = [0u8; SECURITY_MAX_SID_SIZE as usize];
+    let mut length = SECURITY_MAX_SID_SIZE;
+    let created = unsafe {
+        CreateWellKnownSid(
+            WinBuiltinAdministratorsSid,
+            ptr::null_mut(),
+            sid.as_mut_ptr().cast(),
+            &raw mut length,
+        )
+    };
+    ensure!(
+        created != 0,
+        "could not create the SID: {}",
+        io::Error::last_os_error()
+    );
+    Ok(sid)
+}
+
+/// Returns the REG_DWORD value `name` of the service key of the driver, or
+/// `None` if it is not set.
+fn service_dword(name: &str) -> Result<Option<u32>> {
+    let key: Vec<u16> = r"SYSTEM\CurrentControlSet\Services\capcom"
+        .encode_utf16()
+        .chain([0])
+        .collect();
+    let value_name: Vec<u16> = name.encode_utf16().chain([0]).collect();
+    let mut value = 0_u32;
+    let mut size = size_of::<u32>() as u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_LOCAL_MACHINE,
+            key.as_ptr(),
+            value_name.as_ptr(),
+            RRF_RT_REG_DWORD,
+            ptr::null_mut(),
+            (&raw mut value).cast(),
+            &raw mut size,
+        )
+    };
+    match status {
+        ERROR_SUCCESS => Ok(Some(value)),
+        ERROR_FILE_NOT_FOUND => Ok(None),
+        _ => bail!(
+            "could not read {name}: {}",
+            io::Error::from_raw_os_error(status.cast_signed())
+        ),
+    }
+}
+
+/// Checks whether this process is a member of the Administrators group, which
+/// it is not if the group is deny-only.
+fn is_administrator() -> Result<bool> {
+    let mut sid = administrators_sid()?;
+    let mut member = FALSE;
+    let checked =
+        unsafe { CheckTokenMembership(ptr::null_mut(), sid.as_mut_ptr().cast(), &raw mut member) };
+    ensure!(
+        checked != 0,
+        "could not check the membership: {}",
+        io::Error::last_os_error()
+    );
+    Ok(member != FALSE)
+}
+
+/// Runs this program with `arg` and a restricted token of this process in
+/// which the Administrators group is deny-only, and returns its exit code.
+fn run_restricted(arg: &str) -> Result<u32> {
+    let mut sid = administrators_sid()?;
+    let mut token = ptr::null_mut();
+    ensure!(
+        unsafe {
+            OpenProcessToken(
+                GetCurrentProcess(),
+                TOKEN_ASSIGN_PRIMARY | TOKEN_DUPLICATE | TOKEN_QUERY,
+                &raw mut token,
+            )
+        } != 0,
+        "could not open the token: {}",
+        io::Error::last_os_error()
+    );
+    let disabled = SID_AND_ATTRIBUTES {
+        Sid: sid.as_mut_ptr().cast(),
+        Attributes: 0,
+    };
+    let mut restricted = ptr::null_mut();
+    let created = unsafe {
+        CreateRestrictedToken(
+            token,
+            0,
+            1,
+            &raw const disabled,
+            0,
+            ptr::null(),
+            0,
+            ptr::null(),
+            &raw mut restricted,
+        )
+    };
+    let _ = unsafe { CloseHandle(token) };
+    ensure!(
+        created != 0,
+        "could not create a restricted token: {}",
+        io::Error::last_os_error()
+    );
+
+    let program = env::current_exe()?;
+    let mut command_line: Vec<u16> = format!("\"{}\" {arg}", program.display())
+        .encode_utf16()
+        .chain([0])
+        .collect();
+    let mut startup_info: STARTUPINFOW = unsafe { mem::zeroed() };
+    startup_info.cb = size_of::<STARTUPINFOW>() as u32;
+    let mut process_info = PROCESS_INFORMATION::default();
+    let created = unsafe {
+        CreateProcessAsUserW(
+            restricted,
+            ptr::null(),
+            command_line.as_mut_ptr(),
+            ptr::null(),
+            ptr::null(),
+            FALSE,
+            0,
+            ptr::null(),
+            ptr::null(),
+            &raw const startup_info,
+            &raw mut process_info,
+        )
+    };
+    let _ = unsafe { CloseHandle(restricted) };
+    ensure!(
+        created != 0,
+        "could not run {}: {}",
+        program.display(),
+        io::Error::last_os_error()
+    );
+
+    let mut code = 0;
+    let _ = unsafe { WaitForSingleObject(process_info.hProcess, INFINITE) };
+    let queried = unsafe { GetExitCodeProcess(process_info.hProcess, &raw mut code) };
+    let error = io::Error::last_os_error();
+    let _ = unsafe { CloseHandle(process_info.hThread) };
+    let _ = unsafe { CloseHandle(process_info.hProcess) };
+    ensure!(queried != 0, "could not get the exit code: {error}");
+    Ok(code)
+}
+
+/// A page allocated with `VirtualAlloc` that is readable, writable and
+/// executable, as exploits for the original driver allocate payloads in.
+struct ExecutablePage(*mut c_void);
+
+impl ExecutablePage {
+    fn new() -> Result<Self> {
+        let memory = unsafe {
+            VirtualAlloc(
+                ptr::null(),
+                0x1000,
+                MEM_COMMIT | MEM_RESERVE,
+                PAGE_EXECUTE_READWRITE,
+            )
+        };
+        ensure!(
+            !memory.is_null(),
+            "could not allocate memory: {}",
+            io::Error::last_os_error()
+        );
+        Ok(Self(memory))
+    }
+
+    fn address(&self) -> usize {
+        self.0.addr()
+    }
+
+    /// Writes `bytes` at `offset` bytes into the page.
+    fn write(&self, offset: usize, bytes: &[u8]) {
+        assert!(offset + bytes.len() <= 0x1000, "out of the page");
+        unsafe {
+            ptr::copy_nonoverlapping(bytes.as_ptr(), self.0.cast::<u8>().add(offset), bytes.len());
+        }
+    }
+}
+
+impl Drop for ExecutablePage {
+    fn drop(&mut self) {
+        let _ = unsafe { VirtualFree(self.0, 0, MEM_RELEASE) };
+    }
+}
+
+/// Maps shared memory for the handle, which must be granted
+/// `CLASS_KERNEL_MEMORY`, and streams records to it. Returns the event
+/// signaled when records are pushed, which must be closed, and the ring.
+fn enable_event_ring(device: &File) -> Result<(*mut c_void, Consumer)> {
+    let request = SharedMemoryRequest { size: 0x1000 };
+    let mut shared = SharedMemoryInfo::default();
+    let _ = device_io_control(
+        device,
+        IOCTL_MAP_SHARED,
+        as_bytes(&request),
+        ptr::from_mut(&mut shared).cast(),
+        size_of::<SharedMemoryInfo>(),
+    )?;
+    let mut info = EventRingInfo::default();
+    let _ = device_io_control(
+        device,
+        IOCTL_ENABLE_EVENT_RING,
+        &[],
+        ptr::from_mut(&mut info).cast(),
+        size_of::<EventRingInfo>(),
+    )?;
+    let event = ptr::without_provenance_mut(info.event as usize);
+    let ring = unsafe { Consumer::new(shared.user_address as *mut EventRingHeader) };
+    Ok((event, ring))
+}
+
+/// Declares that the handle uses `classes`.
+fn negotiate(device: &File, classes: u32) -> io::Result<NegotiateResponse> {
+    let request = NegotiateRequest {
+        abi_version: ABI_VERSION,
+        classes,
+    };
+    let mut response = NegotiateResponse::default();
+    let _ = device_io_control(
+        device,
+        IOCTL_NEGOTIATE,
+        as_bytes(&request),
+        ptr::from_mut(&mut response).cast(),
+        size_of::<NegotiateResponse>(),
+    )?;
+    Ok(response)
+}
+
+/// Returns the bytes of `value`.
+fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe { slice::from_raw_parts(ptr::from_ref(value).cast(), size_of::<T>()) }
+}
+
+/// Sends an IOCTL with `input` to the device, and returns the number of bytes
+/// written to `output`.
+fn device_io_control(
+    device: &File,
+    code: u32,
+    input: &[u8],
+    output: *mut c_void,
+    output_length: usize,
+) -> io::Result<usize> {
+    trace::record_ioctl(device.as_raw_handle(), code, input, output_length);
+    let mut bytes_returned = 0;
+    let succeeded = unsafe {
+        DeviceIoControl(
+            device.as_raw_handle(),
+            code,
+            input.as_ptr().cast(),
+            input.len() as _,
+            output,
+            output_length as _,
+            &raw mut bytes_returned,
+            ptr::null_mut(),
+        )
+    };
+    if succeeded == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(bytes_returned as _)
+    }
+}
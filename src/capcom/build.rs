@@ -1,5 +1,140 @@
-//! Specifies the way to build the Windows driver using the wdk-build crate.
+//! Specifies the way to build the Windows driver using the wdk-build crate,
+//! generates the built-in table of kernel offsets from offsets.csv, and embeds
+//! the metadata of the build.
+
+use std::{
+    env,
+    fmt::Write as _,
+    fs,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The fields of `capcom_abi::KernelOffsets` given in offsets.csv, in order.
+const FIELDS: [&str; 9] = [
+    "build_number",
+    "eprocess_unique_process_id",
+    "eprocess_active_process_links",
+    "eprocess_token",
+    "eprocess_signature_level",
+    "eprocess_protection",
+    "kthread_previous_mode",
+    "kthread_kernel_stack",
+    "token_privileges",
+];
 
 fn main() -> Result<(), wdk_build::ConfigError> {
+    generate_offsets();
+    generate_build_info();
     wdk_build::configure_wdk_binary_build()
 }
+
+/// Writes the rows of offsets.csv as an array of `KernelOffsets` to
+/// `$OUT_DIR/offsets.rs`. The offsets are for x64, so the array is empty for
+/// other architectures.
+fn generate_offsets() {
+    println!("cargo::rerun-if-changed=offsets.csv");
+    let csv = fs::read_to_string("offsets.csv").expect("offsets.csv should be readable");
+    let is_x64 = env::var("CARGO_CFG_TARGET_ARCH").is_ok_and(|arch| arch == "x86_64");
+
+    let mut rows = Vec::new();
+    for (index, line) in csv.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line
+            .split(',')
+            .map(|value| parse_number(value.trim()))
+            .collect::<Option<Vec<_>>>()
+            .filter(|values| values.len() == FIELDS.len())
+            .unwrap_or_else(|| panic!("offsets.csv:{} is malformed", index + 1));
+        rows.push(values);
+    }
+    rows.sort_unstable_by_key(|values| values[0]);
+    assert!(
+        rows.windows(2).all(|pair| pair[0][0] != pair[1][0]),
+        "offsets.csv has duplicate build numbers"
+    );
+
+    let mut code = String::from("&[\n");
+    for values in rows.iter().filter(|_| is_x64) {
+        code.push_str("    KernelOffsets {\n");
+        for (field, value) in FIELDS.iter().zip(values) {
+            writeln!(code, "        {field}: {value:#x},").unwrap();
+        }
+        code.push_str("        ..KernelOffsets::EMPTY\n    },\n");
+    }
+    code.push(']');
+
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("offsets.rs");
+    fs::write(path, code).expect("offsets.rs should be writable");
+}
+
+/// Writes `capcom_abi::BuildInfo` to `$OUT_DIR/build_info.rs`. The features
+/// are left zero for the driver to fill in.
+fn generate_build_info() {
+    println!("cargo::rerun-if-changed=src");
+    println!("cargo::rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    if let Some(git_dir) = git(&["rev-parse", "--absolute-git-dir"]) {
+        println!("cargo::rerun-if-changed={git_dir}/HEAD");
+        println!("cargo::rerun-if-changed={git_dir}/index");
+        if let Some(head) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            println!("cargo::rerun-if-changed={git_dir}/{head}");
+        }
+    }
+
+    // A repository with SHA-256 object names has the hash truncated.
+    let mut commit = [0u8; 20];
+    if let Some(hash) = git(&["rev-parse", "HEAD"]) {
+        for (byte, hex) in commit.iter_mut().zip(hash.as_bytes().chunks(2)) {
+            *byte = std::str::from_utf8(hex)
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .unwrap_or(0);
+        }
+    }
+    let dirty = git(&["status", "--porcelain"]).is_some_and(|status| !status.is_empty());
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs())
+        });
+    let mut profile = [0u8; 16];
+    let name = env::var("PROFILE").unwrap_or_default();
+    for (byte, &c) in profile.iter_mut().zip(name.as_bytes()) {
+        *byte = c;
+    }
+
+    let code = format!(
+        "BuildInfo {{\n    timestamp: {timestamp},\n    commit: {commit:?},\n    \
+         dirty: {},\n    profile: {profile:?},\n    features: 0,\n    reserved: 0,\n}}",
+        u32::from(dirty)
+    );
+    let path = Path::new(&env::var("OUT_DIR").unwrap()).join("build_info.rs");
+    fs::write(path, code).expect("build_info.rs should be writable");
+}
+
+/// Runs git with `arguments` and returns the trimmed output, or `None` if it
+/// fails, e.g., outside a repository.
+fn git(arguments: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(arguments).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|output| output.trim().to_owned())
+}
+
+/// Parses a decimal or `0x`-prefixed hexadecimal number.
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
@@ -0,0 +1,152 @@
+//! Access to the local APIC of the current processor, either through MMIO in
+//! xAPIC mode or through MSRs in x2APIC mode, and `IOCTL_READ_APIC` and
+//! `IOCTL_WRITE_APIC`. Registers are identified by their xAPIC MMIO offsets in
+//! both modes.
+
+use capcom_abi::ApicRequest;
+use wdk_sys::{NTSTATUS, STATUS_INVALID_PARAMETER, STATUS_NOT_SUPPORTED};
+
+use crate::{
+    arch::{self, ApicMode},
+    ioctl::Request,
+    mmio::Mmio,
+    processor,
+    trace::trace,
+};
+
+/// The local APIC ID register.
+const ID: u32 = 0x20;
+
+/// The low half of the interrupt command register.
+const ICR_LOW: u32 = 0x300;
+
+/// The high half of the interrupt command register in xAPIC mode.
+const ICR_HIGH: u32 = 0x310;
+
+/// The NMI delivery mode of the interrupt command register.
+const ICR_DELIVERY_NMI: u64 = 0b100 << 8;
+
+/// How a register can be accessed.
+#[derive(Clone, Copy)]
+enum Access {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+}
+
+/// Handles `IOCTL_READ_APIC`.
+pub(crate) fn read_apic(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<ApicRequest>()?;
+    let value = processor::run_on(input.processor, || read(input.offset))??;
+    request.write_output(&value)
+}
+
+/// Handles `IOCTL_WRITE_APIC`.
+pub(crate) fn write_apic(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<ApicRequest>()?;
+    processor::run_on(input.processor, || write(input.offset, input.value))??;
+    trace!(APIC_WRITTEN, input.value, input.offset, input.processor);
+    Ok(0)
+}
+
+/// Reads the register at `offset` of the current processor. Only the ICR is
+/// 64-bit in x2APIC mode.
+fn read(offset: u32) -> Result<u64, NTSTATUS> {
+    let mode = mode()?;
+    if !matches!(
+        access(mode, offset),
+        Some(Access::ReadOnly | Access::ReadWrite)
+    ) {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    match mode {
+        ApicMode::X2Apic => Ok(unsafe { arch::read_x2apic(offset) }),
+        ApicMode::XApic(base) => {
+            let mmio = Mmio::map(base)?;
+            Ok(u64::from(unsafe { mmio.read::<u32>(offset as usize) }))
+        }
+    }
+}
+
+/// Writes to the register at `offset` of the current processor.
+fn write(offset: u32, value: u64) -> Result<(), NTSTATUS> {
+    let mode = mode()?;
+    if !matches!(
+        access(mode, offset),
+        Some(Access::WriteOnly | Access::ReadWrite)
+    ) {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    match mode {
+        ApicMode::X2Apic => unsafe { arch::write_x2apic(offset, value) },
+        ApicMode::XApic(base) => {
+            let value = u32::try_from(value).map_err(|_| STATUS_INVALID_PARAMETER)?;
+            let mmio = Mmio::map(base)?;
+            unsafe { mmio.write(offset as usize, value) };
+        }
+    }
+    Ok(())
+}
+
+/// Returns how the register at `offset` can be accessed in `mode`, or `None`
+/// if it does not exist. Accessing an x2APIC MSR that does not exist or in an
+/// unsupported way raises #GP, so only architectural registers are allowed.
+fn access(mode: ApicMode, offset: u32) -> Option<Access> {
+    let x2apic = matches!(mode, ApicMode::X2Apic);
+    match offset {
+        // The ID and the logical destination are read-only in x2APIC mode.
+        ID | 0xd0 if x2apic => Some(Access::ReadOnly),
+        ID | 0xd0 => Some(Access::ReadWrite),
+        // The version, PPR, ISR, TMR and IRR.
+        0x30 | 0xa0 | 0x100..=0x270 => Some(Access::ReadOnly),
+        // The APR, and the DFR and ICR high half that x2APIC folds into the
+        // ICR and LDR.
+        0x90 if !x2apic => Some(Access::ReadOnly),
+        0xe0 | ICR_HIGH if !x2apic => Some(Access::ReadWrite),
+        // The EOI register, and the self IPI register of x2APIC.
+        0xb0 => Some(Access::WriteOnly),
+        0x3f0 if x2apic => Some(Access::WriteOnly),
+        // The TPR, SVR, ESR, ICR, LVT entries, and the timer initial count and
+        // divide configuration.
+        0x80 | 0xf0 | 0x280 | ICR_LOW | 0x320..=0x380 | 0x3e0 => Some(Access::ReadWrite),
+        // The timer current count.
+        0x390 => Some(Access::ReadOnly),
+        _ => None,
+    }
+    .filter(|_| offset.is_multiple_of(16))
+}
+
+/// Returns the APIC ID of the current processor.
+pub(crate) fn id() -> Result<u32, NTSTATUS> {
+    match mode()? {
+        ApicMode::X2Apic => Ok(unsafe { arch::read_x2apic(ID) } as u32),
+        ApicMode::XApic(base) => {
+            let mmio = Mmio::map(base)?;
+            Ok(unsafe { mmio.read::<u32>(ID as usize) } >> 24)
+        }
+    }
+}
+
+/// Sends an NMI to the processor with `apic_id` from the current processor.
+pub(crate) fn send_nmi(apic_id: u32) -> Result<(), NTSTATUS> {
+    match mode()? {
+        ApicMode::X2Apic => unsafe {
+            arch::write_x2apic(ICR_LOW, (u64::from(apic_id) << 32) | ICR_DELIVERY_NMI);
+        },
+        // The kernel sends IPIs through the same registers, so write both
+        // halves without being interrupted.
+        ApicMode::XApic(base) => {
+            let mmio = Mmio::map(base)?;
+            arch::without_interrupts(|| unsafe {
+                mmio.write(ICR_HIGH as usize, apic_id << 24);
+                mmio.write(ICR_LOW as usize, ICR_DELIVERY_NMI as u32);
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the mode of the local APIC of the current processor.
+fn mode() -> Result<ApicMode, NTSTATUS> {
+    arch::apic_mode().ok_or(STATUS_NOT_SUPPORTED)
+}
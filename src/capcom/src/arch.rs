@@ -0,0 +1,59 @@
+//! Architecture-specific code.
+
+// The defanged build does not execute payloads and leaves most of this unused.
+#![cfg_attr(feature = "defanged", allow(dead_code, unused_imports))]
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("The driver supports only x86, x86_64 and aarch64 targets");
+
+use wdk_sys::{EX_RUNDOWN_REF, PKAPC};
+
+#[cfg(target_arch = "aarch64")]
+mod aarch64;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod x86;
+
+#[cfg(target_arch = "aarch64")]
+pub(crate) use aarch64::{
+    BRANCH_TARGET, CAN_RUN_USER_PAYLOAD, CONTROL_REGISTERS, HAS_PORT_IO, PRESERVED_FLAGS,
+    PRESERVED_REGISTERS, apc_kernel_routine, apc_rundown_routine, apic_mode, breakpoint,
+    call_payload, cet, check_protection, cpu_state, disable_protection, flush_instruction_cache,
+    flush_tlb, instruction_length, nmi_frame, page_table_root, read_port, read_x2apic,
+    restore_protection, serialize_instruction_fetch, with_extended_state, without_interrupts,
+    write_port, write_x2apic,
+};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub(crate) use x86::{
+    BRANCH_TARGET, CAN_RUN_USER_PAYLOAD, CONTROL_REGISTERS, HAS_PORT_IO, PRESERVED_FLAGS,
+    PRESERVED_REGISTERS, apc_kernel_routine, apc_rundown_routine, apic_mode, breakpoint,
+    call_payload, cet, check_protection, cpu_state, disable_protection, flush_instruction_cache,
+    flush_tlb, instruction_length, nmi_frame, page_table_root, read_port, read_x2apic,
+    restore_protection, serialize_instruction_fetch, with_extended_state, without_interrupts,
+    write_port, write_x2apic,
+};
+
+/// The work of an APC routine of the driver, done before the rundown protection
+/// keeping the driver loaded is released. See [`apc_kernel_routine`].
+pub(crate) trait ApcRoutine {
+    /// Handles `apc` and returns the rundown protection to release.
+    unsafe extern "C" fn run(apc: PKAPC) -> *mut EX_RUNDOWN_REF;
+}
+
+/// Control-flow enforcement features enabled in kernel-mode.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Cet {
+    /// Supervisor shadow stacks, requiring `ret` to match the preceding `call`.
+    pub(crate) shadow_stack: bool,
+    /// Indirect branch tracking, requiring indirect call targets to start with
+    /// ENDBR.
+    pub(crate) indirect_branch_tracking: bool,
+}
+
+/// The mode of the local APIC.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum ApicMode {
+    /// Registers are memory-mapped at the physical address.
+    XApic(u64),
+    /// Registers are accessed through MSRs.
+    X2Apic,
+}
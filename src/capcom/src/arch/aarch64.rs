@@ -0,0 +1,380 @@
+use core::{
+    arch::{asm, naked_asm},
+    ffi::c_void,
+    mem::offset_of,
+};
+
+use capcom_abi::{CpuState, PayloadTranscript, RegisterState};
+
+use wdk_sys::{NTSTATUS, PKAPC, PVOID, ntddk::ExReleaseRundownProtection};
+
+use super::{ApcRoutine, ApicMode, Cet};
+
+/// Whether a payload in user-mode memory can be executed. Unlike SMEP on x86,
+/// user-mode pages are never executable in kernel-mode due to the PXN bit in
+/// page tables, and there is no control bit to disable it.
+pub(crate) const CAN_RUN_USER_PAYLOAD: bool = false;
+
+/// Whether the processor has I/O ports. ARM64 has only memory-mapped I/O.
+pub(crate) const HAS_PORT_IO: bool = false;
+
+/// The instruction an indirect branch target must start with. Nothing is
+/// needed as pool pages are not guarded pages subject to BTI.
+pub(crate) const BRANCH_TARGET: &[u8] = &[];
+
+/// PSTATE.PAN, accessed through the system register encoding so that the
+/// assembler does not require the ARMv8.1 extension to be enabled. Windows on
+/// ARM requires ARMv8.1 and thus PAN is always available.
+const PAN_MASK: usize = 1 << 22;
+
+/// PSTATE.{D,A,I,F}.
+const DAIF_MASK: usize = 0b1111 << 6;
+
+/// The indexes in `RegisterState::gprs` and the names of the general-purpose
+/// registers a payload must preserve under the calling convention. X18 holds
+/// the KPCR.
+pub(crate) const PRESERVED_REGISTERS: &[(usize, &str)] = &[
+    (18, "x18"),
+    (19, "x19"),
+    (20, "x20"),
+    (21, "x21"),
+    (22, "x22"),
+    (23, "x23"),
+    (24, "x24"),
+    (25, "x25"),
+    (26, "x26"),
+    (27, "x27"),
+    (28, "x28"),
+    (29, "x29"),
+    (31, "sp"),
+];
+
+/// The bits of `RegisterState::flags` a payload must preserve: DAIF and PAN.
+pub(crate) const PRESERVED_FLAGS: u64 = (DAIF_MASK | PAN_MASK) as u64;
+
+/// The names of `RegisterState::cr0` and `RegisterState::cr4`.
+pub(crate) const CONTROL_REGISTERS: [&str; 2] = ["sctlr_el1", "tcr_el1"];
+
+/// The offsets of the fields of `PayloadTranscript` written by [`call_payload`].
+const BEFORE_GPRS: usize = offset_of!(PayloadTranscript, before) + offset_of!(RegisterState, gprs);
+const BEFORE_FLAGS: usize =
+    offset_of!(PayloadTranscript, before) + offset_of!(RegisterState, flags);
+const BEFORE_CR0: usize = offset_of!(PayloadTranscript, before) + offset_of!(RegisterState, cr0);
+const BEFORE_CR4: usize = offset_of!(PayloadTranscript, before) + offset_of!(RegisterState, cr4);
+const AFTER_GPRS: usize = offset_of!(PayloadTranscript, after) + offset_of!(RegisterState, gprs);
+const AFTER_FLAGS: usize = offset_of!(PayloadTranscript, after) + offset_of!(RegisterState, flags);
+const AFTER_CR0: usize = offset_of!(PayloadTranscript, after) + offset_of!(RegisterState, cr0);
+const AFTER_CR4: usize = offset_of!(PayloadTranscript, after) + offset_of!(RegisterState, cr4);
+
+/// Calls `payload` with `argument` on the stack whose top is `stack`, or on the
+/// current stack if zero, and records the registers right before and after
+/// the call in `transcript`, except the IRQL.
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn call_payload(
+    payload: usize,
+    argument: usize,
+    transcript: *mut PayloadTranscript,
+    stack: usize,
+) {
+    naked_asm!(
+        "stp x29, x30, [sp, #-16]!",
+        "mov x29, sp",
+        "cmp x3, #0",
+        "csel x9, x3, x29, ne",
+        "and x9, x9, #-16",
+        "mov sp, x9",
+        // Keep `transcript` and the original SP on the stack, as the payload may
+        // use any register.
+        "stp x2, x29, [sp, #-16]!",
+        "mov x16, x0",
+        "mov x0, x1",
+        "mrs x9, nzcv",
+        "mrs x17, daif",
+        "orr x9, x9, x17",
+        "mrs x17, s3_0_c4_c2_3",
+        "orr x9, x9, x17",
+        "str x9, [x2, #{bf}]",
+        "mrs x9, sctlr_el1",
+        "str x9, [x2, #{bc0}]",
+        "mrs x9, tcr_el1",
+        "str x9, [x2, #{bc4}]",
+        "add x17, x2, #{bg}",
+        "mov x9, sp",
+        "stp x0, x1, [x17, #0]",
+        "stp x2, x3, [x17, #16]",
+        "stp x4, x5, [x17, #32]",
+        "stp x6, x7, [x17, #48]",
+        "stp x8, x9, [x17, #64]",
+        "stp x10, x11, [x17, #80]",
+        "stp x12, x13, [x17, #96]",
+        "stp x14, x15, [x17, #112]",
+        "stp x16, x17, [x17, #128]",
+        "stp x18, x19, [x17, #144]",
+        "stp x20, x21, [x17, #160]",
+        "stp x22, x23, [x17, #176]",
+        "stp x24, x25, [x17, #192]",
+        "stp x26, x27, [x17, #208]",
+        "stp x28, x29, [x17, #224]",
+        "stp x30, x9, [x17, #240]",
+        "blr x16",
+        // Save X0 and X1 first to free registers for `transcript`.
+        "stp x0, x1, [sp, #-16]!",
+        "mrs x0, nzcv",
+        "mrs x1, daif",
+        "orr x0, x0, x1",
+        "mrs x1, s3_0_c4_c2_3",
+        "orr x0, x0, x1",
+        "ldr x1, [sp, #16]",
+        "str x0, [x1, #{af}]",
+        "mrs x0, sctlr_el1",
+        "str x0, [x1, #{ac0}]",
+        "mrs x0, tcr_el1",
+        "str x0, [x1, #{ac4}]",
+        "add x1, x1, #{ag}",
+        "stp x2, x3, [x1, #16]",
+        "stp x4, x5, [x1, #32]",
+        "stp x6, x7, [x1, #48]",
+        "stp x8, x9, [x1, #64]",
+        "stp x10, x11, [x1, #80]",
+        "stp x12, x13, [x1, #96]",
+        "stp x14, x15, [x1, #112]",
+        "stp x16, x17, [x1, #128]",
+        "stp x18, x19, [x1, #144]",
+        "stp x20, x21, [x1, #160]",
+        "stp x22, x23, [x1, #176]",
+        "stp x24, x25, [x1, #192]",
+        "stp x26, x27, [x1, #208]",
+        "stp x28, x29, [x1, #224]",
+        "ldp x2, x3, [sp], #16",
+        "stp x2, x3, [x1, #0]",
+        "mov x2, sp",
+        "stp x30, x2, [x1, #240]",
+        "ldr x2, [sp, #8]",
+        "mov sp, x2",
+        "ldp x29, x30, [sp], #16",
+        "ret",
+        bg = const BEFORE_GPRS,
+        bf = const BEFORE_FLAGS,
+        bc0 = const BEFORE_CR0,
+        bc4 = const BEFORE_CR4,
+        ag = const AFTER_GPRS,
+        af = const AFTER_FLAGS,
+        ac0 = const AFTER_CR0,
+        ac4 = const AFTER_CR4,
+    );
+}
+
+/// Returns the length of the instruction at the start of `code`, or `None` if
+/// it is truncated or permanently undefined (`udf`).
+pub(crate) fn instruction_length(code: &[u8]) -> Option<usize> {
+    let word = u32::from_le_bytes(code.get(..4)?.try_into().ok()?);
+    (word >> 16 != 0).then_some(4)
+}
+
+/// The routine of an APC that calls `R::run` and then releases the rundown
+/// protection it returns by jumping to `ExReleaseRundownProtection`, which
+/// returns to the caller of this routine instead of into the driver. The
+/// driver may unload as soon as the protection is released.
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn apc_kernel_routine<R: ApcRoutine>(
+    _apc: PKAPC,
+    _normal_routine: *mut PVOID,
+    _normal_context: *mut PVOID,
+    _system_argument1: *mut PVOID,
+    _system_argument2: *mut PVOID,
+) {
+    naked_asm!(
+        // Keep `apc` in X0. The returned protection is passed on in X0 too.
+        // The unwind information lets `RtlCaptureStackBackTrace` in `R::run`
+        // walk past this routine.
+        ".seh_proc {this}",
+        "stp x29, x30, [sp, #-16]!",
+        ".seh_save_fplr_x 16",
+        "mov x29, sp",
+        ".seh_set_fp",
+        ".seh_endprologue",
+        "bl {run}",
+        ".seh_startepilogue",
+        "ldp x29, x30, [sp], #16",
+        ".seh_save_fplr_x 16",
+        ".seh_endepilogue",
+        "b {release}",
+        ".seh_endproc",
+        this = sym apc_kernel_routine::<R>,
+        run = sym R::run,
+        release = sym ExReleaseRundownProtection,
+    );
+}
+
+/// The rundown routine of an APC, like [`apc_kernel_routine`].
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn apc_rundown_routine<R: ApcRoutine>(_apc: PKAPC) {
+    naked_asm!(
+        ".seh_proc {this}",
+        "stp x29, x30, [sp, #-16]!",
+        ".seh_save_fplr_x 16",
+        "mov x29, sp",
+        ".seh_set_fp",
+        ".seh_endprologue",
+        "bl {run}",
+        ".seh_startepilogue",
+        "ldp x29, x30, [sp], #16",
+        ".seh_save_fplr_x 16",
+        ".seh_endepilogue",
+        "b {release}",
+        ".seh_endproc",
+        this = sym apc_rundown_routine::<R>,
+        run = sym R::run,
+        release = sym ExReleaseRundownProtection,
+    );
+}
+
+/// Breaks into a debugger.
+pub(crate) fn breakpoint() {
+    unsafe { asm!("brk #0xf000", options(nomem, nostack)) };
+}
+
+/// Returns the CET features enabled in kernel-mode. ARM64 does not have CET.
+pub(crate) fn cet() -> Cet {
+    Cet::default()
+}
+
+/// Returns the descriptor tables of the current processor. ARM64 has no IDT,
+/// GDT or TSS.
+pub(crate) fn cpu_state(_first_vector: u32) -> Option<CpuState> {
+    None
+}
+
+/// Returns the interrupt frame of the NMI being handled. ARM64 has no NMI in
+/// the x86 sense.
+pub(crate) fn nmi_frame() -> Option<[u64; 5]> {
+    None
+}
+
+/// Returns the mode of the local APIC. ARM64 has the GIC instead.
+pub(crate) fn apic_mode() -> Option<ApicMode> {
+    None
+}
+
+/// Reads the x2APIC register. Never called as [`apic_mode`] returns `None`.
+pub(crate) unsafe fn read_x2apic(_offset: u32) -> u64 {
+    unreachable!()
+}
+
+/// Writes to the x2APIC register. Never called as [`apic_mode`] returns
+/// `None`.
+pub(crate) unsafe fn write_x2apic(_offset: u32, _value: u64) {
+    unreachable!()
+}
+
+/// Reads from the I/O port. Never called as [`HAS_PORT_IO`] is `false`.
+pub(crate) unsafe fn read_port(_port: u16, _size: u8) -> u32 {
+    unreachable!()
+}
+
+/// Writes to the I/O port. Never called as [`HAS_PORT_IO`] is `false`.
+pub(crate) unsafe fn write_port(_port: u16, _size: u8, _value: u32) {
+    unreachable!()
+}
+
+/// Runs `f`. `KeSaveExtendedProcessorState` is available only on x86 and
+/// x86_64, so code using the floating-point and SIMD registers must preserve
+/// them itself.
+#[expect(clippy::unnecessary_wraps)]
+pub(crate) fn with_extended_state<T>(f: impl FnOnce() -> T) -> Result<T, NTSTATUS> {
+    Ok(f())
+}
+
+/// Runs `f` with IRQs and FIQs masked on the current processor.
+pub(crate) fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    let daif: usize;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack, preserves_flags));
+        asm!("msr daifset, #0b0011", options(nomem, nostack));
+    }
+    let result = f();
+    unsafe { asm!("msr daif, {}", in(reg) daif & DAIF_MASK, options(nomem, nostack)) };
+    result
+}
+
+/// Returns the physical address of the top-level page table. The driver walks
+/// only x86_64 page tables.
+pub(crate) fn page_table_root() -> Option<u64> {
+    None
+}
+
+/// Flushes TLB entries of all address spaces in the inner shareable domain.
+pub(crate) unsafe fn flush_tlb() {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "isb",
+            options(nostack)
+        );
+    };
+}
+
+/// Disables PSTATE.PAN and disables interrupts so that the payload can access
+/// user-mode memory. Returns the previous PSTATE.{PAN,D,A,I,F}.
+pub(crate) unsafe fn disable_protection() -> usize {
+    let daif: usize;
+    let pan: usize;
+    unsafe {
+        asm!("mrs {}, daif", out(reg) daif, options(nomem, nostack, preserves_flags));
+        asm!("msr daifset, #0b0011", options(nomem, nostack));
+        asm!("mrs {}, s3_0_c4_c2_3", out(reg) pan, options(nomem, nostack, preserves_flags));
+        asm!("msr s3_0_c4_c2_3, xzr", options(nomem, nostack));
+    }
+    (daif & DAIF_MASK) | (pan & PAN_MASK)
+}
+
+/// Restores PSTATE.PAN and interrupts.
+pub(crate) unsafe fn restore_protection(pstate: usize) {
+    unsafe {
+        asm!("msr s3_0_c4_c2_3, {}", in(reg) pstate & PAN_MASK, options(nomem, nostack));
+        asm!("msr daif, {}", in(reg) pstate & DAIF_MASK, options(nomem, nostack));
+    }
+}
+
+/// Writes PSTATE.PAN, which [`disable_protection`] clears, back with its
+/// current value, and returns whether it reads back the same. PAN stays
+/// enabled.
+pub(crate) fn check_protection() -> bool {
+    let (pan, written): (usize, usize);
+    unsafe {
+        asm!("mrs {}, s3_0_c4_c2_3", out(reg) pan, options(nomem, nostack, preserves_flags));
+        asm!("msr s3_0_c4_c2_3, {}", in(reg) pan, options(nomem, nostack));
+        asm!("mrs {}, s3_0_c4_c2_3", out(reg) written, options(nomem, nostack, preserves_flags));
+    }
+    pan == written
+}
+
+/// Makes code written to memory visible to instruction fetches by cleaning the
+/// data cache and invalidating the instruction cache of the range.
+pub(crate) unsafe fn flush_instruction_cache(address: *const c_void, length: usize) {
+    let ctr: usize;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr, options(nomem, nostack, preserves_flags)) };
+    let data_line_size = 4_usize << ((ctr >> 16) & 0xf);
+    let instruction_line_size = 4_usize << (ctr & 0xf);
+    let start = address.addr();
+    let end = start + length;
+
+    unsafe {
+        for line in (start & !(data_line_size - 1)..end).step_by(data_line_size) {
+            asm!("dc cvau, {}", in(reg) line, options(nostack, preserves_flags));
+        }
+        asm!("dsb ish", options(nostack, preserves_flags));
+        for line in (start & !(instruction_line_size - 1)..end).step_by(instruction_line_size) {
+            asm!("ic ivau, {}", in(reg) line, options(nostack, preserves_flags));
+        }
+        asm!("dsb ish", "isb", options(nostack, preserves_flags));
+    }
+}
+
+/// Discards instructions the current processor fetched before code was
+/// written, possibly by another processor, after [`flush_instruction_cache`].
+pub(crate) fn serialize_instruction_fetch() {
+    unsafe { asm!("isb", options(nomem, nostack, preserves_flags)) };
+}
@@ -0,0 +1,707 @@
+//! The implementation for both x86 and x86_64. Control registers are accessed
+//! with the native register width.
+
+use core::{
+    arch::{asm, naked_asm},
+    ffi::c_void,
+    mem::{self, offset_of},
+};
+
+use capcom_abi::{CPU_STATE_IDT_ENTRIES, CpuState, IdtEntry, PayloadTranscript, RegisterState};
+use wdk_sys::{
+    NT_SUCCESS, NTSTATUS, PKAPC, PVOID, XSTATE_SAVE,
+    ntddk::{
+        ExReleaseRundownProtection, KeRestoreExtendedProcessorState, KeSaveExtendedProcessorState,
+    },
+};
+
+use super::{ApcRoutine, ApicMode, Cet};
+
+mod decode;
+
+pub(crate) use decode::instruction_length;
+
+/// Whether a payload in user-mode memory can be executed. Clearing CR4.SMEP
+/// makes user-mode pages executable in kernel-mode.
+pub(crate) const CAN_RUN_USER_PAYLOAD: bool = true;
+
+/// Whether the processor has I/O ports.
+pub(crate) const HAS_PORT_IO: bool = true;
+
+/// The size of an IDT entry.
+#[cfg(target_arch = "x86_64")]
+const IDT_ENTRY_SIZE: usize = 16;
+#[cfg(target_arch = "x86")]
+const IDT_ENTRY_SIZE: usize = 8;
+
+/// The instruction an indirect branch target must start with under indirect
+/// branch tracking, `endbr64` or `endbr32`.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const BRANCH_TARGET: &[u8] = &[0xf3, 0x0f, 0x1e, 0xfa];
+#[cfg(target_arch = "x86")]
+pub(crate) const BRANCH_TARGET: &[u8] = &[0xf3, 0x0f, 0x1e, 0xfb];
+
+/// The indexes in `RegisterState::gprs` and the names of the general-purpose
+/// registers a payload must preserve under the calling convention.
+#[cfg(target_arch = "x86_64")]
+pub(crate) const PRESERVED_REGISTERS: &[(usize, &str)] = &[
+    (3, "rbx"),
+    (4, "rsp"),
+    (5, "rbp"),
+    (6, "rsi"),
+    (7, "rdi"),
+    (12, "r12"),
+    (13, "r13"),
+    (14, "r14"),
+    (15, "r15"),
+];
+#[cfg(target_arch = "x86")]
+pub(crate) const PRESERVED_REGISTERS: &[(usize, &str)] =
+    &[(3, "ebx"), (4, "esp"), (5, "ebp"), (6, "esi"), (7, "edi")];
+
+/// The bits of RFLAGS a payload must preserve: IF, DF and AC.
+pub(crate) const PRESERVED_FLAGS: u64 = (1 << 9) | (1 << 10) | (1 << 18);
+
+/// The names of `RegisterState::cr0` and `RegisterState::cr4`.
+pub(crate) const CONTROL_REGISTERS: [&str; 2] = ["cr0", "cr4"];
+
+/// The offsets of the fields of `PayloadTranscript` written by [`call_payload`].
+const BEFORE_GPRS: usize = offset_of!(PayloadTranscript, before) + offset_of!(RegisterState, gprs);
+const BEFORE_FLAGS: usize =
+    offset_of!(PayloadTranscript, before) + offset_of!(RegisterState, flags);
+const BEFORE_CR0: usize = offset_of!(PayloadTranscript, before) + offset_of!(RegisterState, cr0);
+const BEFORE_CR4: usize = offset_of!(PayloadTranscript, before) + offset_of!(RegisterState, cr4);
+const AFTER_GPRS: usize = offset_of!(PayloadTranscript, after) + offset_of!(RegisterState, gprs);
+const AFTER_FLAGS: usize = offset_of!(PayloadTranscript, after) + offset_of!(RegisterState, flags);
+const AFTER_CR0: usize = offset_of!(PayloadTranscript, after) + offset_of!(RegisterState, cr0);
+const AFTER_CR4: usize = offset_of!(PayloadTranscript, after) + offset_of!(RegisterState, cr4);
+
+/// Calls `payload` with `argument` on the stack whose top is `stack`, or on the
+/// current stack if zero, and records the registers right before and after
+/// the call in `transcript`, except the IRQL.
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn call_payload(
+    payload: usize,
+    argument: usize,
+    transcript: *mut PayloadTranscript,
+    stack: usize,
+) {
+    naked_asm!(
+        // Keep `transcript` and the original RSP above the shadow space, as the
+        // payload may use any register.
+        "mov rax, rsp",
+        "test r9, r9",
+        "cmovz r9, rax",
+        "and r9, -16",
+        "lea rsp, [r9 - 0x30]",
+        "mov [rsp + 0x20], r8",
+        "mov [rsp + 0x28], rax",
+        "mov rax, rcx",
+        "mov rcx, rdx",
+        "pushfq",
+        "pop r9",
+        "mov [r8 + {bf}], r9",
+        "mov r9, cr0",
+        "mov [r8 + {bc0}], r9",
+        "mov r9, cr4",
+        "mov [r8 + {bc4}], r9",
+        "mov [r8 + {bg} + 8 * 0], rax",
+        "mov [r8 + {bg} + 8 * 1], rcx",
+        "mov [r8 + {bg} + 8 * 2], rdx",
+        "mov [r8 + {bg} + 8 * 3], rbx",
+        "mov [r8 + {bg} + 8 * 4], rsp",
+        "mov [r8 + {bg} + 8 * 5], rbp",
+        "mov [r8 + {bg} + 8 * 6], rsi",
+        "mov [r8 + {bg} + 8 * 7], rdi",
+        "mov [r8 + {bg} + 8 * 8], r8",
+        "mov [r8 + {bg} + 8 * 9], r9",
+        "mov [r8 + {bg} + 8 * 10], r10",
+        "mov [r8 + {bg} + 8 * 11], r11",
+        "mov [r8 + {bg} + 8 * 12], r12",
+        "mov [r8 + {bg} + 8 * 13], r13",
+        "mov [r8 + {bg} + 8 * 14], r14",
+        "mov [r8 + {bg} + 8 * 15], r15",
+        "call rax",
+        // Save RFLAGS and RAX first to free a register for `transcript`.
+        "pushfq",
+        "push rax",
+        "mov rax, [rsp + 0x30]",
+        "mov [rax + {ag} + 8 * 1], rcx",
+        "mov [rax + {ag} + 8 * 2], rdx",
+        "mov [rax + {ag} + 8 * 3], rbx",
+        "mov [rax + {ag} + 8 * 5], rbp",
+        "mov [rax + {ag} + 8 * 6], rsi",
+        "mov [rax + {ag} + 8 * 7], rdi",
+        "mov [rax + {ag} + 8 * 8], r8",
+        "mov [rax + {ag} + 8 * 9], r9",
+        "mov [rax + {ag} + 8 * 10], r10",
+        "mov [rax + {ag} + 8 * 11], r11",
+        "mov [rax + {ag} + 8 * 12], r12",
+        "mov [rax + {ag} + 8 * 13], r13",
+        "mov [rax + {ag} + 8 * 14], r14",
+        "mov [rax + {ag} + 8 * 15], r15",
+        "pop rcx",
+        "mov [rax + {ag} + 8 * 0], rcx",
+        "pop rcx",
+        "mov [rax + {af}], rcx",
+        "mov [rax + {ag} + 8 * 4], rsp",
+        "mov rcx, cr0",
+        "mov [rax + {ac0}], rcx",
+        "mov rcx, cr4",
+        "mov [rax + {ac4}], rcx",
+        "mov rsp, [rsp + 0x28]",
+        "ret",
+        bg = const BEFORE_GPRS,
+        bf = const BEFORE_FLAGS,
+        bc0 = const BEFORE_CR0,
+        bc4 = const BEFORE_CR4,
+        ag = const AFTER_GPRS,
+        af = const AFTER_FLAGS,
+        ac0 = const AFTER_CR0,
+        ac4 = const AFTER_CR4,
+    );
+}
+
+/// Calls `payload` with `argument` on the stack whose top is `stack`, or on the
+/// current stack if zero, and records the registers right before and after
+/// the call in `transcript`, except the IRQL. Only the low halves of the
+/// entries are written, so `transcript` must be zeroed.
+#[cfg(target_arch = "x86")]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn call_payload(
+    payload: usize,
+    argument: usize,
+    transcript: *mut PayloadTranscript,
+    stack: usize,
+) {
+    naked_asm!(
+        "mov eax, [esp + 4]",
+        "mov edx, [esp + 12]",
+        "mov ecx, [esp + 16]",
+        "test ecx, ecx",
+        "cmovz ecx, esp",
+        "and ecx, -16",
+        // Keep the original ESP and `transcript` on the stack, as the payload
+        // may use any register.
+        "xchg ecx, esp",
+        "push ecx",
+        "push edx",
+        "push dword ptr [ecx + 8]",
+        "pushfd",
+        "pop ecx",
+        "mov [edx + {bf}], ecx",
+        "mov ecx, cr0",
+        "mov [edx + {bc0}], ecx",
+        "mov ecx, cr4",
+        "mov [edx + {bc4}], ecx",
+        "mov [edx + {bg} + 8 * 0], eax",
+        "mov [edx + {bg} + 8 * 1], ecx",
+        "mov [edx + {bg} + 8 * 2], edx",
+        "mov [edx + {bg} + 8 * 3], ebx",
+        "mov [edx + {bg} + 8 * 4], esp",
+        "mov [edx + {bg} + 8 * 5], ebp",
+        "mov [edx + {bg} + 8 * 6], esi",
+        "mov [edx + {bg} + 8 * 7], edi",
+        "call eax",
+        // Save EFLAGS and EAX first to free a register for `transcript`.
+        "pushfd",
+        "push eax",
+        "mov eax, [esp + 12]",
+        "mov [eax + {ag} + 8 * 1], ecx",
+        "mov [eax + {ag} + 8 * 2], edx",
+        "mov [eax + {ag} + 8 * 3], ebx",
+        "mov [eax + {ag} + 8 * 5], ebp",
+        "mov [eax + {ag} + 8 * 6], esi",
+        "mov [eax + {ag} + 8 * 7], edi",
+        "pop ecx",
+        "mov [eax + {ag} + 8 * 0], ecx",
+        "pop ecx",
+        "mov [eax + {af}], ecx",
+        "mov [eax + {ag} + 8 * 4], esp",
+        "mov ecx, cr0",
+        "mov [eax + {ac0}], ecx",
+        "mov ecx, cr4",
+        "mov [eax + {ac4}], ecx",
+        "mov esp, [esp + 8]",
+        "ret",
+        bg = const BEFORE_GPRS,
+        bf = const BEFORE_FLAGS,
+        bc0 = const BEFORE_CR0,
+        bc4 = const BEFORE_CR4,
+        ag = const AFTER_GPRS,
+        af = const AFTER_FLAGS,
+        ac0 = const AFTER_CR0,
+        ac4 = const AFTER_CR4,
+    );
+}
+
+/// The routine of an APC that calls `R::run` and then releases the rundown
+/// protection it returns by jumping to `ExReleaseRundownProtection`, which
+/// returns to the caller of this routine instead of into the driver. The
+/// driver may unload as soon as the protection is released.
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn apc_kernel_routine<R: ApcRoutine>(
+    _apc: PKAPC,
+    _normal_routine: *mut PVOID,
+    _normal_context: *mut PVOID,
+    _system_argument1: *mut PVOID,
+    _system_argument2: *mut PVOID,
+) {
+    naked_asm!(
+        // Reserve the shadow space and align the stack, keeping `apc` in RCX.
+        // The unwind information lets `RtlCaptureStackBackTrace` in `R::run`
+        // walk past this routine.
+        ".seh_proc {this}",
+        "sub rsp, 0x28",
+        ".seh_stackalloc 0x28",
+        ".seh_endprologue",
+        "call {run}",
+        "mov rcx, rax",
+        "add rsp, 0x28",
+        "jmp {release}",
+        ".seh_endproc",
+        this = sym apc_kernel_routine::<R>,
+        run = sym R::run,
+        release = sym ExReleaseRundownProtection,
+    );
+}
+
+/// The routine of an APC that calls `R::run` and then releases the rundown
+/// protection it returns by jumping to `ExReleaseRundownProtection`, which
+/// returns to the caller of this routine instead of into the driver. The
+/// driver may unload as soon as the protection is released.
+#[cfg(target_arch = "x86")]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn apc_kernel_routine<R: ApcRoutine>(
+    _apc: PKAPC,
+    _normal_routine: *mut PVOID,
+    _normal_context: *mut PVOID,
+    _system_argument1: *mut PVOID,
+    _system_argument2: *mut PVOID,
+) {
+    naked_asm!(
+        // Make a frame that `RtlCaptureStackBackTrace` in `R::run` can walk
+        // past.
+        "push ebp",
+        "mov ebp, esp",
+        "push dword ptr [ebp + 8]",
+        "call {run}",
+        "add esp, 4",
+        "pop ebp",
+        // `ExReleaseRundownProtection` is fastcall.
+        "mov ecx, eax",
+        "jmp {release}",
+        run = sym R::run,
+        release = sym ExReleaseRundownProtection,
+    );
+}
+
+/// The rundown routine of an APC, like [`apc_kernel_routine`].
+#[cfg(target_arch = "x86_64")]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn apc_rundown_routine<R: ApcRoutine>(_apc: PKAPC) {
+    naked_asm!(
+        ".seh_proc {this}",
+        "sub rsp, 0x28",
+        ".seh_stackalloc 0x28",
+        ".seh_endprologue",
+        "call {run}",
+        "mov rcx, rax",
+        "add rsp, 0x28",
+        "jmp {release}",
+        ".seh_endproc",
+        this = sym apc_rundown_routine::<R>,
+        run = sym R::run,
+        release = sym ExReleaseRundownProtection,
+    );
+}
+
+/// The rundown routine of an APC, like [`apc_kernel_routine`].
+#[cfg(target_arch = "x86")]
+#[unsafe(naked)]
+pub(crate) unsafe extern "C" fn apc_rundown_routine<R: ApcRoutine>(_apc: PKAPC) {
+    naked_asm!(
+        "push ebp",
+        "mov ebp, esp",
+        "push dword ptr [ebp + 8]",
+        "call {run}",
+        "add esp, 4",
+        "pop ebp",
+        "mov ecx, eax",
+        "jmp {release}",
+        run = sym R::run,
+        release = sym ExReleaseRundownProtection,
+    );
+}
+
+/// Breaks into a debugger.
+pub(crate) fn breakpoint() {
+    unsafe { asm!("int3", options(nomem, nostack, preserves_flags)) };
+}
+
+/// Returns the CET features enabled in kernel-mode.
+pub(crate) fn cet() -> Cet {
+    const CR4_CET: usize = 1 << 23;
+    const IA32_S_CET: u32 = 0x6a2;
+    const SH_STK_EN: u64 = 1 << 0;
+    const ENDBR_EN: u64 = 1 << 2;
+
+    // IA32_S_CET exists only when the processor supports CET, which CR4.CET
+    // being set implies.
+    if unsafe { cr4() } & CR4_CET == 0 {
+        return Cet::default();
+    }
+    let s_cet = unsafe { rdmsr(IA32_S_CET) };
+    Cet {
+        shadow_stack: s_cet & SH_STK_EN != 0,
+        indirect_branch_tracking: s_cet & ENDBR_EN != 0,
+    }
+}
+
+/// Disables CR4.SMEP and disables interrupts. Other bits are written back as
+/// they are, so CR4.CET and thus supervisor shadow stacks stay enabled.
+pub(crate) unsafe fn disable_protection() -> usize {
+    const CR4_SMEP: usize = 1 << 20;
+
+    unsafe {
+        asm!("cli", options(nomem, nostack));
+        let cr4 = cr4();
+        write_cr4(cr4 & !CR4_SMEP);
+        cr4
+    }
+}
+
+/// Restores CR4 and enables interrupts.
+pub(crate) unsafe fn restore_protection(cr4: usize) {
+    unsafe {
+        write_cr4(cr4);
+        asm!("sti", options(nomem, nostack));
+    };
+}
+
+/// Writes CR4, which [`disable_protection`] changes, back with its current
+/// value with interrupts disabled, and returns whether it reads back the same.
+/// SMEP stays enabled.
+pub(crate) fn check_protection() -> bool {
+    without_interrupts(|| unsafe {
+        let value = cr4();
+        write_cr4(value);
+        cr4() == value
+    })
+}
+
+/// Makes code written to memory visible to instruction fetches. Nothing is
+/// needed as x86 keeps the instruction cache coherent, but the processor
+/// executing the code must still call [`serialize_instruction_fetch`].
+pub(crate) unsafe fn flush_instruction_cache(_address: *const c_void, _length: usize) {}
+
+/// Discards instructions the current processor fetched before code was
+/// written, possibly by another processor, as recommended for cross-modifying
+/// code. `cpuid` is serializing on all processors, unlike `serialize`.
+pub(crate) fn serialize_instruction_fetch() {
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86::__cpuid;
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64::__cpuid;
+
+    let _ = __cpuid(0);
+}
+
+/// Returns the physical address of the PML4 of the current address space, or
+/// `None` if the paging mode is not 4-level paging of x86_64.
+pub(crate) fn page_table_root() -> Option<u64> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        const CR4_LA57: usize = 1 << 12;
+        const CR3_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+        if unsafe { cr4() } & CR4_LA57 != 0 {
+            return None;
+        }
+        let cr3: u64;
+        unsafe { asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags)) };
+        Some(cr3 & CR3_ADDRESS_MASK)
+    }
+    #[cfg(target_arch = "x86")]
+    None
+}
+
+/// Flushes TLB entries of all address spaces on the current processor,
+/// including global ones, by toggling CR4.PGE.
+pub(crate) unsafe fn flush_tlb() {
+    const CR4_PGE: usize = 1 << 7;
+
+    unsafe {
+        let cr4 = cr4();
+        write_cr4(cr4 ^ CR4_PGE);
+        write_cr4(cr4);
+    }
+}
+
+/// The operand of `sidt` and `sgdt`.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Default)]
+struct DescriptorTableRegister {
+    limit: u16,
+    base: usize,
+}
+
+/// Returns IDTR, GDTR and TR of the current processor.
+fn descriptor_tables() -> (DescriptorTableRegister, DescriptorTableRegister, u16) {
+    let mut idtr = DescriptorTableRegister::default();
+    let mut gdtr = DescriptorTableRegister::default();
+    let tr: u16;
+    unsafe {
+        asm!("sidt [{}]", in(reg) &raw mut idtr, options(nostack, preserves_flags));
+        asm!("sgdt [{}]", in(reg) &raw mut gdtr, options(nostack, preserves_flags));
+        asm!("str {:x}", out(reg) tr, options(nomem, nostack, preserves_flags));
+    }
+    (idtr, gdtr, tr)
+}
+
+/// Returns the descriptor tables, the KPCR and the TSS of the current
+/// processor, and IDT entries from `first_vector`. Interrupts must stay on the
+/// current processor, e.g., by setting the affinity.
+pub(crate) fn cpu_state(first_vector: u32) -> Option<CpuState> {
+    let kpcr: usize;
+    let (idtr, gdtr, tr) = descriptor_tables();
+    unsafe {
+        // `KPCR::Self` on x86_64 and `KPCR::SelfPcr` on x86.
+        #[cfg(target_arch = "x86_64")]
+        asm!("mov {}, gs:[0x18]", out(reg) kpcr, options(nostack, preserves_flags, readonly));
+        #[cfg(target_arch = "x86")]
+        asm!("mov {}, fs:[0x1c]", out(reg) kpcr, options(nostack, preserves_flags, readonly));
+    }
+    let (idtr_base, idtr_limit) = (idtr.base, idtr.limit);
+    let (gdtr_base, gdtr_limit) = (gdtr.base, gdtr.limit);
+
+    let vector_count = (usize::from(idtr_limit) + 1) / IDT_ENTRY_SIZE;
+    let first_vector = first_vector as usize;
+    if first_vector >= vector_count {
+        return None;
+    }
+
+    let mut state = CpuState {
+        idtr_base: idtr_base as u64,
+        gdtr_base: gdtr_base as u64,
+        kpcr: kpcr as u64,
+        tss_base: unsafe { tss_base(gdtr_base, gdtr_limit, tr) } as u64,
+        idtr_limit,
+        gdtr_limit,
+        tr,
+        idt_entry_count: (vector_count - first_vector).min(CPU_STATE_IDT_ENTRIES) as u32,
+        ..CpuState::default()
+    };
+    for (i, entry) in state.idt_entries[..state.idt_entry_count as usize]
+        .iter_mut()
+        .enumerate()
+    {
+        let address = idtr_base + (first_vector + i) * IDT_ENTRY_SIZE;
+        *entry = unsafe { decode_idt_entry(address as *const u8) };
+    }
+    Some(state)
+}
+
+/// Returns the interrupt frame the processor pushed when the NMI being handled
+/// on the current processor was delivered, as RIP, CS, RFLAGS, RSP and SS. The
+/// NMI handler runs on an IST stack, and the frame is at its top. Returns
+/// `None` on x86, where the NMI handler is a task gate, or if the frame does
+/// not look valid.
+pub(crate) fn nmi_frame() -> Option<[u64; 5]> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        const NMI_VECTOR: usize = 2;
+        // The offset of `IST1` in the 64-bit TSS.
+        const TSS_IST1: usize = 0x24;
+
+        let (idtr, gdtr, tr) = descriptor_tables();
+        let (idtr_base, gdtr_base, gdtr_limit) = (idtr.base, gdtr.base, gdtr.limit);
+        let entry = unsafe { decode_idt_entry((idtr_base + NMI_VECTOR * IDT_ENTRY_SIZE) as _) };
+        let tss = unsafe { tss_base(gdtr_base, gdtr_limit, tr) };
+        if entry.ist == 0 || tss == 0 {
+            return None;
+        }
+        let ist = tss + TSS_IST1 + (usize::from(entry.ist) - 1) * 8;
+        let top = unsafe { (ist as *const usize).read_unaligned() } & !0xf;
+        let frame = unsafe { ((top - 5 * 8) as *const [u64; 5]).read() };
+        // A null or out-of-range CS means that the frame is not there.
+        (frame[1] != 0 && frame[1] <= 0xffff).then_some(frame)
+    }
+    #[cfg(target_arch = "x86")]
+    None
+}
+
+/// Returns the base address of the TSS selected by `tr` from the GDT, or zero
+/// if the selector is outside the GDT.
+unsafe fn tss_base(gdtr_base: usize, gdtr_limit: u16, tr: u16) -> usize {
+    let offset = usize::from(tr & !7);
+    if offset + 7 > usize::from(gdtr_limit) {
+        return 0;
+    }
+    let descriptor = unsafe { ((gdtr_base + offset) as *const u64).read_unaligned() };
+    let base = ((descriptor >> 16) & 0xff_ffff) | (((descriptor >> 56) & 0xff) << 24);
+    // The TSS descriptor is 16 bytes on x86_64, with bits 63:32 of the base in
+    // the second half.
+    #[cfg(target_arch = "x86_64")]
+    let base = {
+        let high = unsafe { ((gdtr_base + offset + 8) as *const u32).read_unaligned() };
+        base | (u64::from(high) << 32)
+    };
+    base as usize
+}
+
+/// Decodes the IDT entry at `address`.
+unsafe fn decode_idt_entry(address: *const u8) -> IdtEntry {
+    let read_u16 = |offset| unsafe { address.add(offset).cast::<u16>().read_unaligned() };
+    let low = u64::from(read_u16(0));
+    let middle = u64::from(read_u16(6));
+    #[cfg(target_arch = "x86_64")]
+    let high = u64::from(unsafe { address.add(8).cast::<u32>().read_unaligned() });
+    #[cfg(target_arch = "x86")]
+    let high = 0;
+    // Bits 2:0 of the fifth byte hold the IST index on x86_64, and are reserved
+    // on x86.
+    #[cfg(target_arch = "x86_64")]
+    let ist = unsafe { address.add(4).read() } & 7;
+    #[cfg(target_arch = "x86")]
+    let ist = 0;
+    IdtEntry {
+        handler: low | (middle << 16) | (high << 32),
+        selector: read_u16(2),
+        ist,
+        attributes: unsafe { address.add(5).read() },
+        reserved: 0,
+    }
+}
+
+/// Returns the mode and the physical address of the local APIC of the current
+/// processor, or `None` if it is disabled.
+pub(crate) fn apic_mode() -> Option<ApicMode> {
+    const IA32_APIC_BASE: u32 = 0x1b;
+    const APIC_GLOBAL_ENABLE: u64 = 1 << 11;
+    const X2APIC_ENABLE: u64 = 1 << 10;
+    const APIC_BASE_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    let apic_base = unsafe { rdmsr(IA32_APIC_BASE) };
+    if apic_base & APIC_GLOBAL_ENABLE == 0 {
+        None
+    } else if apic_base & X2APIC_ENABLE != 0 {
+        Some(ApicMode::X2Apic)
+    } else {
+        Some(ApicMode::XApic(apic_base & APIC_BASE_MASK))
+    }
+}
+
+/// Reads the x2APIC register at the xAPIC MMIO offset `offset`.
+pub(crate) unsafe fn read_x2apic(offset: u32) -> u64 {
+    unsafe { rdmsr(0x800 + offset / 16) }
+}
+
+/// Writes to the x2APIC register at the xAPIC MMIO offset `offset`.
+pub(crate) unsafe fn write_x2apic(offset: u32, value: u64) {
+    unsafe { wrmsr(0x800 + offset / 16, value) };
+}
+
+/// Runs `f` with the x87, SSE, AVX and AVX-512 registers saved before and
+/// restored after, so that code using them does not corrupt the state of the
+/// thread, e.g., of its user-mode. Must be called at or below `DISPATCH_LEVEL`.
+pub(crate) fn with_extended_state<T>(f: impl FnOnce() -> T) -> Result<T, NTSTATUS> {
+    /// `XSTATE_MASK_LEGACY`, `XSTATE_MASK_AVX` and `XSTATE_MASK_AVX512`.
+    /// Features not enabled on the system are ignored.
+    const XSTATE_MASK: u64 = 0b1110_0111;
+
+    // The save area must stay at the same address until restored, as the
+    // kernel links it to the thread.
+    let mut save: XSTATE_SAVE = unsafe { mem::zeroed() };
+    let status = unsafe { KeSaveExtendedProcessorState(XSTATE_MASK, &raw mut save) };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+    let result = f();
+    unsafe { KeRestoreExtendedProcessorState(&raw mut save) };
+    Ok(result)
+}
+
+/// Runs `f` with interrupts disabled on the current processor.
+pub(crate) fn without_interrupts<T>(f: impl FnOnce() -> T) -> T {
+    const RFLAGS_IF: usize = 1 << 9;
+
+    let flags: usize;
+    unsafe { asm!("pushf", "pop {}", "cli", out(reg) flags, options(nomem)) };
+    let result = f();
+    if flags & RFLAGS_IF != 0 {
+        unsafe { asm!("sti", options(nomem, nostack)) };
+    }
+    result
+}
+
+/// Reads `size` bytes, 1, 2 or 4, from the I/O port.
+pub(crate) unsafe fn read_port(port: u16, size: u8) -> u32 {
+    unsafe {
+        match size {
+            1 => {
+                let value: u8;
+                asm!("in al, dx", in("dx") port, out("al") value, options(nomem, nostack, preserves_flags));
+                u32::from(value)
+            }
+            2 => {
+                let value: u16;
+                asm!("in ax, dx", in("dx") port, out("ax") value, options(nomem, nostack, preserves_flags));
+                u32::from(value)
+            }
+            _ => {
+                let value: u32;
+                asm!("in eax, dx", in("dx") port, out("eax") value, options(nomem, nostack, preserves_flags));
+                value
+            }
+        }
+    }
+}
+
+/// Writes the low `size` bytes, 1, 2 or 4, of `value` to the I/O port.
+pub(crate) unsafe fn write_port(port: u16, size: u8, value: u32) {
+    unsafe {
+        match size {
+            1 => {
+                asm!("out dx, al", in("dx") port, in("al") value as u8, options(nomem, nostack, preserves_flags))
+            }
+            2 => {
+                asm!("out dx, ax", in("dx") port, in("ax") value as u16, options(nomem, nostack, preserves_flags))
+            }
+            _ => {
+                asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags))
+            }
+        }
+    }
+}
+
+/// Reads from CR4.
+unsafe fn cr4() -> usize {
+    let value;
+    unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
+    value
+}
+
+/// Writes to CR4.
+unsafe fn write_cr4(value: usize) {
+    unsafe { asm!("mov cr4, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+}
+
+/// Reads from the model-specific register.
+unsafe fn rdmsr(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    };
+    (u64::from(high) << 32) | u64::from(low)
+}
+
+/// Writes to the model-specific register.
+unsafe fn wrmsr(msr: u32, value: u64) {
+    let (low, high) = (value as u32, (value >> 32) as u32);
+    unsafe {
+        asm!("wrmsr", in("ecx") msr, in("eax") low, in("edx") high, options(nomem, nostack, preserves_flags));
+    };
+}
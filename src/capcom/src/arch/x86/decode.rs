@@ -0,0 +1,339 @@
+//! A length disassembler for x86 and x86_64, to check that bytes look like
+//! instructions before executing them.
+//!
+//! It decodes the lengths of general-purpose, x87, SSE, AVX and AVX-512
+//! instructions from the opcode maps, and rejects opcodes that are undefined or
+//! invalid in the current mode, including `ud0`, `ud1` and `ud2`. Operands are
+//! not validated, so some bytes decoded here still raise #UD.
+
+/// Whether instructions are decoded for 64-bit mode.
+const LONG_MODE: bool = cfg!(target_arch = "x86_64");
+
+/// The maximum length of an instruction.
+const MAX_LENGTH: usize = 15;
+
+/// The size of an immediate operand.
+#[derive(Clone, Copy)]
+enum Immediate {
+    None,
+    Byte,
+    Word,
+    /// `iw` followed by `ib`, of `enter`.
+    WordByte,
+    /// 2 or 4 bytes by the operand size.
+    Full,
+    /// 2, 4 or 8 bytes by the operand size, of `mov r, imm`.
+    Wide,
+    /// 4 bytes in 64-bit mode, of near branches.
+    Branch,
+    /// The address size, of `mov` with `moffs`.
+    Offset,
+    /// A far pointer, of `call far` and `jmp far`.
+    Far,
+}
+
+/// The operands of an opcode.
+#[derive(Clone, Copy)]
+struct Operands {
+    /// Whether the opcode is followed by a ModR/M byte.
+    modrm: bool,
+    immediate: Immediate,
+}
+
+impl Operands {
+    const NONE: Self = Self {
+        modrm: false,
+        immediate: Immediate::None,
+    };
+    const MODRM: Self = Self {
+        modrm: true,
+        immediate: Immediate::None,
+    };
+}
+
+/// The reader of instruction bytes.
+struct Reader<'a> {
+    code: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Option<u8> {
+        let byte = *self.code.get(self.position)?;
+        self.position += 1;
+        Some(byte)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.code.get(self.position).copied()
+    }
+
+    fn skip(&mut self, length: usize) -> Option<()> {
+        if self.position + length > self.code.len() {
+            return None;
+        }
+        self.position += length;
+        Some(())
+    }
+}
+
+/// Returns the length of the instruction at the start of `code`, or `None` if
+/// it is invalid or truncated.
+pub(crate) fn instruction_length(code: &[u8]) -> Option<usize> {
+    let mut reader = Reader { code, position: 0 };
+    let mut operand_size_16 = false;
+    let mut address_override = false;
+    let mut opcode = loop {
+        match reader.byte()? {
+            0x26 | 0x2e | 0x36 | 0x3e | 0x64 | 0x65 | 0xf0 | 0xf2 | 0xf3 => {}
+            0x66 => operand_size_16 = true,
+            0x67 => address_override = true,
+            byte => break byte,
+        }
+    };
+    let mut rex_w = false;
+    while LONG_MODE && opcode & 0xf0 == 0x40 {
+        rex_w = opcode & 0x08 != 0;
+        opcode = reader.byte()?;
+    }
+
+    // VEX and EVEX reuse the opcodes of `les`, `lds` and `bound`, which take
+    // only memory operands, in 32-bit mode.
+    let is_vex = matches!(opcode, 0xc4 | 0xc5 | 0x62) && (LONG_MODE || reader.peek()? >> 6 == 0b11);
+    let operands = if is_vex {
+        vex(&mut reader, opcode)?
+    } else if opcode == 0x0f {
+        let opcode = reader.byte()?;
+        match opcode {
+            0x38 => {
+                let _ = reader.byte()?;
+                Operands::MODRM
+            }
+            0x3a => {
+                let _ = reader.byte()?;
+                Operands {
+                    modrm: true,
+                    immediate: Immediate::Byte,
+                }
+            }
+            _ => two_byte(opcode)?,
+        }
+    } else {
+        one_byte(opcode)?
+    };
+
+    let mut immediate = operands.immediate;
+    if operands.modrm {
+        let reg = modrm(&mut reader, !LONG_MODE && address_override)?;
+        match (opcode, reg) {
+            // `test r/m, imm`.
+            (0xf6, 0 | 1) if !is_vex => immediate = Immediate::Byte,
+            (0xf7, 0 | 1) if !is_vex => immediate = Immediate::Full,
+            // XOP, which only AMD processors had.
+            (0x8f, 1..) if !is_vex => return None,
+            _ => {}
+        }
+    }
+
+    let operand_size = if rex_w {
+        8
+    } else if operand_size_16 {
+        2
+    } else {
+        4
+    };
+    let address_size = match (LONG_MODE, address_override) {
+        (true, false) => 8,
+        (true, true) | (false, false) => 4,
+        (false, true) => 2,
+    };
+    let length = match immediate {
+        Immediate::None => 0,
+        Immediate::Byte => 1,
+        Immediate::Word => 2,
+        Immediate::WordByte => 3,
+        Immediate::Branch if LONG_MODE => 4,
+        Immediate::Full | Immediate::Branch => operand_size.min(4),
+        Immediate::Wide => operand_size,
+        Immediate::Offset => address_size,
+        Immediate::Far => operand_size.min(4) + 2,
+    };
+    reader.skip(length)?;
+    (reader.position <= MAX_LENGTH).then_some(reader.position)
+}
+
+/// Returns the operands of the one-byte opcode `opcode`.
+fn one_byte(opcode: u8) -> Option<Operands> {
+    // `push` and `pop` of segment registers, BCD arithmetic, and others that
+    // were removed from 64-bit mode.
+    if LONG_MODE
+        && matches!(
+            opcode,
+            0x06 | 0x07 | 0x0e | 0x16 | 0x17 | 0x1e | 0x1f | 0x27 | 0x2f | 0x37 | 0x3f | 0x60
+                ..=0x62 | 0x82 | 0x9a | 0xc4 | 0xc5 | 0xce | 0xd4 | 0xd5 | 0xea
+        )
+    {
+        return None;
+    }
+    let (modrm, immediate) = match opcode {
+        // Arithmetic in the forms of `r/m, r`, `r, r/m`, `al, ib` and `eax, iz`.
+        0x00..=0x3f => match opcode & 0x07 {
+            0..=3 => (true, Immediate::None),
+            4 => (false, Immediate::Byte),
+            5 => (false, Immediate::Full),
+            _ => (false, Immediate::None),
+        },
+        0x62
+        | 0x63
+        | 0x84..=0x8f
+        | 0xc4
+        | 0xc5
+        | 0xd0..=0xd3
+        | 0xd8..=0xdf
+        | 0xf6
+        | 0xf7
+        | 0xfe
+        | 0xff => (true, Immediate::None),
+        0x6b | 0x80 | 0x82 | 0x83 | 0xc0 | 0xc1 | 0xc6 => (true, Immediate::Byte),
+        0x69 | 0x81 | 0xc7 => (true, Immediate::Full),
+        0x40..=0x61
+        | 0x6c..=0x6f
+        | 0x90..=0x99
+        | 0x9b..=0x9f
+        | 0xa4..=0xa7
+        | 0xaa..=0xaf
+        | 0xc3
+        | 0xc9
+        | 0xcb
+        | 0xcc
+        | 0xce
+        | 0xcf
+        | 0xd7
+        | 0xec..=0xef
+        | 0xf1
+        | 0xf4
+        | 0xf5
+        | 0xf8..=0xfd => (false, Immediate::None),
+        0x6a | 0x70..=0x7f | 0xa8 | 0xb0..=0xb7 | 0xcd | 0xd4 | 0xd5 | 0xe0..=0xe7 | 0xeb => {
+            (false, Immediate::Byte)
+        }
+        0xc2 | 0xca => (false, Immediate::Word),
+        0xc8 => (false, Immediate::WordByte),
+        0x68 | 0xa9 => (false, Immediate::Full),
+        0xb8..=0xbf => (false, Immediate::Wide),
+        0xe8 | 0xe9 => (false, Immediate::Branch),
+        0xa0..=0xa3 => (false, Immediate::Offset),
+        0x9a | 0xea => (false, Immediate::Far),
+        // Prefixes, and `salc` (d6), which is undefined.
+        _ => return None,
+    };
+    Some(Operands { modrm, immediate })
+}
+
+/// Returns the operands of the two-byte opcode `0f opcode`, except the escapes
+/// to the three-byte opcode maps.
+fn two_byte(opcode: u8) -> Option<Operands> {
+    let (modrm, immediate) = match opcode {
+        0x00..=0x03
+        | 0x0d
+        | 0x10..=0x1f
+        | 0x28..=0x2f
+        | 0x40..=0x6f
+        | 0x74..=0x76
+        | 0x78
+        | 0x79
+        | 0x7c..=0x7f
+        | 0x90..=0x9f
+        | 0xa3
+        | 0xa5
+        | 0xab
+        | 0xad..=0xb8
+        | 0xbb..=0xc1
+        | 0xc3
+        | 0xc7
+        | 0xd0..=0xfe => (true, Immediate::None),
+        // 3DNow! (0f) has its opcode as the immediate.
+        0x0f | 0x70..=0x73 | 0xa4 | 0xac | 0xba | 0xc2 | 0xc4..=0xc6 => (true, Immediate::Byte),
+        // `mov` to and from control and debug registers, whose ModR/M always
+        // encodes registers regardless of the mod field.
+        0x20..=0x23 => (false, Immediate::Byte),
+        0x05..=0x09
+        | 0x0e
+        | 0x30..=0x35
+        | 0x37
+        | 0x77
+        | 0xa0..=0xa2
+        | 0xa8..=0xaa
+        | 0xc8..=0xcf => (false, Immediate::None),
+        0x80..=0x8f => (false, Immediate::Branch),
+        // Undefined opcodes, and `ud2` (0b), `ud1` (b9) and `ud0` (ff).
+        _ => return None,
+    };
+    Some(Operands { modrm, immediate })
+}
+
+/// Decodes the VEX or EVEX prefix starting with `prefix` and the opcode, and
+/// returns the operands.
+fn vex(reader: &mut Reader<'_>, prefix: u8) -> Option<Operands> {
+    let is_evex = prefix == 0x62;
+    let map = match prefix {
+        0xc5 => {
+            let _ = reader.byte()?;
+            1
+        }
+        0xc4 => {
+            let map = reader.byte()? & 0x1f;
+            let _ = reader.byte()?;
+            map
+        }
+        _ => {
+            let map = reader.byte()? & 0x07;
+            reader.skip(2)?;
+            map
+        }
+    };
+    let opcode = reader.byte()?;
+    // Maps 5 and 6 are of AVX512-FP16.
+    if !(matches!(map, 1..=3) || is_evex && matches!(map, 5 | 6)) {
+        return None;
+    }
+    // `vzeroupper` and `vzeroall`.
+    if map == 1 && !is_evex && opcode == 0x77 {
+        return Some(Operands::NONE);
+    }
+    let immediate = if map == 3 || (map == 1 && matches!(opcode, 0x70..=0x73 | 0xc2 | 0xc4..=0xc6))
+    {
+        Immediate::Byte
+    } else {
+        Immediate::None
+    };
+    Some(Operands {
+        modrm: true,
+        immediate,
+    })
+}
+
+/// Decodes the ModR/M byte and what follows it but the immediate, and returns
+/// the reg field.
+fn modrm(reader: &mut Reader<'_>, address_16: bool) -> Option<u8> {
+    let modrm = reader.byte()?;
+    let (mode, reg, rm) = (modrm >> 6, (modrm >> 3) & 0x07, modrm & 0x07);
+    let displacement = match (mode, rm) {
+        (0b11, _) => 0,
+        _ if address_16 => match (mode, rm) {
+            (0b00, 6) | (0b10, _) => 2,
+            (0b01, _) => 1,
+            _ => 0,
+        },
+        _ => {
+            let base = if rm == 4 { reader.byte()? & 0x07 } else { rm };
+            match (mode, base) {
+                (0b00, 5) | (0b10, _) => 4,
+                (0b01, _) => 1,
+                _ => 0,
+            }
+        }
+    };
+    reader.skip(displacement)?;
+    Some(reg)
+}
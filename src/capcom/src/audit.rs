@@ -0,0 +1,130 @@
+//! Rate limiting and counting of payload executions, and the kill switch.
+//!
+//! Executions are numbered and chained with `chain_execution`, and each is
+//! written as an ETW event and a message with the number and the chain, so
+//! that auditors can detect lost, inserted or edited events by comparing them
+//! with `IOCTL_GET_AUDIT`.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use capcom_abi::{AuditInfo, chain_execution};
+use wdk_sys::{
+    NTSTATUS, STATUS_ACCESS_DISABLED_BY_POLICY_OTHER, STATUS_QUOTA_EXCEEDED,
+    ntddk::{KeQueryUnbiasedInterruptTime, PsGetCurrentProcessId},
+};
+
+use crate::{config, context::Context, etw, ioctl::Request, sync::SpinLock, trace::trace};
+
+/// Whether `IOCTL_KILL_SWITCH` was sent.
+static KILLED: AtomicBool = AtomicBool::new(false);
+
+/// The number of payloads executed and the audit chain after the last one.
+static EXECUTIONS: SpinLock<(u64, u64)> = SpinLock::new((0, 0));
+
+/// The number of payloads refused due to the rate limit.
+static THROTTLED: AtomicU64 = AtomicU64::new(0);
+
+/// Runs `run`, which executes a payload, unless the kill switch is engaged or
+/// the handle exceeds the rate limit. Successful executions are counted,
+/// chained, and written as ETW events and messages.
+pub(crate) fn execute(
+    context: &Context,
+    run: impl FnOnce() -> Result<usize, NTSTATUS>,
+) -> Result<usize, NTSTATUS> {
+    if KILLED.load(Ordering::Acquire) {
+        return Err(STATUS_ACCESS_DISABLED_BY_POLICY_OTHER);
+    }
+    if !context
+        .bucket
+        .lock()
+        .take(config::payload_rate(), config::payload_burst())
+    {
+        let _ = THROTTLED.fetch_add(1, Ordering::Relaxed);
+        return Err(STATUS_QUOTA_EXCEEDED);
+    }
+
+    let result = run();
+    if result.is_ok() {
+        let process_id = unsafe { PsGetCurrentProcessId() }.addr() as u64;
+        let (count, chain) = {
+            let mut executions = EXECUTIONS.lock();
+            let (count, chain) = &mut *executions;
+            *count += 1;
+            *chain = chain_execution(*chain, *count, process_id);
+            (*count, *chain)
+        };
+        trace!(PAYLOAD_EXECUTED, count, process_id, chain);
+        etw::write(
+            etw::TRACE_LEVEL_INFORMATION,
+            format_args!("Execution {count} by process {process_id}, audit chain {chain:#x}"),
+        );
+    }
+    result
+}
+
+/// Handles `IOCTL_KILL_SWITCH`.
+pub(crate) fn kill() {
+    KILLED.store(true, Ordering::Release);
+    let process_id = unsafe { PsGetCurrentProcessId() }.addr();
+    trace!(PAYLOAD_EXECUTION_DISABLED, process_id);
+    etw::write(
+        etw::TRACE_LEVEL_INFORMATION,
+        format_args!("Payload execution was disabled by process {process_id}"),
+    );
+}
+
+/// Handles `IOCTL_GET_AUDIT`.
+pub(crate) fn get_audit(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let (executions, chain) = *EXECUTIONS.lock();
+    request.write_output(&AuditInfo {
+        executions,
+        throttled: THROTTLED.load(Ordering::Relaxed),
+        killed: u32::from(KILLED.load(Ordering::Acquire)),
+        reserved: 0,
+        chain,
+    })
+}
+
+/// A token bucket limiting how often a handle executes payloads. The credit is
+/// kept as time in 100ns units, and an execution costs the interval between
+/// executions at the allowed rate.
+pub(crate) struct TokenBucket {
+    /// The accumulated credit, capped at the cost of a burst.
+    credit: u64,
+    /// The interrupt time the credit was last updated at.
+    updated: u64,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub(crate) fn new() -> Self {
+        Self {
+            credit: u64::MAX,
+            updated: 0,
+        }
+    }
+
+    /// Takes a token if available, allowing `rate` executions per second and
+    /// bursts of `burst` executions. A `rate` of zero means no limit, and a
+    /// `burst` of zero bursts of `rate` executions.
+    fn take(&mut self, rate: u32, burst: u32) -> bool {
+        const TICKS_PER_SECOND: u64 = 10_000_000;
+
+        if rate == 0 {
+            return true;
+        }
+        let burst = if burst == 0 { rate } else { burst };
+        let cost = (TICKS_PER_SECOND / u64::from(rate)).max(1);
+        let now = unsafe { KeQueryUnbiasedInterruptTime() };
+        self.credit = self
+            .credit
+            .saturating_add(now - self.updated)
+            .min(cost * u64::from(burst));
+        self.updated = now;
+        if self.credit < cost {
+            return false;
+        }
+        self.credit -= cost;
+        true
+    }
+}
@@ -0,0 +1,73 @@
+//! Starting as a boot-start or system-start driver, e.g., for studying what
+//! early-boot telemetry records of the driver and whether it loads before
+//! security products do.
+//!
+//! `DriverEntry` of such drivers runs while Windows is still initializing, and
+//! creating the symbolic links to the devices may fail. The driver then keeps
+//! its devices, and creates the links and registers the device interface in a
+//! reinitialization routine instead. The routine runs once all boot-start
+//! drivers have started, or once the system-start drivers have loaded, and is
+//! registered again until the links are created or [`MAX_ATTEMPTS`] is
+//! reached.
+
+use wdk_sys::{
+    NTSTATUS, PDEVICE_OBJECT, PDRIVER_OBJECT, PVOID, SERVICE_BOOT_START, SERVICE_SYSTEM_START,
+    ULONG,
+    ntddk::{
+        IoRegisterBootDriverReinitialization, IoRegisterDriverReinitialization,
+        KeQueryUnbiasedInterruptTime,
+    },
+};
+
+use crate::{config, create_links, interface, trace::trace};
+
+/// The number of times the reinitialization routine tries to create the links.
+const MAX_ATTEMPTS: u32 = 8;
+
+/// Logs the start type of the service and how long after boot the driver
+/// started.
+pub(crate) fn log_phase() {
+    trace!(BOOT_PHASE, config::start_type(), uptime_ms());
+}
+
+/// Returns whether the driver started while Windows was booting, as a
+/// boot-start or system-start driver.
+pub(crate) fn is_early() -> bool {
+    config::start_type() <= SERVICE_SYSTEM_START
+}
+
+/// Creates the symbolic links and registers the device interface for the
+/// compatible device `device` later, as creating the links failed with
+/// `status`.
+pub(crate) unsafe fn defer(driver: PDRIVER_OBJECT, device: PDEVICE_OBJECT, status: NTSTATUS) {
+    trace!(LINKS_DEFERRED, status);
+    unsafe {
+        if config::start_type() == SERVICE_BOOT_START {
+            IoRegisterBootDriverReinitialization(driver, Some(reinitialize), device.cast());
+        } else {
+            IoRegisterDriverReinitialization(driver, Some(reinitialize), device.cast());
+        }
+    }
+}
+
+/// Creates the deferred links, with `context` being the compatible device and
+/// `count` the number of calls so far including this one.
+extern "C" fn reinitialize(driver: PDRIVER_OBJECT, context: PVOID, count: ULONG) {
+    match create_links() {
+        Ok(()) => {
+            trace!(LINKS_CREATED, count, uptime_ms());
+            unsafe { interface::register(driver, context.cast()) };
+        }
+        Err(_) if count < MAX_ATTEMPTS => unsafe {
+            IoRegisterDriverReinitialization(driver, Some(reinitialize), context);
+        },
+        // The devices remain, and can be opened by their names.
+        Err(status) => trace!(LINK_CREATION_FAILED, status),
+    }
+}
+
+/// Returns the time since boot in milliseconds, excluding sleep.
+fn uptime_ms() -> u64 {
+    let interrupt_time = unsafe { KeQueryUnbiasedInterruptTime() };
+    interrupt_time / 10_000
+}
@@ -0,0 +1,325 @@
+//! Driver-wide settings loaded from the service key when the driver starts,
+//! and from the config `IOCTL_SAVE_CONFIG` saved in its `Parameters` subkey.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use capcom_abi::{
+    CLASS_ALL, CONFIG_ALL, CONFIG_DEBUG_BREAK, CONFIG_DEVICE_INTERFACE, CONFIG_ENABLED_CLASSES,
+    CONFIG_PAYLOAD_BURST, CONFIG_PAYLOAD_RATE, CONFIG_PAYLOAD_STACK_SIZE, CONFIG_PRINT_LEVEL_MASK,
+    CONFIG_STEALTH_SEED, DEBUG_BREAK_ON_LOAD, DEBUG_BREAK_ON_PANIC_ONLY, DebugBreakRequest,
+    PRINT_LEVEL_MASK_ALL, PrintFilterRequest, SAVED_CONFIG_VERSION, SavedConfig,
+};
+use wdk_sys::{
+    NTSTATUS, PCUNICODE_STRING, REG_BINARY, SERVICE_DEMAND_START, STATUS_INVALID_PARAMETER,
+    STATUS_NOT_SUPPORTED, UNICODE_STRING,
+};
+
+use crate::{RTL_CONSTANT_STRING, ioctl::Request, registry, sync::SpinLock, trace::trace};
+
+/// The path of the service key the driver was started with.
+static SERVICE_KEY: SpinLock<ServiceKey> = SpinLock::new(ServiceKey {
+    buffer: [0; 256],
+    length: 0,
+});
+
+/// `CLASS_*` flags that can be granted to handles.
+static ENABLED_CLASSES: AtomicU32 = AtomicU32::new(CLASS_ALL);
+
+/// The number of payloads a handle can execute per second, or zero for no
+/// limit.
+static PAYLOAD_RATE: AtomicU32 = AtomicU32::new(0);
+
+/// The number of payloads a handle can execute in a burst before being limited
+/// to [`PAYLOAD_RATE`], or zero for as many as [`PAYLOAD_RATE`].
+static PAYLOAD_BURST: AtomicU32 = AtomicU32::new(0);
+
+/// The size in bytes of the stack payloads run on, or zero to run them on the
+/// stack of the calling thread as the original driver does.
+static PAYLOAD_STACK_SIZE: AtomicU32 = AtomicU32::new(0);
+
+/// The maximum of [`PAYLOAD_STACK_SIZE`].
+const MAX_PAYLOAD_STACK_SIZE: u32 = 1024 * 1024;
+
+/// The `DEBUG_BREAK_*` mode deciding when to break into a kernel debugger.
+static DEBUG_BREAK: AtomicU32 = AtomicU32::new(DEBUG_BREAK_ON_LOAD);
+
+/// Whether the device interface of the compatible device is registered.
+static DEVICE_INTERFACE: AtomicBool = AtomicBool::new(false);
+
+/// The seed of the names of the devices, or zero to use those of the original
+/// driver.
+static STEALTH_SEED: AtomicU32 = AtomicU32::new(0);
+
+/// The `Start` value of the service key, e.g., `SERVICE_BOOT_START`.
+static START_TYPE: AtomicU32 = AtomicU32::new(SERVICE_DEMAND_START);
+
+/// The `messages::LEVEL_*` levels of the messages printed to the kernel
+/// debugger, with bit `n` for level `n`.
+static PRINT_LEVEL_MASK: AtomicU32 = AtomicU32::new(PRINT_LEVEL_MASK_ALL);
+
+/// Loads the settings from the service key at `registry_path`. Settings
+/// without a value keep the defaults.
+pub(crate) fn load(registry_path: PCUNICODE_STRING) {
+    let path = unsafe {
+        core::slice::from_raw_parts(
+            (*registry_path).Buffer,
+            usize::from((*registry_path).Length) / 2,
+        )
+    };
+    let mut service_key = SERVICE_KEY.lock();
+    if let Some(buffer) = service_key.buffer.get_mut(..path.len()) {
+        buffer.copy_from_slice(path);
+        service_key.length = path.len();
+    }
+    drop(service_key);
+
+    if let Some(start_type) = registry::read_dword(registry_path, &utf16_lit::utf16!("Start")) {
+        START_TYPE.store(start_type, Ordering::Relaxed);
+    }
+
+    // Values of the saved config take precedence over those of the service
+    // key.
+    let saved = read_saved_config().unwrap_or_default();
+    let value = |name: &[u16], field: u32, saved_value: u32| {
+        if saved.fields & field == 0 {
+            registry::read_dword(registry_path, name)
+        } else {
+            Some(saved_value)
+        }
+    };
+
+    if let Some(classes) = value(
+        &utf16_lit::utf16!("EnabledClasses"),
+        CONFIG_ENABLED_CLASSES,
+        saved.enabled_classes,
+    ) {
+        trace!(ENABLED_CLASSES, classes);
+        ENABLED_CLASSES.store(classes & CLASS_ALL, Ordering::Relaxed);
+    }
+    if let Some(rate) = value(
+        &utf16_lit::utf16!("PayloadRate"),
+        CONFIG_PAYLOAD_RATE,
+        saved.payload_rate,
+    ) {
+        trace!(PAYLOAD_RATE, rate);
+        PAYLOAD_RATE.store(rate, Ordering::Relaxed);
+    }
+    if let Some(burst) = value(
+        &utf16_lit::utf16!("PayloadBurst"),
+        CONFIG_PAYLOAD_BURST,
+        saved.payload_burst,
+    ) {
+        trace!(PAYLOAD_BURST, burst);
+        PAYLOAD_BURST.store(burst, Ordering::Relaxed);
+    }
+    if let Some(size) = value(
+        &utf16_lit::utf16!("PayloadStackSize"),
+        CONFIG_PAYLOAD_STACK_SIZE,
+        saved.payload_stack_size,
+    ) {
+        let size = size.min(MAX_PAYLOAD_STACK_SIZE);
+        trace!(PAYLOAD_STACK_SIZE, size);
+        PAYLOAD_STACK_SIZE.store(size, Ordering::Relaxed);
+    }
+    if let Some(mode) = value(
+        &utf16_lit::utf16!("DebugBreak"),
+        CONFIG_DEBUG_BREAK,
+        saved.debug_break,
+    ) && mode <= DEBUG_BREAK_ON_PANIC_ONLY
+    {
+        trace!(DEBUG_BREAK_MODE, mode);
+        DEBUG_BREAK.store(mode, Ordering::Relaxed);
+    }
+    if let Some(mask) = value(
+        &utf16_lit::utf16!("PrintLevelMask"),
+        CONFIG_PRINT_LEVEL_MASK,
+        saved.print_level_mask,
+    ) {
+        let mask = mask & PRINT_LEVEL_MASK_ALL;
+        trace!(PRINT_LEVEL_MASK, mask);
+        PRINT_LEVEL_MASK.store(mask, Ordering::Relaxed);
+    }
+    if let Some(enabled) = value(
+        &utf16_lit::utf16!("DeviceInterface"),
+        CONFIG_DEVICE_INTERFACE,
+        saved.device_interface,
+    ) {
+        trace!(DEVICE_INTERFACE, enabled);
+        DEVICE_INTERFACE.store(enabled != 0, Ordering::Relaxed);
+    }
+    if let Some(seed) = value(
+        &utf16_lit::utf16!("StealthSeed"),
+        CONFIG_STEALTH_SEED,
+        saved.stealth_seed,
+    ) {
+        // Only whether it is enabled, as the seed reveals the names.
+        trace!(STEALTH_NAMING, u32::from(seed != 0));
+        STEALTH_SEED.store(seed, Ordering::Relaxed);
+    }
+}
+
+/// Reads the config `IOCTL_SAVE_CONFIG` saved. Returns `None` if none is
+/// saved or it is invalid.
+fn read_saved_config() -> Option<SavedConfig> {
+    let key_path = parameters_key()?.as_unicode_string();
+    let value = registry::read_value(&raw const key_path, &utf16_lit::utf16!("Config")).ok()?;
+    let data = value.data();
+    let config = (data.len() == size_of::<SavedConfig>())
+        .then(|| unsafe { data.as_ptr().cast::<SavedConfig>().read_unaligned() });
+    match config {
+        Some(config)
+            if value.value_type() == REG_BINARY && config.version == SAVED_CONFIG_VERSION =>
+        {
+            trace!(SAVED_CONFIG_LOADED, config.fields);
+            Some(config)
+        }
+        _ => {
+            trace!(
+                SAVED_CONFIG_INVALID,
+                config.map_or(0, |config| config.version),
+                data.len()
+            );
+            None
+        }
+    }
+}
+
+/// Returns `CLASS_*` flags that can be granted to handles.
+pub(crate) fn enabled_classes() -> u32 {
+    ENABLED_CLASSES.load(Ordering::Relaxed)
+}
+
+/// Returns the number of payloads a handle can execute per second, or zero for
+/// no limit.
+pub(crate) fn payload_rate() -> u32 {
+    PAYLOAD_RATE.load(Ordering::Relaxed)
+}
+
+/// Returns the number of payloads a handle can execute in a burst, or zero for
+/// as many as it can per second.
+pub(crate) fn payload_burst() -> u32 {
+    PAYLOAD_BURST.load(Ordering::Relaxed)
+}
+
+/// Returns the size in bytes of the stack payloads run on, or zero to run them
+/// on the stack of the calling thread.
+pub(crate) fn payload_stack_size() -> usize {
+    PAYLOAD_STACK_SIZE.load(Ordering::Relaxed) as usize
+}
+
+/// Returns the `DEBUG_BREAK_*` mode.
+pub(crate) fn debug_break() -> u32 {
+    DEBUG_BREAK.load(Ordering::Relaxed)
+}
+
+/// Returns the start type of the service, e.g., `SERVICE_BOOT_START`.
+pub(crate) fn start_type() -> u32 {
+    START_TYPE.load(Ordering::Relaxed)
+}
+
+/// Returns the mask of the `messages::LEVEL_*` levels of the messages printed
+/// to the kernel debugger.
+pub(crate) fn print_level_mask() -> u32 {
+    PRINT_LEVEL_MASK.load(Ordering::Relaxed)
+}
+
+/// Returns whether the device interface of the compatible device is
+/// registered. It always is with stealth naming, as the names are unknown to
+/// those without the seed.
+pub(crate) fn device_interface() -> bool {
+    DEVICE_INTERFACE.load(Ordering::Relaxed) || stealth_seed() != 0
+}
+
+/// Returns the seed of the names of the devices, or zero to use those of the
+/// original driver, which the `strict-compat` feature always does.
+pub(crate) fn stealth_seed() -> u32 {
+    if cfg!(feature = "strict-compat") {
+        0
+    } else {
+        STEALTH_SEED.load(Ordering::Relaxed)
+    }
+}
+
+/// Handles `IOCTL_SET_DEBUG_BREAK`.
+pub(crate) fn set_debug_break(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<DebugBreakRequest>()?;
+    if input.mode > DEBUG_BREAK_ON_PANIC_ONLY {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    trace!(DEBUG_BREAK_MODE, input.mode);
+    DEBUG_BREAK.store(input.mode, Ordering::Relaxed);
+    Ok(0)
+}
+
+/// Handles `IOCTL_SET_PRINT_FILTER`.
+pub(crate) fn set_print_filter(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<PrintFilterRequest>()?;
+    let mask = input.level_mask & PRINT_LEVEL_MASK_ALL;
+    // Logged before applying, so that silencing the level is visible.
+    trace!(PRINT_LEVEL_MASK, mask);
+    PRINT_LEVEL_MASK.store(mask, Ordering::Relaxed);
+    Ok(0)
+}
+
+/// Handles `IOCTL_SAVE_CONFIG`.
+pub(crate) fn save_config(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<SavedConfig>()?;
+    if input.version != SAVED_CONFIG_VERSION || input.fields & !CONFIG_ALL != 0 {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    let Some(parameters_key) = parameters_key() else {
+        return Err(STATUS_NOT_SUPPORTED);
+    };
+    let key_path = parameters_key.as_unicode_string();
+    registry::create_key(&raw const key_path)?;
+    let data = unsafe {
+        core::slice::from_raw_parts((&raw const input).cast::<u8>(), size_of::<SavedConfig>())
+    };
+    registry::write_value(
+        &raw const key_path,
+        &utf16_lit::utf16!("Config"),
+        REG_BINARY,
+        data,
+    )?;
+    trace!(CONFIG_SAVED, input.fields);
+    Ok(0)
+}
+
+/// Returns the path of the service key, or `None` if it was too long to keep.
+pub(crate) fn service_key() -> Option<ServiceKey> {
+    let service_key = *SERVICE_KEY.lock();
+    (service_key.length != 0).then_some(service_key)
+}
+
+/// Returns the path of the `Parameters` subkey of the service key, where
+/// `IOCTL_SAVE_CONFIG` saves the config, or `None` if too long to keep.
+pub(crate) fn parameters_key() -> Option<ServiceKey> {
+    service_key()?.subkey(&utf16_lit::utf16!("Parameters"))
+}
+
+/// A copy of the path of the service key, e.g.,
+/// `\REGISTRY\MACHINE\SYSTEM\ControlSet001\Services\capcom`.
+#[derive(Clone, Copy)]
+pub(crate) struct ServiceKey {
+    buffer: [u16; 256],
+    length: usize,
+}
+
+impl ServiceKey {
+    /// Returns the path as `UNICODE_STRING` referring to `self`.
+    pub(crate) fn as_unicode_string(&self) -> UNICODE_STRING {
+        RTL_CONSTANT_STRING(&self.buffer[..self.length])
+    }
+
+    /// Returns the path of the subkey `name`, or `None` if too long to keep.
+    fn subkey(&self, name: &[u16]) -> Option<Self> {
+        let mut subkey = *self;
+        let buffer = subkey
+            .buffer
+            .get_mut(self.length..self.length + 1 + name.len())?;
+        buffer[0] = u16::from(b'\\');
+        buffer[1..].copy_from_slice(name);
+        subkey.length += 1 + name.len();
+        Some(subkey)
+    }
+}
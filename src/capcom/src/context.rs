@@ -0,0 +1,252 @@
+//! The per-handle context stored in `FILE_OBJECT::FsContext`.
+
+use core::{
+    mem, ptr,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use capcom_abi::PayloadTranscript;
+use wdk_sys::{
+    HANDLE, NTSTATUS, PFILE_OBJECT, POOL_FLAG_NON_PAGED, PVOID, STATUS_ACCESS_DENIED,
+    STATUS_DELETE_PENDING, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_DEVICE_REQUEST,
+    STATUS_INVALID_DEVICE_STATE, STATUS_NOT_FOUND, ntddk::PsGetCurrentProcessId,
+};
+
+use crate::{
+    audit::TokenBucket,
+    config,
+    coverage::cover,
+    pool::{self, Tag},
+    ring,
+    shared::SharedMemory,
+    sync::SpinLock,
+    trace::trace,
+};
+
+/// Set in [`Context::grant`] once the handle negotiated.
+const NEGOTIATED: u32 = 1 << 31;
+
+/// The state of a handle to the device.
+pub(crate) struct Context {
+    /// The process that opened the handle.
+    pub(crate) process_id: HANDLE,
+    /// Resources the handle owns, released when the handle is closed.
+    resources: SpinLock<*mut Resource>,
+    /// The memory shared with the process with `IOCTL_MAP_SHARED`.
+    shared_memory: SpinLock<Option<SharedMemory>>,
+    /// Whether the last handle was closed and `IRP_MJ_CLEANUP` was handled.
+    cleaned_up: AtomicBool,
+    /// `CLASS_*` flags granted with `IOCTL_NEGOTIATE`, and [`NEGOTIATED`].
+    grant: AtomicU32,
+    /// The rate limit of payload execution.
+    pub(crate) bucket: SpinLock<TokenBucket>,
+    /// The transcript of the shellcode written to the handle, returned by the
+    /// next read.
+    pub(crate) transcript: SpinLock<Option<PayloadTranscript>>,
+}
+
+/// A resource owned by a handle, e.g., a memory allocation.
+struct Resource {
+    next: *mut Resource,
+    object: PVOID,
+    release: unsafe fn(PVOID),
+}
+
+impl Context {
+    /// Allocates the context for the handle being opened with `file`.
+    pub(crate) unsafe fn create(file: PFILE_OBJECT) -> Result<(), NTSTATUS> {
+        let context = allocate(Self {
+            process_id: unsafe { PsGetCurrentProcessId() },
+            resources: SpinLock::new(ptr::null_mut()),
+            shared_memory: SpinLock::new(None),
+            cleaned_up: AtomicBool::new(false),
+            grant: AtomicU32::new(0),
+            bucket: SpinLock::new(TokenBucket::new()),
+            transcript: SpinLock::new(None),
+        })?;
+        unsafe { (*file).FsContext = context.cast() };
+        Ok(())
+    }
+
+    /// Returns the context of the handle opened with `file`.
+    pub(crate) unsafe fn get<'a>(file: PFILE_OBJECT) -> Option<&'a Self> {
+        unsafe { (*file).FsContext.cast::<Self>().as_ref() }
+    }
+
+    /// Grants `classes` to the handle. A handle can negotiate only once.
+    pub(crate) fn negotiate(&self, classes: u32) -> Result<(), NTSTATUS> {
+        self.grant
+            .compare_exchange(0, classes | NEGOTIATED, Ordering::AcqRel, Ordering::Acquire)
+            .map(|_| ())
+            .map_err(|_| STATUS_INVALID_DEVICE_REQUEST)
+    }
+
+    /// Checks whether the handle may use IOCTLs of `class`, which may be zero
+    /// for IOCTLs without a class. A handle that did not negotiate may only use
+    /// IOCTLs of the original driver (`original`), as long as `class` is
+    /// enabled.
+    pub(crate) fn check_access(&self, class: u32, original: bool) -> Result<(), NTSTATUS> {
+        let grant = self.grant.load(Ordering::Acquire);
+        let granted = if grant & NEGOTIATED != 0 {
+            grant
+        } else if original {
+            cover!();
+            config::enabled_classes()
+        } else {
+            cover!();
+            return Err(STATUS_ACCESS_DENIED);
+        };
+        if granted & class == class {
+            Ok(())
+        } else {
+            cover!(class);
+            Err(STATUS_ACCESS_DENIED)
+        }
+    }
+
+    /// Handles `IRP_MJ_CLEANUP`, sent when the last handle is closed. It runs
+    /// in the context of the process closing the handle, so resources tied to
+    /// the process, such as memory mapped into it, are released here rather
+    /// than on close. Requests for the handle may still be in progress and
+    /// fail with `STATUS_DELETE_PENDING` from now on.
+    pub(crate) fn cleanup(&self) {
+        self.cleaned_up.store(true, Ordering::Release);
+        self.release_resources();
+        // Detach the ring while holding the lock, so that it is not attached
+        // to the shared memory being unmapped. Unmap it outside the lock, at
+        // `PASSIVE_LEVEL`.
+        let shared_memory = {
+            let mut shared_memory = self.shared_memory.lock();
+            ring::detach(self);
+            shared_memory.take()
+        };
+        drop(shared_memory);
+    }
+
+    /// Checks whether [`Context::cleanup`] was called.
+    pub(crate) fn is_cleaned_up(&self) -> bool {
+        self.cleaned_up.load(Ordering::Acquire)
+    }
+
+    /// Releases the resources and frees the context of the handle opened with
+    /// `file`. No other request may be in progress for the handle.
+    pub(crate) unsafe fn destroy(file: PFILE_OBJECT) {
+        unsafe {
+            let context = (*file).FsContext.cast::<Self>();
+            if context.is_null() {
+                return;
+            }
+            (*file).FsContext = ptr::null_mut();
+            (*context).release_resources();
+            pool::free(context.cast(), Tag::Context);
+        }
+    }
+
+    /// Makes the handle own `object`. `release` is called with `object` when
+    /// the handle is closed. Fails if the handle is already being closed.
+    pub(crate) fn add_resource(
+        &self,
+        object: PVOID,
+        release: unsafe fn(PVOID),
+    ) -> Result<(), NTSTATUS> {
+        let mut resources = self.resources.lock();
+        if self.is_cleaned_up() {
+            return Err(STATUS_DELETE_PENDING);
+        }
+        let resource = allocate(Resource {
+            next: *resources,
+            object,
+            release,
+        })?;
+        *resources = resource;
+        Ok(())
+    }
+
+    /// Releases `object` before the handle is closed. Fails if the handle does
+    /// not own `object`.
+    pub(crate) fn release_resource(&self, object: PVOID) -> Result<(), NTSTATUS> {
+        let resource = {
+            let mut resources = self.resources.lock();
+            let mut link: *mut *mut Resource = &raw mut *resources;
+            loop {
+                let resource = unsafe { *link };
+                if resource.is_null() {
+                    return Err(STATUS_NOT_FOUND);
+                }
+                if unsafe { (*resource).object } == object {
+                    unsafe { *link = (*resource).next };
+                    break resource;
+                }
+                link = unsafe { &raw mut (*resource).next };
+            }
+        };
+        unsafe {
+            ((*resource).release)((*resource).object);
+            pool::free(resource.cast(), Tag::Context);
+        }
+        Ok(())
+    }
+
+    /// Makes the handle own `shared`. Fails if the handle already owns shared
+    /// memory or is being closed.
+    pub(crate) fn set_shared_memory(&self, shared: SharedMemory) -> Result<(), NTSTATUS> {
+        let mut slot = self.shared_memory.lock();
+        let status = if self.is_cleaned_up() {
+            STATUS_DELETE_PENDING
+        } else if slot.is_some() {
+            STATUS_INVALID_DEVICE_STATE
+        } else {
+            *slot = Some(shared);
+            return Ok(());
+        };
+        // Unmap the rejected memory outside the lock, at `PASSIVE_LEVEL`.
+        drop(slot);
+        drop(shared);
+        Err(status)
+    }
+
+    /// Runs `f` with the shared memory of the handle, which stays mapped
+    /// while `f` runs. Fails if the handle does not own shared memory or is
+    /// being closed.
+    pub(crate) fn with_shared_memory<T>(
+        &self,
+        f: impl FnOnce(&SharedMemory) -> T,
+    ) -> Result<T, NTSTATUS> {
+        let shared_memory = self.shared_memory.lock();
+        if self.is_cleaned_up() {
+            return Err(STATUS_DELETE_PENDING);
+        }
+        shared_memory
+            .as_ref()
+            .map(f)
+            .ok_or(STATUS_INVALID_DEVICE_STATE)
+    }
+
+    /// Releases all resources the handle owns.
+    fn release_resources(&self) {
+        let mut resource = mem::replace(&mut *self.resources.lock(), ptr::null_mut());
+        let mut count = 0;
+        while !resource.is_null() {
+            unsafe {
+                let next = (*resource).next;
+                ((*resource).release)((*resource).object);
+                pool::free(resource.cast(), Tag::Context);
+                resource = next;
+            }
+            count += 1;
+        }
+        if count != 0 {
+            trace!(RESOURCES_RELEASED, count, self.process_id.addr());
+        }
+    }
+}
+
+/// Moves `value` into non-paged pool.
+fn allocate<T>(value: T) -> Result<*mut T, NTSTATUS> {
+    let memory = pool::allocate(POOL_FLAG_NON_PAGED, size_of::<T>(), Tag::Context).cast::<T>();
+    if memory.is_null() {
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    unsafe { memory.write(value) };
+    Ok(memory)
+}
@@ -0,0 +1,75 @@
+//! The control device, for administering the driver from a privileged console
+//! while the Capcom-compatible device is used by callers with any privilege.
+//! Only elevated administrators can open it, and it accepts
+//! `CONTROL_IOCTLS` without negotiation.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use capcom_abi::{
+    IOCTL_GET_AUDIT, IOCTL_GET_VERSION, IOCTL_KILL_SWITCH, IOCTL_QUERY_ALLOCATIONS, IOCTL_READ_LOG,
+    IOCTL_SAVE_CONFIG, IOCTL_SELF_DESTRUCT, IOCTL_SELF_TEST, IOCTL_SET_DEBUG_BREAK,
+    IOCTL_SET_PRINT_FILTER,
+};
+use wdk_sys::{
+    DEVICE_OBJECT, NTSTATUS, PDEVICE_OBJECT, PDRIVER_OBJECT, STATUS_ACCESS_DENIED,
+    STATUS_INVALID_DEVICE_REQUEST, ULONG, ntddk::IoGetCurrentProcess,
+};
+
+use crate::{
+    audit, config, create_device,
+    ioctl::{self, Request},
+    log, names, pool, self_destruct, self_test, shutdown,
+};
+
+/// The control device, or null if not created.
+static DEVICE: AtomicPtr<DEVICE_OBJECT> = AtomicPtr::new(ptr::null_mut());
+
+/// Creates the control device, which also receives `IRP_MJ_SHUTDOWN`. The
+/// symbolic link to it is created with that of the compatible device.
+pub(crate) unsafe fn create(driver: PDRIVER_OBJECT) -> Result<(), NTSTATUS> {
+    let device = unsafe { create_device(driver, names::device(true).as_slice()) }?;
+    unsafe { shutdown::register(device) };
+    DEVICE.store(device, Ordering::Release);
+    Ok(())
+}
+
+/// Checks whether `device` is the control device.
+pub(crate) fn is_control_device(device: PDEVICE_OBJECT) -> bool {
+    device == DEVICE.load(Ordering::Acquire)
+}
+
+/// Checks whether the current process may open the control device.
+pub(crate) fn check_caller() -> Result<(), NTSTATUS> {
+    if unsafe { log::is_elevated(IoGetCurrentProcess()) } {
+        Ok(())
+    } else {
+        Err(STATUS_ACCESS_DENIED)
+    }
+}
+
+/// Handles the IOCTL request sent to the control device.
+pub(crate) fn dispatch(
+    device: PDEVICE_OBJECT,
+    control_code: ULONG,
+    request: &mut Request,
+) -> Result<usize, NTSTATUS> {
+    match control_code {
+        IOCTL_GET_VERSION => ioctl::get_version(request),
+        IOCTL_READ_LOG => log::read(request),
+        IOCTL_GET_AUDIT => audit::get_audit(request),
+        IOCTL_KILL_SWITCH => {
+            audit::kill();
+            Ok(0)
+        }
+        IOCTL_SET_DEBUG_BREAK => config::set_debug_break(request),
+        IOCTL_SET_PRINT_FILTER => config::set_print_filter(request),
+        IOCTL_SAVE_CONFIG => config::save_config(request),
+        IOCTL_SELF_DESTRUCT => self_destruct::self_destruct(device, request),
+        IOCTL_QUERY_ALLOCATIONS => pool::query_allocations(request),
+        IOCTL_SELF_TEST => self_test::self_test(request),
+        _ => Err(STATUS_INVALID_DEVICE_REQUEST),
+    }
+}
@@ -0,0 +1,95 @@
+//! `IOCTL_CORRELATED`, tagging the messages and ETW events written while
+//! dispatching an IOCTL with the correlation ID the client generated for it,
+//! so that the logs of the client, the driver and the host can be joined.
+//!
+//! The ID is kept for the dispatching thread in a small table instead of being
+//! passed down to every handler. Messages written on other threads, e.g., by
+//! DPCs and NMI callbacks, are not tagged, and neither are those of requests
+//! that find the table full.
+
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use capcom_abi::{CorrelationHeader, IOCTL_CORRELATED, METHOD_BUFFERED};
+use wdk_sys::{NTSTATUS, PDEVICE_OBJECT, STATUS_INVALID_PARAMETER, ntddk::PsGetCurrentThreadId};
+
+use crate::{
+    context::Context,
+    coverage::cover,
+    ioctl::{self, Request},
+};
+
+/// The number of requests that can be tagged at once.
+const SLOTS: usize = 64;
+
+/// The IDs of the threads dispatching tagged requests, or zero for free slots.
+static THREADS: [AtomicUsize; SLOTS] = [const { AtomicUsize::new(0) }; SLOTS];
+
+/// The correlation IDs of the requests of [`THREADS`].
+static IDS: [AtomicU64; SLOTS] = [const { AtomicU64::new(0) }; SLOTS];
+
+/// Handles `IOCTL_CORRELATED`, dispatching the IOCTL of the header with the
+/// rest of the input while the correlation ID is current.
+pub(crate) fn correlated(
+    device: PDEVICE_OBJECT,
+    context: &Context,
+    request: &mut Request,
+) -> Result<usize, NTSTATUS> {
+    let header = request.read_input::<CorrelationHeader>()?;
+    if header.reserved != 0
+        || header.control_code == IOCTL_CORRELATED
+        || header.control_code & 3 != METHOD_BUFFERED
+    {
+        cover!();
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    request.skip_input(size_of::<CorrelationHeader>())?;
+
+    let _scope = Scope::enter(header.correlation_id);
+    ioctl::dispatch(device, context, header.control_code, request)
+}
+
+/// Returns the correlation ID of the request the current thread dispatches,
+/// if tagged.
+pub(crate) fn current() -> Option<u64> {
+    let thread = current_thread();
+    THREADS
+        .iter()
+        .position(|owner| owner.load(Ordering::Acquire) == thread)
+        .map(|index| IDS[index].load(Ordering::Relaxed))
+}
+
+/// The slot of the current thread while it dispatches a tagged request.
+struct Scope(Option<usize>);
+
+impl Scope {
+    /// Makes `id` current for the current thread, unless zero or the table is
+    /// full.
+    fn enter(id: u64) -> Self {
+        if id == 0 {
+            return Self(None);
+        }
+        let thread = current_thread();
+        let index = THREADS.iter().position(|owner| {
+            owner
+                .compare_exchange(0, thread, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+        });
+        if let Some(index) = index {
+            IDS[index].store(id, Ordering::Relaxed);
+        }
+        Self(index)
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        if let Some(index) = self.0 {
+            THREADS[index].store(0, Ordering::Release);
+        }
+    }
+}
+
+/// Returns the ID of the current thread, which is never zero.
+fn current_thread() -> usize {
+    unsafe { PsGetCurrentThreadId() }.addr()
+}
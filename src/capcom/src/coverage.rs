@@ -0,0 +1,86 @@
+//! `IOCTL_GET_COVERAGE`, counters of the branches the dispatch and validation
+//! paths take, so that a fuzzer can be guided by coverage instead of sending
+//! inputs blindly. They are only built with the `coverage` feature. Otherwise,
+//! [`cover!`] expands to nothing and the IOCTL fails.
+//!
+//! [`cover!`] marks a branch by incrementing the counter indexed by the hash of
+//! its file and line, computed at compile time, or of them and a value, such as
+//! the IOCTL code, to count the cases of one branch separately. Branches whose
+//! hashes collide share a counter, like the coverage map of AFL.
+
+#[cfg(feature = "coverage")]
+use core::sync::atomic::{AtomicU32, Ordering};
+
+#[cfg(feature = "coverage")]
+use capcom_abi::{COVERAGE_COUNTERS, CoverageRequest};
+#[cfg(feature = "coverage")]
+use wdk_sys::NTSTATUS;
+
+#[cfg(feature = "coverage")]
+use crate::ioctl::Request;
+
+/// Counts that the branch was taken, or with a `u32` value, that it was taken
+/// with the value.
+macro_rules! cover {
+    () => {{
+        #[cfg(feature = "coverage")]
+        {
+            const INDEX: usize = $crate::coverage::index(file!(), line!(), 0);
+            $crate::coverage::hit(INDEX);
+        }
+    }};
+    ($value:expr) => {{
+        #[cfg(feature = "coverage")]
+        $crate::coverage::hit($crate::coverage::index(file!(), line!(), $value));
+    }};
+}
+pub(crate) use cover;
+
+#[cfg(feature = "coverage")]
+static COUNTERS: [AtomicU32; COVERAGE_COUNTERS] = [const { AtomicU32::new(0) }; COVERAGE_COUNTERS];
+
+/// Returns the index of the counter of the branch at `line` of `file` taken
+/// with `value`, with 32-bit FNV-1a.
+#[cfg(feature = "coverage")]
+#[expect(clippy::cast_lossless)]
+pub(crate) const fn index(file: &str, line: u32, value: u32) -> usize {
+    const PRIME: u32 = 0x0100_0193;
+
+    let mut hash: u32 = 0x811c_9dc5;
+    let bytes = file.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u32).wrapping_mul(PRIME);
+        i += 1;
+    }
+    hash = (hash ^ line).wrapping_mul(PRIME);
+    hash = (hash ^ value).wrapping_mul(PRIME);
+    hash as usize % COVERAGE_COUNTERS
+}
+
+/// Increments the counter at `index`.
+#[cfg(feature = "coverage")]
+pub(crate) fn hit(index: usize) {
+    let _ = COUNTERS[index].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Handles `IOCTL_GET_COVERAGE`. Counters that do not fit into the output
+/// buffer are still reset if requested.
+#[cfg(feature = "coverage")]
+pub(crate) fn get_coverage(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let reset = request
+        .read_input::<CoverageRequest>()
+        .is_ok_and(|input| input.reset != 0);
+    let mut offset = 0;
+    for counter in &COUNTERS {
+        let count = if reset {
+            counter.swap(0, Ordering::Relaxed)
+        } else {
+            counter.load(Ordering::Relaxed)
+        };
+        if let Ok(length) = request.write_output_at(offset, &count) {
+            offset += length;
+        }
+    }
+    Ok(offset)
+}
@@ -0,0 +1,175 @@
+//! `IOCTL_DUMP_PHYSICAL_RANGE`, dumping physical memory in chunks, and
+//! `IOCTL_READ_MEMORY`, reading virtual memory.
+//!
+//! Only RAM reported by `MmGetPhysicalMemoryRanges` is read, as reading device
+//! memory can have side effects or hang the system. Each chunk is contiguous
+//! within one range of RAM, and the client resumes with the cursor of the
+//! previous chunk, so it never needs to know where the holes are.
+//!
+//! Both read with `MmCopyMemory`, or its fallback on builds without it, which
+//! fails on pages that are not mapped instead of raising a page fault the
+//! driver could not handle.
+
+use core::{ptr, slice};
+
+use capcom_abi::{MemoryReadRequest, PhysicalDumpChunk, PhysicalDumpRequest};
+use wdk_sys::{
+    MM_COPY_ADDRESS, MM_COPY_MEMORY_PHYSICAL, MM_COPY_MEMORY_VIRTUAL, NT_SUCCESS, NTSTATUS,
+    PAGE_SIZE, PPHYSICAL_MEMORY_RANGE, STATUS_ACCESS_VIOLATION, STATUS_BUFFER_TOO_SMALL,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER, ntddk::ExFreePoolWithTag,
+};
+
+use crate::{imports::MmGetPhysicalMemoryRanges, ioctl::Request, routines};
+
+/// Handles `IOCTL_DUMP_PHYSICAL_RANGE`.
+pub(crate) fn dump_physical_range(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<PhysicalDumpRequest>()?;
+    let Some(end) = input.address.checked_add(input.size) else {
+        return Err(STATUS_INVALID_PARAMETER);
+    };
+    if input.size == 0 || input.cursor > input.size {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    let capacity = request
+        .output_mut()
+        .len()
+        .saturating_sub(size_of::<PhysicalDumpChunk>());
+    if capacity == 0 {
+        return Err(STATUS_BUFFER_TOO_SMALL);
+    }
+
+    let position = input.address + input.cursor;
+    let ranges = MemoryRanges::get()?;
+    let Some((start, range_end)) = ranges
+        .iter()
+        .filter(|&(start, range_end)| range_end > position && start < end)
+        .min_by_key(|&(start, _)| start)
+    else {
+        // No RAM is left in the range.
+        return request.write_output(&PhysicalDumpChunk {
+            address: end,
+            size: 0,
+            cursor: input.size,
+        });
+    };
+    drop(ranges);
+
+    let start = start.max(position);
+    let size = (range_end.min(end) - start).min(capacity as u64) as usize;
+    let data = &mut request.output_mut()[size_of::<PhysicalDumpChunk>()..][..size];
+    copy_physical(start, data);
+
+    let _ = request.write_output(&PhysicalDumpChunk {
+        address: start,
+        size: size as u64,
+        cursor: start + size as u64 - input.address,
+    })?;
+    Ok(size_of::<PhysicalDumpChunk>() + size)
+}
+
+/// Copies the physical memory at `address` into `buffer` page by page. Pages
+/// that cannot be read are filled with zeros.
+fn copy_physical(address: u64, buffer: &mut [u8]) {
+    let page_size = PAGE_SIZE as u64;
+    let mut copied = 0;
+    while copied < buffer.len() {
+        let physical = address + copied as u64;
+        let length = (buffer.len() - copied).min((page_size - physical % page_size) as usize);
+        let target = &mut buffer[copied..copied + length];
+        let transferred = copy_memory(physical, target, true);
+        target[transferred..].fill(0);
+        copied += length;
+    }
+}
+
+/// Handles `IOCTL_READ_MEMORY`. It fails only if not even the first byte can
+/// be read.
+pub(crate) fn read_memory(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<MemoryReadRequest>()?;
+    let buffer = request.output_mut();
+    if input.address.checked_add(buffer.len() as u64).is_none() {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    let copied = copy_virtual(input.address, buffer);
+    if copied == 0 && !buffer.is_empty() {
+        return Err(STATUS_ACCESS_VIOLATION);
+    }
+    Ok(copied)
+}
+
+/// Copies the virtual memory at `address` into `buffer` page by page, and
+/// returns the number of bytes copied before the first page that cannot be
+/// read.
+pub(crate) fn copy_virtual(address: u64, buffer: &mut [u8]) -> usize {
+    let page_size = PAGE_SIZE as u64;
+    let mut copied = 0;
+    while copied < buffer.len() {
+        let virtual_address = address + copied as u64;
+        let length =
+            (buffer.len() - copied).min((page_size - virtual_address % page_size) as usize);
+        let transferred = copy_memory(virtual_address, &mut buffer[copied..][..length], false);
+        copied += transferred;
+        if transferred != length {
+            break;
+        }
+    }
+    copied
+}
+
+/// Copies the physical memory at `address`, or the virtual memory if not
+/// `physical`, into `buffer`, which must not cross a page in the source, and
+/// returns the number of bytes copied.
+pub(crate) fn copy_memory(address: u64, buffer: &mut [u8], physical: bool) -> usize {
+    let mut source = MM_COPY_ADDRESS::default();
+    let flags = if physical {
+        source.__bindgen_anon_1.PhysicalAddress.QuadPart = address.cast_signed();
+        MM_COPY_MEMORY_PHYSICAL
+    } else {
+        source.__bindgen_anon_1.VirtualAddress = ptr::without_provenance_mut(address as usize);
+        MM_COPY_MEMORY_VIRTUAL
+    };
+    let (status, transferred) = routines::copy_memory(buffer, source, flags);
+    if NT_SUCCESS(status) {
+        buffer.len()
+    } else {
+        transferred
+    }
+}
+
+/// The ranges of RAM, freed when dropped.
+pub(crate) struct MemoryRanges(PPHYSICAL_MEMORY_RANGE);
+
+impl MemoryRanges {
+    /// Gets the current ranges of RAM.
+    pub(crate) fn get() -> Result<Self, NTSTATUS> {
+        let ranges = unsafe { MmGetPhysicalMemoryRanges() };
+        if ranges.is_null() {
+            Err(STATUS_INSUFFICIENT_RESOURCES)
+        } else {
+            Ok(Self(ranges))
+        }
+    }
+
+    /// Returns the start and end addresses of the ranges.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (u64, u64)> {
+        // The array ends with an entry of zeros.
+        let mut count = 0;
+        unsafe {
+            while (*self.0.add(count)).NumberOfBytes.QuadPart != 0 {
+                count += 1;
+            }
+        }
+        unsafe { slice::from_raw_parts(self.0, count) }
+            .iter()
+            .map(|range| unsafe {
+                let start = range.BaseAddress.QuadPart.cast_unsigned();
+                (start, start + range.NumberOfBytes.QuadPart.cast_unsigned())
+            })
+    }
+}
+
+impl Drop for MemoryRanges {
+    fn drop(&mut self) {
+        unsafe { ExFreePoolWithTag(self.0.cast(), 0) };
+    }
+}
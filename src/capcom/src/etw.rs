@@ -0,0 +1,98 @@
+//! The ETW provider of the driver, reporting IOCTL callers and, in the defanged
+//! build, payloads that would have been executed. Events are strings written
+//! with `EtwWriteString`, so they can be viewed without a manifest, e.g., with
+//! `tracelog -start capcom -guid #6c1d5f8e-3b2a-4f7c-9a41-2e8d0c7b5a93 -rt`.
+
+use core::{
+    fmt::{self, Write},
+    ptr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use wdk_sys::{
+    GUID, NT_SUCCESS, REGHANDLE,
+    ntddk::{EtwRegister, EtwUnregister, EtwWriteString},
+};
+
+use crate::{correlation, trace::trace};
+
+/// {6c1d5f8e-3b2a-4f7c-9a41-2e8d0c7b5a93}
+const PROVIDER_ID: GUID = GUID {
+    Data1: 0x6c1d_5f8e,
+    Data2: 0x3b2a,
+    Data3: 0x4f7c,
+    Data4: [0x9a, 0x41, 0x2e, 0x8d, 0x0c, 0x7b, 0x5a, 0x93],
+};
+
+/// `TRACE_LEVEL_WARNING` in evntrace.h.
+pub(crate) const TRACE_LEVEL_WARNING: u8 = 3;
+
+/// `TRACE_LEVEL_INFORMATION` in evntrace.h.
+pub(crate) const TRACE_LEVEL_INFORMATION: u8 = 4;
+
+/// The registration handle, or zero if not registered.
+static REG_HANDLE: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the provider. Failure is not fatal as events are also printed.
+pub(crate) fn register() {
+    let mut handle: REGHANDLE = 0;
+    let status = unsafe { EtwRegister(&PROVIDER_ID, None, ptr::null_mut(), &raw mut handle) };
+    if NT_SUCCESS(status) {
+        REG_HANDLE.store(handle, Ordering::Relaxed);
+    } else {
+        trace!(ETW_REGISTRATION_FAILED, status);
+    }
+}
+
+/// Unregisters the provider if registered.
+pub(crate) fn unregister() {
+    let handle = REG_HANDLE.swap(0, Ordering::Relaxed);
+    if handle != 0 {
+        let _ = unsafe { EtwUnregister(handle) };
+    }
+}
+
+/// Writes `args` as a string event of `level`, after the current correlation
+/// ID in brackets, if any. Messages longer than the buffer are truncated.
+pub(crate) fn write(level: u8, args: fmt::Arguments<'_>) {
+    let handle = REG_HANDLE.load(Ordering::Relaxed);
+    if handle == 0 {
+        return;
+    }
+
+    let mut message = Utf16Buffer::default();
+    if let Some(correlation_id) = correlation::current() {
+        let _ = write!(message, "[{correlation_id:#x}] ");
+    }
+    let _ = message.write_fmt(args);
+    let _ = unsafe { EtwWriteString(handle, level, 0, ptr::null(), message.data.as_ptr()) };
+}
+
+/// A fixed-size UTF-16 buffer to format messages into without allocation.
+struct Utf16Buffer {
+    data: [u16; 256],
+    length: usize,
+}
+
+impl Default for Utf16Buffer {
+    fn default() -> Self {
+        Self {
+            data: [0; 256],
+            length: 0,
+        }
+    }
+}
+
+impl Write for Utf16Buffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Never write the last element so the buffer stays null-terminated.
+        for unit in s.encode_utf16() {
+            if self.length == self.data.len() - 1 {
+                break;
+            }
+            self.data[self.length] = unit;
+            self.length += 1;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+//! `IOCTL_READ_FILE` and `IOCTL_WRITE_FILE`, accessing files from kernel-mode.
+//! Their direct I/O variants are handled the same way.
+//!
+//! Files are opened with `IoCreateFileEx` and `IO_NO_PARAMETER_CHECKING`, so
+//! the request is treated as from kernel-mode regardless of the previous mode
+//! of the caller, which skips the access check against the security
+//! descriptor, and with `IO_IGNORE_SHARE_ACCESS_CHECK`, so files opened
+//! exclusively by others, such as registry hives, can also be accessed.
+
+use core::ptr;
+
+use capcom_abi::FileRequest;
+use wdk_sys::{
+    _CREATE_FILE_TYPE::CreateFileTypeNone, ACCESS_MASK, FILE_ATTRIBUTE_NORMAL,
+    FILE_NON_DIRECTORY_FILE, FILE_OPEN, FILE_OPEN_IF, FILE_SHARE_DELETE, FILE_SHARE_READ,
+    FILE_SHARE_WRITE, FILE_SYNCHRONOUS_IO_NONALERT, GENERIC_READ, GENERIC_WRITE, HANDLE,
+    IO_IGNORE_SHARE_ACCESS_CHECK, IO_NO_PARAMETER_CHECKING, IO_STATUS_BLOCK, LARGE_INTEGER,
+    NT_SUCCESS, NTSTATUS, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE, OBJECT_ATTRIBUTES,
+    STATUS_END_OF_FILE, STATUS_INVALID_PARAMETER, SYNCHRONIZE, ULONG, ntddk::ZwClose,
+};
+
+use crate::{
+    RTL_CONSTANT_STRING,
+    imports::{IoCreateFileEx, ZwReadFile, ZwWriteFile},
+    ioctl::Request,
+    trace::trace,
+};
+
+/// Handles `IOCTL_READ_FILE`.
+pub(crate) fn read_file(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<FileRequest>()?;
+    // The input and output may share the buffer, so open the file before
+    // reading into it over the path.
+    let file = File::open(request, &input, GENERIC_READ, FILE_OPEN)?;
+
+    let buffer = request.output_mut();
+    let mut offset = LARGE_INTEGER {
+        QuadPart: input.offset.cast_signed(),
+    };
+    let mut io_status = IO_STATUS_BLOCK::default();
+    let status = unsafe {
+        ZwReadFile(
+            file.0,
+            ptr::null_mut(),
+            None,
+            ptr::null_mut(),
+            &raw mut io_status,
+            buffer.as_mut_ptr().cast(),
+            buffer.len() as _,
+            &raw mut offset,
+            ptr::null_mut(),
+        )
+    };
+    match status {
+        STATUS_END_OF_FILE => Ok(0),
+        status if NT_SUCCESS(status) => Ok(io_status.Information as _),
+        status => Err(status),
+    }
+}
+
+/// Handles `IOCTL_WRITE_FILE`.
+pub(crate) fn write_file(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<FileRequest>()?;
+    let data_offset = size_of::<FileRequest>() + input.path_length as usize;
+    let Some(data) = request.data(data_offset).get(..input.data_length as usize) else {
+        return Err(STATUS_INVALID_PARAMETER);
+    };
+    let file = File::open(request, &input, GENERIC_WRITE, FILE_OPEN_IF)?;
+
+    let mut offset = LARGE_INTEGER {
+        QuadPart: input.offset.cast_signed(),
+    };
+    let mut io_status = IO_STATUS_BLOCK::default();
+    let status = unsafe {
+        ZwWriteFile(
+            file.0,
+            ptr::null_mut(),
+            None,
+            ptr::null_mut(),
+            &raw mut io_status,
+            data.as_ptr().cast_mut().cast(),
+            data.len() as _,
+            &raw mut offset,
+            ptr::null_mut(),
+        )
+    };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+    trace!(FILE_WRITTEN, io_status.Information, input.offset);
+    Ok(0)
+}
+
+/// An open file, closed when dropped.
+struct File(HANDLE);
+
+impl File {
+    /// Opens the file whose path follows `input` in the input buffer of
+    /// `request`, for synchronous I/O.
+    fn open(
+        request: &Request,
+        input: &FileRequest,
+        access: ACCESS_MASK,
+        disposition: ULONG,
+    ) -> Result<Self, NTSTATUS> {
+        let path_length = input.path_length as usize;
+        if path_length == 0 || path_length > usize::from(u16::MAX) {
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        let mut path =
+            RTL_CONSTANT_STRING(request.input_utf16(size_of::<FileRequest>(), path_length)?);
+        let mut attributes = OBJECT_ATTRIBUTES {
+            Length: size_of::<OBJECT_ATTRIBUTES>() as _,
+            RootDirectory: ptr::null_mut(),
+            ObjectName: &raw mut path,
+            Attributes: OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+            SecurityDescriptor: ptr::null_mut(),
+            SecurityQualityOfService: ptr::null_mut(),
+        };
+        let mut file = ptr::null_mut();
+        let mut io_status = IO_STATUS_BLOCK::default();
+        let status = unsafe {
+            IoCreateFileEx(
+                &raw mut file,
+                access | SYNCHRONIZE,
+                &raw mut attributes,
+                &raw mut io_status,
+                ptr::null_mut(),
+                FILE_ATTRIBUTE_NORMAL,
+                FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                disposition,
+                FILE_NON_DIRECTORY_FILE | FILE_SYNCHRONOUS_IO_NONALERT,
+                ptr::null_mut(),
+                0,
+                CreateFileTypeNone,
+                ptr::null_mut(),
+                IO_IGNORE_SHARE_ACCESS_CHECK | IO_NO_PARAMETER_CHECKING,
+                ptr::null_mut(),
+            )
+        };
+        if NT_SUCCESS(status) {
+            Ok(Self(file))
+        } else {
+            Err(status)
+        }
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        let _ = unsafe { ZwClose(self.0) };
+    }
+}
@@ -0,0 +1,54 @@
+//! `IOCTL_DUP_HANDLE`, duplicating handles of other processes into the caller.
+
+use core::ptr;
+
+use capcom_abi::{DupHandleRequest, DupHandleResponse};
+use wdk_sys::{ACCESS_MASK, HANDLE, NT_SUCCESS, NTSTATUS, STATUS_INVALID_PARAMETER};
+
+use crate::{imports::ZwDuplicateObject, ioctl::Request, process::ProcessHandle, trace::trace};
+
+/// `PROCESS_DUP_HANDLE` in ntifs.h.
+const PROCESS_DUP_HANDLE: ACCESS_MASK = 0x0040;
+
+/// `DUPLICATE_CLOSE_SOURCE`, `DUPLICATE_SAME_ACCESS` and
+/// `DUPLICATE_SAME_ATTRIBUTES`.
+const DUPLICATE_OPTIONS: u32 = 0x0000_0007;
+
+/// Handles `IOCTL_DUP_HANDLE`. The request is handled in the context of the
+/// caller, so the current process is the target.
+pub(crate) fn dup_handle(request: &mut Request) -> Result<usize, NTSTATUS> {
+    /// `NtCurrentProcess()`.
+    const CURRENT_PROCESS: HANDLE = ptr::without_provenance_mut(usize::MAX);
+
+    let mut input = request.read_input::<DupHandleRequest>()?;
+    input.handle = request.user_handle(input.handle)?;
+    if input.options & !DUPLICATE_OPTIONS != 0 {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+
+    let source_process = ProcessHandle::open(input.process_id, PROCESS_DUP_HANDLE)?;
+    let mut handle = ptr::null_mut();
+    let status = unsafe {
+        ZwDuplicateObject(
+            source_process.0,
+            ptr::without_provenance_mut(input.handle as usize),
+            CURRENT_PROCESS,
+            &raw mut handle,
+            input.desired_access,
+            0,
+            input.options,
+        )
+    };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+    trace!(
+        HANDLE_DUPLICATED,
+        input.handle,
+        input.process_id,
+        handle.addr()
+    );
+    request.write_output(&DupHandleResponse {
+        handle: handle.addr() as u64,
+    })
+}
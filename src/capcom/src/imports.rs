@@ -0,0 +1,318 @@
+//! Kernel routines called only on behalf of IOCTLs, such as those reading
+//! files, opening processes and queuing APCs. With the `dynamic-imports`
+//! feature, they are looked up with `MmGetSystemRoutineAddress` when the
+//! driver loads and called through a table, so the import table of the driver
+//! only lists the routines it needs to load, create its devices and complete
+//! IRPs, for studying how static scanners weigh imports. Without the feature,
+//! they are imported as usual. The callers are the same either way.
+//!
+//! The routines of the `hardened` and `dangerous` features are not in the
+//! table, as builds without those features do not import them at all.
+
+#[cfg(feature = "dynamic-imports")]
+use core::{
+    mem,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+#[cfg(feature = "dynamic-imports")]
+use wdk_sys::STATUS_PROCEDURE_NOT_FOUND;
+use wdk_sys::{
+    ACCESS_MASK, BOOLEAN, CREATE_FILE_TYPE, EVENT_TYPE, HANDLE, KEY_VALUE_INFORMATION_CLASS,
+    KPRIORITY, KPROCESSOR_MODE, MEMORY_CACHING_TYPE, NTSTATUS, PACCESS_STATE, PCLIENT_ID,
+    PEPROCESS, PETHREAD, PHANDLE, PHYSICAL_ADDRESS, PIO_APC_ROUTINE, PIO_DRIVER_CREATE_CONTEXT,
+    PIO_STATUS_BLOCK, PKAPC, PKIPI_BROADCAST_WORKER, PLARGE_INTEGER, PNMI_CALLBACK,
+    POBJECT_ATTRIBUTES, POBJECT_HANDLE_INFORMATION, POBJECT_TYPE, PPHYSICAL_MEMORY_RANGE,
+    PRKAPC_STATE, PRKPROCESS, PSIZE_T, PULONG, PUNICODE_STRING, PVOID, SECTION_INHERIT, SIZE_T,
+    ULONG, ULONG_PTR, USHORT,
+};
+
+use crate::thread::{KernelRoutine, RundownRoutine};
+#[cfg(feature = "dynamic-imports")]
+use crate::{routines, trace::trace};
+
+/// Declares the routines of the table as functions with the signatures of the
+/// routines, calling them through the table with the `dynamic-imports`
+/// feature, or as imports otherwise.
+macro_rules! imports {
+    ($($name:ident($($argument:ident: $type:ty),* $(,)?) $(-> $return:ty)?;)*) => {
+        /// The indexes of the routines in [`TABLE`].
+        #[cfg(feature = "dynamic-imports")]
+        #[derive(Clone, Copy)]
+        enum Import {
+            $($name,)*
+        }
+
+        /// The names of the routines.
+        #[cfg(feature = "dynamic-imports")]
+        const NAMES: &[&str] = &[$(stringify!($name),)*];
+
+        /// The addresses of the routines, resolved by [`resolve`].
+        #[cfg(feature = "dynamic-imports")]
+        static TABLE: [AtomicUsize; NAMES.len()] = [const { AtomicUsize::new(0) }; NAMES.len()];
+
+        #[cfg(not(feature = "dynamic-imports"))]
+        mod imported {
+            use super::*;
+
+            unsafe extern "system" {
+                $(pub(super) fn $name($($argument: $type),*) $(-> $return)?;)*
+            }
+        }
+
+        $(
+            #[expect(non_snake_case)]
+            #[allow(clippy::too_many_arguments)]
+            pub(crate) unsafe fn $name($($argument: $type),*) $(-> $return)? {
+                #[cfg(feature = "dynamic-imports")]
+                unsafe {
+                    let address = TABLE[Import::$name as usize].load(Ordering::Relaxed);
+                    let routine = mem::transmute::<
+                        usize,
+                        unsafe extern "system" fn($($type),*) $(-> $return)?,
+                    >(address);
+                    routine($($argument),*)
+                }
+                #[cfg(not(feature = "dynamic-imports"))]
+                unsafe {
+                    imported::$name($($argument),*)
+                }
+            }
+        )*
+    };
+}
+
+imports! {
+    // file.rs
+    IoCreateFileEx(
+        file_handle: PHANDLE,
+        desired_access: ACCESS_MASK,
+        object_attributes: POBJECT_ATTRIBUTES,
+        io_status_block: PIO_STATUS_BLOCK,
+        allocation_size: PLARGE_INTEGER,
+        file_attributes: ULONG,
+        share_access: ULONG,
+        disposition: ULONG,
+        create_options: ULONG,
+        ea_buffer: PVOID,
+        ea_length: ULONG,
+        create_file_type: CREATE_FILE_TYPE,
+        internal_parameters: PVOID,
+        options: ULONG,
+        driver_context: PIO_DRIVER_CREATE_CONTEXT,
+    ) -> NTSTATUS;
+    ZwReadFile(
+        file_handle: HANDLE,
+        event: HANDLE,
+        apc_routine: PIO_APC_ROUTINE,
+        apc_context: PVOID,
+        io_status_block: PIO_STATUS_BLOCK,
+        buffer: PVOID,
+        length: ULONG,
+        byte_offset: PLARGE_INTEGER,
+        key: PULONG,
+    ) -> NTSTATUS;
+    ZwWriteFile(
+        file_handle: HANDLE,
+        event: HANDLE,
+        apc_routine: PIO_APC_ROUTINE,
+        apc_context: PVOID,
+        io_status_block: PIO_STATUS_BLOCK,
+        buffer: PVOID,
+        length: ULONG,
+        byte_offset: PLARGE_INTEGER,
+        key: PULONG,
+    ) -> NTSTATUS;
+
+    // registry.rs
+    ZwCreateKey(
+        key_handle: PHANDLE,
+        desired_access: ACCESS_MASK,
+        object_attributes: POBJECT_ATTRIBUTES,
+        title_index: ULONG,
+        class: PUNICODE_STRING,
+        create_options: ULONG,
+        disposition: PULONG,
+    ) -> NTSTATUS;
+    ZwOpenKey(
+        key_handle: PHANDLE,
+        desired_access: ACCESS_MASK,
+        object_attributes: POBJECT_ATTRIBUTES,
+    ) -> NTSTATUS;
+    ZwQueryValueKey(
+        key_handle: HANDLE,
+        value_name: PUNICODE_STRING,
+        key_value_information_class: KEY_VALUE_INFORMATION_CLASS,
+        key_value_information: PVOID,
+        length: ULONG,
+        result_length: PULONG,
+    ) -> NTSTATUS;
+    ZwSetValueKey(
+        key_handle: HANDLE,
+        value_name: PUNICODE_STRING,
+        title_index: ULONG,
+        type_: ULONG,
+        data: PVOID,
+        data_size: ULONG,
+    ) -> NTSTATUS;
+    ZwDeleteKey(key_handle: HANDLE) -> NTSTATUS;
+
+    // process.rs
+    PsLookupProcessByProcessId(process_id: HANDLE, process: *mut PEPROCESS) -> NTSTATUS;
+    KeStackAttachProcess(process: PRKPROCESS, apc_state: PRKAPC_STATE);
+    KeUnstackDetachProcess(apc_state: PRKAPC_STATE);
+    ZwOpenProcess(
+        process_handle: PHANDLE,
+        desired_access: ACCESS_MASK,
+        object_attributes: POBJECT_ATTRIBUTES,
+        client_id: PCLIENT_ID,
+    ) -> NTSTATUS;
+
+    // thread.rs
+    PsLookupThreadByThreadId(thread_id: HANDLE, thread: *mut PETHREAD) -> NTSTATUS;
+    PsGetThreadProcessId(thread: PETHREAD) -> HANDLE;
+    KeInitializeApc(
+        apc: PKAPC,
+        thread: PETHREAD,
+        environment: i32,
+        kernel_routine: KernelRoutine,
+        rundown_routine: Option<RundownRoutine>,
+        normal_routine: PVOID,
+        processor_mode: KPROCESSOR_MODE,
+        normal_context: PVOID,
+    );
+    KeInsertQueueApc(
+        apc: PKAPC,
+        system_argument1: PVOID,
+        system_argument2: PVOID,
+        increment: KPRIORITY,
+    ) -> BOOLEAN;
+    KeRemoveQueueApc(apc: PKAPC) -> BOOLEAN;
+    RtlCaptureStackBackTrace(
+        frames_to_skip: ULONG,
+        frames_to_capture: ULONG,
+        back_trace: *mut PVOID,
+        back_trace_hash: PULONG,
+    ) -> USHORT;
+
+    // handle.rs
+    ZwDuplicateObject(
+        source_process_handle: HANDLE,
+        source_handle: HANDLE,
+        target_process_handle: HANDLE,
+        target_handle: PHANDLE,
+        desired_access: ACCESS_MASK,
+        handle_attributes: ULONG,
+        options: ULONG,
+    ) -> NTSTATUS;
+
+    // object.rs
+    ZwOpenDirectoryObject(
+        directory_handle: PHANDLE,
+        desired_access: ACCESS_MASK,
+        object_attributes: POBJECT_ATTRIBUTES,
+    ) -> NTSTATUS;
+    ZwQueryDirectoryObject(
+        directory_handle: HANDLE,
+        buffer: PVOID,
+        length: ULONG,
+        return_single_entry: BOOLEAN,
+        restart_scan: BOOLEAN,
+        context: PULONG,
+        return_length: PULONG,
+    ) -> NTSTATUS;
+
+    // module.rs and payload.rs
+    ZwQuerySystemInformation(
+        system_information_class: ULONG,
+        system_information: PVOID,
+        system_information_length: ULONG,
+        return_length: PULONG,
+    ) -> NTSTATUS;
+
+    // log.rs
+    PsGetProcessImageFileName(process: PEPROCESS) -> *const u8;
+    PsReferencePrimaryToken(process: PEPROCESS) -> PVOID;
+    PsDereferencePrimaryToken(token: PVOID);
+    SeTokenIsAdmin(token: PVOID) -> BOOLEAN;
+
+    // ring.rs
+    ZwCreateEvent(
+        event_handle: PHANDLE,
+        desired_access: ACCESS_MASK,
+        object_attributes: POBJECT_ATTRIBUTES,
+        event_type: EVENT_TYPE,
+        initial_state: BOOLEAN,
+    ) -> NTSTATUS;
+    ObOpenObjectByPointer(
+        object: PVOID,
+        handle_attributes: ULONG,
+        passed_access_state: PACCESS_STATE,
+        desired_access: ACCESS_MASK,
+        object_type: POBJECT_TYPE,
+        access_mode: KPROCESSOR_MODE,
+        handle: PHANDLE,
+    ) -> NTSTATUS;
+    ObReferenceObjectByHandle(
+        handle: HANDLE,
+        desired_access: ACCESS_MASK,
+        object_type: POBJECT_TYPE,
+        access_mode: KPROCESSOR_MODE,
+        object: *mut PVOID,
+        handle_information: POBJECT_HANDLE_INFORMATION,
+    ) -> NTSTATUS;
+
+    // memory.rs and shared.rs
+    MmAllocateContiguousMemorySpecifyCache(
+        number_of_bytes: SIZE_T,
+        lowest_acceptable_address: PHYSICAL_ADDRESS,
+        highest_acceptable_address: PHYSICAL_ADDRESS,
+        boundary_address_multiple: PHYSICAL_ADDRESS,
+        cache_type: MEMORY_CACHING_TYPE,
+    ) -> PVOID;
+    MmFreeContiguousMemory(base_address: PVOID);
+    ZwOpenSection(
+        section_handle: PHANDLE,
+        desired_access: ACCESS_MASK,
+        object_attributes: POBJECT_ATTRIBUTES,
+    ) -> NTSTATUS;
+    ZwMapViewOfSection(
+        section_handle: HANDLE,
+        process_handle: HANDLE,
+        base_address: *mut PVOID,
+        zero_bits: ULONG_PTR,
+        commit_size: SIZE_T,
+        section_offset: PLARGE_INTEGER,
+        view_size: PSIZE_T,
+        inherit_disposition: SECTION_INHERIT,
+        allocation_type: ULONG,
+        win32_protect: ULONG,
+    ) -> NTSTATUS;
+    ZwUnmapViewOfSection(process_handle: HANDLE, base_address: PVOID) -> NTSTATUS;
+
+    // dump.rs and page_table.rs
+    MmGetPhysicalMemoryRanges() -> PPHYSICAL_MEMORY_RANGE;
+    MmGetVirtualForPhysical(physical_address: PHYSICAL_ADDRESS) -> PVOID;
+    KeIpiGenericCall(broadcast_function: PKIPI_BROADCAST_WORKER, context: ULONG_PTR) -> ULONG_PTR;
+
+    // nmi.rs
+    KeRegisterNmiCallback(callback_routine: PNMI_CALLBACK, context: PVOID) -> PVOID;
+    KeDeregisterNmiCallback(handle: PVOID) -> NTSTATUS;
+
+    // self_destruct.rs
+    ZwUnloadDriver(driver_service_name: PUNICODE_STRING) -> NTSTATUS;
+}
+
+/// Resolves the routines of the table, failing if any is not exported.
+#[cfg(feature = "dynamic-imports")]
+pub(crate) fn resolve() -> Result<(), NTSTATUS> {
+    for (name, address) in NAMES.iter().zip(&TABLE) {
+        let resolved = routines::system_routine(name.as_bytes());
+        if resolved == 0 {
+            trace!(IMPORT_NOT_FOUND; name.as_bytes());
+            return Err(STATUS_PROCEDURE_NOT_FOUND);
+        }
+        address.store(resolved, Ordering::Relaxed);
+    }
+    Ok(())
+}
@@ -0,0 +1,150 @@
+//! The device interface of the compatible device, registered when the
+//! `DeviceInterface` value of the service key is nonzero, so tools enumerating
+//! interfaces with `CM_Get_Device_Interface_List` or SetupDi find the device
+//! regardless of the name of the symbolic link.
+//!
+//! Interfaces belong to physical device objects (PDOs), which a legacy driver
+//! does not have. The driver reports a root-enumerated device with
+//! `IoReportDetectedDevice` to get a PDO, and attaches the compatible device to
+//! it, so that opening the interface reaches the compatible device. PnP and
+//! power requests are passed down to the PDO.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use capcom_abi::DEVICE_INTERFACE_GUID;
+use wdk_sys::{
+    _INTERFACE_TYPE::InterfaceTypeUndefined,
+    DEVICE_OBJECT, DO_POWER_PAGABLE, FALSE, GUID, IRP_MN_REMOVE_DEVICE, NT_SUCCESS, NTSTATUS,
+    PDEVICE_OBJECT, PDRIVER_OBJECT, PIRP, STATUS_NO_SUCH_DEVICE, TRUE, UNICODE_STRING,
+    ntddk::{
+        IoAttachDeviceToDeviceStack, IoDetachDevice, IoRegisterDeviceInterface,
+        IoReportDetectedDevice, IoSetDeviceInterfaceState, IofCallDriver, PoCallDriver,
+        RtlFreeUnicodeString,
+    },
+};
+
+use crate::{
+    IoSkipCurrentIrpStackLocation, complete_request, config, current_stack_location,
+    sync::SpinLock, trace::trace,
+};
+
+/// The symbolic link of the registered interface.
+static LINK: SpinLock<Option<Link>> = SpinLock::new(None);
+
+/// The PDO, or the device attached to it, the compatible device is attached to,
+/// or null if not attached.
+static LOWER: AtomicPtr<DEVICE_OBJECT> = AtomicPtr::new(ptr::null_mut());
+
+/// The symbolic link `IoRegisterDeviceInterface` allocated.
+struct Link(UNICODE_STRING);
+
+unsafe impl Send for Link {}
+
+/// Registers and enables the interface for `device` if configured. Failure is
+/// not fatal as the device can be opened with the symbolic link.
+pub(crate) unsafe fn register(driver: PDRIVER_OBJECT, device: PDEVICE_OBJECT) {
+    if config::device_interface()
+        && let Err(status) = unsafe { try_register(driver, device) }
+    {
+        trace!(INTERFACE_REGISTRATION_FAILED, status);
+    }
+}
+
+/// Registers and enables the interface for `device`.
+unsafe fn try_register(driver: PDRIVER_OBJECT, device: PDEVICE_OBJECT) -> Result<(), NTSTATUS> {
+    unsafe {
+        let mut pdo = ptr::null_mut();
+        let status = IoReportDetectedDevice(
+            driver,
+            InterfaceTypeUndefined,
+            u32::MAX,
+            u32::MAX,
+            ptr::null_mut(),
+            ptr::null_mut(),
+            FALSE as _,
+            &raw mut pdo,
+        );
+        if !NT_SUCCESS(status) {
+            return Err(status);
+        }
+        let lower = IoAttachDeviceToDeviceStack(device, pdo);
+        if lower.is_null() {
+            return Err(STATUS_NO_SUCH_DEVICE);
+        }
+        (*device).Flags |= (*lower).Flags & DO_POWER_PAGABLE;
+        LOWER.store(lower, Ordering::Release);
+
+        let guid = GUID {
+            Data1: DEVICE_INTERFACE_GUID.data1,
+            Data2: DEVICE_INTERFACE_GUID.data2,
+            Data3: DEVICE_INTERFACE_GUID.data3,
+            Data4: DEVICE_INTERFACE_GUID.data4,
+        };
+        let mut link = UNICODE_STRING::default();
+        let mut status =
+            IoRegisterDeviceInterface(pdo, &raw const guid, ptr::null_mut(), &raw mut link);
+        if NT_SUCCESS(status) {
+            status = IoSetDeviceInterfaceState(&raw mut link, TRUE as _);
+            if NT_SUCCESS(status) {
+                *LINK.lock() = Some(Link(link));
+                return Ok(());
+            }
+            RtlFreeUnicodeString(&raw mut link);
+        }
+        unregister();
+        Err(status)
+    }
+}
+
+/// Disables the interface and detaches the compatible device from the PDO, if
+/// registered.
+pub(crate) fn unregister() {
+    let link = LINK.lock().take();
+    if let Some(Link(mut link)) = link {
+        unsafe {
+            let _ = IoSetDeviceInterfaceState(&raw mut link, FALSE as _);
+            RtlFreeUnicodeString(&raw mut link);
+        }
+    }
+    let lower = LOWER.swap(ptr::null_mut(), Ordering::AcqRel);
+    if !lower.is_null() {
+        unsafe { IoDetachDevice(lower) };
+    }
+}
+
+/// Passes the PnP request `irp` down to the PDO. When the PDO is removed, the
+/// interface is unregistered.
+pub(crate) unsafe fn forward_pnp(irp: PIRP) -> NTSTATUS {
+    unsafe {
+        let lower = LOWER.load(Ordering::Acquire);
+        let stack = match current_stack_location(irp) {
+            Ok(stack) => stack,
+            Err(status) => return complete_request(irp, status),
+        };
+        if lower.is_null() {
+            return complete_request(irp, (*irp).IoStatus.__bindgen_anon_1.Status);
+        }
+        let minor_function = u32::from((*stack).MinorFunction);
+        IoSkipCurrentIrpStackLocation(irp);
+        let status = IofCallDriver(lower, irp);
+        if minor_function == IRP_MN_REMOVE_DEVICE {
+            unregister();
+        }
+        status
+    }
+}
+
+/// Passes the power request `irp` down to the PDO.
+pub(crate) unsafe fn forward_power(irp: PIRP) -> NTSTATUS {
+    unsafe {
+        let lower = LOWER.load(Ordering::Acquire);
+        if lower.is_null() {
+            return complete_request(irp, (*irp).IoStatus.__bindgen_anon_1.Status);
+        }
+        IoSkipCurrentIrpStackLocation(irp);
+        PoCallDriver(lower, irp)
+    }
+}
@@ -0,0 +1,554 @@
+//! IOCTL dispatching and access to request buffers.
+
+use core::{ptr, slice};
+
+use capcom_abi::{
+    ABI_VERSION, BUILD_FEATURE_COVERAGE, BUILD_FEATURE_DANGEROUS, BUILD_FEATURE_DEFANGED,
+    BUILD_FEATURE_DYNAMIC_IMPORTS, BUILD_FEATURE_FAITHFUL, BUILD_FEATURE_HARDENED,
+    BUILD_FEATURE_PARANOID, BUILD_FEATURE_STRICT_COMPAT, BUILD_FEATURE_TEACHING, BUILD_INFO_OFFSET,
+    BuildInfo, CLASS_ELEVATION, CLASS_EXECUTE, CLASS_KERNEL_MEMORY, CLASS_MSR,
+    CLASS_PHYSICAL_MEMORY, IOCTL_ALLOC_CONTIGUOUS, IOCTL_CAPTURE_THREAD, IOCTL_CORRELATED,
+    IOCTL_DUMP_PHYSICAL_RANGE, IOCTL_DUP_HANDLE, IOCTL_ENABLE_EVENT_RING, IOCTL_ENUM_DIRECTORY,
+    IOCTL_ENUM_MAPPED_DRIVERS, IOCTL_FREE_CONTIGUOUS, IOCTL_GET_AUDIT, IOCTL_GET_COVERAGE,
+    IOCTL_GET_KERNEL_BASE, IOCTL_GET_OFFSETS, IOCTL_GET_PTE, IOCTL_GET_VERSION, IOCTL_KILL_SWITCH,
+    IOCTL_MAP_DRIVER, IOCTL_MAP_SHARED, IOCTL_NEGOTIATE, IOCTL_PCI_CONFIG_RW,
+    IOCTL_QUERY_ALLOCATIONS, IOCTL_QUEUE_USER_APC, IOCTL_READ_APIC, IOCTL_READ_FILE,
+    IOCTL_READ_FILE_DIRECT, IOCTL_READ_LOG, IOCTL_READ_MEMORY, IOCTL_REG_QUERY, IOCTL_REG_SET,
+    IOCTL_RUN_SHELLCODE, IOCTL_RUN_SHELLCODE_DIRECT, IOCTL_SAMPLE_NMI, IOCTL_SAVE_CONFIG,
+    IOCTL_SCAN_MEMORY, IOCTL_SELF_DESTRUCT, IOCTL_SELF_TEST, IOCTL_SET_DEBUG_BREAK,
+    IOCTL_SET_NMI_CALLBACK, IOCTL_SET_OFFSETS, IOCTL_SET_PRINT_FILTER, IOCTL_SET_PTE,
+    IOCTL_SNAPSHOT_CPU_STATE, IOCTL_UNMAP_DRIVER, IOCTL_WRITE_APIC, IOCTL_WRITE_FILE,
+    IOCTL_WRITE_FILE_DIRECT, METHOD_OUT_DIRECT, NegotiateRequest, NegotiateResponse, VersionInfo,
+};
+#[cfg(any(not(feature = "dangerous"), not(feature = "coverage")))]
+use wdk_sys::STATUS_NOT_SUPPORTED;
+use wdk_sys::{
+    NTSTATUS, PDEVICE_OBJECT, PVOID, STATUS_BUFFER_TOO_SMALL, STATUS_DELETE_PENDING,
+    STATUS_INVALID_PARAMETER, STATUS_REVISION_MISMATCH, STATUS_SUCCESS, ULONG,
+    ntddk::PsGetCurrentProcessId,
+};
+
+#[cfg(feature = "coverage")]
+use crate::coverage;
+#[cfg(feature = "dangerous")]
+use crate::mapper;
+use crate::{
+    apic, audit, config, context::Context, control, correlation, coverage::cover, dump, file,
+    handle, log, memory, module, nmi, object, offsets, page_table, payload, pci, pool, processor,
+    registry, ring, scan, self_destruct, self_test, shared, thread, trace::teach,
+};
+
+/// The IOCTL code to run a payload in user-mode memory. Like the original
+/// driver, the x86 build takes a 4-byte address with a different code.
+#[cfg(target_arch = "x86")]
+const IOCTL_RUN_NATIVE_PAYLOAD: ULONG = capcom_abi::IOCTL_RUN_PAYLOAD32;
+#[cfg(not(target_arch = "x86"))]
+const IOCTL_RUN_NATIVE_PAYLOAD: ULONG = capcom_abi::IOCTL_RUN_PAYLOAD;
+
+/// Handles the IOCTL request and returns the number of bytes written to the
+/// output buffer. Every request is recorded with its caller. Unknown IOCTL codes
+/// succeed without doing anything, like the original driver, except on the
+/// control device.
+pub(crate) fn dispatch(
+    device: PDEVICE_OBJECT,
+    context: &Context,
+    control_code: ULONG,
+    request: &mut Request,
+) -> Result<usize, NTSTATUS> {
+    log::record_caller(control_code);
+    cover!(control_code);
+    if context.is_cleaned_up() {
+        cover!();
+        return Err(STATUS_DELETE_PENDING);
+    }
+    if control_code == IOCTL_CORRELATED {
+        return correlation::correlated(device, context, request);
+    }
+    if control::is_control_device(device) {
+        return control::dispatch(device, control_code, request);
+    }
+
+    match control_code {
+        IOCTL_GET_VERSION => get_version(request),
+        IOCTL_READ_LOG => log::read(request),
+        IOCTL_KILL_SWITCH => {
+            audit::kill();
+            Ok(0)
+        }
+        IOCTL_GET_AUDIT => audit::get_audit(request),
+        IOCTL_QUERY_ALLOCATIONS => pool::query_allocations(request),
+        IOCTL_SELF_TEST => self_test::self_test(request),
+        IOCTL_SET_DEBUG_BREAK => {
+            context.check_access(0, false)?;
+            config::set_debug_break(request)
+        }
+        IOCTL_SET_PRINT_FILTER => {
+            context.check_access(0, false)?;
+            config::set_print_filter(request)
+        }
+        IOCTL_SAVE_CONFIG => {
+            context.check_access(0, false)?;
+            config::save_config(request)
+        }
+        IOCTL_SELF_DESTRUCT => {
+            context.check_access(0, false)?;
+            self_destruct::self_destruct(device, request)
+        }
+        IOCTL_SNAPSHOT_CPU_STATE => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            processor::snapshot_cpu_state(request)
+        }
+        IOCTL_GET_PTE => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            page_table::get_pte(request)
+        }
+        IOCTL_SET_PTE => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            page_table::set_pte(request)
+        }
+        IOCTL_CAPTURE_THREAD => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            thread::capture_thread(request)
+        }
+        IOCTL_ENUM_DIRECTORY => {
+            context.check_access(0, false)?;
+            object::enum_directory(request)
+        }
+        IOCTL_DUP_HANDLE => {
+            context.check_access(CLASS_ELEVATION, false)?;
+            handle::dup_handle(request)
+        }
+        IOCTL_QUEUE_USER_APC => {
+            context.check_access(CLASS_ELEVATION, false)?;
+            thread::queue_user_apc(request)
+        }
+        IOCTL_REG_QUERY => {
+            context.check_access(CLASS_ELEVATION, false)?;
+            registry::reg_query(request)
+        }
+        IOCTL_REG_SET => {
+            context.check_access(CLASS_ELEVATION, false)?;
+            registry::reg_set(request)
+        }
+        IOCTL_READ_FILE => {
+            context.check_access(CLASS_ELEVATION, false)?;
+            file::read_file(request)
+        }
+        IOCTL_WRITE_FILE => {
+            context.check_access(CLASS_ELEVATION, false)?;
+            file::write_file(request)
+        }
+        IOCTL_SET_NMI_CALLBACK => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            nmi::set_nmi_callback(request)
+        }
+        IOCTL_SAMPLE_NMI => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            nmi::sample_nmi(request)
+        }
+        IOCTL_READ_APIC => {
+            context.check_access(CLASS_MSR, false)?;
+            apic::read_apic(request)
+        }
+        IOCTL_WRITE_APIC => {
+            context.check_access(CLASS_MSR, false)?;
+            apic::write_apic(request)
+        }
+        IOCTL_PCI_CONFIG_RW => {
+            context.check_access(CLASS_PHYSICAL_MEMORY, false)?;
+            pci::pci_config_rw(request)
+        }
+        IOCTL_ALLOC_CONTIGUOUS => {
+            context.check_access(CLASS_PHYSICAL_MEMORY, false)?;
+            memory::alloc_contiguous(context, request)
+        }
+        IOCTL_FREE_CONTIGUOUS => {
+            context.check_access(CLASS_PHYSICAL_MEMORY, false)?;
+            memory::free_contiguous(context, request)
+        }
+        IOCTL_DUMP_PHYSICAL_RANGE => {
+            context.check_access(CLASS_PHYSICAL_MEMORY, false)?;
+            dump::dump_physical_range(request)
+        }
+        IOCTL_READ_MEMORY => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            dump::read_memory(request)
+        }
+        IOCTL_SCAN_MEMORY => scan::scan_memory(context, request),
+        IOCTL_SET_OFFSETS => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            offsets::set_offsets(request)
+        }
+        IOCTL_GET_OFFSETS => {
+            context.check_access(0, false)?;
+            offsets::get_offsets(request)
+        }
+        IOCTL_GET_KERNEL_BASE => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            module::get_kernel_base(request)
+        }
+        IOCTL_MAP_SHARED => {
+            context.check_access(CLASS_KERNEL_MEMORY, false)?;
+            shared::map_shared(context, request)
+        }
+        IOCTL_ENABLE_EVENT_RING => {
+            context.check_access(0, false)?;
+            ring::enable_event_ring(context, request)
+        }
+        IOCTL_READ_FILE_DIRECT => {
+            context.check_access(CLASS_ELEVATION, false)?;
+            file::read_file(request)
+        }
+        IOCTL_WRITE_FILE_DIRECT => {
+            context.check_access(CLASS_ELEVATION, false)?;
+            file::write_file(request)
+        }
+        IOCTL_NEGOTIATE => negotiate(context, request),
+        IOCTL_RUN_NATIVE_PAYLOAD => {
+            let process_id = unsafe { PsGetCurrentProcessId() }.addr();
+            teach!(TEACHING_IRP_ARRIVED, control_code, process_id);
+            context.check_access(CLASS_EXECUTE, true)?;
+            let result = audit::execute(context, || payload::run_user_payload(request));
+            teach!(
+                TEACHING_COMPLETED,
+                result.err().unwrap_or(STATUS_SUCCESS),
+                result.unwrap_or(0)
+            );
+            result
+        }
+        IOCTL_RUN_SHELLCODE => {
+            context.check_access(CLASS_EXECUTE, false)?;
+            audit::execute(context, || payload::run_shellcode(request))
+        }
+        IOCTL_RUN_SHELLCODE_DIRECT => {
+            context.check_access(CLASS_EXECUTE, false)?;
+            audit::execute(context, || payload::run_shellcode(request))
+        }
+        #[cfg(feature = "dangerous")]
+        IOCTL_MAP_DRIVER => {
+            context.check_access(CLASS_EXECUTE, false)?;
+            audit::execute(context, || mapper::map_driver(request))
+        }
+        #[cfg(feature = "dangerous")]
+        IOCTL_ENUM_MAPPED_DRIVERS => {
+            context.check_access(CLASS_EXECUTE, false)?;
+            Ok(mapper::enum_mapped_drivers(request))
+        }
+        #[cfg(feature = "dangerous")]
+        IOCTL_UNMAP_DRIVER => {
+            context.check_access(CLASS_EXECUTE, false)?;
+            mapper::unmap_driver(request)
+        }
+        #[cfg(not(feature = "dangerous"))]
+        IOCTL_MAP_DRIVER | IOCTL_ENUM_MAPPED_DRIVERS | IOCTL_UNMAP_DRIVER => {
+            context.check_access(CLASS_EXECUTE, false)?;
+            Err(STATUS_NOT_SUPPORTED)
+        }
+        #[cfg(feature = "coverage")]
+        IOCTL_GET_COVERAGE => {
+            context.check_access(0, false)?;
+            coverage::get_coverage(request)
+        }
+        #[cfg(not(feature = "coverage"))]
+        IOCTL_GET_COVERAGE => {
+            context.check_access(0, false)?;
+            Err(STATUS_NOT_SUPPORTED)
+        }
+        _ => Ok(0),
+    }
+}
+
+/// The metadata of this build, embedded by the build script.
+pub(crate) const BUILD_INFO: BuildInfo = BuildInfo {
+    features: if cfg!(feature = "defanged") {
+        BUILD_FEATURE_DEFANGED
+    } else {
+        0
+    } | if cfg!(feature = "dangerous") {
+        BUILD_FEATURE_DANGEROUS
+    } else {
+        0
+    } | if cfg!(feature = "coverage") {
+        BUILD_FEATURE_COVERAGE
+    } else {
+        0
+    } | if cfg!(feature = "paranoid") {
+        BUILD_FEATURE_PARANOID
+    } else {
+        0
+    } | if cfg!(feature = "strict-compat") {
+        BUILD_FEATURE_STRICT_COMPAT
+    } else {
+        0
+    } | if cfg!(feature = "faithful") {
+        BUILD_FEATURE_FAITHFUL
+    } else {
+        0
+    } | if cfg!(feature = "hardened") {
+        BUILD_FEATURE_HARDENED
+    } else {
+        0
+    } | if cfg!(feature = "teaching") {
+        BUILD_FEATURE_TEACHING
+    } else {
+        0
+    } | if cfg!(feature = "dynamic-imports") {
+        BUILD_FEATURE_DYNAMIC_IMPORTS
+    } else {
+        0
+    },
+    ..include!(concat!(env!("OUT_DIR"), "/build_info.rs"))
+};
+
+/// Handles `IOCTL_GET_VERSION`.
+pub(crate) fn get_version(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let length = request.write_output(&VersionInfo {
+        abi_version: ABI_VERSION,
+        capabilities: payload::capabilities(),
+        enabled_classes: config::enabled_classes(),
+    })?;
+    // Clients of older versions give a buffer only for `VersionInfo`.
+    let padding = [0; BUILD_INFO_OFFSET - size_of::<VersionInfo>()];
+    if request.write_output_bytes_at(length, &padding).is_err() {
+        return Ok(length);
+    }
+    Ok(request
+        .write_output_at(BUILD_INFO_OFFSET, &BUILD_INFO)
+        .map_or(length, |written| BUILD_INFO_OFFSET + written))
+}
+
+/// Handles `IOCTL_NEGOTIATE`, granting the requested classes that are enabled.
+fn negotiate(context: &Context, request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<NegotiateRequest>()?;
+    if input.abi_version != ABI_VERSION {
+        cover!();
+        return Err(STATUS_REVISION_MISMATCH);
+    }
+
+    let granted_classes = input.classes & config::enabled_classes();
+    context.negotiate(granted_classes)?;
+    request.write_output(&NegotiateResponse { granted_classes })
+}
+
+/// The buffers of an IOCTL request. With `METHOD_BUFFERED`, the input and
+/// output buffers share the same system buffer. With `METHOD_IN_DIRECT` and
+/// `METHOD_OUT_DIRECT`, the output buffer is described by an MDL instead.
+pub(crate) struct Request {
+    buffer: *mut u8,
+    input_length: usize,
+    /// The output buffer. This is `buffer` unless with `METHOD_OUT_DIRECT`.
+    output: *mut u8,
+    output_length: usize,
+    /// The buffer described by the MDL with `METHOD_IN_DIRECT`, read as data
+    /// by the driver.
+    direct_input: *const u8,
+    direct_input_length: usize,
+    /// Whether the caller is a 32-bit process under WOW64.
+    wow64: bool,
+}
+
+impl Request {
+    /// Wraps the system buffer of the request. `buffer` must be valid for the
+    /// larger of `input_length` and `output_length` bytes, or be null.
+    pub(crate) fn new(buffer: PVOID, input_length: usize, output_length: usize) -> Self {
+        if buffer.is_null() {
+            Self {
+                buffer: ptr::null_mut(),
+                input_length: 0,
+                output: ptr::null_mut(),
+                output_length: 0,
+                direct_input: ptr::null(),
+                direct_input_length: 0,
+                wow64: false,
+            }
+        } else {
+            Self {
+                buffer: buffer.cast(),
+                input_length,
+                output: buffer.cast(),
+                output_length,
+                direct_input: ptr::null(),
+                direct_input_length: 0,
+                wow64: false,
+            }
+        }
+    }
+
+    /// Wraps the buffers of a `METHOD_IN_DIRECT` or `METHOD_OUT_DIRECT`
+    /// request. `buffer` must be valid for `input_length` bytes, and `direct`
+    /// for `direct_length` bytes, or be null.
+    pub(crate) fn new_direct(
+        method: ULONG,
+        buffer: PVOID,
+        input_length: usize,
+        direct: PVOID,
+        direct_length: usize,
+    ) -> Self {
+        let mut request = Self::new(buffer, input_length, 0);
+        if direct.is_null() {
+            return request;
+        }
+        if method == METHOD_OUT_DIRECT {
+            request.output = direct.cast();
+            request.output_length = direct_length;
+        } else {
+            request.direct_input = direct.cast();
+            request.direct_input_length = direct_length;
+        }
+        request
+    }
+
+    /// Marks the request as sent by a 32-bit process under WOW64, whose
+    /// pointers and handles are given in the lower 32 bits of `u64` fields.
+    pub(crate) fn set_wow64(&mut self, wow64: bool) {
+        self.wow64 = wow64;
+    }
+
+    /// Checks whether the caller is a 32-bit process under WOW64.
+    pub(crate) fn is_wow64(&self) -> bool {
+        self.wow64
+    }
+
+    /// Returns `address`, a user-mode address given in a field of the input.
+    /// From a WOW64 caller, the upper 32 bits must be zero or, if the caller
+    /// sign-extended the pointer, all ones, and are cleared, so that the
+    /// address is not truncated or sign-extended into kernel-mode memory.
+    pub(crate) fn user_pointer(&self, address: u64) -> Result<u64, NTSTATUS> {
+        if !self.wow64 {
+            return Ok(address);
+        }
+        check_upper_bits(address)?;
+        Ok(address & u64::from(u32::MAX))
+    }
+
+    /// Returns `handle` given in a field of the input. From a WOW64 caller,
+    /// the lower 32 bits are sign-extended like the system does, so pseudo
+    /// handles, such as `-1` for the current process, keep their meaning.
+    pub(crate) fn user_handle(&self, handle: u64) -> Result<u64, NTSTATUS> {
+        if !self.wow64 {
+            return Ok(handle);
+        }
+        check_upper_bits(handle)?;
+        Ok(i64::from((handle as u32).cast_signed()).cast_unsigned())
+    }
+
+    /// Drops the first `length` bytes of the input buffer, e.g., a header that
+    /// was read, so that the rest is read as the input. The output buffer does
+    /// not change.
+    pub(crate) fn skip_input(&mut self, length: usize) -> Result<(), NTSTATUS> {
+        if self.input_length < length {
+            cover!();
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        if length != 0 {
+            self.buffer = unsafe { self.buffer.add(length) };
+            self.input_length -= length;
+        }
+        Ok(())
+    }
+
+    /// Returns the input buffer.
+    pub(crate) fn input(&self) -> &[u8] {
+        if self.buffer.is_null() {
+            &[]
+        } else {
+            unsafe { slice::from_raw_parts(self.buffer, self.input_length) }
+        }
+    }
+
+    /// Returns `length` bytes of the input buffer at `offset` as UTF-16. Both
+    /// must be even.
+    #[expect(clippy::cast_ptr_alignment)]
+    pub(crate) fn input_utf16(&self, offset: usize, length: usize) -> Result<&[u16], NTSTATUS> {
+        if !offset.is_multiple_of(2) || !length.is_multiple_of(2) {
+            cover!();
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        let Some(bytes) = self.input().get(offset..offset.saturating_add(length)) else {
+            cover!();
+            return Err(STATUS_INVALID_PARAMETER);
+        };
+        // The system buffer is 8-byte aligned, so an even offset is 2-byte
+        // aligned.
+        Ok(unsafe { slice::from_raw_parts(bytes.as_ptr().cast::<u16>(), length / 2) })
+    }
+
+    /// Returns the data following `offset` bytes of the input buffer. With
+    /// `METHOD_IN_DIRECT`, this is the buffer described by the MDL instead, so
+    /// large data is not copied through the system buffer.
+    pub(crate) fn data(&self, offset: usize) -> &[u8] {
+        if self.direct_input.is_null() {
+            self.input().get(offset..).unwrap_or_default()
+        } else {
+            unsafe { slice::from_raw_parts(self.direct_input, self.direct_input_length) }
+        }
+    }
+
+    /// Returns the output buffer. The input buffer must not be used anymore
+    /// once this is written, as it may share the memory.
+    pub(crate) fn output_mut(&mut self) -> &mut [u8] {
+        if self.output.is_null() {
+            &mut []
+        } else {
+            unsafe { slice::from_raw_parts_mut(self.output, self.output_length) }
+        }
+    }
+
+    /// Reads `T` from the start of the input buffer.
+    pub(crate) fn read_input<T: Copy>(&self) -> Result<T, NTSTATUS> {
+        if self.input_length < size_of::<T>() {
+            cover!();
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        Ok(unsafe { self.buffer.cast::<T>().read_unaligned() })
+    }
+
+    /// Writes `value` to the start of the output buffer and returns the number
+    /// of bytes written.
+    pub(crate) fn write_output<T: Copy>(&mut self, value: &T) -> Result<usize, NTSTATUS> {
+        self.write_output_at(0, value)
+    }
+
+    /// Writes `value` at `offset` bytes into the output buffer and returns the
+    /// number of bytes written.
+    pub(crate) fn write_output_at<T: Copy>(
+        &mut self,
+        offset: usize,
+        value: &T,
+    ) -> Result<usize, NTSTATUS> {
+        if self.output_length < offset + size_of::<T>() {
+            cover!();
+            return Err(STATUS_BUFFER_TOO_SMALL);
+        }
+        unsafe { self.output.add(offset).cast::<T>().write_unaligned(*value) };
+        Ok(size_of::<T>())
+    }
+
+    /// Copies `bytes` to `offset` bytes into the output buffer and returns the
+    /// number of bytes written. The input buffer must not be used anymore, as
+    /// it may share the memory.
+    pub(crate) fn write_output_bytes_at(
+        &mut self,
+        offset: usize,
+        bytes: &[u8],
+    ) -> Result<usize, NTSTATUS> {
+        if self.output_length < offset + bytes.len() {
+            cover!();
+            return Err(STATUS_BUFFER_TOO_SMALL);
+        }
+        unsafe { ptr::copy(bytes.as_ptr(), self.output.add(offset), bytes.len()) };
+        Ok(bytes.len())
+    }
+}
+
+/// Checks that the upper 32 bits of `value` from a WOW64 caller are zero or all
+/// ones, i.e., that it was extended from 32 bits.
+fn check_upper_bits(value: u64) -> Result<(), NTSTATUS> {
+    match value >> 32 {
+        0 | 0xffff_ffff => Ok(()),
+        _ => {
+            cover!();
+            Err(STATUS_INVALID_PARAMETER)
+        }
+    }
+}
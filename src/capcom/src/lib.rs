@@ -1,41 +1,162 @@
 #![doc = include_str!("../../../README.md")]
 #![no_std]
 
-use core::{arch::asm, ptr};
+mod apic;
+mod arch;
+mod audit;
+mod boot;
+mod config;
+mod context;
+mod control;
+mod correlation;
+mod coverage;
+mod dump;
+mod etw;
+mod file;
+mod handle;
+mod imports;
+mod interface;
+mod ioctl;
+mod log;
+#[cfg(feature = "dangerous")]
+mod mapper;
+mod memory;
+mod mmio;
+mod module;
+mod names;
+mod nmi;
+mod object;
+mod offsets;
+mod page_table;
+mod paranoid;
+mod payload;
+mod pci;
+mod pool;
+mod process;
+mod processor;
+mod registry;
+#[cfg(all(feature = "hardened", not(feature = "defanged")))]
+mod remap;
+mod ring;
+mod routines;
+mod scan;
+mod self_destruct;
+mod self_test;
+mod shared;
+mod shutdown;
+mod stream;
+mod sync;
+mod thread;
+mod trace;
 
+#[cfg(all(feature = "hardened", feature = "strict-compat"))]
+compile_error!("The hardened personality cannot be combined with strict-compat or faithful");
+
+use core::ptr;
+
+use capcom_abi::{
+    DEBUG_BREAK_ON_EVERY_PAYLOAD, DEBUG_BREAK_ON_LOAD, DEBUG_BREAK_ON_PANIC_ONLY, DEVICE_TYPE,
+    METHOD_IN_DIRECT, METHOD_OUT_DIRECT,
+};
+#[cfg(target_pointer_width = "64")]
+use wdk_sys::ntddk::IoIs32bitProcess;
 use wdk_sys::{
-    DRIVER_OBJECT, FALSE, IO_NO_INCREMENT, IRP_MJ_CLOSE, IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL,
+    _MEMORY_CACHING_TYPE::MmCached,
+    _MM_PAGE_PRIORITY::NormalPagePriority,
+    _MODE::KernelMode,
+    DO_BUFFERED_IO, DRIVER_OBJECT, FALSE, IO_NO_INCREMENT, IRP_MJ_CLEANUP, IRP_MJ_CLOSE,
+    IRP_MJ_CREATE, IRP_MJ_DEVICE_CONTROL, IRP_MJ_PNP, IRP_MJ_POWER, IRP_MJ_READ, IRP_MJ_SHUTDOWN,
+    IRP_MJ_WRITE, MDL_MAPPED_TO_SYSTEM_VA, MDL_SOURCE_IS_NONPAGED_POOL, MdlMappingNoExecute,
     NT_SUCCESS, NTSTATUS, PAGED_CODE, PCUNICODE_STRING, PDEVICE_OBJECT, PDRIVER_OBJECT,
-    PIO_STACK_LOCATION, PIRP, PUNICODE_STRING, PVOID, STATUS_SUCCESS, ULONG, UNICODE_STRING,
+    PIO_STACK_LOCATION, PIRP, PMDL, PVOID, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_DEVICE_REQUEST, STATUS_INVALID_HANDLE, STATUS_INVALID_PARAMETER, STATUS_SUCCESS,
+    ULONG, UNICODE_STRING,
     ntddk::{
         IoCreateDevice, IoCreateSymbolicLink, IoDeleteDevice, IoDeleteSymbolicLink,
-        IofCompleteRequest, KdRefreshDebuggerNotPresent, MmGetSystemRoutineAddress,
+        IofCompleteRequest, KdRefreshDebuggerNotPresent, MmMapLockedPagesSpecifyCache,
     },
 };
 
-const DEVICE_NAME: [u16; 18] = utf16_lit::utf16!("\\Device\\Htsysm72FB");
-const LINK_NAME: [u16; 22] = utf16_lit::utf16!("\\DosDevices\\Htsysm72FB");
-
-const DEVICE_TYPE: ULONG = 0xaa01;
-const IOCTL_RUN_PAYLOAD: ULONG = (DEVICE_TYPE << 16) | 0x3044;
+use crate::{context::Context, ioctl::Request, trace::trace};
 
 /// The entry point.
 #[unsafe(link_section = "INIT")]
 #[unsafe(export_name = "DriverEntry")]
 extern "system" fn driver_entry(
     driver: &mut DRIVER_OBJECT,
-    _registry_path: PCUNICODE_STRING,
+    registry_path: PCUNICODE_STRING,
 ) -> NTSTATUS {
     unsafe {
-        // Break into a kernel debugger if present.
-        if KdRefreshDebuggerNotPresent() == 0 {
-            asm!("int3", options(nomem, nostack, preserves_flags));
+        routines::resolve();
+        #[cfg(feature = "dynamic-imports")]
+        if let Err(status) = imports::resolve() {
+            return status;
+        }
+        config::load(registry_path);
+        log::restore();
+        boot::log_phase();
+        debug_break(&[DEBUG_BREAK_ON_LOAD, DEBUG_BREAK_ON_EVERY_PAYLOAD]);
+        offsets::init();
+        etw::register();
+        #[cfg(not(feature = "defanged"))]
+        payload::allocate_staging_area();
+
+        let driver_object = ptr::from_mut(driver);
+        let result =
+            create_device(driver_object, names::device(false).as_slice()).and_then(|device| {
+                control::create(driver_object)?;
+                match create_links() {
+                    Ok(()) => {
+                        interface::register(driver_object, device);
+                        Ok(())
+                    }
+                    // Windows may not be ready for the links yet.
+                    Err(status) if boot::is_early() => {
+                        boot::defer(driver_object, device, status);
+                        Ok(())
+                    }
+                    Err(status) => {
+                        trace!(LINK_CREATION_FAILED, status);
+                        Err(status)
+                    }
+                }
+            });
+        if let Err(status) = result {
+            shutdown::unregister();
+            delete_devices(driver_object);
+            #[cfg(not(feature = "defanged"))]
+            payload::free_staging_area();
+            etw::unregister();
+            return status;
         }
+    }
+
+    driver.DriverUnload = Some(driver_unload);
+    driver.MajorFunction[IRP_MJ_CREATE as usize] = Some(driver_create);
+    driver.MajorFunction[IRP_MJ_CLEANUP as usize] = Some(driver_cleanup);
+    driver.MajorFunction[IRP_MJ_CLOSE as usize] = Some(driver_close);
+    driver.MajorFunction[IRP_MJ_DEVICE_CONTROL as usize] = Some(driver_ioctl);
+    driver.MajorFunction[IRP_MJ_READ as usize] = Some(driver_read_write);
+    driver.MajorFunction[IRP_MJ_WRITE as usize] = Some(driver_read_write);
+    driver.MajorFunction[IRP_MJ_PNP as usize] = Some(driver_pnp);
+    driver.MajorFunction[IRP_MJ_POWER as usize] = Some(driver_power);
+    driver.MajorFunction[IRP_MJ_SHUTDOWN as usize] = Some(driver_shutdown);
+    trace!(LOADED);
+    let build = ioctl::BUILD_INFO;
+    trace!(BUILD, build.timestamp, build.features, build.dirty; &build.commit);
+    STATUS_SUCCESS
+}
 
-        let mut device_name = RTL_CONSTANT_STRING(&DEVICE_NAME);
+/// Creates a device object named `device_name`.
+unsafe fn create_device(
+    driver: PDRIVER_OBJECT,
+    device_name: &[u16],
+) -> Result<PDEVICE_OBJECT, NTSTATUS> {
+    unsafe {
+        let mut device_name = RTL_CONSTANT_STRING(device_name);
         let mut device = ptr::null_mut();
         let status = IoCreateDevice(
-            ptr::from_mut(driver),
+            driver,
             0,
             &raw mut device_name,
             DEVICE_TYPE,
@@ -43,19 +164,37 @@ extern "system" fn driver_entry(
             FALSE as _,
             &raw mut device,
         );
-        assert!(NT_SUCCESS(status));
-
-        let mut link_name = RTL_CONSTANT_STRING(&LINK_NAME);
-        let status = IoCreateSymbolicLink(&raw mut link_name, &raw mut device_name);
-        assert!(NT_SUCCESS(status));
+        if !NT_SUCCESS(status) {
+            trace!(DEVICE_CREATION_FAILED, status);
+            return Err(status);
+        }
+        // For `IRP_MJ_READ` and `IRP_MJ_WRITE`.
+        (*device).Flags |= DO_BUFFERED_IO;
+        Ok(device)
     }
+}
 
-    driver.DriverUnload = Some(driver_unload);
-    driver.MajorFunction[IRP_MJ_CREATE as usize] = Some(driver_open_close);
-    driver.MajorFunction[IRP_MJ_CLOSE as usize] = Some(driver_open_close);
-    driver.MajorFunction[IRP_MJ_DEVICE_CONTROL as usize] = Some(driver_ioctl);
-    wdk::println!("Loaded the driver successfully");
-    STATUS_SUCCESS
+/// Creates the symbolic links to the compatible device and the control
+/// device. Neither is left created on failure.
+fn create_links() -> Result<(), NTSTATUS> {
+    for control in [false, true] {
+        let device_name = names::device(control);
+        let link_name = names::link(control);
+        let mut device_name = RTL_CONSTANT_STRING(device_name.as_slice());
+        let mut link_name = RTL_CONSTANT_STRING(link_name.as_slice());
+        let status = unsafe { IoCreateSymbolicLink(&raw mut link_name, &raw mut device_name) };
+        if !NT_SUCCESS(status) {
+            if control {
+                // Not `delete_link`, as the name of the control link may be
+                // taken by another driver.
+                let name = names::link(false);
+                let mut link_name = RTL_CONSTANT_STRING(name.as_slice());
+                let _ = unsafe { IoDeleteSymbolicLink(&raw mut link_name) };
+            }
+            return Err(status);
+        }
+    }
+    Ok(())
 }
 
 /// Handles the driver unload request.
@@ -63,101 +202,301 @@ extern "system" fn driver_entry(
 extern "C" fn driver_unload(driver: PDRIVER_OBJECT) {
     PAGED_CODE!();
 
-    let mut link_name = RTL_CONSTANT_STRING(&LINK_NAME);
+    delete_link();
+    interface::unregister();
+    nmi::unregister();
+    shutdown::unregister();
+    #[cfg(feature = "dangerous")]
+    mapper::unmap_all();
+    unsafe { delete_devices(driver) };
+    thread::drain();
+    #[cfg(not(feature = "defanged"))]
+    payload::free_staging_area();
+    pool::report_leaks();
+    etw::unregister();
+}
+
+/// Deletes the devices of `driver`.
+unsafe fn delete_devices(driver: PDRIVER_OBJECT) {
     unsafe {
-        let _ = IoDeleteSymbolicLink(&raw mut link_name);
-        IoDeleteDevice((*driver).DeviceObject);
+        let mut device = (*driver).DeviceObject;
+        while !device.is_null() {
+            let next = (*device).NextDevice;
+            IoDeleteDevice(device);
+            device = next;
+        }
+    }
+}
+
+/// Deletes the symbolic links to the devices. They may be already deleted with
+/// `IOCTL_SELF_DESTRUCT`, or not created yet when started during boot.
+fn delete_link() {
+    for control in [false, true] {
+        let name = names::link(control);
+        let mut link_name = RTL_CONSTANT_STRING(name.as_slice());
+        let _ = unsafe { IoDeleteSymbolicLink(&raw mut link_name) };
     }
 }
 
-/// Handles the driver open and close request.
+/// Handles the driver open request by allocating the per-handle context. Only
+/// elevated administrators may open the control device.
 #[unsafe(link_section = "PAGE")]
-extern "C" fn driver_open_close(_device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+extern "C" fn driver_create(device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
     PAGED_CODE!();
     unsafe {
-        (*irp).IoStatus.__bindgen_anon_1.Status = STATUS_SUCCESS;
-        (*irp).IoStatus.Information = 0;
-        IofCompleteRequest(irp, IO_NO_INCREMENT as _);
+        let caller = if control::is_control_device(device) {
+            control::check_caller()
+        } else {
+            Ok(())
+        };
+        let status = match caller
+            .and_then(|()| current_stack_location(irp))
+            .and_then(|stack| Context::create((*stack).FileObject))
+        {
+            Ok(()) => STATUS_SUCCESS,
+            Err(status) => status,
+        };
+        complete_request(irp, status)
     }
-    STATUS_SUCCESS
 }
 
-/// Handles the driver IOCTL request.
+/// Handles the driver cleanup request sent when the last handle is closed.
 #[unsafe(link_section = "PAGE")]
-extern "C" fn driver_ioctl(_device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+extern "C" fn driver_cleanup(_device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
     PAGED_CODE!();
     unsafe {
-        let stack = IoGetCurrentIrpStackLocation(irp);
-        let control_code = (*stack).Parameters.DeviceIoControl.IoControlCode;
-
-        // Execute payload if IOCTL_RUN_PAYLOAD is geven.
-        if control_code == IOCTL_RUN_PAYLOAD {
-            let buffer = (*irp).AssociatedIrp.SystemBuffer;
-            let buffer = buffer.cast::<PayloadType>();
-            run_payload(*buffer);
+        let stack = match current_stack_location(irp) {
+            Ok(stack) => stack,
+            Err(status) => return complete_request(irp, status),
+        };
+        if let Some(context) = Context::get((*stack).FileObject) {
+            context.cleanup();
         }
+        complete_request(irp, STATUS_SUCCESS)
+    }
+}
 
-        (*irp).IoStatus.__bindgen_anon_1.Status = STATUS_SUCCESS;
-        IofCompleteRequest(irp, IO_NO_INCREMENT as _);
+/// Handles the driver close request by freeing the per-handle context. The
+/// I/O manager sends this after all requests for the handle completed.
+#[unsafe(link_section = "PAGE")]
+extern "C" fn driver_close(_device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    PAGED_CODE!();
+    unsafe {
+        let stack = match current_stack_location(irp) {
+            Ok(stack) => stack,
+            Err(status) => return complete_request(irp, status),
+        };
+        Context::destroy((*stack).FileObject);
+        complete_request(irp, STATUS_SUCCESS)
     }
-    STATUS_SUCCESS
 }
 
-type PayloadType = unsafe extern "C" fn(unsafe extern "C" fn(PUNICODE_STRING) -> PVOID);
+/// Handles the PnP request sent to the compatible device attached to the PDO
+/// of the device interface.
+#[unsafe(link_section = "PAGE")]
+extern "C" fn driver_pnp(_device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    PAGED_CODE!();
+    unsafe { interface::forward_pnp(irp) }
+}
 
-/// Executes `payload` without CR4.SMEP and interrupts.
-unsafe fn run_payload(payload: PayloadType) {
+/// Handles the power request sent to the compatible device attached to the
+/// PDO of the device interface.
+extern "C" fn driver_power(_device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    unsafe { interface::forward_power(irp) }
+}
+
+/// Handles the shutdown request sent to the control device when Windows shuts
+/// down or restarts.
+#[unsafe(link_section = "PAGE")]
+extern "C" fn driver_shutdown(_device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    PAGED_CODE!();
+    shutdown::shutdown();
+    unsafe { complete_request(irp, STATUS_SUCCESS) }
+}
+
+/// Completes `irp` with `status` and no information.
+unsafe fn complete_request(irp: PIRP, status: NTSTATUS) -> NTSTATUS {
     unsafe {
-        let cr4 = disable_smep();
-        payload(MmGetSystemRoutineAddress);
-        restore_smep(cr4);
+        (*irp).IoStatus.__bindgen_anon_1.Status = status;
+        (*irp).IoStatus.Information = 0;
+        IofCompleteRequest(irp, IO_NO_INCREMENT as _);
     }
+    status
 }
 
-/// Disables CR4.SMEP and disables interrupts.
-unsafe fn disable_smep() -> u64 {
-    const CR4_SMEP: u64 = 1 << 20;
-
+/// Handles the driver IOCTL request.
+#[unsafe(link_section = "PAGE")]
+extern "C" fn driver_ioctl(device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    PAGED_CODE!();
     unsafe {
-        asm!("cli", options(nomem, nostack));
-        let cr4 = cr4();
-        write_cr4(cr4 & !CR4_SMEP);
-        cr4
+        let stack = match current_stack_location(irp) {
+            Ok(stack) => stack,
+            Err(status) => return complete_request(irp, status),
+        };
+        let parameters = (*stack).Parameters.DeviceIoControl;
+        let result = match (Context::get((*stack).FileObject), request(irp, stack)) {
+            (Some(context), Ok(mut request)) => {
+                request.set_wow64(is_wow64(irp));
+                ioctl::dispatch(device, context, parameters.IoControlCode, &mut request)
+            }
+            (None, _) => Err(STATUS_INVALID_HANDLE),
+            (_, Err(status)) => Err(status),
+        };
+        let (status, information) = match result {
+            Ok(information) => (STATUS_SUCCESS, information),
+            Err(status) => (status, 0),
+        };
+
+        (*irp).IoStatus.__bindgen_anon_1.Status = status;
+        (*irp).IoStatus.Information = information as _;
+        IofCompleteRequest(irp, IO_NO_INCREMENT as _);
+        status
     }
 }
 
-/// Restores CR4 and enables interrupts.
-unsafe fn restore_smep(cr4: u64) {
+/// Handles the read and write requests, which stream shellcode and its results
+/// as an alternative to IOCTLs. The control device does not accept them.
+#[unsafe(link_section = "PAGE")]
+extern "C" fn driver_read_write(device: PDEVICE_OBJECT, irp: PIRP) -> NTSTATUS {
+    PAGED_CODE!();
     unsafe {
-        write_cr4(cr4);
-        asm!("sti", options(nomem, nostack));
+        let stack = match current_stack_location(irp) {
+            Ok(stack) => stack,
+            Err(status) => return complete_request(irp, status),
+        };
+        let buffer = (*irp).AssociatedIrp.SystemBuffer;
+        let result = match Context::get((*stack).FileObject) {
+            None => Err(STATUS_INVALID_HANDLE),
+            Some(_) if control::is_control_device(device) => Err(STATUS_INVALID_DEVICE_REQUEST),
+            Some(context) if u32::from((*stack).MajorFunction) == IRP_MJ_WRITE => {
+                let length = (*stack).Parameters.Write.Length;
+                stream::write(context, &Request::new(buffer, length as _, 0))
+            }
+            Some(context) => {
+                let length = (*stack).Parameters.Read.Length;
+                stream::read(context, &mut Request::new(buffer, 0, length as _))
+            }
+        };
+        let (status, information) = match result {
+            Ok(information) => (STATUS_SUCCESS, information),
+            Err(status) => (status, 0),
+        };
+
+        (*irp).IoStatus.__bindgen_anon_1.Status = status;
+        (*irp).IoStatus.Information = information as _;
+        IofCompleteRequest(irp, IO_NO_INCREMENT as _);
+        status
+    }
+}
+
+/// Wraps the buffers of the IOCTL request `irp` with the transfer type of the
+/// IOCTL code.
+unsafe fn request(irp: PIRP, stack: PIO_STACK_LOCATION) -> Result<Request, NTSTATUS> {
+    let parameters = unsafe { (*stack).Parameters.DeviceIoControl };
+    let method = parameters.IoControlCode & 3;
+    let system_buffer = unsafe { (*irp).AssociatedIrp.SystemBuffer };
+    if method != METHOD_IN_DIRECT && method != METHOD_OUT_DIRECT {
+        return Ok(Request::new(
+            system_buffer,
+            parameters.InputBufferLength as _,
+            parameters.OutputBufferLength as _,
+        ));
+    }
+
+    // The MDL is null if the output buffer is empty.
+    let mdl = unsafe { (*irp).MdlAddress };
+    let direct = if mdl.is_null() {
+        ptr::null_mut()
+    } else {
+        let address = unsafe {
+            MmGetSystemAddressForMdlSafe(mdl, NormalPagePriority as ULONG | MdlMappingNoExecute)
+        };
+        if address.is_null() {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+        address
     };
+    Ok(Request::new_direct(
+        method,
+        system_buffer,
+        parameters.InputBufferLength as _,
+        direct,
+        parameters.OutputBufferLength as _,
+    ))
 }
 
-/// Reads from CR4.
-unsafe fn cr4() -> u64 {
-    let value;
-    unsafe { asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags)) };
-    value
+/// Returns a system-space address of the buffer described by `mdl`, mapping it
+/// if not yet, or null if it cannot be mapped.
+#[expect(non_snake_case, clippy::inline_always)]
+#[inline(always)]
+unsafe fn MmGetSystemAddressForMdlSafe(mdl: PMDL, priority: ULONG) -> PVOID {
+    unsafe {
+        let flags = u32::from((*mdl).MdlFlags.cast_unsigned());
+        if flags & (MDL_MAPPED_TO_SYSTEM_VA | MDL_SOURCE_IS_NONPAGED_POOL) != 0 {
+            (*mdl).MappedSystemVa
+        } else {
+            MmMapLockedPagesSpecifyCache(
+                mdl,
+                KernelMode as _,
+                MmCached,
+                ptr::null_mut(),
+                FALSE as _,
+                priority,
+            )
+        }
+    }
 }
 
-/// Writes to CR4.
-unsafe fn write_cr4(value: u64) {
-    unsafe { asm!("mov cr4, {}", in(reg) value, options(nomem, nostack, preserves_flags)) };
+/// Returns a pointer to the current stack location in an I/O Request Packet
+/// (IRP), or `STATUS_INVALID_PARAMETER` if the IRP is malformed. Unlike
+/// `IoGetCurrentIrpStackLocation`, whose `ASSERT` would bug check, the request
+/// can be failed instead.
+unsafe fn current_stack_location(irp: PIRP) -> Result<PIO_STACK_LOCATION, NTSTATUS> {
+    unsafe {
+        if (*irp).CurrentLocation > (*irp).StackCount + 1 {
+            trace!(
+                MALFORMED_IRP,
+                irp.addr(),
+                (*irp).CurrentLocation.cast_unsigned(),
+                (*irp).StackCount.cast_unsigned()
+            );
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        Ok((*irp)
+            .Tail
+            .Overlay
+            .__bindgen_anon_2
+            .__bindgen_anon_1
+            .CurrentStackLocation)
+    }
 }
 
-/// Returns a pointer to the current stack location in an I/O Request Packet (IRP).
+/// Checks whether `irp` was sent by a 32-bit process under WOW64.
+unsafe fn is_wow64(irp: PIRP) -> bool {
+    #[cfg(target_pointer_width = "64")]
+    {
+        unsafe { IoIs32bitProcess(irp) != 0 }
+    }
+    #[cfg(not(target_pointer_width = "64"))]
+    {
+        let _ = irp;
+        false
+    }
+}
+
+/// Makes the next lower driver receive the current stack location of `irp`.
 #[expect(non_snake_case, clippy::inline_always)]
 #[inline(always)]
-unsafe fn IoGetCurrentIrpStackLocation(irp: PIRP) -> PIO_STACK_LOCATION {
+unsafe fn IoSkipCurrentIrpStackLocation(irp: PIRP) {
     unsafe {
-        assert!((*irp).CurrentLocation <= (*irp).StackCount + 1);
-        (*irp)
+        (*irp).CurrentLocation += 1;
+        let location = &mut (*irp)
             .Tail
             .Overlay
             .__bindgen_anon_2
             .__bindgen_anon_1
-            .CurrentStackLocation
+            .CurrentStackLocation;
+        *location = location.add(1);
     }
 }
 
@@ -173,6 +512,14 @@ fn RTL_CONSTANT_STRING(utf16: &[u16]) -> UNICODE_STRING {
     }
 }
 
+/// Breaks into a kernel debugger if present and the `DEBUG_BREAK_*` mode is one
+/// of `modes`.
+pub(crate) fn debug_break(modes: &[u32]) {
+    if modes.contains(&config::debug_break()) && unsafe { KdRefreshDebuggerNotPresent() } == 0 {
+        arch::breakpoint();
+    }
+}
+
 /// Handles panic by breaking into a debugger if present and bug checking.
 #[cfg(not(test))]
 #[panic_handler]
@@ -180,10 +527,10 @@ fn handle_panic(info: &core::panic::PanicInfo<'_>) -> ! {
     const MANUALLY_INITIATED_CRASH: ULONG = 0x0000_00e2;
 
     wdk::println!("{info}");
-    unsafe {
-        if KdRefreshDebuggerNotPresent() == 0 {
-            asm!("int3", options(nomem, nostack, preserves_flags));
-        }
-        wdk_sys::ntddk::KeBugCheck(MANUALLY_INITIATED_CRASH);
-    }
+    debug_break(&[
+        DEBUG_BREAK_ON_LOAD,
+        DEBUG_BREAK_ON_EVERY_PAYLOAD,
+        DEBUG_BREAK_ON_PANIC_ONLY,
+    ]);
+    unsafe { wdk_sys::ntddk::KeBugCheck(MANUALLY_INITIATED_CRASH) };
 }
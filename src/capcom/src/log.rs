@@ -0,0 +1,283 @@
+//! The ring buffer of records of IOCTL callers, read with `IOCTL_READ_LOG`.
+//! When the buffer is full, the oldest record is overwritten. Records not read
+//! when Windows shuts down are saved as the `ShutdownLog` value of the
+//! `Parameters` subkey of the service key, and restored when the driver starts
+//! next.
+
+use core::slice;
+
+use capcom_abi::{IOCTL_SAMPLE_NMI, LogRecord, NmiSample};
+use wdk_sys::{
+    NTSTATUS, PEPROCESS, POOL_FLAG_NON_PAGED, REG_BINARY, STATUS_BUFFER_TOO_SMALL,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_NOT_SUPPORTED, ULONG,
+    ntddk::{
+        IoGetCurrentProcess, ObfDereferenceObject, PsGetCurrentProcessId, PsGetCurrentThreadId,
+    },
+};
+
+use crate::{
+    config, etw,
+    imports::{
+        PsDereferencePrimaryToken, PsGetProcessImageFileName, PsReferencePrimaryToken,
+        SeTokenIsAdmin,
+    },
+    ioctl::Request,
+    pool::{self, Tag},
+    process, registry, ring,
+    sync::SpinLock,
+    trace::trace,
+};
+
+/// The number of records the ring buffer holds.
+const CAPACITY: usize = 128;
+
+/// The ring buffer. It is in the non-paged data section of the image.
+static LOG: SpinLock<Ring<CAPACITY>> = SpinLock::new(Ring::new());
+
+/// Records the current process and thread as the sender of an IOCTL request
+/// with `control_code`, and writes them as an ETW event.
+pub(crate) fn record_caller(control_code: ULONG) {
+    let process = unsafe { IoGetCurrentProcess() };
+    let mut record = LogRecord {
+        sequence: 0,
+        process_id: unsafe { PsGetCurrentProcessId() }.addr() as u64,
+        thread_id: unsafe { PsGetCurrentThreadId() }.addr() as u64,
+        control_code,
+        elevated: u32::from(unsafe { is_elevated(process) }),
+        image_name: unsafe { image_name(process) },
+        rip: 0,
+    };
+    let length = record.image_name.iter().position(|&c| c == 0).unwrap_or(0);
+    let name = core::str::from_utf8(&record.image_name[..length]).unwrap_or("?");
+    etw::write(
+        etw::TRACE_LEVEL_INFORMATION,
+        format_args!(
+            "Process {} ({name}, elevated: {}) thread {} sent IOCTL {control_code:#x}",
+            record.process_id,
+            record.elevated != 0,
+            record.thread_id,
+        ),
+    );
+
+    // Push to the event ring under the lock too, so that records are in the
+    // order of sequence numbers there.
+    let mut log = LOG.lock();
+    record.sequence = log.sequence;
+    ring::push_log(&record);
+    log.push(record);
+}
+
+/// Records the process and thread `sample` interrupted, and the interrupted
+/// instruction pointer, as a record of `IOCTL_SAMPLE_NMI`. Must not be called
+/// from the NMI callback, which may interrupt the holder of the lock of the
+/// log.
+pub(crate) fn record_nmi_sample(sample: &NmiSample) {
+    // The process may have exited since, or be the idle process, which cannot
+    // be looked up. The image name is left empty then.
+    let (elevated, image_name) = match process::lookup(sample.process_id) {
+        Ok(process) => unsafe {
+            let info = (u32::from(is_elevated(process)), image_name(process));
+            let _ = ObfDereferenceObject(process.cast());
+            info
+        },
+        Err(_) => (0, [0; 16]),
+    };
+    let mut log = LOG.lock();
+    let record = LogRecord {
+        sequence: log.sequence,
+        process_id: sample.process_id,
+        thread_id: sample.thread_id,
+        control_code: IOCTL_SAMPLE_NMI,
+        elevated,
+        image_name,
+        rip: sample.rip,
+    };
+    log.push(record);
+}
+
+/// Handles `IOCTL_READ_LOG`, moving as many records as fit to the output
+/// buffer.
+pub(crate) fn read(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let mut log = LOG.lock();
+    let mut offset = 0;
+    while let Some(record) = log.front() {
+        if request.write_output_at(offset, record).is_err() {
+            break;
+        }
+        log.pop();
+        offset += size_of::<LogRecord>();
+    }
+    if offset == 0 && log.length != 0 {
+        return Err(STATUS_BUFFER_TOO_SMALL);
+    }
+    Ok(offset)
+}
+
+/// Saves the records not read yet, returning how many were saved.
+pub(crate) fn save() -> Result<usize, NTSTATUS> {
+    let Some(parameters_key) = config::parameters_key() else {
+        return Err(STATUS_NOT_SUPPORTED);
+    };
+    // Copied out, as the registry cannot be written under the lock.
+    let buffer = pool::allocate(
+        POOL_FLAG_NON_PAGED,
+        CAPACITY * size_of::<LogRecord>(),
+        Tag::Log,
+    )
+    .cast::<LogRecord>();
+    if buffer.is_null() {
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    let mut count = 0;
+    for record in LOG.lock().iter() {
+        unsafe { buffer.add(count).write(*record) };
+        count += 1;
+    }
+
+    let key_path = parameters_key.as_unicode_string();
+    let result = registry::create_key(&raw const key_path).and_then(|()| {
+        registry::write_value(
+            &raw const key_path,
+            &utf16_lit::utf16!("ShutdownLog"),
+            REG_BINARY,
+            unsafe { slice::from_raw_parts(buffer.cast(), count * size_of::<LogRecord>()) },
+        )
+    });
+    unsafe { pool::free(buffer.cast(), Tag::Log) };
+    result.map(|()| count)
+}
+
+/// Restores the records [`save`] saved, before any new record is made, and
+/// clears them so that they are not restored again if Windows does not shut
+/// down cleanly. Sequence numbers continue from them.
+pub(crate) fn restore() {
+    let Some(parameters_key) = config::parameters_key() else {
+        return;
+    };
+    let key_path = parameters_key.as_unicode_string();
+    let value_name = utf16_lit::utf16!("ShutdownLog");
+    let Ok(value) = registry::read_value(&raw const key_path, &value_name) else {
+        return;
+    };
+    let data = value.data();
+    if value.value_type() != REG_BINARY || data.is_empty() {
+        return;
+    }
+
+    let mut log = LOG.lock();
+    for chunk in data.chunks_exact(size_of::<LogRecord>()) {
+        let record = unsafe { chunk.as_ptr().cast::<LogRecord>().read_unaligned() };
+        log.sequence = record.sequence;
+        log.push(record);
+    }
+    let count = log.length;
+    drop(log);
+    trace!(LOG_RESTORED, count);
+    let _ = registry::write_value(&raw const key_path, &value_name, REG_BINARY, &[]);
+}
+
+/// Checks that [`Ring`] returns records in order and overwrites the oldest
+/// when full, with a small ring instead of the one of the callers.
+pub(crate) fn self_test() -> bool {
+    let mut ring = Ring::<4>::new();
+    for sequence in 0..6 {
+        ring.push(LogRecord {
+            sequence,
+            ..LogRecord::default()
+        });
+    }
+    (2..6).all(|sequence| {
+        let in_order = ring
+            .front()
+            .is_some_and(|record| record.sequence == sequence);
+        ring.pop();
+        in_order
+    }) && ring.front().is_none()
+}
+
+/// Returns the image file name of `process`, e.g., `"cmd.exe"`.
+unsafe fn image_name(process: PEPROCESS) -> [u8; 16] {
+    // `EPROCESS::ImageFileName` is a 15-byte array followed by a terminator.
+    let mut name = [0; 16];
+    let source = unsafe { PsGetProcessImageFileName(process) };
+    if !source.is_null() {
+        for (i, byte) in name.iter_mut().take(15).enumerate() {
+            *byte = unsafe { source.add(i).read() };
+            if *byte == 0 {
+                break;
+            }
+        }
+    }
+    name
+}
+
+/// Checks whether the primary token of `process` is elevated. The token of a
+/// non-elevated administrator has the Administrators group only for deny.
+pub(crate) unsafe fn is_elevated(process: PEPROCESS) -> bool {
+    unsafe {
+        let token = PsReferencePrimaryToken(process);
+        let elevated = SeTokenIsAdmin(token) != 0;
+        PsDereferencePrimaryToken(token);
+        elevated
+    }
+}
+
+/// Up to `N` records in the order they were made.
+struct Ring<const N: usize> {
+    records: [LogRecord; N],
+    /// The index of the oldest record.
+    head: usize,
+    /// The number of records held.
+    length: usize,
+    /// The sequence number of the next record.
+    sequence: u64,
+}
+
+impl<const N: usize> Ring<N> {
+    const fn new() -> Self {
+        const EMPTY: LogRecord = LogRecord {
+            sequence: 0,
+            process_id: 0,
+            thread_id: 0,
+            control_code: 0,
+            elevated: 0,
+            image_name: [0; 16],
+            rip: 0,
+        };
+        Self {
+            records: [EMPTY; N],
+            head: 0,
+            length: 0,
+            sequence: 0,
+        }
+    }
+
+    /// Appends `record`, overwriting the oldest record if full.
+    fn push(&mut self, record: LogRecord) {
+        self.records[(self.head + self.length) % N] = record;
+        if self.length == N {
+            self.head = (self.head + 1) % N;
+        } else {
+            self.length += 1;
+        }
+        self.sequence += 1;
+    }
+
+    /// Returns the oldest record.
+    fn front(&self) -> Option<&LogRecord> {
+        (self.length != 0).then(|| &self.records[self.head])
+    }
+
+    /// Returns the records, oldest first.
+    fn iter(&self) -> impl Iterator<Item = &LogRecord> {
+        (0..self.length).map(|i| &self.records[(self.head + i) % N])
+    }
+
+    /// Removes the oldest record.
+    fn pop(&mut self) {
+        if self.length != 0 {
+            self.head = (self.head + 1) % N;
+            self.length -= 1;
+        }
+    }
+}
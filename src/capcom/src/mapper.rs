@@ -0,0 +1,580 @@
+//! `IOCTL_MAP_DRIVER`, manual mapping of driver images that are not signed.
+//!
+//! The image is mapped into executable non-paged pool the way the loader would
+//! map it: sections are copied to their RVAs, base relocations are applied, and
+//! imports are resolved against the exports of loaded kernel modules. The entry
+//! point is then called with no driver object and registry path, so the driver
+//! must not depend on them, as with any other manual mapper. Only images of the
+//! native architecture are accepted.
+//!
+//! Mapped images are kept in a table until unmapped with `IOCTL_UNMAP_DRIVER`
+//! or this driver is unloaded, so repeatedly mapping drivers in a lab VM does
+//! not exhaust executable pool.
+
+use core::{mem, ops::Range, ptr, slice};
+
+use capcom_abi::{MAX_MAPPED_DRIVERS, MapDriverRequest, MappedDriver, UnmapDriverRequest};
+#[cfg(not(feature = "defanged"))]
+use wdk_sys::{NT_SUCCESS, PDRIVER_OBJECT, PUNICODE_STRING};
+use wdk_sys::{
+    NTSTATUS, POOL_FLAG_NON_PAGED_EXECUTE, STATUS_BUFFER_TOO_SMALL, STATUS_DLL_NOT_FOUND,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_IMAGE_FORMAT, STATUS_NOT_FOUND,
+    STATUS_NOT_SUPPORTED, STATUS_ORDINAL_NOT_FOUND, STATUS_PROCEDURE_NOT_FOUND,
+};
+
+use crate::{
+    arch,
+    ioctl::Request,
+    module,
+    paranoid::{self, Region},
+    payload,
+    pool::{self, Tag},
+    sync::SpinLock,
+    trace::trace,
+};
+
+/// `IMAGE_DOS_SIGNATURE`, "MZ".
+const IMAGE_DOS_SIGNATURE: u16 = 0x5a4d;
+
+/// `IMAGE_NT_SIGNATURE`, "PE\0\0".
+const IMAGE_NT_SIGNATURE: u32 = 0x4550;
+
+/// `IMAGE_FILE_MACHINE_*` of the native architecture.
+#[cfg(target_arch = "x86")]
+const IMAGE_FILE_MACHINE: u16 = 0x14c;
+#[cfg(target_arch = "x86_64")]
+const IMAGE_FILE_MACHINE: u16 = 0x8664;
+#[cfg(target_arch = "aarch64")]
+const IMAGE_FILE_MACHINE: u16 = 0xaa64;
+
+/// `IMAGE_NT_OPTIONAL_HDR_MAGIC`, the magic of the optional header, and offsets
+/// of the fields whose layout differs between PE32 and PE32+.
+#[cfg(target_pointer_width = "32")]
+mod optional_header {
+    pub(super) const MAGIC: u16 = 0x10b;
+    pub(super) const IMAGE_BASE: usize = 28;
+    pub(super) const NUMBER_OF_RVA_AND_SIZES: usize = 92;
+    pub(super) const DATA_DIRECTORY: usize = 96;
+}
+#[cfg(target_pointer_width = "64")]
+mod optional_header {
+    pub(super) const MAGIC: u16 = 0x20b;
+    pub(super) const IMAGE_BASE: usize = 24;
+    pub(super) const NUMBER_OF_RVA_AND_SIZES: usize = 108;
+    pub(super) const DATA_DIRECTORY: usize = 112;
+}
+
+/// `IMAGE_DIRECTORY_ENTRY_EXPORT`.
+const IMAGE_DIRECTORY_ENTRY_EXPORT: usize = 0;
+
+/// `IMAGE_DIRECTORY_ENTRY_IMPORT`.
+const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+
+/// `IMAGE_DIRECTORY_ENTRY_BASERELOC`.
+const IMAGE_DIRECTORY_ENTRY_BASERELOC: usize = 5;
+
+/// `IMAGE_REL_BASED_ABSOLUTE`, padding of relocation blocks.
+const IMAGE_REL_BASED_ABSOLUTE: u16 = 0;
+
+/// `IMAGE_REL_BASED_HIGHLOW` or `IMAGE_REL_BASED_DIR64`, relocation of a
+/// pointer.
+#[cfg(target_pointer_width = "32")]
+const IMAGE_REL_BASED_POINTER: u16 = 3;
+#[cfg(target_pointer_width = "64")]
+const IMAGE_REL_BASED_POINTER: u16 = 10;
+
+/// `IMAGE_ORDINAL_FLAG`, set in thunks importing by ordinal.
+const IMAGE_ORDINAL_FLAG: usize = 1 << (usize::BITS - 1);
+
+/// The size of `IMAGE_SECTION_HEADER`.
+const SECTION_HEADER_SIZE: usize = 40;
+
+/// The size of `IMAGE_IMPORT_DESCRIPTOR`.
+const IMPORT_DESCRIPTOR_SIZE: usize = 20;
+
+/// The mapped images. An entry with the zero base is reserved for an image
+/// being mapped.
+static MAPPED: SpinLock<[Option<MappedDriver>; MAX_MAPPED_DRIVERS]> =
+    SpinLock::new([None; MAX_MAPPED_DRIVERS]);
+
+#[cfg(not(feature = "defanged"))]
+type DriverEntry = unsafe extern "system" fn(PDRIVER_OBJECT, PUNICODE_STRING) -> NTSTATUS;
+
+/// Handles `IOCTL_MAP_DRIVER`. The defanged build maps the image to validate
+/// it, but only reports it instead of calling the entry point.
+pub(crate) fn map_driver(request: &mut Request) -> Result<usize, NTSTATUS> {
+    // Like shellcode, the image runs from pool, which HVCI does not allow.
+    if payload::is_hvci_enabled() {
+        trace!(MAPPING_REFUSED_FOR_HVCI);
+        return Err(STATUS_NOT_SUPPORTED);
+    }
+    let input = request.read_input::<MapDriverRequest>()?;
+    // The output buffer shares the memory with the input buffer, so check it
+    // can receive the result before running anything.
+    if request.output_mut().len() < size_of::<MappedDriver>() {
+        return Err(STATUS_BUFFER_TOO_SMALL);
+    }
+
+    // Reserve an entry first, as the image cannot be freed once it runs.
+    let slot = Slot::reserve()?;
+    let image = map(&request.input()[size_of::<MapDriverRequest>()..])?;
+    // Indirect branch tracking would fault on the call unless the entry point
+    // is a valid branch target.
+    if arch::cet().indirect_branch_tracking
+        && !image.bytes()[image.entry_point..].starts_with(arch::BRANCH_TARGET)
+    {
+        trace!(MAPPING_REFUSED_FOR_ENDBR);
+        return Err(STATUS_NOT_SUPPORTED);
+    }
+    payload::check_code(&image.bytes()[image.entry_point..])?;
+
+    #[cfg(feature = "defanged")]
+    {
+        drop(slot);
+        payload::report(format_args!(
+            "a driver image {} of {} bytes with the entry point at RVA {:#x}",
+            name(&input.name).escape_ascii(),
+            image.size,
+            image.entry_point
+        ));
+        Ok(0)
+    }
+    #[cfg(not(feature = "defanged"))]
+    {
+        let entry = image.address() + image.entry_point;
+        let status = unsafe {
+            arch::flush_instruction_cache(image.memory.cast(), image.size);
+            arch::serialize_instruction_fetch();
+            mem::transmute::<usize, DriverEntry>(entry)(ptr::null_mut(), ptr::null_mut())
+        };
+        trace!(DRIVER_MAPPED, image.address(), status; name(&input.name));
+        if !NT_SUCCESS(status) {
+            return Err(status);
+        }
+        let mapped = MappedDriver {
+            base: image.address() as u64,
+            entry: entry as u64,
+            size: image.size as u32,
+            status,
+            name: input.name,
+        };
+        // The driver is running now and must stay mapped until unmapped
+        // explicitly.
+        mem::forget(image);
+        slot.commit(mapped);
+        request.write_output(&mapped)
+    }
+}
+
+/// Handles `IOCTL_ENUM_MAPPED_DRIVERS` and returns the number of bytes written.
+pub(crate) fn enum_mapped_drivers(request: &mut Request) -> usize {
+    let mapped = *MAPPED.lock();
+    let mut offset = 0;
+    for driver in mapped.iter().flatten().filter(|driver| driver.base != 0) {
+        if request.write_output_at(offset, driver).is_err() {
+            break;
+        }
+        offset += size_of::<MappedDriver>();
+    }
+    offset
+}
+
+/// Handles `IOCTL_UNMAP_DRIVER`.
+pub(crate) fn unmap_driver(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<UnmapDriverRequest>()?;
+    let driver = MAPPED
+        .lock()
+        .iter_mut()
+        .find(|entry| entry.is_some_and(|driver| driver.base != 0 && driver.base == input.base))
+        .and_then(Option::take);
+    let Some(driver) = driver else {
+        return Err(STATUS_NOT_FOUND);
+    };
+    unsafe { unmap(&driver) };
+    Ok(0)
+}
+
+/// Unmaps all mapped images. Called when the driver is unloaded.
+pub(crate) fn unmap_all() {
+    let mapped = mem::take(&mut *MAPPED.lock());
+    for driver in mapped.iter().flatten().filter(|driver| driver.base != 0) {
+        unsafe { unmap(driver) };
+    }
+}
+
+/// Frees the image of `driver`. Nothing of the driver may be running.
+unsafe fn unmap(driver: &MappedDriver) {
+    trace!(DRIVER_UNMAPPING, driver.base; name(&driver.name));
+    unsafe {
+        pool::free(
+            ptr::without_provenance_mut(driver.base as usize),
+            Tag::Mapper,
+        )
+    };
+}
+
+/// Returns `name` without the padding.
+fn name(name: &[u8]) -> &[u8] {
+    let length = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+    &name[..length]
+}
+
+/// An entry of [`MAPPED`] reserved for an image being mapped, released when
+/// dropped unless committed.
+struct Slot(usize);
+
+impl Slot {
+    /// Reserves a free entry, or fails with `STATUS_INSUFFICIENT_RESOURCES` if
+    /// [`MAX_MAPPED_DRIVERS`] images are mapped.
+    fn reserve() -> Result<Self, NTSTATUS> {
+        let mut mapped = MAPPED.lock();
+        let Some(index) = mapped.iter().position(Option::is_none) else {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        };
+        mapped[index] = Some(MappedDriver::default());
+        Ok(Self(index))
+    }
+
+    /// Records `driver` in the entry.
+    #[cfg(not(feature = "defanged"))]
+    fn commit(self, driver: MappedDriver) {
+        MAPPED.lock()[self.0] = Some(driver);
+        mem::forget(self);
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        MAPPED.lock()[self.0] = None;
+    }
+}
+
+/// Maps the image `file` into a new image: copies the headers and sections,
+/// applies relocations and resolves imports.
+fn map(file: &[u8]) -> Result<Image, NTSTATUS> {
+    let headers = Headers::parse(file)?;
+    let entry_point = headers.entry_point as usize;
+    if entry_point == 0
+        || entry_point >= headers.image_size
+        || headers.header_size > headers.image_size
+    {
+        return Err(STATUS_INVALID_IMAGE_FORMAT);
+    }
+    let mut image = Image::allocate(headers.image_size, entry_point)?;
+    let bytes = image.bytes_mut();
+
+    let Some(header_bytes) = file.get(..headers.header_size) else {
+        return Err(STATUS_INVALID_IMAGE_FORMAT);
+    };
+    bytes[..header_bytes.len()].copy_from_slice(header_bytes);
+    for index in 0..headers.number_of_sections {
+        let section = headers.section(file, index)?;
+        // The rest of the section is zero as the pool is zeroed.
+        let length = match section.virtual_size {
+            0 => section.raw_size,
+            size => section.raw_size.min(size),
+        };
+        let source = file.get(section.raw_offset..section.raw_offset.saturating_add(length));
+        let target = bytes.get_mut(section.rva..section.rva.saturating_add(length));
+        let (Some(source), Some(target)) = (source, target) else {
+            return Err(STATUS_INVALID_IMAGE_FORMAT);
+        };
+        target.copy_from_slice(source);
+    }
+
+    let delta = image.address().wrapping_sub(headers.image_base);
+    let bytes = image.bytes_mut();
+    if let Some(relocations) = headers.directory(file, IMAGE_DIRECTORY_ENTRY_BASERELOC)? {
+        relocate(bytes, relocations, delta)?;
+    }
+    if let Some(imports) = headers.directory(file, IMAGE_DIRECTORY_ENTRY_IMPORT)? {
+        resolve_imports(bytes, imports.start)?;
+    }
+    Ok(image)
+}
+
+/// Adds `delta` to the pointers listed in the relocation blocks at `blocks`.
+fn relocate(image: &mut [u8], blocks: Range<usize>, delta: usize) -> Result<(), NTSTATUS> {
+    let mut block = blocks.start;
+    while block < blocks.end {
+        let page = read::<u32>(image, block)? as usize;
+        let block_size = read::<u32>(image, block + 4)? as usize;
+        if block_size < 8 {
+            return Err(STATUS_INVALID_IMAGE_FORMAT);
+        }
+        for index in 0..(block_size - 8) / 2 {
+            let entry = read::<u16>(image, block + 8 + index * 2)?;
+            let offset = page + usize::from(entry & 0xfff);
+            match entry >> 12 {
+                IMAGE_REL_BASED_ABSOLUTE => {}
+                IMAGE_REL_BASED_POINTER => {
+                    let value = read::<usize>(image, offset)?;
+                    write(image, offset, value.wrapping_add(delta))?;
+                }
+                _ => return Err(STATUS_INVALID_IMAGE_FORMAT),
+            }
+        }
+        block = block.saturating_add(block_size);
+    }
+    Ok(())
+}
+
+/// Fills the import address tables described by the import descriptors at
+/// `descriptors` with the addresses of the exports of loaded kernel modules.
+fn resolve_imports(image: &mut [u8], descriptors: usize) -> Result<(), NTSTATUS> {
+    let mut descriptor = descriptors;
+    loop {
+        let name = read::<u32>(image, descriptor + 12)? as usize;
+        if name == 0 {
+            return Ok(());
+        }
+        let original_first_thunk = read::<u32>(image, descriptor)? as usize;
+        let first_thunk = read::<u32>(image, descriptor + 16)? as usize;
+        let name = c_str(image, name)?;
+        let Ok(info) = module::find(Some(name)) else {
+            trace!(MODULE_NOT_LOADED; name);
+            return Err(STATUS_DLL_NOT_FOUND);
+        };
+        let module = unsafe { slice::from_raw_parts(info.base as *const u8, info.size as usize) };
+
+        // Old linkers leave the lookup table empty, and only fill the import
+        // address table.
+        let lookup = match original_first_thunk {
+            0 => first_thunk,
+            rva => rva,
+        };
+        for index in 0.. {
+            let offset = index * size_of::<usize>();
+            let thunk = read::<usize>(image, lookup + offset)?;
+            if thunk == 0 {
+                break;
+            }
+            let import = if thunk & IMAGE_ORDINAL_FLAG == 0 {
+                // Skip the hint of `IMAGE_IMPORT_BY_NAME`.
+                Import::Name(c_str(image, thunk + 2)?)
+            } else {
+                Import::Ordinal(thunk as u16)
+            };
+            let address = find_export(module, &import)?;
+            write(image, first_thunk + offset, address)?;
+        }
+        descriptor += IMPORT_DESCRIPTOR_SIZE;
+    }
+}
+
+/// An imported function.
+enum Import<'a> {
+    Name(&'a [u8]),
+    Ordinal(u16),
+}
+
+/// Returns the address of the function `import` exported by the loaded
+/// `module`. Forwarded exports are not supported.
+fn find_export(module: &[u8], import: &Import<'_>) -> Result<usize, NTSTATUS> {
+    let headers = Headers::parse(module)?;
+    let Some(exports) = headers.directory(module, IMAGE_DIRECTORY_ENTRY_EXPORT)? else {
+        return Err(STATUS_PROCEDURE_NOT_FOUND);
+    };
+    paranoid::check(
+        module.as_ptr().addr() + exports.start,
+        exports.len(),
+        Region::Module,
+    )?;
+    let directory = exports.start;
+    let ordinal_base = read::<u32>(module, directory + 16)?;
+    let number_of_functions = read::<u32>(module, directory + 20)?;
+    let number_of_names = read::<u32>(module, directory + 24)? as usize;
+    let functions = read::<u32>(module, directory + 28)? as usize;
+    let names = read::<u32>(module, directory + 32)? as usize;
+    let name_ordinals = read::<u32>(module, directory + 36)? as usize;
+
+    let (index, not_found) = match *import {
+        Import::Name(name) => {
+            let mut index = None;
+            for position in 0..number_of_names {
+                let rva = read::<u32>(module, names + position * 4)? as usize;
+                if c_str(module, rva)? == name {
+                    index = Some(u32::from(read::<u16>(
+                        module,
+                        name_ordinals + position * 2,
+                    )?));
+                    break;
+                }
+            }
+            (index, STATUS_PROCEDURE_NOT_FOUND)
+        }
+        Import::Ordinal(ordinal) => (
+            u32::from(ordinal).checked_sub(ordinal_base),
+            STATUS_ORDINAL_NOT_FOUND,
+        ),
+    };
+    let function = match index.filter(|&index| index < number_of_functions) {
+        Some(index) => read::<u32>(module, functions + index as usize * 4)? as usize,
+        None => 0,
+    };
+    if function == 0 || exports.contains(&function) {
+        match *import {
+            Import::Name(name) => trace!(NAME_NOT_EXPORTED; name),
+            Import::Ordinal(ordinal) => trace!(ORDINAL_NOT_EXPORTED, ordinal),
+        }
+        return Err(not_found);
+    }
+    Ok(module.as_ptr().addr() + function)
+}
+
+/// The fields of the headers of a PE image the mapper uses.
+struct Headers {
+    entry_point: u32,
+    image_base: usize,
+    image_size: usize,
+    header_size: usize,
+    number_of_sections: usize,
+    sections: usize,
+    number_of_directories: usize,
+    directories: usize,
+}
+
+/// The location of a section in the file and in the image.
+struct Section {
+    rva: usize,
+    virtual_size: usize,
+    raw_offset: usize,
+    raw_size: usize,
+}
+
+impl Headers {
+    /// Validates the headers at the start of `bytes`, a file or a mapped image,
+    /// and returns their fields.
+    fn parse(bytes: &[u8]) -> Result<Self, NTSTATUS> {
+        if read::<u16>(bytes, 0)? != IMAGE_DOS_SIGNATURE {
+            return Err(STATUS_INVALID_IMAGE_FORMAT);
+        }
+        let nt_headers = read::<u32>(bytes, 0x3c)? as usize;
+        if read::<u32>(bytes, nt_headers)? != IMAGE_NT_SIGNATURE {
+            return Err(STATUS_INVALID_IMAGE_FORMAT);
+        }
+        let file_header = nt_headers + 4;
+        let optional_header = file_header + 20;
+        if read::<u16>(bytes, file_header)? != IMAGE_FILE_MACHINE
+            || read::<u16>(bytes, optional_header)? != optional_header::MAGIC
+        {
+            return Err(STATUS_INVALID_IMAGE_FORMAT);
+        }
+        let number_of_sections = usize::from(read::<u16>(bytes, file_header + 2)?);
+        let sections = optional_header + usize::from(read::<u16>(bytes, file_header + 16)?);
+        let headers = Self {
+            entry_point: read(bytes, optional_header + 16)?,
+            image_base: read(bytes, optional_header + optional_header::IMAGE_BASE)?,
+            image_size: read::<u32>(bytes, optional_header + 56)? as usize,
+            header_size: read::<u32>(bytes, optional_header + 60)? as usize,
+            number_of_sections,
+            sections,
+            number_of_directories: read::<u32>(
+                bytes,
+                optional_header + optional_header::NUMBER_OF_RVA_AND_SIZES,
+            )? as usize,
+            directories: optional_header + optional_header::DATA_DIRECTORY,
+        };
+        // The section table must be within the file.
+        let _ = read::<[u8; SECTION_HEADER_SIZE]>(
+            bytes,
+            sections + number_of_sections.saturating_sub(1) * SECTION_HEADER_SIZE,
+        )?;
+        Ok(headers)
+    }
+
+    /// Returns the RVA range of the data directory at `index`, or `None` if
+    /// the image lacks it.
+    fn directory(&self, bytes: &[u8], index: usize) -> Result<Option<Range<usize>>, NTSTATUS> {
+        if index >= self.number_of_directories {
+            return Ok(None);
+        }
+        let entry = self.directories + index * 8;
+        let rva = read::<u32>(bytes, entry)? as usize;
+        let size = read::<u32>(bytes, entry + 4)? as usize;
+        Ok((rva != 0 && size != 0).then(|| rva..rva.saturating_add(size)))
+    }
+
+    /// Returns the section at `index` of the section table.
+    fn section(&self, bytes: &[u8], index: usize) -> Result<Section, NTSTATUS> {
+        let header = self.sections + index * SECTION_HEADER_SIZE;
+        Ok(Section {
+            virtual_size: read::<u32>(bytes, header + 8)? as usize,
+            rva: read::<u32>(bytes, header + 12)? as usize,
+            raw_size: read::<u32>(bytes, header + 16)? as usize,
+            raw_offset: read::<u32>(bytes, header + 20)? as usize,
+        })
+    }
+}
+
+/// A mapped image in executable non-paged pool, freed on drop.
+struct Image {
+    memory: *mut u8,
+    size: usize,
+    entry_point: usize,
+}
+
+impl Image {
+    /// Allocates a zeroed image of `size` bytes.
+    fn allocate(size: usize, entry_point: usize) -> Result<Self, NTSTATUS> {
+        let memory = pool::allocate(POOL_FLAG_NON_PAGED_EXECUTE, size, Tag::Mapper);
+        if memory.is_null() {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+        Ok(Self {
+            memory: memory.cast(),
+            size,
+            entry_point,
+        })
+    }
+
+    /// Returns the address of the image.
+    fn address(&self) -> usize {
+        self.memory.addr()
+    }
+
+    /// Returns the bytes of the image.
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.memory, self.size) }
+    }
+
+    /// Returns the bytes of the image to write.
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.memory, self.size) }
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe { pool::free(self.memory.cast(), Tag::Mapper) };
+    }
+}
+
+/// Reads `T` at `offset` bytes into `bytes`. `T` must be valid for any bytes.
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Result<T, NTSTATUS> {
+    match bytes.get(offset..offset.saturating_add(size_of::<T>())) {
+        Some(bytes) if bytes.len() == size_of::<T>() => {
+            Ok(unsafe { bytes.as_ptr().cast::<T>().read_unaligned() })
+        }
+        _ => Err(STATUS_INVALID_IMAGE_FORMAT),
+    }
+}
+
+/// Writes `value` at `offset` bytes into `bytes`.
+fn write(bytes: &mut [u8], offset: usize, value: usize) -> Result<(), NTSTATUS> {
+    let Some(target) = bytes.get_mut(offset..offset.saturating_add(size_of::<usize>())) else {
+        return Err(STATUS_INVALID_IMAGE_FORMAT);
+    };
+    target.copy_from_slice(&value.to_ne_bytes());
+    Ok(())
+}
+
+/// Returns the null-terminated string at `offset` bytes into `bytes`, without
+/// the terminator.
+fn c_str(bytes: &[u8], offset: usize) -> Result<&[u8], NTSTATUS> {
+    let string = bytes.get(offset..).unwrap_or_default();
+    match string.iter().position(|&c| c == 0) {
+        Some(length) => Ok(&string[..length]),
+        None => Err(STATUS_INVALID_IMAGE_FORMAT),
+    }
+}
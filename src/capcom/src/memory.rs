@@ -0,0 +1,93 @@
+//! `IOCTL_ALLOC_CONTIGUOUS` and `IOCTL_FREE_CONTIGUOUS`, allocating physically
+//! contiguous memory, e.g., for DMA and for page tables at known physical
+//! addresses.
+//!
+//! Allocations are owned by the handle that made them and freed when it is
+//! closed if not freed explicitly, so a crashing client does not leak memory
+//! that is scarce once physical memory is fragmented.
+
+use core::ptr;
+
+use capcom_abi::{ContiguousAllocRequest, ContiguousAllocation, ContiguousFreeRequest};
+use wdk_sys::{
+    _MEMORY_CACHING_TYPE::{MmCached, MmNonCached, MmWriteCombined},
+    NTSTATUS, PHYSICAL_ADDRESS, PVOID, STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER,
+    ntddk::MmGetPhysicalAddress,
+};
+
+use crate::{
+    context::Context,
+    imports::{MmAllocateContiguousMemorySpecifyCache, MmFreeContiguousMemory},
+    ioctl::Request,
+    trace::trace,
+};
+
+/// Handles `IOCTL_ALLOC_CONTIGUOUS`.
+pub(crate) fn alloc_contiguous(
+    context: &Context,
+    request: &mut Request,
+) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<ContiguousAllocRequest>()?;
+    let cache_type = match input.cache_type {
+        0 => MmNonCached,
+        1 => MmCached,
+        2 => MmWriteCombined,
+        _ => return Err(STATUS_INVALID_PARAMETER),
+    };
+    let Ok(size) = usize::try_from(input.size) else {
+        return Err(STATUS_INVALID_PARAMETER);
+    };
+    if size == 0 {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    let highest_address = match input.highest_address {
+        0 => u64::MAX,
+        address => address,
+    };
+
+    let address = unsafe {
+        MmAllocateContiguousMemorySpecifyCache(
+            size as _,
+            PHYSICAL_ADDRESS { QuadPart: 0 },
+            PHYSICAL_ADDRESS {
+                QuadPart: highest_address.cast_signed(),
+            },
+            PHYSICAL_ADDRESS { QuadPart: 0 },
+            cache_type,
+        )
+    };
+    if address.is_null() {
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    // The memory is not zeroed, and may hold data of previous owners.
+    unsafe { ptr::write_bytes(address.cast::<u8>(), 0, size) };
+    if let Err(status) = context.add_resource(address, free) {
+        unsafe { free(address) };
+        return Err(status);
+    }
+
+    let allocation = ContiguousAllocation {
+        address: address.addr() as u64,
+        physical_address: unsafe { MmGetPhysicalAddress(address).QuadPart }.cast_unsigned(),
+    };
+    trace!(
+        CONTIGUOUS_MEMORY_ALLOCATED,
+        size, allocation.address, allocation.physical_address
+    );
+    // Free the memory the caller would not know about.
+    request.write_output(&allocation).inspect_err(|_| {
+        let _ = context.release_resource(address);
+    })
+}
+
+/// Handles `IOCTL_FREE_CONTIGUOUS`.
+pub(crate) fn free_contiguous(context: &Context, request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<ContiguousFreeRequest>()?;
+    context.release_resource(ptr::without_provenance_mut(input.address as usize))?;
+    Ok(0)
+}
+
+/// Frees the memory allocated with [`alloc_contiguous`].
+unsafe fn free(address: PVOID) {
+    unsafe { MmFreeContiguousMemory(address) };
+}
@@ -0,0 +1,48 @@
+//! Access to device registers mapped from physical addresses, such as those of
+//! the local APIC and the PCI configuration space.
+
+use core::ptr;
+
+use wdk_sys::{
+    _MEMORY_CACHING_TYPE::MmNonCached,
+    NTSTATUS, PAGE_SIZE, PHYSICAL_ADDRESS, STATUS_INSUFFICIENT_RESOURCES,
+    ntddk::{MmMapIoSpace, MmUnmapIoSpace},
+};
+
+/// A page of registers mapped as non-cached, unmapped when dropped.
+pub(crate) struct Mmio(*mut u8);
+
+impl Mmio {
+    /// Maps the page at the physical address `address`.
+    pub(crate) fn map(address: u64) -> Result<Self, NTSTATUS> {
+        let address = PHYSICAL_ADDRESS {
+            QuadPart: address.cast_signed(),
+        };
+        let mmio = unsafe { MmMapIoSpace(address, PAGE_SIZE as _, MmNonCached) }.cast::<u8>();
+        if mmio.is_null() {
+            Err(STATUS_INSUFFICIENT_RESOURCES)
+        } else {
+            Ok(Self(mmio))
+        }
+    }
+
+    /// Reads the register at `offset`. `offset` must be within the page and
+    /// aligned for `T`.
+    pub(crate) unsafe fn read<T: Copy>(&self, offset: usize) -> T {
+        debug_assert!(offset + size_of::<T>() <= PAGE_SIZE as usize);
+        unsafe { ptr::read_volatile(self.0.add(offset).cast::<T>()) }
+    }
+
+    /// Writes to the register at `offset`. `offset` must be within the page and
+    /// aligned for `T`.
+    pub(crate) unsafe fn write<T: Copy>(&self, offset: usize, value: T) {
+        debug_assert!(offset + size_of::<T>() <= PAGE_SIZE as usize);
+        unsafe { ptr::write_volatile(self.0.add(offset).cast::<T>(), value) };
+    }
+}
+
+impl Drop for Mmio {
+    fn drop(&mut self) {
+        unsafe { MmUnmapIoSpace(self.0.cast(), PAGE_SIZE as _) };
+    }
+}
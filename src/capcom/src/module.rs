@@ -0,0 +1,165 @@
+//! `IOCTL_GET_KERNEL_BASE`, locating ntoskrnl.exe and other kernel modules in
+//! memory.
+//!
+//! The driver queries the list of modules with `ZwQuerySystemInformation` from
+//! kernel-mode, which returns their addresses regardless of the integrity level
+//! of the client. User-mode callers below medium integrity get no addresses
+//! from the same query.
+
+use core::{ops::Range, ptr, slice};
+
+use capcom_abi::{ModuleInfo, ModuleRequest};
+use wdk_sys::{
+    NT_SUCCESS, NTSTATUS, POOL_FLAG_PAGED, PVOID, STATUS_INFO_LENGTH_MISMATCH,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER, STATUS_NOT_FOUND, ULONG,
+};
+
+use crate::{
+    imports::ZwQuerySystemInformation,
+    ioctl::Request,
+    pool::{self, Tag},
+};
+
+/// `SystemModuleInformation` of `SYSTEM_INFORMATION_CLASS`.
+const SYSTEM_MODULE_INFORMATION: ULONG = 11;
+
+/// `RTL_PROCESS_MODULE_INFORMATION`.
+#[repr(C)]
+struct ModuleEntry {
+    section: PVOID,
+    mapped_base: PVOID,
+    image_base: PVOID,
+    image_size: u32,
+    flags: u32,
+    load_order_index: u16,
+    init_order_index: u16,
+    load_count: u16,
+    offset_to_file_name: u16,
+    full_path_name: [u8; 256],
+}
+
+/// `RTL_PROCESS_MODULES`.
+#[repr(C)]
+struct ModuleList {
+    number_of_modules: u32,
+    modules: [ModuleEntry; 1],
+}
+
+/// Handles `IOCTL_GET_KERNEL_BASE`.
+pub(crate) fn get_kernel_base(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<ModuleRequest>()?;
+    let name = match input.name_length as usize {
+        0 => None,
+        length => {
+            let offset = size_of::<ModuleRequest>();
+            let Some(name) = request.input().get(offset..offset.saturating_add(length)) else {
+                return Err(STATUS_INVALID_PARAMETER);
+            };
+            Some(name)
+        }
+    };
+    let info = find(name)?;
+    request.write_output(&info)
+}
+
+/// Returns the address and size of the module whose file name is `name`, e.g.,
+/// `CI.dll`, case-insensitively, or of ntoskrnl.exe if `None`.
+pub(crate) fn find(name: Option<&[u8]>) -> Result<ModuleInfo, NTSTATUS> {
+    let modules = Modules::query()?;
+    let entries = modules.entries();
+    let entry = match name {
+        // ntoskrnl.exe is always loaded first, whatever its file name is.
+        None => entries.first(),
+        Some(name) => entries.iter().find(|entry| {
+            let path = &entry.full_path_name;
+            let file_name = path
+                .get(usize::from(entry.offset_to_file_name)..)
+                .unwrap_or_default();
+            let length = file_name
+                .iter()
+                .position(|&c| c == 0)
+                .unwrap_or(file_name.len());
+            file_name[..length].eq_ignore_ascii_case(name)
+        }),
+    };
+    let Some(entry) = entry else {
+        return Err(STATUS_NOT_FOUND);
+    };
+    Ok(ModuleInfo {
+        base: entry.image_base.addr() as u64,
+        size: entry.image_size,
+        reserved: 0,
+    })
+}
+
+/// Checks whether `range` is within the image of a loaded kernel module.
+pub(crate) fn contains(range: &Range<usize>) -> bool {
+    let Ok(modules) = Modules::query() else {
+        return false;
+    };
+    modules.entries().iter().any(|entry| {
+        let base = entry.image_base.addr();
+        base <= range.start && range.end <= base + entry.image_size as usize
+    })
+}
+
+/// The list of loaded kernel modules, in paged pool.
+struct Modules(*mut ModuleList);
+
+impl Modules {
+    /// Queries the list of loaded kernel modules.
+    fn query() -> Result<Self, NTSTATUS> {
+        // Modules may be loaded in between, so retry until the buffer is large
+        // enough.
+        let mut length = 0;
+        let status = unsafe {
+            ZwQuerySystemInformation(
+                SYSTEM_MODULE_INFORMATION,
+                ptr::null_mut(),
+                0,
+                &raw mut length,
+            )
+        };
+        if status != STATUS_INFO_LENGTH_MISMATCH {
+            return Err(status);
+        }
+        loop {
+            let list =
+                pool::allocate(POOL_FLAG_PAGED, length as usize, Tag::Module).cast::<ModuleList>();
+            if list.is_null() {
+                return Err(STATUS_INSUFFICIENT_RESOURCES);
+            }
+            let modules = Self(list);
+            let status = unsafe {
+                ZwQuerySystemInformation(
+                    SYSTEM_MODULE_INFORMATION,
+                    list.cast(),
+                    length,
+                    &raw mut length,
+                )
+            };
+            if NT_SUCCESS(status) {
+                return Ok(modules);
+            }
+            if status != STATUS_INFO_LENGTH_MISMATCH {
+                return Err(status);
+            }
+        }
+    }
+
+    /// Returns the entries of the modules in the load order.
+    fn entries(&self) -> &[ModuleEntry] {
+        unsafe {
+            slice::from_raw_parts(
+                (&raw const (*self.0).modules).cast(),
+                (*self.0).number_of_modules as usize,
+            )
+        }
+    }
+}
+
+impl Drop for Modules {
+    fn drop(&mut self) {
+        unsafe { pool::free(self.0.cast(), Tag::Module) };
+    }
+}
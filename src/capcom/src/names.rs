@@ -0,0 +1,79 @@
+//! The names of the devices and the symbolic links to them. They are those of
+//! the original driver unless the `StealthSeed` value of the service key is
+//! nonzero, in which case they are generated with `capcom_abi::stealth_name`
+//! every boot, so scanners looking for `Htsysm72FB` do not find them.
+
+use capcom_abi::{
+    CONTROL_DEVICE_NAME_UTF16, CONTROL_LINK_NAME_UTF16, DEVICE_NAME_UTF16, LINK_NAME_UTF16,
+    STEALTH_NAME_LENGTH, stealth_name,
+};
+
+use crate::config;
+
+/// The address of `BootId` of `KUSER_SHARED_DATA`, which is incremented every
+/// boot.
+#[cfg(not(target_arch = "x86"))]
+const BOOT_ID: usize = 0xffff_f780_0000_02c4;
+#[cfg(target_arch = "x86")]
+const BOOT_ID: usize = 0xffdf_02c4;
+
+/// The longest name.
+const MAX_LENGTH: usize = CONTROL_LINK_NAME_UTF16.len();
+
+/// A name in UTF-16.
+pub(crate) struct Name {
+    buffer: [u16; MAX_LENGTH],
+    length: usize,
+}
+
+impl Name {
+    /// Returns the name.
+    pub(crate) fn as_slice(&self) -> &[u16] {
+        &self.buffer[..self.length]
+    }
+}
+
+/// Returns the name of the compatible device, or the control device if
+/// `control`.
+pub(crate) fn device(control: bool) -> Name {
+    let original = if control {
+        &CONTROL_DEVICE_NAME_UTF16[..]
+    } else {
+        &DEVICE_NAME_UTF16
+    };
+    build(&utf16_lit::utf16!("\\Device\\"), original, control)
+}
+
+/// Returns the name of the symbolic link to the compatible device, or the
+/// control device if `control`.
+pub(crate) fn link(control: bool) -> Name {
+    let original = if control {
+        &CONTROL_LINK_NAME_UTF16[..]
+    } else {
+        &LINK_NAME_UTF16
+    };
+    build(&utf16_lit::utf16!("\\DosDevices\\"), original, control)
+}
+
+/// Returns `original`, or `prefix` followed by the stealth name.
+fn build(prefix: &[u16], original: &[u16], control: bool) -> Name {
+    let mut name = Name {
+        buffer: [0; MAX_LENGTH],
+        length: 0,
+    };
+    let seed = config::stealth_seed();
+    if seed == 0 {
+        name.buffer[..original.len()].copy_from_slice(original);
+        name.length = original.len();
+        return name;
+    }
+
+    let boot_id = unsafe { (BOOT_ID as *const u32).read_volatile() };
+    let stealth = stealth_name(seed, boot_id, control);
+    name.buffer[..prefix.len()].copy_from_slice(prefix);
+    for (c, &byte) in name.buffer[prefix.len()..].iter_mut().zip(&stealth) {
+        *c = u16::from(byte);
+    }
+    name.length = prefix.len() + STEALTH_NAME_LENGTH;
+    name
+}
@@ -0,0 +1,192 @@
+//! `IOCTL_SET_NMI_CALLBACK` and `IOCTL_SAMPLE_NMI`, sampling what a processor
+//! runs by sending it an NMI. Unlike DPCs and IPIs, NMIs are delivered even
+//! while interrupts are disabled.
+//!
+//! The callback runs at `HIGH_LEVEL` and may interrupt any code, including
+//! code holding spin locks, so it only stores the sample into atomics. The
+//! request records the sample to the log after the callback.
+//!
+//! Windows bug checks when no callback handles an NMI. The callback therefore
+//! counts NMIs sent by the driver and claims that many, even if the request
+//! waiting for one already timed out.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, Ordering},
+};
+
+use capcom_abi::{NmiCallbackRequest, NmiSample, NmiSampleRequest};
+use wdk_sys::{
+    BOOLEAN, NTSTATUS, PVOID, STATUS_DEVICE_BUSY, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_DEVICE_STATE, STATUS_TIMEOUT, TRUE,
+    ntddk::{
+        KeGetCurrentProcessorNumberEx, KeQueryUnbiasedInterruptTime, PsGetCurrentProcessId,
+        PsGetCurrentThreadId,
+    },
+};
+
+use crate::{
+    apic, arch, etw,
+    imports::{KeDeregisterNmiCallback, KeRegisterNmiCallback},
+    ioctl::Request,
+    log, processor,
+};
+
+/// How long to wait for the NMI in 100ns units.
+const TIMEOUT: u64 = 1_000_000;
+
+/// The handle of the registered callback, or null.
+static CALLBACK: AtomicPtr<core::ffi::c_void> = AtomicPtr::new(ptr::null_mut());
+
+/// Whether a request is waiting for a sample.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// The number of NMIs sent and claimed by the callback.
+static SENT: AtomicU64 = AtomicU64::new(0);
+static CLAIMED: AtomicU64 = AtomicU64::new(0);
+
+/// The system-wide processor index plus one the sample is requested for, or
+/// zero.
+static TARGET: AtomicU32 = AtomicU32::new(0);
+
+/// Whether [`SAMPLE`] was filled for the current request.
+static SAMPLED: AtomicBool = AtomicBool::new(false);
+
+/// The sample as RIP, CS, RFLAGS, RSP, SS, the process ID and the thread ID.
+static SAMPLE: [AtomicU64; 7] = [const { AtomicU64::new(0) }; 7];
+
+/// Handles `IOCTL_SET_NMI_CALLBACK`.
+pub(crate) fn set_nmi_callback(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<NmiCallbackRequest>()?;
+    if input.enable == 0 {
+        // NMIs sent but not delivered yet would cause a bug check without the
+        // callback.
+        if BUSY.swap(true, Ordering::AcqRel) {
+            return Err(STATUS_DEVICE_BUSY);
+        }
+        let pending = CLAIMED.load(Ordering::Acquire) != SENT.load(Ordering::Acquire);
+        if !pending {
+            unregister();
+        }
+        BUSY.store(false, Ordering::Release);
+        return if pending {
+            Err(STATUS_DEVICE_BUSY)
+        } else {
+            Ok(0)
+        };
+    }
+    if !CALLBACK.load(Ordering::Acquire).is_null() {
+        return Ok(0);
+    }
+
+    let handle = unsafe { KeRegisterNmiCallback(Some(nmi_callback), ptr::null_mut()) };
+    if handle.is_null() {
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    if CALLBACK
+        .compare_exchange(ptr::null_mut(), handle, Ordering::AcqRel, Ordering::Acquire)
+        .is_err()
+    {
+        let _ = unsafe { KeDeregisterNmiCallback(handle) };
+    }
+    Ok(0)
+}
+
+/// Unregisters the callback if registered.
+pub(crate) fn unregister() {
+    let handle = CALLBACK.swap(ptr::null_mut(), Ordering::AcqRel);
+    if !handle.is_null() {
+        let _ = unsafe { KeDeregisterNmiCallback(handle) };
+    }
+}
+
+/// Handles `IOCTL_SAMPLE_NMI`.
+pub(crate) fn sample_nmi(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<NmiSampleRequest>()?;
+    let apic_id = processor::run_on(input.processor, apic::id)??;
+    if BUSY.swap(true, Ordering::AcqRel) {
+        return Err(STATUS_DEVICE_BUSY);
+    }
+    let result = send_and_wait(input.processor, apic_id);
+    BUSY.store(false, Ordering::Release);
+    result?;
+
+    let load = |i: usize| SAMPLE[i].load(Ordering::Acquire);
+    let sample = NmiSample {
+        rip: load(0),
+        rflags: load(2),
+        rsp: load(3),
+        process_id: load(5),
+        thread_id: load(6),
+        cs: load(1) as u16,
+        ss: load(4) as u16,
+        processor: input.processor,
+    };
+    etw::write(
+        etw::TRACE_LEVEL_INFORMATION,
+        format_args!(
+            "NMI on processor {} interrupted process {} thread {} at {:#x}",
+            sample.processor, sample.process_id, sample.thread_id, sample.rip,
+        ),
+    );
+    log::record_nmi_sample(&sample);
+    request.write_output(&sample)
+}
+
+/// Sends an NMI to the processor with the system-wide index `index` and
+/// `apic_id`, and waits for the callback to fill [`SAMPLE`]. The caller must
+/// hold [`BUSY`].
+fn send_and_wait(index: u32, apic_id: u32) -> Result<(), NTSTATUS> {
+    if CALLBACK.load(Ordering::Acquire).is_null() {
+        return Err(STATUS_INVALID_DEVICE_STATE);
+    }
+    SAMPLED.store(false, Ordering::Release);
+    TARGET.store(index + 1, Ordering::Release);
+    let _ = SENT.fetch_add(1, Ordering::AcqRel);
+    let result = apic::send_nmi(apic_id);
+    if result.is_err() {
+        let _ = SENT.fetch_sub(1, Ordering::AcqRel);
+    }
+    let result = result.and_then(|()| wait());
+    TARGET.store(0, Ordering::Release);
+    result
+}
+
+/// Waits for the callback to fill [`SAMPLE`].
+fn wait() -> Result<(), NTSTATUS> {
+    let start = unsafe { KeQueryUnbiasedInterruptTime() };
+    while !SAMPLED.load(Ordering::Acquire) {
+        if unsafe { KeQueryUnbiasedInterruptTime() } - start > TIMEOUT {
+            return Err(STATUS_TIMEOUT);
+        }
+        core::hint::spin_loop();
+    }
+    Ok(())
+}
+
+/// Claims NMIs the driver sent, and samples the interrupted context if the
+/// current processor is the target of the request. Runs at `HIGH_LEVEL`.
+unsafe extern "C" fn nmi_callback(_context: PVOID, handled: BOOLEAN) -> BOOLEAN {
+    let claimed = CLAIMED
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |claimed| {
+            (claimed < SENT.load(Ordering::Acquire)).then_some(claimed + 1)
+        })
+        .is_ok();
+    if !claimed {
+        return handled;
+    }
+
+    let index = unsafe { KeGetCurrentProcessorNumberEx(ptr::null_mut()) };
+    if TARGET.load(Ordering::Acquire) == index + 1 {
+        let frame = arch::nmi_frame().unwrap_or_default();
+        let ids = unsafe { [PsGetCurrentProcessId(), PsGetCurrentThreadId()] };
+        for (slot, value) in SAMPLE
+            .iter()
+            .zip(frame.into_iter().chain(ids.map(|id| id.addr() as u64)))
+        {
+            slot.store(value, Ordering::Relaxed);
+        }
+        SAMPLED.store(true, Ordering::Release);
+    }
+    TRUE as _
+}
@@ -0,0 +1,145 @@
+//! `IOCTL_ENUM_DIRECTORY`, listing objects in the object manager namespace.
+//!
+//! The directory is opened with a kernel handle, so the access check against
+//! the caller is skipped and directories such as `\Driver` can be listed
+//! without privileges.
+
+use core::{ptr, slice};
+
+use capcom_abi::{
+    DIRECTORY_NAME_LENGTH, DIRECTORY_TYPE_NAME_LENGTH, DirectoryEntry, EnumDirectoryRequest,
+};
+use wdk_sys::{
+    ACCESS_MASK, FALSE, HANDLE, NT_SUCCESS, NTSTATUS, OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE,
+    OBJECT_ATTRIBUTES, STATUS_INVALID_PARAMETER, STATUS_NO_MORE_ENTRIES, TRUE, UNICODE_STRING,
+    ntddk::ZwClose,
+};
+
+use crate::{
+    RTL_CONSTANT_STRING,
+    imports::{ZwOpenDirectoryObject, ZwQueryDirectoryObject},
+    ioctl::Request,
+};
+
+/// `DIRECTORY_QUERY` in ntifs.h.
+const DIRECTORY_QUERY: ACCESS_MASK = 0x0001;
+
+/// `OBJECT_DIRECTORY_INFORMATION`, followed by the strings it points to.
+#[repr(C)]
+struct ObjectDirectoryInformation {
+    name: UNICODE_STRING,
+    type_name: UNICODE_STRING,
+}
+
+/// Handles `IOCTL_ENUM_DIRECTORY`.
+pub(crate) fn enum_directory(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<EnumDirectoryRequest>()?;
+    // The input and output share the buffer, so open the directory before
+    // writing any output over the path.
+    let directory = Directory::open(directory_path(request)?)?;
+
+    let mut index = input.index;
+    let mut offset = 0;
+    loop {
+        let entry = match directory.query(index) {
+            Ok(entry) => entry,
+            Err(STATUS_NO_MORE_ENTRIES) => break,
+            Err(status) => return Err(status),
+        };
+        if request.write_output_at(offset, &entry).is_err() {
+            break;
+        }
+        offset += size_of::<DirectoryEntry>();
+        index += 1;
+    }
+    Ok(offset)
+}
+
+/// Returns the path following [`EnumDirectoryRequest`] in the input buffer.
+fn directory_path(request: &Request) -> Result<UNICODE_STRING, NTSTATUS> {
+    let offset = size_of::<EnumDirectoryRequest>();
+    let length = request.input().len() - offset;
+    if length == 0 || length > usize::from(u16::MAX) {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    Ok(RTL_CONSTANT_STRING(request.input_utf16(offset, length)?))
+}
+
+/// An open object directory, closed when dropped.
+struct Directory(HANDLE);
+
+impl Directory {
+    fn open(mut path: UNICODE_STRING) -> Result<Self, NTSTATUS> {
+        let mut attributes = OBJECT_ATTRIBUTES {
+            Length: size_of::<OBJECT_ATTRIBUTES>() as _,
+            RootDirectory: ptr::null_mut(),
+            ObjectName: &raw mut path,
+            Attributes: OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+            SecurityDescriptor: ptr::null_mut(),
+            SecurityQualityOfService: ptr::null_mut(),
+        };
+        let mut directory = ptr::null_mut();
+        let status = unsafe {
+            ZwOpenDirectoryObject(&raw mut directory, DIRECTORY_QUERY, &raw mut attributes)
+        };
+        if NT_SUCCESS(status) {
+            Ok(Self(directory))
+        } else {
+            Err(status)
+        }
+    }
+
+    /// Returns the object at `index`, or `STATUS_NO_MORE_ENTRIES` past the
+    /// end.
+    fn query(&self, index: u32) -> Result<DirectoryEntry, NTSTATUS> {
+        // Room for the information and both strings, aligned for the former.
+        let mut buffer = [0u64; 128];
+        let mut context = index;
+        let status = unsafe {
+            ZwQueryDirectoryObject(
+                self.0,
+                buffer.as_mut_ptr().cast(),
+                size_of_val(&buffer) as _,
+                TRUE as _,
+                FALSE as _,
+                &raw mut context,
+                ptr::null_mut(),
+            )
+        };
+        if !NT_SUCCESS(status) {
+            return Err(status);
+        }
+
+        let information = unsafe { &*buffer.as_ptr().cast::<ObjectDirectoryInformation>() };
+        let mut entry = DirectoryEntry {
+            name_length: u32::from(information.name.Length / 2),
+            ..DirectoryEntry::default()
+        };
+        unsafe {
+            copy_truncated(
+                &information.name,
+                &mut entry.name[..DIRECTORY_NAME_LENGTH - 1],
+            );
+            copy_truncated(
+                &information.type_name,
+                &mut entry.type_name[..DIRECTORY_TYPE_NAME_LENGTH - 1],
+            );
+        }
+        Ok(entry)
+    }
+}
+
+impl Drop for Directory {
+    fn drop(&mut self) {
+        let _ = unsafe { ZwClose(self.0) };
+    }
+}
+
+/// Copies as much of `source` as fits into `destination`.
+unsafe fn copy_truncated(source: &UNICODE_STRING, destination: &mut [u16]) {
+    let length = usize::from(source.Length / 2).min(destination.len());
+    if length != 0 {
+        let source = unsafe { slice::from_raw_parts(source.Buffer, length) };
+        destination[..length].copy_from_slice(source);
+    }
+}
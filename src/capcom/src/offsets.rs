@@ -0,0 +1,155 @@
+//! Offsets of kernel structure fields and RVAs of kernel globals for the
+//! running build of Windows, for features that access undocumented structures.
+//!
+//! The built-in table is generated from offsets.csv by the build script and
+//! covers known builds of x64 Windows. `IOCTL_SET_OFFSETS` sets them at runtime,
+//! e.g., for new builds and for RVAs of globals, which change with every update.
+//! Without offsets, features depending on them fail with `STATUS_NOT_SUPPORTED`
+//! instead of corrupting memory with offsets of another build.
+
+use capcom_abi::KernelOffsets;
+use wdk_sys::{
+    NT_SUCCESS, NTSTATUS, RTL_OSVERSIONINFOW, STATUS_INVALID_PARAMETER, STATUS_NOT_SUPPORTED,
+    STATUS_REVISION_MISMATCH, ntddk::RtlGetVersion,
+};
+
+use crate::{ioctl::Request, sync::SpinLock, trace::trace};
+
+/// The upper bound of field offsets, larger than any of the structures.
+const MAX_FIELD_OFFSET: u32 = 0x2000;
+
+/// The built-in offsets sorted by the build number.
+static BUILTIN: &[KernelOffsets] = include!(concat!(env!("OUT_DIR"), "/offsets.rs"));
+
+/// The offsets for the running build, or [`KernelOffsets::EMPTY`] if unknown.
+static ACTIVE: SpinLock<KernelOffsets> = SpinLock::new(KernelOffsets::EMPTY);
+
+/// Selects the built-in offsets for the running build, if any.
+pub(crate) fn init() {
+    let build_number = build_number();
+    match BUILTIN.binary_search_by_key(&build_number, |offsets| offsets.build_number) {
+        Ok(index) => {
+            trace!(BUILTIN_OFFSETS, build_number);
+            *ACTIVE.lock() = BUILTIN[index];
+        }
+        Err(_) => trace!(NO_BUILTIN_OFFSETS, build_number),
+    }
+}
+
+/// Handles `IOCTL_SET_OFFSETS`.
+pub(crate) fn set_offsets(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<KernelOffsets>()?;
+    if input.build_number != build_number() {
+        return Err(STATUS_REVISION_MISMATCH);
+    }
+    let fields = [
+        input.eprocess_unique_process_id,
+        input.eprocess_active_process_links,
+        input.eprocess_token,
+        input.eprocess_signature_level,
+        input.eprocess_protection,
+        input.kthread_previous_mode,
+        input.kthread_kernel_stack,
+        input.token_privileges,
+    ];
+    let pointers = [
+        input.eprocess_unique_process_id,
+        input.eprocess_active_process_links,
+        input.eprocess_token,
+        input.kthread_kernel_stack,
+        input.token_privileges,
+        input.psp_create_process_notify_routine,
+        input.psp_create_thread_notify_routine,
+        input.psp_load_image_notify_routine,
+    ];
+    if fields.iter().any(|&offset| offset >= MAX_FIELD_OFFSET)
+        || pointers
+            .iter()
+            .any(|&offset| !offset.is_multiple_of(size_of::<usize>() as u32))
+        || !input.ci_options.is_multiple_of(4)
+    {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+
+    let merge = |current: u32, new: u32| if new == 0 { current } else { new };
+    let mut active = ACTIVE.lock();
+    let current = *active;
+    *active = KernelOffsets {
+        build_number: input.build_number,
+        eprocess_unique_process_id: merge(
+            current.eprocess_unique_process_id,
+            input.eprocess_unique_process_id,
+        ),
+        eprocess_active_process_links: merge(
+            current.eprocess_active_process_links,
+            input.eprocess_active_process_links,
+        ),
+        eprocess_token: merge(current.eprocess_token, input.eprocess_token),
+        eprocess_signature_level: merge(
+            current.eprocess_signature_level,
+            input.eprocess_signature_level,
+        ),
+        eprocess_protection: merge(current.eprocess_protection, input.eprocess_protection),
+        kthread_previous_mode: merge(current.kthread_previous_mode, input.kthread_previous_mode),
+        kthread_kernel_stack: merge(current.kthread_kernel_stack, input.kthread_kernel_stack),
+        token_privileges: merge(current.token_privileges, input.token_privileges),
+        psp_create_process_notify_routine: merge(
+            current.psp_create_process_notify_routine,
+            input.psp_create_process_notify_routine,
+        ),
+        psp_create_thread_notify_routine: merge(
+            current.psp_create_thread_notify_routine,
+            input.psp_create_thread_notify_routine,
+        ),
+        psp_load_image_notify_routine: merge(
+            current.psp_load_image_notify_routine,
+            input.psp_load_image_notify_routine,
+        ),
+        ci_options: merge(current.ci_options, input.ci_options),
+    };
+    drop(active);
+    trace!(OFFSETS_SET, input.build_number);
+    Ok(0)
+}
+
+/// Handles `IOCTL_GET_OFFSETS`.
+pub(crate) fn get_offsets(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let offsets = *ACTIVE.lock();
+    request.write_output(&offsets)
+}
+
+/// Returns the offsets for the running build, or `STATUS_NOT_SUPPORTED` if
+/// they are unknown.
+pub(crate) fn get() -> Result<KernelOffsets, NTSTATUS> {
+    let offsets = *ACTIVE.lock();
+    if offsets.build_number == 0 {
+        Err(STATUS_NOT_SUPPORTED)
+    } else {
+        Ok(offsets)
+    }
+}
+
+/// Checks that [`BUILTIN`] is sorted by the build number, as [`init`] searches
+/// it, and that the offsets in use, if any, are for the running build.
+pub(crate) fn self_test() -> bool {
+    let active = ACTIVE.lock().build_number;
+    BUILTIN
+        .windows(2)
+        .all(|pair| pair[0].build_number < pair[1].build_number)
+        && (active == 0 || active == build_number())
+}
+
+/// Returns the build number of the running Windows, or 0 if unknown, which no
+/// offsets match.
+pub(crate) fn build_number() -> u32 {
+    let mut version = RTL_OSVERSIONINFOW {
+        dwOSVersionInfoSize: size_of::<RTL_OSVERSIONINFOW>() as _,
+        ..RTL_OSVERSIONINFOW::default()
+    };
+    let status = unsafe { RtlGetVersion(&raw mut version) };
+    if !NT_SUCCESS(status) {
+        trace!(VERSION_QUERY_FAILED, status);
+        return 0;
+    }
+    version.dwBuildNumber
+}
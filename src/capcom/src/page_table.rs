@@ -0,0 +1,145 @@
+//! `IOCTL_GET_PTE` and `IOCTL_SET_PTE`, walking 4-level page tables of x86_64.
+//!
+//! The walk runs attached to the target process, so that
+//! `MmGetVirtualForPhysical` returns addresses of page tables mapped in its
+//! address space.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use capcom_abi::{
+    PTE_LARGE_PAGE, PTE_NX, PTE_PFN, PTE_PRESENT, PTE_USER, PTE_WRITE, PteInfo, PteRequest,
+    SetPteRequest,
+};
+use wdk_sys::{
+    LARGE_INTEGER, NTSTATUS, STATUS_INVALID_ADDRESS, STATUS_INVALID_PARAMETER,
+    STATUS_NOT_SUPPORTED, ULONG_PTR,
+};
+
+use crate::{
+    arch,
+    imports::{KeIpiGenericCall, MmGetVirtualForPhysical},
+    ioctl::Request,
+    paranoid::{self, Region},
+    process,
+    trace::trace,
+};
+
+/// Bits of an entry pointing to a page table or a large page.
+const ADDRESS_MASK: u64 = PTE_PFN;
+
+/// Handles `IOCTL_GET_PTE`.
+pub(crate) fn get_pte(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<PteRequest>()?;
+    let info = process::attach(input.process_id, || {
+        find_entry(input.address).map(|entry| entry.info(input.address))
+    })??;
+    request.write_output(&info)
+}
+
+/// Handles `IOCTL_SET_PTE`.
+pub(crate) fn set_pte(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<SetPteRequest>()?;
+    if input.mask & !(PTE_NX | PTE_WRITE | PTE_USER | PTE_PFN) != 0 {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+
+    let info = process::attach(input.process_id, || {
+        let entry = find_entry(input.address)?;
+        if entry.value & PTE_PRESENT == 0 || (input.mask & PTE_PFN != 0 && entry.level != 1) {
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+
+        // The processor may set the accessed and dirty bits concurrently.
+        let atomic = unsafe { AtomicU64::from_ptr(entry.address) };
+        let (Ok(previous) | Err(previous)) =
+            atomic.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |value| {
+                Some((value & !input.mask) | (input.value & input.mask))
+            });
+        let updated = Entry {
+            value: atomic.load(Ordering::SeqCst),
+            ..entry
+        };
+        trace!(
+            PAGE_TABLE_ENTRY_CHANGED,
+            entry.level, input.address, previous, updated.value
+        );
+        Ok(updated.info(input.address))
+    })??;
+
+    // Other processors may cache the old entry. Flush all TLB entries, as the
+    // address may be global or mapped in other address spaces.
+    let _ = unsafe { KeIpiGenericCall(Some(flush_tlb), 0) };
+    request.write_output(&info)
+}
+
+/// Flushes TLBs of the current processor in an IPI.
+unsafe extern "C" fn flush_tlb(_context: ULONG_PTR) -> ULONG_PTR {
+    unsafe { arch::flush_tlb() };
+    0
+}
+
+/// A page table entry found by [`find_entry`].
+#[derive(Clone, Copy)]
+struct Entry {
+    value: u64,
+    address: *mut u64,
+    level: u32,
+}
+
+impl Entry {
+    /// Returns the information of the entry translating `address`.
+    fn info(&self, address: u64) -> PteInfo {
+        // The entry maps 4KB, 2MB or 1GB depending on the level.
+        let page_offset_mask = (1u64 << (12 + 9 * (self.level - 1))) - 1;
+        let physical_address = if self.value & PTE_PRESENT == 0 {
+            0
+        } else {
+            (self.value & ADDRESS_MASK & !page_offset_mask) | (address & page_offset_mask)
+        };
+        PteInfo {
+            entry: self.value,
+            entry_address: self.address.addr() as u64,
+            physical_address,
+            level: self.level,
+            reserved: 0,
+        }
+    }
+}
+
+/// Walks the page tables of the current address space and returns the entry
+/// translating `address`, or the first entry that is not present.
+fn find_entry(address: u64) -> Result<Entry, NTSTATUS> {
+    let Some(mut table) = arch::page_table_root() else {
+        return Err(STATUS_NOT_SUPPORTED);
+    };
+    // Bits 63:48 must be copies of bit 47.
+    let upper = address.cast_signed() >> 47;
+    if upper != 0 && upper != -1 {
+        return Err(STATUS_INVALID_ADDRESS);
+    }
+
+    for level in (1..=4).rev() {
+        let index = (address >> (12 + 9 * (level - 1))) & 0x1ff;
+        let physical_address = LARGE_INTEGER {
+            QuadPart: (table + index * 8).cast_signed(),
+        };
+        let entry_address = unsafe { MmGetVirtualForPhysical(physical_address) }.cast::<u64>();
+        if entry_address.is_null() {
+            return Err(STATUS_INVALID_ADDRESS);
+        }
+        paranoid::check(entry_address.addr(), size_of::<u64>(), Region::System)?;
+        let entry = Entry {
+            value: unsafe { entry_address.read_volatile() },
+            address: entry_address,
+            level,
+        };
+        if entry.value & PTE_PRESENT == 0
+            || level == 1
+            || ((level == 2 || level == 3) && entry.value & PTE_LARGE_PAGE != 0)
+        {
+            return Ok(entry);
+        }
+        table = entry.value & ADDRESS_MASK;
+    }
+    unreachable!()
+}
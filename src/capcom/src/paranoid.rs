@@ -0,0 +1,70 @@
+//! Validation of the pointers the driver dereferences on behalf of requests,
+//! enabled with the `paranoid` feature for fuzzing. A pointer that would fault,
+//! or that points outside where it is expected, is logged and fails the
+//! request with `STATUS_ACCESS_VIOLATION`, instead of bug checking the target,
+//! so that a fuzzing run goes on and tells what was wrong. Without the feature,
+//! [`check`] accepts every pointer.
+//!
+//! Pages are checked with `MmIsAddressValid`, which tells whether they are
+//! resident at the moment, so pageable memory that is paged out is rejected
+//! too. Pool has no ranges to check against, so pointers to system space are
+//! only checked to be in the upper half of the address space.
+
+use core::ptr;
+
+use wdk_sys::{NTSTATUS, PAGE_SIZE, STATUS_ACCESS_VIOLATION, ntddk::MmIsAddressValid};
+
+use crate::{module, trace::trace};
+
+/// Where a pointer is expected to point.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Region {
+    /// User-mode memory of the current process.
+    User,
+    /// System space, e.g., pool or page tables.
+    System,
+    /// The image of a loaded kernel module.
+    #[cfg_attr(not(feature = "dangerous"), expect(dead_code))]
+    Module,
+}
+
+impl Region {
+    /// Returns the name of the region for messages.
+    fn name(self) -> &'static [u8] {
+        match self {
+            Region::User => b"user space",
+            Region::System => b"system space",
+            Region::Module => b"a kernel module",
+        }
+    }
+}
+
+/// Checks that the `size` bytes at `address` are in `region` and resident,
+/// when built with the `paranoid` feature.
+pub(crate) fn check(address: usize, size: usize, region: Region) -> Result<(), NTSTATUS> {
+    if cfg!(feature = "paranoid") && !is_valid(address, size, region) {
+        trace!(POINTER_REJECTED, size, address; region.name());
+        return Err(STATUS_ACCESS_VIOLATION);
+    }
+    Ok(())
+}
+
+fn is_valid(address: usize, size: usize, region: Region) -> bool {
+    // The upper half of the address space is system space on x86_64, ARM64
+    // and x86 without /3GB.
+    const SYSTEM_START: usize = 1 << (usize::BITS - 1);
+
+    let Some(end) = address.checked_add(size) else {
+        return false;
+    };
+    let in_region = match region {
+        Region::User => end <= SYSTEM_START,
+        Region::System => address >= SYSTEM_START,
+        Region::Module => module::contains(&(address..end)),
+    };
+    let page_size = PAGE_SIZE as usize;
+    in_region
+        && (address & !(page_size - 1)..end)
+            .step_by(page_size)
+            .all(|page| unsafe { MmIsAddressValid(ptr::without_provenance_mut(page)) } != 0)
+}
@@ -0,0 +1,526 @@
+//! Execution of payloads in kernel-mode.
+
+use core::ptr;
+#[cfg(not(feature = "defanged"))]
+use core::{
+    mem, slice,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+#[cfg(not(feature = "defanged"))]
+use capcom_abi::DEBUG_BREAK_ON_EVERY_PAYLOAD;
+use capcom_abi::{
+    CAPABILITY_CET, CAPABILITY_MAP_DRIVER, CAPABILITY_RUN_PAYLOAD, CAPABILITY_RUN_SHELLCODE,
+    PayloadTranscript,
+};
+#[cfg(feature = "defanged")]
+use wdk_sys::ntddk::PsGetCurrentProcessId;
+use wdk_sys::{
+    MM_COPY_ADDRESS, MM_COPY_MEMORY_VIRTUAL, NT_SUCCESS, NTSTATUS, PUNICODE_STRING, PVOID,
+    STATUS_INVALID_IMAGE_FORMAT, STATUS_INVALID_PARAMETER, STATUS_NOT_SUPPORTED, ULONG,
+};
+#[cfg(not(feature = "defanged"))]
+use wdk_sys::{
+    POOL_FLAG_NON_PAGED, POOL_FLAG_NON_PAGED_EXECUTE, STATUS_INSUFFICIENT_RESOURCES,
+    ntddk::{KeGetCurrentIrql, MmGetSystemRoutineAddress},
+};
+
+#[cfg(all(feature = "hardened", not(feature = "defanged")))]
+use crate::remap::Remapped;
+use crate::{
+    arch,
+    coverage::cover,
+    etw,
+    imports::ZwQuerySystemInformation,
+    ioctl::Request,
+    paranoid::{self, Region},
+    routines,
+    trace::{teach, trace},
+};
+#[cfg(not(feature = "defanged"))]
+use crate::{
+    config,
+    pool::{self, Tag},
+};
+
+/// The number of bytes at the start of a payload that must decode as
+/// instructions.
+const CHECKED_LENGTH: usize = 16;
+
+/// The size of [`STAGING_AREA`]. Larger shellcode is copied into pool
+/// allocated for each execution.
+#[cfg(not(feature = "defanged"))]
+const STAGING_AREA_SIZE: usize = 64 * 1024;
+
+/// Executable non-paged pool allocated when the driver starts, which shellcode
+/// is copied into and executed on instead of allocating pool every time, or
+/// null while an execution uses it. Repeated executions are otherwise dominated
+/// by the allocation.
+#[cfg(not(feature = "defanged"))]
+static STAGING_AREA: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+
+type PayloadType = unsafe extern "C" fn(unsafe extern "C" fn(PUNICODE_STRING) -> PVOID);
+
+/// Returns `CAPABILITY_*` flags describing which payload execution modes are
+/// available on this system.
+pub(crate) fn capabilities() -> u32 {
+    let cet = arch::cet();
+    let mut capabilities = 0;
+    if !is_hvci_enabled() {
+        capabilities |= CAPABILITY_RUN_SHELLCODE;
+        if cfg!(feature = "dangerous") {
+            capabilities |= CAPABILITY_MAP_DRIVER;
+        }
+        if arch::CAN_RUN_USER_PAYLOAD && !cet.indirect_branch_tracking {
+            capabilities |= CAPABILITY_RUN_PAYLOAD;
+        }
+    }
+    if cet.shadow_stack || cet.indirect_branch_tracking {
+        capabilities |= CAPABILITY_CET;
+    }
+    capabilities
+}
+
+/// Handles `IOCTL_RUN_PAYLOAD`, executing the payload at the address given as
+/// the input buffer, the way the personality of the build does.
+pub(crate) fn run_user_payload(request: &mut Request) -> Result<usize, NTSTATUS> {
+    // When HVCI is enabled, the hypervisor keeps user-mode pages non-executable
+    // in kernel-mode regardless of CR4.SMEP, and the payload would cause a bug
+    // check. Refuse the request instead. With indirect branch tracking, the call
+    // would fault unless the payload starts with ENDBR, which we cannot ensure.
+    if is_hvci_enabled() {
+        trace!(PAYLOAD_REFUSED_FOR_HVCI);
+        return Err(STATUS_NOT_SUPPORTED);
+    }
+    if !arch::CAN_RUN_USER_PAYLOAD || arch::cet().indirect_branch_tracking {
+        trace!(USER_PAYLOAD_REFUSED);
+        return Err(STATUS_NOT_SUPPORTED);
+    }
+
+    // The original driver takes only a pointer-sized input buffer and a 4-byte
+    // output buffer.
+    if cfg!(feature = "strict-compat")
+        && (request.input().len() != size_of::<usize>() || request.output_mut().len() != 4)
+    {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    teach!(
+        TEACHING_BUFFERS,
+        request.input().len(),
+        request.input().as_ptr().addr(),
+        request.output_mut().len()
+    );
+    // WOW64 callers may give a 4-byte address, as to the x86 build.
+    let address = if request.is_wow64() && request.input().len() < size_of::<usize>() {
+        u64::from(request.read_input::<u32>()?)
+    } else {
+        request.user_pointer(request.read_input::<usize>()? as u64)?
+    };
+    let Some(payload) =
+        (unsafe { core::mem::transmute::<usize, Option<PayloadType>>(address as usize) })
+    else {
+        return Err(STATUS_INVALID_PARAMETER);
+    };
+    teach!(TEACHING_PAYLOAD_ADDRESS, payload as usize);
+    if cfg!(feature = "strict-compat") {
+        // The original driver only checks that the address of the payload is
+        // stored right before it, which tools written for it do.
+        let address = payload as usize;
+        let mut pointer = [0u8; size_of::<usize>()];
+        let Some(start) = address.checked_sub(pointer.len()) else {
+            return Err(STATUS_INVALID_PARAMETER);
+        };
+        paranoid::check(start, pointer.len(), Region::User)?;
+        if copy_user(start, &mut pointer) != pointer.len()
+            || usize::from_ne_bytes(pointer) != address
+        {
+            trace!(PAYLOAD_NOT_SELF_REFERENCED, address);
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+    } else {
+        paranoid::check(payload as usize, CHECKED_LENGTH, Region::User)?;
+        // An instruction may extend past the checked bytes.
+        let mut code = [0u8; CHECKED_LENGTH * 2];
+        let transferred = copy_user(payload as usize, &mut code);
+        check_code(&code[..transferred])?;
+    }
+    #[cfg(feature = "defanged")]
+    {
+        report(format_args!(
+            "a user-mode payload at {:#x}",
+            payload as usize
+        ));
+        Ok(0)
+    }
+    #[cfg(all(feature = "faithful", not(feature = "defanged")))]
+    {
+        teach_calling(payload as usize);
+        unsafe { call_faithfully(payload) };
+        teach!(TEACHING_RETURNED);
+        Ok(0)
+    }
+    #[cfg(all(feature = "hardened", not(feature = "defanged")))]
+    {
+        let remapped = Remapped::copy(payload as usize)?;
+        // Validate the copy again, as the caller can change the original.
+        check_code(remapped.code(payload as usize))?;
+        let address = remapped.translate(payload as usize);
+        teach_calling(address);
+        let transcript = unsafe { run_payload(mem::transmute::<usize, PayloadType>(address))? };
+        teach_returned(&transcript);
+        Ok(request.write_output(&transcript).unwrap_or(0))
+    }
+    #[cfg(not(any(feature = "defanged", feature = "faithful", feature = "hardened")))]
+    {
+        teach_calling(payload as usize);
+        let transcript = unsafe { run_payload(payload)? };
+        teach_returned(&transcript);
+        // The transcript is optional, as clients of the original driver give
+        // a 4-byte output buffer.
+        Ok(request.write_output(&transcript).unwrap_or(0))
+    }
+}
+
+/// Logs that the payload at `address` is about to be called, for the
+/// `teaching` feature.
+#[cfg(not(feature = "defanged"))]
+fn teach_calling(address: usize) {
+    teach!(
+        TEACHING_CALLING,
+        address,
+        MmGetSystemRoutineAddress as *const () as usize
+    );
+}
+
+/// Logs that the payload returned with `transcript`, for the `teaching`
+/// feature.
+#[cfg(all(not(feature = "defanged"), not(feature = "faithful")))]
+fn teach_returned(transcript: &PayloadTranscript) {
+    teach!(TEACHING_RETURNED);
+    teach!(TEACHING_CR4, transcript.before.cr4, transcript.after.cr4);
+}
+
+/// Calls `payload` the way the original driver does: straight through the
+/// pointer on the stack of the calling thread, with CR4.SMEP (PSTATE.PAN on
+/// ARM64) and interrupts disabled, and nothing saved or recorded around it.
+#[cfg(all(feature = "faithful", not(feature = "defanged")))]
+unsafe fn call_faithfully(payload: PayloadType) {
+    crate::debug_break(&[DEBUG_BREAK_ON_EVERY_PAYLOAD]);
+    unsafe {
+        let state = arch::disable_protection();
+        arch::serialize_instruction_fetch();
+        payload(MmGetSystemRoutineAddress);
+        arch::restore_protection(state);
+    }
+}
+
+/// Copies the user-mode memory at `address` into `buffer` without touching the
+/// address directly, as it may not be mapped, and returns the number of bytes
+/// copied.
+pub(crate) fn copy_user(address: usize, buffer: &mut [u8]) -> usize {
+    let mut source = MM_COPY_ADDRESS::default();
+    source.__bindgen_anon_1.VirtualAddress = address as PVOID;
+    routines::copy_memory(buffer, source, MM_COPY_MEMORY_VIRTUAL).1
+}
+
+/// Handles `IOCTL_RUN_SHELLCODE` and `IOCTL_RUN_SHELLCODE_DIRECT`, executing
+/// the shellcode given as the input buffer, or as the output buffer with direct
+/// I/O, with [`run_shellcode_bytes`].
+pub(crate) fn run_shellcode(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let transcript = run_shellcode_bytes(request.data(0))?;
+    // Nothing is written with direct I/O, where the output buffer is the
+    // shellcode.
+    Ok(transcript
+        .and_then(|transcript| request.write_output(&transcript).ok())
+        .unwrap_or(0))
+}
+
+/// Copies `shellcode` into executable non-paged pool and executes it as a
+/// payload, and returns the transcript, or `None` with the `defanged` feature.
+/// Unlike [`run_user_payload`], this does not rely on user-mode pages being
+/// executable in kernel-mode.
+pub(crate) fn run_shellcode_bytes(shellcode: &[u8]) -> Result<Option<PayloadTranscript>, NTSTATUS> {
+    if is_hvci_enabled() {
+        trace!(PAYLOAD_REFUSED_FOR_HVCI);
+        return Err(STATUS_NOT_SUPPORTED);
+    }
+
+    if shellcode.is_empty() {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    check_code(shellcode)?;
+
+    // Report only the beginning of the shellcode as it may be large.
+    #[cfg(feature = "defanged")]
+    {
+        const SHOWN: usize = 16;
+        report(format_args!(
+            "{} bytes of shellcode starting with {:02x?}",
+            shellcode.len(),
+            &shellcode[..shellcode.len().min(SHOWN)]
+        ));
+        Ok(None)
+    }
+    #[cfg(not(feature = "defanged"))]
+    {
+        unsafe { execute_shellcode(shellcode) }.map(Some)
+    }
+}
+
+/// Checks that `code` looks like instructions before it is executed: neither
+/// filled with zeros or `int3`, nor containing bytes that do not decode within
+/// the first [`CHECKED_LENGTH`] bytes. This catches wrong pointers and corrupt
+/// buffers, which would otherwise crash the system.
+pub(crate) fn check_code(code: &[u8]) -> Result<(), NTSTATUS> {
+    let checked = &code[..code.len().min(CHECKED_LENGTH)];
+    let mut valid = !checked.is_empty()
+        && !checked.iter().all(|&byte| byte == 0)
+        && !checked.iter().all(|&byte| byte == 0xcc);
+    let mut offset = 0;
+    while valid && offset < checked.len() {
+        match arch::instruction_length(&code[offset..]) {
+            Some(length) => offset += length,
+            None => valid = false,
+        }
+    }
+    if !valid {
+        cover!();
+        trace!(INVALID_CODE; checked);
+        return Err(STATUS_INVALID_IMAGE_FORMAT);
+    }
+    Ok(())
+}
+
+/// Allocates [`STAGING_AREA`] unless HVCI is enabled, where shellcode is never
+/// executed. Without it, shellcode is copied into pool allocated for each
+/// execution.
+#[cfg(not(feature = "defanged"))]
+pub(crate) fn allocate_staging_area() {
+    if is_hvci_enabled() {
+        return;
+    }
+    let memory = pool::allocate(
+        POOL_FLAG_NON_PAGED_EXECUTE,
+        STAGING_AREA_SIZE,
+        Tag::Shellcode,
+    );
+    STAGING_AREA.store(memory.cast(), Ordering::Release);
+}
+
+/// Frees [`STAGING_AREA`]. No shellcode may be executing.
+#[cfg(not(feature = "defanged"))]
+pub(crate) fn free_staging_area() {
+    let memory = STAGING_AREA.swap(ptr::null_mut(), Ordering::Acquire);
+    if !memory.is_null() {
+        unsafe { pool::free(memory.cast(), Tag::Shellcode) };
+    }
+}
+
+/// Copies `shellcode` into [`STAGING_AREA`], or executable non-paged pool if it
+/// is too small or used by another execution, and executes it.
+#[cfg(not(feature = "defanged"))]
+unsafe fn execute_shellcode(shellcode: &[u8]) -> Result<PayloadTranscript, NTSTATUS> {
+    // Make the shellcode a valid indirect branch target if required.
+    let prefix = if arch::cet().indirect_branch_tracking {
+        arch::BRANCH_TARGET
+    } else {
+        &[]
+    };
+    let length = prefix.len() + shellcode.len();
+
+    // Take the staging area so concurrent executions do not overwrite it.
+    let staging_area = if length <= STAGING_AREA_SIZE {
+        STAGING_AREA.swap(ptr::null_mut(), Ordering::Acquire)
+    } else {
+        ptr::null_mut()
+    };
+    let code = if staging_area.is_null() {
+        pool::allocate(POOL_FLAG_NON_PAGED_EXECUTE, length, Tag::Shellcode).cast::<u8>()
+    } else {
+        staging_area
+    };
+    if code.is_null() {
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+
+    unsafe {
+        ptr::copy_nonoverlapping(prefix.as_ptr(), code, prefix.len());
+        ptr::copy_nonoverlapping(shellcode.as_ptr(), code.add(prefix.len()), shellcode.len());
+        arch::flush_instruction_cache(code.cast(), length);
+        let result = run_payload(mem::transmute::<*mut u8, PayloadType>(code));
+        if staging_area.is_null() {
+            pool::free(code.cast(), Tag::Shellcode);
+        } else {
+            STAGING_AREA.store(staging_area, Ordering::Release);
+        }
+        result
+    }
+}
+
+/// Logs and writes an ETW event describing the payload the defanged build
+/// would have executed on behalf of the current process.
+#[cfg(feature = "defanged")]
+pub(crate) fn report(payload: core::fmt::Arguments<'_>) {
+    let process_id = unsafe { PsGetCurrentProcessId() } as usize;
+    trace!(PAYLOAD_REQUESTED, process_id);
+    etw::write(
+        etw::TRACE_LEVEL_WARNING,
+        format_args!("Process {process_id} requested to run {payload}"),
+    );
+}
+
+/// Executes `payload` without CR4.SMEP (PSTATE.PAN on ARM64) and interrupts
+/// on the dedicated stack if one is configured, with the extended processor
+/// state saved, and returns
+/// the registers around the call. With
+/// shadow stacks enabled, the payload must return normally.
+#[cfg(not(feature = "defanged"))]
+unsafe fn run_payload(payload: PayloadType) -> Result<PayloadTranscript, NTSTATUS> {
+    // The original driver calls payloads on the stack of the calling thread.
+    let stack_size = if cfg!(feature = "strict-compat") {
+        0
+    } else {
+        config::payload_stack_size()
+    };
+    let stack = match stack_size {
+        0 => None,
+        size => Some(Stack::allocate(size)?),
+    };
+    let mut transcript = PayloadTranscript::default();
+    crate::debug_break(&[DEBUG_BREAK_ON_EVERY_PAYLOAD]);
+    // Payloads built with SSE or AVX code generation would otherwise clobber
+    // the registers of the calling thread.
+    arch::with_extended_state(|| unsafe {
+        let state = arch::disable_protection();
+        // The thread may have moved to this processor after the payload was
+        // written, and cannot move any more with interrupts disabled.
+        arch::serialize_instruction_fetch();
+        transcript.before.irql = KeGetCurrentIrql();
+        arch::call_payload(
+            payload as usize,
+            MmGetSystemRoutineAddress as *const () as usize,
+            &raw mut transcript,
+            stack.as_ref().map_or(0, Stack::top),
+        );
+        transcript.after.irql = KeGetCurrentIrql();
+        arch::restore_protection(state);
+    })?;
+    report_changes(&transcript);
+    if let Some(stack) = &stack
+        && stack.overflowed()
+    {
+        trace!(PAYLOAD_STACK_OVERFLOWED, stack.size);
+        etw::write(
+            etw::TRACE_LEVEL_WARNING,
+            format_args!("The payload overflowed the stack of {} bytes", stack.size),
+        );
+    }
+    Ok(transcript)
+}
+
+/// A stack payloads run on instead of the stack of the calling thread, which is
+/// only 24 KB on x86_64 and overflows into a double fault. It is in non-paged
+/// pool, with [`Stack::CANARY`] at the bottom to detect overflows.
+///
+/// The kernel does not know the stack, so exceptions raised on it cannot be
+/// dispatched to handlers, and functions checking the stack limits of the
+/// thread misbehave.
+#[cfg(not(feature = "defanged"))]
+struct Stack {
+    memory: *mut u8,
+    size: usize,
+}
+
+#[cfg(not(feature = "defanged"))]
+impl Stack {
+    /// The size of the canary at the bottom.
+    const CANARY_SIZE: usize = 256;
+
+    /// The byte the canary is filled with.
+    const CANARY: u8 = 0xa5;
+
+    /// Allocates a stack of `size` bytes.
+    fn allocate(size: usize) -> Result<Self, NTSTATUS> {
+        let size = size.max(Self::CANARY_SIZE * 2);
+        let memory = pool::allocate(POOL_FLAG_NON_PAGED, size, Tag::Stack).cast::<u8>();
+        if memory.is_null() {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+        unsafe { memory.write_bytes(Self::CANARY, Self::CANARY_SIZE) };
+        Ok(Self { memory, size })
+    }
+
+    /// Returns the address of the top of the stack.
+    fn top(&self) -> usize {
+        self.memory.addr() + self.size
+    }
+
+    /// Returns whether the payload wrote over the canary.
+    fn overflowed(&self) -> bool {
+        let canary = unsafe { slice::from_raw_parts(self.memory, Self::CANARY_SIZE) };
+        canary.iter().any(|&byte| byte != Self::CANARY)
+    }
+}
+
+#[cfg(not(feature = "defanged"))]
+impl Drop for Stack {
+    fn drop(&mut self) {
+        unsafe { pool::free(self.memory.cast(), Tag::Stack) };
+    }
+}
+
+/// Logs the registers the payload should have preserved but changed. Such
+/// corruption, e.g., interrupts left enabled, otherwise causes a crash much
+/// later than the payload returns.
+#[cfg(not(feature = "defanged"))]
+fn report_changes(transcript: &PayloadTranscript) {
+    let (before, after) = (&transcript.before, &transcript.after);
+    let [cr0, cr4] = arch::CONTROL_REGISTERS;
+    let changes = arch::PRESERVED_REGISTERS
+        .iter()
+        .map(|&(index, name)| (name, before.gprs[index], after.gprs[index]))
+        .chain([
+            (
+                "flags",
+                before.flags & arch::PRESERVED_FLAGS,
+                after.flags & arch::PRESERVED_FLAGS,
+            ),
+            (cr0, before.cr0, after.cr0),
+            (cr4, before.cr4, after.cr4),
+            ("irql", u64::from(before.irql), u64::from(after.irql)),
+        ]);
+    for (name, old, new) in changes.filter(|(_, old, new)| old != new) {
+        trace!(REGISTER_CHANGED, old, new; name.as_bytes());
+        etw::write(
+            etw::TRACE_LEVEL_WARNING,
+            format_args!("The payload changed {name} from {old:#x} to {new:#x}"),
+        );
+    }
+}
+
+/// Checks whether hypervisor-protected code integrity (HVCI) is enabled.
+pub(crate) fn is_hvci_enabled() -> bool {
+    const SYSTEM_CODE_INTEGRITY_INFORMATION: ULONG = 103;
+    const CODEINTEGRITY_OPTION_HVCI_KMCI_ENABLED: ULONG = 0x400;
+
+    #[repr(C)]
+    struct SystemCodeIntegrityInformation {
+        length: ULONG,
+        code_integrity_options: ULONG,
+    }
+
+    let mut info = SystemCodeIntegrityInformation {
+        length: size_of::<SystemCodeIntegrityInformation>() as _,
+        code_integrity_options: 0,
+    };
+    let status = unsafe {
+        ZwQuerySystemInformation(
+            SYSTEM_CODE_INTEGRITY_INFORMATION,
+            (&raw mut info).cast(),
+            info.length,
+            ptr::null_mut(),
+        )
+    };
+    NT_SUCCESS(status)
+        && (info.code_integrity_options & CODEINTEGRITY_OPTION_HVCI_KMCI_ENABLED) != 0
+}
@@ -0,0 +1,143 @@
+//! `IOCTL_PCI_CONFIG_RW`, accessing the PCI configuration space.
+//!
+//! The enhanced configuration access mechanism (ECAM) is used when the ACPI
+//! MCFG table describes the bus, as accesses through MMIO are atomic and reach
+//! the extended configuration space. Otherwise, the legacy space of segment 0
+//! is accessed through the I/O ports 0xcf8 and 0xcfc. The HAL serializes its
+//! own accesses to the ports with a lock the driver cannot take, so the pair
+//! of accesses is only protected against interrupts on the current processor.
+
+use core::slice;
+
+use capcom_abi::PciConfigRequest;
+use wdk_sys::{NT_SUCCESS, NTSTATUS, STATUS_INVALID_PARAMETER, STATUS_NOT_FOUND};
+
+use crate::{arch, ioctl::Request, mmio::Mmio, routines, trace::trace};
+
+/// The I/O port selecting the register accessed through [`CONFIG_DATA`].
+const CONFIG_ADDRESS: u16 = 0xcf8;
+
+/// The I/O port accessing the register selected with [`CONFIG_ADDRESS`].
+const CONFIG_DATA: u16 = 0xcfc;
+
+/// The size of the configuration space of a function with ECAM.
+const EXTENDED_CONFIG_SIZE: u32 = 0x1000;
+
+/// The size of the configuration space reachable through the I/O ports.
+const LEGACY_CONFIG_SIZE: u32 = 0x100;
+
+/// The offset of the first allocation entry in the MCFG table, after the ACPI
+/// header and reserved bytes.
+const MCFG_ENTRIES_OFFSET: usize = 44;
+
+/// The size of an allocation entry in the MCFG table.
+const MCFG_ENTRY_SIZE: usize = 16;
+
+/// Handles `IOCTL_PCI_CONFIG_RW`.
+pub(crate) fn pci_config_rw(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<PciConfigRequest>()?;
+    if !matches!(input.size, 1 | 2 | 4)
+        || !input.offset.is_multiple_of(u32::from(input.size))
+        || input.offset >= EXTENDED_CONFIG_SIZE
+        || input.device >= 32
+        || input.function >= 8
+    {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+
+    let value = if let Some(address) = ecam_address(&input) {
+        let mmio = Mmio::map(address)?;
+        unsafe { access_mmio(&mmio, &input) }
+    } else if arch::HAS_PORT_IO && input.segment == 0 && input.offset < LEGACY_CONFIG_SIZE {
+        arch::without_interrupts(|| unsafe { access_port(&input) })
+    } else {
+        return Err(STATUS_NOT_FOUND);
+    };
+
+    if input.write == 0 {
+        return request.write_output(&value);
+    }
+    trace!(
+        PCI_WRITTEN,
+        input.value, input.segment, input.bus, input.device, input.function, input.offset
+    );
+    Ok(0)
+}
+
+/// Returns the physical address of the configuration space of the function
+/// from the MCFG table, or `None` if the table does not describe its bus.
+fn ecam_address(input: &PciConfigRequest) -> Option<u64> {
+    let acpi = u32::from_be_bytes(*b"ACPI");
+    let mcfg = u32::from_le_bytes(*b"MCFG");
+
+    // Room for 28 allocations, far more than firmware reports.
+    let mut table = [0u64; 64];
+    let mut length = 0;
+    let table_bytes =
+        unsafe { slice::from_raw_parts_mut(table.as_mut_ptr().cast::<u8>(), size_of_val(&table)) };
+    let status = routines::get_system_firmware_table(acpi, mcfg, table_bytes, &mut length);
+    if !NT_SUCCESS(status) {
+        return None;
+    }
+
+    let length = (length as usize).min(size_of_val(&table));
+    let table = unsafe { slice::from_raw_parts(table.as_ptr().cast::<u8>(), length) };
+    table
+        .get(MCFG_ENTRIES_OFFSET..)?
+        .chunks_exact(MCFG_ENTRY_SIZE)
+        .find_map(|entry| {
+            let base = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+            let segment = u16::from_le_bytes(entry[8..10].try_into().unwrap());
+            let (start_bus, end_bus) = (entry[10], entry[11]);
+            (segment == input.segment && (start_bus..=end_bus).contains(&input.bus)).then(|| {
+                base + (u64::from(input.bus - start_bus) << 20)
+                    + (u64::from(input.device) << 15)
+                    + (u64::from(input.function) << 12)
+            })
+        })
+}
+
+/// Reads or writes the register of `input` in the configuration space mapped
+/// at `mmio`. Returns the value read, or zero for a write.
+unsafe fn access_mmio(mmio: &Mmio, input: &PciConfigRequest) -> u32 {
+    let offset = input.offset as usize;
+    unsafe {
+        match (input.size, input.write != 0) {
+            (1, false) => u32::from(mmio.read::<u8>(offset)),
+            (2, false) => u32::from(mmio.read::<u16>(offset)),
+            (_, false) => mmio.read::<u32>(offset),
+            (1, true) => {
+                mmio.write(offset, input.value as u8);
+                0
+            }
+            (2, true) => {
+                mmio.write(offset, input.value as u16);
+                0
+            }
+            (_, true) => {
+                mmio.write(offset, input.value);
+                0
+            }
+        }
+    }
+}
+
+/// Reads or writes the register of `input` through the I/O ports. Returns the
+/// value read, or zero for a write. Interrupts must be disabled.
+unsafe fn access_port(input: &PciConfigRequest) -> u32 {
+    let address = (1 << 31)
+        | (u32::from(input.bus) << 16)
+        | (u32::from(input.device) << 11)
+        | (u32::from(input.function) << 8)
+        | (input.offset & 0xfc);
+    let port = CONFIG_DATA + (input.offset & 3) as u16;
+    unsafe {
+        arch::write_port(CONFIG_ADDRESS, 4, address);
+        if input.write == 0 {
+            arch::read_port(port, input.size)
+        } else {
+            arch::write_port(port, input.size, input.value);
+            0
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Pool allocations with a pool tag for each subsystem, counted per tag for
+//! `IOCTL_QUERY_ALLOCATIONS`, so leaks show up as outstanding allocations, and
+//! under poolmon or Driver Verifier with the responsible subsystem.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use capcom_abi::{AllocationInfo, MAX_POOL_TAGS};
+use wdk_sys::{NTSTATUS, POOL_FLAGS, PVOID, ntddk::ExFreePoolWithTag};
+
+use crate::{ioctl::Request, routines, trace::trace};
+
+/// A subsystem allocating from pool.
+#[derive(Clone, Copy)]
+pub(crate) enum Tag {
+    /// Handle contexts and the resources they own.
+    Context,
+    /// Executable copies of shellcode, and the staging area they are copied into.
+    Shellcode,
+    /// Stacks payloads run on.
+    Stack,
+    /// Thread captures and user-mode APCs.
+    Thread,
+    /// Registry values being queried.
+    Registry,
+    /// Lists of kernel modules.
+    Module,
+    /// Paths used to unload the driver.
+    SelfDestruct,
+    /// Images mapped with `IOCTL_MAP_DRIVER`.
+    Mapper,
+    /// Allocations checked by `IOCTL_SELF_TEST`.
+    SelfTest,
+    /// Copies of log records saved at shutdown.
+    Log,
+}
+
+/// The pool tags of [`Tag`]s.
+const TAGS: [[u8; 4]; 10] = [
+    *b"CpcC", *b"CpcX", *b"CpcK", *b"CpcT", *b"CpcR", *b"CpcM", *b"CpcU", *b"CpcD", *b"CpcS",
+    *b"CpcL",
+];
+
+const _: () = assert!(TAGS.len() <= MAX_POOL_TAGS);
+
+/// The number of allocations not freed yet for each tag.
+static OUTSTANDING: [AtomicU64; TAGS.len()] = [const { AtomicU64::new(0) }; TAGS.len()];
+
+/// The number of allocations made for each tag.
+static TOTAL: [AtomicU64; TAGS.len()] = [const { AtomicU64::new(0) }; TAGS.len()];
+
+impl Tag {
+    /// Returns the pool tag.
+    fn value(self) -> u32 {
+        u32::from_le_bytes(TAGS[self as usize])
+    }
+}
+
+/// Allocates `size` bytes of pool with `flags` for `tag`, or returns null.
+pub(crate) fn allocate(flags: POOL_FLAGS, size: usize, tag: Tag) -> PVOID {
+    let memory = routines::allocate_pool(flags, size, tag.value());
+    if !memory.is_null() {
+        let _ = OUTSTANDING[tag as usize].fetch_add(1, Ordering::Relaxed);
+        let _ = TOTAL[tag as usize].fetch_add(1, Ordering::Relaxed);
+    }
+    memory
+}
+
+/// Frees `memory` allocated with [`allocate`] for `tag`.
+pub(crate) unsafe fn free(memory: PVOID, tag: Tag) {
+    unsafe { ExFreePoolWithTag(memory, tag.value()) };
+    let _ = OUTSTANDING[tag as usize].fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Handles `IOCTL_QUERY_ALLOCATIONS`.
+pub(crate) fn query_allocations(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let mut offset = 0;
+    for (i, tag) in TAGS.iter().enumerate() {
+        let info = AllocationInfo {
+            tag: u32::from_le_bytes(*tag),
+            reserved: 0,
+            outstanding: OUTSTANDING[i].load(Ordering::Relaxed),
+            total: TOTAL[i].load(Ordering::Relaxed),
+        };
+        match request.write_output_at(offset, &info) {
+            Ok(length) => offset += length,
+            Err(_) => break,
+        }
+    }
+    Ok(offset)
+}
+
+/// Logs tags with outstanding allocations when the driver unloads.
+pub(crate) fn report_leaks() {
+    for (i, tag) in TAGS.iter().enumerate() {
+        let outstanding = OUTSTANDING[i].load(Ordering::Relaxed);
+        if outstanding != 0 {
+            trace!(ALLOCATIONS_LEAKED, outstanding; tag);
+        }
+    }
+}
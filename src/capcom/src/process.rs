@@ -0,0 +1,87 @@
+//! Access to other processes.
+
+use core::{mem, ptr};
+
+use wdk_sys::{
+    ACCESS_MASK, CLIENT_ID, HANDLE, KAPC_STATE, NT_SUCCESS, NTSTATUS, OBJ_KERNEL_HANDLE,
+    OBJECT_ATTRIBUTES, PEPROCESS, STATUS_INVALID_CID,
+    ntddk::{ObfDereferenceObject, ZwClose},
+};
+
+use crate::imports::{
+    KeStackAttachProcess, KeUnstackDetachProcess, PsLookupProcessByProcessId, ZwOpenProcess,
+};
+
+/// Runs `f` in the address space of the process with `process_id`, or of the
+/// current process if `process_id` is zero.
+pub(crate) fn attach<T>(process_id: u64, f: impl FnOnce() -> T) -> Result<T, NTSTATUS> {
+    if process_id == 0 {
+        return Ok(f());
+    }
+
+    let process = lookup(process_id)?;
+    let result = unsafe {
+        let mut apc_state: KAPC_STATE = mem::zeroed();
+        KeStackAttachProcess(process, &raw mut apc_state);
+        let result = f();
+        KeUnstackDetachProcess(&raw mut apc_state);
+        let _ = ObfDereferenceObject(process.cast());
+        result
+    };
+    Ok(result)
+}
+
+/// Returns the referenced process with `process_id`. The caller must
+/// dereference it.
+pub(crate) fn lookup(process_id: u64) -> Result<PEPROCESS, NTSTATUS> {
+    let mut process = ptr::null_mut();
+    let process_id = ptr::without_provenance_mut(process_id as usize);
+    let status = unsafe { PsLookupProcessByProcessId(process_id, &raw mut process) };
+    if NT_SUCCESS(status) {
+        Ok(process)
+    } else {
+        Err(STATUS_INVALID_CID)
+    }
+}
+
+/// An open kernel handle to a process, closed when dropped.
+pub(crate) struct ProcessHandle(pub(crate) HANDLE);
+
+impl ProcessHandle {
+    /// Opens the process with `process_id`. The handle is opened from
+    /// kernel-mode, so `access` is granted regardless of the caller.
+    pub(crate) fn open(process_id: u64, access: ACCESS_MASK) -> Result<Self, NTSTATUS> {
+        let mut attributes = OBJECT_ATTRIBUTES {
+            Length: size_of::<OBJECT_ATTRIBUTES>() as _,
+            RootDirectory: ptr::null_mut(),
+            ObjectName: ptr::null_mut(),
+            Attributes: OBJ_KERNEL_HANDLE,
+            SecurityDescriptor: ptr::null_mut(),
+            SecurityQualityOfService: ptr::null_mut(),
+        };
+        let mut client_id = CLIENT_ID {
+            UniqueProcess: ptr::without_provenance_mut(process_id as usize),
+            UniqueThread: ptr::null_mut(),
+        };
+        let mut process = ptr::null_mut();
+        let status = unsafe {
+            ZwOpenProcess(
+                &raw mut process,
+                access,
+                &raw mut attributes,
+                &raw mut client_id,
+            )
+        };
+        if NT_SUCCESS(status) {
+            Ok(Self(process))
+        } else {
+            Err(STATUS_INVALID_CID)
+        }
+    }
+}
+
+impl Drop for ProcessHandle {
+    fn drop(&mut self) {
+        let _ = unsafe { ZwClose(self.0) };
+    }
+}
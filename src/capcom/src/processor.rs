@@ -0,0 +1,47 @@
+//! Running code on a selected processor, and IOCTLs inspecting processors.
+
+use capcom_abi::CpuStateRequest;
+use wdk_sys::{
+    GROUP_AFFINITY, NT_SUCCESS, NTSTATUS, PROCESSOR_NUMBER, STATUS_INVALID_PARAMETER,
+    STATUS_NOT_SUPPORTED,
+    ntddk::{
+        KeGetProcessorNumberFromIndex, KeRevertToUserGroupAffinityThread,
+        KeSetSystemGroupAffinityThread,
+    },
+};
+
+use crate::{arch, ioctl::Request};
+
+/// Runs `f` on the processor with the system-wide index `index` by temporarily
+/// setting the affinity of the current thread. Must be called at
+/// `PASSIVE_LEVEL` or `APC_LEVEL`.
+pub(crate) fn run_on<T>(index: u32, f: impl FnOnce() -> T) -> Result<T, NTSTATUS> {
+    let mut number = PROCESSOR_NUMBER::default();
+    let status = unsafe { KeGetProcessorNumberFromIndex(index, &raw mut number) };
+    if !NT_SUCCESS(status) {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+
+    let mut affinity = GROUP_AFFINITY {
+        Mask: 1 << number.Number,
+        Group: number.Group,
+        Reserved: [0; 3],
+    };
+    let mut previous = GROUP_AFFINITY::default();
+    unsafe { KeSetSystemGroupAffinityThread(&raw mut affinity, &raw mut previous) };
+    let result = f();
+    unsafe { KeRevertToUserGroupAffinityThread(&raw mut previous) };
+    Ok(result)
+}
+
+/// Handles `IOCTL_SNAPSHOT_CPU_STATE`.
+pub(crate) fn snapshot_cpu_state(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<CpuStateRequest>()?;
+    let state = run_on(input.processor, || arch::cpu_state(input.first_vector))?;
+    let state = match state {
+        Some(state) => state,
+        None if cfg!(target_arch = "aarch64") => return Err(STATUS_NOT_SUPPORTED),
+        None => return Err(STATUS_INVALID_PARAMETER),
+    };
+    request.write_output(&state)
+}
@@ -0,0 +1,285 @@
+//! Access to the registry, and `IOCTL_REG_QUERY` and `IOCTL_REG_SET` exposing
+//! it to user-mode.
+
+use core::{mem, ptr, slice};
+
+use capcom_abi::{RegistryRequest, RegistryValue};
+use wdk_sys::{
+    _KEY_VALUE_INFORMATION_CLASS::KeyValuePartialInformation, ACCESS_MASK, DELETE, HANDLE,
+    KEY_READ, KEY_SET_VALUE, KEY_VALUE_PARTIAL_INFORMATION, NT_SUCCESS, NTSTATUS,
+    OBJ_CASE_INSENSITIVE, OBJ_KERNEL_HANDLE, OBJECT_ATTRIBUTES, PCUNICODE_STRING, POOL_FLAG_PAGED,
+    REG_DWORD, REG_OPTION_NON_VOLATILE, STATUS_BUFFER_OVERFLOW, STATUS_BUFFER_TOO_SMALL,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER, UNICODE_STRING, ntddk::ZwClose,
+};
+
+use crate::{
+    RTL_CONSTANT_STRING,
+    imports::{ZwCreateKey, ZwDeleteKey, ZwOpenKey, ZwQueryValueKey, ZwSetValueKey},
+    ioctl::Request,
+    pool::{self, Tag},
+    trace::trace,
+};
+
+/// Handles `IOCTL_REG_QUERY`.
+pub(crate) fn reg_query(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<RegistryRequest>()?;
+    let (key_path, name) = key_and_name(request, input)?;
+    let value = read_value(&raw const key_path, name)?;
+
+    let data = value.data();
+    let mut length = request.write_output(&RegistryValue {
+        value_type: value.value_type(),
+        data_length: data.len() as _,
+    })?;
+    if let Ok(written) = request.write_output_bytes_at(length, data) {
+        length += written;
+    }
+    Ok(length)
+}
+
+/// Handles `IOCTL_REG_SET`.
+pub(crate) fn reg_set(request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<RegistryRequest>()?;
+    let (key_path, name) = key_and_name(request, input)?;
+    let data_offset =
+        size_of::<RegistryRequest>() + input.key_length as usize + input.name_length as usize;
+    let Some(data) = request
+        .input()
+        .get(data_offset..data_offset.saturating_add(input.data_length as usize))
+    else {
+        return Err(STATUS_INVALID_PARAMETER);
+    };
+    write_value(&raw const key_path, name, input.value_type, data)?;
+    trace!(REGISTRY_WRITTEN, data.len(), input.value_type);
+    Ok(0)
+}
+
+/// Returns the key path and the value name following `input` in the input
+/// buffer.
+fn key_and_name(
+    request: &Request,
+    input: RegistryRequest,
+) -> Result<(UNICODE_STRING, &[u16]), NTSTATUS> {
+    let key_length = input.key_length as usize;
+    let name_length = input.name_length as usize;
+    if key_length == 0 || key_length > usize::from(u16::MAX) || name_length > usize::from(u16::MAX)
+    {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    let offset = size_of::<RegistryRequest>();
+    let key_path = request.input_utf16(offset, key_length)?;
+    let name = request.input_utf16(offset + key_length, name_length)?;
+    Ok((RTL_CONSTANT_STRING(key_path), name))
+}
+
+/// Reads the `REG_DWORD` value `name` under the key `key_path`. Returns `None`
+/// if the value does not exist or is of another type.
+pub(crate) fn read_dword(key_path: PCUNICODE_STRING, name: &[u16]) -> Option<u32> {
+    /// `KEY_VALUE_PARTIAL_INFORMATION` with room for 4-byte data.
+    #[repr(C)]
+    struct DwordInformation {
+        information: KEY_VALUE_PARTIAL_INFORMATION,
+        _data: [u8; 3],
+    }
+
+    let key = Key::open(key_path, KEY_READ).ok()?;
+    let mut value_name = RTL_CONSTANT_STRING(name);
+    let mut buffer: DwordInformation = unsafe { mem::zeroed() };
+    let mut result_length = 0;
+    let status = unsafe {
+        ZwQueryValueKey(
+            key.0,
+            &raw mut value_name,
+            KeyValuePartialInformation,
+            (&raw mut buffer).cast(),
+            size_of::<DwordInformation>() as _,
+            &raw mut result_length,
+        )
+    };
+    let information = &buffer.information;
+    if !NT_SUCCESS(status) || information.Type != REG_DWORD || information.DataLength != 4 {
+        return None;
+    }
+    let data = (&raw const information.Data).cast::<[u8; 4]>();
+    Some(u32::from_ne_bytes(unsafe { data.read_unaligned() }))
+}
+
+/// Reads the value `name` of any type and size under the key `key_path`.
+pub(crate) fn read_value(key_path: PCUNICODE_STRING, name: &[u16]) -> Result<Value, NTSTATUS> {
+    let key = Key::open(key_path, KEY_READ)?;
+    let mut value_name = RTL_CONSTANT_STRING(name);
+
+    // Get the size first. The value may grow in between, so retry until the
+    // buffer is large enough.
+    let mut result_length = 0;
+    let status = unsafe {
+        ZwQueryValueKey(
+            key.0,
+            &raw mut value_name,
+            KeyValuePartialInformation,
+            ptr::null_mut(),
+            0,
+            &raw mut result_length,
+        )
+    };
+    if status != STATUS_BUFFER_TOO_SMALL && status != STATUS_BUFFER_OVERFLOW {
+        return Err(status);
+    }
+    loop {
+        let information = pool::allocate(POOL_FLAG_PAGED, result_length as usize, Tag::Registry)
+            .cast::<KEY_VALUE_PARTIAL_INFORMATION>();
+        if information.is_null() {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+        let value = Value(information);
+        let status = unsafe {
+            ZwQueryValueKey(
+                key.0,
+                &raw mut value_name,
+                KeyValuePartialInformation,
+                information.cast(),
+                result_length,
+                &raw mut result_length,
+            )
+        };
+        if NT_SUCCESS(status) {
+            return Ok(value);
+        }
+        if status != STATUS_BUFFER_TOO_SMALL && status != STATUS_BUFFER_OVERFLOW {
+            return Err(status);
+        }
+    }
+}
+
+/// Writes `data` as the value `name` of `value_type` under the key `key_path`.
+pub(crate) fn write_value(
+    key_path: PCUNICODE_STRING,
+    name: &[u16],
+    value_type: u32,
+    data: &[u8],
+) -> Result<(), NTSTATUS> {
+    let key = Key::open(key_path, KEY_SET_VALUE)?;
+    let mut value_name = RTL_CONSTANT_STRING(name);
+    let status = unsafe {
+        ZwSetValueKey(
+            key.0,
+            &raw mut value_name,
+            0,
+            value_type,
+            data.as_ptr().cast_mut().cast(),
+            data.len() as _,
+        )
+    };
+    if NT_SUCCESS(status) {
+        Ok(())
+    } else {
+        Err(status)
+    }
+}
+
+/// Creates the key `key_path` unless it exists. Its parent must exist.
+pub(crate) fn create_key(key_path: PCUNICODE_STRING) -> Result<(), NTSTATUS> {
+    let _key = Key::create(key_path, KEY_SET_VALUE)?;
+    Ok(())
+}
+
+/// Deletes the key `key_path`. It must not have subkeys.
+pub(crate) fn delete_key(key_path: PCUNICODE_STRING) -> Result<(), NTSTATUS> {
+    let key = Key::open(key_path, DELETE)?;
+    let status = unsafe { ZwDeleteKey(key.0) };
+    if NT_SUCCESS(status) {
+        Ok(())
+    } else {
+        Err(status)
+    }
+}
+
+/// A value read with [`read_value`], in paged pool.
+pub(crate) struct Value(*mut KEY_VALUE_PARTIAL_INFORMATION);
+
+impl Value {
+    /// Returns the type of the value, e.g., `REG_SZ`.
+    pub(crate) fn value_type(&self) -> u32 {
+        unsafe { (*self.0).Type }
+    }
+
+    /// Returns the data of the value.
+    pub(crate) fn data(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                (&raw const (*self.0).Data).cast(),
+                (*self.0).DataLength as _,
+            )
+        }
+    }
+
+    /// Returns the data of the value as UTF-16 including terminators, e.g., for
+    /// `REG_SZ`. A trailing odd byte is ignored.
+    #[expect(clippy::cast_ptr_alignment)]
+    pub(crate) fn utf16(&self) -> &[u16] {
+        let data = self.data();
+        // The data is at offset 12 of the allocation, so 2-byte aligned.
+        unsafe { slice::from_raw_parts(data.as_ptr().cast::<u16>(), data.len() / 2) }
+    }
+}
+
+impl Drop for Value {
+    fn drop(&mut self) {
+        unsafe { pool::free(self.0.cast(), Tag::Registry) };
+    }
+}
+
+/// An open key, closed when dropped.
+struct Key(HANDLE);
+
+impl Key {
+    fn open(key_path: PCUNICODE_STRING, access: ACCESS_MASK) -> Result<Self, NTSTATUS> {
+        let mut attributes = Self::attributes(key_path);
+        let mut key = ptr::null_mut();
+        let status = unsafe { ZwOpenKey(&raw mut key, access, &raw mut attributes) };
+        if NT_SUCCESS(status) {
+            Ok(Self(key))
+        } else {
+            Err(status)
+        }
+    }
+
+    /// Opens the key, creating it as a non-volatile key if it does not exist.
+    fn create(key_path: PCUNICODE_STRING, access: ACCESS_MASK) -> Result<Self, NTSTATUS> {
+        let mut attributes = Self::attributes(key_path);
+        let mut key = ptr::null_mut();
+        let status = unsafe {
+            ZwCreateKey(
+                &raw mut key,
+                access,
+                &raw mut attributes,
+                0,
+                ptr::null_mut(),
+                REG_OPTION_NON_VOLATILE,
+                ptr::null_mut(),
+            )
+        };
+        if NT_SUCCESS(status) {
+            Ok(Self(key))
+        } else {
+            Err(status)
+        }
+    }
+
+    fn attributes(key_path: PCUNICODE_STRING) -> OBJECT_ATTRIBUTES {
+        OBJECT_ATTRIBUTES {
+            Length: size_of::<OBJECT_ATTRIBUTES>() as _,
+            RootDirectory: ptr::null_mut(),
+            ObjectName: key_path.cast_mut(),
+            Attributes: OBJ_KERNEL_HANDLE | OBJ_CASE_INSENSITIVE,
+            SecurityDescriptor: ptr::null_mut(),
+            SecurityQualityOfService: ptr::null_mut(),
+        }
+    }
+}
+
+impl Drop for Key {
+    fn drop(&mut self) {
+        let _ = unsafe { ZwClose(self.0) };
+    }
+}
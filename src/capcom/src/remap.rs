@@ -0,0 +1,112 @@
+//! The execution of user-mode payloads by the `hardened` personality. Instead
+//! of calling through the user-mode pointer, the pages of the payload are
+//! copied into pages described by an MDL, which are mapped into system space
+//! read-only and executable, and the payload is validated and called there. The
+//! caller cannot change the code once validated, and user-mode pages are never
+//! executed in kernel-mode.
+//!
+//! Only [`WINDOW_SIZE`] bytes from the page of the payload are copied, up to
+//! the first page that cannot be read, so a payload must not reach code or data
+//! outside them by relative addresses.
+
+use core::{ptr, slice};
+
+use wdk_sys::{
+    _MEMORY_CACHING_TYPE::MmCached,
+    _MM_PAGE_PRIORITY::NormalPagePriority,
+    _MODE::KernelMode,
+    FALSE, MM_ALLOCATE_FULLY_REQUIRED, NT_SUCCESS, NTSTATUS, PAGE_EXECUTE_READ, PAGE_SIZE,
+    PHYSICAL_ADDRESS, PMDL, PVOID, STATUS_INSUFFICIENT_RESOURCES, ULONG,
+    ntddk::{
+        ExFreePoolWithTag, MmAllocatePagesForMdlEx, MmFreePagesFromMdl,
+        MmMapLockedPagesSpecifyCache, MmProtectMdlSystemAddress, MmUnmapLockedPages,
+    },
+};
+
+use crate::payload;
+
+/// The number of bytes copied from the start of the page of a payload.
+const WINDOW_SIZE: usize = 0x10000;
+
+/// A copy of the pages of a payload, mapped read-only and executable.
+pub(crate) struct Remapped {
+    mdl: PMDL,
+    mapping: PVOID,
+    /// The address of the page the copy starts at.
+    base: usize,
+    /// The number of bytes copied.
+    length: usize,
+}
+
+impl Remapped {
+    /// Copies the pages from that of the user-mode address `address`.
+    pub(crate) fn copy(address: usize) -> Result<Self, NTSTATUS> {
+        let zero = PHYSICAL_ADDRESS { QuadPart: 0 };
+        let mdl = unsafe {
+            MmAllocatePagesForMdlEx(
+                zero,
+                PHYSICAL_ADDRESS { QuadPart: -1 },
+                zero,
+                WINDOW_SIZE as _,
+                MmCached,
+                MM_ALLOCATE_FULLY_REQUIRED,
+            )
+        };
+        if mdl.is_null() {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+        let mapping = unsafe {
+            MmMapLockedPagesSpecifyCache(
+                mdl,
+                KernelMode as _,
+                MmCached,
+                ptr::null_mut(),
+                FALSE as _,
+                NormalPagePriority as ULONG,
+            )
+        };
+        let mut remapped = Self {
+            mdl,
+            mapping,
+            base: address & !(PAGE_SIZE as usize - 1),
+            length: 0,
+        };
+        if mapping.is_null() {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+
+        let window = unsafe { slice::from_raw_parts_mut(mapping.cast(), WINDOW_SIZE) };
+        remapped.length = payload::copy_user(remapped.base, window);
+        let status = unsafe { MmProtectMdlSystemAddress(mdl, PAGE_EXECUTE_READ) };
+        if !NT_SUCCESS(status) {
+            return Err(status);
+        }
+        Ok(remapped)
+    }
+
+    /// Returns the copy of the user-mode address `address` and the bytes after
+    /// it, or an empty slice if it is not copied.
+    pub(crate) fn code(&self, address: usize) -> &[u8] {
+        let offset = address.wrapping_sub(self.base).min(self.length);
+        let copied = unsafe { slice::from_raw_parts(self.mapping.cast(), self.length) };
+        &copied[offset..]
+    }
+
+    /// Returns the address of the copy of the user-mode address `address`,
+    /// which must be copied.
+    pub(crate) fn translate(&self, address: usize) -> usize {
+        self.mapping.addr() + (address - self.base)
+    }
+}
+
+impl Drop for Remapped {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.mapping.is_null() {
+                MmUnmapLockedPages(self.mapping, self.mdl);
+            }
+            MmFreePagesFromMdl(self.mdl);
+            ExFreePoolWithTag(self.mdl.cast(), 0);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! `IOCTL_ENABLE_EVENT_RING`, streaming events into a ring buffer in the memory
+//! shared with `IOCTL_MAP_SHARED`, so a client can trace them as they happen
+//! instead of polling `IOCTL_READ_LOG`.
+//!
+//! The driver is the only producer and advances the head, and the client is
+//! the only consumer and advances the tail, following `capcom_abi::ring`.
+//! Producers are serialized with the lock of the ring, and never read back what
+//! the client can write, except the tail to check for room. An event is
+//! signaled for every record appended.
+//!
+//! One ring is active at a time. It is detached before the shared memory is
+//! unmapped as the handle owning it is cleaned up.
+
+use core::ptr;
+
+use capcom_abi::{
+    EVENT_KIND_IOCTL, EVENT_KIND_MESSAGE, EventRecord, EventRingHeader, EventRingInfo, LogRecord,
+    MessageRecord, ring::Producer,
+};
+use wdk_sys::{
+    _EVENT_TYPE::SynchronizationEvent,
+    _MODE::{KernelMode, UserMode},
+    ACCESS_MASK, FALSE, HANDLE, NT_SUCCESS, NTSTATUS, OBJ_KERNEL_HANDLE, OBJECT_ATTRIBUTES,
+    PKEVENT, STATUS_BUFFER_TOO_SMALL, STATUS_DEVICE_BUSY, SYNCHRONIZE,
+    ntddk::{KeSetEvent, ObfDereferenceObject, ZwClose},
+};
+
+use crate::{
+    context::Context,
+    imports::{ObOpenObjectByPointer, ObReferenceObjectByHandle, ZwCreateEvent},
+    ioctl::Request,
+    shared::SharedMemory,
+    sync::SpinLock,
+};
+
+/// `EVENT_ALL_ACCESS` in ntifs.h.
+const EVENT_ALL_ACCESS: ACCESS_MASK = 0x001f_0003;
+
+/// The active ring.
+static RING: SpinLock<Option<Ring>> = SpinLock::new(None);
+
+/// A ring buffer in shared memory.
+struct Ring {
+    /// The handle owning the shared memory.
+    owner: *const Context,
+    producer: Producer,
+    /// The referenced event signaled for every record.
+    event: PKEVENT,
+}
+
+unsafe impl Send for Ring {}
+
+/// Handles `IOCTL_ENABLE_EVENT_RING`.
+pub(crate) fn enable_event_ring(
+    context: &Context,
+    request: &mut Request,
+) -> Result<usize, NTSTATUS> {
+    let (event, user_event) = create_event()?;
+    let result = context.with_shared_memory(|shared| unsafe { attach(context, shared, event) });
+    let capacity = match result {
+        Ok(Ok(capacity)) => capacity,
+        Ok(Err(status)) | Err(status) => {
+            unsafe {
+                let _ = ZwClose(user_event);
+                let _ = ObfDereferenceObject(event.cast());
+            }
+            return Err(status);
+        }
+    };
+    request.write_output(&EventRingInfo {
+        event: user_event.addr() as u64,
+        capacity: capacity as u32,
+        reserved: 0,
+    })
+}
+
+/// Formats `shared` as the ring and makes it active. `shared` must stay mapped
+/// until [`detach`] is called for `owner`.
+unsafe fn attach(owner: &Context, shared: &SharedMemory, event: PKEVENT) -> Result<u64, NTSTATUS> {
+    let capacity = capcom_abi::ring::capacity(shared.size);
+    if capacity == 0 {
+        return Err(STATUS_BUFFER_TOO_SMALL);
+    }
+    let mut ring = RING.lock();
+    if ring.is_some() {
+        return Err(STATUS_DEVICE_BUSY);
+    }
+
+    *ring = Some(Ring {
+        owner: ptr::from_ref(owner),
+        producer: unsafe { Producer::format(shared.address.cast::<EventRingHeader>(), capacity) },
+        event,
+    });
+    Ok(capacity)
+}
+
+/// Deactivates the ring if `owner` owns it.
+pub(crate) fn detach(owner: &Context) {
+    let detached = RING
+        .lock()
+        .take_if(|ring| ring.owner == ptr::from_ref(owner));
+    if let Some(ring) = detached {
+        let _ = unsafe { ObfDereferenceObject(ring.event.cast()) };
+    }
+}
+
+/// Appends `record` to the active ring, if any.
+pub(crate) fn push_log(record: &LogRecord) {
+    push(&EventRecord {
+        kind: EVENT_KIND_IOCTL,
+        log: *record,
+        ..EventRecord::default()
+    });
+}
+
+/// Appends `message` to the active ring, if any.
+pub(crate) fn push_message(message: &MessageRecord) {
+    push(&EventRecord {
+        kind: EVENT_KIND_MESSAGE,
+        message: *message,
+        ..EventRecord::default()
+    });
+}
+
+/// Appends `record` to the active ring, if any. If the ring is full, the
+/// record is dropped and counted in [`EventRingHeader::dropped`].
+fn push(record: &EventRecord) {
+    let mut ring = RING.lock();
+    let Some(ring) = ring.as_mut() else {
+        return;
+    };
+    if ring.producer.push(record) {
+        let _ = unsafe { KeSetEvent(ring.event, 0, FALSE as _) };
+    }
+}
+
+/// Creates an auto-reset event, and returns it referenced and a handle to it
+/// in the current process.
+fn create_event() -> Result<(PKEVENT, HANDLE), NTSTATUS> {
+    let mut attributes = OBJECT_ATTRIBUTES {
+        Length: size_of::<OBJECT_ATTRIBUTES>() as _,
+        RootDirectory: ptr::null_mut(),
+        ObjectName: ptr::null_mut(),
+        Attributes: OBJ_KERNEL_HANDLE,
+        SecurityDescriptor: ptr::null_mut(),
+        SecurityQualityOfService: ptr::null_mut(),
+    };
+    let mut handle = ptr::null_mut();
+    let status = unsafe {
+        ZwCreateEvent(
+            &raw mut handle,
+            EVENT_ALL_ACCESS,
+            &raw mut attributes,
+            SynchronizationEvent,
+            FALSE as _,
+        )
+    };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+
+    // The kernel handle cannot be replaced by the client, so the object is
+    // known to be an event.
+    let mut event = ptr::null_mut();
+    let status = unsafe {
+        ObReferenceObjectByHandle(
+            handle,
+            EVENT_ALL_ACCESS,
+            ptr::null_mut(),
+            KernelMode as _,
+            &raw mut event,
+            ptr::null_mut(),
+        )
+    };
+    let _ = unsafe { ZwClose(handle) };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+
+    let mut user_event = ptr::null_mut();
+    let status = unsafe {
+        ObOpenObjectByPointer(
+            event,
+            0,
+            ptr::null_mut(),
+            SYNCHRONIZE,
+            ptr::null_mut(),
+            UserMode as _,
+            &raw mut user_event,
+        )
+    };
+    if !NT_SUCCESS(status) {
+        let _ = unsafe { ObfDereferenceObject(event) };
+        return Err(status);
+    }
+    Ok((event.cast(), user_event))
+}
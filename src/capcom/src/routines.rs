@@ -0,0 +1,215 @@
+//! Kernel routines newer than Windows 7, resolved with
+//! `MmGetSystemRoutineAddress` when the driver loads instead of being imported,
+//! so that the driver also loads on the older builds the original Capcom
+//! driver is commonly studied on. Where a routine is not exported, the legacy
+//! routine it replaced is used instead:
+//!
+//! - `ExAllocatePool2` (Windows 10 2004): `ExAllocatePoolWithTag`, zeroing the
+//!   allocation as `ExAllocatePool2` does.
+//! - `MmCopyMemory` (Windows 8.1): `MmIsAddressValid` for each page of virtual
+//!   memory, and `MmMapIoSpace` for physical memory.
+//! - `ExGetSystemFirmwareTable` (Windows 10): none. Firmware tables are
+//!   reported as not found, and PCI configuration falls back to the I/O ports.
+//!
+//! `IoCreateDevice` is used for the devices on every build, as their security
+//! is set by `control` instead of an SDDL string of `IoCreateDeviceSecure`.
+
+use core::{
+    mem, ptr, slice,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use wdk_sys::{
+    _MEMORY_CACHING_TYPE::MmCached,
+    _POOL_TYPE::{NonPagedPool, NonPagedPoolNx, PagedPool},
+    MM_COPY_ADDRESS, MM_COPY_MEMORY_PHYSICAL, NTSTATUS, PAGE_SIZE, PHYSICAL_ADDRESS,
+    POOL_FLAG_NON_PAGED, POOL_FLAG_PAGED, POOL_FLAG_UNINITIALIZED, POOL_FLAGS, PSIZE_T, PULONG,
+    PVOID, SIZE_T, STATUS_ACCESS_VIOLATION, STATUS_NOT_FOUND, STATUS_SUCCESS, ULONG,
+    UNICODE_STRING,
+    ntddk::{
+        ExAllocatePoolWithTag, MmGetSystemRoutineAddress, MmIsAddressValid, MmMapIoSpace,
+        MmUnmapIoSpace,
+    },
+};
+
+use crate::{offsets, trace::trace};
+
+/// The first build with `NonPagedPoolNx`, Windows 8.
+const NON_PAGED_POOL_NX_BUILD: u32 = 9200;
+
+type ExAllocatePool2Fn = unsafe extern "system" fn(POOL_FLAGS, SIZE_T, ULONG) -> PVOID;
+type MmCopyMemoryFn =
+    unsafe extern "system" fn(PVOID, MM_COPY_ADDRESS, SIZE_T, ULONG, PSIZE_T) -> NTSTATUS;
+type ExGetSystemFirmwareTableFn =
+    unsafe extern "system" fn(ULONG, ULONG, PVOID, ULONG, PULONG) -> NTSTATUS;
+
+/// The address of `ExAllocatePool2`, or zero if not exported.
+static EX_ALLOCATE_POOL2: AtomicUsize = AtomicUsize::new(0);
+
+/// The address of `MmCopyMemory`, or zero if not exported.
+static MM_COPY_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// The address of `ExGetSystemFirmwareTable`, or zero if not exported.
+static EX_GET_SYSTEM_FIRMWARE_TABLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Resolves the routines. Must be called before anything else in
+/// `DriverEntry`, as the pool is allocated through [`allocate_pool`].
+pub(crate) fn resolve() {
+    for (name, address) in [
+        (&b"ExAllocatePool2"[..], &EX_ALLOCATE_POOL2),
+        (b"MmCopyMemory", &MM_COPY_MEMORY),
+        (b"ExGetSystemFirmwareTable", &EX_GET_SYSTEM_FIRMWARE_TABLE),
+    ] {
+        let resolved = system_routine(name);
+        if resolved == 0 {
+            trace!(LEGACY_ROUTINE; name);
+        }
+        address.store(resolved, Ordering::Relaxed);
+    }
+}
+
+/// Returns the address of the routine exported from ntoskrnl.exe or hal.dll
+/// as `name`, or zero if not exported.
+pub(crate) fn system_routine(name: &[u8]) -> usize {
+    // `MmGetSystemRoutineAddress` takes the name in UTF-16, and no routine
+    // name is longer.
+    let mut buffer = [0u16; 64];
+    for (wide, &byte) in buffer.iter_mut().zip(name) {
+        *wide = byte.into();
+    }
+    let length = (name.len().min(buffer.len()) * size_of::<u16>()) as u16;
+    let mut name = UNICODE_STRING {
+        Length: length,
+        MaximumLength: length,
+        Buffer: buffer.as_mut_ptr(),
+    };
+    unsafe { MmGetSystemRoutineAddress(&raw mut name) }.addr()
+}
+
+/// Allocates `size` bytes of pool as `ExAllocatePool2` does, or returns null.
+pub(crate) fn allocate_pool(flags: POOL_FLAGS, size: usize, tag: u32) -> PVOID {
+    let address = EX_ALLOCATE_POOL2.load(Ordering::Relaxed);
+    if address != 0 {
+        let allocate = unsafe { mem::transmute::<usize, ExAllocatePool2Fn>(address) };
+        return unsafe { allocate(flags, size as _, tag) };
+    }
+
+    let pool_type = if flags & POOL_FLAG_PAGED != 0 {
+        PagedPool
+    } else if flags & POOL_FLAG_NON_PAGED != 0 && offsets::build_number() >= NON_PAGED_POOL_NX_BUILD
+    {
+        NonPagedPoolNx
+    } else {
+        // Executable non-paged pool, and the only non-paged pool before
+        // Windows 8.
+        NonPagedPool
+    };
+    let memory = unsafe { ExAllocatePoolWithTag(pool_type, size as _, tag) };
+    if !memory.is_null() && flags & POOL_FLAG_UNINITIALIZED == 0 {
+        unsafe { ptr::write_bytes(memory.cast::<u8>(), 0, size) };
+    }
+    memory
+}
+
+/// Copies memory as `MmCopyMemory` does, returning the status and the number
+/// of bytes copied.
+pub(crate) fn copy_memory(
+    buffer: &mut [u8],
+    source: MM_COPY_ADDRESS,
+    flags: ULONG,
+) -> (NTSTATUS, usize) {
+    let address = MM_COPY_MEMORY.load(Ordering::Relaxed);
+    if address != 0 {
+        let copy = unsafe { mem::transmute::<usize, MmCopyMemoryFn>(address) };
+        let mut transferred = 0;
+        let status = unsafe {
+            copy(
+                buffer.as_mut_ptr().cast(),
+                source,
+                buffer.len() as _,
+                flags,
+                &raw mut transferred,
+            )
+        };
+        return (status, transferred as usize);
+    }
+
+    let physical = flags == MM_COPY_MEMORY_PHYSICAL;
+    let mut source = if physical {
+        unsafe {
+            source
+                .__bindgen_anon_1
+                .PhysicalAddress
+                .QuadPart
+                .cast_unsigned() as usize
+        }
+    } else {
+        unsafe { source.__bindgen_anon_1.VirtualAddress }.addr()
+    };
+    let mut copied = 0;
+    while copied < buffer.len() {
+        let length = (PAGE_SIZE as usize - source % PAGE_SIZE as usize).min(buffer.len() - copied);
+        let destination = &mut buffer[copied..copied + length];
+        let copied_page = if physical {
+            copy_physical_page(source as u64, destination)
+        } else {
+            copy_virtual_page(source, destination)
+        };
+        if !copied_page {
+            return (STATUS_ACCESS_VIOLATION, copied);
+        }
+        copied += length;
+        source += length;
+    }
+    (STATUS_SUCCESS, copied)
+}
+
+/// Copies the virtual memory at `address` within a page into `buffer` if the
+/// page is mapped.
+fn copy_virtual_page(address: usize, buffer: &mut [u8]) -> bool {
+    let source = ptr::without_provenance::<u8>(address);
+    if unsafe { MmIsAddressValid(source.cast_mut().cast()) } == 0 {
+        return false;
+    }
+    buffer.copy_from_slice(unsafe { slice::from_raw_parts(source, buffer.len()) });
+    true
+}
+
+/// Copies the physical memory at `address` within a page into `buffer` by
+/// mapping it.
+fn copy_physical_page(address: u64, buffer: &mut [u8]) -> bool {
+    let physical = PHYSICAL_ADDRESS {
+        QuadPart: address.cast_signed(),
+    };
+    let mapped = unsafe { MmMapIoSpace(physical, buffer.len() as _, MmCached) }.cast::<u8>();
+    if mapped.is_null() {
+        return false;
+    }
+    buffer.copy_from_slice(unsafe { slice::from_raw_parts(mapped, buffer.len()) });
+    unsafe { MmUnmapIoSpace(mapped.cast(), buffer.len() as _) };
+    true
+}
+
+/// Calls `ExGetSystemFirmwareTable`, or returns `STATUS_NOT_FOUND` if not
+/// exported.
+pub(crate) fn get_system_firmware_table(
+    provider: ULONG,
+    table_id: ULONG,
+    buffer: &mut [u8],
+    return_length: &mut ULONG,
+) -> NTSTATUS {
+    let address = EX_GET_SYSTEM_FIRMWARE_TABLE.load(Ordering::Relaxed);
+    if address == 0 {
+        return STATUS_NOT_FOUND;
+    }
+    let get = unsafe { mem::transmute::<usize, ExGetSystemFirmwareTableFn>(address) };
+    unsafe {
+        get(
+            provider,
+            table_id,
+            buffer.as_mut_ptr().cast(),
+            buffer.len() as _,
+            return_length,
+        )
+    }
+}
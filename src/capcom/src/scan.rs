@@ -0,0 +1,173 @@
+//! `IOCTL_SCAN_MEMORY`, searching memory for a byte pattern with wildcards in
+//! kernel mode, so that clients do not copy out a whole range to search it.
+//!
+//! Memory is read in small chunks into a buffer on the stack, after the bytes
+//! at the end of the previous chunk that a match may start at. Pages that
+//! cannot be read, and holes in a physical range that are not RAM, are skipped
+//! and drop those bytes, so a match never spans them.
+
+use capcom_abi::{
+    CLASS_KERNEL_MEMORY, CLASS_PHYSICAL_MEMORY, MAX_SCAN_PATTERN_LENGTH, SCAN_PHYSICAL,
+    ScanRequest, ScanResult,
+};
+use wdk_sys::{NTSTATUS, PAGE_SIZE, STATUS_BUFFER_TOO_SMALL, STATUS_INVALID_PARAMETER};
+
+use crate::{
+    context::Context,
+    dump::{self, MemoryRanges},
+    ioctl::Request,
+};
+
+/// The number of bytes read at once. Pages are a multiple of it.
+const CHUNK_SIZE: usize = 0x400;
+
+/// The number of bytes a request reads at most before returning the cursor.
+const BYTES_PER_REQUEST: u64 = 0x400_0000;
+
+/// Handles `IOCTL_SCAN_MEMORY`.
+pub(crate) fn scan_memory(context: &Context, request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<ScanRequest>()?;
+    let physical = input.flags & SCAN_PHYSICAL != 0;
+    context.check_access(
+        if physical {
+            CLASS_PHYSICAL_MEMORY
+        } else {
+            CLASS_KERNEL_MEMORY
+        },
+        false,
+    )?;
+    let Some(end) = input.address.checked_add(input.size) else {
+        return Err(STATUS_INVALID_PARAMETER);
+    };
+    if input.flags & !SCAN_PHYSICAL != 0 || input.cursor > input.size {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    let pattern = Pattern::read(request, input.pattern_length as usize)?;
+    let length = pattern.length;
+
+    let capacity = request
+        .output_mut()
+        .len()
+        .saturating_sub(size_of::<ScanResult>())
+        / size_of::<u64>();
+    if capacity == 0 {
+        return Err(STATUS_BUFFER_TOO_SMALL);
+    }
+    let ranges = if physical {
+        Some(MemoryRanges::get()?)
+    } else {
+        None
+    };
+
+    let mut buffer = [0; MAX_SCAN_PATTERN_LENGTH - 1 + CHUNK_SIZE];
+    // The number of bytes at the start of `buffer` that end at `position`.
+    let mut carried = 0;
+    let mut position = input.address + input.cursor;
+    let mut budget = BYTES_PER_REQUEST;
+    let mut count = 0;
+    while position < end && budget != 0 {
+        let mut chunk_end = ((position | (CHUNK_SIZE as u64 - 1)) + 1).min(end);
+        if let Some(ranges) = &ranges {
+            match ranges
+                .iter()
+                .filter(|&(_, range_end)| range_end > position)
+                .min_by_key(|&(start, _)| start)
+            {
+                Some((start, range_end)) if start <= position => {
+                    chunk_end = chunk_end.min(range_end);
+                }
+                next => {
+                    // Skip to the next range of RAM.
+                    position = next.map_or(end, |(start, _)| start.min(end));
+                    carried = 0;
+                    continue;
+                }
+            }
+        }
+
+        let chunk_length = (chunk_end - position) as usize;
+        let target = &mut buffer[carried..carried + chunk_length];
+        let transferred = dump::copy_memory(position, target, physical);
+        let data_length = carried + transferred;
+        let data_address = position - carried as u64;
+        for start in 0..(data_length + 1).saturating_sub(length) {
+            if !pattern.matches(&buffer[start..start + length]) {
+                continue;
+            }
+            if count == capacity {
+                return write_result(request, data_address + start as u64 - input.address, count);
+            }
+            let _ = request.write_output_at(
+                size_of::<ScanResult>() + count * size_of::<u64>(),
+                &(data_address + start as u64),
+            )?;
+            count += 1;
+        }
+        budget = budget.saturating_sub(chunk_length as u64);
+
+        if transferred == chunk_length {
+            let kept = data_length.min(length - 1);
+            buffer.copy_within(data_length - kept..data_length, 0);
+            carried = kept;
+            position = chunk_end;
+        } else {
+            // Skip the rest of the page that cannot be read.
+            carried = 0;
+            position = ((position | (PAGE_SIZE as u64 - 1)) + 1).min(end);
+        }
+    }
+
+    let cursor = if position < end {
+        position - carried as u64 - input.address
+    } else {
+        input.size
+    };
+    write_result(request, cursor, count)
+}
+
+/// The pattern of a request with its mask, copied out of the input buffer, as
+/// the output buffer shares the memory.
+struct Pattern {
+    bytes: [u8; MAX_SCAN_PATTERN_LENGTH],
+    mask: [u8; MAX_SCAN_PATTERN_LENGTH],
+    length: usize,
+}
+
+impl Pattern {
+    /// Reads the pattern of `length` bytes and its mask after [`ScanRequest`].
+    fn read(request: &Request, length: usize) -> Result<Self, NTSTATUS> {
+        let offset = size_of::<ScanRequest>();
+        if length == 0 || length > MAX_SCAN_PATTERN_LENGTH {
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        let Some(input) = request.input().get(offset..offset + length * 2) else {
+            return Err(STATUS_INVALID_PARAMETER);
+        };
+        let mut pattern = Self {
+            bytes: [0; MAX_SCAN_PATTERN_LENGTH],
+            mask: [0; MAX_SCAN_PATTERN_LENGTH],
+            length,
+        };
+        pattern.bytes[..length].copy_from_slice(&input[..length]);
+        pattern.mask[..length].copy_from_slice(&input[length..]);
+        Ok(pattern)
+    }
+
+    /// Returns whether `data`, which is as long as the pattern, matches it.
+    fn matches(&self, data: &[u8]) -> bool {
+        data.iter()
+            .zip(self.bytes.iter().zip(&self.mask))
+            .all(|(&byte, (&expected, &mask))| byte & mask == expected & mask)
+    }
+}
+
+/// Writes [`ScanResult`] before `count` addresses written, and returns the
+/// number of bytes of the output.
+fn write_result(request: &mut Request, cursor: u64, count: usize) -> Result<usize, NTSTATUS> {
+    let _ = request.write_output(&ScanResult {
+        cursor,
+        count: count as u32,
+        reserved: 0,
+    })?;
+    Ok(size_of::<ScanResult>() + count * size_of::<u64>())
+}
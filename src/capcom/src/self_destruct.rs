@@ -0,0 +1,182 @@
+//! `IOCTL_SELF_DESTRUCT`, removing the driver from the system from kernel-mode.
+//!
+//! The symbolic link is deleted first so that no new handle is opened. The
+//! unload is then requested from a work item, which references the device
+//! object. This makes the I/O manager defer the unload until the work item
+//! returns and all handles are closed, so the image is not unmapped while its
+//! code is still running. `DriverUnload` deletes the device and unregisters
+//! the ETW provider as usual.
+
+use core::{
+    slice,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+};
+
+use capcom_abi::{SELF_DESTRUCT_DELETE_FILE, SELF_DESTRUCT_DELETE_SERVICE, SelfDestructRequest};
+use wdk_sys::{
+    _WORK_QUEUE_TYPE::DelayedWorkQueue,
+    NTSTATUS, PCUNICODE_STRING, PDEVICE_OBJECT, POOL_FLAG_PAGED, PVOID, REG_EXPAND_SZ,
+    REG_MULTI_SZ, REG_SZ, STATUS_DELETE_PENDING, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_PARAMETER, STATUS_NOT_SUPPORTED, STATUS_OBJECT_NAME_NOT_FOUND,
+    STATUS_OBJECT_TYPE_MISMATCH,
+    ntddk::{IoAllocateWorkItem, IoFreeWorkItem, IoQueueWorkItem},
+};
+
+use crate::{
+    RTL_CONSTANT_STRING, config, delete_link,
+    imports::ZwUnloadDriver,
+    ioctl::Request,
+    pool::{self, Tag},
+    registry,
+    trace::trace,
+};
+
+/// Whether `IOCTL_SELF_DESTRUCT` was accepted.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// `SELF_DESTRUCT_*` flags of the accepted request.
+static FLAGS: AtomicU32 = AtomicU32::new(0);
+
+/// Handles `IOCTL_SELF_DESTRUCT` sent to `device`.
+pub(crate) fn self_destruct(device: PDEVICE_OBJECT, request: &Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<SelfDestructRequest>()?;
+    if input.flags & !(SELF_DESTRUCT_DELETE_SERVICE | SELF_DESTRUCT_DELETE_FILE) != 0 {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    if config::service_key().is_none() {
+        return Err(STATUS_NOT_SUPPORTED);
+    }
+    if REQUESTED.swap(true, Ordering::AcqRel) {
+        return Err(STATUS_DELETE_PENDING);
+    }
+
+    let work_item = unsafe { IoAllocateWorkItem(device) };
+    if work_item.is_null() {
+        REQUESTED.store(false, Ordering::Release);
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    FLAGS.store(input.flags, Ordering::Relaxed);
+    delete_link();
+    trace!(SELF_DESTRUCT_REQUESTED, input.flags);
+    unsafe {
+        IoQueueWorkItem(
+            work_item,
+            Some(unload_worker),
+            DelayedWorkQueue,
+            work_item.cast(),
+        );
+    }
+    Ok(0)
+}
+
+/// Requests the unload and removes the service and file as requested. Runs at
+/// `PASSIVE_LEVEL` in the system process with `context` being the work item.
+unsafe extern "C" fn unload_worker(_device: PDEVICE_OBJECT, context: PVOID) {
+    let flags = FLAGS.load(Ordering::Relaxed);
+    // `self_destruct` refused the request without the service key.
+    let Some(service_key) = config::service_key() else {
+        trace!(SERVICE_KEY_UNKNOWN);
+        unsafe { IoFreeWorkItem(context.cast()) };
+        return;
+    };
+    let mut key_path = service_key.as_unicode_string();
+
+    // Read the image path before the service key may be deleted.
+    if flags & SELF_DESTRUCT_DELETE_FILE != 0
+        && let Err(status) = mark_image_for_deletion(&raw const key_path)
+    {
+        trace!(FILE_DELETION_FAILED, status);
+    }
+
+    let status = unsafe { ZwUnloadDriver(&raw mut key_path) };
+    trace!(UNLOAD_REQUESTED, status);
+
+    if flags & SELF_DESTRUCT_DELETE_SERVICE != 0
+        && let Err(status) = delete_service_key(&raw const key_path)
+    {
+        trace!(SERVICE_DELETION_FAILED, status);
+    }
+
+    unsafe { IoFreeWorkItem(context.cast()) };
+}
+
+/// Deletes the service key and the `Parameters` subkey `IOCTL_SAVE_CONFIG` may
+/// have created, as keys with subkeys cannot be deleted.
+fn delete_service_key(service_key: PCUNICODE_STRING) -> Result<(), NTSTATUS> {
+    if let Some(parameters_key) = config::parameters_key() {
+        let parameters_path = parameters_key.as_unicode_string();
+        match registry::delete_key(&raw const parameters_path) {
+            Ok(()) | Err(STATUS_OBJECT_NAME_NOT_FOUND) => {}
+            Err(status) => return Err(status),
+        }
+    }
+    registry::delete_key(service_key)
+}
+
+/// Adds the image file of the service to `PendingFileRenameOperations`, so that
+/// the session manager deletes it on the next reboot.
+fn mark_image_for_deletion(service_key: PCUNICODE_STRING) -> Result<(), NTSTATUS> {
+    const BACKSLASH: u16 = b'\\' as u16;
+
+    let session_manager = RTL_CONSTANT_STRING(&utf16_lit::utf16!(
+        "\\Registry\\Machine\\SYSTEM\\CurrentControlSet\\Control\\Session Manager"
+    ));
+    let value_name = utf16_lit::utf16!("PendingFileRenameOperations");
+
+    let image = registry::read_value(service_key, &utf16_lit::utf16!("ImagePath"))?;
+    if image.value_type() != REG_SZ && image.value_type() != REG_EXPAND_SZ {
+        return Err(STATUS_OBJECT_TYPE_MISMATCH);
+    }
+    let image_path = trim_terminators(image.utf16());
+    // Without a leading backslash, the path is relative to the system root,
+    // e.g., `System32\drivers\capcom.sys`.
+    let prefix: &[u16] = if image_path.first() == Some(&BACKSLASH) {
+        &[]
+    } else {
+        &utf16_lit::utf16!("\\SystemRoot\\")
+    };
+
+    // Each operation is a pair of a source and a target, and an empty target
+    // means deletion. Remove only the terminator of the list, as the last
+    // string may be an empty target.
+    let pending = match registry::read_value(&raw const session_manager, &value_name) {
+        Ok(value) if value.value_type() == REG_MULTI_SZ => Some(value),
+        Ok(_) => return Err(STATUS_OBJECT_TYPE_MISMATCH),
+        Err(STATUS_OBJECT_NAME_NOT_FOUND) => None,
+        Err(status) => return Err(status),
+    };
+    let existing = pending.as_ref().map_or(&[][..], |value| {
+        let units = value.utf16();
+        units.strip_suffix(&[0]).unwrap_or(units)
+    });
+
+    let length = existing.len() + prefix.len() + image_path.len() + 3;
+    let buffer = pool::allocate(POOL_FLAG_PAGED, length * 2, Tag::SelfDestruct);
+    if buffer.is_null() {
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    // The pool is zeroed, so the terminators are already in place.
+    let operations = unsafe { slice::from_raw_parts_mut(buffer.cast::<u16>(), length) };
+    let mut offset = 0;
+    for part in [existing, prefix, image_path] {
+        operations[offset..offset + part.len()].copy_from_slice(part);
+        offset += part.len();
+    }
+    let result = registry::write_value(
+        &raw const session_manager,
+        &value_name,
+        REG_MULTI_SZ,
+        unsafe { slice::from_raw_parts(buffer.cast::<u8>(), length * 2) },
+    );
+    unsafe { pool::free(buffer, Tag::SelfDestruct) };
+    result
+}
+
+/// Returns `units` without trailing null characters.
+fn trim_terminators(units: &[u16]) -> &[u16] {
+    let length = units
+        .iter()
+        .rposition(|&unit| unit != 0)
+        .map_or(0, |i| i + 1);
+    &units[..length]
+}
@@ -0,0 +1,59 @@
+//! `IOCTL_SELF_TEST`, checking the internal subsystems, so that failing tests
+//! can be told apart as problems of the environment or bugs of the driver.
+
+use core::slice;
+
+#[cfg(not(feature = "defanged"))]
+use capcom_abi::SELF_TEST_PROTECTION;
+use capcom_abi::{SELF_TEST_ALLOCATOR, SELF_TEST_LOG_RING, SELF_TEST_OFFSETS, SelfTestResult};
+use wdk_sys::{NTSTATUS, POOL_FLAG_NON_PAGED};
+
+#[cfg(not(feature = "defanged"))]
+use crate::arch;
+use crate::{
+    ioctl::Request,
+    log, offsets,
+    pool::{self, Tag},
+    trace::trace,
+};
+
+/// Handles `IOCTL_SELF_TEST`.
+pub(crate) fn self_test(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let mut result = SelfTestResult::default();
+    let mut check = |flag, passed: fn() -> bool| {
+        result.checks |= flag;
+        if !passed() {
+            result.failures |= flag;
+        }
+    };
+    check(SELF_TEST_ALLOCATOR, check_allocator);
+    check(SELF_TEST_LOG_RING, log::self_test);
+    // The defanged build leaves the control registers alone.
+    #[cfg(not(feature = "defanged"))]
+    check(SELF_TEST_PROTECTION, arch::check_protection);
+    check(SELF_TEST_OFFSETS, offsets::self_test);
+
+    if result.failures != 0 {
+        trace!(SELF_TEST_FAILED, result.failures);
+    }
+    request.write_output(&result)
+}
+
+/// Allocates non-paged pool, and checks that it is writable.
+fn check_allocator() -> bool {
+    const SIZE: usize = 64;
+    const PATTERN: u8 = 0xa5;
+
+    let memory = pool::allocate(POOL_FLAG_NON_PAGED, SIZE, Tag::SelfTest).cast::<u8>();
+    if memory.is_null() {
+        return false;
+    }
+    unsafe {
+        memory.write_bytes(PATTERN, SIZE);
+        let written = slice::from_raw_parts(memory, SIZE)
+            .iter()
+            .all(|&byte| byte == PATTERN);
+        pool::free(memory.cast(), Tag::SelfTest);
+        written
+    }
+}
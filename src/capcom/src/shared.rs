@@ -0,0 +1,184 @@
+//! `IOCTL_MAP_SHARED`, memory shared between the driver and the process that
+//! opened a handle, e.g., to pass large data without copying it through the
+//! system buffer.
+//!
+//! The memory is non-paged and physically contiguous, so the driver can access
+//! it at any IRQL. It is mapped into the process with a view of
+//! `\Device\PhysicalMemory` rather than with `MmMapLockedPagesSpecifyCache`,
+//! which raises an exception on failure and bug checks if the process exits
+//! before the memory is unmapped. The view is unmapped when the handle is
+//! cleaned up. If the process already exited, the view is already gone.
+
+use core::ptr;
+
+use capcom_abi::{SharedMemoryInfo, SharedMemoryRequest};
+use wdk_sys::{
+    _MEMORY_CACHING_TYPE::MmCached,
+    _SECTION_INHERIT::ViewUnmap,
+    ACCESS_MASK, HANDLE, LARGE_INTEGER, NT_SUCCESS, NTSTATUS, OBJ_KERNEL_HANDLE, OBJECT_ATTRIBUTES,
+    PAGE_READWRITE, PAGE_SIZE, PHYSICAL_ADDRESS, SECTION_MAP_READ, SECTION_MAP_WRITE,
+    STATUS_INSUFFICIENT_RESOURCES, STATUS_INVALID_PARAMETER,
+    ntddk::{MmGetPhysicalAddress, PsGetCurrentProcessId, ZwClose},
+};
+
+use crate::{
+    RTL_CONSTANT_STRING,
+    context::Context,
+    imports::{
+        MmAllocateContiguousMemorySpecifyCache, MmFreeContiguousMemory, ZwMapViewOfSection,
+        ZwOpenSection, ZwUnmapViewOfSection,
+    },
+    ioctl::Request,
+    process::ProcessHandle,
+    trace::trace,
+};
+
+/// `PROCESS_VM_OPERATION` in ntifs.h.
+const PROCESS_VM_OPERATION: ACCESS_MASK = 0x0008;
+
+/// The largest size of shared memory. Larger contiguous allocations likely
+/// fail once physical memory is fragmented.
+const MAX_SIZE: usize = 16 * 1024 * 1024;
+
+/// Memory mapped into both the system space and a process.
+pub(crate) struct SharedMemory {
+    /// The kernel-mode address.
+    pub(crate) address: *mut u8,
+    /// The size in bytes, a multiple of the page size.
+    pub(crate) size: usize,
+    /// The user-mode address in the process.
+    user_address: *mut u8,
+    /// The process the memory is mapped into.
+    process: ProcessHandle,
+}
+
+unsafe impl Send for SharedMemory {}
+
+/// Handles `IOCTL_MAP_SHARED`.
+pub(crate) fn map_shared(context: &Context, request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<SharedMemoryRequest>()?;
+    let Some(size) = usize::try_from(input.size)
+        .ok()
+        .filter(|size| (1..=MAX_SIZE).contains(size))
+    else {
+        return Err(STATUS_INVALID_PARAMETER);
+    };
+    let size = size.next_multiple_of(PAGE_SIZE as usize);
+
+    let shared = SharedMemory::create(size)?;
+    let info = SharedMemoryInfo {
+        user_address: shared.user_address.addr() as u64,
+        kernel_address: shared.address.addr() as u64,
+        size: shared.size as u64,
+    };
+    context.set_shared_memory(shared)?;
+    trace!(
+        MEMORY_SHARED,
+        size,
+        info.kernel_address,
+        context.process_id.addr(),
+        info.user_address
+    );
+    request.write_output(&info)
+}
+
+impl SharedMemory {
+    /// Allocates zeroed memory of `size` bytes and maps it into the current
+    /// process.
+    fn create(size: usize) -> Result<Self, NTSTATUS> {
+        let process = ProcessHandle::open(
+            unsafe { PsGetCurrentProcessId() }.addr() as u64,
+            PROCESS_VM_OPERATION,
+        )?;
+        let address = unsafe {
+            MmAllocateContiguousMemorySpecifyCache(
+                size as _,
+                PHYSICAL_ADDRESS { QuadPart: 0 },
+                PHYSICAL_ADDRESS { QuadPart: -1 },
+                PHYSICAL_ADDRESS { QuadPart: 0 },
+                MmCached,
+            )
+        }
+        .cast::<u8>();
+        if address.is_null() {
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+        unsafe { ptr::write_bytes(address, 0, size) };
+
+        match unsafe { map_into_process(process.0, address, size) } {
+            Ok(user_address) => Ok(Self {
+                address,
+                size,
+                user_address,
+                process,
+            }),
+            Err(status) => {
+                unsafe { MmFreeContiguousMemory(address.cast()) };
+                Err(status)
+            }
+        }
+    }
+}
+
+impl Drop for SharedMemory {
+    fn drop(&mut self) {
+        // This fails if the process already exited, which unmapped the view.
+        let _ = unsafe { ZwUnmapViewOfSection(self.process.0, self.user_address.cast()) };
+        unsafe { MmFreeContiguousMemory(self.address.cast()) };
+    }
+}
+
+/// Maps `size` bytes of the physical memory behind `address` into `process`,
+/// and returns the user-mode address.
+unsafe fn map_into_process(
+    process: HANDLE,
+    address: *mut u8,
+    size: usize,
+) -> Result<*mut u8, NTSTATUS> {
+    let mut name = RTL_CONSTANT_STRING(&utf16_lit::utf16!("\\Device\\PhysicalMemory"));
+    let mut attributes = OBJECT_ATTRIBUTES {
+        Length: size_of::<OBJECT_ATTRIBUTES>() as _,
+        RootDirectory: ptr::null_mut(),
+        ObjectName: &raw mut name,
+        Attributes: OBJ_KERNEL_HANDLE,
+        SecurityDescriptor: ptr::null_mut(),
+        SecurityQualityOfService: ptr::null_mut(),
+    };
+    let mut section = ptr::null_mut();
+    let status = unsafe {
+        ZwOpenSection(
+            &raw mut section,
+            SECTION_MAP_READ | SECTION_MAP_WRITE,
+            &raw mut attributes,
+        )
+    };
+    if !NT_SUCCESS(status) {
+        return Err(status);
+    }
+
+    let mut user_address = ptr::null_mut();
+    let mut offset = LARGE_INTEGER {
+        QuadPart: unsafe { MmGetPhysicalAddress(address.cast()).QuadPart },
+    };
+    let mut view_size = size as _;
+    let status = unsafe {
+        ZwMapViewOfSection(
+            section,
+            process,
+            &raw mut user_address,
+            0,
+            size as _,
+            &raw mut offset,
+            &raw mut view_size,
+            ViewUnmap,
+            0,
+            PAGE_READWRITE,
+        )
+    };
+    let _ = unsafe { ZwClose(section) };
+    if NT_SUCCESS(status) {
+        Ok(user_address.cast())
+    } else {
+        Err(status)
+    }
+}
@@ -0,0 +1,52 @@
+//! `IRP_MJ_SHUTDOWN`, which Windows sends to the control device when it shuts
+//! down or restarts, while file systems and the registry are still writable,
+//! so that long-running experiments do not silently lose state on reboot.
+//!
+//! The driver saves the log records not read yet with `log::save`, and stops
+//! what could otherwise run while Windows shuts down: payload execution is
+//! disabled as with `IOCTL_KILL_SWITCH`, and the NMI callback is deregistered.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use wdk_sys::{
+    DEVICE_OBJECT, NT_SUCCESS, PDEVICE_OBJECT,
+    ntddk::{IoRegisterShutdownNotification, IoUnregisterShutdownNotification},
+};
+
+use crate::{audit, log, nmi, trace::trace};
+
+/// The device registered for the notification, or null.
+static DEVICE: AtomicPtr<DEVICE_OBJECT> = AtomicPtr::new(ptr::null_mut());
+
+/// Registers `device` for `IRP_MJ_SHUTDOWN`. Failure is not fatal, as only the
+/// log records are lost on reboot.
+pub(crate) unsafe fn register(device: PDEVICE_OBJECT) {
+    let status = unsafe { IoRegisterShutdownNotification(device) };
+    if NT_SUCCESS(status) {
+        DEVICE.store(device, Ordering::Release);
+    } else {
+        trace!(SHUTDOWN_REGISTRATION_FAILED, status);
+    }
+}
+
+/// Unregisters the device if registered. Must be called before the device is
+/// deleted.
+pub(crate) fn unregister() {
+    let device = DEVICE.swap(ptr::null_mut(), Ordering::AcqRel);
+    if !device.is_null() {
+        unsafe { IoUnregisterShutdownNotification(device) };
+    }
+}
+
+/// Handles `IRP_MJ_SHUTDOWN`.
+pub(crate) fn shutdown() {
+    audit::kill();
+    nmi::unregister();
+    match log::save() {
+        Ok(count) => trace!(LOG_SAVED, count),
+        Err(status) => trace!(LOG_SAVE_FAILED, status),
+    }
+}
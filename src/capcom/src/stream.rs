@@ -0,0 +1,43 @@
+//! `IRP_MJ_WRITE` and `IRP_MJ_READ` on the compatible device, for clients that
+//! can read and write files but not send IOCTLs, such as PowerShell without
+//! P/Invoke. Writing shellcode runs it like `IOCTL_RUN_SHELLCODE`, and reading
+//! returns its `PayloadTranscript`, or log records like `IOCTL_READ_LOG` once
+//! the transcript was read.
+
+use capcom_abi::CLASS_EXECUTE;
+use wdk_sys::{NTSTATUS, STATUS_DELETE_PENDING};
+
+use crate::{audit, context::Context, ioctl::Request, log, payload};
+
+/// Handles `IRP_MJ_WRITE`, running the written bytes as shellcode, and returns
+/// the number of bytes written. As writes cannot negotiate, this is allowed
+/// like the IOCTLs of the original driver, which run arbitrary code too.
+pub(crate) fn write(context: &Context, request: &Request) -> Result<usize, NTSTATUS> {
+    if context.is_cleaned_up() {
+        return Err(STATUS_DELETE_PENDING);
+    }
+    context.check_access(CLASS_EXECUTE, true)?;
+    audit::execute(context, || {
+        let shellcode = request.input();
+        let transcript = payload::run_shellcode_bytes(shellcode)?;
+        *context.transcript.lock() = transcript;
+        Ok(shellcode.len())
+    })
+}
+
+/// Handles `IRP_MJ_READ`, returning the transcript of the last shellcode
+/// written to the handle, or log records if it was read already.
+pub(crate) fn read(context: &Context, request: &mut Request) -> Result<usize, NTSTATUS> {
+    if context.is_cleaned_up() {
+        return Err(STATUS_DELETE_PENDING);
+    }
+    let mut transcript = context.transcript.lock();
+    if let Some(value) = *transcript {
+        // Keep the transcript for a retry with a larger buffer.
+        let length = request.write_output(&value)?;
+        *transcript = None;
+        return Ok(length);
+    }
+    drop(transcript);
+    log::read(request)
+}
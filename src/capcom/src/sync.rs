@@ -0,0 +1,103 @@
+//! Synchronization primitives.
+
+use core::{
+    cell::UnsafeCell,
+    mem,
+    ops::{Deref, DerefMut},
+};
+
+use wdk_sys::{
+    EX_RUNDOWN_REF, KIRQL, KSPIN_LOCK,
+    ntddk::{
+        ExAcquireRundownProtection, ExReleaseRundownProtection, ExWaitForRundownProtectionRelease,
+        KeAcquireSpinLockRaiseToDpc, KeReleaseSpinLock,
+    },
+};
+
+/// A value protected by a spin lock. The lock raises IRQL to `DISPATCH_LEVEL`
+/// while held, so the value must be in non-paged memory.
+pub(crate) struct SpinLock<T> {
+    lock: UnsafeCell<KSPIN_LOCK>,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for SpinLock<T> {}
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+
+impl<T> SpinLock<T> {
+    /// Creates a spin lock. `KeInitializeSpinLock` only zeroes the lock, so this
+    /// can be used for statics.
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            lock: UnsafeCell::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Acquires the lock and returns the guard releasing it when dropped.
+    pub(crate) fn lock(&self) -> SpinLockGuard<'_, T> {
+        let irql = unsafe { KeAcquireSpinLockRaiseToDpc(self.lock.get()) };
+        SpinLockGuard { lock: self, irql }
+    }
+}
+
+/// The guard of [`SpinLock`].
+pub(crate) struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    irql: KIRQL,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { KeReleaseSpinLock(self.lock.lock.get(), self.irql) };
+    }
+}
+
+/// Rundown protection, letting the driver wait until the code that acquired it,
+/// e.g., a routine called back after the request returned, releases it.
+pub(crate) struct Rundown(UnsafeCell<EX_RUNDOWN_REF>);
+
+unsafe impl Sync for Rundown {}
+
+impl Rundown {
+    /// Creates rundown protection. `ExInitializeRundownProtection` only zeroes
+    /// it, so this can be used for statics.
+    pub(crate) const fn new() -> Self {
+        Self(UnsafeCell::new(unsafe { mem::zeroed() }))
+    }
+
+    /// Acquires the protection, unless [`Rundown::wait`] was called.
+    pub(crate) fn acquire(&self) -> bool {
+        unsafe { ExAcquireRundownProtection(self.0.get()) != 0 }
+    }
+
+    /// Releases the protection acquired with [`Rundown::acquire`].
+    pub(crate) fn release(&self) {
+        unsafe { ExReleaseRundownProtection(self.0.get()) };
+    }
+
+    /// Returns the protection, for releasing it outside the driver with
+    /// [`crate::arch::apc_kernel_routine`].
+    pub(crate) fn as_ptr(&self) -> *mut EX_RUNDOWN_REF {
+        self.0.get()
+    }
+
+    /// Waits until all acquisitions are released, and makes further ones fail.
+    pub(crate) fn wait(&self) {
+        unsafe { ExWaitForRundownProtectionRelease(self.0.get()) };
+    }
+}
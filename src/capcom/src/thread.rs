@@ -0,0 +1,545 @@
+//! APCs to other threads: `IOCTL_CAPTURE_THREAD`, capturing the kernel stack
+//! of a thread, and `IOCTL_QUEUE_USER_APC`, running a user-mode routine in a
+//! thread.
+//!
+//! The context of a thread that is not running is saved in undocumented fields
+//! of `KTHREAD`, so the thread is made to capture its own backtrace in a
+//! special kernel APC first. Such APCs are delivered even while the thread
+//! waits, unless the thread disabled them, e.g., by holding a guarded mutex.
+//! If the APC does not run in time, or the request asks so, the stack saved in
+//! `KTHREAD::KernelStack` is unwound instead, on a copy, as the thread may run
+//! and change it meanwhile.
+//!
+//! The APC may run after the request timed out, or never if the thread exits,
+//! so the state shared with it is in pool and freed by whichever side finishes
+//! last. As the routines of APCs, including the one freeing a user-mode APC,
+//! are in the driver, every pending APC holds [`PENDING`], which the driver
+//! waits for when it unloads. The routines release it last by jumping out of
+//! the driver with [`arch::apc_kernel_routine`], so that they do not return
+//! into the driver once it may unload. A user-mode APC is pending until the
+//! thread enters an alertable wait or exits, which may never happen, so the
+//! driver removes the user-mode APCs still queued when it unloads.
+
+use core::{
+    mem, ptr, slice,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use capcom_abi::{
+    THREAD_CAPTURE_FRAMES, THREAD_CAPTURE_SAVED_STACK, ThreadCapture, ThreadCaptureRequest,
+    UserApcRequest,
+};
+use wdk_sys::{
+    _EVENT_TYPE::NotificationEvent,
+    _KWAIT_REASON::Executive,
+    _MODE::{KernelMode, UserMode},
+    EX_RUNDOWN_REF, FALSE, KAPC, KEVENT, LARGE_INTEGER, NT_SUCCESS, NTSTATUS, PETHREAD, PKAPC,
+    POOL_FLAG_NON_PAGED, PVOID, STATUS_DELETE_PENDING, STATUS_INSUFFICIENT_RESOURCES,
+    STATUS_INVALID_CID, STATUS_INVALID_PARAMETER, STATUS_NOT_SUPPORTED, STATUS_SUCCESS,
+    STATUS_THREAD_IS_TERMINATING, STATUS_TIMEOUT, STATUS_UNSUCCESSFUL,
+    ntddk::{
+        KeInitializeEvent, KeSetEvent, KeWaitForSingleObject, ObfDereferenceObject,
+        PsIsSystemThread,
+    },
+};
+
+#[cfg(target_arch = "x86_64")]
+use wdk_sys::{CONTEXT, ULONG};
+
+#[cfg(target_arch = "x86_64")]
+use crate::routines;
+use crate::{
+    arch::{self, ApcRoutine},
+    dump,
+    imports::{
+        KeInitializeApc, KeInsertQueueApc, KeRemoveQueueApc, PsGetThreadProcessId,
+        PsLookupThreadByThreadId, RtlCaptureStackBackTrace,
+    },
+    ioctl::Request,
+    offsets,
+    pool::{self, Tag},
+    sync::{Rundown, SpinLock},
+    trace::trace,
+};
+
+/// `OriginalApcEnvironment` of `KAPC_ENVIRONMENT`.
+const ORIGINAL_APC_ENVIRONMENT: i32 = 0;
+
+/// How long to wait for the APC when the request does not specify it.
+const DEFAULT_TIMEOUT_MS: u32 = 1000;
+
+/// The size of the kernel stack of a thread, and so how much of the saved
+/// stack is copied to unwind.
+const SAVED_STACK_SIZE: usize = 0x6000;
+
+pub(crate) type KernelRoutine =
+    unsafe extern "C" fn(PKAPC, *mut PVOID, *mut PVOID, *mut PVOID, *mut PVOID);
+pub(crate) type RundownRoutine = unsafe extern "C" fn(PKAPC);
+
+/// Held by each APC queued and not run or discarded yet.
+static PENDING: Rundown = Rundown::new();
+
+/// The user-mode APCs queued and not run or discarded yet.
+static USER_APCS: SpinLock<UserApcs> = SpinLock::new(UserApcs(ptr::null_mut()));
+
+/// The state shared between the request and the APC.
+#[repr(C)]
+struct Capture {
+    /// The APC. It must be the first field, as the routines receive its
+    /// address.
+    apc: KAPC,
+    /// Signaled once [`Capture::output`] is filled.
+    event: KEVENT,
+    /// The referenced target thread.
+    thread: PETHREAD,
+    /// The number of sides, the request and the APC, that still use this.
+    references: AtomicU32,
+    output: ThreadCapture,
+}
+
+/// A user-mode APC queued by `IOCTL_QUEUE_USER_APC`.
+#[repr(C)]
+struct UserApc {
+    /// The APC. It must be the first field, as the routines receive its
+    /// address.
+    apc: KAPC,
+    /// The next APC in [`USER_APCS`].
+    next: *mut UserApc,
+}
+
+/// The first of a list of [`UserApc`]s.
+struct UserApcs(*mut UserApc);
+
+unsafe impl Send for UserApcs {}
+
+/// Handles `IOCTL_CAPTURE_THREAD`.
+pub(crate) fn capture_thread(request: &mut Request) -> Result<usize, NTSTATUS> {
+    let input = request.read_input::<ThreadCaptureRequest>()?;
+    if input.flags & !THREAD_CAPTURE_SAVED_STACK != 0 {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    let thread = lookup(input.thread_id)?;
+    if input.flags & THREAD_CAPTURE_SAVED_STACK != 0 {
+        let result = unsafe { unwind_saved_stack(thread) };
+        let _ = unsafe { ObfDereferenceObject(thread.cast()) };
+        return request.write_output(&result?);
+    }
+    if !PENDING.acquire() {
+        let _ = unsafe { ObfDereferenceObject(thread.cast()) };
+        return Err(STATUS_DELETE_PENDING);
+    }
+
+    let capture =
+        pool::allocate(POOL_FLAG_NON_PAGED, size_of::<Capture>(), Tag::Thread).cast::<Capture>();
+    if capture.is_null() {
+        let _ = unsafe { ObfDereferenceObject(thread.cast()) };
+        PENDING.release();
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+
+    let status = unsafe {
+        capture.write(Capture {
+            apc: mem::zeroed(),
+            event: mem::zeroed(),
+            thread,
+            references: AtomicU32::new(2),
+            output: ThreadCapture {
+                process_id: PsGetThreadProcessId(thread).addr() as u64,
+                ..ThreadCapture::default()
+            },
+        });
+        KeInitializeEvent(&raw mut (*capture).event, NotificationEvent, FALSE as _);
+        KeInitializeApc(
+            &raw mut (*capture).apc,
+            thread,
+            ORIGINAL_APC_ENVIRONMENT,
+            arch::apc_kernel_routine::<CaptureRoutine>,
+            Some(arch::apc_rundown_routine::<ReleaseCapture>),
+            ptr::null_mut(),
+            KernelMode as _,
+            ptr::null_mut(),
+        );
+        if KeInsertQueueApc(&raw mut (*capture).apc, ptr::null_mut(), ptr::null_mut(), 0) == 0 {
+            // The APC will never run, so release its reference too.
+            release(capture);
+            release(capture);
+            PENDING.release();
+            return Err(STATUS_THREAD_IS_TERMINATING);
+        }
+
+        let timeout_ms = match input.timeout_ms {
+            0 => DEFAULT_TIMEOUT_MS,
+            timeout_ms => timeout_ms,
+        };
+        let mut timeout = LARGE_INTEGER {
+            QuadPart: -i64::from(timeout_ms) * 10_000,
+        };
+        KeWaitForSingleObject(
+            (&raw mut (*capture).event).cast(),
+            Executive,
+            KernelMode as _,
+            FALSE as _,
+            &raw mut timeout,
+        )
+    };
+    let result = if status == STATUS_SUCCESS {
+        Ok(unsafe { (*capture).output })
+    } else {
+        // The thread may have disabled APCs, e.g., in a deadlock.
+        unsafe { unwind_saved_stack(thread) }.map_err(|_| STATUS_TIMEOUT)
+    };
+    unsafe { release(capture) };
+    request.write_output(&result?)
+}
+
+/// Captures the backtrace of the current thread into the [`Capture`] of the
+/// APC. Runs at `APC_LEVEL` in the target thread.
+struct CaptureRoutine;
+
+impl ApcRoutine for CaptureRoutine {
+    unsafe extern "C" fn run(apc: PKAPC) -> *mut EX_RUNDOWN_REF {
+        let capture = apc.cast::<Capture>();
+        let mut frames = [ptr::null_mut(); THREAD_CAPTURE_FRAMES];
+        unsafe {
+            // Skip this routine and `arch::apc_kernel_routine`.
+            let count = RtlCaptureStackBackTrace(
+                2,
+                THREAD_CAPTURE_FRAMES as _,
+                frames.as_mut_ptr(),
+                ptr::null_mut(),
+            );
+            let output = &mut (*capture).output;
+            output.stack_pointer = ptr::from_ref(&frames).addr() as u64;
+            output.frame_count = u32::from(count);
+            for (frame, address) in output.frames.iter_mut().zip(&frames[..usize::from(count)]) {
+                *frame = address.addr() as u64;
+            }
+            let _ = KeSetEvent(&raw mut (*capture).event, 0, FALSE as _);
+            release(capture);
+        }
+        PENDING.as_ptr()
+    }
+}
+
+/// Unwinds the kernel stack `thread` saved when it was last switched out. The
+/// frames are accurate only if the thread is not running.
+unsafe fn unwind_saved_stack(thread: PETHREAD) -> Result<ThreadCapture, NTSTATUS> {
+    let offset = offsets::get()?.kthread_kernel_stack;
+    if offset == 0 || !cfg!(target_arch = "x86_64") {
+        return Err(STATUS_NOT_SUPPORTED);
+    }
+    let stack_pointer = unsafe {
+        thread
+            .cast::<u8>()
+            .add(offset as usize)
+            .cast::<u64>()
+            .read_volatile()
+    };
+
+    // The copy is followed by as many zeroes, which the unwind of the
+    // outermost frames may read.
+    let copy = pool::allocate(POOL_FLAG_NON_PAGED, SAVED_STACK_SIZE * 2, Tag::Thread).cast::<u8>();
+    if copy.is_null() {
+        return Err(STATUS_INSUFFICIENT_RESOURCES);
+    }
+    let stack = unsafe { slice::from_raw_parts_mut(copy, SAVED_STACK_SIZE * 2) };
+    let length = dump::copy_virtual(stack_pointer, &mut stack[..SAVED_STACK_SIZE]);
+    let mut output = ThreadCapture {
+        process_id: unsafe { PsGetThreadProcessId(thread) }.addr() as u64,
+        stack_pointer,
+        unwound: 1,
+        ..ThreadCapture::default()
+    };
+    let count = unwind(stack_pointer, stack, length, &mut output.frames);
+    unsafe { pool::free(copy.cast(), Tag::Thread) };
+    if count == 0 {
+        return Err(STATUS_UNSUCCESSFUL);
+    }
+    output.frame_count = count as u32;
+    Ok(output)
+}
+
+#[cfg(target_arch = "x86_64")]
+type RtlLookupFunctionEntryFn = unsafe extern "system" fn(u64, *mut u64, PVOID) -> PVOID;
+#[cfg(target_arch = "x86_64")]
+type RtlVirtualUnwindFn = unsafe extern "system" fn(
+    ULONG,
+    u64,
+    u64,
+    PVOID,
+    *mut CONTEXT,
+    *mut PVOID,
+    *mut u64,
+    PVOID,
+) -> PVOID;
+
+/// Unwinds `stack`, a copy of `length` bytes of the kernel stack at
+/// `stack_pointer` saved by a context switch followed by zeroes, into `frames`,
+/// and returns the number of frames.
+///
+/// Each frame is unwound with `RtlVirtualUnwind` as for an exception, with RSP
+/// and RBP moved into the copy, and unwinding stops where they leave it.
+#[cfg(target_arch = "x86_64")]
+fn unwind(stack_pointer: u64, stack: &[u8], length: usize, frames: &mut [u64]) -> usize {
+    // `KTHREAD::KernelStack` points to `KSWITCH_FRAME`, which ends with RBP
+    // and the return address into the context switch.
+    const SWITCH_FRAME_RBP: u64 = 0x30;
+    const SWITCH_FRAME_RETURN: u64 = 0x38;
+    const SWITCH_FRAME_SIZE: u64 = 0x40;
+    // `UNWIND_INFO::FrameRegister` for RBP.
+    const FRAME_REGISTER_RBP: u8 = 5;
+    const UNW_FLAG_NHANDLER: ULONG = 0;
+    const KERNEL_BASE: u64 = 0xffff_8000_0000_0000;
+
+    let lookup = routines::system_routine(b"RtlLookupFunctionEntry");
+    let virtual_unwind = routines::system_routine(b"RtlVirtualUnwind");
+    if lookup == 0 || virtual_unwind == 0 {
+        return 0;
+    }
+    let lookup = unsafe { mem::transmute::<usize, RtlLookupFunctionEntryFn>(lookup) };
+    let virtual_unwind = unsafe { mem::transmute::<usize, RtlVirtualUnwindFn>(virtual_unwind) };
+
+    let copy = stack.as_ptr().addr() as u64;
+    let end = stack_pointer + length as u64;
+    let to_copy = |address: u64| {
+        (stack_pointer..end)
+            .contains(&address)
+            .then(|| address - stack_pointer + copy)
+    };
+    let to_original = |address: u64| {
+        (copy..copy + length as u64)
+            .contains(&address)
+            .then(|| address - copy + stack_pointer)
+    };
+    let read = |address: u64| {
+        let offset = usize::try_from(address.checked_sub(stack_pointer)?).ok()?;
+        let bytes = stack[..length].get(offset..offset + 8)?;
+        Some(u64::from_le_bytes(bytes.try_into().ok()?))
+    };
+
+    let (Some(rbp), Some(rip)) = (
+        read(stack_pointer + SWITCH_FRAME_RBP),
+        read(stack_pointer + SWITCH_FRAME_RETURN),
+    ) else {
+        return 0;
+    };
+    let mut context: CONTEXT = unsafe { mem::zeroed() };
+    context.Rip = rip;
+    context.Rbp = rbp;
+    context.Rsp = stack_pointer + SWITCH_FRAME_SIZE;
+    let mut count = 0;
+    while count < frames.len() && context.Rip >= KERNEL_BASE {
+        frames[count] = context.Rip;
+        count += 1;
+        let previous = context.Rsp;
+        let mut image_base = 0;
+        let entry = unsafe { lookup(context.Rip, &raw mut image_base, ptr::null_mut()) };
+        if entry.is_null() {
+            // A leaf function, which has the return address at RSP.
+            let Some(rip) = read(context.Rsp) else {
+                break;
+            };
+            context.Rip = rip;
+            context.Rsp += 8;
+            continue;
+        }
+
+        // `RUNTIME_FUNCTION::UnwindData` and `UNWIND_INFO::FrameRegister`. The
+        // frame of a function with a frame pointer is found through it.
+        let frame_register = unsafe {
+            let unwind_data = entry.cast::<u32>().add(2).read();
+            ptr::without_provenance::<u8>((image_base + u64::from(unwind_data) + 3) as usize).read()
+                & 0xf
+        };
+        let Some(rsp) = to_copy(context.Rsp) else {
+            break;
+        };
+        if frame_register != 0 {
+            let Some(rbp) = to_copy(context.Rbp).filter(|_| frame_register == FRAME_REGISTER_RBP)
+            else {
+                break;
+            };
+            context.Rbp = rbp;
+        }
+        context.Rsp = rsp;
+        let mut handler_data = ptr::null_mut();
+        let mut establisher_frame = 0;
+        unsafe {
+            let _ = virtual_unwind(
+                UNW_FLAG_NHANDLER,
+                image_base,
+                context.Rip,
+                entry,
+                &raw mut context,
+                &raw mut handler_data,
+                &raw mut establisher_frame,
+                ptr::null_mut(),
+            );
+        }
+        // RBP is either restored from the stack or left in the copy.
+        context.Rbp = to_original(context.Rbp).unwrap_or(context.Rbp);
+        let Some(rsp) = to_original(context.Rsp).filter(|&rsp| rsp > previous) else {
+            break;
+        };
+        context.Rsp = rsp;
+    }
+    count
+}
+
+/// Returns no frames, as unwinding is implemented only for x64.
+#[cfg(not(target_arch = "x86_64"))]
+fn unwind(_stack_pointer: u64, _stack: &[u8], _length: usize, _frames: &mut [u64]) -> usize {
+    0
+}
+
+/// Releases the reference of the APC that is discarded as the thread exits.
+struct ReleaseCapture;
+
+impl ApcRoutine for ReleaseCapture {
+    unsafe extern "C" fn run(apc: PKAPC) -> *mut EX_RUNDOWN_REF {
+        unsafe { release(apc.cast()) };
+        PENDING.as_ptr()
+    }
+}
+
+/// Removes the user-mode APCs still queued, and waits until the other APCs
+/// queued by the driver run or are discarded, as their routines are in the
+/// driver. Called when the driver unloads, after which no APC can be queued.
+pub(crate) fn drain() {
+    {
+        let mut apcs = USER_APCS.lock();
+        let mut link: *mut *mut UserApc = &raw mut apcs.0;
+        unsafe {
+            while !(*link).is_null() {
+                let apc = *link;
+                // An APC no longer queued is being delivered or discarded, and
+                // its routine unlinks it.
+                if KeRemoveQueueApc(&raw mut (*apc).apc) == 0 {
+                    link = &raw mut (*apc).next;
+                    continue;
+                }
+                *link = (*apc).next;
+                pool::free(apc.cast(), Tag::Thread);
+                PENDING.release();
+            }
+        }
+    }
+    PENDING.wait();
+}
+
+/// Releases a reference to `capture`, freeing it with the last one.
+unsafe fn release(capture: *mut Capture) {
+    unsafe {
+        if (*capture).references.fetch_sub(1, Ordering::AcqRel) == 1 {
+            let _ = ObfDereferenceObject((*capture).thread.cast());
+            pool::free(capture.cast(), Tag::Thread);
+        }
+    }
+}
+
+/// Handles `IOCTL_QUEUE_USER_APC`.
+pub(crate) fn queue_user_apc(request: &Request) -> Result<usize, NTSTATUS> {
+    let mut input = request.read_input::<UserApcRequest>()?;
+    input.routine = request.user_pointer(input.routine)?;
+    if input.routine == 0 {
+        return Err(STATUS_INVALID_PARAMETER);
+    }
+    let thread = lookup(input.thread_id)?;
+    let result = unsafe { insert_user_apc(thread, &input) };
+    let _ = unsafe { ObfDereferenceObject(thread.cast()) };
+    result?;
+    trace!(APC_QUEUED, input.routine, input.thread_id);
+    Ok(0)
+}
+
+/// Queues the user-mode APC described by `input` to `thread`.
+unsafe fn insert_user_apc(thread: PETHREAD, input: &UserApcRequest) -> Result<(), NTSTATUS> {
+    let argument = |value: u64| ptr::without_provenance_mut(value as usize);
+
+    unsafe {
+        if PsIsSystemThread(thread) != 0 {
+            return Err(STATUS_INVALID_PARAMETER);
+        }
+        if !PENDING.acquire() {
+            return Err(STATUS_DELETE_PENDING);
+        }
+        let apc = pool::allocate(POOL_FLAG_NON_PAGED, size_of::<UserApc>(), Tag::Thread)
+            .cast::<UserApc>();
+        if apc.is_null() {
+            PENDING.release();
+            return Err(STATUS_INSUFFICIENT_RESOURCES);
+        }
+        KeInitializeApc(
+            &raw mut (*apc).apc,
+            thread,
+            ORIGINAL_APC_ENVIRONMENT,
+            arch::apc_kernel_routine::<FreeUserApc>,
+            Some(arch::apc_rundown_routine::<FreeUserApc>),
+            argument(input.routine),
+            UserMode as _,
+            argument(input.argument1),
+        );
+        // Link the APC first, as its routine may run as soon as it is queued.
+        {
+            let mut apcs = USER_APCS.lock();
+            (*apc).next = apcs.0;
+            apcs.0 = apc;
+        }
+        let inserted = KeInsertQueueApc(
+            &raw mut (*apc).apc,
+            argument(input.argument2),
+            argument(input.argument3),
+            0,
+        );
+        if inserted == 0 {
+            unlink(apc);
+            pool::free(apc.cast(), Tag::Thread);
+            PENDING.release();
+            return Err(STATUS_THREAD_IS_TERMINATING);
+        }
+    }
+    Ok(())
+}
+
+/// Frees the user-mode APC before its routine is called, or when it is
+/// discarded as the thread exits.
+struct FreeUserApc;
+
+impl ApcRoutine for FreeUserApc {
+    unsafe extern "C" fn run(apc: PKAPC) -> *mut EX_RUNDOWN_REF {
+        let apc = apc.cast::<UserApc>();
+        unsafe {
+            unlink(apc);
+            pool::free(apc.cast(), Tag::Thread);
+        }
+        PENDING.as_ptr()
+    }
+}
+
+/// Removes `apc` from [`USER_APCS`].
+unsafe fn unlink(apc: *mut UserApc) {
+    let mut apcs = USER_APCS.lock();
+    let mut link: *mut *mut UserApc = &raw mut apcs.0;
+    unsafe {
+        while !(*link).is_null() {
+            if *link == apc {
+                *link = (*apc).next;
+                return;
+            }
+            link = &raw mut (**link).next;
+        }
+    }
+}
+
+/// Returns the referenced thread with `thread_id`. The caller must dereference
+/// it.
+fn lookup(thread_id: u64) -> Result<PETHREAD, NTSTATUS> {
+    let mut thread = ptr::null_mut();
+    let thread_id = ptr::without_provenance_mut(thread_id as usize);
+    let status = unsafe { PsLookupThreadByThreadId(thread_id, &raw mut thread) };
+    if NT_SUCCESS(status) {
+        Ok(thread)
+    } else {
+        Err(STATUS_INVALID_CID)
+    }
+}
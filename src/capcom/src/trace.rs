@@ -0,0 +1,163 @@
+//! Messages of the driver, logged as the IDs and arguments defined in
+//! `capcom_abi::messages` instead of text, which `cargo xtask decode` renders.
+//! This keeps the text out of the driver, and lets clients parse messages
+//! streamed to the event ring without matching text.
+
+use core::fmt::{self, Write};
+
+use capcom_abi::{MessageRecord, PRINT_COMPONENT_ID, messages};
+use wdk_sys::ntddk::DbgPrintEx;
+
+use crate::{config, correlation, ring};
+
+/// Logs the message `$id` of `capcom_abi::messages` with the numeric
+/// arguments, followed by the bytes after `;` for the `{:s}` or `{:02x?}`
+/// placeholder, if any.
+macro_rules! trace {
+    ($id:ident $(, $argument:expr)*; $bytes:expr) => {
+        $crate::trace::write(
+            capcom_abi::messages::$id,
+            &[$($crate::trace::Argument::to_u64($argument)),*],
+            $bytes,
+        )
+    };
+    ($id:ident $(, $argument:expr)* $(,)?) => {
+        $crate::trace::trace!($id $(, $argument)*; &[])
+    };
+}
+pub(crate) use trace;
+
+/// Logs the message `$id` like [`trace!`], only with the `teaching` feature.
+macro_rules! teach {
+    ($($tokens:tt)*) => {
+        if cfg!(feature = "teaching") {
+            $crate::trace::trace!($($tokens)*);
+        }
+    };
+}
+pub(crate) use teach;
+
+/// A value that can be an argument of a message.
+pub(crate) trait Argument {
+    /// Returns the value as an argument.
+    fn to_u64(self) -> u64;
+}
+
+impl Argument for u8 {
+    fn to_u64(self) -> u64 {
+        self.into()
+    }
+}
+
+impl Argument for u16 {
+    fn to_u64(self) -> u64 {
+        self.into()
+    }
+}
+
+impl Argument for u32 {
+    fn to_u64(self) -> u64 {
+        self.into()
+    }
+}
+
+impl Argument for u64 {
+    fn to_u64(self) -> u64 {
+        self
+    }
+}
+
+impl Argument for usize {
+    fn to_u64(self) -> u64 {
+        self as u64
+    }
+}
+
+/// `NTSTATUS`, rendered the same as `{:#x}` of `i32` does.
+impl Argument for i32 {
+    fn to_u64(self) -> u64 {
+        self.cast_unsigned().into()
+    }
+}
+
+/// Logs the message `id` with `numbers` followed by `bytes` as arguments, to
+/// the kernel debugger and the event ring. Arguments that do not fit are
+/// dropped. The kernel debugger also gets the current correlation ID, if any,
+/// and only messages of the levels in `config::print_level_mask`.
+pub(crate) fn write(id: u32, numbers: &[u64], bytes: &[u8]) {
+    let mut record = MessageRecord {
+        id,
+        ..MessageRecord::default()
+    };
+    let words = bytes.chunks(size_of::<u64>()).map(|chunk| {
+        let mut word = [0; size_of::<u64>()];
+        word[..chunk.len()].copy_from_slice(chunk);
+        u64::from_le_bytes(word)
+    });
+    let arguments = numbers.iter().copied().chain(words);
+    for (slot, argument) in record.arguments.iter_mut().zip(arguments) {
+        *slot = argument;
+        record.argument_count += 1;
+    }
+    let level = messages::level(id);
+    if config::print_level_mask() & (1 << level) != 0 {
+        let arguments = &record.arguments[..record.argument_count as usize];
+        let mut line = TextBuffer::default();
+        let _ = match correlation::current() {
+            Some(correlation_id) => {
+                write!(line, "capcom#{id}@{correlation_id:x}{}", Hex(arguments))
+            }
+            None => write!(line, "capcom#{id}{}", Hex(arguments)),
+        };
+        let _ = unsafe {
+            DbgPrintEx(
+                PRINT_COMPONENT_ID,
+                level,
+                c"%s\n".as_ptr(),
+                line.data.as_ptr(),
+            )
+        };
+    }
+    ring::push_message(&record);
+}
+
+/// A fixed-size buffer to format a line into without allocation. Six
+/// arguments in hex with the ID and the correlation ID always fit.
+struct TextBuffer {
+    data: [u8; 160],
+    length: usize,
+}
+
+impl Default for TextBuffer {
+    fn default() -> Self {
+        Self {
+            data: [0; 160],
+            length: 0,
+        }
+    }
+}
+
+impl Write for TextBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        // Never write the last byte so the buffer stays null-terminated.
+        for &byte in s.as_bytes() {
+            if self.length == self.data.len() - 1 {
+                break;
+            }
+            self.data[self.length] = byte;
+            self.length += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Arguments formatted as space-prefixed hex.
+struct Hex<'a>(&'a [u64]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0
+            .iter()
+            .try_for_each(|argument| write!(f, " {argument:x}"))
+    }
+}
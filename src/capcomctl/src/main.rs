@@ -0,0 +1,205 @@
+//! A command-line utility to drive the device from scripts and scheduled tasks
+//! without writing Rust or C. With `--json`, each command prints one JSON
+//! object, e.g., for `ConvertFrom-Json` of PowerShell. Addresses and flags are
+//! hexadecimal strings, as JSON numbers cannot hold every 64-bit value.
+//!
+//! ```shell
+//! capcomctl.exe [--json] version
+//! capcomctl.exe [--json] stats
+//! capcomctl.exe [--json] read <address> <length>
+//! capcomctl.exe [--json] exec-shellcode <file>
+//! ```
+
+use std::{
+    fmt::Write as _,
+    fs,
+    num::ParseIntError,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use capcom_abi::{
+    BUILD_FEATURE_FAITHFUL, BUILD_FEATURE_HARDENED, CLASS_EXECUTE, CLASS_KERNEL_MEMORY,
+    RegisterState,
+};
+use capcom_client::{Device, kernel_memory::KernelMem};
+use clap::{Parser, Subcommand};
+use serde_json::{Value, json};
+
+#[derive(Parser)]
+#[command(author, about, long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+
+    /// Print the result as a JSON object.
+    #[arg(long, global = true)]
+    json: bool,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Show the version, capabilities and build of the driver
+    Version,
+    /// Show the counts of payloads executed and throttled, and of pool allocations
+    Stats,
+    /// Read kernel memory
+    Read {
+        /// The virtual address, in hexadecimal with `0x` or in decimal.
+        #[arg(value_parser = parse_number)]
+        address: u64,
+
+        /// The number of bytes, in hexadecimal with `0x` or in decimal.
+        #[arg(value_parser = parse_number)]
+        length: u64,
+    },
+    /// Run shellcode in a file in kernel mode and show the registers around it
+    ExecShellcode {
+        /// The path to the shellcode.
+        file: PathBuf,
+    },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let device = Device::open().context("failed to open the device")?;
+    let output = match cli.command {
+        Commands::Version => version(&device)?,
+        Commands::Stats => stats(&device)?,
+        Commands::Read { address, length } => read(&device, address, length)?,
+        Commands::ExecShellcode { file } => exec_shellcode(&device, &file)?,
+    };
+    if cli.json {
+        println!("{output}");
+    } else {
+        print_text("", &output);
+    }
+    Ok(())
+}
+
+fn version(device: &Device) -> Result<Value> {
+    let version = device.get_version()?;
+    let build = device.get_build_info()?;
+    let profile = build.profile.split(|&byte| byte == 0).next().unwrap_or(&[]);
+    let personality = if build.features & BUILD_FEATURE_FAITHFUL != 0 {
+        "faithful"
+    } else if build.features & BUILD_FEATURE_HARDENED != 0 {
+        "hardened"
+    } else {
+        "default"
+    };
+    Ok(json!({
+        "abi_version": version.abi_version,
+        "capabilities": hex(version.capabilities.into()),
+        "enabled_classes": hex(version.enabled_classes.into()),
+        "build": {
+            "commit": bytes_to_hex(&build.commit),
+            "dirty": build.dirty != 0,
+            "profile": String::from_utf8_lossy(profile),
+            "timestamp": build.timestamp,
+            "features": hex(build.features.into()),
+            "personality": personality,
+        },
+    }))
+}
+
+fn stats(device: &Device) -> Result<Value> {
+    let audit = device.get_audit()?;
+    let allocations = device
+        .query_allocations()?
+        .iter()
+        .map(|allocation| {
+            json!({
+                "tag": String::from_utf8_lossy(&allocation.tag.to_le_bytes()),
+                "outstanding": allocation.outstanding,
+                "total": allocation.total,
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(json!({
+        "executions": audit.executions,
+        "chain": format!("{:#018x}", audit.chain),
+        "throttled": audit.throttled,
+        "killed": audit.killed != 0,
+        "allocations": allocations,
+    }))
+}
+
+fn read(device: &Device, address: u64, length: u64) -> Result<Value> {
+    let _ = device.negotiate(CLASS_KERNEL_MEMORY)?;
+    let mut buffer = vec![0; usize::try_from(length)?];
+    let read = KernelMem::new(device)
+        .read(address, &mut buffer)
+        .with_context(|| format!("failed to read {address:#x}"))?;
+    Ok(json!({
+        "address": hex(address),
+        "length": read,
+        "bytes": bytes_to_hex(&buffer[..read]),
+    }))
+}
+
+fn exec_shellcode(device: &Device, file: &Path) -> Result<Value> {
+    let shellcode = fs::read(file).with_context(|| format!("failed to read {}", file.display()))?;
+    let _ = device.negotiate(CLASS_EXECUTE)?;
+    let transcript = device.run_shellcode(&shellcode)?;
+    Ok(json!({
+        "transcript": transcript.map(|transcript| json!({
+            "before": registers(&transcript.before),
+            "after": registers(&transcript.after),
+        })),
+    }))
+}
+
+fn registers(state: &RegisterState) -> Value {
+    json!({
+        "gprs": state.gprs.iter().map(|&gpr| hex(gpr)).collect::<Vec<_>>(),
+        "flags": hex(state.flags),
+        "cr0": hex(state.cr0),
+        "cr4": hex(state.cr4),
+        "irql": state.irql,
+    })
+}
+
+/// Prints `value` as `key: value` lines, with the keys of nested objects and
+/// the indexes of arrays joined with dots.
+fn print_text(key: &str, value: &Value) {
+    let join = |name: &str| {
+        if key.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{key}.{name}")
+        }
+    };
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map {
+                print_text(&join(name), value);
+            }
+        }
+        Value::Array(values) => {
+            for (index, value) in values.iter().enumerate() {
+                print_text(&join(&index.to_string()), value);
+            }
+        }
+        Value::String(string) => println!("{key}: {string}"),
+        _ => println!("{key}: {value}"),
+    }
+}
+
+fn hex(value: u64) -> String {
+    format!("{value:#x}")
+}
+
+fn bytes_to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn parse_number(text: &str) -> Result<u64, ParseIntError> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => text.parse(),
+    }
+}
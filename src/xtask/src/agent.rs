@@ -0,0 +1,74 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::{Ok, Result, ensure};
+
+use crate::{
+    Profile,
+    backend::{Backend, GuestPath, copy_and_verify},
+    ui::{self, say},
+};
+
+pub(crate) const AGENT_PROGRAM_NAME: &str = "capcom-agent";
+pub(crate) const AGENT_SERVICE_NAME: &str = AGENT_PROGRAM_NAME;
+
+const SC_PATH: &str = r"C:\Windows\System32\sc.exe";
+
+/// The error `sc create` reports when the service already exists, e.g., on a
+/// physical machine the agent was installed on before.
+const ERROR_SERVICE_EXISTS: &str = "1073";
+
+/// Builds the in-guest agent and returns the path to it.
+pub(crate) fn build(profile: Profile) -> Result<PathBuf> {
+    say!("🕒 Building {AGENT_PROGRAM_NAME}");
+    let mut cargo = Command::new("cargo");
+    let _ = cargo.args(["build", "--package", AGENT_PROGRAM_NAME]);
+    if profile.release {
+        let _ = cargo.arg("--release");
+    }
+    if let Some(triple) = profile.arch.target_triple() {
+        let _ = cargo.args(["--target", triple]);
+    }
+    let status = ui::status(&mut cargo)?;
+    ensure!(status.success(), "cargo failed with {status:?}");
+
+    Ok(profile
+        .target_dir()
+        .join(AGENT_PROGRAM_NAME.to_owned() + ".exe"))
+}
+
+/// Copies the agent at `agent_program` next to the driver in the target and
+/// creates its auto-start service, so that the driver is started again when
+/// the target reboots. The agent is not started now, as the driver is started
+/// by xtask.
+pub(crate) fn install(backend: &impl Backend, agent_program: &Path) -> Result<()> {
+    let guest_path = backend
+        .driver_dir()
+        .join(&(AGENT_PROGRAM_NAME.to_owned() + ".exe"));
+    let sc = GuestPath::new(PathBuf::from(SC_PATH));
+
+    say!("🕒 Installing {AGENT_PROGRAM_NAME} in the target");
+    backend.delete_file(&guest_path)?;
+    copy_and_verify(backend, agent_program, &guest_path)?;
+    let output = backend.run_program_with_output(
+        &sc,
+        &[
+            "create",
+            AGENT_SERVICE_NAME,
+            "type=",
+            "own",
+            "start=",
+            "auto",
+            "binPath=",
+            &guest_path.to_string(),
+        ],
+    )?;
+    ensure!(
+        !output.contains("FAILED") || output.contains(ERROR_SERVICE_EXISTS),
+        "sc create failed: {}",
+        output.trim()
+    );
+    Ok(())
+}
@@ -0,0 +1,355 @@
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, PoisonError},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Ok, Result, ensure};
+use colored::Colorize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Profile, agent,
+    config::{COMMAND_TIMEOUT, INSTALL_METHOD, MODULE_NAME, START_TIMEOUT},
+    control, health, logs, preflight, retry, symbols, timeline,
+    ui::{self, say},
+    verifier,
+};
+
+const SC_PATH: &str = r"C:\Windows\System32\sc.exe";
+const PNPUTIL_PATH: &str = r"C:\Windows\System32\pnputil.exe";
+const SERVICE_NAME: &str = MODULE_NAME;
+
+/// Serializes operations on the running target between the deployment and
+/// commands of the control channel.
+pub(crate) static TARGET_LOCK: Mutex<()> = Mutex::new(());
+
+/// How the driver is installed in the target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum InstallMethod {
+    /// Create the service with `sc create`.
+    Sc,
+    /// Install the INF and the catalog generated by `cargo make` with
+    /// `pnputil /add-driver /install`, the path standard installers take.
+    #[expect(dead_code)]
+    Inf,
+}
+
+/// A machine the driver can be deployed to and tested on.
+pub(crate) trait Backend: Send + Sync + 'static {
+    /// Prepares the host for starting the target, e.g., by closing windows
+    /// that interfere with it.
+    fn prepare(&self) -> Result<()>;
+
+    /// Brings the target into a clean, running state.
+    fn start(&self) -> Result<()>;
+
+    /// Stops the target. Errors are ignored as the target may not be running.
+    fn stop(&self) -> Result<()>;
+
+    /// Reboots the target and waits until it becomes ready again.
+    fn reboot(&self) -> Result<()>;
+
+    /// Waits until the target becomes ready, e.g., after it rebooted due to a
+    /// bug check.
+    fn wait_until_ready(&self) -> Result<()>;
+
+    /// Returns the directory on the target to place the driver file in.
+    fn driver_dir(&self) -> GuestPath;
+
+    /// Deletes a file on the target. Errors are ignored as the file may not
+    /// exist.
+    fn delete_file(&self, path: &GuestPath) -> Result<()>;
+
+    /// Copies a file from the host to the target.
+    fn copy_file(&self, src: &Path, dst: &GuestPath) -> Result<()>;
+
+    /// Copies a file from the target to the host.
+    fn copy_file_from_target(&self, src: &GuestPath, dst: &Path) -> Result<()>;
+
+    /// Runs a program on the target and waits for its completion up to
+    /// [`COMMAND_TIMEOUT`].
+    fn run_program(&self, program: &GuestPath, args: &[&str]) -> Result<()> {
+        self.run_program_within(program, args, COMMAND_TIMEOUT)
+    }
+
+    /// Runs a program on the target and waits for its completion up to
+    /// `timeout`.
+    fn run_program_within(
+        &self,
+        program: &GuestPath,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<()>;
+
+    /// Runs a program on the target and returns its standard output and error
+    /// regardless of its exit code, waiting for it up to [`COMMAND_TIMEOUT`].
+    fn run_program_with_output(&self, program: &GuestPath, args: &[&str]) -> Result<String> {
+        self.run_program_with_output_within(program, args, COMMAND_TIMEOUT)
+    }
+
+    /// Runs a program on the target and returns its standard output and error
+    /// regardless of its exit code, waiting for it up to `timeout`.
+    fn run_program_with_output_within(
+        &self,
+        program: &GuestPath,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<String>;
+
+    /// Returns the path to the file on the host where the debug output of the
+    /// target is written, if available.
+    fn log_path(&self) -> Option<&Path>;
+
+    /// Saves a screenshot of the target to `path` on the host as PNG.
+    fn capture_screen(&self, path: &Path) -> Result<()>;
+}
+
+/// Deploys the driver to the target and shows logs until CTRL+C is pressed.
+/// With `verifier`, the driver is started under Driver Verifier and tested.
+pub(crate) fn run(backend: impl Backend, profile: Profile, verifier: bool) -> Result<()> {
+    let backend = Arc::new(backend);
+
+    // Stop the target if it is running, and get ready for starting it.
+    backend.stop()?;
+    backend.prepare()?;
+
+    // Archive the log file left by an interrupted run before starting the
+    // target.
+    if let Some(log_path) = backend.log_path() {
+        drop(logs::archive(log_path)?);
+    }
+    timeline::follow(backend.log_path());
+
+    // Start the target and show logs using threads.
+    let deploy_backend = Arc::clone(&backend);
+    let _unused = thread::Builder::new()
+        .name("deploy".to_owned())
+        .spawn(move || deploy_thread(deploy_backend.as_ref(), profile, verifier));
+    if let Some(log_path) = backend.log_path().map(Path::to_path_buf) {
+        let _unused = thread::Builder::new()
+            .name("logging".to_owned())
+            .spawn(move || log_thread(&log_path));
+    }
+    control::spawn(Arc::clone(&backend), profile)?;
+
+    // Finally, indefinitely run the target until CTRL+C is pressed.
+    ui::wait_for_exit()?;
+
+    say!("🕒 Shutting down the target");
+    backend.stop()?;
+    if let Some(log_path) = backend.log_path()
+        && let Some(archived_path) = logs::archive(log_path)?
+    {
+        say!("📜 Saved the debug output to {}", archived_path.display());
+    }
+    Ok(())
+}
+
+fn deploy_thread(backend: &impl Backend, profile: Profile, verifier: bool) {
+    fn start_and_deploy(backend: &impl Backend, profile: Profile, verifier: bool) -> Result<()> {
+        backend.start()?;
+        if verifier {
+            verifier::deploy_and_test(backend, profile)
+        } else {
+            deploy(backend, profile)
+        }
+    }
+
+    // Keep the target running for investigation, e.g., with the debugger.
+    let _guard = TARGET_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    if let Err(err) = start_and_deploy(backend, profile, verifier) {
+        say!(
+            "{}",
+            format!("❌ Failed to deploy the driver: {err:#}").red()
+        );
+    }
+}
+
+/// Copies the driver to the running target, and creates and starts its
+/// service.
+pub(crate) fn deploy(backend: &impl Backend, profile: Profile) -> Result<()> {
+    install(backend, profile)?;
+    start_driver(backend, profile)
+}
+
+/// Copies the driver to the running target and creates its service, and
+/// installs the in-guest agent starting it again after reboots.
+pub(crate) fn install(backend: &impl Backend, profile: Profile) -> Result<()> {
+    let guest_path = backend
+        .driver_dir()
+        .join(&(MODULE_NAME.to_owned() + ".sys"));
+    let host_path = driver_path(profile);
+    let sc = GuestPath::new(PathBuf::from_str(SC_PATH)?);
+
+    preflight::check_test_signing(backend)?;
+    preflight::check_build(backend, profile.arch)?;
+
+    say!("🕒 Deleting an old driver file in the target");
+    backend.delete_file(&guest_path)?;
+
+    say!("🕒 Copying the new driver file to the target");
+    copy_and_verify(backend, &host_path, &guest_path)?;
+
+    symbols::deploy(backend, profile)?;
+    agent::install(backend, &agent::build(profile)?)?;
+
+    match INSTALL_METHOD {
+        InstallMethod::Sc => {
+            // Creating the service fails while the old one is marked for
+            // deletion, until its last handle is closed.
+            say!("🕒 Creating the '{SERVICE_NAME}' service in the target");
+            retry::retry("Creating the service", || {
+                let output = backend.run_program_with_output(
+                    &sc,
+                    &[
+                        "create",
+                        SERVICE_NAME,
+                        "type=",
+                        "kernel",
+                        "binPath=",
+                        &guest_path.to_string(),
+                    ],
+                )?;
+                ensure!(
+                    !output.contains("FAILED"),
+                    "sc create failed: {}",
+                    output.trim()
+                );
+                Ok(())
+            })
+        }
+        InstallMethod::Inf => install_inf(backend, &host_path),
+    }
+}
+
+/// Copies the INF and catalog files generated next to the driver file at
+/// `driver_path` to the target, and installs the driver with `pnputil`, which
+/// adds it to the driver store and creates the service from the INF.
+fn install_inf(backend: &impl Backend, driver_path: &Path) -> Result<()> {
+    let inf_path = copy_inf(backend, driver_path)?;
+    let pnputil = GuestPath::new(PathBuf::from_str(PNPUTIL_PATH)?);
+
+    say!("🕒 Installing the driver with pnputil in the target");
+    backend.run_program(
+        &pnputil,
+        &["/add-driver", &inf_path.to_string(), "/install"],
+    )
+}
+
+/// Copies the INF and catalog files generated next to the driver file at
+/// `driver_path` to the target, and returns the path to the INF file there.
+pub(crate) fn copy_inf(backend: &impl Backend, driver_path: &Path) -> Result<GuestPath> {
+    let inf_path = backend
+        .driver_dir()
+        .join(&(MODULE_NAME.to_owned() + ".inf"));
+
+    say!("🕒 Copying the INF and catalog files to the target");
+    for extension in ["inf", "cat"] {
+        let host_path = driver_path.with_extension(extension);
+        ensure!(
+            host_path.exists(),
+            "{} does not exist. Build the driver with `cargo make`",
+            host_path.display()
+        );
+        let guest_path = backend
+            .driver_dir()
+            .join(&format!("{MODULE_NAME}.{extension}"));
+        backend.delete_file(&guest_path)?;
+        copy_and_verify(backend, &host_path, &guest_path)?;
+    }
+    Ok(inf_path)
+}
+
+/// Returns the path to the driver file built with `profile`.
+pub(crate) fn driver_path(profile: Profile) -> PathBuf {
+    profile
+        .target_dir()
+        .join(MODULE_NAME.to_owned() + "_package")
+        .join(MODULE_NAME.to_owned() + ".sys")
+}
+
+/// Copies a file from the host to the target and verifies that the copy has the
+/// same SHA-256 hash as the original. This catches stale or truncated copies,
+/// e.g., when the old file was still locked.
+pub(crate) fn copy_and_verify(backend: &impl Backend, src: &Path, dst: &GuestPath) -> Result<()> {
+    const CERTUTIL_PATH: &str = r"C:\Windows\System32\certutil.exe";
+
+    backend.copy_file(src, dst)?;
+
+    let expected = format!("{:x}", Sha256::digest(fs::read(src)?));
+
+    // The hash is printed on the second line. Older versions of certutil
+    // separate each byte with a space.
+    let certutil = GuestPath::new(PathBuf::from(CERTUTIL_PATH));
+    let output =
+        backend.run_program_with_output(&certutil, &["-hashfile", &dst.to_string(), "SHA256"])?;
+    let actual = output
+        .lines()
+        .nth(1)
+        .unwrap_or_default()
+        .replace(' ', "")
+        .to_lowercase();
+    ensure!(
+        actual == expected,
+        "{dst} has SHA-256 {actual:?} in the target but {expected} is expected"
+    );
+    Ok(())
+}
+
+/// Starts the driver service in the target, and checks that the driver loaded.
+pub(crate) fn start_driver(backend: &impl Backend, profile: Profile) -> Result<()> {
+    let sc = GuestPath::new(PathBuf::from_str(SC_PATH)?);
+
+    say!("🕒 Starting the driver in the target");
+    let output =
+        backend.run_program_with_output_within(&sc, &["start", SERVICE_NAME], START_TIMEOUT)?;
+    health::check(backend, profile, &output)
+}
+
+fn log_thread(log_path: &Path) {
+    fn wait_and_show_logs(log_path: &Path) -> Result<()> {
+        while !log_path.exists() {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let file = File::open(log_path)?;
+        let mut reader = BufReader::new(&file);
+        loop {
+            let mut line = String::new();
+            let bytes_read = reader.read_line(&mut line)?;
+            if bytes_read > 0 {
+                ui::log(&line);
+            } else {
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+
+    wait_and_show_logs(log_path).expect("boo!");
+}
+
+/// A path on the target.
+#[derive(Clone, Debug)]
+pub(crate) struct GuestPath(PathBuf);
+
+impl GuestPath {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Self(path)
+    }
+
+    pub(crate) fn join(&self, child: &str) -> Self {
+        Self(self.0.join(child))
+    }
+}
+
+impl fmt::Display for GuestPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
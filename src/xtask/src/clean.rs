@@ -0,0 +1,169 @@
+use std::path::PathBuf;
+
+use anyhow::{Ok, Result, ensure};
+use colored::Colorize;
+
+use crate::{
+    agent::{AGENT_PROGRAM_NAME, AGENT_SERVICE_NAME},
+    backend::{Backend, GuestPath},
+    config::{GUEST_SYMBOL_DIR, MODULE_NAME},
+    replay::REPLAY_FILE_NAME,
+    scenario::MAP_TEST_DRIVER_NAME,
+    test::{COMPAT_POC_FILE_NAME, REPORT_FILE_NAME, TEST_PROGRAM_NAME, TRACE_FILE_NAME},
+    verifier::MEMORY_DUMP_PATH,
+    vmware::OUTPUT_FILE_NAME,
+};
+
+const CMD_PATH: &str = r"C:\Windows\System32\cmd.exe";
+const SC_PATH: &str = r"C:\Windows\System32\sc.exe";
+const PNPUTIL_PATH: &str = r"C:\Windows\System32\pnputil.exe";
+const VERIFIER_PATH: &str = r"C:\Windows\System32\verifier.exe";
+
+/// The error `sc query` reports when the service does not exist.
+const ERROR_SERVICE_DOES_NOT_EXIST: &str = "1060";
+
+/// Removes what xtask placed in the running target, i.e., the services, the
+/// driver package in the driver store, the Driver Verifier settings, and the
+/// files, then verifies none of them remains. Run it before taking a new
+/// snapshot of the target.
+///
+/// Test signing, which xtask may have enabled, is left as it is, as it is a
+/// prerequisite of deployment rather than an artifact of it.
+pub(crate) fn run(backend: &impl Backend) -> Result<()> {
+    backend.wait_until_ready()?;
+
+    let sc = GuestPath::new(PathBuf::from(SC_PATH));
+    println!("🕒 Deleting the '{MODULE_NAME}' service in the target");
+    let _unused = backend.run_program_with_output(&sc, &["stop", MODULE_NAME])?;
+    let _unused = backend.run_program_with_output(&sc, &["delete", MODULE_NAME])?;
+    println!("🕒 Deleting the '{AGENT_SERVICE_NAME}' service in the target");
+    let _unused = backend.run_program_with_output(&sc, &["delete", AGENT_SERVICE_NAME])?;
+
+    let pnputil = GuestPath::new(PathBuf::from(PNPUTIL_PATH));
+    for published_name in driver_packages(backend)? {
+        println!("🕒 Deleting {published_name} from the driver store in the target");
+        let _unused = backend.run_program_with_output(
+            &pnputil,
+            &["/delete-driver", &published_name, "/uninstall", "/force"],
+        )?;
+    }
+
+    if is_verified(backend)? {
+        println!("🕒 Resetting Driver Verifier in the target");
+        let verifier = GuestPath::new(PathBuf::from(VERIFIER_PATH));
+        let _unused = backend.run_program_with_output(&verifier, &["/reset"])?;
+    }
+
+    println!("🕒 Deleting the files in the target");
+    for path in files(backend) {
+        backend.delete_file(&path)?;
+    }
+
+    println!("🕒 Verifying the target is clean");
+    let leftovers = leftovers(backend)?;
+
+    // Checking the target leaves the output file of vmrun behind.
+    backend.delete_file(&backend.driver_dir().join(OUTPUT_FILE_NAME))?;
+
+    ensure!(
+        leftovers.is_empty(),
+        "the target still has {}. Reboot it and run the command again",
+        leftovers.join(", ")
+    );
+    println!("{}", "✅ The target is clean".green());
+    Ok(())
+}
+
+/// Returns the files xtask copies to the target or leaves behind there. The
+/// output file of vmrun is excluded as checking the target creates it again.
+fn files(backend: &impl Backend) -> Vec<GuestPath> {
+    let driver_dir = backend.driver_dir();
+    let mut files: Vec<_> = ["sys", "inf", "cat"]
+        .into_iter()
+        .map(|extension| driver_dir.join(&format!("{MODULE_NAME}.{extension}")))
+        .collect();
+    files.push(driver_dir.join(&(TEST_PROGRAM_NAME.to_owned() + ".exe")));
+    files.push(driver_dir.join(&(AGENT_PROGRAM_NAME.to_owned() + ".exe")));
+    files.push(driver_dir.join(REPORT_FILE_NAME));
+    files.push(driver_dir.join(TRACE_FILE_NAME));
+    files.push(driver_dir.join(COMPAT_POC_FILE_NAME));
+    files.push(driver_dir.join(REPLAY_FILE_NAME));
+    files.push(driver_dir.join(&(MAP_TEST_DRIVER_NAME.to_owned() + ".sys")));
+    files.push(
+        GuestPath::new(PathBuf::from(GUEST_SYMBOL_DIR)).join(&(MODULE_NAME.to_owned() + ".pdb")),
+    );
+    files.push(GuestPath::new(PathBuf::from(MEMORY_DUMP_PATH)));
+    files
+}
+
+/// Returns the descriptions of what remains in the target.
+fn leftovers(backend: &impl Backend) -> Result<Vec<String>> {
+    let mut leftovers = Vec::new();
+
+    let sc = GuestPath::new(PathBuf::from(SC_PATH));
+    for service in [MODULE_NAME, AGENT_SERVICE_NAME] {
+        let output = backend.run_program_with_output(&sc, &["query", service])?;
+        if !output.contains(ERROR_SERVICE_DOES_NOT_EXIST) {
+            leftovers.push(format!("the '{service}' service"));
+        }
+    }
+
+    for published_name in driver_packages(backend)? {
+        leftovers.push(format!("{published_name} in the driver store"));
+    }
+
+    if is_verified(backend)? {
+        leftovers.push("Driver Verifier settings".to_owned());
+    }
+
+    // Paths are not quoted as the VMware backend runs the command with cmd.exe,
+    // which strips the first and last quotes of the command line.
+    let cmd = GuestPath::new(PathBuf::from(CMD_PATH));
+    for path in files(backend) {
+        let output = backend.run_program_with_output(
+            &cmd,
+            &["/c", "if", "exist", &path.to_string(), "echo", "exists"],
+        )?;
+        if output.contains("exists") {
+            leftovers.push(path.to_string());
+        }
+    }
+
+    Ok(leftovers)
+}
+
+/// Returns the published names, e.g., `oem5.inf`, of the driver packages added
+/// from the INF of the driver with `pnputil`.
+fn driver_packages(backend: &impl Backend) -> Result<Vec<String>> {
+    let pnputil = GuestPath::new(PathBuf::from(PNPUTIL_PATH));
+    let output = backend.run_program_with_output(&pnputil, &["/enum-drivers"])?;
+
+    // Each package is listed as lines of `<name>: <value>`, starting with the
+    // published name followed by the original name.
+    let inf_name = MODULE_NAME.to_owned() + ".inf";
+    let mut published_name = None;
+    let mut packages = Vec::new();
+    for line in output.lines() {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim() {
+            "Published Name" => published_name = Some(value.trim().to_owned()),
+            "Original Name" if value.trim().eq_ignore_ascii_case(&inf_name) => {
+                packages.extend(published_name.take());
+            }
+            _ => {}
+        }
+    }
+    Ok(packages)
+}
+
+/// Checks whether Driver Verifier is configured to verify the driver.
+fn is_verified(backend: &impl Backend) -> Result<bool> {
+    let verifier = GuestPath::new(PathBuf::from(VERIFIER_PATH));
+    let output = backend.run_program_with_output(&verifier, &["/querysettings"])?;
+    let driver_name = MODULE_NAME.to_owned() + ".sys";
+    Ok(output
+        .split_whitespace()
+        .any(|word| word.eq_ignore_ascii_case(&driver_name)))
+}
@@ -0,0 +1,160 @@
+use std::{collections::BTreeSet, fs, path::Path};
+
+use anyhow::{Ok, Result};
+use capcom_abi::{DEVICE_NAME, DEVICE_TYPE, IOCTL_RUN_PAYLOAD32, IOCTLS};
+use colored::Colorize;
+use object::{Object, ObjectSection, SectionKind};
+
+use crate::{Profile, backend::driver_path};
+
+/// Compares the built driver with the original Capcom.sys at `original`, and
+/// reports differences in exports, imports, the device name and IOCTL codes.
+pub(crate) fn run(original: &Path, profile: Profile) -> Result<()> {
+    let original = Binary::new(original)?;
+    let ours = Binary::new(&driver_path(profile))?;
+    let mut compatible = true;
+
+    println!("Exports");
+    compatible &= print_diff(&original.exports, &ours.exports);
+
+    // Imports may differ without affecting compatibility, as the behavior is
+    // what matters.
+    println!();
+    println!("Imports");
+    let _ = print_diff(&original.imports, &ours.imports);
+
+    // The original decrypts the device name at runtime, so it may not be
+    // found as a plain string.
+    println!();
+    println!("Device name");
+    let device_name = DEVICE_NAME.rsplit('\\').next().unwrap();
+    for (label, binary) in [("original", &original), ("ours", &ours)] {
+        if binary.contains_utf16(device_name) {
+            println!("  {label}: {DEVICE_NAME}");
+        } else {
+            println!("  {label}: {}", "not found as a plain string".yellow());
+        }
+    }
+    if !ours.contains_utf16(device_name) {
+        compatible = false;
+    }
+
+    // Dispatch behaviors are identified by the IOCTL codes compared in the
+    // code sections. Codes known to the ABI crate are shown with their names.
+    println!();
+    println!("IOCTL codes (device type {DEVICE_TYPE:#x})");
+    let implemented: BTreeSet<_> = IOCTLS.iter().map(|&(code, _)| code).collect();
+    for &code in original.ioctls.union(&ours.ioctls) {
+        let line = format!("  {code:#010x} {:<20}", ioctl_name(code));
+        match (original.ioctls.contains(&code), implemented.contains(&code)) {
+            (true, true) => println!("{line} ✅"),
+            (true, false) => {
+                println!("{}", format!("{line} only in the original").red());
+                compatible = false;
+            }
+            (false, _) => println!("{}", format!("{line} only in ours").yellow()),
+        }
+    }
+
+    println!();
+    if compatible {
+        println!(
+            "{}",
+            "✅ The driver is compatible with the original".green()
+        );
+    } else {
+        println!(
+            "{}",
+            "❌ The driver is not compatible with the original".red()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the name of the IOCTL code if known.
+fn ioctl_name(code: u32) -> &'static str {
+    if code == IOCTL_RUN_PAYLOAD32 {
+        return "IOCTL_RUN_PAYLOAD32";
+    }
+    IOCTLS
+        .iter()
+        .find(|&&(known, _)| known == code)
+        .map_or("unknown", |&(_, name)| name)
+}
+
+/// Prints items only in `original` in red and items only in `ours` in yellow.
+/// Returns whether `ours` has all items in `original`.
+fn print_diff(original: &BTreeSet<String>, ours: &BTreeSet<String>) -> bool {
+    for item in original.intersection(ours) {
+        println!("  {item}");
+    }
+    for item in original.difference(ours) {
+        println!("{}", format!("  {item} (only in the original)").red());
+    }
+    for item in ours.difference(original) {
+        println!("{}", format!("  {item} (only in ours)").yellow());
+    }
+    original.is_subset(ours)
+}
+
+/// Properties of a driver file compared.
+#[derive(Debug)]
+struct Binary {
+    data: Vec<u8>,
+    exports: BTreeSet<String>,
+    imports: BTreeSet<String>,
+    ioctls: BTreeSet<u32>,
+}
+
+impl Binary {
+    fn new(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        let file = object::File::parse(data.as_slice())?;
+        let exports = file
+            .exports()?
+            .iter()
+            .map(|export| String::from_utf8_lossy(export.name()).into_owned())
+            .collect();
+        let imports = file
+            .imports()?
+            .iter()
+            .map(|import| {
+                format!(
+                    "{}!{}",
+                    String::from_utf8_lossy(import.library()),
+                    String::from_utf8_lossy(import.name())
+                )
+            })
+            .collect();
+
+        // Look for 32-bit immediates that look like IOCTL codes of the device
+        // type, e.g., operands of `cmp`.
+        let mut ioctls = BTreeSet::new();
+        for section in file.sections() {
+            if section.kind() != SectionKind::Text {
+                continue;
+            }
+            for bytes in section.data()?.windows(4) {
+                let value = u32::from_le_bytes(bytes.try_into()?);
+                if value >> 16 == DEVICE_TYPE {
+                    let _ = ioctls.insert(value);
+                }
+            }
+        }
+
+        Ok(Self {
+            data,
+            exports,
+            imports,
+            ioctls,
+        })
+    }
+
+    /// Checks whether the file contains `text` encoded in UTF-16LE.
+    fn contains_utf16(&self, text: &str) -> bool {
+        let needle: Vec<_> = text.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        self.data
+            .windows(needle.len())
+            .any(|window| window == needle)
+    }
+}
@@ -1,8 +1,83 @@
+use std::time::Duration;
+
+use crate::{
+    backend::InstallMethod,
+    logs::{Level, LogPreset},
+    remote::{CopyMethod, RebootMethod},
+};
+
 pub(crate) const VMX_PATH: &str = VMX_PATH_W11;
 pub(crate) const LOG_PATH: &str = r"C:\OST2\serial.log";
+pub(crate) const VMX_PATH_ARM64: &str = r"C:\OST2\Win11ARM64\Win11ARM64.vmx";
+pub(crate) const LOG_PATH_ARM64: &str = r"C:\OST2\serial_arm64.log";
 pub(crate) const SNAPSHOT_NAME: &str = "OST2";
 pub(crate) const USER_NAME: &str = "user";
 pub(crate) const PASSWORD: &str = "123";
 pub(crate) const MODULE_NAME: &str = "capcom";
+pub(crate) const GUEST_SYMBOL_DIR: &str = r"C:\Symbols";
+pub(crate) const INSTALL_METHOD: InstallMethod = InstallMethod::Sc;
+pub(crate) const KD_PATH: &str = r"C:\Program Files (x86)\Windows Kits\10\Debuggers\x64\kd.exe";
+pub(crate) const YARA_PATH: &str = "yara64.exe";
+
+// A public exploit for the original driver on the host, e.g., a build of
+// ExploitCapcom, that runs in the target as the `compat_poc` test. The test is
+// reported as skipped with `None`.
+pub(crate) const COMPAT_POC_PATH: Option<&str> = None;
+
+// Timeouts of operations in the target. An operation that does not complete in
+// time is killed and fails the run, as does any after `RUN_DEADLINE`.
+// `TOOLS_TIMEOUT` is for the target to become ready after starting it, and
+// `COMMAND_TIMEOUT` is for programs in the target without a specific timeout.
+pub(crate) const TOOLS_TIMEOUT: Duration = Duration::from_mins(5);
+pub(crate) const COPY_TIMEOUT: Duration = Duration::from_mins(2);
+pub(crate) const START_TIMEOUT: Duration = Duration::from_mins(1);
+pub(crate) const TEST_TIMEOUT: Duration = Duration::from_mins(10);
+pub(crate) const COMMAND_TIMEOUT: Duration = Duration::from_mins(2);
+pub(crate) const RUN_DEADLINE: Duration = Duration::from_hours(1);
+
+// The total time to wait for retrying steps that failed transiently.
+pub(crate) const RETRY_BUDGET: Duration = Duration::from_mins(3);
+
+// The number of IOCTLs `cargo xtask fuzz` sends by default, and how many of
+// the last ones before a crash it saves with the run.
+pub(crate) const FUZZ_ITERATIONS: u32 = 10_000;
+pub(crate) const FUZZ_LAST_REQUESTS: usize = 100;
+
+// The port of localhost `vmware` and `remote` accept commands on while the
+// target runs, e.g., `redeploy` after rebuilding the driver. `None` disables it.
+pub(crate) const CONTROL_PORT: Option<u16> = Some(47_101);
+
+// Named filters of `cargo xtask logs --preset <name>`.
+pub(crate) const LOG_PRESETS: &[LogPreset] = &[
+    LogPreset {
+        name: "driver",
+        patterns: &["capcom#"],
+        level: Level::Info,
+    },
+    LogPreset {
+        name: "crash",
+        patterns: &[
+            "*** Fatal System Error",
+            "BUGCHECK",
+            "Bugcheck",
+            "Driver Verifier",
+        ],
+        level: Level::Info,
+    },
+    LogPreset {
+        name: "errors",
+        patterns: &[],
+        level: Level::Error,
+    },
+];
+
+// The remote physical machine. It must run the OpenSSH server with key-based
+// authentication configured for `REMOTE_USER`.
+pub(crate) const REMOTE_HOST: &str = "192.168.1.100";
+pub(crate) const REMOTE_USER: &str = "user";
+pub(crate) const REMOTE_COPY: CopyMethod = CopyMethod::Scp;
+pub(crate) const REMOTE_REBOOT: RebootMethod = RebootMethod::Os;
+pub(crate) const REMOTE_BOOT_TIMEOUT: Duration = Duration::from_mins(5);
+pub(crate) const REMOTE_LOG_PATH: Option<&str> = None;
 
 const VMX_PATH_W11: &str = r"C:\OST2\Win11\Win11.vmx";
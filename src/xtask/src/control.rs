@@ -0,0 +1,125 @@
+use std::{
+    fs,
+    io::{BufRead, BufReader, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, PoisonError},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Ok, Result, bail};
+use colored::Colorize;
+
+use crate::{
+    Profile,
+    backend::{Backend, GuestPath, TARGET_LOCK, deploy},
+    config::{CONTROL_PORT, MODULE_NAME},
+    test,
+    ui::{self, say},
+    workspace_root_dir,
+};
+
+const SC_PATH: &str = r"C:\Windows\System32\sc.exe";
+
+const COMMANDS: &str = "redeploy, run-test [<name>], screenshot, stop, help";
+
+/// Starts accepting commands on [`CONTROL_PORT`] of localhost, so that other
+/// tools can drive the running session. Does nothing if the port is not set.
+///
+/// Each command is a line, and is answered with a line of `ok`, optionally
+/// followed by a result such as a path, or `error <message>`.
+pub(crate) fn spawn(backend: Arc<impl Backend>, profile: Profile) -> Result<()> {
+    let Some(port) = CONTROL_PORT else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, port)).with_context(|| {
+        format!("failed to listen on localhost:{port}. Another session may be running")
+    })?;
+    say!("🎛️ Accepting commands on localhost:{port}: {COMMANDS}");
+    let _unused = thread::Builder::new()
+        .name("control".to_owned())
+        .spawn(move || {
+            // Clients are served one at a time, as are their commands.
+            for stream in listener.incoming().flatten() {
+                // A client that disconnects does not stop the others.
+                drop(serve(backend.as_ref(), profile, stream));
+            }
+        })?;
+    Ok(())
+}
+
+fn serve(backend: &impl Backend, profile: Profile, stream: TcpStream) -> Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let command = line.trim();
+        if command.is_empty() {
+            continue;
+        }
+
+        say!("🎛️ Received {command:?}");
+        let response = match execute(backend, profile, command) {
+            Result::Ok(Some(result)) => format!("ok {result}"),
+            Result::Ok(None) => "ok".to_owned(),
+            Err(err) => {
+                say!("{}", format!("❌ {command} failed: {err:#}").red());
+                format!("error {}", format!("{err:#}").replace(['\r', '\n'], " "))
+            }
+        };
+        writeln!(writer, "{response}")?;
+        if command == "stop" {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Executes `command` and returns its result, if any.
+fn execute(backend: &impl Backend, profile: Profile, command: &str) -> Result<Option<String>> {
+    let (name, argument) = match command.split_once(char::is_whitespace) {
+        Some((name, argument)) => (name, Some(argument.trim())),
+        None => (command, None),
+    };
+    if name == "help" {
+        return Ok(Some(COMMANDS.to_owned()));
+    }
+    if name == "stop" {
+        ui::request_exit();
+        return Ok(None);
+    }
+
+    let _guard = TARGET_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    match name {
+        "redeploy" => redeploy(backend, profile).map(|()| None),
+        "run-test" => {
+            let test_program = test::build(profile)?;
+            let args: Vec<_> = argument
+                .map(|name| ["--test", name])
+                .into_iter()
+                .flatten()
+                .collect();
+            test::run(backend, &test_program, &args).map(|()| None)
+        }
+        "screenshot" => {
+            let screenshot_dir = workspace_root_dir().join("target").join("screenshots");
+            fs::create_dir_all(&screenshot_dir)?;
+            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let path = screenshot_dir.join(format!("{timestamp}.png"));
+            say!("🕒 Capturing the screen of the target");
+            backend.capture_screen(&path)?;
+            Ok(Some(path.display().to_string()))
+        }
+        _ => bail!("unknown command {name:?}. Commands are {COMMANDS}"),
+    }
+}
+
+/// Stops and deletes the service of the driver, and deploys the driver again,
+/// e.g., after it is rebuilt.
+fn redeploy(backend: &impl Backend, profile: Profile) -> Result<()> {
+    let sc = GuestPath::new(PathBuf::from(SC_PATH));
+    say!("🕒 Deleting the '{MODULE_NAME}' service in the target");
+    let _unused = backend.run_program_with_output(&sc, &["stop", MODULE_NAME])?;
+    let _unused = backend.run_program_with_output(&sc, &["delete", MODULE_NAME])?;
+    deploy(backend, profile)
+}
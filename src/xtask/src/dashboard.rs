@@ -0,0 +1,300 @@
+use std::{
+    collections::VecDeque,
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Ok, Result};
+use ratatui::{
+    DefaultTerminal, Frame,
+    crossterm::event::{self, Event as TermEvent, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line, Span},
+    widgets::{Block, List, ListItem, Paragraph},
+};
+
+use crate::{
+    config::MODULE_NAME,
+    logs::{Level, is_crash},
+    ui::Event,
+};
+
+/// The maximum number of lines of the debug output kept.
+const MAX_LOG_LINES: usize = 10_000;
+
+/// Which lines of the debug output are shown.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Filter {
+    All,
+    /// Messages of the driver, such as `capcom#4`.
+    Driver,
+    Errors,
+}
+
+impl Filter {
+    fn next(self) -> Self {
+        match self {
+            Filter::All => Filter::Driver,
+            Filter::Driver => Filter::Errors,
+            Filter::Errors => Filter::All,
+        }
+    }
+
+    fn matches(self, line: &str) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Driver => line.contains(&format!("{MODULE_NAME}#")),
+            Filter::Errors => Level::of(line) == Level::Error,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum StageState {
+    Running,
+    Done,
+    Failed,
+}
+
+/// What the dashboard shows.
+#[derive(Debug)]
+struct Dashboard {
+    start: Instant,
+    /// The stages of the pipeline, one per `🕒` line.
+    stages: Vec<(String, StageState)>,
+    /// Other progress lines, such as results and hints.
+    messages: VecDeque<String>,
+    logs: VecDeque<String>,
+    filter: Filter,
+    crashed: bool,
+    /// The result of the pipeline once it completed.
+    result: Option<Option<String>>,
+    quitting: bool,
+}
+
+impl Dashboard {
+    fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            stages: Vec::new(),
+            messages: VecDeque::new(),
+            logs: VecDeque::new(),
+            filter: Filter::All,
+            crashed: false,
+            result: None,
+            quitting: false,
+        }
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Message(line) => self.handle_message(line),
+            Event::Log(line) => {
+                self.crashed |= is_crash(&line);
+                if self.logs.len() == MAX_LOG_LINES {
+                    let _ = self.logs.pop_front();
+                }
+                self.logs.push_back(line);
+            }
+            Event::Done(error) => {
+                self.finish_stage(if error.is_some() {
+                    StageState::Failed
+                } else {
+                    StageState::Done
+                });
+                self.result = Some(error);
+            }
+        }
+    }
+
+    fn handle_message(&mut self, line: String) {
+        if let Some(stage) = line.strip_prefix("🕒 ") {
+            self.finish_stage(StageState::Done);
+            self.stages.push((stage.to_owned(), StageState::Running));
+            return;
+        }
+        if line.starts_with('❌') {
+            self.finish_stage(StageState::Failed);
+        }
+        if self.messages.len() == MAX_LOG_LINES {
+            let _ = self.messages.pop_front();
+        }
+        self.messages.push_back(line);
+    }
+
+    /// Marks the running stage as `state`.
+    fn finish_stage(&mut self, state: StageState) {
+        if let Some((_, last)) = self.stages.last_mut()
+            && *last == StageState::Running
+        {
+            *last = state;
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame<'_>) {
+        let [header, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, logs] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(body);
+        let [stages, messages] =
+            Layout::vertical([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(left);
+
+        frame.render_widget(Paragraph::new(self.status_line()), header);
+        self.draw_stages(frame, stages);
+        draw_tail(
+            frame,
+            messages,
+            "Messages",
+            self.messages.iter().map(|line| message_line(line)),
+        );
+        draw_tail(
+            frame,
+            logs,
+            &format!("Debug output ({:?})", self.filter),
+            self.logs
+                .iter()
+                .filter(|line| self.filter.matches(line))
+                .map(|line| log_line(line)),
+        );
+        let help = if self.quitting {
+            "Shutting down the target..."
+        } else {
+            "q: quit  f: change the filter of the debug output"
+        };
+        frame.render_widget(
+            Paragraph::new(help).style(Style::new().add_modifier(Modifier::DIM)),
+            footer,
+        );
+    }
+
+    fn status_line(&self) -> Line<'_> {
+        let (status, color) = if self.crashed {
+            ("CRASHED".to_owned(), Color::Red)
+        } else {
+            match &self.result {
+                Some(None) => ("completed".to_owned(), Color::Green),
+                Some(Some(error)) => (format!("failed: {error}"), Color::Red),
+                None => ("running".to_owned(), Color::Yellow),
+            }
+        };
+        Line::from(vec![
+            Span::styled(" xtask ", Style::new().add_modifier(Modifier::REVERSED)),
+            Span::raw(format!(" {}s  target: ", self.start.elapsed().as_secs())),
+            Span::styled(status, Style::new().fg(color).add_modifier(Modifier::BOLD)),
+        ])
+    }
+
+    fn draw_stages(&self, frame: &mut Frame<'_>, area: Rect) {
+        let items: Vec<_> = self
+            .stages
+            .iter()
+            .map(|(stage, state)| {
+                let (mark, color) = match state {
+                    StageState::Running => ("…", Color::Yellow),
+                    StageState::Done => ("✔", Color::Green),
+                    StageState::Failed => ("✘", Color::Red),
+                };
+                ListItem::new(Line::styled(
+                    format!("{mark} {stage}"),
+                    Style::new().fg(color),
+                ))
+            })
+            .collect();
+        let skip = items
+            .len()
+            .saturating_sub(usize::from(area.height.saturating_sub(2)));
+        frame.render_widget(
+            List::new(items.into_iter().skip(skip)).block(Block::bordered().title("Stages")),
+            area,
+        );
+    }
+}
+
+/// Shows the dashboard with events from `events` until the user quits and the
+/// pipeline completes. `quit` is called when the user asks to quit, so that the
+/// pipeline shuts the target down.
+pub(crate) fn run(events: &Receiver<Event>, quit: impl Fn()) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = show(&mut terminal, events, quit);
+    ratatui::restore();
+    result
+}
+
+fn show(terminal: &mut DefaultTerminal, events: &Receiver<Event>, quit: impl Fn()) -> Result<()> {
+    let mut dashboard = Dashboard::new();
+    loop {
+        while let Result::Ok(event) = events.try_recv() {
+            dashboard.handle(event);
+        }
+        if dashboard.quitting
+            && let Some(result) = &dashboard.result
+        {
+            return match result {
+                Some(error) => Err(anyhow::anyhow!("{error}")),
+                None => Ok(()),
+            };
+        }
+
+        let _ = terminal.draw(|frame| dashboard.draw(frame))?;
+
+        if event::poll(Duration::from_millis(100))?
+            && let TermEvent::Key(key) = event::read()?
+            && key.kind == KeyEventKind::Press
+        {
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.code == KeyCode::Char('q') || ctrl_c {
+                dashboard.quitting = true;
+                quit();
+            } else if key.code == KeyCode::Char('f') {
+                dashboard.filter = dashboard.filter.next();
+            }
+        }
+    }
+}
+
+/// Draws the last lines of `lines` that fit in `area`.
+fn draw_tail<'a>(
+    frame: &mut Frame<'_>,
+    area: Rect,
+    title: &str,
+    lines: impl Iterator<Item = Line<'a>>,
+) {
+    let lines: Vec<_> = lines.collect();
+    let skip = lines
+        .len()
+        .saturating_sub(usize::from(area.height.saturating_sub(2)));
+    let lines: Vec<_> = lines.into_iter().skip(skip).collect();
+    frame.render_widget(
+        Paragraph::new(lines).block(Block::bordered().title(title.to_owned())),
+        area,
+    );
+}
+
+fn message_line(line: &str) -> Line<'_> {
+    let color = if line.starts_with('✅') {
+        Color::Green
+    } else if line.starts_with('❌') {
+        Color::Red
+    } else if line.starts_with(['💡', '⚠', '🔁', '⏳']) {
+        Color::Yellow
+    } else {
+        Color::Reset
+    };
+    Line::styled(line, Style::new().fg(color))
+}
+
+fn log_line(line: &str) -> Line<'_> {
+    let color = match Level::of(line) {
+        Level::Error => Color::Red,
+        Level::Warn => Color::Yellow,
+        Level::Info => Color::Reset,
+    };
+    Line::styled(line, Style::new().fg(color))
+}
@@ -0,0 +1,125 @@
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::Path,
+};
+
+use anyhow::{Ok, Result};
+use capcom_abi::messages::MESSAGES;
+
+/// The prefix of messages the driver prints to the kernel debugger.
+const PREFIX: &str = "capcom#";
+
+/// Renders messages of the driver in the debug output read from `input`, or
+/// from stdin if `None`, and prints the output. Other lines are printed as is.
+pub(crate) fn run(input: Option<&Path>) -> Result<()> {
+    let reader: Box<dyn BufRead> = match input {
+        Some(path) => Box::new(BufReader::new(File::open(path)?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    for line in reader.lines() {
+        let line = line?;
+        println!("{}", decode_line(&line).unwrap_or(line));
+    }
+    Ok(())
+}
+
+/// Renders the message in `line`, e.g., `capcom#10 1`, keeping the text before
+/// it, such as a timestamp. The correlation ID of a message written for
+/// `IOCTL_CORRELATED`, e.g., `capcom#10@2a 1`, is rendered in brackets before
+/// the text.
+pub(crate) fn decode_line(line: &str) -> Option<String> {
+    let (prefix, message) = line.split_once(PREFIX)?;
+    let mut words = message.split_whitespace();
+    let word = words.next()?;
+    let (id, correlation_id) = match word.split_once('@') {
+        Some((id, correlation_id)) => (id, Some(u64::from_str_radix(correlation_id, 16).ok()?)),
+        None => (word, None),
+    };
+    let id = id.parse().ok()?;
+    let arguments = words
+        .map(|word| u64::from_str_radix(word, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+    let text = render(id, &arguments)?;
+    Some(match correlation_id {
+        Some(correlation_id) => format!("{prefix}[{correlation_id:#x}] {text}"),
+        None => format!("{prefix}{text}"),
+    })
+}
+
+/// Renders the message `id` with `arguments`, or returns `None` if the ID is
+/// unknown.
+fn render(id: u32, arguments: &[u64]) -> Option<String> {
+    let &(_, format) = MESSAGES.iter().find(|&&(message_id, _)| message_id == id)?;
+
+    // Numeric placeholders take the arguments first, and the bytes placeholder
+    // takes the rest.
+    let specs = placeholders(format);
+    let numeric = specs.iter().filter(|spec| !is_bytes(spec)).count();
+    let (mut numbers, bytes) = arguments.split_at(numeric.min(arguments.len()));
+    let bytes: Vec<u8> = bytes.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+    let mut text = String::new();
+    let mut rest = format;
+    for spec in specs {
+        let (before, after) = rest.split_once('{').unwrap_or((rest, ""));
+        text.push_str(before);
+        rest = after.split_once('}').map_or("", |(_, after)| after);
+        if is_bytes(spec) {
+            render_bytes(&mut text, spec, &bytes);
+        } else if let Some((&number, remaining)) = numbers.split_first() {
+            render_number(&mut text, spec, number);
+            numbers = remaining;
+        } else {
+            text.push('?');
+        }
+    }
+    text.push_str(rest);
+    Some(text)
+}
+
+/// Returns the specs of the placeholders in `format`, e.g., `:#x` of `{:#x}`.
+fn placeholders(format: &str) -> Vec<&str> {
+    format
+        .split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}').map(|(spec, _)| spec))
+        .collect()
+}
+
+/// Checks whether the placeholder with `spec` takes the bytes.
+fn is_bytes(spec: &str) -> bool {
+    spec == ":s" || spec.ends_with('?')
+}
+
+/// Appends `bytes` as ASCII text up to the first NUL for `:s`, or as a list of
+/// bytes in hex, with trailing padding removed.
+fn render_bytes(text: &mut String, spec: &str, bytes: &[u8]) {
+    if spec == ":s" {
+        let length = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+        let _ = write!(text, "{}", bytes[..length].escape_ascii());
+    } else {
+        let _ = write!(text, "{bytes:02x?}");
+    }
+}
+
+/// Appends `number` formatted with `spec`, one of `{}`, `{:x}`, `{:#x}` and
+/// hex with zero padding, such as `{:04x}`.
+fn render_number(text: &mut String, spec: &str, number: u64) {
+    let spec = spec.strip_prefix(':').unwrap_or(spec);
+    let Some(spec) = spec.strip_suffix('x') else {
+        let _ = write!(text, "{number}");
+        return;
+    };
+    let (alternate, width) = match spec.strip_prefix('#') {
+        Some(width) => (true, width),
+        None => (false, spec),
+    };
+    let width = width.parse().unwrap_or(0);
+    let _ = if alternate {
+        write!(text, "{number:#0width$x}")
+    } else {
+        write!(text, "{number:0width$x}")
+    };
+}
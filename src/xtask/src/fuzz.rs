@@ -0,0 +1,150 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Ok, Result};
+use colored::Colorize;
+
+use crate::{
+    Profile,
+    backend::{Backend, deploy},
+    config::FUZZ_LAST_REQUESTS,
+    logs,
+    test::{self, TRACE_FILE_NAME},
+    ui::say,
+    verifier,
+};
+
+/// The file the last IOCTLs before a crash are saved to in the directory of
+/// the run.
+const LAST_REQUESTS_FILE_NAME: &str = "last-requests.trace";
+
+/// Sends `iterations` random IOCTLs generated from `seed`, or from a seed taken
+/// from the time, on a fresh target with the in-guest test program. If the
+/// target crashes, fetches and triages the crash dump, and saves the last
+/// [`FUZZ_LAST_REQUESTS`] IOCTLs of the trace to the directory of the run under
+/// `target/runs`, which `cargo xtask replay` can send again.
+pub(crate) fn run(
+    backend: &impl Backend,
+    profile: Profile,
+    seed: Option<u64>,
+    iterations: u32,
+) -> Result<()> {
+    let seed = match seed {
+        Some(seed) => seed,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+    };
+    let test_program = test::build(profile)?;
+
+    backend.stop()?;
+    backend.prepare()?;
+    if let Some(log_path) = backend.log_path() {
+        drop(logs::archive(log_path)?);
+    }
+
+    say!("🎲 Fuzzing with seed {seed}. Pass `--seed {seed}` to repeat the run");
+    let result = fuzz(backend, profile, &test_program, seed, iterations);
+    let trace_path = match &result {
+        Result::Ok(()) => {
+            say!("{}", "✅ Fuzzing did not crash the target".green());
+            None
+        }
+        Err(err) => {
+            say!("{}", format!("❌ Fuzzing failed: {err:#}").red());
+            drop(verifier::collect_crash_dump(backend)?);
+            test::collect_trace(backend)?
+        }
+    };
+
+    backend.stop()?;
+    let run_dir = match backend.log_path() {
+        Some(log_path) => logs::archive(log_path)?
+            .and_then(|archived_path| archived_path.parent().map(Path::to_path_buf)),
+        None => None,
+    };
+    if let Some(trace_path) = trace_path {
+        let run_dir = match run_dir {
+            Some(run_dir) => run_dir,
+            None => new_run_dir()?,
+        };
+        let last_path = run_dir.join(LAST_REQUESTS_FILE_NAME);
+        let trace = fs::read_to_string(&trace_path)?;
+        fs::write(&last_path, last_requests(&trace, seed, FUZZ_LAST_REQUESTS))?;
+        say!(
+            "📼 Saved the last {FUZZ_LAST_REQUESTS} IOCTLs before the crash to {}",
+            last_path.display()
+        );
+    }
+    result
+}
+
+fn fuzz(
+    backend: &impl Backend,
+    profile: Profile,
+    test_program: &Path,
+    seed: u64,
+    iterations: u32,
+) -> Result<()> {
+    backend.start()?;
+    deploy(backend, profile)?;
+
+    let trace_path = backend.driver_dir().join(TRACE_FILE_NAME);
+    backend.delete_file(&trace_path)?;
+
+    say!("🕒 Sending {iterations} IOCTLs");
+    let iterations = iterations.to_string();
+    let seed = seed.to_string();
+    let trace_path = trace_path.to_string();
+    test::run(
+        backend,
+        test_program,
+        &[
+            "--fuzz",
+            &iterations,
+            "--seed",
+            &seed,
+            "--trace",
+            &trace_path,
+        ],
+    )
+}
+
+/// Creates a directory for a run without debug output under `target/runs`.
+fn new_run_dir() -> Result<PathBuf> {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let run_dir = logs::runs_dir().join(timestamp.to_string());
+    fs::create_dir_all(&run_dir)?;
+    Ok(run_dir)
+}
+
+/// Returns a trace file of the last `count` IOCTLs of the trace file `trace`,
+/// with the handles they were sent through and `seed` as a comment. The times
+/// are made relative to the first IOCTL kept, so that replaying it with the
+/// delays does not first wait for the IOCTLs left out.
+fn last_requests(trace: &str, seed: u64, count: usize) -> String {
+    let mut lines = trace.lines();
+    let header = lines.next().unwrap_or_default();
+    let (opens, ioctls): (Vec<_>, Vec<_>) = lines
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .partition(|line| line.starts_with("open\t"));
+    let kept = &ioctls[ioctls.len().saturating_sub(count)..];
+    let micros = |line: &str| -> Option<u64> { line.split('\t').nth(1)?.parse().ok() };
+    let start = kept.first().and_then(|line| micros(line)).unwrap_or(0);
+
+    let mut text = format!("{header}\n# seed {seed}\n");
+    for line in opens {
+        text.push_str(line);
+        text.push('\n');
+    }
+    for line in kept {
+        let mut fields: Vec<_> = line.split('\t').map(str::to_owned).collect();
+        if let Some(micros) = micros(line) {
+            fields[1] = micros.saturating_sub(start).to_string();
+        }
+        text.push_str(&fields.join("\t"));
+        text.push('\n');
+    }
+    text
+}
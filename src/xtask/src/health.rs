@@ -0,0 +1,191 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Ok, Result, bail};
+use capcom_abi::messages::LOADED;
+use colored::Colorize;
+
+use crate::{
+    Profile,
+    backend::{Backend, GuestPath},
+    config::MODULE_NAME,
+    test,
+    ui::say,
+};
+
+const SC_PATH: &str = r"C:\Windows\System32\sc.exe";
+const WEVTUTIL_PATH: &str = r"C:\Windows\System32\wevtutil.exe";
+
+/// The event logs searched for entries about the driver when it fails to load.
+/// The Service Control Manager writes failures to start to the System log, and
+/// Code Integrity writes rejected signatures to its operational log.
+const EVENT_LOGS: [&str; 2] = ["System", "Microsoft-Windows-CodeIntegrity/Operational"];
+
+/// The number of the most recent entries of each event log to search.
+const EVENT_COUNT: u32 = 50;
+
+/// How long to wait for the load message to appear in the debug output.
+const LOG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks that the driver loaded after `sc start`, whose output is
+/// `start_output`: the service is running, the device answers the probe of the
+/// in-guest test program, and the driver printed the load message. If it did
+/// not load, prints the hint for the error and the event log entries about the
+/// driver.
+pub(crate) fn check(backend: &impl Backend, profile: Profile, start_output: &str) -> Result<()> {
+    say!("🕒 Checking the driver loaded in the target");
+    let error = start_error(start_output);
+    let running = is_running(backend)?;
+    let probed = running && test::run(backend, &test::build(profile)?, &["--probe"]).is_ok();
+    if probed {
+        check_log(backend);
+        say!("{}", "✅ The driver is running".green());
+        return Ok(());
+    }
+
+    say!("{}", start_output.trim_end());
+    if let Some(error) = error {
+        say!("{}", format!("💡 {}", hint(error)).yellow());
+    } else if running {
+        say!(
+            "{}",
+            "💡 The service is running but the device did not answer. The device name may \
+             differ, e.g., with stealth naming"
+                .yellow()
+        );
+    }
+    print_events(backend)?;
+
+    match error {
+        Some(error) => bail!("the driver failed to start with error {error}"),
+        None if !running => bail!("`sc start` succeeded but the service is not running"),
+        None => bail!("the driver is running but the device did not answer"),
+    }
+}
+
+/// Returns the Win32 error `sc start` failed with, e.g., 577 of
+/// `[SC] StartService FAILED 577:`.
+pub(crate) fn start_error(output: &str) -> Option<u32> {
+    let (_, rest) = output.split_once("FAILED ")?;
+    rest.split(|c: char| !c.is_ascii_digit())
+        .next()?
+        .parse()
+        .ok()
+}
+
+/// Returns what to do about the Win32 error the driver failed to start with.
+fn hint(error: u32) -> &'static str {
+    match error {
+        // ERROR_FILE_NOT_FOUND and ERROR_PATH_NOT_FOUND
+        2 | 3 => {
+            "The driver file is not where the service points to. Check the binPath of the service"
+        }
+        // ERROR_GEN_FAILURE
+        31 => {
+            "DriverEntry failed. Decode the debug output with `cargo xtask decode` for the reason"
+        }
+        // ERROR_PROC_NOT_FOUND
+        127 => {
+            "The driver imports a function the kernel does not export. The target may be too old"
+        }
+        // ERROR_BAD_EXE_FORMAT
+        193 => "The driver is not for the architecture of the target. Pass or drop --arm64",
+        // ERROR_INVALID_IMAGE_HASH
+        577 => "The signature was rejected. Enable test signing with `bcdedit /set testsigning on`",
+        // ERROR_SERVICE_ALREADY_RUNNING
+        1056 => {
+            "An old instance of the driver is running. Stop it with `sc stop` or reboot the target"
+        }
+        // ERROR_SERVICE_DISABLED
+        1058 => "The service is disabled. Set its start type with `sc config`",
+        // ERROR_SERVICE_MARKED_FOR_DELETE
+        1072 => "The service is marked for deletion. Reboot the target to complete it",
+        // ERROR_DRIVER_BLOCKED
+        1275 => {
+            "The driver is blocked, e.g., by the vulnerable driver blocklist. Turn it off in \
+             Windows Security > Device security > Core isolation"
+        }
+        _ => "See the event log entries below",
+    }
+}
+
+/// Checks whether the service of the driver is running, as reported by
+/// `sc query`, e.g., `STATE : 4  RUNNING`.
+pub(crate) fn is_running(backend: &impl Backend) -> Result<bool> {
+    let sc = GuestPath::new(PathBuf::from(SC_PATH));
+    let output = backend.run_program_with_output(&sc, &["query", MODULE_NAME])?;
+    Ok(output
+        .lines()
+        .any(|line| line.contains("STATE") && line.contains("RUNNING")))
+}
+
+/// Waits for the load message in the debug output, if available, and warns if
+/// it does not appear. It is not an error as the debug print filter of the
+/// target may drop the message.
+fn check_log(backend: &impl Backend) {
+    let Some(log_path) = backend.log_path() else {
+        return;
+    };
+    let message = format!("{MODULE_NAME}#{LOADED}");
+    let start = Instant::now();
+    while start.elapsed() < LOG_TIMEOUT {
+        if has_line(log_path, &message) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    say!(
+        "{}",
+        format!(
+            "⚠️ {message} is not in {}. Set the IHVDRIVER value of the debug print filter of \
+             the target to 0xf to see the messages of the driver",
+            log_path.display()
+        )
+        .yellow()
+    );
+}
+
+/// Checks whether the file at `path` has a line starting with `message`, after
+/// any prefix such as a timestamp.
+fn has_line(path: &Path, message: &str) -> bool {
+    fs::read(path).is_ok_and(|bytes| {
+        String::from_utf8_lossy(&bytes).lines().any(|line| {
+            line.split_once(message)
+                .is_some_and(|(_, rest)| rest.is_empty() || rest.starts_with(' '))
+        })
+    })
+}
+
+/// Prints the recent entries of [`EVENT_LOGS`] that mention the driver.
+fn print_events(backend: &impl Backend) -> Result<()> {
+    let wevtutil = GuestPath::new(PathBuf::from(WEVTUTIL_PATH));
+    for log in EVENT_LOGS {
+        let output = backend.run_program_with_output(
+            &wevtutil,
+            &[
+                "qe",
+                log,
+                &format!("/c:{EVENT_COUNT}"),
+                "/rd:true",
+                "/f:text",
+            ],
+        )?;
+        let events: Vec<_> = output
+            .split("Event[")
+            .skip(1)
+            .filter(|event| event.to_lowercase().contains(MODULE_NAME))
+            .collect();
+        if events.is_empty() {
+            continue;
+        }
+        say!("📜 Entries about {MODULE_NAME} in the {log} log of the target:");
+        for event in events {
+            say!("Event[{}", event.trim_end());
+        }
+    }
+    Ok(())
+}
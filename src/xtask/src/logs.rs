@@ -0,0 +1,408 @@
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Ok, Result, bail};
+use clap::{Args, ValueEnum};
+use colored::Colorize;
+
+use crate::{config::LOG_PRESETS, decode::decode_line, timeline, workspace_root_dir};
+
+/// The name of the debug output in a run directory.
+const LOG_FILE_NAME: &str = "debug.log";
+
+/// Markers of a bug check in the debug output.
+const CRASH_MARKERS: [&str; 3] = ["*** Fatal System Error", "BUGCHECK", "Bugcheck"];
+
+/// The severity of a line of the debug output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub(crate) enum Level {
+    Info,
+    /// Lines with `:WARN :`.
+    Warn,
+    /// Lines with `:ERROR:` and bug checks.
+    Error,
+}
+
+impl Level {
+    pub(crate) fn of(line: &str) -> Self {
+        if line.contains(":ERROR:") || is_crash(line) {
+            Level::Error
+        } else if line.contains(":WARN :") {
+            Level::Warn
+        } else {
+            Level::Info
+        }
+    }
+}
+
+/// A named filter of the debug output, selected with `--preset`.
+#[derive(Debug)]
+pub(crate) struct LogPreset {
+    pub(crate) name: &'static str,
+    /// A line matches if it contains any of them, either as is or after the
+    /// messages of the driver are rendered. Empty matches all lines.
+    pub(crate) patterns: &'static [&'static str],
+    pub(crate) level: Level,
+}
+
+/// The options of `cargo xtask logs`.
+#[derive(Debug, Args)]
+pub(crate) struct Options {
+    /// Keep showing the debug output of the live run as it is written.
+    #[arg(long)]
+    pub(crate) follow: bool,
+
+    /// Only runs written within this time, e.g., `30m`, `2h` or `1d`.
+    #[arg(long, value_parser = parse_age)]
+    pub(crate) since: Option<Duration>,
+
+    /// Only lines of this level or higher.
+    #[arg(long, value_enum)]
+    pub(crate) level: Option<Level>,
+
+    /// Only lines matching this filter in `LOG_PRESETS` of config.rs.
+    #[arg(long)]
+    pub(crate) preset: Option<String>,
+}
+
+/// Checks whether `line` tells the target crashed.
+pub(crate) fn is_crash(line: &str) -> bool {
+    CRASH_MARKERS.iter().any(|marker| line.contains(marker))
+}
+
+/// Moves the debug output at `log_path` to a new directory under
+/// `target/runs`, named after when it was last written, with the timeline of
+/// the run, and returns the path to the moved file. Returns `None` if there is
+/// no debug output.
+pub(crate) fn archive(log_path: &Path) -> Result<Option<PathBuf>> {
+    if !log_path.exists() {
+        return Ok(None);
+    }
+    let modified = fs::metadata(log_path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs();
+    let run_dir = runs_dir().join(modified.to_string());
+    fs::create_dir_all(&run_dir)?;
+    timeline::save(&run_dir)?;
+
+    // Copy rather than rename, as the log may be on another drive.
+    let archived_path = run_dir.join(LOG_FILE_NAME);
+    let _ = fs::copy(log_path, &archived_path)?;
+    fs::remove_file(log_path)?;
+    Ok(Some(archived_path))
+}
+
+/// Shows the debug output of the archived runs and then that of the live run
+/// at `live_path`, filtered with `options`. With `follow`, keeps showing lines
+/// the live run writes.
+pub(crate) fn run(options: &Options, live_path: Option<&Path>) -> Result<()> {
+    let (patterns, preset_level) = match options.preset.as_deref() {
+        Some(name) => {
+            let Some(preset) = LOG_PRESETS.iter().find(|preset| preset.name == name) else {
+                let names: Vec<_> = LOG_PRESETS.iter().map(|preset| preset.name).collect();
+                bail!("no preset is named {name:?}. Presets in config.rs are {names:?}");
+            };
+            (preset.patterns, preset.level)
+        }
+        None => (&[][..], Level::Info),
+    };
+    let filter = Filter {
+        patterns,
+        level: options.level.unwrap_or(preset_level),
+    };
+
+    let mut paths = archived_logs(options.since)?;
+    if let Some(live_path) = live_path
+        && live_path.exists()
+        && is_recent(live_path, options.since)?
+    {
+        paths.push(live_path.to_path_buf());
+    }
+    for path in &paths {
+        if Some(path.as_path()) == live_path && options.follow {
+            break;
+        }
+        println!("📜 {}", path.display());
+        let _ = show(&mut BufReader::new(File::open(path)?), &filter)?;
+    }
+
+    if options.follow {
+        let Some(live_path) = live_path else {
+            bail!("the target has no debug output to follow. Set REMOTE_LOG_PATH in config.rs");
+        };
+        follow(live_path, &filter)?;
+    }
+    Ok(())
+}
+
+/// Which lines of the debug output are shown.
+#[derive(Debug)]
+struct Filter {
+    patterns: &'static [&'static str],
+    level: Level,
+}
+
+impl Filter {
+    fn matches(&self, line: &str, decoded: &str) -> bool {
+        Level::of(line) >= self.level
+            && (self.patterns.is_empty()
+                || self
+                    .patterns
+                    .iter()
+                    .any(|pattern| line.contains(pattern) || decoded.contains(pattern)))
+    }
+}
+
+pub(crate) fn runs_dir() -> PathBuf {
+    workspace_root_dir().join("target").join("runs")
+}
+
+/// Returns the paths to the debug output of the archived runs written within
+/// `since`, from the oldest.
+fn archived_logs(since: Option<Duration>) -> Result<Vec<PathBuf>> {
+    let runs_dir = runs_dir();
+    if !runs_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut runs = Vec::new();
+    for entry in fs::read_dir(&runs_dir)? {
+        let path = entry?.path().join(LOG_FILE_NAME);
+        let Some(timestamp) = path
+            .parent()
+            .and_then(Path::file_name)
+            .and_then(|name| name.to_str()?.parse::<u64>().ok())
+        else {
+            continue;
+        };
+        if path.exists() && is_recent(&path, since)? {
+            runs.push((timestamp, path));
+        }
+    }
+    runs.sort();
+    Ok(runs.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Checks whether the file at `path` was written within `since`.
+fn is_recent(path: &Path, since: Option<Duration>) -> Result<bool> {
+    let Some(since) = since else {
+        return Ok(true);
+    };
+    let age = SystemTime::now()
+        .duration_since(fs::metadata(path)?.modified()?)
+        .unwrap_or_default();
+    Ok(age <= since)
+}
+
+/// Shows the lines of `reader` that match `filter` until the end, and returns
+/// the number of bytes read.
+fn show(reader: &mut impl BufRead, filter: &Filter) -> Result<u64> {
+    let mut total = 0;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let bytes_read = reader.read_line(&mut line)?;
+        if bytes_read == 0 {
+            return Ok(total);
+        }
+        total += bytes_read as u64;
+        show_line(line.trim_end(), filter);
+    }
+}
+
+fn show_line(line: &str, filter: &Filter) {
+    let decoded = decode_line(line).unwrap_or_else(|| line.to_owned());
+    if !filter.matches(line, &decoded) {
+        return;
+    }
+    match Level::of(line) {
+        Level::Error => println!("{}", decoded.red()),
+        Level::Warn => println!("{}", decoded.yellow()),
+        Level::Info => println!("{decoded}"),
+    }
+}
+
+/// Shows the lines of the live run at `live_path` until CTRL+C is pressed.
+/// When a new run replaces the file, follows the new one.
+fn follow(live_path: &Path, filter: &Filter) -> Result<()> {
+    println!("📜 {} (press CTRL+C to stop)", live_path.display());
+    loop {
+        while !live_path.exists() {
+            thread::sleep(Duration::from_millis(100));
+        }
+        let mut reader = BufReader::new(
+            File::open(live_path)
+                .with_context(|| format!("failed to open {}", live_path.display()))?,
+        );
+        let mut position = 0;
+        loop {
+            position += show(&mut reader, filter)?;
+            thread::sleep(Duration::from_millis(100));
+
+            // The file is archived or truncated when the next run starts.
+            let Result::Ok(metadata) = fs::metadata(live_path) else {
+                break;
+            };
+            if metadata.len() < position {
+                break;
+            }
+        }
+        println!("📜 A new run started");
+    }
+}
+
+/// Parses the age of `--since`, e.g., `90s`, `30m`, `2h` or `1d`.
+pub(crate) fn parse_age(text: &str) -> Result<Duration> {
+    let split = text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("{text:?} does not start with a number"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("{text:?} does not end with s, m, h or d"),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    /// Returns the filter of the preset `name` in config.rs.
+    fn preset(name: &str) -> Filter {
+        let preset = LOG_PRESETS
+            .iter()
+            .find(|preset| preset.name == name)
+            .unwrap();
+        Filter {
+            patterns: preset.patterns,
+            level: preset.level,
+        }
+    }
+
+    /// Checks whether `filter` shows `line`, decoded as `logs` would.
+    fn shows(filter: &Filter, line: &str) -> bool {
+        let decoded = decode_line(line).unwrap_or_else(|| line.to_owned());
+        filter.matches(line, &decoded)
+    }
+
+    #[test]
+    fn classifies_levels() {
+        const CASES: &[(&str, Level)] = &[
+            ("12.345 capcom#7 64", Level::Info),
+            (
+                "12.345 capcom:WARN : the offsets are not known",
+                Level::Warn,
+            ),
+            ("12.345 capcom:ERROR: could not map the page", Level::Error),
+            ("*** Fatal System Error: 0x0000003b", Level::Error),
+            ("BUGCHECK_CODE:  3b", Level::Error),
+            ("Bugcheck Analysis", Level::Error),
+            ("warn and error in lowercase", Level::Info),
+        ];
+        for &(line, level) in CASES {
+            assert_eq!(Level::of(line), level, "{line}");
+        }
+        assert!(Level::Info < Level::Warn && Level::Warn < Level::Error);
+    }
+
+    #[test]
+    fn filters_by_level() {
+        let filter = Filter {
+            patterns: &[],
+            level: Level::Warn,
+        };
+        assert!(!shows(&filter, "capcom#7 64"));
+        assert!(shows(&filter, "capcom:WARN : slow"));
+        assert!(shows(&filter, "capcom:ERROR: failed"));
+    }
+
+    #[test]
+    fn filters_by_patterns_before_and_after_decoding() {
+        let filter = Filter {
+            patterns: &["Payload rate", "DriverEntry"],
+            level: Level::Info,
+        };
+        // `capcom#7` is rendered as `Payload rate: 100/s`.
+        assert!(shows(&filter, "1.0 capcom#7 64"));
+        assert!(shows(&filter, "1.0 DriverEntry returned 0"));
+        assert!(!shows(&filter, "1.0 capcom#8 64"));
+        assert!(!shows(&filter, "1.0 payload rate in lowercase"));
+    }
+
+    #[test]
+    fn filters_with_presets() {
+        let driver = preset("driver");
+        assert!(shows(&driver, "1.0 capcom#7 64"));
+        assert!(!shows(&driver, "1.0 Loading symbols for ntoskrnl.exe"));
+
+        let crash = preset("crash");
+        assert!(shows(&crash, "*** Fatal System Error: 0x0000003b"));
+        assert!(shows(&crash, "Driver Verifier detected a violation"));
+        assert!(!shows(&crash, "1.0 capcom#7 64"));
+
+        let errors = preset("errors");
+        assert!(shows(&errors, "1.0 capcom:ERROR: failed"));
+        assert!(shows(&errors, "BUGCHECK_CODE:  3b"));
+        assert!(!shows(&errors, "1.0 capcom:WARN : slow"));
+    }
+
+    #[test]
+    fn refuses_unknown_presets() {
+        let options = Options {
+            follow: false,
+            since: None,
+            level: None,
+            preset: Some("nope".to_owned()),
+        };
+        let err = run(&options, None).unwrap_err();
+        assert!(
+            err.to_string().contains("no preset is named \"nope\""),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn counts_bytes_of_shown_and_hidden_lines() {
+        let filter = Filter {
+            patterns: &[],
+            level: Level::Error,
+        };
+        let text = "capcom#7 64\r\ncapcom:ERROR: failed\nno newline";
+        let mut reader = Cursor::new(text);
+        assert_eq!(show(&mut reader, &filter).unwrap(), text.len() as u64);
+        assert_eq!(show(&mut reader, &filter).unwrap(), 0);
+    }
+
+    #[test]
+    fn parses_ages() {
+        const CASES: &[(&str, u64)] = &[
+            ("0s", 0),
+            ("90s", 90),
+            ("30m", 30 * 60),
+            ("2h", 2 * 60 * 60),
+            ("1d", 24 * 60 * 60),
+        ];
+        for &(text, seconds) in CASES {
+            assert_eq!(
+                parse_age(text).unwrap(),
+                Duration::from_secs(seconds),
+                "{text}"
+            );
+        }
+        for text in ["", "m", "30", "30x", "-1h", "1.5h", "30 m"] {
+            assert!(parse_age(text).is_err(), "{text}");
+        }
+    }
+}
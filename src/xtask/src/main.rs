@@ -4,7 +4,34 @@
 //! cargo xtask
 //! ```
 
+mod agent;
+mod backend;
+mod clean;
+mod compare;
 mod config;
+mod control;
+#[cfg(feature = "tui")]
+mod dashboard;
+mod decode;
+mod fuzz;
+mod health;
+mod logs;
+mod matrix;
+mod package;
+mod preflight;
+mod remote;
+mod replay;
+mod report;
+mod retry;
+mod scan;
+mod scenario;
+mod size;
+mod symbols;
+mod test;
+mod timeline;
+mod timeout;
+mod ui;
+mod verifier;
 mod vmware;
 
 use std::{
@@ -15,8 +42,11 @@ use std::{
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 
+use crate::backend::Backend;
+
 #[derive(Parser)]
 #[command(author, about, long_about = None)]
+#[expect(clippy::struct_excessive_bools)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
@@ -24,18 +54,226 @@ struct Cli {
     /// Build the driver with a specified profile.
     #[arg(short, long)]
     release: bool,
+
+    /// Use the ARM64 build of the driver and the ARM64 VM.
+    #[arg(long)]
+    arm64: bool,
+
+    /// Show the progress and debug output of `vmware`, `remote`, `matrix`,
+    /// `scenario`, `replay` and `fuzz` on a dashboard. Requires the `tui`
+    /// feature.
+    #[arg(long, global = true)]
+    tui: bool,
+
+    /// Deploy the driver even if it has no built-in offsets for the Windows
+    /// build of the target.
+    #[arg(long, global = true)]
+    allow_unsupported_build: bool,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     /// Start a VMware VM
-    Vmware,
+    Vmware {
+        /// Start the driver under Driver Verifier and run the in-guest tests.
+        #[arg(long)]
+        verifier: bool,
+    },
+    /// Deploy the driver to a remote physical machine
+    Remote {
+        /// Start the driver under Driver Verifier and run the in-guest tests.
+        #[arg(long)]
+        verifier: bool,
+    },
+    /// Run the in-guest tests on a VMware VM with and without HVCI
+    Matrix,
+    /// Run an end-to-end scenario on a VMware VM or a remote physical machine
+    Scenario {
+        /// The scenario to run.
+        #[arg(value_enum)]
+        scenario: scenario::Scenario,
+
+        /// Run on the remote physical machine instead of the VMware VM.
+        #[arg(long)]
+        remote: bool,
+    },
+    /// Send the IOCTLs of a trace file on a fresh VMware VM or remote physical machine to reproduce a crash
+    Replay {
+        /// The path to the trace file, e.g., one under target/traces.
+        trace: PathBuf,
+
+        /// Send the IOCTLs without the delays they were recorded with.
+        #[arg(long)]
+        no_delay: bool,
+
+        /// Run on the remote physical machine instead of the VMware VM.
+        #[arg(long)]
+        remote: bool,
+    },
+    /// Send random IOCTLs generated from a seed on a fresh VMware VM or remote physical machine, and save the last ones before a crash
+    Fuzz {
+        /// The seed to generate the IOCTLs from, e.g., that of a run to repeat.
+        /// A seed is taken from the time if omitted.
+        #[arg(long)]
+        seed: Option<u64>,
+
+        /// The number of IOCTLs to send.
+        #[arg(long, default_value_t = config::FUZZ_ITERATIONS)]
+        iterations: u32,
+
+        /// Run on the remote physical machine instead of the VMware VM.
+        #[arg(long)]
+        remote: bool,
+    },
+    /// Report section sizes and imports of the driver, and changes since the last run
+    Size,
+    /// Compare the driver with the original Capcom.sys
+    Compare {
+        /// The path to the original Capcom.sys.
+        #[arg(long)]
+        original: PathBuf,
+    },
+    /// Render the messages the driver printed in debug output
+    Decode {
+        /// The path to the debug output, e.g., a log of WinDbg or DebugView.
+        /// Read from stdin if omitted.
+        input: Option<PathBuf>,
+    },
+    /// Archive the driver, its symbols and the in-guest test program with a manifest of hashes under target/dist
+    Package,
+    /// Show the debug output of past runs under target/runs and the live run, with the messages of the driver rendered
+    Logs {
+        #[command(flatten)]
+        options: logs::Options,
+
+        /// Use the debug output of the remote physical machine as that of the live run.
+        #[arg(long)]
+        remote: bool,
+    },
+    /// Show the progress of xtask, the debug output and the messages of the driver of a run under target/runs in one timeline
+    Timeline {
+        /// The directory of the run, e.g., target/runs/1700000000. The latest
+        /// run if omitted.
+        run: Option<PathBuf>,
+
+        /// Also write the timeline to this path as JSON of the trace event
+        /// format of Chrome, e.g., for chrome://tracing or Perfetto.
+        #[arg(long)]
+        chrome: Option<PathBuf>,
+    },
+    /// Scan the driver with YARA rules, and with --guest its image loaded in a VMware VM or remote physical machine
+    Scan {
+        /// The directory of the rules, with a .yar or .yara file each.
+        #[arg(long)]
+        rules: PathBuf,
+
+        /// Also deploy the driver and scan its image in kernel memory.
+        #[arg(long)]
+        guest: bool,
+
+        /// Deploy to the remote physical machine instead of the VMware VM.
+        #[arg(long)]
+        remote: bool,
+    },
+    /// Remove what xtask placed in a running target and verify it is clean, e.g., before taking a new snapshot
+    CleanGuest {
+        /// Clean the remote physical machine instead of the VMware VM.
+        #[arg(long)]
+        remote: bool,
+    },
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
+    timeout::start_run();
+    if cli.allow_unsupported_build {
+        preflight::allow_unsupported_build();
+    }
+    let arch = if cli.arm64 { Arch::Arm64 } else { Arch::X64 };
+    let profile = Profile::new(cli.release, arch);
     match cli.command {
-        Commands::Vmware => vmware::run(Profile::from(cli.release)),
+        Commands::Vmware { verifier } => ui::run(cli.tui, move || {
+            backend::run(vmware::Vmware::new(arch), profile, verifier)
+        }),
+        Commands::Remote { verifier } => ui::run(cli.tui, move || {
+            backend::run(remote::Remote::new(), profile, verifier)
+        }),
+        Commands::Matrix => ui::run(cli.tui, move || {
+            matrix::run(&vmware::Vmware::new(arch), profile)
+        }),
+        Commands::Scenario {
+            scenario,
+            remote: false,
+        } => ui::run(cli.tui, move || {
+            scenario::run(&vmware::Vmware::new(arch), profile, scenario)
+        }),
+        Commands::Scenario {
+            scenario,
+            remote: true,
+        } => ui::run(cli.tui, move || {
+            scenario::run(&remote::Remote::new(), profile, scenario)
+        }),
+        Commands::Replay {
+            trace,
+            no_delay,
+            remote: false,
+        } => ui::run(cli.tui, move || {
+            replay::run(&vmware::Vmware::new(arch), profile, &trace, !no_delay)
+        }),
+        Commands::Replay {
+            trace,
+            no_delay,
+            remote: true,
+        } => ui::run(cli.tui, move || {
+            replay::run(&remote::Remote::new(), profile, &trace, !no_delay)
+        }),
+        Commands::Fuzz {
+            seed,
+            iterations,
+            remote: false,
+        } => ui::run(cli.tui, move || {
+            fuzz::run(&vmware::Vmware::new(arch), profile, seed, iterations)
+        }),
+        Commands::Fuzz {
+            seed,
+            iterations,
+            remote: true,
+        } => ui::run(cli.tui, move || {
+            fuzz::run(&remote::Remote::new(), profile, seed, iterations)
+        }),
+        Commands::Size => size::run(profile),
+        Commands::Compare { original } => compare::run(&original, profile),
+        Commands::Decode { input } => decode::run(input.as_deref()),
+        Commands::Package => package::run(profile),
+        Commands::Logs { options, remote } => {
+            if remote {
+                logs::run(&options, remote::Remote::new().log_path())
+            } else {
+                logs::run(&options, vmware::Vmware::new(arch).log_path())
+            }
+        }
+        Commands::Timeline { run, chrome } => timeline::run(run.as_deref(), chrome.as_deref()),
+        Commands::Scan {
+            rules,
+            guest: false,
+            remote: false,
+        } => scan::run(&rules, profile),
+        Commands::Scan {
+            rules,
+            remote: false,
+            ..
+        } => ui::run(cli.tui, move || {
+            scan::run_in_target(&vmware::Vmware::new(arch), &rules, profile)
+        }),
+        Commands::Scan {
+            rules,
+            remote: true,
+            ..
+        } => ui::run(cli.tui, move || {
+            scan::run_in_target(&remote::Remote::new(), &rules, profile)
+        }),
+        Commands::CleanGuest { remote: false } => clean::run(&vmware::Vmware::new(arch)),
+        Commands::CleanGuest { remote: true } => clean::run(&remote::Remote::new()),
     }
 }
 
@@ -50,23 +288,62 @@ fn workspace_root_dir() -> PathBuf {
     fs::canonicalize(root_dir).unwrap()
 }
 
+/// The configuration the driver and in-guest programs are built with.
 #[derive(Copy, Clone, Debug)]
-enum Profile {
-    Dev,
-    Release,
+struct Profile {
+    release: bool,
+    arch: Arch,
 }
 
-impl From<bool> for Profile {
-    fn from(release: bool) -> Self {
-        if release { Self::Release } else { Self::Dev }
+impl Profile {
+    fn new(release: bool, arch: Arch) -> Self {
+        Self { release, arch }
+    }
+
+    /// Returns the directory cargo places build artifacts in.
+    fn target_dir(self) -> PathBuf {
+        let target_dir = workspace_root_dir().join("target");
+        match self.arch.target_triple() {
+            Some(triple) => target_dir.join(triple),
+            None => target_dir,
+        }
+        .join(self.to_string())
     }
 }
 
 impl fmt::Display for Profile {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.release {
+            write!(f, "release")
+        } else {
+            write!(f, "debug")
+        }
+    }
+}
+
+/// The architecture of the target.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Arch {
+    X64,
+    Arm64,
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arch::X64 => write!(f, "x64"),
+            Arch::Arm64 => write!(f, "arm64"),
+        }
+    }
+}
+
+impl Arch {
+    /// Returns the target triple to pass to cargo, or `None` to build for the
+    /// host.
+    fn target_triple(self) -> Option<&'static str> {
         match self {
-            Profile::Dev => write!(f, "debug"),
-            Profile::Release => write!(f, "release"),
+            Arch::X64 => None,
+            Arch::Arm64 => Some("aarch64-pc-windows-msvc"),
         }
     }
 }
@@ -0,0 +1,95 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Ok, Result, ensure};
+use colored::Colorize;
+
+use crate::{
+    Profile,
+    backend::{Backend, GuestPath, deploy},
+    logs,
+    report::{self, Run},
+    test, timeline,
+    ui::say,
+};
+
+const REG_PATH: &str = r"C:\Windows\System32\reg.exe";
+const DEVICE_GUARD_KEY: &str = r"HKLM\SYSTEM\CurrentControlSet\Control\DeviceGuard";
+
+/// Runs the in-guest tests with HVCI disabled and enabled. The VM must be
+/// configured to support virtualization-based security for the latter.
+pub(crate) fn run(backend: &impl Backend, profile: Profile) -> Result<()> {
+    let test_program = test::build(profile)?;
+
+    backend.stop()?;
+    backend.prepare()?;
+    timeline::follow(backend.log_path());
+
+    let mut runs = Vec::new();
+    let mut failures = Vec::new();
+    for hvci in [false, true] {
+        let name = if hvci {
+            "HVCI enabled"
+        } else {
+            "HVCI disabled"
+        };
+        say!("🕒 Testing with {name}");
+        let mut run = match test_configuration(backend, profile, &test_program, hvci, name) {
+            Result::Ok(run) => run,
+            Err(err) => Run::failed(name, &err),
+        };
+        if run.passed() {
+            say!("{}", format!("✅ Passed with {name}").green());
+        } else {
+            say!("{}", format!("❌ Failed with {name}").red());
+            failures.push(name);
+        }
+        backend.stop()?;
+        if let Some(log_path) = backend.log_path()
+            && let Some(archived_path) = logs::archive(log_path)?
+        {
+            run.add_artifact(archived_path);
+        }
+        runs.push(run);
+    }
+
+    report::write("matrix", &runs)?;
+    ensure!(failures.is_empty(), "tests failed with {failures:?}");
+    Ok(())
+}
+
+fn test_configuration(
+    backend: &impl Backend,
+    profile: Profile,
+    test_program: &Path,
+    hvci: bool,
+    name: &str,
+) -> Result<Run> {
+    backend.start()?;
+
+    // Changes to the HVCI configuration take effect after reboot.
+    say!("🕒 Configuring HVCI in the target");
+    set_hvci(backend, hvci)?;
+    backend.reboot()?;
+
+    deploy(backend, profile)?;
+
+    let args: &[&str] = if hvci { &["--hvci"] } else { &[] };
+    test::run_with_report(backend, test_program, args, name)
+}
+
+/// Enables or disables HVCI (and VBS, which it depends on) in the target.
+fn set_hvci(backend: &impl Backend, enabled: bool) -> Result<()> {
+    let reg = GuestPath::new(PathBuf::from(REG_PATH));
+    let data = if enabled { "1" } else { "0" };
+    let hvci_key = DEVICE_GUARD_KEY.to_owned() + r"\Scenarios\HypervisorEnforcedCodeIntegrity";
+    for (key, value) in [
+        (DEVICE_GUARD_KEY, "EnableVirtualizationBasedSecurity"),
+        (hvci_key.as_str(), "Enabled"),
+    ] {
+        backend.run_program(
+            &reg,
+            &["add", key, "/v", value, "/t", "REG_DWORD", "/d", data, "/f"],
+        )?;
+    }
+    Ok(())
+}
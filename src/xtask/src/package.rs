@@ -0,0 +1,108 @@
+use std::{fmt::Write, fs, path::Path, process::Command};
+
+use anyhow::{Context, Ok, Result, ensure};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    Profile, agent, backend::driver_path, config::MODULE_NAME, symbols, test, workspace_root_dir,
+};
+
+/// The name of the file listing the SHA-256 hashes of the packaged files.
+const MANIFEST_NAME: &str = "manifest.txt";
+
+/// Gathers the driver, its PDB, INF and CAT files if generated, and the
+/// in-guest test program with a manifest of their hashes, and archives them as
+/// `target/dist/<name>.zip`, where the name has the version, the commit, the
+/// architecture and the profile. The driver must be built beforehand.
+pub(crate) fn run(profile: Profile) -> Result<()> {
+    let driver_path = driver_path(profile);
+    ensure!(
+        driver_path.exists(),
+        "{} is not found. Build the driver with `cargo make` first",
+        driver_path.display()
+    );
+    let pdb_path = symbols::find_pdb(profile, &(MODULE_NAME.to_owned() + ".pdb"))?;
+    let _ = symbols::verify_pdb(&driver_path, &pdb_path)?;
+
+    let mut files = vec![driver_path.clone(), pdb_path];
+    files.extend(
+        ["inf", "cat"]
+            .iter()
+            .map(|extension| driver_path.with_extension(extension))
+            .filter(|path| path.exists()),
+    );
+    files.push(test::build(profile)?);
+    files.push(agent::build(profile)?);
+
+    let name = format!(
+        "{MODULE_NAME}-{}-{}-{}-{profile}",
+        env!("CARGO_PKG_VERSION"),
+        commit()?,
+        profile.arch
+    );
+    let dist_dir = workspace_root_dir().join("target").join("dist");
+    let package_dir = dist_dir.join(&name);
+    if package_dir.exists() {
+        fs::remove_dir_all(&package_dir)?;
+    }
+    fs::create_dir_all(&package_dir)?;
+
+    println!("🕒 Copying files to {}", package_dir.display());
+    let mut manifest = String::new();
+    for path in &files {
+        let file_name = path
+            .file_name()
+            .context("the path should have a file name")?;
+        let _ = fs::copy(path, package_dir.join(file_name))?;
+        let hash = Sha256::digest(fs::read(path)?);
+        let _ = writeln!(manifest, "{hash:x}  {}", file_name.display());
+    }
+    fs::write(package_dir.join(MANIFEST_NAME), manifest)?;
+
+    let archive_path = dist_dir.join(format!("{name}.zip"));
+    archive(&dist_dir, &name, &archive_path)?;
+    println!("✅ Packaged {}", archive_path.display());
+    Ok(())
+}
+
+/// Returns the abbreviated hash of the commit checked out, suffixed with
+/// `-dirty` if the working tree has changes not committed.
+fn commit() -> Result<String> {
+    let hash = git(&["rev-parse", "--short", "HEAD"])?;
+    let dirty = !git(&["status", "--porcelain"])?.is_empty();
+    Ok(if dirty { hash + "-dirty" } else { hash })
+}
+
+/// Runs git with `args` in the workspace and returns the trimmed output.
+fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .current_dir(workspace_root_dir())
+        .output()?;
+    ensure!(
+        output.status.success(),
+        "git failed with {:?}",
+        output.status
+    );
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+/// Archives the directory `name` in `dir` as the ZIP file `archive_path` with
+/// `tar`, which Windows ships and creates ZIP files with `-a`.
+fn archive(dir: &Path, name: &str, archive_path: &Path) -> Result<()> {
+    if archive_path.exists() {
+        fs::remove_file(archive_path)?;
+    }
+    println!("🕒 Archiving {name}");
+    let status = Command::new("tar")
+        .arg("-a")
+        .arg("-c")
+        .arg("-f")
+        .arg(archive_path)
+        .arg("-C")
+        .arg(dir)
+        .arg(name)
+        .status()?;
+    ensure!(status.success(), "tar failed with {status:?}");
+    Ok(())
+}
@@ -0,0 +1,192 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::{Ok, Result, bail, ensure};
+use colored::Colorize;
+
+use crate::{
+    Arch,
+    backend::{Backend, GuestPath},
+    ui::{self, say},
+    workspace_root_dir,
+};
+
+const BCDEDIT_PATH: &str = r"C:\Windows\System32\bcdedit.exe";
+const REG_PATH: &str = r"C:\Windows\System32\reg.exe";
+const CURRENT_VERSION_KEY: &str = r"HKLM\SOFTWARE\Microsoft\Windows NT\CurrentVersion";
+
+/// Whether to deploy to builds the driver has no built-in offsets for, set with
+/// `--allow-unsupported-build`.
+static ALLOW_UNSUPPORTED_BUILD: AtomicBool = AtomicBool::new(false);
+
+/// Makes [`check_build`] warn instead of failing for builds the driver has no
+/// built-in offsets for.
+pub(crate) fn allow_unsupported_build() {
+    ALLOW_UNSUPPORTED_BUILD.store(true, Ordering::Relaxed);
+}
+
+/// Checks that the target is configured to load test-signed drivers. If not,
+/// offers to enable test signing and reboot the target, unless Secure Boot
+/// prevents it.
+///
+/// Without this, starting the driver fails with error 577 (ERROR_INVALID_IMAGE_HASH).
+pub(crate) fn check_test_signing(backend: &impl Backend) -> Result<()> {
+    let bcdedit = GuestPath::new(PathBuf::from(BCDEDIT_PATH));
+
+    if is_test_signing_enabled(backend)? {
+        return Ok(());
+    }
+
+    if is_secure_boot_enabled(backend)? {
+        bail!(
+            "test signing is disabled and cannot be enabled as Secure Boot is enabled \
+             in the target. Disable Secure Boot in the firmware settings (for VMware, \
+             in the VM settings), then run `bcdedit /set testsigning on` in the target"
+        );
+    }
+
+    // The dashboard owns the terminal and cannot ask.
+    ensure!(
+        !ui::is_tui(),
+        "test signing is disabled in the target. Run without --tui to enable it, or run \
+         `bcdedit /set testsigning on` in the target and reboot it"
+    );
+
+    print!("❓ Test signing is disabled in the target. Enable it and reboot the target? [y/N] ");
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    let _ = io::stdin().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        bail!(
+            "test signing is disabled in the target. The driver would fail to start with \
+             error 577. Run `bcdedit /set testsigning on` in the target and reboot it"
+        );
+    }
+
+    backend.run_program(&bcdedit, &["/set", "testsigning", "on"])?;
+    backend.reboot()
+}
+
+/// Checks whether the boot configuration of the target enables test signing,
+/// or disables integrity checks altogether.
+pub(crate) fn is_test_signing_enabled(backend: &impl Backend) -> Result<bool> {
+    let bcdedit = GuestPath::new(PathBuf::from(BCDEDIT_PATH));
+
+    say!("🕒 Checking the boot configuration of the target");
+    let output = backend.run_program_with_output(&bcdedit, &["/enum", "{current}"])?;
+    let enabled = |option: &str| {
+        output.lines().any(|line| {
+            let mut words = line.split_whitespace();
+            words.next() == Some(option) && words.next() == Some("Yes")
+        })
+    };
+    Ok(enabled("testsigning") || enabled("nointegritychecks"))
+}
+
+/// Checks that the driver has built-in offsets for the build of Windows in the
+/// target, as features that access undocumented kernel structures fail without
+/// them, and offsets set wrongly with `IOCTL_SET_OFFSETS` cause bug checks.
+/// Prerelease builds, e.g., of the Insider Program, fail the check even when
+/// the build number is known, as the structures may change within a build.
+///
+/// Fails if unsupported, unless [`allow_unsupported_build`] was called, in
+/// which case it only warns.
+pub(crate) fn check_build(backend: &impl Backend, arch: Arch) -> Result<()> {
+    // The built-in offsets are for x64 only.
+    if arch != Arch::X64 {
+        return Ok(());
+    }
+
+    say!("🕒 Checking the Windows build of the target");
+    let reg = GuestPath::new(PathBuf::from(REG_PATH));
+    let output = backend.run_program_with_output(&reg, &["query", CURRENT_VERSION_KEY])?;
+    let values = registry_values(&output);
+    let value = |name: &str| values.get(name).copied().unwrap_or("unknown");
+    let Some(build) = values
+        .get("CurrentBuildNumber")
+        .and_then(|build| build.parse::<u32>().ok())
+    else {
+        bail!(
+            "failed to query the build number of the target: {}",
+            output.trim()
+        );
+    };
+    let prerelease = value("BuildLabEx").contains("prerelease");
+
+    let supported = supported_builds()?;
+    if supported.contains(&build) && !prerelease {
+        return Ok(());
+    }
+
+    let description = format!(
+        "build {build}.{} ({} {})",
+        u32::from_str_radix(value("UBR").trim_start_matches("0x"), 16).unwrap_or_default(),
+        value("EditionID"),
+        value("BuildLabEx")
+    );
+    let reason = if prerelease {
+        "is a prerelease build".to_owned()
+    } else if supported.last().is_some_and(|&newest| build > newest) {
+        "is newer than any build in capcom/offsets.csv".to_owned()
+    } else {
+        "is not in capcom/offsets.csv".to_owned()
+    };
+    let message = format!(
+        "the target runs {description}, which {reason}. Features that access kernel \
+         structures will fail with STATUS_NOT_SUPPORTED, and setting wrong offsets with \
+         IOCTL_SET_OFFSETS causes bug checks"
+    );
+    if ALLOW_UNSUPPORTED_BUILD.load(Ordering::Relaxed) {
+        say!("{}", format!("⚠️ {message}").yellow());
+        return Ok(());
+    }
+    bail!("{message}. Pass --allow-unsupported-build to deploy anyway")
+}
+
+/// Returns the build numbers in capcom/offsets.csv in ascending order.
+fn supported_builds() -> Result<Vec<u32>> {
+    let csv_path = workspace_root_dir().join("capcom").join("offsets.csv");
+    let csv = fs::read_to_string(csv_path)?;
+    let mut builds: Vec<u32> = csv
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split(',').next()?.trim().parse().ok())
+        .collect();
+    builds.sort_unstable();
+    Ok(builds)
+}
+
+/// Parses the output of `reg query` into a map from value names to the data.
+fn registry_values(output: &str) -> HashMap<&str, &str> {
+    // Each value is listed as `    <name>    <type>    <data>`.
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.trim().splitn(3, "    ");
+            let name = fields.next()?;
+            let _type = fields.next()?;
+            Some((name, fields.next()?.trim()))
+        })
+        .collect()
+}
+
+fn is_secure_boot_enabled(backend: &impl Backend) -> Result<bool> {
+    let reg = GuestPath::new(PathBuf::from(REG_PATH));
+    let output = backend.run_program_with_output(
+        &reg,
+        &[
+            "query",
+            r"HKLM\SYSTEM\CurrentControlSet\Control\SecureBoot\State",
+            "/v",
+            "UEFISecureBootEnabled",
+        ],
+    )?;
+    Ok(output
+        .lines()
+        .any(|line| line.contains("UEFISecureBootEnabled") && line.trim_end().ends_with("0x1")))
+}
@@ -0,0 +1,289 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Ok, Result, bail, ensure};
+
+use crate::{
+    backend::{Backend, GuestPath},
+    config::{
+        COMMAND_TIMEOUT, COPY_TIMEOUT, MODULE_NAME, REMOTE_BOOT_TIMEOUT, REMOTE_COPY, REMOTE_HOST,
+        REMOTE_LOG_PATH, REMOTE_REBOOT, REMOTE_USER,
+    },
+    retry, timeout,
+    ui::{self, say},
+};
+
+/// A physical machine reachable over SSH. Since there is no snapshot to revert
+/// to, the machine is rebooted for every run instead.
+#[derive(Clone, Debug)]
+pub(crate) struct Remote {
+    host: String,
+    user: String,
+    copy: CopyMethod,
+    reboot: RebootMethod,
+}
+
+/// How files are copied to the remote machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CopyMethod {
+    /// Copy with `scp`.
+    Scp,
+    /// Copy through the administrative share (`\\host\C$`).
+    #[expect(dead_code)]
+    Smb,
+}
+
+/// How the remote machine is rebooted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RebootMethod {
+    /// Ask Windows to reboot with `shutdown /r`.
+    Os,
+    /// Power-cycle the machine through its BMC with `ipmitool`.
+    #[expect(dead_code)]
+    Ipmi {
+        bmc: &'static str,
+        user: &'static str,
+        password: &'static str,
+    },
+    /// Run a host command that power-cycles the machine, e.g., a script
+    /// toggling a smart plug.
+    #[expect(dead_code)]
+    Command(&'static [&'static str]),
+}
+
+impl Remote {
+    pub(crate) fn new() -> Self {
+        Self {
+            host: REMOTE_HOST.to_owned(),
+            user: REMOTE_USER.to_owned(),
+            copy: REMOTE_COPY,
+            reboot: REMOTE_REBOOT,
+        }
+    }
+
+    fn destination(&self) -> String {
+        format!("{}@{}", self.user, self.host)
+    }
+
+    fn ssh(&self, command: &str) -> Command {
+        let mut ssh = Command::new("ssh");
+        let _ = ssh.args([
+            "-o",
+            "BatchMode=yes",
+            "-o",
+            "ConnectTimeout=5",
+            &self.destination(),
+            command,
+        ]);
+        ssh
+    }
+
+    fn power_cycle(&self) -> Result<()> {
+        match self.reboot {
+            RebootMethod::Os => {
+                // The connection may be dropped before ssh returns, so do not
+                // check the exit status.
+                let _unused = self.ssh("shutdown /r /f /t 0").output()?;
+            }
+            RebootMethod::Ipmi {
+                bmc,
+                user,
+                password,
+            } => {
+                let mut ipmitool = Command::new("ipmitool");
+                let _ = ipmitool.args([
+                    "-I", "lanplus", "-H", bmc, "-U", user, "-P", password, "chassis", "power",
+                    "cycle",
+                ]);
+                let status = ui::status(&mut ipmitool)?;
+                ensure!(status.success(), "ipmitool failed with {status:?}");
+            }
+            RebootMethod::Command(command) => {
+                let Some((program, args)) = command.split_first() else {
+                    bail!("the reboot command is empty");
+                };
+                let status = ui::status(Command::new(program).args(args))?;
+                ensure!(status.success(), "{command:?} failed with {status:?}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until the machine goes down and accepts SSH connections again.
+    fn wait_for_boot(&self) -> Result<()> {
+        let start = Instant::now();
+
+        // Give the machine time to go down so that we do not talk to the OS
+        // being shut down.
+        thread::sleep(Duration::from_secs(10));
+        while !self.ssh("exit").output()?.status.success() {
+            ensure!(
+                start.elapsed() < REMOTE_BOOT_TIMEOUT,
+                "{} did not come back within {REMOTE_BOOT_TIMEOUT:?}",
+                self.host
+            );
+            thread::sleep(Duration::from_secs(5));
+        }
+        Ok(())
+    }
+
+    /// Fails if ssh exited with `status` as it could not connect to the
+    /// machine, e.g., as the SSH server is not up yet. ssh exits with 255 on
+    /// its own errors, and with the exit code of the command otherwise.
+    fn check_connected(&self, status: ExitStatus) -> Result<()> {
+        ensure!(
+            status.code() != Some(255),
+            "ssh could not connect to {}",
+            self.host
+        );
+        Ok(())
+    }
+
+    /// Converts a path on the remote machine to the form `scp` accepts, e.g.,
+    /// `C:\foo` to `user@host:C:/foo`.
+    fn scp_path(&self, path: &GuestPath) -> String {
+        format!(
+            "{}:{}",
+            self.destination(),
+            path.to_string().replace('\\', "/")
+        )
+    }
+
+    /// Converts a path on the remote machine to the UNC path of the
+    /// administrative share, e.g., `C:\foo` to `\\host\C$\foo`.
+    fn unc_path(&self, path: &GuestPath) -> Result<PathBuf> {
+        let path = path.to_string();
+        let Some((drive, rest)) = path.split_once(':') else {
+            bail!("{path} is not an absolute path");
+        };
+        Ok(PathBuf::from(format!(r"\\{}\{drive}${rest}", self.host)))
+    }
+}
+
+impl Backend for Remote {
+    fn prepare(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn start(&self) -> Result<()> {
+        say!("🕒 Rebooting {} (press CTRL+C to stop)", self.host);
+        self.power_cycle()?;
+        self.wait_for_boot()?;
+
+        // The service survives reboots unlike on a VM reverted to a snapshot,
+        // and the in-guest agent may have started the driver again. Stop and
+        // delete it so that it can be created again.
+        for command in [
+            format!("sc.exe stop {MODULE_NAME}"),
+            format!("sc.exe delete {MODULE_NAME}"),
+        ] {
+            let _unused = timeout::output(&mut self.ssh(&command), &command, COMMAND_TIMEOUT)?;
+        }
+        Ok(())
+    }
+
+    fn stop(&self) -> Result<()> {
+        // Leave the machine running but unload the driver.
+        let command = format!("sc.exe stop {MODULE_NAME}");
+        let _unused = timeout::output(&mut self.ssh(&command), &command, COMMAND_TIMEOUT)?;
+        Ok(())
+    }
+
+    fn reboot(&self) -> Result<()> {
+        say!("🕒 Rebooting {}", self.host);
+        self.power_cycle()?;
+        self.wait_for_boot()
+    }
+
+    fn wait_until_ready(&self) -> Result<()> {
+        self.wait_for_boot()
+    }
+
+    fn driver_dir(&self) -> GuestPath {
+        GuestPath::new(Path::new(r"C:\Users").join(&self.user).join("Desktop"))
+    }
+
+    fn delete_file(&self, path: &GuestPath) -> Result<()> {
+        let command = format!(r#"del /f "{path}""#);
+        let _unused = timeout::output(&mut self.ssh(&command), &command, COMMAND_TIMEOUT)?;
+        Ok(())
+    }
+
+    fn copy_file(&self, src: &Path, dst: &GuestPath) -> Result<()> {
+        match self.copy {
+            CopyMethod::Scp => {
+                let dst = self.scp_path(dst);
+                let status = timeout::status(
+                    Command::new("scp").args(["-B".as_ref(), src.as_os_str(), dst.as_ref()]),
+                    &format!("scp to {dst}"),
+                    COPY_TIMEOUT,
+                )?;
+                ensure!(status.success(), "scp failed with {status:?}");
+            }
+            CopyMethod::Smb => {
+                let _ = fs::copy(src, self.unc_path(dst)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_file_from_target(&self, src: &GuestPath, dst: &Path) -> Result<()> {
+        match self.copy {
+            CopyMethod::Scp => {
+                let src = self.scp_path(src);
+                let status = timeout::status(
+                    Command::new("scp").args(["-B".as_ref(), src.as_ref(), dst.as_os_str()]),
+                    &format!("scp from {src}"),
+                    COPY_TIMEOUT,
+                )?;
+                ensure!(status.success(), "scp failed with {status:?}");
+            }
+            CopyMethod::Smb => {
+                let _ = fs::copy(self.unc_path(src)?, dst)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn run_program_within(
+        &self,
+        program: &GuestPath,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<()> {
+        let command = format!(r#""{program}" {}"#, args.join(" "));
+        retry::retry(&format!("Running {program}"), || {
+            let status = timeout::status(&mut self.ssh(&command), &command, timeout)?;
+            self.check_connected(status)?;
+            ensure!(status.success(), "{command} failed with {status:?}");
+            Ok(())
+        })
+    }
+
+    fn run_program_with_output_within(
+        &self,
+        program: &GuestPath,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<String> {
+        let command = format!(r#""{program}" {} 2>&1"#, args.join(" "));
+        retry::retry(&format!("Running {program}"), || {
+            let output = timeout::output(&mut self.ssh(&command), &command, timeout)?;
+            self.check_connected(output.status)?;
+            Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+        })
+    }
+
+    fn log_path(&self) -> Option<&Path> {
+        REMOTE_LOG_PATH.map(Path::new)
+    }
+
+    fn capture_screen(&self, _path: &Path) -> Result<()> {
+        bail!("capturing the screen of a remote physical machine is not supported")
+    }
+}
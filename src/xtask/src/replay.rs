@@ -0,0 +1,82 @@
+use std::path::Path;
+
+use anyhow::{Result, ensure};
+use colored::Colorize;
+
+use crate::{
+    Profile,
+    backend::{Backend, copy_and_verify, deploy},
+    logs, test, timeline,
+    ui::say,
+    verifier,
+};
+
+pub(crate) const REPLAY_FILE_NAME: &str = "capcom-replay.trace";
+
+/// Sends the IOCTLs of the trace file at `trace` on a fresh target with the
+/// in-guest test program, to reproduce the crash they were recorded before.
+/// With `timing`, keeps the delays between them. If the target crashes,
+/// fetches and triages the crash dump.
+pub(crate) fn run(
+    backend: &impl Backend,
+    profile: Profile,
+    trace: &Path,
+    timing: bool,
+) -> Result<()> {
+    ensure!(trace.is_file(), "{} does not exist", trace.display());
+    let test_program = test::build(profile)?;
+
+    backend.stop()?;
+    backend.prepare()?;
+    if let Some(log_path) = backend.log_path() {
+        drop(logs::archive(log_path)?);
+    }
+    timeline::follow(backend.log_path());
+
+    let result = replay(backend, profile, &test_program, trace, timing);
+    match &result {
+        Ok(()) => say!(
+            "{}",
+            "✅ Replayed the trace without crashing the target".green()
+        ),
+        Err(err) => {
+            say!(
+                "{}",
+                format!("❌ Replaying the trace failed: {err:#}").red()
+            );
+            drop(verifier::collect_crash_dump(backend)?);
+        }
+    }
+
+    backend.stop()?;
+    if let Some(log_path) = backend.log_path()
+        && let Some(archived_path) = logs::archive(log_path)?
+    {
+        say!("📜 Saved the debug output to {}", archived_path.display());
+    }
+    result
+}
+
+fn replay(
+    backend: &impl Backend,
+    profile: Profile,
+    test_program: &Path,
+    trace: &Path,
+    timing: bool,
+) -> Result<()> {
+    backend.start()?;
+    deploy(backend, profile)?;
+
+    say!("🕒 Copying {} to the target", trace.display());
+    let guest_path = backend.driver_dir().join(REPLAY_FILE_NAME);
+    backend.delete_file(&guest_path)?;
+    copy_and_verify(backend, trace, &guest_path)?;
+
+    say!("🕒 Replaying the trace");
+    let guest_path = guest_path.to_string();
+    let mut args = vec!["--replay", &guest_path];
+    if !timing {
+        args.push("--no-delay");
+    }
+    test::run(backend, test_program, &args)
+}
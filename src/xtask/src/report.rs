@@ -0,0 +1,421 @@
+use std::{
+    env,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Ok, Result};
+use serde_json::{Value, json};
+
+use crate::{
+    backend::{Backend, GuestPath},
+    ui::say,
+    workspace_root_dir,
+};
+
+const CMD_PATH: &str = r"C:\Windows\System32\cmd.exe";
+
+/// The result of one in-guest test.
+#[derive(Debug)]
+struct TestCase {
+    name: String,
+    /// Whether the test did not fail, including when it was skipped.
+    passed: bool,
+    /// Whether the test could not run in the environment, e.g., as an option
+    /// it needs was not given.
+    skipped: bool,
+    duration: Duration,
+    /// The error if the test failed, the reason if it was skipped, or the
+    /// outcome of an observed step.
+    message: String,
+}
+
+/// The build of the driver the tests ran against, as it reported.
+#[derive(Debug)]
+struct DriverBuild {
+    commit: String,
+    dirty: bool,
+    profile: String,
+    timestamp: u64,
+    features: u32,
+}
+
+/// The results of running the in-guest tests in one configuration of the
+/// target, e.g., with HVCI enabled.
+#[derive(Debug)]
+pub(crate) struct Run {
+    configuration: String,
+    guest_build: Option<String>,
+    driver: Option<DriverBuild>,
+    tests: Vec<TestCase>,
+    /// The error that failed the run other than failed tests, e.g., the
+    /// driver failing to start.
+    error: Option<String>,
+    artifacts: Vec<PathBuf>,
+}
+
+impl Run {
+    /// Returns a run in `configuration` that failed with `error` before tests
+    /// ran.
+    pub(crate) fn failed(configuration: &str, error: &anyhow::Error) -> Self {
+        Self {
+            configuration: configuration.to_owned(),
+            guest_build: None,
+            driver: None,
+            tests: Vec::new(),
+            error: Some(format!("{error:#}")),
+            artifacts: Vec::new(),
+        }
+    }
+
+    /// Returns a run in `configuration` of steps that xtask observes in the
+    /// target itself rather than tests of the in-guest test program, e.g.,
+    /// whether the driver loads. Steps are added with [`Run::observe`].
+    pub(crate) fn observed(backend: &impl Backend, configuration: &str) -> Result<Self> {
+        let mut run = Self::parse(configuration, "");
+        run.guest_build = guest_build(backend)?;
+        run.artifacts
+            .extend(backend.log_path().map(Path::to_path_buf));
+        Ok(run)
+    }
+
+    /// Runs `step` and records it as a test named `name`, which passes with
+    /// the outcome `step` returns as the message, e.g., that the driver was
+    /// blocked, or fails with the error.
+    pub(crate) fn observe(&mut self, name: &str, step: impl FnOnce() -> Result<String>) {
+        let start = Instant::now();
+        let result = step();
+        let (passed, message) = match result {
+            Result::Ok(outcome) => (true, outcome),
+            Err(err) => (false, format!("{err:#}")),
+        };
+        say!("📝 {name}: {message}");
+        self.tests.push(TestCase {
+            name: name.to_owned(),
+            passed,
+            skipped: false,
+            duration: start.elapsed(),
+            message,
+        });
+    }
+
+    /// Collects the results from the report file the in-guest test program
+    /// wrote to `guest_report` in the target, after it ran with `result`.
+    pub(crate) fn collect(
+        backend: &impl Backend,
+        configuration: &str,
+        guest_report: &GuestPath,
+        result: Result<()>,
+    ) -> Result<Self> {
+        let host_report = env::temp_dir().join("capcom-test-report.txt");
+        let text = match backend.copy_file_from_target(guest_report, &host_report) {
+            Result::Ok(()) => fs::read_to_string(&host_report)?,
+            Err(_) => String::new(),
+        };
+        // The copy does not exist if copying failed.
+        drop(fs::remove_file(&host_report));
+
+        let mut run = Self::parse(configuration, &text);
+        run.error = result.err().map(|err| format!("{err:#}"));
+        run.guest_build = guest_build(backend)?;
+        run.artifacts
+            .extend(backend.log_path().map(Path::to_path_buf));
+        Ok(run)
+    }
+
+    /// Parses the report file of the in-guest test program. Malformed lines
+    /// are ignored.
+    fn parse(configuration: &str, text: &str) -> Self {
+        let mut driver = None;
+        let mut tests = Vec::new();
+        for line in text.lines() {
+            let fields: Vec<_> = line.split('\t').collect();
+            match fields[..] {
+                ["driver", commit, dirty, profile, timestamp, features] => {
+                    driver = Some(DriverBuild {
+                        commit: commit.to_owned(),
+                        dirty: dirty == "1",
+                        profile: profile.to_owned(),
+                        timestamp: timestamp.parse().unwrap_or_default(),
+                        features: u32::from_str_radix(features, 16).unwrap_or_default(),
+                    });
+                }
+                ["test", name, status, millis, message] => tests.push(TestCase {
+                    name: name.to_owned(),
+                    passed: status != "fail",
+                    skipped: status == "skip",
+                    duration: Duration::from_millis(millis.parse().unwrap_or_default()),
+                    message: message.to_owned(),
+                }),
+                _ => {}
+            }
+        }
+        Self {
+            configuration: configuration.to_owned(),
+            guest_build: None,
+            driver,
+            tests,
+            error: None,
+            artifacts: Vec::new(),
+        }
+    }
+
+    /// Adds a file produced by the run, e.g., a crash dump.
+    pub(crate) fn add_artifact(&mut self, path: PathBuf) {
+        self.artifacts.push(path);
+    }
+
+    /// Checks whether the run completed and every test passed.
+    pub(crate) fn passed(&self) -> bool {
+        self.error.is_none() && self.tests.iter().all(|test| test.passed)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "configuration": self.configuration,
+            "passed": self.passed(),
+            "guest_build": self.guest_build,
+            "driver": self.driver.as_ref().map(|driver| json!({
+                "commit": driver.commit,
+                "dirty": driver.dirty,
+                "profile": driver.profile,
+                "timestamp": driver.timestamp,
+                "features": driver.features,
+            })),
+            "error": self.error,
+            "tests": self.tests.iter().map(|test| json!({
+                "name": test.name,
+                "passed": test.passed,
+                "skipped": test.skipped,
+                "duration_ms": test.duration.as_millis() as u64,
+                "message": test.message,
+            })).collect::<Vec<_>>(),
+            "artifacts": self.artifacts,
+        })
+    }
+
+    fn to_junit(&self, xml: &mut String) {
+        let failures = self.tests.iter().filter(|test| !test.passed).count();
+        let skipped = self.tests.iter().filter(|test| test.skipped).count();
+        let errors = usize::from(self.error.is_some());
+        let time: Duration = self.tests.iter().map(|test| test.duration).sum();
+        let _ = writeln!(
+            xml,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{failures}\" errors=\"{errors}\" \
+             skipped=\"{skipped}\" time=\"{:.3}\">",
+            escape(&self.configuration),
+            self.tests.len() + errors,
+            time.as_secs_f64()
+        );
+
+        let _ = writeln!(xml, "    <properties>");
+        let mut properties = Vec::new();
+        if let Some(guest_build) = &self.guest_build {
+            properties.push(("guest_build", guest_build.clone()));
+        }
+        if let Some(driver) = &self.driver {
+            properties.extend([
+                ("driver_commit", driver.commit.clone()),
+                ("driver_dirty", driver.dirty.to_string()),
+                ("driver_profile", driver.profile.clone()),
+                ("driver_timestamp", driver.timestamp.to_string()),
+                ("driver_features", format!("{:#x}", driver.features)),
+            ]);
+        }
+        for artifact in &self.artifacts {
+            properties.push(("artifact", artifact.display().to_string()));
+        }
+        for (name, value) in properties {
+            let _ = writeln!(
+                xml,
+                "      <property name=\"{name}\" value=\"{}\"/>",
+                escape(&value)
+            );
+        }
+        let _ = writeln!(xml, "    </properties>");
+
+        for test in &self.tests {
+            let _ = write!(
+                xml,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                escape(&test.name),
+                escape(&self.configuration),
+                test.duration.as_secs_f64()
+            );
+            if test.skipped {
+                let _ = writeln!(
+                    xml,
+                    ">\n      <skipped message=\"{}\"/>\n    </testcase>",
+                    escape(&test.message)
+                );
+            } else if test.passed && test.message.is_empty() {
+                let _ = writeln!(xml, "/>");
+            } else if test.passed {
+                let _ = writeln!(
+                    xml,
+                    ">\n      <system-out>{}</system-out>\n    </testcase>",
+                    escape(&test.message)
+                );
+            } else {
+                let _ = writeln!(
+                    xml,
+                    ">\n      <failure message=\"{}\"/>\n    </testcase>",
+                    escape(&test.message)
+                );
+            }
+        }
+        // JUnit has errors only in test cases. Report the error of the run as
+        // that of a test case `run`.
+        if let Some(error) = &self.error {
+            let _ = writeln!(
+                xml,
+                "    <testcase name=\"run\" classname=\"{}\">\n      <error message=\"{}\"/>\n    \
+                 </testcase>",
+                escape(&self.configuration),
+                escape(error)
+            );
+        }
+        let _ = writeln!(xml, "  </testsuite>");
+    }
+}
+
+/// Writes `runs` as `<name>-<timestamp>.json` and `.xml` in JUnit XML under
+/// `target/reports`.
+pub(crate) fn write(name: &str, runs: &[Run]) -> Result<()> {
+    let report_dir = workspace_root_dir().join("target").join("reports");
+    fs::create_dir_all(&report_dir)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let json_path = report_dir.join(format!("{name}-{timestamp}.json"));
+    let junit_path = json_path.with_extension("xml");
+
+    let report = json!({
+        "name": name,
+        "timestamp": timestamp,
+        "passed": runs.iter().all(Run::passed),
+        "runs": runs.iter().map(Run::to_json).collect::<Vec<_>>(),
+    });
+    fs::write(&json_path, serde_json::to_string_pretty(&report)?)?;
+
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites name=\"{}\">\n",
+        escape(name)
+    );
+    for run in runs {
+        run.to_junit(&mut xml);
+    }
+    xml.push_str("</testsuites>\n");
+    fs::write(&junit_path, xml)?;
+
+    say!(
+        "📄 Wrote the reports to {} and {}",
+        json_path.display(),
+        junit_path.display()
+    );
+    Ok(())
+}
+
+/// Returns the build number of Windows in the target, e.g., `10.0.22631.4317`,
+/// from the output of `ver`.
+fn guest_build(backend: &impl Backend) -> Result<Option<String>> {
+    let cmd = GuestPath::new(PathBuf::from(CMD_PATH));
+    let output = backend.run_program_with_output(&cmd, &["/c", "ver"])?;
+    Ok(output
+        .split_once("[Version ")
+        .and_then(|(_, rest)| rest.split_once(']'))
+        .map(|(version, _)| version.to_owned()))
+}
+
+/// Escapes `text` for XML attribute values. Line breaks and tabs are written
+/// as character references, as parsers turn them into spaces in attribute
+/// values otherwise, and other control characters, which XML 1.0 does not
+/// allow even as references, as `\u{..}`.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\t' | '\n' | '\r' => {
+                let _ = write!(escaped, "&#{};", u32::from(c));
+            }
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\u{{{:x}}}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a run with one test failing with `message`.
+    fn run_with_message(message: &str) -> Run {
+        let mut run = Run::parse("hvci \"on\"", "");
+        run.tests.push(TestCase {
+            name: "read_<file>".to_owned(),
+            passed: false,
+            skipped: false,
+            duration: Duration::from_millis(12),
+            message: message.to_owned(),
+        });
+        run.error = Some(message.to_owned());
+        run
+    }
+
+    #[test]
+    fn escapes_xml() {
+        const CASES: &[(&str, &str)] = &[
+            ("plain", "plain"),
+            (r#"say "hi""#, "say &quot;hi&quot;"),
+            ("it's", "it&apos;s"),
+            ("a < b > c", "a &lt; b &gt; c"),
+            ("R&D", "R&amp;D"),
+            ("&amp;", "&amp;amp;"),
+            ("line 1\nline 2\r\n", "line 1&#10;line 2&#13;&#10;"),
+            ("a\tb", "a&#9;b"),
+            ("\0\u{1b}[31m\u{7f}", r"\u{0}\u{1b}[31m\u{7f}"),
+            ("ドライバー ✅ é", "ドライバー ✅ é"),
+        ];
+        for &(text, expected) in CASES {
+            assert_eq!(escape(text), expected, "{text:?}");
+        }
+    }
+
+    #[test]
+    fn writes_junit_without_raw_markup_or_control_characters() {
+        let message = "expected \"<ok>\" & got 'é'\n\u{7}";
+        let mut xml = String::new();
+        run_with_message(message).to_junit(&mut xml);
+
+        let escaped = r"expected &quot;&lt;ok&gt;&quot; &amp; got &apos;é&apos;&#10;\u{7}";
+        assert!(xml.contains(&format!("<failure message=\"{escaped}\"/>")));
+        assert!(xml.contains(&format!("<error message=\"{escaped}\"/>")));
+        assert!(
+            xml.contains("<testcase name=\"read_&lt;file&gt;\" classname=\"hvci &quot;on&quot;\"")
+        );
+        assert!(xml.chars().all(|c| c == '\n' || !c.is_control()), "{xml:?}");
+    }
+
+    #[test]
+    fn writes_json_that_round_trips() {
+        let message = "expected \"<ok>\" & got 'é'\n\t\u{0}\u{1b}ドライバー";
+        let json = run_with_message(message).to_json();
+        let text = serde_json::to_string(&json).unwrap();
+        assert!(!text.chars().any(char::is_control), "{text:?}");
+
+        let parsed: Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(parsed["configuration"], "hvci \"on\"");
+        assert_eq!(parsed["error"], message);
+        assert_eq!(parsed["tests"][0]["name"], "read_<file>");
+        assert_eq!(parsed["tests"][0]["message"], message);
+        assert_eq!(parsed["passed"], false);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{fmt, sync::Mutex, thread, time::Duration};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+
+use crate::{config::RETRY_BUDGET, ui::say};
+
+/// The total time spent waiting for retries in this run.
+static WAITED: Mutex<Duration> = Mutex::new(Duration::ZERO);
+
+/// A failure of a step that is expected to go away by waiting, e.g., as the
+/// target is still booting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Transient {
+    /// VMware Tools or the SSH server in the target is not ready yet.
+    ToolsNotReady,
+    /// A file in the target is in use, e.g., by the driver still unloading.
+    FileLocked,
+    /// The target rejected the credentials, e.g., before the user logged on.
+    CredentialsRejected,
+    /// The service is marked for deletion until its last handle is closed.
+    ServiceMarkedForDeletion,
+}
+
+impl Transient {
+    /// Classifies the failure from its message, or returns `None` if it is not
+    /// transient.
+    fn classify(message: &str) -> Option<Self> {
+        const PATTERNS: &[(&str, Transient)] = &[
+            ("vmware tools are not running", Transient::ToolsNotReady),
+            ("guest operations agent", Transient::ToolsNotReady),
+            ("ssh could not connect", Transient::ToolsNotReady),
+            ("is in use", Transient::FileLocked),
+            ("being used by another process", Transient::FileLocked),
+            ("sharing violation", Transient::FileLocked),
+            (
+                "invalid user name or password",
+                Transient::CredentialsRejected,
+            ),
+            ("marked for deletion", Transient::ServiceMarkedForDeletion),
+        ];
+
+        let message = message.to_lowercase();
+        PATTERNS
+            .iter()
+            .find(|(pattern, _)| message.contains(pattern))
+            .map(|&(_, transient)| transient)
+    }
+
+    /// Returns how long to wait before retrying.
+    fn delay(self) -> Duration {
+        match self {
+            Transient::ToolsNotReady | Transient::CredentialsRejected => Duration::from_secs(10),
+            Transient::FileLocked => Duration::from_secs(2),
+            Transient::ServiceMarkedForDeletion => Duration::from_secs(5),
+        }
+    }
+}
+
+impl fmt::Display for Transient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Transient::ToolsNotReady => write!(f, "the target is not ready"),
+            Transient::FileLocked => write!(f, "the file is in use"),
+            Transient::CredentialsRejected => write!(f, "the credentials were rejected"),
+            Transient::ServiceMarkedForDeletion => {
+                write!(f, "the service is marked for deletion")
+            }
+        }
+    }
+}
+
+/// Checks whether `err` is a transient failure that [`retry`] retries.
+pub(crate) fn is_transient(err: &anyhow::Error) -> bool {
+    Transient::classify(&format!("{err:#}")).is_some()
+}
+
+/// Runs `step`, and retries it after waiting while it fails transiently, until
+/// the waits of the whole run exceed [`RETRY_BUDGET`]. Other failures are
+/// returned immediately.
+pub(crate) fn retry<T>(what: &str, mut step: impl FnMut() -> Result<T>) -> Result<T> {
+    loop {
+        let err = match step() {
+            Result::Ok(value) => return Result::Ok(value),
+            Err(err) => err,
+        };
+        let Some(transient) = Transient::classify(&format!("{err:#}")) else {
+            return Err(err);
+        };
+
+        let delay = transient.delay();
+        {
+            let mut waited = WAITED.lock().unwrap();
+            if *waited + delay > RETRY_BUDGET {
+                return Err(err).with_context(|| {
+                    format!("{what} failed after retries used up {RETRY_BUDGET:?}")
+                });
+            }
+            *waited += delay;
+        }
+        say!(
+            "{}",
+            format!("🔁 {what} failed as {transient}. Retrying in {delay:?}").yellow()
+        );
+        thread::sleep(delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use anyhow::{anyhow, bail};
+
+    use super::*;
+
+    #[test]
+    fn classifies_transient_failures() {
+        const CASES: &[(&str, Transient)] = &[
+            (
+                "Error: The VMware Tools are not running in the virtual machine",
+                Transient::ToolsNotReady,
+            ),
+            (
+                "Error: The guest operations agent could not be contacted",
+                Transient::ToolsNotReady,
+            ),
+            (
+                "ssh could not connect to the target: Connection refused",
+                Transient::ToolsNotReady,
+            ),
+            (
+                "Error: The file is in use by another process",
+                Transient::FileLocked,
+            ),
+            (
+                "The process cannot access the file because it is being used by another process.",
+                Transient::FileLocked,
+            ),
+            ("Error: A sharing violation occurred", Transient::FileLocked),
+            (
+                "Error: Invalid user name or password for the guest OS",
+                Transient::CredentialsRejected,
+            ),
+            (
+                "[SC] CreateService FAILED 1072: The specified service has been marked for deletion.",
+                Transient::ServiceMarkedForDeletion,
+            ),
+        ];
+        for &(message, expected) in CASES {
+            assert_eq!(Transient::classify(message), Some(expected), "{message}");
+            assert_eq!(
+                Transient::classify(&message.to_uppercase()),
+                Some(expected),
+                "{message}"
+            );
+        }
+    }
+
+    #[test]
+    fn does_not_classify_fatal_failures() {
+        const CASES: &[&str] = &[
+            "",
+            "[SC] StartService FAILED 577: Windows cannot verify the digital signature for this file.",
+            "[SC] CreateService FAILED 1073: The specified service already exists.",
+            "Error: The system cannot find the file specified.",
+            "Access is denied.",
+            "the driver did not start within 60s",
+        ];
+        for &message in CASES {
+            assert_eq!(Transient::classify(message), None, "{message}");
+        }
+    }
+
+    #[test]
+    fn waits_longer_for_the_target_than_for_files() {
+        assert!(Transient::FileLocked.delay() < Transient::ServiceMarkedForDeletion.delay());
+        assert!(Transient::ServiceMarkedForDeletion.delay() < Transient::ToolsNotReady.delay());
+        assert_eq!(
+            Transient::ToolsNotReady.delay(),
+            Transient::CredentialsRejected.delay()
+        );
+    }
+
+    #[test]
+    fn classifies_the_whole_chain_of_context() {
+        let err = anyhow!("Error: A sharing violation occurred").context("Copying the driver");
+        assert!(is_transient(&err));
+        let err = anyhow!("Access is denied.").context("Copying the driver");
+        assert!(!is_transient(&err));
+    }
+
+    #[test]
+    fn returns_fatal_failures_without_retrying() {
+        let calls = Cell::new(0);
+        let result: Result<()> = retry("Starting the driver", || {
+            calls.set(calls.get() + 1);
+            bail!("[SC] StartService FAILED 577")
+        });
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            format!("{:#}", result.unwrap_err()),
+            "[SC] StartService FAILED 577"
+        );
+    }
+
+    #[test]
+    fn retries_transient_failures_until_they_pass() {
+        let calls = Cell::new(0);
+        let result = retry("Copying the driver", || {
+            calls.set(calls.get() + 1);
+            if calls.get() == 1 {
+                bail!("The file is in use");
+            }
+            Ok(calls.get())
+        });
+        assert_eq!(result.unwrap(), 2);
+    }
+}
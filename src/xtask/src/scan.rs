@@ -0,0 +1,200 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+use anyhow::{Context, Ok, Result, ensure};
+use colored::Colorize;
+
+use crate::{
+    Profile,
+    backend::{Backend, deploy, driver_path},
+    config::YARA_PATH,
+    logs, test, timeline,
+    ui::say,
+};
+
+/// The name of the image the in-guest test program dumps in the target.
+const DUMP_FILE_NAME: &str = "capcom-image.bin";
+
+/// The extensions of the files of YARA rules.
+const RULE_EXTENSIONS: [&str; 2] = ["yar", "yara"];
+
+/// How long a scan of a file may take.
+const SCAN_TIMEOUT: Duration = Duration::from_mins(1);
+
+/// A match of a rule in scanned data.
+#[derive(Debug)]
+struct Match {
+    rule: String,
+    /// The identifiers of the strings of the rule and the offsets they
+    /// matched at.
+    strings: Vec<(String, usize)>,
+}
+
+/// Scans the built driver with the YARA rules in the files under `rules_dir`,
+/// and prints the rules that match.
+pub(crate) fn run(rules_dir: &Path, profile: Profile) -> Result<()> {
+    let rules = Rules::find(rules_dir)?;
+    scan_driver(&rules, profile)
+}
+
+/// Scans the built driver like [`run`], then deploys it to a fresh target and
+/// scans its image in kernel memory, which the in-guest test program reads
+/// with `IOCTL_READ_MEMORY`. The image differs from the file, e.g., as
+/// relocations are applied and discardable sections are freed.
+pub(crate) fn run_in_target(
+    backend: &impl Backend,
+    rules_dir: &Path,
+    profile: Profile,
+) -> Result<()> {
+    let rules = Rules::find(rules_dir)?;
+    scan_driver(&rules, profile)?;
+    let test_program = test::build(profile)?;
+
+    backend.stop()?;
+    backend.prepare()?;
+    timeline::follow(backend.log_path());
+
+    let result = dump_image(backend, profile, &test_program);
+    backend.stop()?;
+    if let Some(log_path) = backend.log_path()
+        && let Some(archived_path) = logs::archive(log_path)?
+    {
+        say!("📜 Saved the debug output to {}", archived_path.display());
+    }
+    let image_path = result?;
+    print_matches("the image in the target", &rules.scan(&image_path)?);
+    Ok(())
+}
+
+/// Scans the driver file built with `profile` with `rules`.
+fn scan_driver(rules: &Rules, profile: Profile) -> Result<()> {
+    let path = driver_path(profile);
+    ensure!(
+        path.exists(),
+        "{} does not exist. Build the driver with `cargo make`",
+        path.display()
+    );
+    print_matches(&path.display().to_string(), &rules.scan(&path)?);
+    Ok(())
+}
+
+/// Deploys the driver, has the in-guest test program dump its image, and
+/// copies the dump to the host. Returns the path to the copy.
+fn dump_image(backend: &impl Backend, profile: Profile, test_program: &Path) -> Result<PathBuf> {
+    backend.start()?;
+    deploy(backend, profile)?;
+
+    let guest_path = backend.driver_dir().join(DUMP_FILE_NAME);
+    backend.delete_file(&guest_path)?;
+    test::run(
+        backend,
+        test_program,
+        &["--dump-image", &guest_path.to_string()],
+    )?;
+    let host_path = env::temp_dir().join(DUMP_FILE_NAME);
+    backend.copy_file_from_target(&guest_path, &host_path)?;
+    Ok(host_path)
+}
+
+/// Prints the rules in `matches` that matched `name`.
+fn print_matches(name: &str, matches: &[Match]) {
+    if matches.is_empty() {
+        say!("{}", format!("✅ No rule matched {name}").green());
+        return;
+    }
+    say!(
+        "{}",
+        format!("🎯 {} rules matched {name}", matches.len()).yellow()
+    );
+    for found in matches {
+        say!("  {}", found.rule);
+        for (string, offset) in &found.strings {
+            say!("    {string} at {offset:#x}");
+        }
+    }
+}
+
+/// The files of YARA rules in a directory.
+struct Rules(Vec<PathBuf>);
+
+impl Rules {
+    /// Returns the files with [`RULE_EXTENSIONS`] directly under `rules_dir`.
+    fn find(rules_dir: &Path) -> Result<Self> {
+        let mut paths = Vec::new();
+        for entry in fs::read_dir(rules_dir)
+            .with_context(|| format!("could not read {}", rules_dir.display()))?
+        {
+            let path = entry?.path();
+            if path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| RULE_EXTENSIONS.contains(&extension))
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        ensure!(
+            !paths.is_empty(),
+            "{} has no .yar or .yara files",
+            rules_dir.display()
+        );
+        Ok(Self(paths))
+    }
+
+    /// Scans the file at `target` with [`YARA_PATH`], and returns the rules
+    /// that match it.
+    fn scan(&self, target: &Path) -> Result<Vec<Match>> {
+        say!(
+            "🕒 Scanning {} with {} rule files",
+            target.display(),
+            self.0.len()
+        );
+        let timeout = SCAN_TIMEOUT.as_secs().to_string();
+        let output = Command::new(YARA_PATH)
+            .args(["--print-strings", "--no-warnings", "--timeout", &timeout])
+            .args(&self.0)
+            .arg(target)
+            .output()
+            .with_context(|| {
+                format!("could not run {YARA_PATH}. Install YARA and add it to PATH")
+            })?;
+        ensure!(
+            output.status.success(),
+            "{YARA_PATH} failed with {:?}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Ok(parse_matches(&String::from_utf8_lossy(&output.stdout)))
+    }
+}
+
+/// Parses the output of YARA with `--print-strings`, which is a line of the
+/// rule name and the target per matching rule, each followed by a line per
+/// string match, e.g., `0x1f40:$name: ...`.
+fn parse_matches(output: &str) -> Vec<Match> {
+    let mut matches: Vec<Match> = Vec::new();
+    for line in output.lines() {
+        if let Some(string_match) = line.strip_prefix("0x") {
+            let mut fields = string_match.splitn(3, ':');
+            let (Some(offset), Some(string), Some(found)) =
+                (fields.next(), fields.next(), matches.last_mut())
+            else {
+                continue;
+            };
+            if let Result::Ok(offset) = usize::from_str_radix(offset, 16) {
+                found.strings.push((string.to_owned(), offset));
+            }
+        } else if let Some(rule) = line.split_whitespace().next() {
+            matches.push(Match {
+                rule: rule.to_owned(),
+                strings: Vec::new(),
+            });
+        }
+    }
+    matches
+}
@@ -0,0 +1,408 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Ok, Result, bail, ensure};
+use clap::ValueEnum;
+use colored::Colorize;
+
+use crate::{
+    Profile,
+    agent::AGENT_SERVICE_NAME,
+    backend::{Backend, GuestPath, copy_and_verify, copy_inf, deploy, driver_path, install},
+    config::{COMMAND_TIMEOUT, MODULE_NAME, START_TIMEOUT},
+    health, logs, preflight,
+    report::{self, Run},
+    test, timeline,
+    ui::{self, say},
+};
+
+pub(crate) const MAP_TEST_DRIVER_NAME: &str = "capcom-map-test";
+
+const SC_PATH: &str = r"C:\Windows\System32\sc.exe";
+const REG_PATH: &str = r"C:\Windows\System32\reg.exe";
+const PNPUTIL_PATH: &str = r"C:\Windows\System32\pnputil.exe";
+const CI_CONFIG_KEY: &str = r"HKLM\SYSTEM\CurrentControlSet\Control\CI\Config";
+const BLOCKLIST_VALUE: &str = "VulnerableDriverBlocklistEnable";
+
+/// How often the state of the driver service is queried while waiting for it
+/// to start after a reboot.
+const SERVICE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// An end-to-end scenario run on a fresh target.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Scenario {
+    /// Load capcom-map-test, a driver that is not signed, with
+    /// `IOCTL_MAP_DRIVER`, check its device appears, and unload it. Requires
+    /// the driver built with the `dangerous` feature and HVCI disabled.
+    MapDriver,
+    /// Run the in-guest test program with a token that is not an
+    /// administrator's, elevate it to SYSTEM with the token-steal payload of
+    /// capcom-client, and check its token and the output of `whoami`. Requires
+    /// HVCI disabled and the driver built without the `defanged` feature.
+    Elevate,
+    /// Save a configuration with `IOCTL_SAVE_CONFIG` and reboot the target
+    /// twice: once for Windows to start the driver as a system-start service
+    /// with capcom-agent disabled, and once for capcom-agent to start it as a
+    /// demand-start service. After each reboot, check that test signing is
+    /// still enabled and the saved configuration applies, and continue with
+    /// the in-guest tests.
+    Reboot,
+    /// Enable the Microsoft vulnerable driver blocklist, reboot the target,
+    /// and record how each way of loading the driver behaves: `sc start`,
+    /// `pnputil` with the INF, and mapping capcom-map-test through the
+    /// instance of the driver `sc start` loaded. The outcomes are recorded
+    /// rather than checked. Requires the driver built with `cargo make`, and
+    /// with the `dangerous` feature for mapping.
+    Blocklist,
+}
+
+/// A step of a scenario made of steps.
+#[derive(Clone, Copy, Debug)]
+enum Step {
+    /// Runs `sc` in the target with the arguments.
+    Sc(&'static [&'static str]),
+    /// Runs the in-guest test program with the arguments and records the
+    /// results.
+    Test(&'static [&'static str]),
+    /// Reboots the target, checks that test signing is still enabled, and
+    /// waits for the driver to be started again, by Windows or by
+    /// capcom-agent depending on the start types of their services.
+    Reboot,
+}
+
+/// The steps of [`Scenario::Reboot`] after deploying the driver.
+const REBOOT_STEPS: &[Step] = &[
+    Step::Test(&["--test", "persistence", "--persistence", "save"]),
+    // Not boot-start, as the boot loader only loads drivers under the Windows
+    // directory.
+    Step::Sc(&["config", MODULE_NAME, "start=", "system"]),
+    Step::Sc(&["config", AGENT_SERVICE_NAME, "start=", "demand"]),
+    Step::Reboot,
+    Step::Test(&["--test", "persistence", "--persistence", "check"]),
+    Step::Test(&["--test", "persistence", "--persistence", "save"]),
+    Step::Sc(&["config", MODULE_NAME, "start=", "demand"]),
+    Step::Sc(&["config", AGENT_SERVICE_NAME, "start=", "auto"]),
+    Step::Reboot,
+    Step::Test(&["--test", "persistence", "--persistence", "check"]),
+    Step::Test(&[]),
+];
+
+impl Scenario {
+    fn name(self) -> &'static str {
+        match self {
+            Scenario::MapDriver => "map-driver",
+            Scenario::Elevate => "elevate",
+            Scenario::Reboot => "reboot",
+            Scenario::Blocklist => "blocklist",
+        }
+    }
+}
+
+/// Runs `scenario` and writes a report of it.
+pub(crate) fn run(backend: &impl Backend, profile: Profile, scenario: Scenario) -> Result<()> {
+    let test_program = test::build(profile)?;
+    let map_test_driver = build_map_test_driver(profile)?;
+
+    backend.stop()?;
+    backend.prepare()?;
+    timeline::follow(backend.log_path());
+
+    let name = scenario.name();
+    say!("🕒 Running the {name} scenario");
+    let mut runs = Vec::new();
+    let result = match scenario {
+        Scenario::MapDriver => map_driver(backend, profile, &test_program, &map_test_driver, name)
+            .map(|run| runs.push(run)),
+        Scenario::Elevate => {
+            elevate(backend, profile, &test_program, name).map(|run| runs.push(run))
+        }
+        Scenario::Reboot => run_steps(
+            backend,
+            profile,
+            &test_program,
+            REBOOT_STEPS,
+            name,
+            &mut runs,
+        ),
+        Scenario::Blocklist => blocklist(backend, profile, &test_program, &map_test_driver, name)
+            .map(|run| runs.push(run)),
+    };
+    if let Err(err) = result {
+        runs.push(Run::failed(name, &err));
+    }
+    let passed = runs.iter().all(Run::passed);
+    if passed {
+        say!("{}", format!("✅ The {name} scenario passed").green());
+    } else {
+        say!("{}", format!("❌ The {name} scenario failed").red());
+    }
+    backend.stop()?;
+    if let Some(log_path) = backend.log_path()
+        && let Some(archived_path) = logs::archive(log_path)?
+        && let Some(run) = runs.last_mut()
+    {
+        run.add_artifact(archived_path);
+    }
+
+    report::write(&format!("scenario-{name}"), &runs)?;
+    ensure!(passed, "the {name} scenario failed");
+    Ok(())
+}
+
+/// Deploys the driver, copies capcom-map-test to the target, and has the
+/// in-guest test program map, check and unmap it.
+fn map_driver(
+    backend: &impl Backend,
+    profile: Profile,
+    test_program: &Path,
+    map_test_driver: &Path,
+    name: &str,
+) -> Result<Run> {
+    backend.start()?;
+    deploy(backend, profile)?;
+
+    let guest_path = copy_map_test_driver(backend, map_test_driver)?.to_string();
+    test::run_with_report(
+        backend,
+        test_program,
+        &[
+            "--test",
+            "map_test_driver",
+            "--map-test-driver",
+            &guest_path,
+        ],
+        name,
+    )
+}
+
+/// Copies capcom-map-test at `map_test_driver` to the target, and returns the
+/// path to it there.
+fn copy_map_test_driver(backend: &impl Backend, map_test_driver: &Path) -> Result<GuestPath> {
+    say!("🕒 Copying {MAP_TEST_DRIVER_NAME} to the target");
+    let guest_path = backend
+        .driver_dir()
+        .join(&(MAP_TEST_DRIVER_NAME.to_owned() + ".sys"));
+    backend.delete_file(&guest_path)?;
+    copy_and_verify(backend, map_test_driver, &guest_path)?;
+    Ok(guest_path)
+}
+
+/// Installs the driver without starting it, enables the vulnerable driver
+/// blocklist, reboots the target, and records whether the driver loads with
+/// `sc start` and with `pnputil`, and whether the instance `sc start` loaded
+/// maps capcom-map-test.
+fn blocklist(
+    backend: &impl Backend,
+    profile: Profile,
+    test_program: &Path,
+    map_test_driver: &Path,
+    name: &str,
+) -> Result<Run> {
+    let sc = GuestPath::new(PathBuf::from(SC_PATH));
+    let reg = GuestPath::new(PathBuf::from(REG_PATH));
+    let pnputil = GuestPath::new(PathBuf::from(PNPUTIL_PATH));
+
+    backend.start()?;
+    install(backend, profile)?;
+    let map_test_driver = copy_map_test_driver(backend, map_test_driver)?.to_string();
+
+    // capcom-agent would start the driver after the reboot. The blocklist is
+    // read when Windows boots.
+    say!("🕒 Enabling the vulnerable driver blocklist in the target");
+    backend.run_program(&sc, &["config", AGENT_SERVICE_NAME, "start=", "demand"])?;
+    backend.run_program(
+        &reg,
+        &[
+            "add",
+            CI_CONFIG_KEY,
+            "/v",
+            BLOCKLIST_VALUE,
+            "/t",
+            "REG_DWORD",
+            "/d",
+            "1",
+            "/f",
+        ],
+    )?;
+    backend.reboot()?;
+
+    let mut run = Run::observed(backend, name)?;
+    run.observe("blocklist", || {
+        let output = backend
+            .run_program_with_output(&reg, &["query", CI_CONFIG_KEY, "/v", BLOCKLIST_VALUE])?;
+        let enabled = output
+            .lines()
+            .any(|line| line.contains(BLOCKLIST_VALUE) && line.trim_end().ends_with("0x1"));
+        Ok(if enabled { "enabled" } else { "not enabled" }.to_owned())
+    });
+
+    let mut loaded = false;
+    run.observe("sc", || {
+        say!("🕒 Starting the driver with sc in the target");
+        let output =
+            backend.run_program_with_output_within(&sc, &["start", MODULE_NAME], START_TIMEOUT)?;
+        let (started, outcome) = load_outcome(backend, &output)?;
+        loaded = started;
+        Ok(outcome)
+    });
+    run.observe("map_driver", || {
+        if !loaded {
+            return Ok("skipped as the driver is not loaded".to_owned());
+        }
+        let result = test::run(
+            backend,
+            test_program,
+            &[
+                "--test",
+                "map_test_driver",
+                "--map-test-driver",
+                &map_test_driver,
+            ],
+        );
+        Ok(match result {
+            Result::Ok(()) => format!("mapped and unmapped {MAP_TEST_DRIVER_NAME}"),
+            Err(err) => format!("failed: {err:#}"),
+        })
+    });
+
+    // The service of the INF has the same name, so remove the one of
+    // `sc create` first. Stopping fails if the driver was not loaded.
+    say!("🕒 Deleting the '{MODULE_NAME}' service in the target");
+    drop(backend.run_program_with_output(&sc, &["stop", MODULE_NAME])?);
+    drop(backend.run_program_with_output(&sc, &["delete", MODULE_NAME])?);
+    run.observe("pnputil", || {
+        let inf_path = copy_inf(backend, &driver_path(profile))?;
+        say!("🕒 Installing the driver with pnputil in the target");
+        let output = backend.run_program_with_output(
+            &pnputil,
+            &["/add-driver", &inf_path.to_string(), "/install"],
+        )?;
+        let start_output =
+            backend.run_program_with_output_within(&sc, &["start", MODULE_NAME], START_TIMEOUT)?;
+        Ok(match load_outcome(backend, &start_output)? {
+            (true, outcome) => outcome,
+            (false, outcome) => format!("{outcome} after pnputil printed: {}", output.trim()),
+        })
+    });
+    Ok(run)
+}
+
+/// Returns whether the driver loaded after `sc start`, whose output is
+/// `start_output`, and the outcome to record, e.g., the error 1275
+/// (ERROR_DRIVER_BLOCKED) for the blocklist.
+fn load_outcome(backend: &impl Backend, start_output: &str) -> Result<(bool, String)> {
+    if health::is_running(backend)? {
+        return Ok((true, "loaded".to_owned()));
+    }
+    let outcome = match health::start_error(start_output) {
+        Some(error) => format!("failed with error {error}"),
+        None => format!("not loaded: {}", start_output.trim()),
+    };
+    Ok((false, outcome))
+}
+
+/// Deploys the driver, and has the in-guest test program elevate an instance
+/// of itself that is not an administrator to SYSTEM.
+fn elevate(
+    backend: &impl Backend,
+    profile: Profile,
+    test_program: &Path,
+    name: &str,
+) -> Result<Run> {
+    backend.start()?;
+    deploy(backend, profile)?;
+    test::run_with_report(backend, test_program, &["--test", "elevate"], name)
+}
+
+/// Deploys the driver and runs `steps`, adding the results of each test step
+/// to `runs` as a run named after the scenario and the number of reboots so
+/// far, e.g., `reboot after 1 reboot`. Stops at the first step that fails.
+fn run_steps(
+    backend: &impl Backend,
+    profile: Profile,
+    test_program: &Path,
+    steps: &[Step],
+    name: &str,
+    runs: &mut Vec<Run>,
+) -> Result<()> {
+    let sc = GuestPath::new(PathBuf::from(SC_PATH));
+
+    backend.start()?;
+    deploy(backend, profile)?;
+
+    let mut reboots = 0;
+    for step in steps {
+        match *step {
+            Step::Sc(args) => {
+                let output = backend.run_program_with_output(&sc, args)?;
+                ensure!(
+                    !output.contains("FAILED"),
+                    "sc {} failed: {}",
+                    args.join(" "),
+                    output.trim()
+                );
+            }
+            Step::Test(args) => {
+                let configuration = match reboots {
+                    0 => name.to_owned(),
+                    1 => format!("{name} after 1 reboot"),
+                    _ => format!("{name} after {reboots} reboots"),
+                };
+                let run = test::run_with_report(backend, test_program, args, &configuration)?;
+                let passed = run.passed();
+                runs.push(run);
+                if !passed {
+                    return Ok(());
+                }
+            }
+            Step::Reboot => {
+                backend.reboot()?;
+                reboots += 1;
+                ensure!(
+                    preflight::is_test_signing_enabled(backend)?,
+                    "test signing was disabled by the reboot"
+                );
+                wait_for_driver(backend)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits for the driver service to be running in the target, e.g., after a
+/// reboot until capcom-agent has started it.
+fn wait_for_driver(backend: &impl Backend) -> Result<()> {
+    say!("🕒 Waiting for the driver to be started in the target");
+    let start = Instant::now();
+    while !health::is_running(backend)? {
+        if start.elapsed() > COMMAND_TIMEOUT {
+            bail!("the driver was not started within {COMMAND_TIMEOUT:?} after the reboot");
+        }
+        thread::sleep(SERVICE_POLL_INTERVAL);
+    }
+    Ok(())
+}
+
+/// Builds capcom-map-test and returns the path to it. It is not signed, so
+/// `cargo make` is not needed.
+fn build_map_test_driver(profile: Profile) -> Result<PathBuf> {
+    say!("🕒 Building {MAP_TEST_DRIVER_NAME}");
+    let mut cargo = Command::new("cargo");
+    let _ = cargo.args(["build", "--package", MAP_TEST_DRIVER_NAME]);
+    if profile.release {
+        let _ = cargo.arg("--release");
+    }
+    if let Some(triple) = profile.arch.target_triple() {
+        let _ = cargo.args(["--target", triple]);
+    }
+    let status = ui::status(&mut cargo)?;
+    ensure!(status.success(), "cargo failed with {status:?}");
+
+    Ok(profile
+        .target_dir()
+        .join(MAP_TEST_DRIVER_NAME.replace('-', "_") + ".dll"))
+}
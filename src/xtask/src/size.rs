@@ -0,0 +1,119 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::Path,
+};
+
+use anyhow::{Ok, Result};
+use colored::Colorize;
+use object::{Object, ObjectSection};
+
+use crate::{Profile, backend::driver_path};
+
+/// The growth of a section, in percent, flagged as unexpected.
+const GROWTH_THRESHOLD: u64 = 10;
+
+/// Reports the section sizes and imports of the built driver, and compares
+/// them with the previous report.
+pub(crate) fn run(profile: Profile) -> Result<()> {
+    let driver_path = driver_path(profile);
+    let report_path = driver_path.with_extension("size.txt");
+    let current = Report::new(&driver_path)?;
+    let previous = Report::load(&report_path).ok();
+
+    println!("{:<10} {:>10} {:>10}", "Section", "Size", "Delta");
+    for (name, &size) in &current.sections {
+        let previous_size = previous
+            .as_ref()
+            .and_then(|report| report.sections.get(name).copied());
+        let line = match previous_size {
+            Some(previous_size) => {
+                let delta = i128::from(size) - i128::from(previous_size);
+                let line = format!("{name:<10} {size:>10} {delta:>+10}");
+                if size * 100 > previous_size * (100 + GROWTH_THRESHOLD) {
+                    line.red().to_string()
+                } else {
+                    line
+                }
+            }
+            None => format!("{name:<10} {size:>10} {:>10}", "new"),
+        };
+        println!("{line}");
+    }
+
+    println!();
+    println!("Imports");
+    for import in &current.imports {
+        let is_new = previous
+            .as_ref()
+            .is_some_and(|report| !report.imports.contains(import));
+        if is_new {
+            println!("{}", format!("  {import} (new)").yellow());
+        } else {
+            println!("  {import}");
+        }
+    }
+    if let Some(previous) = &previous {
+        for import in previous.imports.difference(&current.imports) {
+            println!("{}", format!("  {import} (removed)").green());
+        }
+    }
+
+    current.save(&report_path)
+}
+
+/// Section sizes and imports of a PE file.
+#[derive(Debug, Default)]
+struct Report {
+    sections: BTreeMap<String, u64>,
+    imports: BTreeSet<String>,
+}
+
+impl Report {
+    fn new(path: &Path) -> Result<Self> {
+        let data = fs::read(path)?;
+        let file = object::File::parse(&*data)?;
+        let mut report = Self::default();
+        for section in file.sections() {
+            let _ = report
+                .sections
+                .insert(section.name()?.to_owned(), section.size());
+        }
+        for import in file.imports()? {
+            let _ = report.imports.insert(format!(
+                "{}!{}",
+                String::from_utf8_lossy(import.library()),
+                String::from_utf8_lossy(import.name())
+            ));
+        }
+        Ok(report)
+    }
+
+    /// Loads the report saved with [`Report::save`].
+    fn load(path: &Path) -> Result<Self> {
+        let mut report = Self::default();
+        for line in fs::read_to_string(path)?.lines() {
+            match line.split_whitespace().collect::<Vec<_>>()[..] {
+                ["section", name, size] => {
+                    let _ = report.sections.insert(name.to_owned(), size.parse()?);
+                }
+                ["import", name] => {
+                    let _ = report.imports.insert(name.to_owned());
+                }
+                _ => {}
+            }
+        }
+        Ok(report)
+    }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let lines: Vec<_> = self
+            .sections
+            .iter()
+            .map(|(name, size)| format!("section {name} {size}"))
+            .chain(self.imports.iter().map(|import| format!("import {import}")))
+            .collect();
+        fs::write(path, lines.join("\n") + "\n")?;
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Ok, Result, ensure};
+use object::Object;
+use uuid::Uuid;
+
+use crate::{
+    Profile,
+    backend::{Backend, GuestPath, copy_and_verify, driver_path},
+    config::{GUEST_SYMBOL_DIR, MODULE_NAME},
+    ui::say,
+    workspace_root_dir,
+};
+
+const CMD_PATH: &str = r"C:\Windows\System32\cmd.exe";
+
+/// Copies the PDB of the driver to the symbol directory of the target and the
+/// host, after verifying it matches the driver file.
+///
+/// The host directory uses the symbol store layout, so the debugger finds the
+/// PDB with `.sympath+ <workspace>\target\symbols`.
+pub(crate) fn deploy(backend: &impl Backend, profile: Profile) -> Result<()> {
+    let pdb_name = MODULE_NAME.to_owned() + ".pdb";
+    let pdb_path = find_pdb(profile, &pdb_name)?;
+    let (guid, age) = verify_pdb(&driver_path(profile), &pdb_path)?;
+
+    let store_dir = workspace_root_dir()
+        .join("target")
+        .join("symbols")
+        .join(&pdb_name)
+        .join(format!(
+            "{}{age:X}",
+            guid.simple().to_string().to_uppercase()
+        ));
+    fs::create_dir_all(&store_dir)?;
+    let _ = fs::copy(&pdb_path, store_dir.join(&pdb_name))?;
+
+    say!("🕒 Copying the PDB file to {GUEST_SYMBOL_DIR} in the target");
+    let symbol_dir = GuestPath::new(PathBuf::from(GUEST_SYMBOL_DIR));
+    let _unused = backend.run_program_with_output(
+        &GuestPath::new(PathBuf::from(CMD_PATH)),
+        &["/c", "mkdir", &symbol_dir.to_string()],
+    )?;
+    copy_and_verify(backend, &pdb_path, &symbol_dir.join(&pdb_name))
+}
+
+/// Locates the PDB file generated next to the driver file.
+pub(crate) fn find_pdb(profile: Profile, pdb_name: &str) -> Result<PathBuf> {
+    let package_dir = driver_path(profile)
+        .parent()
+        .context("the driver path should have a parent")?
+        .to_path_buf();
+    let target_dir = profile.target_dir();
+    [package_dir, target_dir]
+        .iter()
+        .map(|dir| dir.join(pdb_name))
+        .find(|path| path.exists())
+        .with_context(|| format!("{pdb_name} is not found"))
+}
+
+/// Checks that the GUID and age recorded in the driver file match the PDB file,
+/// and returns them.
+pub(crate) fn verify_pdb(driver_path: &Path, pdb_path: &Path) -> Result<(Uuid, u32)> {
+    let data = fs::read(driver_path)?;
+    let driver = object::File::parse(&*data)?;
+    let code_view = driver
+        .pdb_info()?
+        .with_context(|| format!("{} has no CodeView record", driver_path.display()))?;
+    let expected_guid = Uuid::from_bytes_le(code_view.guid());
+    let expected_age = code_view.age();
+
+    let mut pdb = pdb::PDB::open(File::open(pdb_path)?)?;
+    let guid = pdb.pdb_information()?.guid;
+    let age = pdb.debug_information()?.age().unwrap_or_default();
+    ensure!(
+        guid == expected_guid && age == expected_age,
+        "{} ({guid}, {age}) does not match {} ({expected_guid}, {expected_age})",
+        pdb_path.display(),
+        driver_path.display()
+    );
+    Ok((guid, age))
+}
@@ -0,0 +1,119 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Ok, Result, ensure};
+
+use crate::{
+    Profile,
+    backend::{Backend, GuestPath, copy_and_verify},
+    config::{COMPAT_POC_PATH, TEST_TIMEOUT},
+    report::Run,
+    ui::{self, say},
+    workspace_root_dir,
+};
+
+pub(crate) const TEST_PROGRAM_NAME: &str = "capcom-test";
+pub(crate) const REPORT_FILE_NAME: &str = "capcom-test-report.txt";
+pub(crate) const TRACE_FILE_NAME: &str = "capcom-test.trace";
+pub(crate) const COMPAT_POC_FILE_NAME: &str = "capcom-poc.exe";
+
+/// Builds the in-guest test program and returns the path to it.
+pub(crate) fn build(profile: Profile) -> Result<PathBuf> {
+    say!("🕒 Building {TEST_PROGRAM_NAME}");
+    let mut cargo = Command::new("cargo");
+    let _ = cargo.args(["build", "--package", TEST_PROGRAM_NAME]);
+    if profile.release {
+        let _ = cargo.arg("--release");
+    }
+    if let Some(triple) = profile.arch.target_triple() {
+        let _ = cargo.args(["--target", triple]);
+    }
+    let status = ui::status(&mut cargo)?;
+    ensure!(status.success(), "cargo failed with {status:?}");
+
+    Ok(profile
+        .target_dir()
+        .join(TEST_PROGRAM_NAME.to_owned() + ".exe"))
+}
+
+/// Copies the in-guest test program to the target and runs it with `args`. The
+/// driver must be started beforehand.
+pub(crate) fn run(backend: &impl Backend, test_program: &Path, args: &[&str]) -> Result<()> {
+    say!("🕒 Running {TEST_PROGRAM_NAME} in the target");
+    let guest_path = backend
+        .driver_dir()
+        .join(&(TEST_PROGRAM_NAME.to_owned() + ".exe"));
+    backend.delete_file(&guest_path)?;
+    copy_and_verify(backend, test_program, &guest_path)?;
+    backend.run_program_within(&guest_path, args, TEST_TIMEOUT)
+}
+
+/// Runs the in-guest test program like [`run`] in `configuration`, e.g., `HVCI
+/// enabled`, and collects the results for reports. Failures of the tests are
+/// recorded in the results rather than returned. The IOCTLs the tests send are
+/// recorded into a trace file, which `cargo xtask replay` can send again. The
+/// exploit at [`COMPAT_POC_PATH`], if any, is copied to the target for the
+/// `compat_poc` test.
+pub(crate) fn run_with_report(
+    backend: &impl Backend,
+    test_program: &Path,
+    args: &[&str],
+    configuration: &str,
+) -> Result<Run> {
+    let report_path = backend.driver_dir().join(REPORT_FILE_NAME);
+    backend.delete_file(&report_path)?;
+    let trace_path = backend.driver_dir().join(TRACE_FILE_NAME);
+    backend.delete_file(&trace_path)?;
+
+    let report_arg = report_path.to_string();
+    let trace_arg = trace_path.to_string();
+    let mut args = args.to_vec();
+    args.extend(["--report", &report_arg, "--trace", &trace_arg]);
+    let poc_arg;
+    if let Some(poc_path) = COMPAT_POC_PATH {
+        poc_arg = copy_compat_poc(backend, Path::new(poc_path))?.to_string();
+        args.extend(["--compat-poc", &poc_arg]);
+    }
+    let result = run(backend, test_program, &args);
+    let mut run = Run::collect(backend, configuration, &report_path, result)?;
+    if let Some(trace_path) = collect_trace(backend)? {
+        run.add_artifact(trace_path);
+    }
+    Ok(run)
+}
+
+/// Copies the public exploit at `poc_path` to the target, and returns the path
+/// to it there.
+fn copy_compat_poc(backend: &impl Backend, poc_path: &Path) -> Result<GuestPath> {
+    say!("🕒 Copying {} to the target", poc_path.display());
+    let guest_path = backend.driver_dir().join(COMPAT_POC_FILE_NAME);
+    backend.delete_file(&guest_path)?;
+    copy_and_verify(backend, poc_path, &guest_path)?;
+    Ok(guest_path)
+}
+
+/// Copies the trace file the in-guest test program recorded from the target
+/// to `target/traces`, and returns the path to the copy. Returns `None` if it
+/// cannot be copied, e.g., as the target crashed and is not back yet.
+pub(crate) fn collect_trace(backend: &impl Backend) -> Result<Option<PathBuf>> {
+    let trace_dir = workspace_root_dir().join("target").join("traces");
+    fs::create_dir_all(&trace_dir)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let trace_path = trace_dir.join(format!("{timestamp}.trace"));
+    let guest_path = backend.driver_dir().join(TRACE_FILE_NAME);
+    if backend
+        .copy_file_from_target(&guest_path, &trace_path)
+        .is_err()
+    {
+        return Ok(None);
+    }
+    say!(
+        "📼 Saved the IOCTLs the tests sent to {}",
+        trace_path.display()
+    );
+    Ok(Some(trace_path))
+}
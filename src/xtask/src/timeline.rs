@@ -0,0 +1,473 @@
+use std::{
+    fmt::Write as _,
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    mem,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, Once, PoisonError},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Ok, Result, bail};
+use serde_json::{Value, json};
+
+use crate::{decode::decode_line, logs};
+
+/// The name of the timeline in a run directory.
+const TIMELINE_FILE_NAME: &str = "timeline.tsv";
+
+/// How often the debug output is read for new lines.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where an entry of the timeline came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Source {
+    /// A line of progress of xtask, e.g., reverting the snapshot.
+    Host,
+    /// A line of the debug output of the target, e.g., a bug check.
+    Guest,
+    /// A message of the driver in the debug output, rendered as `decode` does.
+    Driver,
+}
+
+impl Source {
+    const ALL: [Self; 3] = [Self::Host, Self::Guest, Self::Driver];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Host => "host",
+            Self::Guest => "guest",
+            Self::Driver => "driver",
+        }
+    }
+}
+
+/// An entry of the timeline.
+#[derive(Debug)]
+struct Entry {
+    /// Microseconds since the Unix epoch.
+    time: u64,
+    source: Source,
+    text: String,
+}
+
+/// The entries recorded since the timeline was last saved.
+static ENTRIES: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// The debug output of the target being followed, if any.
+static FOLLOWER: Mutex<Option<Follower>> = Mutex::new(None);
+
+/// Records `text` from `source` at the current time. Escape sequences for
+/// colors are removed.
+pub(crate) fn record(source: Source, text: &str) {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    lock(&ENTRIES).push(Entry {
+        time,
+        source,
+        text: strip_colors(text.trim_end()),
+    });
+}
+
+/// Starts recording the lines of the debug output at `log_path`. As the debug
+/// output has no timestamps, lines are recorded at when they are read, which
+/// is at most [`POLL_INTERVAL`] late.
+pub(crate) fn follow(log_path: Option<&Path>) {
+    static SPAWN: Once = Once::new();
+
+    let Some(log_path) = log_path else {
+        return;
+    };
+    *lock(&FOLLOWER) = Some(Follower::new(log_path));
+    SPAWN.call_once(|| {
+        let _unused = thread::Builder::new()
+            .name("timeline".to_owned())
+            .spawn(|| {
+                loop {
+                    poll();
+                    thread::sleep(POLL_INTERVAL);
+                }
+            });
+    });
+}
+
+/// Writes the entries recorded so far in chronological order into
+/// `run_dir`, before the debug output being followed is moved there. Does
+/// nothing unless following the debug output, e.g., when `logs::archive`
+/// moves the debug output of an interrupted run before a new run.
+pub(crate) fn save(run_dir: &Path) -> Result<()> {
+    {
+        let mut follower = lock(&FOLLOWER);
+        let Some(follower) = follower.as_mut() else {
+            return Ok(());
+        };
+        follower.read_lines();
+        follower.reset();
+    }
+
+    let entries = mem::take(&mut *lock(&ENTRIES));
+    fs::write(run_dir.join(TIMELINE_FILE_NAME), to_text(entries))?;
+    Ok(())
+}
+
+/// Returns `entries` as a timeline file in chronological order. Entries
+/// recorded at the same time keep the order they were recorded in.
+fn to_text(mut entries: Vec<Entry>) -> String {
+    entries.sort_by_key(|entry| entry.time);
+    entries.iter().fold(String::new(), |mut text, entry| {
+        let _ = writeln!(
+            text,
+            "{}\t{}\t{}",
+            entry.time,
+            entry.source.name(),
+            entry.text.replace('\t', " ")
+        );
+        text
+    })
+}
+
+/// Prints the timeline of the run in `run_dir`, or of the latest run under
+/// `target/runs` if `None`, and writes it in the trace event format of Chrome
+/// to `chrome` if given, e.g., for `chrome://tracing` or Perfetto.
+pub(crate) fn run(run_dir: Option<&Path>, chrome: Option<&Path>) -> Result<()> {
+    let path = match run_dir {
+        Some(run_dir) => run_dir.join(TIMELINE_FILE_NAME),
+        None => latest_timeline()?,
+    };
+    let text =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    let entries = parse(&text);
+    println!("🕒 {}", path.display());
+    for entry in &entries {
+        println!(
+            "{} {:6} {}",
+            format_time(entry.time),
+            entry.source.name(),
+            entry.text
+        );
+    }
+
+    if let Some(chrome) = chrome {
+        fs::write(chrome, to_chrome_trace(&entries).to_string())?;
+        println!("📊 Saved the trace to {}", chrome.display());
+    }
+    Ok(())
+}
+
+/// Returns the path to the timeline of the latest run that has one.
+fn latest_timeline() -> Result<PathBuf> {
+    let mut runs = Vec::new();
+    if logs::runs_dir().exists() {
+        for entry in fs::read_dir(logs::runs_dir())? {
+            let path = entry?.path().join(TIMELINE_FILE_NAME);
+            let Some(timestamp) = path
+                .parent()
+                .and_then(Path::file_name)
+                .and_then(|name| name.to_str()?.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            if path.exists() {
+                runs.push((timestamp, path));
+            }
+        }
+    }
+    match runs.into_iter().max() {
+        Some((_, path)) => Ok(path),
+        None => bail!("no run under {} has a timeline", logs::runs_dir().display()),
+    }
+}
+
+/// Parses a timeline file. Malformed lines are ignored.
+fn parse(text: &str) -> Vec<Entry> {
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            let time = fields.next()?.parse().ok()?;
+            let name = fields.next()?;
+            let source = Source::ALL
+                .into_iter()
+                .find(|source| source.name() == name)?;
+            Some(Entry {
+                time,
+                source,
+                text: fields.next()?.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Formats `time` in microseconds since the Unix epoch as the time of day in
+/// UTC with milliseconds, e.g., `12:03:05.123`.
+fn format_time(time: u64) -> String {
+    let millis = time / 1000;
+    let seconds = millis / 1000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        seconds / 3600 % 24,
+        seconds / 60 % 60,
+        seconds % 60,
+        millis % 1000
+    )
+}
+
+/// Returns `entries` as instant events of the trace event format, with a
+/// thread per source.
+fn to_chrome_trace(entries: &[Entry]) -> Value {
+    let names = Source::ALL.into_iter().map(|source| {
+        json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": 1,
+            "tid": source as usize,
+            "args": { "name": source.name() },
+        })
+    });
+    let events = entries.iter().map(|entry| {
+        json!({
+            "name": entry.text,
+            "cat": entry.source.name(),
+            "ph": "i",
+            "s": "t",
+            "ts": entry.time,
+            "pid": 1,
+            "tid": entry.source as usize,
+        })
+    });
+    json!({
+        "traceEvents": names.chain(events).collect::<Vec<_>>(),
+        "displayTimeUnit": "ms",
+    })
+}
+
+/// Reads the lines of the debug output written since the last call.
+fn poll() {
+    if let Some(follower) = lock(&FOLLOWER).as_mut() {
+        follower.read_lines();
+    }
+}
+
+/// The debug output of the target and how far it has been read.
+#[derive(Debug)]
+struct Follower {
+    path: PathBuf,
+    reader: Option<BufReader<File>>,
+    position: u64,
+    /// The bytes of the last line read so far without its newline.
+    partial: Vec<u8>,
+}
+
+impl Follower {
+    fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            reader: None,
+            position: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Records the complete lines written since the last call. Errors are
+    /// ignored as the file may not exist yet.
+    fn read_lines(&mut self) {
+        // The file is archived or truncated when the next run starts.
+        if fs::metadata(&self.path).map_or(0, |metadata| metadata.len()) < self.position {
+            self.reset();
+        }
+        if self.reader.is_none() {
+            let Result::Ok(file) = File::open(&self.path) else {
+                return;
+            };
+            self.reader = Some(BufReader::new(file));
+        }
+        let Some(reader) = self.reader.as_mut() else {
+            return;
+        };
+        while let Result::Ok(read) = reader.read_until(b'\n', &mut self.partial) {
+            if read == 0 || !self.partial.ends_with(b"\n") {
+                break;
+            }
+            self.position += self.partial.len() as u64;
+            let line = String::from_utf8_lossy(&self.partial);
+            match decode_line(line.trim_end()) {
+                Some(decoded) => record(Source::Driver, &decoded),
+                None => record(Source::Guest, &line),
+            }
+            self.partial.clear();
+        }
+    }
+
+    /// Reads the file from the start when it is written next.
+    fn reset(&mut self) {
+        self.reader = None;
+        self.position = 0;
+        self.partial.clear();
+    }
+}
+
+/// Removes escape sequences for colors, e.g., `\x1b[31m`.
+fn strip_colors(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            let _ = chars.by_ref().find(|&c| c == 'm');
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs::OpenOptions, io::Write as _, process};
+
+    use super::*;
+
+    fn entry(time: u64, source: Source, text: &str) -> Entry {
+        Entry {
+            time,
+            source,
+            text: text.to_owned(),
+        }
+    }
+
+    /// Returns the time, source and text of `entries`.
+    fn fields(entries: &[Entry]) -> Vec<(u64, Source, &str)> {
+        entries
+            .iter()
+            .map(|entry| (entry.time, entry.source, entry.text.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn merges_sources_in_chronological_order() {
+        // The guest and the driver lines are recorded when they are read, and
+        // the host lines as they happen, so each source is in order but not
+        // the whole.
+        let entries = vec![
+            entry(3_000, Source::Host, "🕒 Starting the driver"),
+            entry(1_000, Source::Host, "🕒 Reverting the snapshot"),
+            entry(2_500, Source::Guest, "Loading symbols"),
+            entry(3_500, Source::Driver, "Payload rate: 100/s"),
+            entry(2_000, Source::Guest, "KDTARGET: Refreshing KD connection"),
+        ];
+        let text = to_text(entries);
+        assert_eq!(
+            text,
+            "1000\thost\t🕒 Reverting the snapshot\n\
+             2000\tguest\tKDTARGET: Refreshing KD connection\n\
+             2500\tguest\tLoading symbols\n\
+             3000\thost\t🕒 Starting the driver\n\
+             3500\tdriver\tPayload rate: 100/s\n"
+        );
+    }
+
+    #[test]
+    fn keeps_the_order_of_entries_at_the_same_time() {
+        let entries = vec![
+            entry(2, Source::Driver, "second"),
+            entry(1, Source::Host, "first"),
+            entry(2, Source::Guest, "third"),
+            entry(2, Source::Host, "fourth"),
+        ];
+        let parsed = parse(&to_text(entries));
+        assert_eq!(
+            fields(&parsed),
+            [
+                (1, Source::Host, "first"),
+                (2, Source::Driver, "second"),
+                (2, Source::Guest, "third"),
+                (2, Source::Host, "fourth"),
+            ]
+        );
+    }
+
+    #[test]
+    fn round_trips_text_with_tabs() {
+        let parsed = parse(&to_text(vec![entry(7, Source::Guest, "a\tb\tc")]));
+        assert_eq!(fields(&parsed), [(7, Source::Guest, "a b c")]);
+    }
+
+    #[test]
+    fn ignores_malformed_lines() {
+        let text = "1\thost\tok\n\
+                    \n\
+                    x\thost\tnot a time\n\
+                    2\tvm\tunknown source\n\
+                    3\tguest\n\
+                    4\tdriver\t\n";
+        assert_eq!(
+            fields(&parse(text)),
+            [(1, Source::Host, "ok"), (4, Source::Driver, "")]
+        );
+    }
+
+    #[test]
+    fn formats_times_of_day() {
+        assert_eq!(format_time(0), "00:00:00.000");
+        assert_eq!(format_time(1_999), "00:00:00.001");
+        // 2023-11-14 22:13:20.123456 UTC.
+        assert_eq!(format_time(1_700_000_000_123_456), "22:13:20.123");
+    }
+
+    #[test]
+    fn writes_a_thread_per_source_to_chrome_traces() {
+        let trace = to_chrome_trace(&[
+            entry(1, Source::Host, "host"),
+            entry(2, Source::Driver, "driver"),
+        ]);
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events.len(), Source::ALL.len() + 2);
+        assert_eq!(events[Source::ALL.len()]["ts"], 1);
+        assert_eq!(events[Source::ALL.len()]["tid"], Source::Host as usize);
+        assert_eq!(events[Source::ALL.len() + 1]["name"], "driver");
+        assert_eq!(
+            events[Source::ALL.len() + 1]["tid"],
+            Source::Driver as usize
+        );
+    }
+
+    #[test]
+    fn strips_colors() {
+        assert_eq!(strip_colors("\x1b[31m❌ failed\x1b[0m"), "❌ failed");
+        assert_eq!(strip_colors("plain"), "plain");
+    }
+
+    #[test]
+    fn records_complete_lines_of_the_debug_output() {
+        let path = env::temp_dir().join(format!("capcom-timeline-{}.log", process::id()));
+        fs::write(&path, "guest line\ncapcom#7 64\npartial").unwrap();
+        let mut follower = Follower::new(&path);
+        follower.read_lines();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b" line\n").unwrap();
+        follower.read_lines();
+        drop(fs::remove_file(&path));
+
+        // Other tests record the progress of xtask concurrently.
+        let entries = lock(&ENTRIES);
+        let recorded: Vec<_> = fields(&entries)
+            .into_iter()
+            .filter(|&(_, source, _)| source != Source::Host)
+            .map(|(_, source, text)| (source, text))
+            .collect();
+        assert_eq!(
+            recorded,
+            [
+                (Source::Guest, "guest line"),
+                (Source::Driver, "Payload rate: 100/s"),
+                (Source::Guest, "partial line"),
+            ]
+        );
+        assert!(entries.windows(2).all(|pair| pair[0].time <= pair[1].time));
+    }
+}
@@ -0,0 +1,112 @@
+use std::{
+    io::Read,
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    sync::LazyLock,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Ok, Result, bail};
+
+use crate::{
+    config::RUN_DEADLINE,
+    ui::{self, say},
+};
+
+/// How often a running command is checked for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often to tell what is still being waited on.
+const NOTICE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// When the run started, for [`RUN_DEADLINE`].
+static RUN_START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Starts the clock of [`RUN_DEADLINE`].
+pub(crate) fn start_run() {
+    let _ = LazyLock::force(&RUN_START);
+}
+
+/// Runs `command` described as `what` and returns its output, or kills it and
+/// fails if it does not complete within `timeout` or before the run deadline.
+pub(crate) fn output(command: &mut Command, what: &str, timeout: Duration) -> Result<Output> {
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read the pipes in threads so that the command does not block on a full
+    // pipe while being waited for.
+    let stdout = read_to_end(child.stdout.take());
+    let stderr = read_to_end(child.stderr.take());
+    let status = wait(&mut child, what, timeout)?;
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+/// Runs `command` described as `what` with the standard I/O of xtask, and
+/// returns its exit status, or kills it and fails if it does not complete
+/// within `timeout` or before the run deadline. On the dashboard, the output is
+/// shown as progress lines after the command exits.
+pub(crate) fn status(command: &mut Command, what: &str, timeout: Duration) -> Result<ExitStatus> {
+    if ui::is_tui() {
+        let output = output(command, what, timeout)?;
+        for line in String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .chain(String::from_utf8_lossy(&output.stderr).lines())
+        {
+            say!("{line}");
+        }
+        return Ok(output.status);
+    }
+    let mut child = command.spawn()?;
+    wait(&mut child, what, timeout)
+}
+
+fn read_to_end(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut bytes = Vec::new();
+        if let Some(mut pipe) = pipe {
+            // The output read before an error is returned as is.
+            drop(pipe.read_to_end(&mut bytes));
+        }
+        bytes
+    })
+}
+
+/// Waits for `child`, telling what is being waited on every
+/// [`NOTICE_INTERVAL`], and kills it when the time runs out.
+fn wait(child: &mut Child, what: &str, timeout: Duration) -> Result<ExitStatus> {
+    let start = Instant::now();
+    let run_remaining = RUN_DEADLINE.saturating_sub(RUN_START.elapsed());
+    let mut next_notice = NOTICE_INTERVAL;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        let elapsed = start.elapsed();
+        if elapsed >= timeout || elapsed >= run_remaining {
+            // Killing fails if the child exited since it was polled.
+            drop(child.kill());
+            drop(child.wait());
+            if elapsed >= timeout {
+                bail!("{what} did not complete within {timeout:?}");
+            }
+            bail!("{what} did not complete before the run deadline of {RUN_DEADLINE:?}");
+        }
+        if elapsed >= next_notice {
+            say!(
+                "⏳ Still waiting for {what} ({}s of {}s)",
+                elapsed.as_secs(),
+                timeout.as_secs()
+            );
+            next_notice += NOTICE_INTERVAL;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    process::{Command, ExitStatus},
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{Ok, Result, ensure};
+use colored::Colorize;
+
+use crate::timeline::{self, Source};
+
+/// Prints a line of progress, e.g., `🕒 Starting the driver in the target`,
+/// to the console, or to the dashboard if it is shown.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        $crate::ui::say_line(format!($($arg)*))
+    };
+}
+pub(crate) use say;
+
+/// What the pipeline tells the dashboard.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "tui"), expect(dead_code))]
+pub(crate) enum Event {
+    /// A line of progress.
+    Message(String),
+    /// A line of the debug output of the target.
+    Log(String),
+    /// The pipeline completed with the error, if any.
+    Done(Option<String>),
+}
+
+/// The sender to the dashboard, set while it is shown.
+static DASHBOARD: OnceLock<Sender<Event>> = OnceLock::new();
+
+/// Whether the user asked to quit.
+static QUIT: AtomicBool = AtomicBool::new(false);
+
+/// Checks whether the dashboard is shown.
+pub(crate) fn is_tui() -> bool {
+    DASHBOARD.get().is_some()
+}
+
+/// Prints `line` as [`say!`] does.
+pub(crate) fn say_line(line: String) {
+    timeline::record(Source::Host, &line);
+    match DASHBOARD.get() {
+        // Sending fails only once the dashboard has closed.
+        Some(dashboard) => drop(dashboard.send(Event::Message(line))),
+        None => println!("{line}"),
+    }
+}
+
+/// Shows a line of the debug output of the target.
+pub(crate) fn log(line: &str) {
+    if let Some(dashboard) = DASHBOARD.get() {
+        drop(dashboard.send(Event::Log(line.trim_end().to_owned())));
+    } else if line.contains(":ERROR:") {
+        print!("{}", line.red());
+    } else if line.contains(":WARN :") {
+        print!("{}", line.yellow());
+    } else {
+        print!("{line}");
+    }
+}
+
+/// Runs `command` and returns its exit status. On the dashboard, its output is
+/// shown as progress lines after it exits instead of being written to the
+/// terminal the dashboard owns.
+pub(crate) fn status(command: &mut Command) -> Result<ExitStatus> {
+    if !is_tui() {
+        return Ok(command.status()?);
+    }
+    let output = command.output()?;
+    for line in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+    {
+        say_line(line.to_owned());
+    }
+    Ok(output.status)
+}
+
+/// Waits until the user asks to quit, with CTRL+C, from the dashboard, or
+/// with `stop` of the control channel.
+pub(crate) fn wait_for_exit() -> Result<()> {
+    ctrlc::set_handler(request_exit)?;
+    while !QUIT.load(Ordering::Relaxed) {
+        thread::sleep(Duration::from_millis(100));
+    }
+    Ok(())
+}
+
+/// Makes [`wait_for_exit`] return.
+pub(crate) fn request_exit() {
+    QUIT.store(true, Ordering::Relaxed);
+}
+
+/// Runs `pipeline`, on the dashboard if `tui` is set, or on the console
+/// otherwise.
+pub(crate) fn run(tui: bool, pipeline: impl FnOnce() -> Result<()> + Send + 'static) -> Result<()> {
+    if !tui {
+        return pipeline();
+    }
+    ensure!(
+        cfg!(feature = "tui"),
+        "the dashboard requires the `tui` feature. Run with `cargo run --package xtask \
+         --features tui -- --tui ...`"
+    );
+
+    #[cfg(feature = "tui")]
+    {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let _ = DASHBOARD.set(tx.clone());
+
+        // Lines are styled by the dashboard rather than with escape sequences.
+        colored::control::set_override(false);
+
+        let _unused = thread::Builder::new()
+            .name("pipeline".to_owned())
+            .spawn(move || {
+                let result = pipeline();
+                let _ = tx.send(Event::Done(result.err().map(|err| format!("{err:#}"))));
+            })?;
+        crate::dashboard::run(&rx, request_exit)
+    }
+    #[cfg(not(feature = "tui"))]
+    unreachable!()
+}
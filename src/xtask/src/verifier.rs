@@ -0,0 +1,113 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Ok, Result, bail, ensure};
+use colored::Colorize;
+
+use crate::{
+    Profile,
+    backend::{Backend, GuestPath, install, start_driver},
+    config::{KD_PATH, MODULE_NAME},
+    report::{self, Run},
+    test,
+    ui::say,
+    workspace_root_dir,
+};
+
+const CMD_PATH: &str = r"C:\Windows\System32\cmd.exe";
+pub(crate) const MEMORY_DUMP_PATH: &str = r"C:\Windows\MEMORY.DMP";
+
+/// Starts the driver under Driver Verifier with the standard flags and runs the
+/// in-guest tests. If the driver fails to start or the tests fail, e.g., as
+/// the target crashed, fetches and triages the crash dump, and fails.
+pub(crate) fn deploy_and_test(backend: &impl Backend, profile: Profile) -> Result<()> {
+    let test_program = test::build(profile)?;
+
+    install(backend, profile)?;
+
+    // verifier.exe exits with 2 when the settings take effect after reboot.
+    // Treat it as success.
+    say!("🕒 Enabling Driver Verifier for {MODULE_NAME}.sys in the target");
+    let command = format!(
+        "verifier.exe /standard /driver {MODULE_NAME}.sys & if errorlevel 3 (exit 1) \
+         else if errorlevel 2 (exit 0) else if errorlevel 1 (exit 1)"
+    );
+    backend.run_program(&GuestPath::new(PathBuf::from(CMD_PATH)), &["/c", &command])?;
+    backend.reboot()?;
+
+    // Driver Verifier most likely bug checks while the driver starts, so the
+    // crash dump is collected for that too.
+    let name = "Driver Verifier";
+    let mut run = match start_driver(backend, profile) {
+        Result::Ok(()) => test::run_with_report(backend, &test_program, &[], name)?,
+        Err(err) => Run::failed(name, &err),
+    };
+    let passed = run.passed();
+    if passed {
+        say!("{}", "✅ Tests passed under Driver Verifier".green());
+    } else {
+        say!("{}", "❌ Failed under Driver Verifier".red());
+        if let Some(dump_path) = collect_crash_dump(backend)? {
+            run.add_artifact(dump_path);
+
+            // The trace could not be copied while the target was down. Each
+            // IOCTL is flushed to it before being sent, so it survives.
+            if let Some(trace_path) = test::collect_trace(backend)? {
+                run.add_artifact(trace_path);
+            }
+        }
+    }
+
+    report::write("verifier", &[run])?;
+    ensure!(passed, "failed under Driver Verifier");
+    Ok(())
+}
+
+/// Copies the crash dump from the target, if any, prints the summary of
+/// `!analyze -v`, and returns the path to the copy.
+pub(crate) fn collect_crash_dump(backend: &impl Backend) -> Result<Option<PathBuf>> {
+    const KEYS: [&str; 6] = [
+        "BUGCHECK_CODE:",
+        "BUGCHECK_P1:",
+        "BUGCHECK_P2:",
+        "MODULE_NAME:",
+        "SYMBOL_NAME:",
+        "FAILURE_BUCKET_ID:",
+    ];
+
+    // If the target bug checked, it is rebooting.
+    backend.wait_until_ready()?;
+
+    let dump_dir = workspace_root_dir().join("target").join("dumps");
+    fs::create_dir_all(&dump_dir)?;
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let dump_path = dump_dir.join(format!("MEMORY-{timestamp}.DMP"));
+    let guest_dump_path = GuestPath::new(PathBuf::from(MEMORY_DUMP_PATH));
+    if backend
+        .copy_file_from_target(&guest_dump_path, &dump_path)
+        .is_err()
+    {
+        say!("🕒 No crash dump was found in the target");
+        return Ok(None);
+    }
+
+    say!("🕒 Analyzing {}", dump_path.display());
+    let output = Command::new(KD_PATH)
+        .args(["-z".as_ref(), dump_path.as_os_str()])
+        .args(["-c", "!analyze -v; q"])
+        .output()?;
+    if !output.status.success() {
+        bail!("{KD_PATH} failed with {:?}", output.status);
+    }
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if KEYS.iter().any(|key| line.starts_with(key)) {
+            say!("{}", line.yellow());
+        }
+    }
+    Ok(Some(dump_path))
+}
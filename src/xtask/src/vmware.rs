@@ -1,175 +1,201 @@
 use std::{
-    fmt,
-    fs::{self, File},
-    io::{BufRead, BufReader},
+    env, fmt, fs,
     path::{Path, PathBuf},
     process::Command,
-    str::FromStr,
-    sync::mpsc::channel,
     thread,
     time::Duration,
 };
 
-use anyhow::{Ok, Result, ensure};
-use colored::Colorize;
+use anyhow::{Ok, Result, anyhow};
 
 use crate::{
-    Profile,
-    config::{LOG_PATH, MODULE_NAME, PASSWORD, SNAPSHOT_NAME, USER_NAME, VMX_PATH},
-    workspace_root_dir,
+    Arch,
+    backend::{Backend, GuestPath},
+    config::{
+        COMMAND_TIMEOUT, COPY_TIMEOUT, LOG_PATH, LOG_PATH_ARM64, PASSWORD, SNAPSHOT_NAME,
+        TOOLS_TIMEOUT, USER_NAME, VMX_PATH, VMX_PATH_ARM64,
+    },
+    retry, timeout,
+    ui::say,
 };
 
-pub(crate) fn run(profile: Profile) -> Result<()> {
-    let vmx_path = VmxFile::new(VMX_PATH.into());
-
-    // Shutdown the VM if it is running.
-    vmrun(
-        vmx_path.clone(),
-        VmRunCommand::Stop(PowerControl::Force),
-        IgnoreError::Yes,
-    )?;
-
-    // Then, close the VMware Workstation window. If the window remains open,
-    // the VM does not start after reverting a snapshot.
-    let _unused = Command::new("taskkill")
-        .args(["/f", "/t", "/im", "vmware.exe"])
-        .output()?;
-
-    // Delete the log file before starting the VM.
-    if Path::new(LOG_PATH).exists() {
-        fs::remove_file(LOG_PATH)?;
-    }
+const CMD_PATH: &str = r"C:\Windows\System32\cmd.exe";
+pub(crate) const OUTPUT_FILE_NAME: &str = "xtask_output.txt";
 
-    // Start the VM and show logs using threads.
-    let _unused = thread::Builder::new()
-        .name("vmrun".to_owned())
-        .spawn(move || vmrun_thread(profile));
-    let _unused = thread::Builder::new()
-        .name("logging".to_owned())
-        .spawn(log_thread);
-
-    // Finally, indefinitely run the VM until CTRL+C is pressed.
-    let (tx, rx) = channel();
-    ctrlc::set_handler(move || tx.send(()).unwrap())?;
-    rx.recv()?;
-
-    println!("🕒 Shutting down the VM");
-    vmrun(
-        vmx_path,
-        VmRunCommand::Stop(PowerControl::Force),
-        IgnoreError::Yes,
-    )
+/// A VMware Workstation VM reverted to a snapshot for every run.
+#[derive(Clone, Debug)]
+pub(crate) struct Vmware {
+    vmx_path: VmxFile,
+    log_path: &'static str,
+    cred: Credential,
 }
 
-fn vmrun_thread(profile: Profile) {
-    fn run_commands(profile: Profile) -> Result<()> {
-        const SC_PATH: &str = r"C:\Windows\System32\sc.exe";
-        const SERVICE_NAME: &str = MODULE_NAME;
-
-        let guest_path = r"C:\Users\".to_owned() + USER_NAME + r"\Desktop\" + MODULE_NAME + ".sys";
-        let host_path = workspace_root_dir()
-            .join("target")
-            .join(profile.to_string())
-            .join(MODULE_NAME.to_owned() + "_package")
-            .join(MODULE_NAME.to_owned() + ".sys");
-        let vmx_path = VmxFile::new(VMX_PATH.into());
-        let cred = Credential::new(USER_NAME.to_owned(), PASSWORD.to_owned());
-
-        println!("🕒 Reverting the snapshot: {SNAPSHOT_NAME}");
+impl Vmware {
+    /// Returns the VM of `arch`. VMware Workstation cannot run ARM64 guests,
+    /// so the ARM64 VM requires a host that can, e.g., VMware Fusion on Apple
+    /// silicon.
+    pub(crate) fn new(arch: Arch) -> Self {
+        let (vmx_path, log_path) = match arch {
+            Arch::X64 => (VMX_PATH, LOG_PATH),
+            Arch::Arm64 => (VMX_PATH_ARM64, LOG_PATH_ARM64),
+        };
+        Self {
+            vmx_path: VmxFile::new(vmx_path.into()),
+            log_path,
+            cred: Credential::new(USER_NAME.to_owned(), PASSWORD.to_owned()),
+        }
+    }
+}
+
+impl Backend for Vmware {
+    fn prepare(&self) -> Result<()> {
+        // Close the VMware Workstation window. If the window remains open,
+        // the VM does not start after reverting a snapshot.
+        let _unused = Command::new("taskkill")
+            .args(["/f", "/t", "/im", "vmware.exe"])
+            .output()?;
+        Ok(())
+    }
+
+    fn start(&self) -> Result<()> {
+        say!("🕒 Reverting the snapshot: {SNAPSHOT_NAME}");
         vmrun(
-            vmx_path.clone(),
+            self.vmx_path.clone(),
             VmRunCommand::RevertToSnapshot(SNAPSHOT_NAME.to_owned()),
             IgnoreError::No,
         )?;
 
-        println!("🕒 Starting the VM (press CTRL+C to terminate it)");
+        say!("🕒 Starting the VM (press CTRL+C to terminate it)");
         vmrun(
-            vmx_path.clone(),
+            self.vmx_path.clone(),
             VmRunCommand::Start(Gui::Show),
             IgnoreError::No,
-        )?;
+        )
+    }
 
-        println!("🕒 Deleting an old driver file in the VM");
+    fn stop(&self) -> Result<()> {
         vmrun(
-            vmx_path.clone(),
-            VmRunCommand::DeleteFileInGuest(
-                cred.clone(),
-                GuestPath::new(PathBuf::from_str(&guest_path)?),
-            ),
+            self.vmx_path.clone(),
+            VmRunCommand::Stop(PowerControl::Force),
             IgnoreError::Yes,
+        )
+    }
+
+    fn reboot(&self) -> Result<()> {
+        say!("🕒 Rebooting the VM");
+        vmrun(
+            self.vmx_path.clone(),
+            VmRunCommand::Reset(PowerControl::Normal),
+            IgnoreError::No,
         )?;
 
-        println!("🕒 Copying the new driver file to the VM");
+        // VMware Tools may still be reported as running for a moment after the
+        // reset request. Give the guest time to go down before waiting for it.
+        thread::sleep(Duration::from_secs(10));
+        self.wait_until_ready()
+    }
+
+    fn wait_until_ready(&self) -> Result<()> {
+        vmrun(
+            self.vmx_path.clone(),
+            VmRunCommand::WaitForGuest,
+            IgnoreError::No,
+        )
+    }
+
+    fn driver_dir(&self) -> GuestPath {
+        GuestPath::new(Path::new(r"C:\Users").join(USER_NAME).join("Desktop"))
+    }
+
+    fn delete_file(&self, path: &GuestPath) -> Result<()> {
+        vmrun(
+            self.vmx_path.clone(),
+            VmRunCommand::DeleteFileInGuest(self.cred.clone(), path.clone()),
+            IgnoreError::Yes,
+        )
+    }
+
+    fn copy_file(&self, src: &Path, dst: &GuestPath) -> Result<()> {
         vmrun(
-            vmx_path.clone(),
+            self.vmx_path.clone(),
             VmRunCommand::CopyFileFromHostToGuest(
-                cred.clone(),
-                host_path,
-                GuestPath::new(PathBuf::from_str(&guest_path)?),
+                self.cred.clone(),
+                src.to_path_buf(),
+                dst.clone(),
             ),
             IgnoreError::No,
-        )?;
+        )
+    }
 
-        println!("🕒 Creating the '{SERVICE_NAME}' service in the VM");
+    fn copy_file_from_target(&self, src: &GuestPath, dst: &Path) -> Result<()> {
         vmrun(
-            vmx_path.clone(),
-            VmRunCommand::RunProgramInGuest(
-                cred.clone(),
-                GuestPath::new(PathBuf::from_str(SC_PATH)?),
-                vec![
-                    "create".to_owned(),
-                    SERVICE_NAME.to_owned(),
-                    "type=".to_owned(),
-                    "kernel".to_owned(),
-                    "binPath=".to_owned(),
-                    guest_path,
-                ],
+            self.vmx_path.clone(),
+            VmRunCommand::CopyFileFromGuestToHost(
+                self.cred.clone(),
+                src.clone(),
+                dst.to_path_buf(),
             ),
             IgnoreError::No,
-        )?;
+        )
+    }
 
-        println!("🕒 Starting the driver in the VM");
+    fn run_program_within(
+        &self,
+        program: &GuestPath,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<()> {
         vmrun(
-            vmx_path,
+            self.vmx_path.clone(),
             VmRunCommand::RunProgramInGuest(
-                cred,
-                GuestPath::new(PathBuf::from_str(SC_PATH)?),
-                vec!["start".to_owned(), SERVICE_NAME.to_owned()],
+                self.cred.clone(),
+                program.clone(),
+                args.iter().map(|&arg| arg.to_owned()).collect(),
+                timeout,
             ),
             IgnoreError::No,
         )
     }
 
-    run_commands(profile).expect("vmrun should run all commands");
-}
-
-fn log_thread() {
-    fn wait_and_show_logs() -> Result<()> {
-        while !Path::new(LOG_PATH).exists() {
-            thread::sleep(Duration::from_millis(100));
-        }
+    fn run_program_with_output_within(
+        &self,
+        program: &GuestPath,
+        args: &[&str],
+        timeout: Duration,
+    ) -> Result<String> {
+        // vmrun does not relay the output of the program. Redirect it to a file
+        // and copy the file back to the host. Paths are not quoted as cmd.exe
+        // strips the first and last quotes of the command line.
+        let guest_output = self.driver_dir().join(OUTPUT_FILE_NAME);
+        let host_output = env::temp_dir().join(OUTPUT_FILE_NAME);
+        let command = format!("{program} {} > {guest_output} 2>&1", args.join(" "));
+        vmrun(
+            self.vmx_path.clone(),
+            VmRunCommand::RunProgramInGuest(
+                self.cred.clone(),
+                GuestPath::new(PathBuf::from(CMD_PATH)),
+                vec!["/c".to_owned(), command],
+                timeout,
+            ),
+            IgnoreError::Yes,
+        )?;
+        self.copy_file_from_target(&guest_output, &host_output)?;
+        let output = fs::read_to_string(&host_output)?;
+        fs::remove_file(host_output)?;
+        Ok(output)
+    }
 
-        let file = File::open(LOG_PATH)?;
-        let mut reader = BufReader::new(&file);
-        loop {
-            let mut line = String::new();
-            let bytes_read = reader.read_line(&mut line)?;
-            if bytes_read > 0 {
-                // Show the log line with a color if needed.
-                if line.contains(":ERROR:") {
-                    print!("{}", line.red());
-                } else if line.contains(":WARN :") {
-                    print!("{}", line.yellow());
-                } else {
-                    print!("{line}");
-                }
-            } else {
-                thread::sleep(Duration::from_millis(100));
-            }
-        }
+    fn log_path(&self) -> Option<&Path> {
+        Some(Path::new(self.log_path))
     }
 
-    wait_and_show_logs().expect("boo!");
+    fn capture_screen(&self, path: &Path) -> Result<()> {
+        vmrun(
+            self.vmx_path.clone(),
+            VmRunCommand::CaptureScreen(self.cred.clone(), path.to_path_buf()),
+            IgnoreError::No,
+        )
+    }
 }
 
 fn vmrun(vmx_path: VmxFile, command: VmRunCommand, error_handling: IgnoreError) -> Result<()> {
@@ -177,113 +203,104 @@ fn vmrun(vmx_path: VmxFile, command: VmRunCommand, error_handling: IgnoreError)
     const VM_PASSWORD: &str = "12345678";
 
     let vmx_path = vmx_path.0.into_os_string().into_string().unwrap();
-    let mut vmrun = Command::new(VMRUN);
-    let process = match command {
-        VmRunCommand::RevertToSnapshot(snapshot_name) => vmrun.args([
-            "-T",
-            "ws",
-            "-vp",
-            VM_PASSWORD,
+    let (name, cred, operands, timeout) = match command {
+        VmRunCommand::RevertToSnapshot(snapshot_name) => (
             "revertToSnapshot",
-            &vmx_path,
-            &snapshot_name,
-        ]),
-        VmRunCommand::Start(gui) => vmrun.args([
-            "-T",
-            "ws",
-            "-vp",
-            VM_PASSWORD,
-            "start",
-            &vmx_path,
-            &gui.to_string(),
-        ]),
-        VmRunCommand::Stop(power) => vmrun.args([
-            "-T",
-            "ws",
-            "-vp",
-            VM_PASSWORD,
-            "stop",
-            &vmx_path,
-            &power.to_string(),
-        ]),
-        VmRunCommand::DeleteFileInGuest(cred, file_path) => {
-            let file_path = file_path.0.into_os_string().into_string().unwrap();
-            vmrun.args([
-                "-T",
-                "ws",
-                "-vp",
-                VM_PASSWORD,
-                "-gu",
-                &cred.user,
-                "-gp",
-                &cred.pass,
-                "deleteFileInGuest",
-                &vmx_path,
-                &file_path,
-            ])
-        }
-        VmRunCommand::CopyFileFromHostToGuest(cred, src_path, dst_path) => {
-            let src_path = src_path.into_os_string().into_string().unwrap();
-            let dst_path = dst_path.0.into_os_string().into_string().unwrap();
-            vmrun.args([
-                "-T",
-                "ws",
-                "-vp",
-                VM_PASSWORD,
-                "-gu",
-                &cred.user,
-                "-gp",
-                &cred.pass,
-                "copyFileFromHostToGuest",
-                &vmx_path,
-                &src_path,
-                &dst_path,
-            ])
-        }
-        VmRunCommand::RunProgramInGuest(cred, program_path, args) => {
-            let program_path = program_path.0.into_os_string().into_string().unwrap();
-            let mut all_args = vec![
-                "-T",
-                "ws",
-                "-vp",
-                VM_PASSWORD,
-                "-gu",
-                &cred.user,
-                "-gp",
-                &cred.pass,
-                "runProgramInGuest",
-                &vmx_path,
-                &program_path,
-            ];
-            all_args.extend(&args.iter().map(String::as_str).collect::<Vec<&str>>());
-            vmrun.args(all_args)
-        }
+            None,
+            vec![snapshot_name],
+            COMMAND_TIMEOUT,
+        ),
+        VmRunCommand::Start(gui) => ("start", None, vec![gui.to_string()], COMMAND_TIMEOUT),
+        VmRunCommand::Stop(power) => ("stop", None, vec![power.to_string()], COMMAND_TIMEOUT),
+        VmRunCommand::Reset(power) => ("reset", None, vec![power.to_string()], COMMAND_TIMEOUT),
+        VmRunCommand::WaitForGuest => (
+            "getGuestIPAddress",
+            None,
+            vec!["-wait".to_owned()],
+            TOOLS_TIMEOUT,
+        ),
+        VmRunCommand::DeleteFileInGuest(cred, file_path) => (
+            "deleteFileInGuest",
+            Some(cred),
+            vec![file_path.to_string()],
+            COMMAND_TIMEOUT,
+        ),
+        VmRunCommand::CopyFileFromHostToGuest(cred, src_path, dst_path) => (
+            "copyFileFromHostToGuest",
+            Some(cred),
+            vec![
+                src_path.into_os_string().into_string().unwrap(),
+                dst_path.to_string(),
+            ],
+            COPY_TIMEOUT,
+        ),
+        VmRunCommand::CopyFileFromGuestToHost(cred, src_path, dst_path) => (
+            "copyFileFromGuestToHost",
+            Some(cred),
+            vec![
+                src_path.to_string(),
+                dst_path.into_os_string().into_string().unwrap(),
+            ],
+            COPY_TIMEOUT,
+        ),
+        VmRunCommand::RunProgramInGuest(cred, program_path, args, timeout) => (
+            "runProgramInGuest",
+            Some(cred),
+            [program_path.to_string()].into_iter().chain(args).collect(),
+            timeout,
+        ),
+        VmRunCommand::CaptureScreen(cred, path) => (
+            "captureScreen",
+            Some(cred),
+            vec![path.into_os_string().into_string().unwrap()],
+            COMMAND_TIMEOUT,
+        ),
     };
 
-    match error_handling {
-        IgnoreError::Yes => {
-            let _unused = process.output()?;
+    let mut args = vec![
+        "-T".to_owned(),
+        "ws".to_owned(),
+        "-vp".to_owned(),
+        VM_PASSWORD.to_owned(),
+    ];
+    if let Some(cred) = cred {
+        args.extend(["-gu".to_owned(), cred.user, "-gp".to_owned(), cred.pass]);
+    }
+    args.extend([name.to_owned(), vmx_path]);
+    args.extend(operands.iter().cloned());
+
+    // Even if errors are ignored, retry transient failures, such as VMware
+    // Tools not running yet, as the command would not have taken effect.
+    let what = format!("vmrun {name} {}", operands.join(" "));
+    retry::retry(&format!("vmrun {name}"), || {
+        let output = timeout::output(Command::new(VMRUN).args(&args), &what, timeout)?;
+        if output.status.success() {
+            return Ok(());
         }
-        IgnoreError::No => {
-            let status = process.spawn()?.wait()?;
-            let args: Vec<_> = vmrun.get_args().collect();
-            ensure!(
-                status.success(),
-                format!("vmrun {args:?} failed with {status:?}")
-            );
+        let err = anyhow!(
+            "vmrun {args:?} failed with {:?}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stdout).trim()
+        );
+        match error_handling {
+            IgnoreError::Yes if !retry::is_transient(&err) => Ok(()),
+            _ => Err(err),
         }
-    }
-    Ok(())
+    })
 }
 
 #[derive(Clone, Debug)]
 enum VmRunCommand {
     Start(Gui),
     Stop(PowerControl),
+    Reset(PowerControl),
+    WaitForGuest,
     RevertToSnapshot(String),
     DeleteFileInGuest(Credential, GuestPath),
     CopyFileFromHostToGuest(Credential, PathBuf, GuestPath),
-    RunProgramInGuest(Credential, GuestPath, Vec<String>),
+    CopyFileFromGuestToHost(Credential, GuestPath, PathBuf),
+    RunProgramInGuest(Credential, GuestPath, Vec<String>, Duration),
+    CaptureScreen(Credential, PathBuf),
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
@@ -304,7 +321,6 @@ impl fmt::Display for Gui {
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum PowerControl {
-    #[expect(dead_code)]
     Normal,
     Force,
 }
@@ -339,15 +355,6 @@ impl VmxFile {
     }
 }
 
-#[derive(Clone, Debug)]
-struct GuestPath(PathBuf);
-
-impl GuestPath {
-    fn new(path: PathBuf) -> Self {
-        Self(path)
-    }
-}
-
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum IgnoreError {
     Yes,